@@ -0,0 +1,65 @@
+//! Generation of typed bindings for applications.
+
+pub mod rust;
+pub mod ts;
+
+use std::{collections::BTreeMap, sync::Arc};
+
+use anyhow::Result;
+use thiserror::Error;
+
+use crate::schema::compile::CompiledSchema;
+
+#[derive(Error, Debug)]
+pub enum CodegenError {
+  #[error("types `{0}` and `{1}` map to the same name `{2}`")]
+  NameCollision(String, String, String),
+}
+
+/// Splits a specialized type name such as `Item<string>` into its identifiers, `Item` and
+/// `string`.
+fn type_name_parts(name: &str) -> impl Iterator<Item = &str> {
+  name
+    .split(|c: char| !c.is_ascii_alphanumeric() && c != '_')
+    .filter(|x| !x.is_empty())
+}
+
+/// `item_list` -> `ItemList`
+fn upper_camel_case(name: &str) -> String {
+  name
+    .split('_')
+    .filter(|x| !x.is_empty())
+    .map(|x| {
+      let mut chars = x.chars();
+      match chars.next() {
+        Some(first) => first.to_ascii_uppercase().to_string() + chars.as_str(),
+        None => String::new(),
+      }
+    })
+    .collect()
+}
+
+/// Names of the generated types for the table types of `schema`, e.g. `ItemString` for
+/// `Item<string>`.
+fn type_names(schema: &CompiledSchema) -> Result<BTreeMap<Arc<str>, String>> {
+  let mut names: BTreeMap<Arc<str>, String> = BTreeMap::new();
+  let mut seen: BTreeMap<String, Arc<str>> = BTreeMap::new();
+  for name in schema.types.keys() {
+    let generated = type_name_parts(name)
+      .map(upper_camel_case)
+      .collect::<String>();
+    if let Some(other) = seen.get(&generated) {
+      return Err(
+        CodegenError::NameCollision(other.to_string(), name.to_string(), generated).into(),
+      );
+    }
+    seen.insert(generated.clone(), name.clone());
+    names.insert(name.clone(), generated);
+  }
+  Ok(names)
+}
+
+#[cfg(test)]
+mod rust_test;
+#[cfg(test)]
+mod ts_test;
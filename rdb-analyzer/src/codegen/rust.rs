@@ -0,0 +1,223 @@
+//! Rust structs for the table types of a schema.
+//!
+//! Each table type becomes a struct in the map form that `build_table` takes and graphs return,
+//! with serde impls and conversions from and to `SerializedVmValue`. Every field is optional,
+//! since maps may leave out any field. `set` fields have no map form and are left out.
+
+use std::{collections::BTreeMap, fmt::Write, sync::Arc};
+
+use anyhow::Result;
+
+use crate::schema::compile::{CompiledSchema, FieldType, PrimitiveType, SpecializedType};
+
+use super::type_names;
+
+const KEYWORDS: &[&str] = &[
+  "abstract", "as", "async", "await", "become", "box", "break", "const", "continue", "do", "dyn",
+  "else", "enum", "extern", "false", "final", "fn", "for", "if", "impl", "in", "let", "loop",
+  "macro", "match", "mod", "move", "mut", "override", "priv", "pub", "ref", "return", "static",
+  "struct", "trait", "true", "try", "type", "typeof", "unsafe", "unsized", "use", "virtual",
+  "where", "while", "yield",
+];
+
+/// Keywords that cannot be raw identifiers.
+const RESERVED: &[&str] = &["crate", "self", "Self", "super"];
+
+/// Generates a Rust module with a struct for each table type of `schema`. The module depends on
+/// the `anyhow`, `serde` and `rdb-analyzer` crates.
+pub fn generate_rust(schema: &CompiledSchema) -> Result<String> {
+  let g = RustCodegen {
+    names: type_names(schema)?,
+  };
+  let mut out = String::new();
+  out.push_str(
+    "// Generated from a RefineDB schema by `rdbctl codegen --lang rust`. Do not edit.\n\n",
+  );
+  out.push_str("use anyhow::Result;\n");
+  out.push_str(
+    "use rdb_analyzer::data::treewalker::serialize::{SerializedVmValue, TaggedVmValue};\n",
+  );
+  out.push_str("use serde::{Deserialize, Serialize};\n");
+  for ty in schema.types.values() {
+    out.push('\n');
+    g.write_type(&mut out, ty);
+  }
+  Ok(out)
+}
+
+struct RustCodegen {
+  names: BTreeMap<Arc<str>, String>,
+}
+
+impl RustCodegen {
+  fn write_type(&self, out: &mut String, ty: &SpecializedType) {
+    let name = &self.names[&ty.name];
+    let fields = ty
+      .fields
+      .iter()
+      .filter_map(|(field_name, (field_ty, annotations))| {
+        Some((
+          &**field_name,
+          self.rust_type(field_ty)?,
+          field_ty,
+          annotations,
+        ))
+      })
+      .collect::<Vec<_>>();
+
+    writeln!(out, "/// `{}`", ty.name).unwrap();
+    out.push_str("#[allow(non_snake_case)]\n");
+    out.push_str("#[derive(Clone, Debug, Default, PartialEq, Serialize, Deserialize)]\n");
+    writeln!(out, "pub struct {} {{", name).unwrap();
+    for (field_name, rust_ty, _, annotations) in &fields {
+      if !annotations.is_empty() {
+        let annotations = annotations
+          .iter()
+          .map(|x| x.to_string())
+          .collect::<Vec<_>>()
+          .join(" ");
+        writeln!(out, "  /// `{}`", annotations).unwrap();
+      }
+      if RESERVED.contains(field_name) {
+        writeln!(
+          out,
+          "  #[serde(rename = \"{}\", default, skip_serializing_if = \"Option::is_none\")]",
+          field_name
+        )
+        .unwrap();
+      } else {
+        out.push_str("  #[serde(default, skip_serializing_if = \"Option::is_none\")]\n");
+      }
+      writeln!(
+        out,
+        "  pub {}: Option<{}>,",
+        field_ident(field_name),
+        rust_ty
+      )
+      .unwrap();
+    }
+    out.push_str("}\n\n");
+
+    writeln!(out, "impl {} {{", name).unwrap();
+    out.push_str("  pub fn from_vm_value(v: &SerializedVmValue) -> Result<Self> {\n");
+    out.push_str("    v.try_unwrap_map(&[])?;\n");
+    out.push_str("    Ok(Self {\n");
+    for (field_name, _, field_ty, _) in &fields {
+      writeln!(
+        out,
+        "      {}: v\n        .try_get_field({:?})?\n        .map(|x| {})\n        .transpose()?,",
+        field_ident(field_name),
+        field_name,
+        self.from_expr(field_ty, "x")
+      )
+      .unwrap();
+    }
+    out.push_str("    })\n");
+    out.push_str("  }\n\n");
+    out.push_str("  pub fn to_vm_value(&self) -> SerializedVmValue {\n");
+    if fields.is_empty() {
+      out.push_str("    SerializedVmValue::Tagged(TaggedVmValue::M(Default::default()))\n");
+    } else {
+      out.push_str("    let mut m = std::collections::BTreeMap::new();\n");
+      for (field_name, _, field_ty, _) in &fields {
+        writeln!(
+          out,
+          "    if let Some(x) = &self.{} {{\n      m.insert({:?}.to_string(), {});\n    }}",
+          field_ident(field_name),
+          field_name,
+          self.to_expr(field_ty, "x")
+        )
+        .unwrap();
+      }
+      out.push_str("    SerializedVmValue::Tagged(TaggedVmValue::M(m))\n");
+    }
+    out.push_str("  }\n");
+    out.push_str("}\n");
+  }
+
+  /// The Rust type of a field, or `None` if the field has no map form.
+  fn rust_type(&self, ty: &FieldType) -> Option<String> {
+    Some(match ty {
+      FieldType::Primitive(x) => primitive_rust_type(*x).to_string(),
+      FieldType::Table(x) => format!("Box<{}>", self.names[x]),
+      FieldType::List(x) => format!("Vec<{}>", self.rust_type(x)?),
+      FieldType::Map(k, v) => format!("Vec<({}, {})>", self.rust_type(k)?, self.rust_type(v)?),
+      FieldType::Set(_) => return None,
+    })
+  }
+
+  /// An expression converting the `&SerializedVmValue` named `var` to a `Result` of the Rust
+  /// type of `ty`.
+  fn from_expr(&self, ty: &FieldType, var: &str) -> String {
+    match ty {
+      FieldType::Primitive(PrimitiveType::String) => {
+        format!("{}.try_unwrap_string().map(Clone::clone)", var)
+      }
+      FieldType::Primitive(PrimitiveType::Int64) => format!("{}.try_to_int64()", var),
+      FieldType::Primitive(PrimitiveType::Double) => format!("{}.try_to_double()", var),
+      FieldType::Primitive(PrimitiveType::Bytes) => format!("{}.try_to_bytes()", var),
+      FieldType::Table(x) => format!("{}::from_vm_value({}).map(Box::new)", self.names[x], var),
+      FieldType::List(x) => format!(
+        "{}.try_unwrap_list().and_then(|x| x.iter().map(|x| {}).collect::<Result<Vec<_>>>())",
+        var,
+        self.from_expr(x, "x")
+      ),
+      FieldType::Map(k, v) => format!(
+        "{}.try_unwrap_list().and_then(|x| x.iter().map(|x| Ok(({}?, {}?))).collect::<Result<Vec<_>>>())",
+        var,
+        self.from_expr(k, "x.try_get_required_field(\"key\")?"),
+        self.from_expr(v, "x.try_get_required_field(\"value\")?"),
+      ),
+      FieldType::Set(_) => unreachable!(),
+    }
+  }
+
+  /// An expression converting the reference to the Rust type of `ty` named `var` to a
+  /// `SerializedVmValue`.
+  fn to_expr(&self, ty: &FieldType, var: &str) -> String {
+    match ty {
+      FieldType::Primitive(PrimitiveType::String) => {
+        format!("SerializedVmValue::String({}.clone())", var)
+      }
+      FieldType::Primitive(PrimitiveType::Int64) => format!("SerializedVmValue::Int64(*{})", var),
+      FieldType::Primitive(PrimitiveType::Double) => {
+        format!("SerializedVmValue::Double(*{})", var)
+      }
+      FieldType::Primitive(PrimitiveType::Bytes) => {
+        format!("SerializedVmValue::Bytes({}.clone())", var)
+      }
+      FieldType::Table(_) => format!("{}.to_vm_value()", var),
+      FieldType::List(x) => format!(
+        "SerializedVmValue::Tagged(TaggedVmValue::L({}.iter().map(|x| {}).collect()))",
+        var,
+        self.to_expr(x, "x")
+      ),
+      FieldType::Map(k, v) => format!(
+        "SerializedVmValue::Tagged(TaggedVmValue::L({}.iter().map(|(k, v)| SerializedVmValue::Tagged(TaggedVmValue::M(vec![(\"key\".to_string(), {}), (\"value\".to_string(), {})].into_iter().collect()))).collect()))",
+        var,
+        self.to_expr(k, "k"),
+        self.to_expr(v, "v"),
+      ),
+      FieldType::Set(_) => unreachable!(),
+    }
+  }
+}
+
+fn primitive_rust_type(ty: PrimitiveType) -> &'static str {
+  match ty {
+    PrimitiveType::Int64 => "i64",
+    PrimitiveType::Double => "f64",
+    PrimitiveType::String => "String",
+    PrimitiveType::Bytes => "Vec<u8>",
+  }
+}
+
+fn field_ident(name: &str) -> String {
+  if RESERVED.contains(&name) {
+    format!("{}_", name)
+  } else if KEYWORDS.contains(&name) {
+    format!("r#{}", name)
+  } else {
+    name.to_string()
+  }
+}
@@ -0,0 +1,137 @@
+use bumpalo::Bump;
+
+use crate::{
+  codegen::{rust::generate_rust, CodegenError},
+  schema::{
+    compile::{compile, CompiledSchema},
+    grammar::parse,
+  },
+};
+
+fn compile_schema(text: &str) -> CompiledSchema {
+  let alloc = Bump::new();
+  let ast = parse(&alloc, text).unwrap();
+  compile(&ast).unwrap()
+}
+
+#[test]
+fn generate_struct() {
+  let schema = compile_schema(
+    r#"
+    type User {
+      @primary
+      id: string,
+      age: int64,
+    }
+    export set<User> users;
+    "#,
+  );
+  let out = generate_rust(&schema).unwrap();
+  assert_eq!(
+    out,
+    r#"// Generated from a RefineDB schema by `rdbctl codegen --lang rust`. Do not edit.
+
+use anyhow::Result;
+use rdb_analyzer::data::treewalker::serialize::{SerializedVmValue, TaggedVmValue};
+use serde::{Deserialize, Serialize};
+
+/// `User<>`
+#[allow(non_snake_case)]
+#[derive(Clone, Debug, Default, PartialEq, Serialize, Deserialize)]
+pub struct User {
+  #[serde(default, skip_serializing_if = "Option::is_none")]
+  pub age: Option<i64>,
+  /// `@primary`
+  #[serde(default, skip_serializing_if = "Option::is_none")]
+  pub id: Option<String>,
+}
+
+impl User {
+  pub fn from_vm_value(v: &SerializedVmValue) -> Result<Self> {
+    v.try_unwrap_map(&[])?;
+    Ok(Self {
+      age: v
+        .try_get_field("age")?
+        .map(|x| x.try_to_int64())
+        .transpose()?,
+      id: v
+        .try_get_field("id")?
+        .map(|x| x.try_unwrap_string().map(Clone::clone))
+        .transpose()?,
+    })
+  }
+
+  pub fn to_vm_value(&self) -> SerializedVmValue {
+    let mut m = std::collections::BTreeMap::new();
+    if let Some(x) = &self.age {
+      m.insert("age".to_string(), SerializedVmValue::Int64(*x));
+    }
+    if let Some(x) = &self.id {
+      m.insert("id".to_string(), SerializedVmValue::String(x.clone()));
+    }
+    SerializedVmValue::Tagged(TaggedVmValue::M(m))
+  }
+}
+"#
+  );
+}
+
+#[test]
+fn generate_field_types() {
+  let schema = compile_schema(
+    r#"
+    type Item<T> {
+      @primary
+      id: string,
+      value: T,
+      tags: list<int64>,
+      scores: map<string, double>,
+      loop: bytes,
+      self: int64,
+      children: set<Child>,
+      child: Child,
+    }
+    type Child {
+      @primary
+      name: string,
+    }
+    export set<Item<double>> items;
+    "#,
+  );
+  let out = generate_rust(&schema).unwrap();
+  assert!(out.contains("pub struct ItemDouble {"));
+  assert!(out.contains("pub struct Child {"));
+  assert!(out.contains("  pub value: Option<f64>,\n"));
+  assert!(out.contains("  pub tags: Option<Vec<i64>>,\n"));
+  assert!(out.contains("  pub scores: Option<Vec<(String, f64)>>,\n"));
+  assert!(out.contains("  pub r#loop: Option<Vec<u8>>,\n"));
+  assert!(out.contains(
+    "  #[serde(rename = \"self\", default, skip_serializing_if = \"Option::is_none\")]\n  pub self_: Option<i64>,\n"
+  ));
+  assert!(out.contains("  pub child: Option<Box<Child>>,\n"));
+  assert!(out.contains(".map(|x| Child::from_vm_value(x).map(Box::new))"));
+
+  // Sets have no map form.
+  assert!(!out.contains("children"));
+}
+
+#[test]
+fn name_collision() {
+  let schema = compile_schema(
+    r#"
+    type Item_list {
+      x: int64,
+    }
+    type ItemList {
+      x: int64,
+    }
+    export Item_list a;
+    export ItemList b;
+    "#,
+  );
+  let e = generate_rust(&schema).unwrap_err();
+  assert!(matches!(
+    e.downcast_ref::<CodegenError>(),
+    Some(CodegenError::NameCollision(..))
+  ));
+}
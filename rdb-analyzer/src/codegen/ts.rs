@@ -0,0 +1,305 @@
+//! TypeScript bindings for the HTTP API.
+//!
+//! Table types become interfaces in their map form, as in `rust`. Each exported graph of a query
+//! script becomes a method of the client returned by `createClient`, taking its parameters by name
+//! and resolving to its output. Parameter and output types come from the type checker, so the
+//! bindings must be generated against the schema the script is deployed with.
+//!
+//! Queries go through `POST /v1/query`, with `double` values encoded as numbers. `int64` values
+//! are kept as strings to preserve their precision.
+
+use std::{collections::BTreeMap, fmt::Write, sync::Arc};
+
+use anyhow::Result;
+
+use crate::{
+  data::treewalker::{typeck::GlobalTypeInfo, vm::TwVm, vm_value::VmType},
+  schema::compile::{FieldType, PrimitiveType, SpecializedType},
+};
+
+use super::type_names;
+
+const RUNTIME: &str = r#"/** `int64` values. Returned as decimal strings, since JSON numbers cannot hold all of them. */
+export type Int64 = string;
+
+export interface ClientOptions {
+  /** Base URL of the HTTP API of `rdb-server`, e.g. `http://localhost:8080`. */
+  baseUrl: string;
+  namespace: string;
+  /** Id of the query script these bindings were generated from. */
+  script: string;
+  /** Sent as `Authorization: Bearer <token>`. Its role is checked against `@acl` annotations. */
+  token?: string;
+  fetch?: typeof fetch;
+}
+
+/** An error returned by the server, such as `constraint_violation` or `script_error`. */
+export class RdbError extends Error {
+  constructor(readonly status: number, readonly error: string, message: string) {
+    super(message);
+  }
+}
+
+function encodeValue(x: unknown): unknown {
+  if (typeof x === "bigint") return x.toString();
+  if (Array.isArray(x)) return { L: x.map(encodeValue) };
+  if (x !== null && typeof x === "object") {
+    const m: Record<string, unknown> = {};
+    for (const [k, v] of Object.entries(x)) {
+      if (v !== undefined) m[k] = encodeValue(v);
+    }
+    return { M: m };
+  }
+  return x;
+}
+
+function decodeValue(x: any): unknown {
+  if (x !== null && typeof x === "object") {
+    if (Array.isArray(x.L)) return x.L.map(decodeValue);
+    const m: Record<string, unknown> = {};
+    for (const [k, v] of Object.entries(x.M ?? {})) m[k] = decodeValue(v);
+    return m;
+  }
+  return x;
+}
+
+async function invoke(
+  options: ClientOptions,
+  graph: string,
+  params: Record<string, unknown>,
+): Promise<unknown> {
+  const headers: Record<string, string> = { "Content-Type": "application/json" };
+  if (options.token !== undefined) headers["Authorization"] = `Bearer ${options.token}`;
+  const encoded: Record<string, unknown> = {};
+  for (const [k, v] of Object.entries(params)) {
+    if (v !== undefined) encoded[k] = encodeValue(v);
+  }
+  const path = [options.namespace, options.script, graph].map(encodeURIComponent).join("/");
+  const res = await (options.fetch ?? fetch)(
+    `${options.baseUrl.replace(/\/+$/, "")}/v1/query/${path}`,
+    {
+      method: "POST",
+      headers,
+      body: JSON.stringify({ params: encoded, encoding: { double: true } }),
+    },
+  );
+  const text = await res.text();
+  if (!res.ok) {
+    let body: any = {};
+    try {
+      body = JSON.parse(text);
+    } catch {}
+    throw new RdbError(res.status, body.error ?? "http_error", body.message ?? text);
+  }
+  return decodeValue(JSON.parse(text));
+}
+"#;
+
+/// Generates a TypeScript module with an interface for each table type of the schema of `vm`,
+/// and a client with a method for each exported graph of its script. `type_info` is the result
+/// of type checking `vm`.
+pub fn generate_typescript(vm: &TwVm, type_info: &GlobalTypeInfo) -> Result<String> {
+  let g = TsCodegen {
+    names: type_names(vm.schema)?,
+  };
+  let mut out = String::new();
+  out.push_str(
+    "// Generated from a RefineDB schema and query script by `rdbctl codegen --lang ts`. Do not edit.\n\n",
+  );
+  out.push_str(RUNTIME);
+  for ty in vm.schema.types.values() {
+    out.push('\n');
+    g.write_type(&mut out, ty);
+  }
+
+  out.push_str("\nexport function createClient(options: ClientOptions) {\n");
+  out.push_str("  return {\n");
+  for (graph, graph_info) in vm.script.graphs.iter().zip(type_info.graphs.iter()) {
+    if !graph.exported {
+      continue;
+    }
+    let params = graph
+      .param_names
+      .iter()
+      .zip(graph.param_types.iter())
+      .zip(graph_info.params.iter())
+      // The type checker gives `schema` params the type of the root map.
+      .filter(|((_, raw_ty), _)| !matches!(vm.types[**raw_ty as usize], VmType::Schema))
+      .map(|((name, _), ty)| {
+        let optional = vm
+          .script
+          .params
+          .iter()
+          .any(|x| x.name == *name && x.default.is_some());
+        (
+          format!(
+            "{}{}: {}",
+            property_name(name),
+            if optional { "?" } else { "" },
+            g.vm_type(ty, true)
+          ),
+          optional,
+        )
+      })
+      .collect::<Vec<_>>();
+    let params_ty = if params.is_empty() {
+      "{}".to_string()
+    } else {
+      format!(
+        "{{ {} }}",
+        params
+          .iter()
+          .map(|(x, _)| x.as_str())
+          .collect::<Vec<_>>()
+          .join("; ")
+      )
+    };
+    let output = match graph
+      .output
+      .and_then(|x| graph_info.nodes[x as usize].as_ref())
+    {
+      Some(ty) if graph_info.output_nullable => format!("{} | null", g.vm_type(ty, false)),
+      Some(ty) => g.vm_type(ty, false),
+      None => "null".to_string(),
+    };
+
+    writeln!(
+      out,
+      "    /** `{}`{} */",
+      graph.name,
+      if graph.read_only { ", read-only" } else { "" }
+    )
+    .unwrap();
+    writeln!(
+      out,
+      "    {}(params: {}{}): Promise<{}> {{",
+      property_name(&graph.name),
+      params_ty,
+      // The params object may be left out if all params have defaults.
+      if params.iter().all(|(_, optional)| *optional) {
+        " = {}"
+      } else {
+        ""
+      },
+      output
+    )
+    .unwrap();
+    writeln!(
+      out,
+      "      return invoke(options, {:?}, params) as Promise<{}>;",
+      graph.name, output
+    )
+    .unwrap();
+    out.push_str("    },\n");
+  }
+  out.push_str("  };\n");
+  out.push_str("}\n\n");
+  out.push_str("export type Client = ReturnType<typeof createClient>;\n");
+  Ok(out)
+}
+
+struct TsCodegen {
+  names: BTreeMap<Arc<str>, String>,
+}
+
+impl TsCodegen {
+  fn write_type(&self, out: &mut String, ty: &SpecializedType) {
+    writeln!(out, "/** `{}` */", ty.name).unwrap();
+    writeln!(out, "export interface {} {{", self.names[&ty.name]).unwrap();
+    for (field_name, (field_ty, annotations)) in &ty.fields {
+      let ts_ty = match self.field_type(field_ty) {
+        Some(x) => x,
+        None => continue,
+      };
+      if !annotations.is_empty() {
+        let annotations = annotations
+          .iter()
+          .map(|x| x.to_string())
+          .collect::<Vec<_>>()
+          .join(" ");
+        writeln!(out, "  /** `{}` */", annotations).unwrap();
+      }
+      writeln!(out, "  {}?: {} | null;", property_name(field_name), ts_ty).unwrap();
+    }
+    out.push_str("}\n");
+  }
+
+  /// The TypeScript type of a field, or `None` if the field has no map form.
+  fn field_type(&self, ty: &FieldType) -> Option<String> {
+    Some(match ty {
+      FieldType::Primitive(x) => primitive_ts_type(*x, false).to_string(),
+      FieldType::Table(x) => self.names[x].clone(),
+      FieldType::List(x) => array_type(&self.field_type(x)?),
+      FieldType::Map(k, v) => format!(
+        "Array<{{ key: {}; value: {} }}>",
+        self.field_type(k)?,
+        self.field_type(v)?
+      ),
+      FieldType::Set(_) => return None,
+    })
+  }
+
+  /// The TypeScript type of a VM value. `input` selects the wider types accepted in parameters.
+  fn vm_type(&self, ty: &VmType<&str>, input: bool) -> String {
+    match ty {
+      VmType::Primitive(x) => primitive_ts_type(*x, input).to_string(),
+      VmType::Table(x) => self
+        .names
+        .get(x.name)
+        .cloned()
+        .unwrap_or_else(|| "unknown".to_string()),
+      VmType::Bool => "boolean".to_string(),
+      VmType::List(x) => array_type(&self.vm_type(&x.ty, input)),
+      VmType::Map(x) => {
+        if x.is_empty() {
+          return "{}".to_string();
+        }
+        let fields = x
+          .iter()
+          .map(|(k, v)| format!("{}?: {} | null", property_name(k), self.vm_type(v, input)))
+          .collect::<Vec<_>>();
+        format!("{{ {} }}", fields.join("; "))
+      }
+      VmType::Set(_) | VmType::Json | VmType::Unknown | VmType::Schema => "unknown".to_string(),
+    }
+  }
+}
+
+fn primitive_ts_type(ty: PrimitiveType, input: bool) -> &'static str {
+  match ty {
+    PrimitiveType::Int64 if input => "Int64 | number | bigint",
+    PrimitiveType::Int64 => "Int64",
+    PrimitiveType::Double => "number",
+    PrimitiveType::String => "string",
+    // Base64.
+    PrimitiveType::Bytes => "string",
+  }
+}
+
+fn array_type(member: &str) -> String {
+  if member
+    .chars()
+    .all(|c| c.is_ascii_alphanumeric() || c == '_')
+  {
+    format!("{}[]", member)
+  } else {
+    format!("Array<{}>", member)
+  }
+}
+
+/// Identifiers are valid property names in TypeScript, including reserved words.
+fn property_name(name: &str) -> String {
+  let is_ident = name
+    .chars()
+    .next()
+    .map(|c| c.is_ascii_alphabetic() || c == '_' || c == '$')
+    .unwrap_or(false)
+    && name
+      .chars()
+      .all(|c| c.is_ascii_alphanumeric() || c == '_' || c == '$');
+  if is_ident {
+    name.to_string()
+  } else {
+    format!("{:?}", name)
+  }
+}
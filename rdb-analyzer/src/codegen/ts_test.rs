@@ -0,0 +1,80 @@
+use bumpalo::Bump;
+
+use crate::{
+  codegen::ts::generate_typescript,
+  data::treewalker::{asm::codegen::compile_twscript, typeck::GlobalTyckContext, vm::TwVm},
+  schema::{
+    compile::{compile, CompiledSchema},
+    grammar::parse,
+  },
+  storage_plan::planner::generate_plan_for_schema,
+};
+
+fn compile_schema(text: &str) -> CompiledSchema {
+  let alloc = Bump::new();
+  let ast = parse(&alloc, text).unwrap();
+  compile(&ast).unwrap()
+}
+
+fn generate(schema: &str, script: &str) -> String {
+  let schema = compile_schema(schema);
+  let plan = generate_plan_for_schema(&Default::default(), &Default::default(), &schema).unwrap();
+  let script = compile_twscript(script).unwrap();
+  let vm = TwVm::new(&schema, &plan, &script).unwrap();
+  let type_info = GlobalTyckContext::new(&vm).unwrap().typeck().unwrap();
+  generate_typescript(&vm, &type_info).unwrap()
+}
+
+const SCHEMA: &str = r#"
+type User {
+  @primary
+  id: string,
+  age: int64,
+  score: double,
+  tags: list<string>,
+  friends: set<User>,
+}
+export set<User> users;
+"#;
+
+#[test]
+fn generate_interfaces() {
+  let out = generate(SCHEMA, "");
+  assert!(out.contains(
+    "/** `User<>` */\nexport interface User {\n  age?: Int64 | null;\n  /** `@primary` */\n  id?: string | null;\n  score?: number | null;\n  tags?: string[] | null;\n}\n"
+  ));
+}
+
+#[test]
+fn generate_graph_wrappers() {
+  let out = generate(
+    SCHEMA,
+    r#"
+    param {
+      limit: int64 = 10,
+    }
+    export graph add_user(root: schema, id: string, age: int64) {
+      s_insert root.users $ build_table(User) $ m_insert(id) id $ m_insert(age) age create_map;
+    }
+    export readonly graph get_user(root: schema, id: string): map { id: string, score: double } {
+      user = point_get root.users id;
+      return m_insert(id) user.id $ m_insert(score) user.score create_map;
+    }
+    export graph count(root: schema, limit: int64): int64 {
+      return limit;
+    }
+    graph helper(root: schema) {
+    }
+    "#,
+  );
+  assert!(out.contains(
+    "    /** `add_user` */\n    add_user(params: { id: string; age: Int64 | number | bigint }): Promise<null> {\n      return invoke(options, \"add_user\", params) as Promise<null>;\n    },\n"
+  ));
+  assert!(out.contains(
+    "    /** `get_user`, read-only */\n    get_user(params: { id: string }): Promise<{ id?: string | null; score?: number | null }> {\n"
+  ));
+  assert!(
+    out.contains("    count(params: { limit?: Int64 | number | bigint } = {}): Promise<Int64> {\n")
+  );
+  assert!(!out.contains("helper"));
+}
@@ -5,6 +5,12 @@ use thiserror::Error;
 #[async_trait]
 pub trait KeyValueStore: Send + Sync {
   async fn begin_transaction(&self) -> Result<Box<dyn KvTransaction>>;
+
+  /// Begins a transaction that is only used for reading. Backends may read from a snapshot
+  /// without tracking conflicts.
+  async fn begin_read_only_transaction(&self) -> Result<Box<dyn KvTransaction>> {
+    self.begin_transaction().await
+  }
 }
 
 #[async_trait]
@@ -15,6 +21,92 @@ pub trait KvTransaction: Send + Sync {
   async fn delete_range(&self, start: &[u8], end: &[u8]) -> Result<()>;
   async fn scan_keys(&self, start: &[u8], end: &[u8]) -> Result<Box<dyn KvKeyIterator>>;
   async fn commit(self: Box<Self>) -> Result<(), KvError>;
+
+  /// Scans the `(key, value)` entries with keys in `[start, end)`.
+  ///
+  /// The default implementation scans the keys and gets each value. Backends that can read
+  /// values along with keys do it in a single range read.
+  async fn scan(
+    &self,
+    start: &[u8],
+    end: &[u8],
+    options: ScanOptions,
+  ) -> Result<Box<dyn KvIterator>> {
+    let mut keys = vec![];
+    {
+      let mut it = self.scan_keys(start, end).await?;
+      while options.reverse || options.limit.map(|x| keys.len() < x).unwrap_or(true) {
+        match it.next().await? {
+          Some(x) => keys.push(x),
+          None => break,
+        }
+      }
+    }
+    if options.reverse {
+      keys.reverse();
+      if let Some(limit) = options.limit {
+        keys.truncate(limit);
+      }
+    }
+
+    let mut entries = Vec::with_capacity(keys.len());
+    for key in keys {
+      if let Some(value) = self.get(&key).await? {
+        entries.push((key, value));
+      }
+    }
+    Ok(Box::new(VecKvIterator::new(entries)))
+  }
+
+  /// Puts every `(key, value)` entry, in order, as separate `put`s would.
+  ///
+  /// Backends that buffer writes until commit take the whole batch at once, and write
+  /// consecutive puts with fewer round trips or statements.
+  async fn put_many(&self, entries: &[(Vec<u8>, Vec<u8>)]) -> Result<()> {
+    for (key, value) in entries {
+      self.put(key, value).await?;
+    }
+    Ok(())
+  }
+
+  /// Makes the transaction conflict with concurrent writes to `key`, as if it was read. Used on
+  /// the entries of `snapshot` scans.
+  ///
+  /// The default implementation does nothing, for backends that don't track read conflicts.
+  async fn add_read_conflict_key(&self, _key: &[u8]) -> Result<()> {
+    Ok(())
+  }
+
+  /// Adds `delta` to the counter stored at `key`, wrapping on overflow. A missing counter counts
+  /// as zero.
+  ///
+  /// Backends with atomic operations apply the add without reading the key, so that concurrent
+  /// adds to the same counter don't conflict. The default implementation reads, modifies and
+  /// writes it back.
+  async fn atomic_add(&self, key: &[u8], delta: i64) -> Result<()> {
+    let current = self
+      .get(key)
+      .await?
+      .map(|x| decode_counter(&x))
+      .unwrap_or(0);
+    self
+      .put(key, &encode_counter(current.wrapping_add(delta)))
+      .await
+  }
+}
+
+/// Counters are stored as 8-byte little-endian integers, the format of FoundationDB's atomic add.
+pub fn encode_counter(x: i64) -> [u8; 8] {
+  x.to_le_bytes()
+}
+
+/// Inverse of `encode_counter`. Shorter values are zero-extended, and longer ones truncated, as
+/// FoundationDB does for atomic adds.
+pub fn decode_counter(x: &[u8]) -> i64 {
+  let mut buf = [0u8; 8];
+  let n = x.len().min(8);
+  buf[..n].copy_from_slice(&x[..n]);
+  i64::from_le_bytes(buf)
 }
 
 #[async_trait]
@@ -22,6 +114,46 @@ pub trait KvKeyIterator: Send + Sync {
   async fn next(&mut self) -> Result<Option<Vec<u8>>>;
 }
 
+/// Options of `KvTransaction::scan`.
+#[derive(Copy, Clone, Debug, Default)]
+pub struct ScanOptions {
+  /// Returns the entries in descending key order, instead of ascending.
+  pub reverse: bool,
+
+  /// Stops after this many entries.
+  pub limit: Option<usize>,
+
+  /// Reads without conflicting with concurrent writes to the range, so that keys inserted into
+  /// it don't make the transaction fail. Use `KvTransaction::add_read_conflict_key` for the
+  /// entries that are used. Backends that don't track read conflicts ignore it.
+  pub snapshot: bool,
+}
+
+#[async_trait]
+pub trait KvIterator: Send + Sync {
+  async fn next(&mut self) -> Result<Option<(Vec<u8>, Vec<u8>)>>;
+}
+
+/// Iterates over entries that are already read.
+pub struct VecKvIterator {
+  entries: std::vec::IntoIter<(Vec<u8>, Vec<u8>)>,
+}
+
+impl VecKvIterator {
+  pub fn new(entries: Vec<(Vec<u8>, Vec<u8>)>) -> Self {
+    Self {
+      entries: entries.into_iter(),
+    }
+  }
+}
+
+#[async_trait]
+impl KvIterator for VecKvIterator {
+  async fn next(&mut self) -> Result<Option<(Vec<u8>, Vec<u8>)>> {
+    Ok(self.entries.next())
+  }
+}
+
 #[derive(Error, Debug)]
 pub enum KvError {
   #[error("conflict")]
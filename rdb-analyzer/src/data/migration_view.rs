@@ -0,0 +1,113 @@
+use std::{collections::BTreeMap, sync::Arc};
+
+use anyhow::Result;
+use thiserror::Error;
+
+use crate::{
+  schema::compile::{CompiledSchema, FieldType, SpecializedType},
+  storage_plan::StoragePlan,
+};
+
+/// Prefix of the exports of the old schema in a migration view.
+pub const OLD_EXPORT_PREFIX: &str = "old_";
+
+/// Prefix of the types of the old schema in a migration view. Not a valid identifier, so it
+/// cannot collide with the types of the new schema.
+const OLD_TYPE_PREFIX: &str = "old.";
+
+#[derive(Error, Debug)]
+pub enum MigrationViewError {
+  #[error("export `{0}` of the new schema conflicts with an export of the old schema")]
+  ExportNameConflict(String),
+
+  #[error("the old and new storage plans have different aliases for the same storage key")]
+  KeyAliasConflict,
+}
+
+/// Builds a schema and a storage plan that see the data of a namespace through both the old and
+/// the new schema of a migration.
+///
+/// The exports of the new schema keep their names, and each export `x` of the old schema is
+/// available as `old_x`. Types of the old schema are renamed so that they do not collide with
+/// types of the new schema.
+pub fn build_migration_view(
+  old_schema: &CompiledSchema,
+  old_plan: &StoragePlan,
+  new_schema: &CompiledSchema,
+  new_plan: &StoragePlan,
+) -> Result<(CompiledSchema, StoragePlan)> {
+  let mut schema = new_schema.clone();
+  let mut plan = new_plan.clone();
+
+  for (name, ty) in &old_schema.types {
+    let renamed = rename_type(name);
+    schema.types.insert(
+      renamed.clone(),
+      SpecializedType {
+        name: renamed,
+        fields: ty
+          .fields
+          .iter()
+          .map(|(k, (ty, annotations))| (k.clone(), (rename_field_type(ty), annotations.clone())))
+          .collect(),
+        ttl: ty.ttl.clone(),
+      },
+    );
+  }
+
+  for (name, ty) in &old_schema.exports {
+    let renamed: Arc<str> = Arc::from(format!("{}{}", OLD_EXPORT_PREFIX, name));
+    if schema.exports.contains_key(&renamed) {
+      return Err(MigrationViewError::ExportNameConflict(renamed.to_string()).into());
+    }
+    schema
+      .exports
+      .insert(renamed.clone(), rename_field_type(ty));
+    if let Some(node) = old_plan.nodes.get(name) {
+      plan.nodes.insert(renamed, node.clone());
+    }
+  }
+
+  // Old exports may use the shared layouts of the old plan.
+  for (name, node) in &old_plan.shared_layouts {
+    plan.shared_layouts.insert(rename_type(name), node.clone());
+  }
+
+  let mut keys_by_alias = plan
+    .key_aliases
+    .iter()
+    .map(|(k, v)| (*v, *k))
+    .collect::<BTreeMap<_, _>>();
+  for (key, alias) in &old_plan.key_aliases {
+    if plan
+      .key_aliases
+      .get(key)
+      .map(|x| x != alias)
+      .unwrap_or(false)
+      || keys_by_alias.get(alias).map(|x| x != key).unwrap_or(false)
+    {
+      return Err(MigrationViewError::KeyAliasConflict.into());
+    }
+    plan.key_aliases.insert(*key, *alias);
+    keys_by_alias.insert(*alias, *key);
+  }
+
+  Ok((schema, plan))
+}
+
+fn rename_type(name: &str) -> Arc<str> {
+  Arc::from(format!("{}{}", OLD_TYPE_PREFIX, name))
+}
+
+fn rename_field_type(ty: &FieldType) -> FieldType {
+  match ty {
+    FieldType::Primitive(x) => FieldType::Primitive(*x),
+    FieldType::Table(x) => FieldType::Table(rename_type(x)),
+    FieldType::Set(x) => FieldType::Set(Box::new(rename_field_type(x))),
+    FieldType::List(x) => FieldType::List(Box::new(rename_field_type(x))),
+    FieldType::Map(k, v) => FieldType::Map(
+      Box::new(rename_field_type(k)),
+      Box::new(rename_field_type(v)),
+    ),
+  }
+}
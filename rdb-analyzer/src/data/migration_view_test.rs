@@ -0,0 +1,159 @@
+use std::sync::Arc;
+
+use bumpalo::Bump;
+
+use crate::{
+  data::treewalker::{
+    asm::codegen::compile_twscript,
+    exec::{generate_root_map, Executor},
+    typeck::GlobalTyckContext,
+    vm::TwVm,
+    vm_value::VmValue,
+  },
+  schema::{
+    compile::{compile, CompiledSchema},
+    grammar::parse,
+  },
+  storage_plan::{planner::generate_plan_for_schema, StoragePlan},
+  test_util::create_kv,
+};
+
+use super::{kv::KeyValueStore, migration_view::build_migration_view, value::PrimitiveValue};
+
+fn compile_schema(text: &str) -> CompiledSchema {
+  let alloc = Bump::new();
+  let ast = parse(&alloc, text).unwrap();
+  compile(&ast).unwrap()
+}
+
+async fn run(
+  schema: &CompiledSchema,
+  plan: &StoragePlan,
+  kv: &dyn KeyValueStore,
+  code: &str,
+) -> Option<Arc<VmValue<'static>>> {
+  let script = compile_twscript(code).unwrap();
+  let vm = TwVm::new(schema, plan, &script).unwrap();
+  let type_info = GlobalTyckContext::new(&vm).unwrap().typeck().unwrap();
+  let mut executor = Executor::new(&vm, kv, &type_info);
+  executor
+    .run_graph(0, &[Arc::new(generate_root_map(schema, plan).unwrap())])
+    .await
+    .unwrap()
+    .map(|x| match &*x {
+      VmValue::Primitive(x) => Arc::new(VmValue::Primitive(x.clone())),
+      x => panic!("unexpected output: {:?}", x),
+    })
+}
+
+#[tokio::test]
+async fn migrate_through_view() {
+  let _ = pretty_env_logger::try_init();
+  let old_schema = compile_schema(
+    r#"
+  type Config {
+    name: string,
+  }
+  type Item {
+    @primary
+    id: string,
+    name: string,
+  }
+  export Config config;
+  export set<Item> items;
+  "#,
+  );
+  let new_schema = compile_schema(
+    r#"
+  type Config {
+    title: string,
+  }
+  type Item {
+    @primary
+    id: string,
+    first: string,
+  }
+  export Config config;
+  export set<Item> items;
+  "#,
+  );
+  let old_plan =
+    generate_plan_for_schema(&Default::default(), &Default::default(), &old_schema).unwrap();
+  let new_plan = generate_plan_for_schema(&old_plan, &old_schema, &new_schema).unwrap();
+  let kv = create_kv();
+
+  run(
+    &old_schema,
+    &old_plan,
+    &*kv,
+    r#"
+    graph main(root: schema) {
+      t_insert(name) root.config "cfg";
+      s_insert root.items $ build_table(Item) $ m_insert(id) "a" $ m_insert(name) "x" create_map;
+    }
+    "#,
+  )
+  .await;
+
+  let (schema, plan) =
+    build_migration_view(&old_schema, &old_plan, &new_schema, &new_plan).unwrap();
+  run(
+    &schema,
+    &plan,
+    &*kv,
+    r#"
+    graph main(root: schema) {
+      t_insert(title) root.config root.old_config.name;
+      s_insert root.items $ build_table(Item)
+        $ m_insert(id) "a"
+        $ m_insert(first) (point_get root.old_items "a").name
+        create_map;
+    }
+    "#,
+  )
+  .await;
+
+  let output = run(
+    &new_schema,
+    &new_plan,
+    &*kv,
+    r#"
+    graph main(root: schema): string {
+      return root.config.title + " " + (point_get root.items "a").first;
+    }
+    "#,
+  )
+  .await
+  .unwrap();
+  assert_eq!(
+    *output,
+    VmValue::Primitive(PrimitiveValue::String("cfg x".into()))
+  );
+}
+
+#[test]
+fn export_name_conflict() {
+  let old_schema = compile_schema(
+    r#"
+  type Item {
+    @primary
+    id: string,
+  }
+  export set<Item> items;
+  "#,
+  );
+  let new_schema = compile_schema(
+    r#"
+  type Item {
+    @primary
+    id: string,
+  }
+  export set<Item> items;
+  export set<Item> old_items;
+  "#,
+  );
+  let old_plan =
+    generate_plan_for_schema(&Default::default(), &Default::default(), &old_schema).unwrap();
+  let new_plan = generate_plan_for_schema(&old_plan, &old_schema, &new_schema).unwrap();
+  assert!(build_migration_view(&old_schema, &old_plan, &new_schema, &new_plan).is_err());
+}
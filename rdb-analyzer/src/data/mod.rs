@@ -1,7 +1,13 @@
 pub mod kv;
+pub mod migration_view;
+pub mod mount;
 pub mod pathwalker;
 pub mod treewalker;
 pub mod value;
 
+#[cfg(test)]
+mod migration_view_test;
+#[cfg(test)]
+mod mount_test;
 #[cfg(test)]
 mod pathwalker_test;
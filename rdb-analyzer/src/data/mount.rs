@@ -0,0 +1,356 @@
+//! Read-only mounts of other namespaces.
+//!
+//! A query may read the data of other namespaces alongside its own. Each mounted namespace
+//! appears in the root map as `__ns.<mount name>`, with the exports of its schema as children.
+//! Its types are copied into the schema of the query as `<mount name>::<type name>`, and its
+//! storage plan is kept in `StoragePlan::mounts`.
+//!
+//! Keys of the i-th mounted namespace (in mount name order) are generated with the prefix
+//! `[0xff, i]`, which no key of the namespace itself can start with (see
+//! `planner::assign_key_aliases`). `MountedKvStore` routes these keys to the store of the mounted
+//! namespace with the prefix stripped, and rejects writes to them.
+
+use std::{collections::BTreeMap, sync::Arc};
+
+use anyhow::Result;
+use async_trait::async_trait;
+use futures::lock::Mutex;
+use thiserror::Error;
+
+use crate::{
+  schema::compile::{CompiledSchema, FieldType, SpecializedType},
+  storage_plan::StoragePlan,
+};
+
+use super::kv::{KeyValueStore, KvError, KvIterator, KvKeyIterator, KvTransaction, ScanOptions};
+
+/// Leading byte of every key in a mounted namespace.
+pub const MOUNT_KEY_MARKER: u8 = 0xff;
+
+/// Name of the root map field that mounted namespaces go under.
+pub const MOUNT_ROOT_FIELD: &str = "__ns";
+
+const MAX_MOUNTS: usize = 256;
+
+#[derive(Error, Debug)]
+pub enum MountError {
+  #[error("namespace `{0}` is already mounted")]
+  DuplicateMount(String),
+
+  #[error("too many mounted namespaces")]
+  TooManyMounts,
+
+  #[error("the export `__ns` is reserved for mounted namespaces")]
+  ReservedExportName,
+
+  #[error("missing storage plan for mounted namespace `{0}`")]
+  MissingPlan(String),
+
+  #[error("no namespace is mounted at index {0}")]
+  NotMounted(u8),
+
+  #[error("mounted namespaces are read-only")]
+  ReadOnly,
+
+  #[error("cannot scan across mounted namespaces")]
+  ScanAcrossMounts,
+}
+
+/// The key prefix of the mounted namespace at `index`.
+pub fn mount_key_prefix(index: usize) -> Result<[u8; 2]> {
+  if index >= MAX_MOUNTS {
+    return Err(MountError::TooManyMounts.into());
+  }
+  Ok([MOUNT_KEY_MARKER, index as u8])
+}
+
+/// Mounts the namespace with schema `mounted_schema` and plan `mounted_plan` into `schema` and
+/// `plan` as `name`.
+///
+/// The stores passed to `MountedKvStore::new` must then be in the order of `schema.mounts`.
+pub fn mount_namespace(
+  schema: &mut CompiledSchema,
+  plan: &mut StoragePlan,
+  name: &str,
+  mounted_schema: &CompiledSchema,
+  mounted_plan: &StoragePlan,
+) -> Result<()> {
+  if schema.exports.contains_key(MOUNT_ROOT_FIELD) {
+    return Err(MountError::ReservedExportName.into());
+  }
+  if schema.mounts.contains_key(name) {
+    return Err(MountError::DuplicateMount(name.to_string()).into());
+  }
+  if schema.mounts.len() >= MAX_MOUNTS {
+    return Err(MountError::TooManyMounts.into());
+  }
+
+  for (type_name, ty) in &mounted_schema.types {
+    let mounted_name = mounted_type_name(name, type_name);
+    schema.types.insert(
+      mounted_name.clone(),
+      SpecializedType {
+        name: mounted_name,
+        fields: ty
+          .fields
+          .iter()
+          .map(|(k, (field_ty, annotations))| {
+            (
+              k.clone(),
+              (mounted_field_type(name, field_ty), annotations.clone()),
+            )
+          })
+          .collect(),
+        ttl: ty.ttl.clone(),
+      },
+    );
+  }
+
+  let name: Arc<str> = Arc::from(name);
+  schema.mounts.insert(
+    name.clone(),
+    mounted_schema
+      .exports
+      .iter()
+      .map(|(k, v)| (k.clone(), mounted_field_type(&name, v)))
+      .collect::<BTreeMap<_, _>>(),
+  );
+  plan.mounts.insert(name, mounted_plan.clone());
+  Ok(())
+}
+
+fn mounted_type_name(mount_name: &str, type_name: &str) -> Arc<str> {
+  Arc::from(format!("{}::{}", mount_name, type_name).as_str())
+}
+
+fn mounted_field_type(mount_name: &str, ty: &FieldType) -> FieldType {
+  match ty {
+    FieldType::Table(x) => FieldType::Table(mounted_type_name(mount_name, x)),
+    FieldType::Primitive(x) => FieldType::Primitive(*x),
+    FieldType::Set(x) => FieldType::Set(Box::new(mounted_field_type(mount_name, x))),
+    FieldType::List(x) => FieldType::List(Box::new(mounted_field_type(mount_name, x))),
+    FieldType::Map(k, v) => FieldType::Map(
+      Box::new(mounted_field_type(mount_name, k)),
+      Box::new(mounted_field_type(mount_name, v)),
+    ),
+  }
+}
+
+/// A store of a namespace with other namespaces mounted into it read-only.
+pub struct MountedKvStore {
+  own: Box<dyn KeyValueStore>,
+  mounts: Arc<Vec<Box<dyn KeyValueStore>>>,
+}
+
+impl MountedKvStore {
+  pub fn new(own: Box<dyn KeyValueStore>, mounts: Vec<Box<dyn KeyValueStore>>) -> Self {
+    Self {
+      own,
+      mounts: Arc::new(mounts),
+    }
+  }
+}
+
+struct MountedKvTransaction {
+  own: Box<dyn KvTransaction>,
+  mounts: Arc<Vec<Box<dyn KeyValueStore>>>,
+
+  /// Read-only transactions on mounted namespaces, begun on first use.
+  mount_txns: Vec<Mutex<Option<Arc<dyn KvTransaction>>>>,
+}
+
+struct MountedKvKeyIterator {
+  inner: Box<dyn KvKeyIterator>,
+  prefix: [u8; 2],
+}
+
+struct MountedKvIterator {
+  inner: Box<dyn KvIterator>,
+  prefix: [u8; 2],
+}
+
+#[async_trait]
+impl KeyValueStore for MountedKvStore {
+  async fn begin_transaction(&self) -> Result<Box<dyn KvTransaction>> {
+    Ok(Box::new(MountedKvTransaction::new(
+      self.own.begin_transaction().await?,
+      self.mounts.clone(),
+    )))
+  }
+
+  async fn begin_read_only_transaction(&self) -> Result<Box<dyn KvTransaction>> {
+    Ok(Box::new(MountedKvTransaction::new(
+      self.own.begin_read_only_transaction().await?,
+      self.mounts.clone(),
+    )))
+  }
+}
+
+impl MountedKvTransaction {
+  fn new(own: Box<dyn KvTransaction>, mounts: Arc<Vec<Box<dyn KeyValueStore>>>) -> Self {
+    Self {
+      own,
+      mount_txns: mounts.iter().map(|_| Mutex::new(None)).collect(),
+      mounts,
+    }
+  }
+
+  /// If `key` is in a mounted namespace, returns the index of the namespace and the rest of the
+  /// key.
+  fn route<'k>(&self, key: &'k [u8]) -> Result<Option<(usize, &'k [u8])>> {
+    match key {
+      [MOUNT_KEY_MARKER, index, rest @ ..] => {
+        if usize::from(*index) >= self.mounts.len() {
+          return Err(MountError::NotMounted(*index).into());
+        }
+        Ok(Some((usize::from(*index), rest)))
+      }
+      _ => Ok(None),
+    }
+  }
+
+  fn check_writable(&self, key: &[u8]) -> Result<()> {
+    if key.first() == Some(&MOUNT_KEY_MARKER) {
+      Err(MountError::ReadOnly.into())
+    } else {
+      Ok(())
+    }
+  }
+
+  async fn mount_txn(&self, index: usize) -> Result<Arc<dyn KvTransaction>> {
+    let mut txn = self.mount_txns[index].lock().await;
+    if let Some(x) = &*txn {
+      return Ok(x.clone());
+    }
+    let x: Arc<dyn KvTransaction> =
+      Arc::from(self.mounts[index].begin_read_only_transaction().await?);
+    *txn = Some(x.clone());
+    Ok(x)
+  }
+}
+
+#[async_trait]
+impl KvTransaction for MountedKvTransaction {
+  async fn get(&self, key: &[u8]) -> Result<Option<Vec<u8>>> {
+    match self.route(key)? {
+      Some((index, key)) => self.mount_txn(index).await?.get(key).await,
+      None => self.own.get(key).await,
+    }
+  }
+
+  async fn put(&self, key: &[u8], value: &[u8]) -> Result<()> {
+    self.check_writable(key)?;
+    self.own.put(key, value).await
+  }
+
+  async fn put_many(&self, entries: &[(Vec<u8>, Vec<u8>)]) -> Result<()> {
+    for (key, _) in entries {
+      self.check_writable(key)?;
+    }
+    self.own.put_many(entries).await
+  }
+
+  async fn delete(&self, key: &[u8]) -> Result<()> {
+    self.check_writable(key)?;
+    self.own.delete(key).await
+  }
+
+  async fn delete_range(&self, start: &[u8], end: &[u8]) -> Result<()> {
+    self.check_writable(start)?;
+    self.check_writable(end)?;
+    self.own.delete_range(start, end).await
+  }
+
+  async fn atomic_add(&self, key: &[u8], delta: i64) -> Result<()> {
+    self.check_writable(key)?;
+    self.own.atomic_add(key, delta).await
+  }
+
+  async fn scan_keys(&self, start: &[u8], end: &[u8]) -> Result<Box<dyn KvKeyIterator>> {
+    match self.route(start)? {
+      Some((index, start)) => {
+        let prefix = [MOUNT_KEY_MARKER, index as u8];
+        let end = end
+          .strip_prefix(&prefix[..])
+          .ok_or_else(|| MountError::ScanAcrossMounts)?;
+        Ok(Box::new(MountedKvKeyIterator {
+          inner: self.mount_txn(index).await?.scan_keys(start, end).await?,
+          prefix,
+        }))
+      }
+      None => {
+        if end.first() == Some(&MOUNT_KEY_MARKER) {
+          return Err(MountError::ScanAcrossMounts.into());
+        }
+        self.own.scan_keys(start, end).await
+      }
+    }
+  }
+
+  async fn scan(
+    &self,
+    start: &[u8],
+    end: &[u8],
+    options: ScanOptions,
+  ) -> Result<Box<dyn KvIterator>> {
+    match self.route(start)? {
+      Some((index, start)) => {
+        let prefix = [MOUNT_KEY_MARKER, index as u8];
+        let end = end
+          .strip_prefix(&prefix[..])
+          .ok_or(MountError::ScanAcrossMounts)?;
+        Ok(Box::new(MountedKvIterator {
+          inner: self
+            .mount_txn(index)
+            .await?
+            .scan(start, end, options)
+            .await?,
+          prefix,
+        }))
+      }
+      None => {
+        if end.first() == Some(&MOUNT_KEY_MARKER) {
+          return Err(MountError::ScanAcrossMounts.into());
+        }
+        self.own.scan(start, end, options).await
+      }
+    }
+  }
+
+  async fn add_read_conflict_key(&self, key: &[u8]) -> Result<()> {
+    match self.route(key)? {
+      // Transactions on mounted namespaces never commit, so they have no conflicts.
+      Some(_) => Ok(()),
+      None => self.own.add_read_conflict_key(key).await,
+    }
+  }
+
+  async fn commit(self: Box<Self>) -> Result<(), KvError> {
+    // Transactions on mounted namespaces are read-only and are simply dropped.
+    self.own.commit().await
+  }
+}
+
+#[async_trait]
+impl KvKeyIterator for MountedKvKeyIterator {
+  async fn next(&mut self) -> Result<Option<Vec<u8>>> {
+    Ok(self.inner.next().await?.map(|x| {
+      let mut key = Vec::with_capacity(self.prefix.len() + x.len());
+      key.extend_from_slice(&self.prefix);
+      key.extend_from_slice(&x);
+      key
+    }))
+  }
+}
+
+#[async_trait]
+impl KvIterator for MountedKvIterator {
+  async fn next(&mut self) -> Result<Option<(Vec<u8>, Vec<u8>)>> {
+    Ok(self.inner.next().await?.map(|(x, value)| {
+      let mut key = Vec::with_capacity(self.prefix.len() + x.len());
+      key.extend_from_slice(&self.prefix);
+      key.extend_from_slice(&x);
+      (key, value)
+    }))
+  }
+}
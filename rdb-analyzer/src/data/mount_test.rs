@@ -0,0 +1,358 @@
+use std::sync::Arc;
+
+use anyhow::Result;
+use bumpalo::Bump;
+
+use crate::{
+  data::{
+    kv::{KeyValueStore, KvIterator, ScanOptions},
+    mount::{mount_namespace, MountError, MountedKvStore},
+    treewalker::{
+      asm::codegen::compile_twscript,
+      bytecode::TwScript,
+      exec::{generate_root_map, Executor},
+      serialize::SerializedVmValue,
+      typeck::GlobalTyckContext,
+      vm::TwVm,
+    },
+  },
+  schema::{
+    compile::{compile, CompiledSchema},
+    grammar::parse,
+  },
+  storage_plan::{planner::generate_plan_for_schema, StoragePlan},
+  test_util::create_kv,
+};
+
+const SHARED_SCHEMA: &str = r#"
+type Country {
+  @primary
+  code: string,
+  name: string,
+}
+type Config {
+  greeting: string,
+}
+export set<Country> countries;
+export Config config;
+"#;
+
+const APP_SCHEMA: &str = r#"
+type User {
+  @primary
+  id: string,
+  country: string,
+}
+export set<User> users;
+"#;
+
+fn schema_and_plan(text: &str) -> (CompiledSchema, StoragePlan) {
+  let alloc = Bump::new();
+  let schema = compile(&parse(&alloc, text).unwrap()).unwrap();
+  let plan = generate_plan_for_schema(&Default::default(), &Default::default(), &schema).unwrap();
+  (schema, plan)
+}
+
+async fn run_script(
+  schema: &CompiledSchema,
+  plan: &StoragePlan,
+  kv: &dyn KeyValueStore,
+  script: &TwScript,
+) -> Result<Option<String>> {
+  let vm = TwVm::new(schema, plan, script)?;
+  let type_info = GlobalTyckContext::new(&vm)?.typeck()?;
+  let output = Executor::new(&vm, kv, &type_info)
+    .run_graph(0, &[Arc::new(generate_root_map(schema, plan)?)])
+    .await?;
+  Ok(output.map(|x| {
+    format!(
+      "{:?}",
+      SerializedVmValue::encode(&*x, &Default::default()).unwrap()
+    )
+  }))
+}
+
+#[tokio::test]
+async fn read_mounted_namespace() {
+  let _ = pretty_env_logger::try_init();
+  let (shared_schema, shared_plan) = schema_and_plan(SHARED_SCHEMA);
+  let (mut schema, mut plan) = schema_and_plan(APP_SCHEMA);
+  mount_namespace(
+    &mut schema,
+    &mut plan,
+    "shared",
+    &shared_schema,
+    &shared_plan,
+  )
+  .unwrap();
+  assert!(schema.types.contains_key("shared::Country<>"));
+
+  let shared_kv = create_kv();
+  let script = compile_twscript(
+    r#"
+    graph main(root: schema) {
+      s_insert root.countries $ build_table(Country) $
+        m_insert(code) "fr" $ m_insert(name) "France" create_map;
+      s_insert root.countries $ build_table(Country) $
+        m_insert(code) "jp" $ m_insert(name) "Japan" create_map;
+      t_insert(greeting) root.config "hello";
+    }
+    "#,
+  )
+  .unwrap();
+  run_script(&shared_schema, &shared_plan, &*shared_kv, &script)
+    .await
+    .unwrap();
+
+  let kv = MountedKvStore::new(create_kv(), vec![shared_kv]);
+  let script = compile_twscript(
+    r#"
+    mount "shared";
+    graph main(root: schema): map { greeting: string, country: string, count: int64 } {
+      shared = root.__ns("shared");
+      s_insert root.users $ build_table(User) $
+        m_insert(id) "u1" $ m_insert(country) "jp" create_map;
+      return m_insert(greeting) shared.config.greeting $
+        m_insert(country) (point_get shared.countries "jp").name $
+        m_insert(count) (s_count shared.countries) create_map;
+    }
+    "#,
+  )
+  .unwrap();
+  assert_eq!(script.mounts, vec!["shared".to_string()]);
+  assert_eq!(
+    run_script(&schema, &plan, &kv, &script)
+      .await
+      .unwrap()
+      .unwrap(),
+    r#"Tagged(M({"count": String("2"), "country": String("Japan"), "greeting": String("hello")}))"#
+  );
+
+  // The namespace's own data is writable as usual.
+  let script = compile_twscript(
+    r#"
+    graph main(root: schema): string {
+      return (point_get root.users "u1").country;
+    }
+    "#,
+  )
+  .unwrap();
+  assert_eq!(
+    run_script(&schema, &plan, &kv, &script)
+      .await
+      .unwrap()
+      .unwrap(),
+    r#"String("jp")"#
+  );
+
+  // Mounted namespaces are read-only.
+  let script = compile_twscript(
+    r#"
+    graph main(root: schema) {
+      t_insert(greeting) root.__ns("shared").config "bye";
+    }
+    "#,
+  )
+  .unwrap();
+  let e = run_script(&schema, &plan, &kv, &script).await.unwrap_err();
+  assert!(matches!(
+    e.downcast_ref::<MountError>(),
+    Some(MountError::ReadOnly)
+  ));
+}
+
+#[tokio::test]
+async fn mount_errors() {
+  let (shared_schema, shared_plan) = schema_and_plan(SHARED_SCHEMA);
+  let (mut schema, mut plan) = schema_and_plan(APP_SCHEMA);
+  mount_namespace(
+    &mut schema,
+    &mut plan,
+    "shared",
+    &shared_schema,
+    &shared_plan,
+  )
+  .unwrap();
+  let e = mount_namespace(
+    &mut schema,
+    &mut plan,
+    "shared",
+    &shared_schema,
+    &shared_plan,
+  )
+  .unwrap_err();
+  assert!(matches!(
+    e.downcast_ref::<MountError>(),
+    Some(MountError::DuplicateMount(_))
+  ));
+
+  let (mut schema, mut plan) = schema_and_plan("type A { x: int64, } export A __ns;");
+  let e = mount_namespace(
+    &mut schema,
+    &mut plan,
+    "shared",
+    &shared_schema,
+    &shared_plan,
+  )
+  .unwrap_err();
+  assert!(matches!(
+    e.downcast_ref::<MountError>(),
+    Some(MountError::ReservedExportName)
+  ));
+
+  // Scripts reading a namespace that is not mounted fail type checking.
+  let (schema, plan) = schema_and_plan(APP_SCHEMA);
+  let script = compile_twscript(
+    r#"
+    graph main(root: schema): string {
+      return root.__ns("shared").config.greeting;
+    }
+    "#,
+  )
+  .unwrap();
+  let kv = create_kv();
+  assert!(run_script(&schema, &plan, &*kv, &script).await.is_err());
+}
+
+#[tokio::test]
+async fn mounted_kv_routing() {
+  let shared_kv = create_kv();
+  let txn = shared_kv.begin_transaction().await.unwrap();
+  txn.put(b"a1", b"x").await.unwrap();
+  txn.put(b"a2", b"y").await.unwrap();
+  txn.commit().await.unwrap();
+
+  let kv = MountedKvStore::new(create_kv(), vec![shared_kv]);
+  let txn = kv.begin_transaction().await.unwrap();
+  txn.put(b"a1", b"own").await.unwrap();
+  assert_eq!(
+    txn.get(b"\xff\x00a1").await.unwrap().as_deref(),
+    Some(&b"x"[..])
+  );
+
+  let mut it = txn.scan_keys(b"\xff\x00a", b"\xff\x00b").await.unwrap();
+  let mut keys = vec![];
+  while let Some(x) = it.next().await.unwrap() {
+    keys.push(x);
+  }
+  assert_eq!(keys, vec![b"\xff\x00a1".to_vec(), b"\xff\x00a2".to_vec()]);
+
+  for e in vec![
+    txn.put(b"\xff\x00a1", b"z").await.unwrap_err(),
+    txn.delete(b"\xff\x00a1").await.unwrap_err(),
+    txn.atomic_add(b"\xff\x00a3", 1).await.unwrap_err(),
+  ] {
+    assert!(matches!(
+      e.downcast_ref::<MountError>(),
+      Some(MountError::ReadOnly)
+    ));
+  }
+  let e = txn.get(b"\xff\x01a1").await.unwrap_err();
+  assert!(matches!(
+    e.downcast_ref::<MountError>(),
+    Some(MountError::NotMounted(1))
+  ));
+  let e = txn
+    .scan_keys(b"\xff\x00a", b"\xff\x01")
+    .await
+    .err()
+    .unwrap();
+  assert!(matches!(
+    e.downcast_ref::<MountError>(),
+    Some(MountError::ScanAcrossMounts)
+  ));
+  txn.commit().await.unwrap();
+}
+
+#[tokio::test]
+async fn mounted_kv_scan() {
+  async fn collect(mut it: Box<dyn KvIterator>) -> Vec<(Vec<u8>, Vec<u8>)> {
+    let mut entries = vec![];
+    while let Some(x) = it.next().await.unwrap() {
+      entries.push(x);
+    }
+    entries
+  }
+
+  let shared_kv = create_kv();
+  let txn = shared_kv.begin_transaction().await.unwrap();
+  for i in 1..=4u8 {
+    txn.put(&[b'a', b'0' + i], &[i]).await.unwrap();
+  }
+  txn.commit().await.unwrap();
+
+  let kv = MountedKvStore::new(create_kv(), vec![shared_kv]);
+  let txn = kv.begin_transaction().await.unwrap();
+  txn.put(b"b1", b"own").await.unwrap();
+  txn.commit().await.unwrap();
+
+  let txn = kv.begin_transaction().await.unwrap();
+  assert_eq!(
+    collect(
+      txn
+        .scan(b"\xff\x00a", b"\xff\x00b", ScanOptions::default())
+        .await
+        .unwrap()
+    )
+    .await,
+    (1..=4u8)
+      .map(|i| (vec![0xff, 0x00, b'a', b'0' + i], vec![i]))
+      .collect::<Vec<_>>()
+  );
+  assert_eq!(
+    collect(
+      txn
+        .scan(
+          b"\xff\x00a",
+          b"\xff\x00b",
+          ScanOptions {
+            reverse: true,
+            limit: Some(2),
+            snapshot: false,
+          }
+        )
+        .await
+        .unwrap()
+    )
+    .await,
+    vec![
+      (b"\xff\x00a4".to_vec(), vec![4]),
+      (b"\xff\x00a3".to_vec(), vec![3]),
+    ]
+  );
+  assert!(collect(
+    txn
+      .scan(
+        b"\xff\x00a",
+        b"\xff\x00b",
+        ScanOptions {
+          reverse: false,
+          limit: Some(0),
+          snapshot: false,
+        }
+      )
+      .await
+      .unwrap()
+  )
+  .await
+  .is_empty());
+  assert_eq!(
+    collect(
+      txn
+        .scan(b"", b"\xfe", ScanOptions::default())
+        .await
+        .unwrap()
+    )
+    .await,
+    vec![(b"b1".to_vec(), b"own".to_vec())]
+  );
+  let e = txn
+    .scan(b"\xff\x00a", b"\xff\x01", ScanOptions::default())
+    .await
+    .err()
+    .unwrap();
+  assert!(matches!(
+    e.downcast_ref::<MountError>(),
+    Some(MountError::ScanAcrossMounts)
+  ));
+}
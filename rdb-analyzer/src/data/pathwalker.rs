@@ -1,8 +1,9 @@
-use std::{ops::Deref, sync::Arc};
+use std::{collections::BTreeMap, ops::Deref, sync::Arc};
 
 use anyhow::Result;
+use once_cell::sync::OnceCell;
 
-use crate::storage_plan::{StorageNode, StoragePlan};
+use crate::storage_plan::{KeyAlias, StorageKey, StorageNode, StoragePlan};
 use thiserror::Error;
 
 use super::value::PrimitiveValue;
@@ -21,12 +22,30 @@ pub enum PathWalkerError {
   #[error("enter_set called on a non-set node")]
   NotSet,
 
+  #[error("list operation on a non-list node")]
+  NotList,
+
+  #[error("map operation on a non-map node")]
+  NotMap,
+
   #[error("path too deep")]
   PathTooDeep,
+
+  #[error("missing alias for storage key `{0}`")]
+  MissingKeyAlias(String),
 }
 
 const MAX_DEPTH: usize = 64;
 
+/// A segment of the path from an export to a node, as returned by `PathWalker::path`.
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub enum PathSegment {
+  Field(String),
+
+  /// A set member, identified by its primary key.
+  Member(PrimitiveValue),
+}
+
 #[derive(Debug)]
 pub struct PathWalker<'a> {
   /// The "actual" storage node, with subspace references resolved.
@@ -38,6 +57,10 @@ pub struct PathWalker<'a> {
   /// Link to the parent node.
   link: Option<Arc<PathWalker<'a>>>,
 
+  /// The prefix of the keys of this node's children, computed on first use. Shared with the
+  /// children of flattened nodes.
+  child_prefix: OnceCell<Arc<[u8]>>,
+
   /// Current nesting depth.
   depth: usize,
 
@@ -57,12 +80,25 @@ pub struct PathWalker<'a> {
   is_intermediate: bool,
 
   path_segment: Option<&'a str>,
+
+  /// Key aliases of the storage plan. Empty if aliasing is disabled.
+  key_aliases: &'a BTreeMap<StorageKey, KeyAlias>,
+
+  /// Shared layouts of the storage plan, for subspace references that do not point to an
+  /// ancestor.
+  shared_layouts: &'a BTreeMap<Arc<str>, StorageNode>,
+
+  /// Prefix of every generated key, if this path is in a mounted namespace.
+  ///
+  /// See `from_mounted_export`.
+  mount_prefix: Option<Arc<[u8]>>,
 }
 
 #[derive(Clone, Debug)]
 enum KeyCow<'a> {
   Borrowed(&'a [u8]),
   Owned(Arc<[u8]>),
+  Alias([u8; 2]),
 }
 
 impl<'a> Deref for KeyCow<'a> {
@@ -71,6 +107,7 @@ impl<'a> Deref for KeyCow<'a> {
     match self {
       KeyCow::Borrowed(x) => *x,
       KeyCow::Owned(x) => &**x,
+      KeyCow::Alias(x) => &x[..],
     }
   }
 }
@@ -84,14 +121,45 @@ impl<'a> PathWalker<'a> {
 
     Ok(Arc::new(Self {
       node: export,
-      key: KeyCow::Borrowed(&export.key),
+      key: key_component(&plan.key_aliases, &export.key)?,
       link: None,
+      child_prefix: OnceCell::new(),
       depth: 1,
       should_flatten: export.flattened,
       is_intermediate: false,
       path_segment: Some(&**export_name),
+      key_aliases: &plan.key_aliases,
+      shared_layouts: &plan.shared_layouts,
+      mount_prefix: None,
     }))
   }
+
+  /// Like `from_export`, but for an export of a namespace mounted into the current one. Every key
+  /// generated from the returned walker starts with `mount_prefix`.
+  pub fn from_mounted_export(
+    plan: &'a StoragePlan,
+    export_name: &str,
+    mount_prefix: &[u8],
+  ) -> Result<Arc<Self>> {
+    let mut walker = Self::from_export(plan, export_name)?;
+    Arc::get_mut(&mut walker).unwrap().mount_prefix = Some(Arc::from(mount_prefix));
+    Ok(walker)
+  }
+}
+
+/// The key component for the storage key `key`: its alias if aliasing is enabled, or the key
+/// itself otherwise.
+fn key_component<'a>(
+  key_aliases: &BTreeMap<StorageKey, KeyAlias>,
+  key: &'a StorageKey,
+) -> Result<KeyCow<'a>> {
+  if key_aliases.is_empty() {
+    return Ok(KeyCow::Borrowed(key));
+  }
+  key_aliases
+    .get(key)
+    .map(|x| KeyCow::Alias(x.to_be_bytes()))
+    .ok_or_else(|| PathWalkerError::MissingKeyAlias(base64::encode(key)).into())
 }
 
 impl<'a> PartialEq for PathWalker<'a> {
@@ -115,10 +183,36 @@ impl<'a> PathWalker<'a> {
       }
       link = x.link.as_ref();
     }
+    if let Some(prefix) = &self.mount_prefix {
+      components.push(prefix);
+    }
     components.reverse();
     components
   }
 
+  /// The generated key without the current key component.
+  fn prefix(&self) -> &[u8] {
+    match &self.link {
+      Some(x) => x.child_prefix(),
+      None => self.mount_prefix.as_deref().unwrap_or(&[]),
+    }
+  }
+
+  fn child_prefix(&self) -> &Arc<[u8]> {
+    self.child_prefix.get_or_init(|| match &self.link {
+      Some(x) if self.should_flatten => x.child_prefix().clone(),
+      _ => {
+        let prefix = self.prefix();
+        let mut child_prefix = Vec::with_capacity(prefix.len() + self.key.len());
+        child_prefix.extend_from_slice(prefix);
+        if !self.should_flatten {
+          child_prefix.extend_from_slice(&self.key);
+        }
+        Arc::from(child_prefix)
+      }
+    })
+  }
+
   fn check_and_add_depth(&self) -> Result<usize> {
     if self.depth >= MAX_DEPTH {
       Err(PathWalkerError::PathTooDeep.into())
@@ -160,18 +254,43 @@ impl<'a> PathWalker<'a> {
     result
   }
 
+  /// The path from the export to this node, with set members identified by primary key.
+  pub fn path(&self) -> Vec<PathSegment> {
+    let mut link = Some(self);
+    let mut result = vec![];
+    while let Some(x) = link {
+      if x.is_intermediate {
+        let primary_key = x
+          .key
+          .strip_prefix(&[0x00u8][..])
+          .and_then(|x| x.strip_suffix(&[0x00u8][..]))
+          .and_then(PrimitiveValue::deserialize_from_key_component)
+          .expect("inconsistency: bad set member key");
+        result.push(PathSegment::Member(primary_key));
+      } else if let Some(segment) = x.path_segment {
+        result.push(PathSegment::Field(segment.to_string()));
+      }
+      link = x.link.as_ref().map(|x| &**x);
+    }
+    result.reverse();
+    result
+  }
+
   pub fn node(&self) -> &'a StorageNode {
     self.node
   }
 
+  /// Whether the keys of this node's children are generated without this node's own key
+  /// component, i.e. the subtree is not contiguous under `generate_key()`.
+  pub fn is_flattened(&self) -> bool {
+    self.should_flatten
+  }
+
   pub fn generate_key(&self) -> Vec<u8> {
-    let components = self.generate_key_raw();
-    let len = components.iter().fold(0, |a, b| a + b.len());
-    let mut key = Vec::with_capacity(len);
-    for c in components.iter() {
-      key.extend_from_slice(*c);
-    }
-    assert_eq!(key.len(), len);
+    let prefix = self.prefix();
+    let mut key = Vec::with_capacity(prefix.len() + self.key.len());
+    key.extend_from_slice(prefix);
+    key.extend_from_slice(&self.key);
     key
   }
 
@@ -197,35 +316,54 @@ impl<'a> PathWalker<'a> {
       .ok_or_else(|| PathWalkerError::FieldNotFound(field_name.to_string()))?;
 
     if let Some(subspace_reference) = node.subspace_reference {
-      // Walk up the list
+      // Walk up the list, then fall back to the shared layouts.
       let mut me = Some(self);
+      let mut target = None;
       while let Some(link) = me {
         // Here we use `link.node.key` instead of `link.key` to avoid conflicting with set keys.
         if link.node.key == subspace_reference {
-          // Use the referenced node, with our own key.
-          // And do not flatten.
-          return Ok(Arc::new(Self {
-            node: link.node,
-            key: KeyCow::Borrowed(&node.key),
-            link: Some(self.clone()),
-            depth: self.check_and_add_depth()?,
-            should_flatten: false,
-            is_intermediate: false,
-            path_segment: Some(&**field_name),
-          }));
+          target = Some(link.node);
+          break;
         }
         me = link.link.as_ref();
       }
+      let target = target.or_else(|| {
+        self
+          .shared_layouts
+          .values()
+          .find(|x| x.key == subspace_reference)
+      });
+      if let Some(target) = target {
+        // Use the referenced node, with our own key.
+        // And do not flatten.
+        return Ok(Arc::new(Self {
+          node: target,
+          key: key_component(self.key_aliases, &node.key)?,
+          link: Some(self.clone()),
+          child_prefix: OnceCell::new(),
+          depth: self.check_and_add_depth()?,
+          should_flatten: false,
+          is_intermediate: false,
+          path_segment: Some(&**field_name),
+          key_aliases: self.key_aliases,
+          shared_layouts: self.shared_layouts,
+          mount_prefix: self.mount_prefix.clone(),
+        }));
+      }
       return Err(PathWalkerError::ReferenceNodeNotFound.into());
     } else {
       Ok(Arc::new(Self {
         node,
-        key: KeyCow::Borrowed(&node.key),
+        key: key_component(self.key_aliases, &node.key)?,
         link: Some(self.clone()),
+        child_prefix: OnceCell::new(),
         depth: self.check_and_add_depth()?,
         should_flatten: node.flattened,
         is_intermediate: false,
         path_segment: Some(&**field_name),
+        key_aliases: self.key_aliases,
+        shared_layouts: self.shared_layouts,
+        mount_prefix: self.mount_prefix.clone(),
       }))
     }
   }
@@ -254,6 +392,83 @@ impl<'a> PathWalker<'a> {
     Ok(key)
   }
 
+  /// The prefix of index entries for the field `field_name` of members in this set.
+  ///
+  /// An index entry is stored at `prefix + value + 0x00 + primary_key`, with the primary key as
+  /// its value. The value is serialized with `serialize_for_self_delimiting_component`, so that
+  /// entries of different values never share a prefix up to the separator.
+  pub fn set_index_prefix(&self, field_name: &str) -> Result<Vec<u8>> {
+    let set = &**self
+      .node
+      .set
+      .as_ref()
+      .ok_or_else(|| PathWalkerError::NotSet)?;
+    let field = set
+      .children
+      .get(field_name)
+      .ok_or_else(|| PathWalkerError::FieldNotFound(field_name.to_string()))?;
+
+    let mut key = self.generate_key();
+    key.push(0x02u8);
+    key.extend_from_slice(&key_component(self.key_aliases, &field.key)?);
+    Ok(key)
+  }
+
+  /// The key of the member at `index` of this list.
+  ///
+  /// The length of a list is stored at `generate_key()`, and its members at
+  /// `generate_key() + 0x00 + index` with big-endian `u64` indices, so that a scan from
+  /// `list_data_prefix()` reads them in order.
+  pub fn list_element_key(&self, index: u64) -> Result<Vec<u8>> {
+    let mut key = self.list_data_prefix()?;
+    key.extend_from_slice(&index.to_be_bytes());
+    Ok(key)
+  }
+
+  pub fn list_data_prefix(&self) -> Result<Vec<u8>> {
+    if !self.node.list {
+      return Err(PathWalkerError::NotList.into());
+    }
+
+    let mut key = self.generate_key();
+    key.push(0x00u8);
+    Ok(key)
+  }
+
+  /// The key of the entry keyed `key` in this map.
+  ///
+  /// Map entries are stored at `generate_key() + 0x00 + key`, with keys serialized like primary
+  /// keys, so that a scan from `map_data_prefix()` reads them in key order.
+  pub fn map_entry_key(&self, key: &PrimitiveValue) -> Result<Vec<u8>> {
+    let mut out = self.map_data_prefix()?;
+    out.extend_from_slice(&key.serialize_for_key_component());
+    Ok(out)
+  }
+
+  pub fn map_data_prefix(&self) -> Result<Vec<u8>> {
+    if !self.node.map {
+      return Err(PathWalkerError::NotMap.into());
+    }
+
+    let mut key = self.generate_key();
+    key.push(0x00u8);
+    Ok(key)
+  }
+
+  /// If this is a member of a set, returns the set and the raw primary key of this member.
+  pub fn enclosing_set(&self) -> Option<(&Arc<Self>, &[u8])> {
+    let intermediate = self.link.as_ref()?;
+    if !intermediate.is_intermediate {
+      return None;
+    }
+    let set = intermediate.link.as_ref()?;
+    let primary_key = intermediate
+      .key
+      .strip_prefix(&[0x00u8][..])?
+      .strip_suffix(&[0x00u8][..])?;
+    Some((set, primary_key))
+  }
+
   pub fn enter_set_raw(self: &Arc<Self>, primary_key: &[u8]) -> Result<Arc<Self>> {
     let set = &**self
       .node
@@ -263,7 +478,7 @@ impl<'a> PathWalker<'a> {
 
     // 0x00 - data
     // 0x01 - key only
-    // 0x02 - index
+    // 0x02 - index (see `set_index_prefix`)
     let mut dynamic_key_bytes = vec![0x00u8];
     dynamic_key_bytes.extend_from_slice(primary_key);
     dynamic_key_bytes.push(0x00u8);
@@ -275,21 +490,29 @@ impl<'a> PathWalker<'a> {
       node: set,
       key: dynamic_key.clone(),
       link: Some(self.clone()),
+      child_prefix: OnceCell::new(),
       depth: self.check_and_add_depth()?,
       should_flatten: false,
       is_intermediate: true,
       path_segment: None,
+      key_aliases: self.key_aliases,
+      shared_layouts: self.shared_layouts,
+      mount_prefix: self.mount_prefix.clone(),
     });
 
     // And the table key.
     Ok(Arc::new(Self {
       node: set,
-      key: KeyCow::Borrowed(&set.key),
+      key: key_component(self.key_aliases, &set.key)?,
       link: Some(intermediate.clone()),
+      child_prefix: OnceCell::new(),
       depth: intermediate.check_and_add_depth()?,
       should_flatten: true,
       is_intermediate: false,
       path_segment: None,
+      key_aliases: self.key_aliases,
+      shared_layouts: self.shared_layouts,
+      mount_prefix: self.mount_prefix.clone(),
     }))
   }
 
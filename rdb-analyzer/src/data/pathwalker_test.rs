@@ -1,4 +1,4 @@
-use std::{collections::HashSet, sync::Arc};
+use std::{collections::HashSet, sync::Arc, time::Instant};
 
 use bumpalo::Bump;
 
@@ -8,10 +8,13 @@ use crate::{
     compile::{compile, CompiledSchema, FieldAnnotationList, FieldType},
     grammar::parse,
   },
-  storage_plan::{planner::generate_plan_for_schema, StorageNode, StoragePlan},
+  storage_plan::{
+    planner::{assign_key_aliases, generate_plan_for_schema},
+    StorageNode, StoragePlan,
+  },
 };
 
-use super::pathwalker::PathWalker;
+use super::pathwalker::{PathSegment, PathWalker};
 
 fn print_path_examples(
   schema: &CompiledSchema,
@@ -37,7 +40,7 @@ fn print_path_examples(
         recursion_set.remove(&(field as *const _ as usize));
       }
     }
-    FieldType::Primitive(_) => {}
+    FieldType::Primitive(_) | FieldType::List(_) | FieldType::Map(..) => {}
     FieldType::Set(ty) => {
       let specialized_ty = match &**ty {
         FieldType::Table(x) => schema.types.get(x).unwrap(),
@@ -196,3 +199,299 @@ fn basic() {
     );
   }
 }
+
+#[test]
+fn key_aliases() {
+  let _ = pretty_env_logger::try_init();
+  let alloc = Bump::new();
+  let ast = parse(
+    &alloc,
+    r#"
+  type RecursiveItem<T> {
+    @primary
+    id: string,
+    @index
+    value: T,
+    recursive: RecursiveItem<T>,
+  }
+  export set<RecursiveItem<int64>> recursive_items;
+  "#,
+  )
+  .unwrap();
+  let schema = compile(&ast).unwrap();
+  drop(ast);
+  drop(alloc);
+  let mut plan =
+    generate_plan_for_schema(&Default::default(), &Default::default(), &schema).unwrap();
+  assign_key_aliases(&mut plan).unwrap();
+
+  let set = PathWalker::from_export(&plan, "recursive_items").unwrap();
+  let member = set
+    .enter_set(&PrimitiveValue::String("test".into()))
+    .unwrap();
+  assert_eq!(
+    member.enter_field("value").unwrap().generate_key().len(),
+    11
+  );
+  assert_eq!(
+    member
+      .enter_field("recursive")
+      .unwrap()
+      .enter_field("value")
+      .unwrap()
+      .generate_key()
+      .len(),
+    13
+  );
+  assert_eq!(set.set_index_prefix("value").unwrap().len(), 5);
+
+  // Every storage key on the path must have an alias once aliasing is enabled.
+  let member_key = plan
+    .nodes
+    .get("recursive_items")
+    .unwrap()
+    .set
+    .as_ref()
+    .unwrap()
+    .children["value"]
+    .key;
+  plan.key_aliases.remove(&member_key);
+  let set = PathWalker::from_export(&plan, "recursive_items").unwrap();
+  assert!(set
+    .enter_set(&PrimitiveValue::String("test".into()))
+    .unwrap()
+    .enter_field("value")
+    .is_err());
+}
+
+#[test]
+fn paths() {
+  let _ = pretty_env_logger::try_init();
+  let alloc = Bump::new();
+  let ast = parse(
+    &alloc,
+    r#"
+  type Item {
+    @primary
+    id: bytes,
+    inner: Inner,
+  }
+  type Inner {
+    @primary
+    id: int64,
+    children: set<Inner>,
+  }
+  export set<Item> items;
+  "#,
+  )
+  .unwrap();
+  let schema = compile(&ast).unwrap();
+  drop(ast);
+  drop(alloc);
+  let plan = generate_plan_for_schema(&Default::default(), &Default::default(), &schema).unwrap();
+
+  let walker = PathWalker::from_export(&plan, "items")
+    .unwrap()
+    .enter_set(&PrimitiveValue::Bytes(vec![0x00, 0x01, 0x00]))
+    .unwrap()
+    .enter_field("inner")
+    .unwrap()
+    .enter_field("children")
+    .unwrap()
+    .enter_set(&PrimitiveValue::Int64(-5))
+    .unwrap();
+  assert_eq!(
+    walker.path(),
+    vec![
+      PathSegment::Field("items".into()),
+      PathSegment::Member(PrimitiveValue::Bytes(vec![0x00, 0x01, 0x00])),
+      PathSegment::Field("inner".into()),
+      PathSegment::Field("children".into()),
+      PathSegment::Member(PrimitiveValue::Int64(-5)),
+    ]
+  );
+
+  for v in vec![
+    PrimitiveValue::Bytes(vec![]),
+    PrimitiveValue::Bytes(vec![0x00, 0xff, 0x00]),
+    PrimitiveValue::String("hello".into()),
+    PrimitiveValue::Int64(i64::MIN),
+    PrimitiveValue::Double((-1.5f64).to_bits()),
+    PrimitiveValue::Double(2.5f64.to_bits()),
+  ] {
+    assert_eq!(
+      PrimitiveValue::deserialize_from_key_component(&v.serialize_for_key_component()),
+      Some(v)
+    );
+  }
+}
+
+#[test]
+fn bytes_key_components_fuzz() {
+  use rand::Rng;
+
+  let mut rng = rand::thread_rng();
+  let random_bytes = |rng: &mut rand::rngs::ThreadRng| {
+    let len = rng.gen_range(0..8);
+    (0..len)
+      .map(|_| match rng.gen_range(0..4) {
+        0 => 0x00,
+        1 => 0xff,
+        _ => rng.gen(),
+      })
+      .collect::<Vec<u8>>()
+  };
+
+  for _ in 0..1000 {
+    let (a, b) = (random_bytes(&mut rng), random_bytes(&mut rng));
+    let ka = PrimitiveValue::Bytes(a.clone()).serialize_for_key_component();
+    let kb = PrimitiveValue::Bytes(b.clone()).serialize_for_key_component();
+    assert_eq!(
+      PrimitiveValue::deserialize_from_key_component(&ka),
+      Some(PrimitiveValue::Bytes(a.clone()))
+    );
+
+    // Keys of set members are followed by the keys of their fields, which must not change how
+    // members are ordered. Storage keys and key aliases never start with 0xff.
+    let suffix = [&[rng.gen_range(0..0xff)][..], &random_bytes(&mut rng)[..]].concat();
+    let ka_long = [&ka[..], &suffix[..]].concat();
+    assert_eq!(a.cmp(&b), ka.cmp(&kb), "{:?} {:?}", a, b);
+    if a != b {
+      assert_eq!(a.cmp(&b), ka_long[..].cmp(&kb[..]), "{:?} {:?}", a, b);
+    }
+  }
+}
+
+#[test]
+fn composite_key_components_fuzz() {
+  use rand::Rng;
+
+  let mut rng = rand::thread_rng();
+  let random_string = |rng: &mut rand::rngs::ThreadRng| {
+    let len = rng.gen_range(0..4);
+    (0..len)
+      .map(|_| ['\0', 'a', 'b'][rng.gen_range(0..3)])
+      .collect::<String>()
+  };
+
+  for _ in 0..1000 {
+    let a = (random_string(&mut rng), rng.gen_range(-2i64..2));
+    let b = (random_string(&mut rng), rng.gen_range(-2i64..2));
+    let pack = |x: &(String, i64)| {
+      PrimitiveValue::pack_composite_key(&[
+        PrimitiveValue::String(x.0.clone()),
+        PrimitiveValue::Int64(x.1),
+      ])
+    };
+    let (pa, pb) = (pack(&a), pack(&b));
+    assert_eq!(
+      PrimitiveValue::unpack_composite_key(match &pa {
+        PrimitiveValue::Bytes(x) => x,
+        _ => unreachable!(),
+      }),
+      Some(vec![
+        PrimitiveValue::String(a.0.clone()),
+        PrimitiveValue::Int64(a.1)
+      ])
+    );
+    assert_eq!(
+      a.cmp(&b),
+      pa.serialize_for_key_component()
+        .cmp(&pb.serialize_for_key_component()),
+      "{:?} {:?}",
+      a,
+      b
+    );
+  }
+}
+
+fn recursive_items_plan() -> (CompiledSchema, StoragePlan) {
+  let alloc = Bump::new();
+  let ast = parse(
+    &alloc,
+    r#"
+  type RecursiveItem {
+    @primary
+    id: string,
+    value: int64,
+    duration: Duration,
+    recursive: RecursiveItem,
+  }
+  type Duration {
+    start: int64,
+    end: int64,
+  }
+  export set<RecursiveItem> recursive_items;
+  "#,
+  )
+  .unwrap();
+  let schema = compile(&ast).unwrap();
+  let plan = generate_plan_for_schema(&Default::default(), &Default::default(), &schema).unwrap();
+  (schema, plan)
+}
+
+#[test]
+fn cached_prefixes() {
+  let _ = pretty_env_logger::try_init();
+  let (_, plan) = recursive_items_plan();
+  for walker in [
+    PathWalker::from_export(&plan, "recursive_items").unwrap(),
+    PathWalker::from_mounted_export(&plan, "recursive_items", &[0xff, 0x01]).unwrap(),
+  ] {
+    let mut walker = walker
+      .enter_set(&PrimitiveValue::String("test".into()))
+      .unwrap();
+    for _ in 0..4 {
+      for field in [&["value"][..], &["duration", "start"], &["duration"]] {
+        let mut x = walker.clone();
+        for name in field {
+          x = x.enter_field(name).unwrap();
+        }
+        let components = x
+          .generate_key_pretty()
+          .split(' ')
+          .map(|x| base64::decode(x.trim_start_matches('[').trim_end_matches(']')).unwrap())
+          .collect::<Vec<_>>();
+        assert_eq!(x.generate_key(), components.concat());
+      }
+      walker = walker.enter_field("recursive").unwrap();
+    }
+  }
+}
+
+/// Measures key generation for the fields of many set members, as in a reduce over a set:
+///
+/// `cargo test -p rdb-analyzer -- --ignored --nocapture bench_generate_key`
+#[test]
+#[ignore]
+fn bench_generate_key() {
+  let (_, plan) = recursive_items_plan();
+  let set = PathWalker::from_export(&plan, "recursive_items").unwrap();
+  for depth in [0, 4, 16] {
+    let start = Instant::now();
+    let mut total_len = 0;
+    for i in 0..10000 {
+      let mut member = set
+        .enter_set(&PrimitiveValue::String(format!("{}", i)))
+        .unwrap();
+      for _ in 0..depth {
+        member = member.enter_field("recursive").unwrap();
+      }
+      let duration = member.enter_field("duration").unwrap();
+      for field in [
+        member.enter_field("id").unwrap(),
+        member.enter_field("value").unwrap(),
+        duration.enter_field("start").unwrap(),
+        duration.enter_field("end").unwrap(),
+      ] {
+        total_len += field.generate_key().len();
+      }
+    }
+    println!(
+      "depth {}: {:?} per member, {} key bytes",
+      depth,
+      start.elapsed() / 10000,
+      total_len
+    );
+  }
+}
@@ -0,0 +1,261 @@
+use std::collections::{BTreeMap, BTreeSet, HashSet};
+
+use super::{
+  bytecode::TwGraphNode,
+  typeck::GlobalTypeInfo,
+  vm::TwVm,
+  vm_value::{VmTableType, VmType},
+};
+
+/// Max number of iterations when computing the output visibility of recursive graphs.
+const MAX_SCC_ITERATIONS: usize = 64;
+
+/// Visibility of a value derived from `@acl` protected fields.
+///
+/// For lists, this describes each of the members.
+#[derive(Clone, Debug, Default, Eq, PartialEq)]
+pub struct ValueAcl<'a> {
+  /// Roles allowed to see the value, or `None` if unrestricted.
+  pub roles: Option<BTreeSet<&'a str>>,
+
+  /// Additional restrictions on the fields of a map value. Unrestricted fields are omitted.
+  pub fields: BTreeMap<&'a str, ValueAcl<'a>>,
+}
+
+impl<'a> ValueAcl<'a> {
+  fn with_roles(roles: Option<BTreeSet<&'a str>>) -> Self {
+    Self {
+      roles,
+      fields: BTreeMap::new(),
+    }
+  }
+
+  pub fn is_unrestricted(&self) -> bool {
+    self.roles.is_none() && self.fields.is_empty()
+  }
+
+  /// Whether a caller with `role` may see this value, not considering `fields`.
+  pub fn is_visible_to(&self, role: Option<&str>) -> bool {
+    match (&self.roles, role) {
+      (None, _) => true,
+      (Some(roles), Some(role)) => roles.contains(role),
+      (Some(_), None) => false,
+    }
+  }
+
+  /// The roles allowed to see every part of this value.
+  pub fn flattened_roles(&self) -> Option<BTreeSet<&'a str>> {
+    self.fields.values().fold(self.roles.clone(), |a, b| {
+      intersect(&a, &b.flattened_roles())
+    })
+  }
+
+  fn restrict(&mut self, roles: &Option<BTreeSet<&'a str>>) {
+    self.roles = intersect(&self.roles, roles);
+  }
+
+  /// Combines two candidates of the same value, keeping the restrictions of both.
+  fn merge(&self, other: &Self) -> Self {
+    let mut fields = self.fields.clone();
+    for (k, v) in &other.fields {
+      let merged = match fields.get(k) {
+        Some(x) => x.merge(v),
+        None => v.clone(),
+      };
+      fields.insert(*k, merged);
+    }
+    Self {
+      roles: intersect(&self.roles, &other.roles),
+      fields,
+    }
+  }
+}
+
+fn intersect<'a>(
+  a: &Option<BTreeSet<&'a str>>,
+  b: &Option<BTreeSet<&'a str>>,
+) -> Option<BTreeSet<&'a str>> {
+  match (a, b) {
+    (None, None) => None,
+    (Some(x), None) | (None, Some(x)) => Some(x.clone()),
+    (Some(x), Some(y)) => Some(x.intersection(y).copied().collect()),
+  }
+}
+
+/// Computes the visibility of the output of each graph. Callees are processed before callers,
+/// as given by `scc_post_order`.
+pub fn compute_output_acls<'a>(
+  vm: &TwVm<'a>,
+  scc_post_order: &[HashSet<u32>],
+  type_info: &GlobalTypeInfo<'a>,
+) -> Vec<ValueAcl<'a>> {
+  let mut outputs: Vec<ValueAcl<'a>> = vec![ValueAcl::default(); vm.script.graphs.len()];
+  for scc in scc_post_order {
+    let recursive = scc.len() > 1
+      || scc.iter().any(|i| {
+        vm.script.graphs[*i as usize]
+          .nodes
+          .iter()
+          .any(|(n, _, _)| n.subgraph_references().contains(i))
+      });
+
+    if !recursive {
+      for i in scc {
+        outputs[*i as usize] = compute_graph_output_acl(vm, *i as usize, type_info, &outputs);
+      }
+      continue;
+    }
+
+    // Field-level visibility of recursive graphs may not converge, so only whole-value
+    // restrictions are tracked for them. These only shrink so the iteration terminates.
+    for _ in 0..MAX_SCC_ITERATIONS {
+      let mut changed = false;
+      for i in scc {
+        let acl = ValueAcl::with_roles(
+          compute_graph_output_acl(vm, *i as usize, type_info, &outputs).flattened_roles(),
+        );
+        if acl != outputs[*i as usize] {
+          outputs[*i as usize] = acl;
+          changed = true;
+        }
+      }
+      if !changed {
+        break;
+      }
+    }
+  }
+  outputs
+}
+
+fn compute_graph_output_acl<'a>(
+  vm: &TwVm<'a>,
+  graph_index: usize,
+  type_info: &GlobalTypeInfo<'a>,
+  outputs: &[ValueAcl<'a>],
+) -> ValueAcl<'a> {
+  let g = &vm.script.graphs[graph_index];
+  let types = &type_info.graphs[graph_index].nodes;
+  let mut acls: Vec<ValueAcl<'a>> = Vec::with_capacity(g.nodes.len());
+
+  for (node, in_edges, _) in &g.nodes {
+    let inputs = in_edges
+      .iter()
+      .map(|x| &acls[*x as usize])
+      .collect::<Vec<_>>();
+    let flattened_inputs = inputs
+      .iter()
+      .fold(None, |a, b| intersect(&a, &b.flattened_roles()));
+
+    let acl = match node {
+      TwGraphNode::GetField(key_index) => {
+        let key = vm.script.idents[*key_index as usize].as_str();
+        match types[in_edges[0] as usize].as_ref() {
+          Some(VmType::Table(VmTableType { name })) => ValueAcl::with_roles(intersect(
+            &flattened_inputs,
+            &table_field_roles(vm, name, key),
+          )),
+          _ => {
+            let mut acl = inputs[0].fields.get(key).cloned().unwrap_or_default();
+            acl.restrict(&inputs[0].roles);
+            acl
+          }
+        }
+      }
+      TwGraphNode::ListPop(key_index)
+      | TwGraphNode::ListGet(key_index)
+      | TwGraphNode::ListLen(key_index)
+      | TwGraphNode::DictGet(key_index)
+      | TwGraphNode::DictRange(key_index) => {
+        let key = vm.script.idents[*key_index as usize].as_str();
+        let field_roles = match types[*in_edges.last().unwrap() as usize].as_ref() {
+          Some(VmType::Table(VmTableType { name })) => table_field_roles(vm, name, key),
+          _ => None,
+        };
+        ValueAcl::with_roles(intersect(&flattened_inputs, &field_roles))
+      }
+      TwGraphNode::InsertIntoMap(key_index) => {
+        let key = vm.script.idents[*key_index as usize].as_str();
+        let mut acl = inputs[1].clone();
+        if inputs[0].is_unrestricted() {
+          acl.fields.remove(key);
+        } else {
+          acl.fields.insert(key, inputs[0].clone());
+        }
+        acl
+      }
+      TwGraphNode::DeleteFromMap(key_index) => {
+        let key = vm.script.idents[*key_index as usize].as_str();
+        let mut acl = inputs[0].clone();
+        acl.fields.remove(key);
+        acl
+      }
+      TwGraphNode::Select | TwGraphNode::PrependToList => inputs[0].merge(inputs[1]),
+      TwGraphNode::Cond => inputs[1].merge(inputs[2]),
+      TwGraphNode::Nop
+      | TwGraphNode::UnwrapOptional
+      | TwGraphNode::PopFromList
+      | TwGraphNode::ListHead => inputs[0].clone(),
+      TwGraphNode::Call(subgraph_index) => {
+        let mut acl = outputs[*subgraph_index as usize].clone();
+        acl.restrict(&flattened_inputs);
+        acl
+      }
+      TwGraphNode::Try(subgraph_index) => {
+        // The error message may be built from anything the body sees.
+        let mut value = outputs[*subgraph_index as usize].clone();
+        value.restrict(&flattened_inputs);
+        let error = ValueAcl::with_roles(value.flattened_roles());
+        let mut acl = ValueAcl::default();
+        acl.fields.insert("value", value);
+        acl.fields.insert("error", error);
+        acl.fields.retain(|_, x| !x.is_unrestricted());
+        acl
+      }
+      TwGraphNode::SortList(subgraph_index) => {
+        // The order reveals the outputs of the comparator.
+        let mut acl = inputs[0].clone();
+        acl.restrict(&outputs[*subgraph_index as usize].flattened_roles());
+        acl
+      }
+      TwGraphNode::Reduce(subgraph_index, _) | TwGraphNode::Loop(subgraph_index) => {
+        // Everything other than the initial value may flow into the reduced value with an
+        // unknown structure.
+        let others = inputs
+          .iter()
+          .enumerate()
+          .filter(|(i, _)| *i != 1)
+          .fold(None, |a, (_, b)| intersect(&a, &b.flattened_roles()));
+        let mut acl = inputs[1].merge(&outputs[*subgraph_index as usize]);
+        acl.restrict(&others);
+        acl
+      }
+      _ => ValueAcl::with_roles(flattened_inputs),
+    };
+    acls.push(acl);
+  }
+
+  g.output
+    .map(|x| acls[x as usize].clone())
+    .unwrap_or_default()
+}
+
+/// Whether a caller with `role` may see the field `key` of the table type `name`.
+pub(crate) fn table_field_visible(vm: &TwVm, name: &str, key: &str, role: Option<&str>) -> bool {
+  ValueAcl::with_roles(table_field_roles(vm, name, key)).is_visible_to(role)
+}
+
+/// Roles allowed to see the field `key` of the table type `name`, from its `@acl` annotations.
+fn table_field_roles<'a>(vm: &TwVm<'a>, name: &str, key: &str) -> Option<BTreeSet<&'a str>> {
+  vm.schema
+    .types
+    .get(name)
+    .and_then(|x| x.fields.get(key))
+    .and_then(|(_, annotations)| {
+      annotations
+        .iter()
+        .filter_map(|x| x.acl_roles())
+        .map(|x| Some(x.iter().map(|x| x.as_str()).collect()))
+        .reduce(|a, b| intersect(&a, &b))
+    })
+    .flatten()
+}
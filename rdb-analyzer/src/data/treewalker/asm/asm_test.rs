@@ -1,25 +1,40 @@
-use std::{sync::Arc, time::Instant};
+use std::{
+  sync::{
+    atomic::{AtomicUsize, Ordering},
+    Arc,
+  },
+  time::{Duration, Instant, SystemTime, UNIX_EPOCH},
+};
 
 use anyhow::Result;
+use async_trait::async_trait;
 use bumpalo::Bump;
 
 use crate::{
   data::{
+    kv::{KeyValueStore, KvError, KvKeyIterator, KvTransaction},
     treewalker::{
-      asm::codegen::compile_twscript,
-      exec::{generate_root_map, Executor},
+      asm::{codegen::compile_twscript, TwAsmError},
+      bytecode::{TwCacheDirective, TwCacheKey, TwGraphNode, TwScript},
+      exec::{
+        generate_root_map, BulkDeleteOutcome, ChangeKind, ExecConfig, ExecEnv, ExecError, Executor,
+        Scheduler,
+      },
+      limits::{ExecLimit, ExecLimits},
       serialize::{SerializedVmValue, TaggedVmValue},
-      typeck::GlobalTyckContext,
+      trace::TraceEvent,
+      typeck::{GlobalTyckContext, TypeckError},
       vm::TwVm,
       vm_value::{VmType, VmValue},
     },
     value::PrimitiveValue,
   },
+  fixtures,
   schema::{
-    compile::{compile, PrimitiveType},
+    compile::{compile, CompiledSchema, PrimitiveType},
     grammar::parse,
   },
-  storage_plan::planner::generate_plan_for_schema,
+  storage_plan::{planner::generate_plan_for_schema, StoragePlan},
   test_util::create_kv,
 };
 
@@ -68,104 +83,16 @@ async fn simple_test<F: FnMut(Option<Arc<VmValue>>)>(schema: &str, scripts: &[&s
 
 #[tokio::test]
 async fn basic_exec() {
-  const READER: &str = r#"
-  type SomeString = string;
-  graph main(root: schema): map {
-    id: string,
-    name: string,
-    altname: string,
-    value: int64,
-    kind: string,
-    set_member_name_1: string,
-    set_member_name_2: string,
-    set_member_name_3: string,
-    set_member_name_1_nonnull: SomeString,
-  } {
-    some_item = root.some_item;
-    id = some_item.id;
-    name = some_item.name;
-
-    dur = some_item.duration;
-    if name == "test" {
-      v1 = dur.start;
-      k1 = "start";
-    } else {
-      v2 = dur.end;
-      k2 = "end";
-    }
-    value = select v1 v2;
-    kind = select k1 k2;
-
-    s = root.many_items;
-    elem_name_1 = (point_get s "xxx").name;
-    elem_name_2 = (point_get s "yyy").name;
-    elem_name_3 = (point_get s "zzz").name;
-
-    return m_insert(id) id
-      $ m_insert(name) name
-      $ m_insert(value) value
-      $ m_insert(kind) kind
-      $ m_insert(altname) some_item.altname
-      $ m_insert(set_member_name_1) elem_name_1
-      $ m_insert(set_member_name_2) elem_name_2
-      $ m_insert(set_member_name_3) elem_name_3
-      $ m_insert(set_member_name_1_nonnull) (elem_name_1 ?? "<unknown>")
-      create_map;
-  }
-  "#;
+  let fixture = fixtures::get("items").unwrap();
   let _ = pretty_env_logger::try_init();
   let mut chkindex = 0usize;
   simple_test(
-    r#"
-  type Item {
-    @primary
-    id: string,
-    name: string,
-    altname: string,
-    duration: Duration<int64>,
-  }
-  type Duration<T> {
-    start: T,
-    end: T,
-  }
-  export Item some_item;
-  export set<Item> many_items;
-  "#,
+    fixture.schema,
     &[
-      r#"
-    graph main(root: schema) {
-      some_item = root.some_item;
-      t_insert(duration) some_item $
-        build_table(Duration<int64>) $
-        m_insert(start) 1 $
-        m_insert(end) 2 $
-        create_map;
-      t_insert(name) some_item "test_name";
-    }
-    "#,
-      READER,
-      r#"
-  graph main(root: schema) {
-    t_insert(name) root.some_item "test";
-
-    m = m_insert(start) 1 $ m_insert(end) 2 $ create_map;
-    dur = build_table(Duration<int64>) m;
-
-    s_insert root.many_items $ build_table(Item)
-      $ m_insert(id) "xxx"
-      $ m_insert(name) "name_for_xxx"
-      $ m_insert(altname) "testalt"
-      $ m_insert(duration) dur
-      $ create_map;
-    s_insert root.many_items $ build_table(Item)
-      $ m_insert(id) "yyy"
-      $ m_insert(name) "name_for_yyy"
-      $ m_insert(altname) "testalt"
-      $ m_insert(duration) dur
-      $ create_map;
-  }
-  "#,
-      READER,
+      fixture.script("write_item").unwrap(),
+      fixture.script("read").unwrap(),
+      fixture.script("write_many_items").unwrap(),
+      fixture.script("read").unwrap(),
     ],
     |x| {
       match chkindex {
@@ -356,6 +283,31 @@ async fn set_reduce() {
           create_map;
       }
       "#,
+      r#"
+      @relaxed_isolation
+      graph main(root: schema): string {
+        return (reduce(f) from "id2" to null<string> create_map (
+          m_insert(first) true $
+          m_insert(result) "" create_map
+        ) root.items).result;
+      }
+      graph f(ctx: map{}, current: map {
+        first: bool,
+        result: string,
+      }, item: Item): map {
+        first: bool,
+        result: string,
+      } {
+        if !current.first {
+          r1 = current.result + " " + item.id;
+        } else {
+          r2 = item.id;
+        }
+        return m_insert(first) false
+          $ m_insert(result) (select r1 r2)
+          create_map;
+      }
+      "#,
     ],
     |x| {
       match chkindex {
@@ -378,6 +330,12 @@ async fn set_reduce() {
             VmValue::Primitive(PrimitiveValue::String("id2 id3".into()))
           );
         }
+        4 => {
+          assert_eq!(
+            **x.as_ref().unwrap(),
+            VmValue::Primitive(PrimitiveValue::String("id2 id3 id4".into()))
+          );
+        }
         _ => unreachable!(),
       }
       chkindex += 1;
@@ -385,7 +343,300 @@ async fn set_reduce() {
   )
   .await;
 
-  assert_eq!(chkindex, 4);
+  assert_eq!(chkindex, 5);
+}
+
+#[tokio::test]
+async fn scan_index() {
+  const JOIN: &str = r#"
+  graph join(ctx: map{}, current: string, item: Item): string {
+    if current == "" {
+      r1 = item.id;
+    } else {
+      r2 = current + " " + item.id;
+    }
+    return select r1 r2;
+  }
+  "#;
+  let _ = pretty_env_logger::try_init();
+  let scripts = [
+    r#"
+    graph main(root: schema) {
+      s_insert root.items $ build_table(Item) $ m_insert(id) "a" $ m_insert(score) 30 create_map;
+      s_insert root.items $ build_table(Item) $ m_insert(id) "b" $ m_insert(score) 10 create_map;
+      s_insert root.items $ build_table(Item) $ m_insert(id) "c" $ m_insert(score) 40 create_map;
+      s_insert root.items $ build_table(Item) $ m_insert(id) "d" $ m_insert(score) 30 create_map;
+      s_insert root.items $ build_table(Item) $ m_insert(id) "e" $ m_insert(score) 20 create_map;
+    }
+    "#
+    .to_string(),
+    format!(
+      r#"
+      graph main(root: schema): string {{
+        return reduce(join) create_map "" $ scan_index(score) root.items;
+      }}
+      {}
+      "#,
+      JOIN
+    ),
+    format!(
+      r#"
+      graph main(root: schema): string {{
+        return reduce(join) create_map "" $ scan_index(score) from 20 to 40 root.items;
+      }}
+      {}
+      "#,
+      JOIN
+    ),
+    format!(
+      r#"
+      graph main(root: schema): string {{
+        return reduce(join) create_map "" $ scan_index(score) from 30 to null<int64> root.items;
+      }}
+      {}
+      "#,
+      JOIN
+    ),
+    format!(
+      r#"
+      graph main(root: schema): string {{
+        return reduce(join) create_map "" $ scan_index(id) from "b" to "d" root.items;
+      }}
+      {}
+      "#,
+      JOIN
+    ),
+    format!(
+      r#"
+      graph main(root: schema): string {{
+        return reduce(join) create_map "" $ scan_index_desc(score) root.items;
+      }}
+      {}
+      "#,
+      JOIN
+    ),
+    format!(
+      r#"
+      graph main(root: schema): string {{
+        return reduce(join) create_map "" $ scan_index_desc(score, 2) from 10 to 40 root.items;
+      }}
+      {}
+      "#,
+      JOIN
+    ),
+    format!(
+      r#"
+      graph main(root: schema): string {{
+        return reduce(join) create_map "" $ scan_index(score, 2) root.items;
+      }}
+      {}
+      "#,
+      JOIN
+    ),
+    format!(
+      r#"
+      graph main(root: schema): string {{
+        return reduce(join) create_map "" $ scan_index_desc(id, 3) root.items;
+      }}
+      {}
+      "#,
+      JOIN
+    ),
+    format!(
+      r#"
+      graph main(root: schema): string {{
+        return reduce(join) create_map "" $ scan_index(id, -1) root.items;
+      }}
+      {}
+      "#,
+      JOIN
+    ),
+  ];
+  let mut chkindex = 0usize;
+  simple_test(
+    r#"
+  type Item {
+    @primary
+    id: string,
+    @index
+    score: int64,
+  }
+  export set<Item> items;
+  "#,
+    &scripts.iter().map(|x| x.as_str()).collect::<Vec<_>>(),
+    |x| {
+      let expected = match chkindex {
+        0 => None,
+        1 => Some("b e a d c"),
+        2 => Some("e a d"),
+        3 => Some("a d c"),
+        4 => Some("b c"),
+        5 => Some("c d a e b"),
+        6 => Some("d a"),
+        7 => Some("b e"),
+        8 => Some("e d c"),
+        9 => Some(""),
+        _ => unreachable!(),
+      };
+      if let Some(expected) = expected {
+        assert_eq!(
+          **x.as_ref().unwrap(),
+          VmValue::Primitive(PrimitiveValue::String(expected.into()))
+        );
+      }
+      chkindex += 1;
+    },
+  )
+  .await;
+  assert_eq!(chkindex, 10);
+}
+
+#[tokio::test]
+async fn set_aggregates() {
+  let _ = pretty_env_logger::try_init();
+  let mut chkindex = 0usize;
+  simple_test(
+    r#"
+  type Item {
+    @primary
+    id: string,
+    @index
+    score: int64,
+    price: int64,
+  }
+  export set<Item> items;
+  "#,
+    &[
+      r#"
+      graph main(root: schema): int64 {
+        return s_count root.items;
+      }
+      "#,
+      r#"
+      graph main(root: schema): int64 {
+        return s_sum(price) root.items;
+      }
+      "#,
+      r#"
+      graph main(root: schema): int64 {
+        return s_min(score) root.items;
+      }
+      "#,
+      r#"
+      graph main(root: schema) {
+        s_insert root.items $ build_table(Item) $ m_insert(id) "b" $ m_insert(score) 30 $ m_insert(price) 5 create_map;
+        s_insert root.items $ build_table(Item) $ m_insert(id) "c" $ m_insert(score) 10 $ m_insert(price) 7 create_map;
+        s_insert root.items $ build_table(Item) $ m_insert(id) "a" $ m_insert(score) 20 $ m_insert(price) 9 create_map;
+      }
+      "#,
+      r#"
+      graph main(root: schema): int64 {
+        return s_count root.items;
+      }
+      "#,
+      r#"
+      graph main(root: schema): int64 {
+        return s_sum(price) root.items;
+      }
+      "#,
+      r#"
+      graph main(root: schema): int64 {
+        return s_min(score) root.items;
+      }
+      "#,
+      r#"
+      graph main(root: schema): int64 {
+        return s_max(score) root.items;
+      }
+      "#,
+      r#"
+      graph main(root: schema): int64 {
+        return s_max(price) root.items;
+      }
+      "#,
+      r#"
+      graph main(root: schema): string {
+        return s_max(id) root.items;
+      }
+      "#,
+    ],
+    |x| {
+      let expected = match chkindex {
+        0 => Some(PrimitiveValue::Int64(0)),
+        1 => Some(PrimitiveValue::Int64(0)),
+        2 => None,
+        3 => {
+          chkindex += 1;
+          return;
+        }
+        4 => Some(PrimitiveValue::Int64(3)),
+        5 => Some(PrimitiveValue::Int64(21)),
+        6 => Some(PrimitiveValue::Int64(10)),
+        7 => Some(PrimitiveValue::Int64(30)),
+        8 => Some(PrimitiveValue::Int64(9)),
+        9 => Some(PrimitiveValue::String("c".into())),
+        _ => unreachable!(),
+      };
+      let x = x.unwrap();
+      match expected {
+        Some(expected) => assert_eq!(*x, VmValue::Primitive(expected)),
+        None => assert!(x.is_null()),
+      }
+      chkindex += 1;
+    },
+  )
+  .await;
+  assert_eq!(chkindex, 10);
+}
+
+#[tokio::test]
+async fn point_get_many() {
+  let _ = pretty_env_logger::try_init();
+  let mut chkindex = 0usize;
+  simple_test(
+    r#"
+  type Item {
+    @primary
+    id: string,
+    name: string,
+  }
+  export set<Item> items;
+  "#,
+    &[
+      r#"
+      graph main(root: schema) {
+        s_insert root.items $ build_table(Item) $ m_insert(id) "a" $ m_insert(name) "alice" create_map;
+        s_insert root.items $ build_table(Item) $ m_insert(id) "b" $ m_insert(name) "bob" create_map;
+        s_insert root.items $ build_table(Item) $ m_insert(id) "c" $ m_insert(name) "carol" create_map;
+      }
+      "#,
+      r#"
+      graph main(root: schema): string {
+        items = point_get_many root.items ("c" : "x" : "a" : create_list(string));
+        return reduce(join) create_map "" items;
+      }
+      graph join(ctx: map{}, current: string, item: Item): string {
+        if is_null item {
+          r1 = current + "-";
+        } else {
+          r2 = current + item.name;
+        }
+        return select r1 r2;
+      }
+      "#,
+    ],
+    |x| {
+      if chkindex == 1 {
+        assert_eq!(
+          **x.as_ref().unwrap(),
+          VmValue::Primitive(PrimitiveValue::String("carol-alice".into()))
+        );
+      }
+      chkindex += 1;
+    },
+  )
+  .await;
+  assert_eq!(chkindex, 2);
 }
 
 #[tokio::test]
@@ -494,142 +745,2726 @@ async fn list_ops() {
 }
 
 #[tokio::test]
-async fn throw_string() {
+async fn if_expression() {
   let _ = pretty_env_logger::try_init();
-  let mut ok = false;
-  simple_test_with_error(
+  let alloc = Bump::new();
+  let ast = parse(&alloc, "").unwrap();
+  let schema = compile(&ast).unwrap();
+  drop(ast);
+  drop(alloc);
+  let plan = generate_plan_for_schema(&Default::default(), &Default::default(), &schema).unwrap();
+  let kv = create_kv();
+  let root_map = Arc::new(generate_root_map(&schema, &plan).unwrap());
+
+  let cases = [
+    (
+      r#"
+      graph main(root: schema): string {
+        x = if 1 < 2 { "a" } else { "b" };
+        return x + (if 1 > 2 { "a" } else { "b" });
+      }
+      "#,
+      serde_json::json!("ab"),
+    ),
+    (
+      // Only the chosen branch runs.
+      r#"
+      graph main(root: schema): int64 {
+        n = null<int64>;
+        return if is_null n { -1 } else { n! + 1 };
+      }
+      "#,
+      serde_json::json!("-1"),
+    ),
+    (
+      r#"
+      graph main(root: schema): bool {
+        return is_null $ if null<bool> { 1 } else { 2 };
+      }
+      "#,
+      serde_json::json!(true),
+    ),
+    (
+      r#"
+      graph main(root: schema): string {
+        v = 5;
+        if v > 0 {
+          r1 = if v > 3 { if v > 4 { "big" } else { "mid" } } else { "small" };
+        } else {
+          r2 = "negative";
+        }
+        return select r1 r2;
+      }
+      "#,
+      serde_json::json!("big"),
+    ),
+    (
+      // Both branches may be the same node.
+      r#"
+      graph main(root: schema): int64 {
+        x = 1;
+        return if x == x { x } else { x };
+      }
+      "#,
+      serde_json::json!("1"),
+    ),
+    (
+      r#"
+      graph main(root: schema): map { a: int64 } {
+        return if 1 == 2 {
+          m_insert(a) 1 $ m_insert(b) 2 create_map
+        } else {
+          m_insert(a) 3 create_map
+        };
+      }
+      "#,
+      serde_json::json!({ "M": { "a": "3" } }),
+    ),
+  ];
+
+  for (code, expected) in &cases {
+    let script = compile_twscript(code).unwrap();
+    let vm = TwVm::new(&schema, &plan, &script).unwrap();
+    let type_info = GlobalTyckContext::new(&vm).unwrap().typeck().unwrap();
+    for scheduler in [Scheduler::Dataflow, Scheduler::Topological] {
+      let mut executor = Executor::new(&vm, &*kv, &type_info);
+      executor.set_config(ExecConfig {
+        scheduler,
+        ..Default::default()
+      });
+      let output = executor
+        .run_graph(0, &[root_map.clone()])
+        .await
+        .unwrap()
+        .unwrap();
+      let output = SerializedVmValue::encode(&*output, &Default::default()).unwrap();
+      assert_eq!(&serde_json::to_value(&output).unwrap(), expected);
+    }
+  }
+
+  let script = compile_twscript(
     r#"
-  "#,
-    &[r#"
-    graph main(root: schema) {
-      throw "test error";
+    graph main(root: schema): int64 {
+      return if true { 1 } else { "x" };
     }
-    "#],
-    |x| {
-      assert_eq!(
-        x.unwrap_err().to_string(),
-        "script thrown error: `test error`"
-      );
-      ok = true;
-    },
+    "#,
   )
-  .await;
-
-  assert!(ok);
+  .unwrap();
+  let vm = TwVm::new(&schema, &plan, &script).unwrap();
+  let err = GlobalTyckContext::new(&vm).unwrap().typeck().unwrap_err();
+  assert!(matches!(
+    err.downcast_ref::<TypeckError>(),
+    Some(TypeckError::CondBranchTypeMismatch(_, _))
+  ));
 }
 
 #[tokio::test]
-async fn throw_null() {
+async fn for_loop() {
   let _ = pretty_env_logger::try_init();
-  let mut ok = false;
-  simple_test_with_error(
+  let mut outputs = vec![];
+  simple_test(
     r#"
   "#,
-    &[r#"
-    graph main(root: schema) {
-      throw null<string>;
+    &[
+      // Far more iterations than the recursion depth limit.
+      r#"
+    graph main(root: schema): int64 {
+      numbers = for(push) from 0 to 1000 create_map create_list(int64);
+      return reduce(sum) create_map 0 numbers;
     }
-    "#],
-    |x| {
-      assert_eq!(x.unwrap_err().to_string(), "script thrown null");
-      ok = true;
-    },
+    graph push(_unused: map{}, current: list<int64>, i: int64): list<int64> {
+      return i : current;
+    }
+    graph sum(_unused: map{}, current: int64, that: int64): int64 {
+      return current + that;
+    }
+    "#,
+      r#"
+    graph main(root: schema): int64 {
+      return for(count_until) from 0 to 100 10 0;
+    }
+    graph count_until(limit: int64, current: int64, i: int64): int64 {
+      if i == limit {
+        r1 = null<int64>;
+      } else {
+        r2 = current + 1;
+      }
+      return select r1 r2;
+    }
+    "#,
+      r#"
+    graph main(root: schema): int64 {
+      return for(count_until) from 5 to 0 100 7;
+    }
+    graph count_until(limit: int64, current: int64, i: int64): int64 {
+      return current + 1;
+    }
+    "#,
+    ],
+    |x| outputs.push(x.unwrap().unwrap_primitive().clone()),
   )
   .await;
-
-  assert!(ok);
+  assert_eq!(
+    outputs,
+    vec![
+      PrimitiveValue::Int64(499500),
+      PrimitiveValue::Int64(10),
+      PrimitiveValue::Int64(7),
+    ]
+  );
 }
 
 #[tokio::test]
-async fn partial_table_replacement() {
+async fn sort_list() {
   let _ = pretty_env_logger::try_init();
-  let mut chkindex = 0usize;
+  let mut outputs = vec![];
   simple_test(
     r#"
-    type Table {
-      fields: Fields,
-    }
-    type Fields {
-      a: int64,
-      b: int64,
-    }
-    export Table t;
   "#,
     &[
       r#"
-    graph main(root: schema) {
-      t = build_table(Fields) $
-        m_insert(a) 1 $
-        m_insert(b) 2 $
-        create_map;
-      t_insert(fields) root.t t;
+    graph main(root: schema): list<int64> {
+      return sort(lt) (3 : 1 : 4 : 1 : 5 : 9 : 2 : 6 : create_list(int64));
+    }
+    graph lt(a: int64, b: int64): bool {
+      return a < b;
     }
     "#,
       r#"
-    graph main(root: schema): map {
-      a: int64,
-      b: int64,
-    } {
-      return m_insert(a) root.t.fields.a $
-        m_insert(b) root.t.fields.b $
-        create_map;
+    graph main(root: schema): list<int64> {
+      return sort(desc) (3 : 1 : 4 : 1 : 5 : 9 : 2 : 6 : create_list(int64));
+    }
+    graph desc(a: int64, b: int64): int64 {
+      return b - a;
     }
     "#,
+      // Members that compare equal keep their order.
       r#"
-    graph main(root: schema) {
-      t = build_table(Fields) $
-        m_insert(a) 3 $
+    graph main(root: schema): list<int64> {
+      pairs = sort(by_key) (
+        call(pair) [1, 10] : call(pair) [0, 20] : call(pair) [1, 30] : call(pair) [0, 40]
+          : create_list(map { key: int64, value: int64 })
+      );
+      return reduce(values) create_map create_list(int64) pairs;
+    }
+    graph pair(key: int64, value: int64): map { key: int64, value: int64 } {
+      return m_insert(key) key $ m_insert(value) value create_map;
+    }
+    graph by_key(a: map { key: int64, value: int64 }, b: map { key: int64, value: int64 }): bool {
+      return a.key < b.key;
+    }
+    graph values(_unused: map{}, current: list<int64>, x: map { key: int64, value: int64 }): list<int64> {
+      return x.value : current;
+    }
+    "#,
+      r#"
+    graph main(root: schema): list<int64> {
+      return sort(lt) create_list(int64);
+    }
+    graph lt(a: int64, b: int64): bool {
+      return a < b;
+    }
+    "#,
+    ],
+    |x| match &**x.as_ref().unwrap() {
+      VmValue::List(x) => outputs.push(
+        x.node
+          .iter()
+          .map(|x| match &**x {
+            VmValue::Primitive(PrimitiveValue::Int64(x)) => *x,
+            _ => unreachable!(),
+          })
+          .collect::<Vec<_>>(),
+      ),
+      _ => unreachable!(),
+    },
+  )
+  .await;
+  assert_eq!(
+    outputs,
+    vec![
+      vec![1, 1, 2, 3, 4, 5, 6, 9],
+      vec![9, 6, 5, 4, 3, 2, 1, 1],
+      vec![30, 10, 40, 20],
+      vec![],
+    ]
+  );
+}
+
+#[tokio::test]
+async fn map_iteration() {
+  let _ = pretty_env_logger::try_init();
+  let mut ok = false;
+  simple_test(
+    r#"
+  "#,
+    &[r#"
+    graph main(root: schema): map {
+      keys: list<string>,
+      values: list<int64>,
+      c: int64,
+      missing: int64,
+    } {
+      m = m_insert(b) 2 $ m_insert(a) 1 $ m_insert(c) 3 create_map;
+      return m_insert(keys) (m_keys m)
+        $ m_insert(values) (m_values m)
+        $ m_insert(c) (call(get) ["c", m])
+        $ m_insert(missing) ((m_get "z" m) ?? 0)
         create_map;
-      t_insert(fields) root.t t;
+    }
+    graph get(key: string, m: map { a: int64, b: int64, c: int64 }): int64 {
+      return m_get key m;
+    }
+    "#],
+    |x| {
+      let x = SerializedVmValue::encode(&**x.as_ref().unwrap(), &Default::default()).unwrap();
+      assert_eq!(
+        serde_json::to_value(&x).unwrap(),
+        serde_json::json!({
+          "M": {
+            "keys": { "L": ["a", "b", "c"] },
+            "values": { "L": ["1", "2", "3"] },
+            "c": "3",
+            "missing": "0",
+          }
+        })
+      );
+      ok = true;
+    },
+  )
+  .await;
+  assert!(ok);
+
+  let script = compile_twscript(
+    r#"
+    graph main(root: schema): list<int64> {
+      return m_values (m_insert(a) 1 $ m_insert(b) "x" create_map);
     }
     "#,
+  )
+  .unwrap();
+  let alloc = Bump::new();
+  let ast = parse(&alloc, "").unwrap();
+  let schema = compile(&ast).unwrap();
+  drop(ast);
+  drop(alloc);
+  let plan = generate_plan_for_schema(&Default::default(), &Default::default(), &schema).unwrap();
+  let vm = TwVm::new(&schema, &plan, &script).unwrap();
+  let err = GlobalTyckContext::new(&vm).unwrap().typeck().unwrap_err();
+  assert!(matches!(
+    err.downcast_ref::<TypeckError>(),
+    Some(TypeckError::NoCommonMapValueType(_))
+  ));
+}
+
+#[tokio::test]
+async fn json_values() {
+  let _ = pretty_env_logger::try_init();
+  let mut outputs = vec![];
+  simple_test_with_error(
+    r#"
+  "#,
+    &[
       r#"
     graph main(root: schema): map {
-      a: int64,
-      b: int64,
+      first: int64,
+      second: string,
+      third: bool,
+      mismatch: int64,
+      missing: string,
+      sub: json,
+      text: string,
     } {
-      return m_insert(a) root.t.fields.a $
-        m_insert(b) root.t.fields.b $
+      doc = json_parse "{\"a\": {\"b\": [1, \"x\", true]}}";
+      return m_insert(first) (json_get<int64> "a.b[0]" doc)
+        $ m_insert(second) (json_get<string> "a.b[1]" doc)
+        $ m_insert(third) (json_get<bool> "a.b[2]" doc)
+        $ m_insert(mismatch) ((json_get<int64> "a.b[1]" doc) ?? -1)
+        $ m_insert(missing) ((json_get<string> "a.c" doc) ?? "none")
+        $ m_insert(sub) (json_get "a" doc)
+        $ m_insert(text) (json_serialize (json_get "a.b" doc))
         create_map;
     }
+    "#,
+      r#"
+    graph main(root: schema): json {
+      return json_parse "{";
+    }
+    "#,
+      r#"
+    graph main(root: schema): json {
+      return json_get "a..b" (json_parse "{}");
+    }
     "#,
     ],
     |x| {
-      match chkindex {
-        0 => {}
-        1 => match &**x.as_ref().unwrap() {
-          VmValue::Map(x) => {
-            assert_eq!(
-              **x.elements.get("a").unwrap(),
-              VmValue::Primitive(PrimitiveValue::Int64(1))
-            );
-            assert_eq!(
-              **x.elements.get("b").unwrap(),
-              VmValue::Primitive(PrimitiveValue::Int64(2))
-            );
-          }
-          _ => unreachable!(),
-        },
-        2 => {}
-        3 => match &**x.as_ref().unwrap() {
-          VmValue::Map(x) => {
-            assert_eq!(
-              **x.elements.get("a").unwrap(),
-              VmValue::Primitive(PrimitiveValue::Int64(3))
-            );
-            assert_eq!(
-              **x.elements.get("b").unwrap(),
-              VmValue::Null(VmType::Primitive(PrimitiveType::Int64))
-            );
-          }
-          _ => unreachable!(),
-        },
+      outputs.push(x.map(|x| {
+        let x = SerializedVmValue::encode(&**x.as_ref().unwrap(), &Default::default()).unwrap();
+        serde_json::to_value(&x).unwrap()
+      }))
+    },
+  )
+  .await;
+  assert_eq!(outputs.len(), 3);
+  assert_eq!(
+    outputs[0].as_ref().unwrap(),
+    &serde_json::json!({
+      "M": {
+        "first": "1",
+        "second": "x",
+        "third": true,
+        "mismatch": "-1",
+        "missing": "none",
+        "sub": { "M": { "b": { "L": [1, "x", true] } } },
+        "text": "[1,\"x\",true]",
+      }
+    })
+  );
+  let err = outputs[1].as_ref().unwrap_err().downcast_ref::<ExecError>();
+  assert!(matches!(err, Some(ExecError::InvalidJson(_))));
+  assert!(err.unwrap().is_script_error());
+  let err = outputs[2].as_ref().unwrap_err().downcast_ref::<ExecError>();
+  assert!(matches!(err, Some(ExecError::InvalidJsonPath(_))));
+}
+
+#[tokio::test]
+async fn arithmetic() {
+  let _ = pretty_env_logger::try_init();
+  let mut chkindex = 0usize;
+  simple_test_with_error(
+    r#"
+  "#,
+    &[
+      r#"
+      graph main(root: schema): int64 {
+        return 2 + 3 * 4;
+      }
+      "#,
+      r#"
+      graph main(root: schema): int64 {
+        return 7 * 3 - 10 / 3 % 2 + -(4);
+      }
+      "#,
+      r#"
+      graph main(root: schema): int64 {
+        x = -7;
+        return (x / 2) * 100 + x % 2;
+      }
+      "#,
+      r#"
+      graph main(root: schema): int64 {
+        x = 5;
+        return -x;
+      }
+      "#,
+      r#"
+      graph main(root: schema): int64 {
+        return 1 / 0;
+      }
+      "#,
+      r#"
+      graph main(root: schema): int64 {
+        return 1 % 0;
+      }
+      "#,
+    ],
+    |x| {
+      let expected = match chkindex {
+        0 => Some(14),
+        1 => Some(16),
+        2 => Some(-301),
+        3 => Some(-5),
+        4 | 5 => None,
         _ => unreachable!(),
+      };
+      match expected {
+        Some(expected) => assert_eq!(
+          *x.unwrap().unwrap(),
+          VmValue::Primitive(PrimitiveValue::Int64(expected))
+        ),
+        None => assert_eq!(x.unwrap_err().to_string(), "division by zero"),
       }
       chkindex += 1;
     },
   )
   .await;
+  assert_eq!(chkindex, 6);
+}
 
+#[tokio::test]
+async fn comparisons() {
+  let _ = pretty_env_logger::try_init();
+  let mut chkindex = 0usize;
+  simple_test(
+    r#"
+  "#,
+    &[
+      r#"
+      graph main(root: schema): bool {
+        return 1 < 2 && 2 <= 2 && 3 > -3 && 3 >= 3;
+      }
+      "#,
+      r#"
+      graph main(root: schema): bool {
+        return 2 < 2 || 3 <= 2 || -1 > 0 || 1 >= 2;
+      }
+      "#,
+      r#"
+      graph main(root: schema): bool {
+        return "abc" < "abd" && "ab" < "abc" && "b" > "abc" && "" <= "";
+      }
+      "#,
+      r#"
+      graph main(root: schema): bool {
+        return 1 + 1 < 2 * 2 && 0 - 5 >= -5;
+      }
+      "#,
+    ],
+    |x| {
+      let expected = match chkindex {
+        0 | 2 | 3 => true,
+        1 => false,
+        _ => unreachable!(),
+      };
+      assert_eq!(*x.unwrap(), VmValue::Bool(expected));
+      chkindex += 1;
+    },
+  )
+  .await;
   assert_eq!(chkindex, 4);
 }
+
+#[tokio::test]
+async fn double_builtins() {
+  let _ = pretty_env_logger::try_init();
+  let mut outputs = vec![];
+  simple_test(
+    r#"
+  "#,
+    &[
+      r#"
+      graph main(root: schema): string {
+        return format_double (parse_double "1.5")! + "|" + format_double (parse_double "-0")!
+          + "|" + format_double (parse_double "1e3")! + "|" + format_double (parse_double "-inf")!;
+      }
+      "#,
+      r#"
+      graph main(root: schema): map {
+        signed_zero_eq: bool,
+        nan_eq: bool,
+        nan_ne: bool,
+        nan_lt: bool,
+        nan_ge: bool,
+        lt: bool,
+        nested_eq: bool,
+        invalid: bool,
+        nan_text: string,
+      } {
+        zero = (parse_double "0")!;
+        neg_zero = (parse_double "-0")!;
+        nan = (parse_double "NaN")!;
+        return m_insert(signed_zero_eq) (zero == neg_zero)
+          $ m_insert(nan_eq) (nan == nan)
+          $ m_insert(nan_ne) (nan != nan)
+          $ m_insert(nan_lt) (nan < zero)
+          $ m_insert(nan_ge) (nan >= zero)
+          $ m_insert(lt) (neg_zero < (parse_double "0.5")!)
+          $ m_insert(nested_eq) ((m_insert(x) zero create_map) == (m_insert(x) neg_zero create_map))
+          $ m_insert(invalid) (is_null $ parse_double "1.5x")
+          $ m_insert(nan_text) (format_double nan)
+          create_map;
+      }
+      "#,
+    ],
+    |x| {
+      let x = SerializedVmValue::encode(&**x.as_ref().unwrap(), &Default::default()).unwrap();
+      outputs.push(serde_json::to_value(&x).unwrap());
+    },
+  )
+  .await;
+  assert_eq!(outputs.len(), 2);
+  assert_eq!(outputs[0], serde_json::json!("1.5|-0|1000|-inf"));
+  assert_eq!(
+    outputs[1],
+    serde_json::json!({
+      "M": {
+        "signed_zero_eq": true,
+        "nan_eq": false,
+        "nan_ne": true,
+        "nan_lt": false,
+        "nan_ge": false,
+        "lt": true,
+        "nested_eq": true,
+        "invalid": true,
+        "nan_text": "NaN",
+      }
+    })
+  );
+}
+
+#[tokio::test]
+async fn string_builtins() {
+  let _ = pretty_env_logger::try_init();
+  let mut chkindex = 0usize;
+  simple_test(
+    r#"
+  "#,
+    &[
+      r#"
+      graph main(root: schema): bool {
+        s = "Hello, World";
+        return str_starts_with s "Hello" && !(str_starts_with s "World")
+          && str_contains s ", W" && !(str_contains s "world")
+          && str_contains s "";
+      }
+      "#,
+      r#"
+      graph main(root: schema): string {
+        return str_to_lower $ str_substring "Hello, World" 7 100;
+      }
+      "#,
+      r#"
+      graph main(root: schema): string {
+        return (str_substring "héllo" -1 3) + "|" + (str_substring "héllo" 10 1);
+      }
+      "#,
+      r#"
+      graph main(root: schema): int64 {
+        return str_length "héllo" + str_length "";
+      }
+      "#,
+      r#"
+      graph main(root: schema): bool {
+        return is_null $ str_length null<string>;
+      }
+      "#,
+    ],
+    |x| {
+      let x = x.unwrap();
+      match chkindex {
+        0 | 4 => assert_eq!(*x, VmValue::Bool(true)),
+        1 => assert_eq!(
+          *x,
+          VmValue::Primitive(PrimitiveValue::String("world".into()))
+        ),
+        2 => assert_eq!(
+          *x,
+          VmValue::Primitive(PrimitiveValue::String("hél|".into()))
+        ),
+        3 => assert_eq!(*x, VmValue::Primitive(PrimitiveValue::Int64(5))),
+        _ => unreachable!(),
+      }
+      chkindex += 1;
+    },
+  )
+  .await;
+  assert_eq!(chkindex, 5);
+}
+
+#[tokio::test]
+async fn bytes_builtins() {
+  let _ = pretty_env_logger::try_init();
+  let mut outputs = vec![];
+  simple_test_with_error(
+    r#"
+  "#,
+    &[
+      r#"
+      graph main(root: schema): string {
+        return bytes_to_hex $ bytes_concat (bytes_from_hex "00FF") (bytes_from_string "hi");
+      }
+      "#,
+      r#"
+      graph main(root: schema): string {
+        return bytes_to_base64 $ bytes_slice (bytes_from_string "hello, world") -1 5;
+      }
+      "#,
+      r#"
+      graph main(root: schema): string {
+        return bytes_to_string (bytes_from_base64 "aMOpbGxv") + "|"
+          + bytes_to_hex (bytes_slice h"0102" 5 1);
+      }
+      "#,
+      r#"
+      graph main(root: schema): int64 {
+        return bytes_length h"000102" + bytes_length (bytes_from_string "é");
+      }
+      "#,
+      r#"
+      graph main(root: schema): bytes {
+        return bytes_from_hex "0g";
+      }
+      "#,
+      r#"
+      graph main(root: schema): string {
+        return bytes_to_string h"ff";
+      }
+      "#,
+    ],
+    |x| {
+      outputs
+        .push(x.map(|x| unwrap_enum!(&**x.as_ref().unwrap(), VmValue::Primitive(x) => x.clone())))
+    },
+  )
+  .await;
+  assert_eq!(outputs.len(), 6);
+  let string = |x: &str| PrimitiveValue::String(x.into());
+  assert_eq!(outputs[0].as_ref().unwrap(), &string("00ff6869"));
+  assert_eq!(outputs[1].as_ref().unwrap(), &string("aGVsbG8="));
+  assert_eq!(outputs[2].as_ref().unwrap(), &string("héllo|"));
+  assert_eq!(outputs[3].as_ref().unwrap(), &PrimitiveValue::Int64(5));
+  for x in &outputs[4..] {
+    let err = x.as_ref().unwrap_err().downcast_ref::<ExecError>();
+    assert!(matches!(err, Some(ExecError::InvalidBytesEncoding(_))));
+    assert!(err.unwrap().is_script_error());
+  }
+}
+
+/// Inserts members keyed by random byte strings, built in the VM from their hex and base64
+/// halves, and checks that they can be read back by key and are scanned in byte order.
+#[tokio::test]
+async fn bytes_keys_fuzz() {
+  use rand::Rng;
+
+  let _ = pretty_env_logger::try_init();
+  let mut rng = rand::thread_rng();
+  let mut keys: Vec<Vec<u8>> = vec![];
+  while keys.len() < 50 {
+    // Favor the bytes that the key encoding escapes or uses as a terminator.
+    let len = rng.gen_range(0..8);
+    let key = (0..len)
+      .map(|_| match rng.gen_range(0..4) {
+        0 => 0x00,
+        1 => 0xff,
+        _ => rng.gen(),
+      })
+      .collect::<Vec<u8>>();
+    if !keys.contains(&key) {
+      keys.push(key);
+    }
+  }
+
+  let build_key = |key: &[u8]| {
+    let (l, r) = key.split_at(key.len() / 2);
+    format!(
+      "bytes_concat (bytes_from_hex \"{}\") (bytes_from_base64 \"{}\")",
+      hex::encode(l),
+      base64::encode(r)
+    )
+  };
+  let mut writer = String::from("graph main(root: schema) {\n");
+  for (i, key) in keys.iter().enumerate() {
+    writer += &format!(
+      "s_insert root.items $ build_table(Item) $ m_insert(id) ({}) $ m_insert(n) {} create_map;\n",
+      build_key(key),
+      i
+    );
+  }
+  writer += "}\n";
+
+  let mut reader = String::from("graph main(root: schema): string {\n  r0 = \"\";\n");
+  for (i, key) in keys.iter().enumerate() {
+    // Read back through a slice of a longer byte string.
+    let padded = [&[0xaa][..], key, &[0x00, 0xbb][..]].concat();
+    reader += &format!(
+      "r{} = r{} + \",\" + bytes_to_hex (point_get root.items (bytes_slice h\"{}\" 1 {}))!.id;\n",
+      i + 1,
+      i,
+      hex::encode(&padded),
+      key.len()
+    );
+  }
+  reader += &format!("return r{};\n}}\n", keys.len());
+
+  let scanner = r#"
+  graph main(root: schema): string {
+    return reduce(join) create_map "" root.items;
+  }
+  graph join(ctx: map{}, current: string, item: Item): string {
+    return current + "," + bytes_to_hex item.id;
+  }
+  "#;
+
+  let mut outputs = vec![];
+  simple_test(
+    r#"
+  type Item {
+    @primary
+    id: bytes,
+    n: int64,
+  }
+  export set<Item> items;
+  "#,
+    &[&writer, &reader, scanner],
+    |x| {
+      outputs.push(
+        x.map(|x| unwrap_enum!(&*x, VmValue::Primitive(PrimitiveValue::String(x)) => x.clone())),
+      )
+    },
+  )
+  .await;
+  assert_eq!(outputs.len(), 3);
+
+  let joined = |keys: &[Vec<u8>]| {
+    keys
+      .iter()
+      .map(|x| format!(",{}", hex::encode(x)))
+      .collect::<String>()
+  };
+  assert_eq!(outputs[1].as_ref().unwrap(), &joined(&keys));
+  keys.sort();
+  assert_eq!(outputs[2].as_ref().unwrap(), &joined(&keys));
+}
+
+#[tokio::test]
+async fn time_builtins() {
+  let _ = pretty_env_logger::try_init();
+  let start = SystemTime::now()
+    .duration_since(UNIX_EPOCH)
+    .unwrap()
+    .as_millis() as i64;
+  let mut chkindex = 0usize;
+  simple_test(
+    r#"
+  "#,
+    &[
+      r#"
+      graph main(root: schema): int64 {
+        return time_now;
+      }
+      "#,
+      r#"
+      graph main(root: schema): bool {
+        a = time_now;
+        b = call(now) [];
+        return a == b;
+      }
+      graph now(): int64 {
+        return time_now;
+      }
+      "#,
+      r#"
+      graph main(root: schema): int64 {
+        return time_add_days (time_start_of_day 1634567890123) -2;
+      }
+      "#,
+      r#"
+      graph main(root: schema): int64 {
+        return time_start_of_day -1;
+      }
+      "#,
+      r#"
+      graph main(root: schema): int64 {
+        return time_day_of_week 1634567890123 * 100 + time_day_of_week 0 * 10 + time_day_of_week -1;
+      }
+      "#,
+    ],
+    |x| {
+      let x = x.unwrap();
+      match chkindex {
+        0 => {
+          let now = unwrap_enum!(&*x, VmValue::Primitive(PrimitiveValue::Int64(x)) => *x);
+          let end = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .unwrap()
+            .as_millis() as i64;
+          assert!(now >= start && now <= end);
+        }
+        1 => assert_eq!(*x, VmValue::Bool(true)),
+        2 => assert_eq!(
+          *x,
+          VmValue::Primitive(PrimitiveValue::Int64(1634515200000 - 2 * 86400000))
+        ),
+        3 => assert_eq!(*x, VmValue::Primitive(PrimitiveValue::Int64(-86400000))),
+        4 => assert_eq!(*x, VmValue::Primitive(PrimitiveValue::Int64(143))),
+        _ => unreachable!(),
+      }
+      chkindex += 1;
+    },
+  )
+  .await;
+  assert_eq!(chkindex, 5);
+}
+
+#[tokio::test]
+async fn gen_id() {
+  let _ = pretty_env_logger::try_init();
+  let mut chkindex = 0usize;
+  simple_test(
+    r#"
+  type Item {
+    @primary
+    id: string,
+    name: string,
+  }
+  export set<Item> items;
+  "#,
+    &[
+      r#"
+      graph main(root: schema): string {
+        return gen_id;
+      }
+      "#,
+      r#"
+      graph main(root: schema): bool {
+        a = gen_id;
+        b = gen_id;
+        c = call(id) [];
+        return a != b && a != c && b != c;
+      }
+      graph id(): string {
+        return gen_id;
+      }
+      "#,
+      r#"
+      graph main(root: schema) {
+        s_insert root.items $ build_table(Item) $ m_insert(id) gen_id $ m_insert(name) "a" create_map;
+        s_insert root.items $ build_table(Item) $ m_insert(id) gen_id $ m_insert(name) "b" create_map;
+      }
+      "#,
+      r#"
+      graph main(root: schema): int64 {
+        return s_count root.items;
+      }
+      "#,
+    ],
+    |x| {
+      match chkindex {
+        0 => {
+          let x = x.unwrap();
+          let id = unwrap_enum!(&*x, VmValue::Primitive(PrimitiveValue::String(x)) => x);
+          let parts = id.split('-').map(|x| x.len()).collect::<Vec<_>>();
+          assert_eq!(parts, vec![8, 4, 4, 4, 12]);
+          assert!(id.chars().all(|c| c == '-' || c.is_ascii_hexdigit()));
+          assert_eq!(&id[14..15], "4");
+        }
+        1 => assert_eq!(*x.unwrap(), VmValue::Bool(true)),
+        2 => assert!(x.is_none()),
+        3 => assert_eq!(
+          *x.unwrap(),
+          VmValue::Primitive(PrimitiveValue::Int64(2))
+        ),
+        _ => unreachable!(),
+      }
+      chkindex += 1;
+    },
+  )
+  .await;
+  assert_eq!(chkindex, 4);
+}
+
+#[tokio::test]
+async fn throw_string() {
+  let _ = pretty_env_logger::try_init();
+  let mut ok = false;
+  simple_test_with_error(
+    r#"
+  "#,
+    &[r#"
+    graph main(root: schema) {
+      throw "test error";
+    }
+    "#],
+    |x| {
+      assert_eq!(
+        x.unwrap_err().to_string(),
+        "script thrown error: `test error`"
+      );
+      ok = true;
+    },
+  )
+  .await;
+
+  assert!(ok);
+}
+
+#[tokio::test]
+async fn throw_null() {
+  let _ = pretty_env_logger::try_init();
+  let mut ok = false;
+  simple_test_with_error(
+    r#"
+  "#,
+    &[r#"
+    graph main(root: schema) {
+      throw null<string>;
+    }
+    "#],
+    |x| {
+      assert_eq!(x.unwrap_err().to_string(), "script thrown null");
+      ok = true;
+    },
+  )
+  .await;
+
+  assert!(ok);
+}
+
+#[tokio::test]
+async fn try_catch() {
+  let _ = pretty_env_logger::try_init();
+  let mut outputs = vec![];
+  simple_test_with_error(
+    OPTIONAL_CHAINING_SCHEMA,
+    &[
+      r#"
+    graph main(root: schema): string {
+      s = "";
+      x = try {
+        if s == "" {
+          throw "empty";
+        }
+        return s;
+      } catch (e) {
+        return "caught: " + e;
+      }
+      return x;
+    }
+    "#,
+      r#"
+    graph main(root: schema): string {
+      prefix = "value: ";
+      x = try {
+        return prefix + "ok";
+      } catch (e) {
+        return e;
+      }
+      return x;
+    }
+    "#,
+      // Names of the enclosing graph are captured through nested blocks.
+      r#"
+    graph main(root: schema): string {
+      prefix = "outer: ";
+      x = try {
+        y = try {
+          if true {
+            throw prefix + "inner";
+          }
+          return prefix;
+        } catch (e) {
+          throw e + "!";
+          return e;
+        }
+        return y;
+      } catch (e) {
+        return e;
+      }
+      return x;
+    }
+    "#,
+      r#"
+    graph main(root: schema): string {
+      x = try {
+        return (head $ create_list(Item))!.name;
+      } catch (e) {
+        return e;
+      }
+      return x;
+    }
+    "#,
+      r#"
+    graph main(root: schema): string {
+      try {
+        throw "ignored";
+      } catch (e) {
+      }
+      return "done";
+    }
+    "#,
+    ],
+    |x| outputs.push(x.map(|x| x.unwrap().unwrap_primitive().unwrap_string().clone())),
+  )
+  .await;
+  assert_eq!(outputs.len(), 5);
+  assert_eq!(outputs[0].as_ref().unwrap(), "caught: empty");
+  assert_eq!(outputs[1].as_ref().unwrap(), "value: ok");
+  assert_eq!(outputs[2].as_ref().unwrap(), "outer: inner!");
+  assert_eq!(outputs[3].as_ref().unwrap(), "null value unwrapped");
+  assert_eq!(outputs[4].as_ref().unwrap(), "done");
+}
+
+#[test]
+fn try_catch_rejects_bad_blocks() {
+  let alloc = Bump::new();
+  let ast = parse(&alloc, OPTIONAL_CHAINING_SCHEMA).unwrap();
+  let schema = compile(&ast).unwrap();
+  drop(ast);
+  drop(alloc);
+  let plan = generate_plan_for_schema(&Default::default(), &Default::default(), &schema).unwrap();
+
+  let err = compile_twscript(
+    r#"
+    graph main(root: schema): string {
+      x = try {
+        return "a";
+      } catch (e) {
+      }
+      return x;
+    }
+    "#,
+  )
+  .unwrap_err();
+  assert!(matches!(
+    err.downcast_ref::<TwAsmError>(),
+    Some(TwAsmError::TryCatchReturnMismatch(_))
+  ));
+
+  // A failed block is not rolled back, so it can't have effects.
+  let script = compile_twscript(
+    r#"
+    graph main(root: schema) {
+      try {
+        s_insert root.items $ build_table(Item) $ m_insert(id) "a" $ m_insert(name) "b" create_map;
+      } catch (e) {
+      }
+    }
+    "#,
+  )
+  .unwrap();
+  let vm = TwVm::new(&schema, &plan, &script).unwrap();
+  let err = GlobalTyckContext::new(&vm).unwrap().typeck().unwrap_err();
+  assert!(matches!(
+    err.downcast_ref::<TypeckError>(),
+    Some(TypeckError::EffectInTryBlock(_))
+  ));
+}
+
+const OPTIONAL_CHAINING_SCHEMA: &str = r#"
+  type Item {
+    @primary
+    id: string,
+    name: string,
+  }
+  export set<Item> items;
+"#;
+
+#[tokio::test]
+async fn optional_chaining() {
+  let _ = pretty_env_logger::try_init();
+  let mut outputs = vec![];
+  simple_test_with_error(
+    OPTIONAL_CHAINING_SCHEMA,
+    &[
+      r#"
+    graph main(root: schema): string {
+      return (head $ create_list(Item))?.name ?? "none";
+    }
+    "#,
+      r#"
+    graph main(root: schema): string {
+      m = m_insert(item) (head $ create_list(Item)) create_map;
+      return m.item?.name ?? "none";
+    }
+    "#,
+      // Nullability is not tracked through map fields, so this passes typeck and fails at
+      // runtime.
+      r#"
+    graph main(root: schema): string {
+      m = m_insert(item) (head $ create_list(Item)) create_map;
+      return m.item.name ?? "none";
+    }
+    "#,
+      r#"
+    graph main(root: schema): string {
+      return (head ("a" : create_list(string)))! + "b";
+    }
+    "#,
+      r#"
+    graph main(root: schema): string {
+      return (head $ create_list(Item))!.name;
+    }
+    "#,
+    ],
+    |x| outputs.push(x.map(|x| x.unwrap().unwrap_primitive().unwrap_string().clone())),
+  )
+  .await;
+  assert_eq!(outputs.len(), 5);
+  assert_eq!(outputs[0].as_ref().unwrap(), "none");
+  assert_eq!(outputs[1].as_ref().unwrap(), "none");
+  assert!(matches!(
+    outputs[2].as_ref().unwrap_err().downcast_ref::<ExecError>(),
+    Some(ExecError::NullUnwrapped)
+  ));
+  assert_eq!(outputs[3].as_ref().unwrap(), "ab");
+  assert!(matches!(
+    outputs[4].as_ref().unwrap_err().downcast_ref::<ExecError>(),
+    Some(ExecError::NullUnwrapped)
+  ));
+}
+
+#[test]
+fn optional_chaining_typeck() {
+  let alloc = Bump::new();
+  let ast = parse(&alloc, OPTIONAL_CHAINING_SCHEMA).unwrap();
+  let schema = compile(&ast).unwrap();
+  drop(ast);
+  drop(alloc);
+  let plan = generate_plan_for_schema(&Default::default(), &Default::default(), &schema).unwrap();
+
+  let typeck = |code: &str| {
+    let script = compile_twscript(code).unwrap();
+    let vm = TwVm::new(&schema, &plan, &script).unwrap();
+    let nullable = GlobalTyckContext::new(&vm)
+      .unwrap()
+      .typeck()
+      .map(|x| x.graphs.into_iter().map(|x| x.nullable).collect::<Vec<_>>());
+    nullable
+  };
+
+  // The head of a list may be null.
+  let err = typeck(
+    r#"
+    graph main(root: schema): string {
+      return (head $ create_list(Item)).name;
+    }
+    "#,
+  )
+  .unwrap_err();
+  assert!(matches!(
+    err.downcast_ref::<TypeckError>(),
+    Some(TypeckError::NullableParamNotChained(_))
+  ));
+
+  // Through calls, too.
+  let err = typeck(
+    r#"
+    graph main(root: schema): string {
+      return (call(first) [create_list(Item)]).name;
+    }
+    graph first(x: list<Item>): Item {
+      return head x;
+    }
+    "#,
+  )
+  .unwrap_err();
+  assert!(matches!(
+    err.downcast_ref::<TypeckError>(),
+    Some(TypeckError::NullableParamNotChained(_))
+  ));
+
+  // Optional chaining propagates nullability, and `??` ends it.
+  let code = r#"
+    graph main(root: schema): string {
+      item = point_get root.items "a";
+      name = item.name;
+      first = head $ create_list(Item);
+      first_name = first?.name + "!";
+      return first_name ?? name;
+    }
+  "#;
+  let nullable = typeck(code).unwrap();
+  let script = compile_twscript(code).unwrap();
+  let g = &script.graphs[0];
+  let node_of = |n: TwGraphNode| {
+    g.nodes
+      .iter()
+      .position(|x| std::mem::discriminant(&x.0) == std::mem::discriminant(&n))
+      .unwrap()
+  };
+  assert!(!nullable[0][node_of(TwGraphNode::GetSetElement)]);
+  assert!(nullable[0][node_of(TwGraphNode::ListHead)]);
+  assert!(nullable[0][node_of(TwGraphNode::Add)]);
+  assert!(!g.is_optional_chained(node_of(TwGraphNode::IsNull)));
+}
+
+#[test]
+fn script_params() {
+  let alloc = Bump::new();
+  let ast = parse(&alloc, OPTIONAL_CHAINING_SCHEMA).unwrap();
+  let schema = compile(&ast).unwrap();
+  drop(ast);
+  drop(alloc);
+  let plan = generate_plan_for_schema(&Default::default(), &Default::default(), &schema).unwrap();
+
+  let typeck = |code: &str| {
+    let script = compile_twscript(code).unwrap();
+    let vm = TwVm::new(&schema, &plan, &script).unwrap();
+    let res = GlobalTyckContext::new(&vm).unwrap().typeck().map(|_| ());
+    res
+  };
+
+  let code = r#"
+    param {
+      limit: int64 = 10,
+      prefix: string,
+    }
+    export graph main(root: schema, prefix: string, limit: int64): string {
+      return prefix;
+    }
+  "#;
+  typeck(code).unwrap();
+  let script = compile_twscript(code).unwrap();
+  assert_eq!(
+    script
+      .params
+      .iter()
+      .map(|x| &x.name[..])
+      .collect::<Vec<_>>(),
+    vec!["limit", "prefix"]
+  );
+  assert!(script.params[0].default.is_some());
+  assert!(script.params[1].default.is_none());
+  assert_eq!(
+    script.graphs[0].param_names,
+    vec!["root", "prefix", "limit"]
+  );
+
+  let err = compile_twscript(
+    r#"
+    param { a: int64, a: string }
+    "#,
+  )
+  .unwrap_err();
+  assert!(matches!(
+    err.downcast_ref::<TwAsmError>(),
+    Some(TwAsmError::DuplicateScriptParam(_))
+  ));
+
+  let err = typeck(
+    r#"
+    param { limit: int64 = "10" }
+    "#,
+  )
+  .unwrap_err();
+  assert!(matches!(
+    err.downcast_ref::<TypeckError>(),
+    Some(TypeckError::ScriptParamDefaultTypeMismatch(_))
+  ));
+
+  let err = typeck(
+    r#"
+    param { limit: int64 = 10 }
+    export graph main(root: schema, limit: string): string {
+      return limit;
+    }
+    "#,
+  )
+  .unwrap_err();
+  assert!(matches!(
+    err.downcast_ref::<TypeckError>(),
+    Some(TypeckError::ScriptParamTypeMismatch(..))
+  ));
+}
+
+#[tokio::test]
+async fn consts_and_env() {
+  let alloc = Bump::new();
+  let ast = parse(&alloc, OPTIONAL_CHAINING_SCHEMA).unwrap();
+  let schema = compile(&ast).unwrap();
+  drop(ast);
+  drop(alloc);
+  let plan = generate_plan_for_schema(&Default::default(), &Default::default(), &schema).unwrap();
+  let kv = create_kv();
+
+  let script = compile_twscript(
+    r#"
+    const SEP = ":";
+    const LIMIT = 10;
+    graph main(root: schema): string {
+      e = env;
+      x = e.namespace_id + SEP;
+      y = x + e.query_script_id;
+      return y + SEP + e.token_id + SEP + e.role;
+    }
+    graph limit(): int64 {
+      LIMIT = 3;
+      return LIMIT;
+    }
+    graph nested(): int64 {
+      return call(limit) [] + LIMIT;
+    }
+    "#,
+  )
+  .unwrap();
+  let vm = TwVm::new(&schema, &plan, &script).unwrap();
+  let type_info = GlobalTyckContext::new(&vm).unwrap().typeck().unwrap();
+  let root = Arc::new(generate_root_map(&schema, &plan).unwrap());
+
+  let mut executor = Executor::new(&vm, &*kv, &type_info);
+  let output = executor
+    .run_graph(0, &[root.clone()])
+    .await
+    .unwrap()
+    .unwrap();
+  assert_eq!(
+    *output,
+    VmValue::Primitive(PrimitiveValue::String(":::".into()))
+  );
+
+  executor.set_env(&ExecEnv {
+    namespace_id: "ns".into(),
+    query_script_id: "qs".into(),
+    token_id: "t".into(),
+    role: "admin".into(),
+    ..Default::default()
+  });
+  let output = executor.run_graph(0, &[root]).await.unwrap().unwrap();
+  assert_eq!(
+    *output,
+    VmValue::Primitive(PrimitiveValue::String("ns:qs:t:admin".into()))
+  );
+
+  // Nodes shadow constants.
+  let output = executor.run_graph(2, &[]).await.unwrap().unwrap();
+  assert_eq!(*output, VmValue::Primitive(PrimitiveValue::Int64(13)));
+
+  let err = compile_twscript(
+    r#"
+    const A = 1;
+    const A = 2;
+    "#,
+  )
+  .unwrap_err();
+  assert!(matches!(
+    err.downcast_ref::<TwAsmError>(),
+    Some(TwAsmError::DuplicateConst(_))
+  ));
+}
+
+#[tokio::test]
+async fn partial_table_replacement() {
+  let _ = pretty_env_logger::try_init();
+  let mut chkindex = 0usize;
+  simple_test(
+    r#"
+    type Table {
+      fields: Fields,
+    }
+    type Fields {
+      a: int64,
+      b: int64,
+    }
+    export Table t;
+  "#,
+    &[
+      r#"
+    graph main(root: schema) {
+      t = build_table(Fields) $
+        m_insert(a) 1 $
+        m_insert(b) 2 $
+        create_map;
+      t_insert(fields) root.t t;
+    }
+    "#,
+      r#"
+    graph main(root: schema): map {
+      a: int64,
+      b: int64,
+    } {
+      return m_insert(a) root.t.fields.a $
+        m_insert(b) root.t.fields.b $
+        create_map;
+    }
+    "#,
+      r#"
+    graph main(root: schema) {
+      t = build_table(Fields) $
+        m_insert(a) 3 $
+        create_map;
+      t_insert(fields) root.t t;
+    }
+    "#,
+      r#"
+    graph main(root: schema): map {
+      a: int64,
+      b: int64,
+    } {
+      return m_insert(a) root.t.fields.a $
+        m_insert(b) root.t.fields.b $
+        create_map;
+    }
+    "#,
+    ],
+    |x| {
+      match chkindex {
+        0 => {}
+        1 => match &**x.as_ref().unwrap() {
+          VmValue::Map(x) => {
+            assert_eq!(
+              **x.elements.get("a").unwrap(),
+              VmValue::Primitive(PrimitiveValue::Int64(1))
+            );
+            assert_eq!(
+              **x.elements.get("b").unwrap(),
+              VmValue::Primitive(PrimitiveValue::Int64(2))
+            );
+          }
+          _ => unreachable!(),
+        },
+        2 => {}
+        3 => match &**x.as_ref().unwrap() {
+          VmValue::Map(x) => {
+            assert_eq!(
+              **x.elements.get("a").unwrap(),
+              VmValue::Primitive(PrimitiveValue::Int64(3))
+            );
+            assert_eq!(
+              **x.elements.get("b").unwrap(),
+              VmValue::Null(VmType::Primitive(PrimitiveType::Int64))
+            );
+          }
+          _ => unreachable!(),
+        },
+        _ => unreachable!(),
+      }
+      chkindex += 1;
+    },
+  )
+  .await;
+
+  assert_eq!(chkindex, 4);
+}
+
+#[tokio::test]
+async fn resident_copy() {
+  let _ = pretty_env_logger::try_init();
+  let mut chkindex = 0usize;
+  simple_test(
+    r#"
+    type Item {
+      @primary
+      id: string,
+      value: int64,
+      tags: set<Tag>,
+      next: Item,
+    }
+    type Tag {
+      @primary
+      name: string,
+    }
+    type Store {
+      items: set<Item>,
+      backup: set<Item>,
+      first: Item,
+    }
+    export Store s;
+  "#,
+    &[
+      r#"
+    graph main(root: schema) {
+      tags = (build_table(Tag) $ m_insert(name) "x" create_map) : create_list(Tag);
+      s_insert root.s.items $ build_table(Item)
+        $ m_insert(id) "a"
+        $ m_insert(value) 1
+        $ m_insert(tags) (build_set tags)
+        $ m_insert(next) (build_table(Item) $ m_insert(id) "a2" $ m_insert(value) 3 create_map)
+        create_map;
+      s_insert root.s.items $ build_table(Item)
+        $ m_insert(id) "b"
+        $ m_insert(value) 2
+        create_map;
+    }
+    "#,
+      r#"
+    graph main(root: schema) {
+      t_insert(backup) root.s root.s.items;
+      t_insert(first) root.s (point_get root.s.items "a");
+      t_insert(next) (point_get root.s.items "b") (point_get root.s.items "b");
+    }
+    "#,
+      r#"
+    graph main(root: schema): map {
+      backup_a: int64,
+      backup_b: int64,
+      backup_a_next: int64,
+      first: int64,
+      first_next: int64,
+      first_next_next: bool,
+      first_tag: bool,
+      b_next: int64,
+      b_next_next: bool,
+    } {
+      return m_insert(backup_a) (point_get root.s.backup "a").value
+        $ m_insert(backup_b) (point_get root.s.backup "b").value
+        $ m_insert(backup_a_next) (point_get root.s.backup "a").next.value
+        $ m_insert(first) root.s.first.value
+        $ m_insert(first_next) root.s.first.next.value
+        $ m_insert(first_next_next) (is_present root.s.first.next.next)
+        $ m_insert(first_tag) (is_present $ point_get root.s.first.tags "x")
+        $ m_insert(b_next) (point_get root.s.items "b").next.value
+        $ m_insert(b_next_next) (is_present (point_get root.s.items "b").next.next)
+        create_map;
+    }
+    "#,
+    ],
+    |x| {
+      match chkindex {
+        0 | 1 => {}
+        2 => {
+          let x = x.as_ref().unwrap().unwrap_map();
+          let int = |k: &str| match &**x.elements.get(k).unwrap() {
+            VmValue::Primitive(PrimitiveValue::Int64(x)) => *x,
+            x => panic!("unexpected value for {}: {:?}", k, x),
+          };
+          let boolean = |k: &str| x.elements.get(k).unwrap().unwrap_bool();
+          assert_eq!(int("backup_a"), 1);
+          assert_eq!(int("backup_b"), 2);
+          assert_eq!(int("backup_a_next"), 3);
+          assert_eq!(int("first"), 1);
+          assert_eq!(int("first_next"), 3);
+          assert_eq!(boolean("first_next_next"), false);
+          assert_eq!(boolean("first_tag"), true);
+          assert_eq!(int("b_next"), 2);
+          assert_eq!(boolean("b_next_next"), false);
+        }
+        _ => unreachable!(),
+      }
+      chkindex += 1;
+    },
+  )
+  .await;
+
+  assert_eq!(chkindex, 3);
+}
+
+#[tokio::test]
+async fn field_acl() {
+  let _ = pretty_env_logger::try_init();
+  let alloc = Bump::new();
+  let ast = parse(
+    &alloc,
+    r#"
+  type User {
+    @primary
+    id: string,
+    name: string,
+    @acl("admin", "hr")
+    email: string,
+    @acl("admin")
+    salary: int64,
+  }
+  export set<User> users;
+  "#,
+  )
+  .unwrap();
+  let schema = compile(&ast).unwrap();
+  drop(ast);
+  drop(alloc);
+  let plan = generate_plan_for_schema(&Default::default(), &Default::default(), &schema).unwrap();
+  let kv = create_kv();
+
+  let scripts = [
+    r#"
+    graph main(root: schema) {
+      s_insert root.users $ build_table(User) $ m_insert(id) "a" $ m_insert(name) "alice" $ m_insert(email) "a@example.com" $ m_insert(salary) 100 create_map;
+    }
+    "#,
+    r#"
+    type UserMap = map {
+      name: string,
+      email: string,
+    };
+    graph main(root: schema): map {
+      name: string,
+      email: string,
+      salary: int64,
+      users: list<UserMap>,
+    } {
+      u = point_get root.users "a";
+      users = reduce(fold_users) create_map create_list(UserMap) root.users;
+      return m_insert(name) u.name $ m_insert(email) u.email $ m_insert(salary) u.salary $ m_insert(users) users create_map;
+    }
+    graph fold_users(_unused: map{}, current: list<UserMap>, item: User): list<UserMap> {
+      return (m_insert(name) item.name $ m_insert(email) item.email create_map) : current;
+    }
+    "#,
+  ];
+
+  for (i, code) in scripts.iter().enumerate() {
+    let script = compile_twscript(code).unwrap();
+    let vm = TwVm::new(&schema, &plan, &script).unwrap();
+    let type_info = GlobalTyckContext::new(&vm).unwrap().typeck().unwrap();
+    let mut executor = Executor::new(&vm, &*kv, &type_info);
+    let output = executor
+      .run_graph(0, &[Arc::new(generate_root_map(&schema, &plan).unwrap())])
+      .await
+      .unwrap();
+    if i == 0 {
+      assert!(type_info.graphs[0].output_acl.is_unrestricted());
+      continue;
+    }
+
+    let output = output.unwrap();
+    let acl = &type_info.graphs[0].output_acl;
+    for (role, expected_keys, expected_user_keys) in [
+      (None, &["name", "users"][..], &["name"][..]),
+      (
+        Some("hr"),
+        &["email", "name", "users"][..],
+        &["email", "name"][..],
+      ),
+      (
+        Some("admin"),
+        &["email", "name", "salary", "users"][..],
+        &["email", "name"][..],
+      ),
+    ] {
+      let serialized =
+        SerializedVmValue::encode_with_acl(&*output, &Default::default(), acl, role).unwrap();
+      let m = serialized.try_unwrap_map(&[]).unwrap();
+      assert_eq!(m.keys().collect::<Vec<_>>(), expected_keys);
+      let users = m.get("users").unwrap().try_unwrap_list().unwrap();
+      assert_eq!(users.len(), 1);
+      assert_eq!(
+        users[0]
+          .try_unwrap_map(&[])
+          .unwrap()
+          .keys()
+          .collect::<Vec<_>>(),
+        expected_user_keys
+      );
+    }
+  }
+}
+
+#[test]
+fn cache_directive() {
+  let alloc = Bump::new();
+  let ast = parse(
+    &alloc,
+    r#"
+  type Item {
+    @primary
+    id: string,
+    value: int64,
+  }
+  export set<Item> items;
+  "#,
+  )
+  .unwrap();
+  let schema = compile(&ast).unwrap();
+  drop(ast);
+  drop(alloc);
+  let plan = generate_plan_for_schema(&Default::default(), &Default::default(), &schema).unwrap();
+
+  let script = compile_twscript(
+    r#"
+    @cache(ttl = 30s, key = params)
+    export graph get_item(root: schema, id: string) {
+      return (point_get root.items id).value;
+    }
+    @cache(ttl = 500ms, key = global)
+    export graph get_a(root: schema) {
+      return call(get_item) [root, "a"];
+    }
+    @cache(ttl = 2m)
+    export graph get_b(root: schema) {
+      return call(get_item) [root, "b"];
+    }
+    export graph uncached(root: schema) {
+      return call(get_item) [root, "a"];
+    }
+    "#,
+  )
+  .unwrap();
+  assert_eq!(
+    script.graphs[0].cache,
+    Some(TwCacheDirective {
+      ttl_ms: 30000,
+      key: TwCacheKey::Params
+    })
+  );
+  assert_eq!(
+    script.graphs[1].cache,
+    Some(TwCacheDirective {
+      ttl_ms: 500,
+      key: TwCacheKey::Global
+    })
+  );
+  assert_eq!(
+    script.graphs[2].cache,
+    Some(TwCacheDirective {
+      ttl_ms: 120000,
+      key: TwCacheKey::Params
+    })
+  );
+  assert!(script.graphs[3].cache.is_none());
+  TwVm::new(&schema, &plan, &script).unwrap();
+
+  for bad in [
+    "@cache(key = params) graph a() {}",
+    "@cache(ttl = 1s, key = everything) graph a() {}",
+    "@cache(ttl = 1s) @cache(ttl = 2s) graph a() {}",
+    "@memoize(ttl = 1s) graph a() {}",
+    "@relaxed_isolation(on) graph a() {}",
+  ] {
+    assert!(compile_twscript(bad).is_err());
+  }
+
+  // Effectful graphs cannot be cached.
+  let script = compile_twscript(
+    r#"
+    @cache(ttl = 1s)
+    export graph add_item(root: schema) {
+      s_insert root.items $ build_table(Item) $ m_insert(id) "a" $ m_insert(value) 1 create_map;
+    }
+    "#,
+  )
+  .unwrap();
+  assert!(TwVm::new(&schema, &plan, &script).is_err());
+}
+
+#[tokio::test]
+async fn read_only_graphs() {
+  let _ = pretty_env_logger::try_init();
+  let fixture = fixtures::get("indexed").unwrap();
+  let (schema, plan) = fixture.compile_schema_with_plan().unwrap();
+  let kv = create_kv();
+
+  let script = compile_twscript(
+    r#"
+    export readonly graph count(root: schema): int64 {
+      return s_count root.items;
+    }
+    export graph add(root: schema) {
+      s_insert root.items $ build_table(Item) $ m_insert(id) "a" $ m_insert(name) "x" create_map;
+    }
+    "#,
+  )
+  .unwrap();
+  assert!(script.graphs[0].read_only);
+  assert!(!script.graphs[1].read_only);
+  let vm = TwVm::new(&schema, &plan, &script).unwrap();
+  let type_info = GlobalTyckContext::new(&vm).unwrap().typeck().unwrap();
+  let root = Arc::new(generate_root_map(&schema, &plan).unwrap());
+  let mut executor = Executor::new(&vm, &*kv, &type_info);
+  executor.run_graph(1, &[root.clone()]).await.unwrap();
+  let count = executor
+    .run_graph(0, &[root.clone()])
+    .await
+    .unwrap()
+    .unwrap();
+  assert_eq!(*count, VmValue::Primitive(PrimitiveValue::Int64(1)));
+
+  executor.set_read_only(true);
+  executor.run_graph(0, &[root.clone()]).await.unwrap();
+  let e = executor.run_graph(1, &[root]).await.unwrap_err();
+  assert!(matches!(
+    e.downcast_ref::<ExecError>(),
+    Some(ExecError::GraphNotReadOnly(_))
+  ));
+
+  // Effects are not allowed in subgraphs of readonly graphs either.
+  let script = compile_twscript(
+    r#"
+    readonly graph main(root: schema) {
+      call(add) [root];
+    }
+    graph add(root: schema) {
+      s_delete root.items "a";
+    }
+    "#,
+  )
+  .unwrap();
+  assert!(TwVm::new(&schema, &plan, &script).is_err());
+}
+
+#[tokio::test]
+async fn tracing() {
+  let alloc = Bump::new();
+  let ast = parse(
+    &alloc,
+    r#"
+  type Item {
+    @primary
+    id: string,
+    value: int64,
+  }
+  export set<Item> items;
+  "#,
+  )
+  .unwrap();
+  let schema = compile(&ast).unwrap();
+  drop(ast);
+  drop(alloc);
+  let plan = generate_plan_for_schema(&Default::default(), &Default::default(), &schema).unwrap();
+  let kv = create_kv();
+
+  let script = compile_twscript(
+    r#"
+    graph main(root: schema): int64 {
+      return call(add) [1, 2] + call(add) [3, 4];
+    }
+    graph add(a: int64, b: int64): int64 {
+      return a + b;
+    }
+    "#,
+  )
+  .unwrap();
+  let vm = TwVm::new(&schema, &plan, &script).unwrap();
+  let type_info = GlobalTyckContext::new(&vm).unwrap().typeck().unwrap();
+  let mut executor = Executor::new(&vm, &*kv, &type_info);
+  assert!(executor.take_trace().is_none());
+  executor.enable_tracing();
+  let output = executor
+    .run_graph(0, &[Arc::new(generate_root_map(&schema, &plan).unwrap())])
+    .await
+    .unwrap()
+    .unwrap();
+  assert_eq!(*output, VmValue::Primitive(PrimitiveValue::Int64(10)));
+
+  let trace = executor.take_trace().unwrap();
+  assert!(trace
+    .events
+    .iter()
+    .enumerate()
+    .all(|(i, x)| x.seq == i as u64));
+
+  // Two invocations of `add`, each with an `Add` node.
+  let add_events = trace
+    .events
+    .iter()
+    .filter(|x| x.graph == 1 && x.output_type.as_deref() == Some("int64"))
+    .filter(|x| x.inputs.len() == 2)
+    .collect::<Vec<_>>();
+  assert_eq!(add_events.len(), 2);
+  assert_ne!(add_events[0].invocation, add_events[1].invocation);
+  let mut outputs = add_events
+    .iter()
+    .map(|x| x.output.clone().unwrap())
+    .collect::<Vec<_>>();
+  outputs.sort();
+  assert_eq!(outputs, vec!["3", "7"]);
+  for x in &add_events {
+    assert_eq!(
+      x.id,
+      script.graphs.len() + script.graphs[0].nodes.len() + x.node as usize
+    );
+  }
+
+  let last = trace.events.last().unwrap();
+  assert_eq!(last.graph, 0);
+  assert_eq!(last.output.as_deref(), Some("10"));
+}
+
+#[tokio::test]
+async fn topological_scheduler() {
+  let _ = pretty_env_logger::try_init();
+  let alloc = Bump::new();
+  let ast = parse(&alloc, "").unwrap();
+  let schema = compile(&ast).unwrap();
+  drop(ast);
+  drop(alloc);
+  let plan = generate_plan_for_schema(&Default::default(), &Default::default(), &schema).unwrap();
+  let kv = create_kv();
+  let root_map = Arc::new(generate_root_map(&schema, &plan).unwrap());
+
+  let script = compile_twscript(
+    r#"
+    graph main(root: schema): int64 {
+      return call(fib) [10] + reduce(add) create_map 0 (1 : 2 : 3 : create_list(int64));
+    }
+    graph fib(x: int64): int64 {
+      if x == 1 || x == 2 {
+        v1 = 1;
+      } else {
+        v2 = call(fib) [x - 1] + call(fib) [x - 2];
+      }
+      return select v1 v2;
+    }
+    graph add(_unused: map{}, current: int64, x: int64): int64 {
+      return current + x;
+    }
+    "#,
+  )
+  .unwrap();
+  let vm = TwVm::new(&schema, &plan, &script).unwrap();
+  let type_info = GlobalTyckContext::new(&vm).unwrap().typeck().unwrap();
+
+  let mut traces = vec![];
+  for scheduler in [
+    Scheduler::Dataflow,
+    Scheduler::Topological,
+    Scheduler::Topological,
+  ] {
+    let mut executor = Executor::new(&vm, &*kv, &type_info);
+    executor.set_config(ExecConfig {
+      scheduler,
+      ..Default::default()
+    });
+    executor.enable_tracing();
+    let output = executor
+      .run_graph(0, &[root_map.clone()])
+      .await
+      .unwrap()
+      .unwrap();
+    assert_eq!(*output, VmValue::Primitive(PrimitiveValue::Int64(61)));
+    traces.push(executor.take_trace().unwrap());
+  }
+
+  // Both schedulers fire the same nodes.
+  let fired = |events: &[TraceEvent]| {
+    let mut x = events
+      .iter()
+      .map(|x| (x.graph, x.node, x.output.clone()))
+      .collect::<Vec<_>>();
+    x.sort();
+    x
+  };
+  assert_eq!(fired(&traces[0].events), fired(&traces[1].events));
+
+  // The topological scheduler fires them in the same order on each run, and the nodes of a
+  // graph invocation in index order.
+  let order = |events: &[TraceEvent]| {
+    events
+      .iter()
+      .map(|x| (x.graph, x.node, x.invocation))
+      .collect::<Vec<_>>()
+  };
+  assert_eq!(order(&traces[1].events), order(&traces[2].events));
+  let main_invocation = traces[1].events.last().unwrap().invocation;
+  let main_nodes = traces[1]
+    .events
+    .iter()
+    .filter(|x| x.graph == 0 && x.invocation == main_invocation)
+    .map(|x| x.node)
+    .collect::<Vec<_>>();
+  assert!(main_nodes.windows(2).all(|x| x[0] < x[1]));
+}
+
+#[tokio::test]
+async fn deferred_effects() {
+  let _ = pretty_env_logger::try_init();
+  let alloc = Bump::new();
+  let schema = compile(
+    &parse(
+      &alloc,
+      r#"
+  type Item {
+    @primary
+    id: string,
+    n: int64,
+  }
+  export set<Item> items;
+  "#,
+    )
+    .unwrap(),
+  )
+  .unwrap();
+  let plan = generate_plan_for_schema(&Default::default(), &Default::default(), &schema).unwrap();
+  let script = compile_twscript(
+    r#"
+    graph main(root: schema): int64 {
+      defer {
+        s_insert root.items $ build_table(Item) $ m_insert(id) "c" $ m_insert(n) 3 create_map;
+        n = 2;
+      }
+      s_insert root.items $ build_table(Item) $ m_insert(id) "a" $ m_insert(n) 1 create_map;
+      if n == 2 {
+        s_insert root.items $ build_table(Item) $ m_insert(id) "d" $ m_insert(n) 4 create_map;
+      }
+      s_insert root.items $ build_table(Item) $ m_insert(id) "b" $ m_insert(n) 2 create_map;
+      return n;
+    }
+    "#,
+  )
+  .unwrap();
+
+  // Statements that use deferred values are deferred too.
+  let g = &script.graphs[0];
+  let effects = (0..g.nodes.len())
+    .filter(|x| g.nodes[*x].0.is_effect())
+    .map(|x| g.is_deferred(x))
+    .collect::<Vec<_>>();
+  assert_eq!(effects, vec![true, false, true, false]);
+  assert!(g.is_deferred(g.output.unwrap() as usize));
+
+  let vm = TwVm::new(&schema, &plan, &script).unwrap();
+  let type_info = GlobalTyckContext::new(&vm).unwrap().typeck().unwrap();
+  let key = |x: &str| PrimitiveValue::String(x.into());
+  for scheduler in [Scheduler::Dataflow, Scheduler::Topological] {
+    let kv = create_kv();
+    let mut executor = Executor::new(&vm, &*kv, &type_info);
+    executor.set_config(ExecConfig {
+      scheduler,
+      ..Default::default()
+    });
+    executor.enable_change_tracking();
+    let output = executor
+      .run_graph(0, &[Arc::new(generate_root_map(&schema, &plan).unwrap())])
+      .await
+      .unwrap()
+      .unwrap();
+    assert_eq!(*output, VmValue::Primitive(PrimitiveValue::Int64(2)));
+
+    // The deferred statements run after all others, and statements that depend on deferred
+    // values after those.
+    let changes = executor
+      .take_changes()
+      .unwrap()
+      .into_iter()
+      .map(|x| x.kind)
+      .collect::<Vec<_>>();
+    assert_eq!(
+      &changes[2..],
+      &[ChangeKind::Insert(key("c")), ChangeKind::Insert(key("d"))]
+    );
+    let mut first = changes[..2].to_vec();
+    first.sort_by_key(|x| format!("{:?}", x));
+    assert_eq!(
+      first,
+      vec![ChangeKind::Insert(key("a")), ChangeKind::Insert(key("b"))]
+    );
+  }
+}
+
+#[tokio::test]
+async fn bulk_delete() {
+  let _ = pretty_env_logger::try_init();
+  let alloc = Bump::new();
+  let ast = parse(
+    &alloc,
+    r#"
+  type Item {
+    @primary
+    id: string,
+    @unique
+    name: string,
+  }
+  type App {
+    items: set<Item>,
+  }
+  export App app;
+  "#,
+  )
+  .unwrap();
+  let schema = compile(&ast).unwrap();
+  drop(ast);
+  drop(alloc);
+  let plan = generate_plan_for_schema(&Default::default(), &Default::default(), &schema).unwrap();
+  let kv = create_kv();
+
+  let run = |code: &'static str| {
+    let schema = &schema;
+    let plan = &plan;
+    let kv = &kv;
+    async move {
+      let script = compile_twscript(code).unwrap();
+      let vm = TwVm::new(schema, plan, &script).unwrap();
+      let type_info = GlobalTyckContext::new(&vm).unwrap().typeck().unwrap();
+      let mut executor = Executor::new(&vm, &**kv, &type_info);
+      executor
+        .run_graph(0, &[Arc::new(generate_root_map(schema, plan).unwrap())])
+        .await
+        .unwrap()
+        .map(|x| x.unwrap_primitive().clone())
+    }
+  };
+
+  run(
+    r#"
+    graph main(root: schema) {
+      s_insert root.app.items $ build_table(Item) $ m_insert(id) "a" $ m_insert(name) "alice" create_map;
+      s_insert root.app.items $ build_table(Item) $ m_insert(id) "b" $ m_insert(name) "bob" create_map;
+      s_insert root.app.items $ build_table(Item) $ m_insert(id) "c" $ m_insert(name) "carol" create_map;
+    }
+    "#,
+  )
+  .await;
+
+  let script = compile_twscript("").unwrap();
+  let vm = TwVm::new(&schema, &plan, &script).unwrap();
+  let type_info = GlobalTyckContext::new(&vm).unwrap().typeck().unwrap();
+  let executor = Executor::new(&vm, &*kv, &type_info);
+  let outcomes = executor
+    .bulk_delete(
+      "app.items",
+      &[
+        SerializedVmValue::String("a".into()),
+        SerializedVmValue::String("x".into()),
+        SerializedVmValue::Null(None),
+        SerializedVmValue::String("c".into()),
+      ],
+      2,
+    )
+    .await
+    .unwrap();
+  assert_eq!(outcomes[0], BulkDeleteOutcome::Deleted);
+  assert_eq!(outcomes[1], BulkDeleteOutcome::NotFound);
+  assert!(matches!(outcomes[2], BulkDeleteOutcome::Failed(_)));
+  assert_eq!(outcomes[3], BulkDeleteOutcome::Deleted);
+  assert!(executor.bulk_delete("app", &[], 2).await.is_err());
+  assert!(executor.bulk_delete("app.missing", &[], 2).await.is_err());
+
+  assert_eq!(
+    run(
+      r#"
+      graph main(root: schema): int64 {
+        return s_count root.app.items;
+      }
+      "#,
+    )
+    .await,
+    Some(PrimitiveValue::Int64(1))
+  );
+
+  // Index entries of deleted members are gone too, so the unique names can be reused.
+  run(
+    r#"
+    graph main(root: schema) {
+      s_insert root.app.items $ build_table(Item) $ m_insert(id) "d" $ m_insert(name) "alice" create_map;
+    }
+    "#,
+  )
+  .await;
+}
+
+#[tokio::test]
+async fn reduce_without_effects_matches_traced_run() {
+  let _ = pretty_env_logger::try_init();
+  let alloc = Bump::new();
+  let ast = parse(
+    &alloc,
+    r#"
+  type Item {
+    @primary
+    id: int64,
+    value: int64,
+  }
+  export set<Item> items;
+  "#,
+  )
+  .unwrap();
+  let schema = compile(&ast).unwrap();
+  drop(ast);
+  drop(alloc);
+  let plan = generate_plan_for_schema(&Default::default(), &Default::default(), &schema).unwrap();
+  let kv = create_kv();
+  let root_map = Arc::new(generate_root_map(&schema, &plan).unwrap());
+
+  let writer = compile_twscript(
+    r#"
+    graph main(root: schema) {
+      reduce(insert_item) root.items 0 $ call(gen_numbers) [30];
+    }
+    graph insert_item(items: set<Item>, current: int64, n: int64): int64 {
+      s_insert items $ build_table(Item) $ m_insert(id) n $ m_insert(value) (n * 7 % 11) create_map;
+      return current;
+    }
+    graph gen_numbers(n: int64): list<int64> {
+      if n == 0 {
+        r1 = create_list(int64);
+      } else {
+        r2 = n : call(gen_numbers) [n - 1];
+      }
+      return select r1 r2;
+    }
+    "#,
+  )
+  .unwrap();
+  let vm = TwVm::new(&schema, &plan, &writer).unwrap();
+  assert!(!vm.is_graph_read_only(1));
+  let type_info = GlobalTyckContext::new(&vm).unwrap().typeck().unwrap();
+  Executor::new(&vm, &*kv, &type_info)
+    .run_graph(0, &[root_map.clone()])
+    .await
+    .unwrap();
+
+  let reader = compile_twscript(
+    r#"
+    graph main(root: schema): int64 {
+      return reduce(f) create_map 0 root.items;
+    }
+    graph f(_unused: map{}, current: int64, item: Item): int64 {
+      if current > 500 {
+        r1 = null<int64>;
+      } else {
+        if item.value % 3 == 0 {
+          r2 = current + call(triple) [item.value];
+        } else {
+          r3 = current + reduce(add) create_map 0 (item.value : item.id : create_list(int64));
+        }
+      }
+      return select r1 $ select r2 r3;
+    }
+    graph triple(x: int64): int64 {
+      return x * 3;
+    }
+    graph add(_unused: map{}, current: int64, x: int64): int64 {
+      return current + x;
+    }
+    "#,
+  )
+  .unwrap();
+  let vm = TwVm::new(&schema, &plan, &reader).unwrap();
+  assert!(vm.is_graph_read_only(1));
+  let type_info = GlobalTyckContext::new(&vm).unwrap().typeck().unwrap();
+
+  let mut expected = 0i64;
+  for id in 1..=30i64 {
+    if expected > 500 {
+      break;
+    }
+    let value = id * 7 % 11;
+    expected += if value % 3 == 0 {
+      value * 3
+    } else {
+      value + id
+    };
+  }
+
+  // Tracing forces reducers through the configured scheduler.
+  for (tracing, scheduler) in [
+    (false, Scheduler::Dataflow),
+    (true, Scheduler::Dataflow),
+    (true, Scheduler::Topological),
+  ] {
+    let mut executor = Executor::new(&vm, &*kv, &type_info);
+    executor.set_config(ExecConfig {
+      scheduler,
+      ..Default::default()
+    });
+    if tracing {
+      executor.enable_tracing();
+    }
+    let output = executor
+      .run_graph(0, &[root_map.clone()])
+      .await
+      .unwrap()
+      .unwrap();
+    assert_eq!(*output, VmValue::Primitive(PrimitiveValue::Int64(expected)));
+  }
+}
+
+#[tokio::test]
+async fn trace_records_kv_usage() {
+  let _ = pretty_env_logger::try_init();
+  let alloc = Bump::new();
+  let ast = parse(
+    &alloc,
+    r#"
+  type Item {
+    @primary
+    id: string,
+    name: string,
+  }
+  export set<Item> items;
+  "#,
+  )
+  .unwrap();
+  let schema = compile(&ast).unwrap();
+  drop(ast);
+  drop(alloc);
+  let plan = generate_plan_for_schema(&Default::default(), &Default::default(), &schema).unwrap();
+  let kv = create_kv();
+  let root_map = Arc::new(generate_root_map(&schema, &plan).unwrap());
+
+  let writer = compile_twscript(
+    r#"
+    graph main(root: schema) {
+      s_insert root.items $ build_table(Item) $ m_insert(id) "a" $ m_insert(name) "alice" create_map;
+    }
+    "#,
+  )
+  .unwrap();
+  let reader = compile_twscript(
+    r#"
+    graph main(root: schema): string {
+      return (point_get root.items "a").name;
+    }
+    "#,
+  )
+  .unwrap();
+
+  let mut traces = vec![];
+  for script in [&writer, &reader] {
+    let vm = TwVm::new(&schema, &plan, script).unwrap();
+    let type_info = GlobalTyckContext::new(&vm).unwrap().typeck().unwrap();
+    let mut executor = Executor::new(&vm, &*kv, &type_info);
+    executor.enable_tracing();
+    executor.run_graph(0, &[root_map.clone()]).await.unwrap();
+    traces.push(executor.take_trace().unwrap());
+  }
+
+  assert!(traces[0].events.iter().any(|x| x.kv_ops > 0));
+  assert!(traces[1].events.iter().any(|x| x.bytes_read > 0));
+
+  let mut trace = traces.pop().unwrap();
+  trace.strip_values();
+  assert!(trace
+    .events
+    .iter()
+    .all(|x| x.inputs.is_empty() && x.output.is_none()));
+}
+
+/// Runs the first graph of `script`, returning the limit it exceeded if any.
+async fn run_with_limits<'a>(
+  schema: &'a CompiledSchema,
+  plan: &'a StoragePlan,
+  kv: &dyn KeyValueStore,
+  root_map: Arc<VmValue<'a>>,
+  script: &'a TwScript,
+  limits: ExecLimits,
+) -> Option<ExecLimit> {
+  let vm = TwVm::new(schema, plan, script).unwrap();
+  let type_info = GlobalTyckContext::new(&vm).unwrap().typeck().unwrap();
+  let mut executor = Executor::new(&vm, kv, &type_info);
+  executor.set_limits(limits);
+  match executor.run_graph(0, &[root_map]).await {
+    Ok(_) => None,
+    Err(e) => match e.downcast::<ExecError>() {
+      Ok(ExecError::LimitExceeded(x)) => Some(x),
+      x => panic!("unexpected error: {:?}", x),
+    },
+  }
+}
+
+#[tokio::test]
+async fn exec_limits() {
+  let _ = pretty_env_logger::try_init();
+  let alloc = Bump::new();
+  let ast = parse(
+    &alloc,
+    r#"
+  type Item {
+    @primary
+    id: string,
+    name: string,
+  }
+  export set<Item> items;
+  "#,
+  )
+  .unwrap();
+  let schema = compile(&ast).unwrap();
+  drop(ast);
+  drop(alloc);
+  let plan = generate_plan_for_schema(&Default::default(), &Default::default(), &schema).unwrap();
+  let kv = create_kv();
+  let root_map = Arc::new(generate_root_map(&schema, &plan).unwrap());
+
+  let fib = compile_twscript(
+    r#"
+    graph main(root: schema): int64 {
+      return call(fib) [10];
+    }
+    graph fib(x: int64): int64 {
+      if x == 1 || x == 2 {
+        v1 = 1;
+      } else {
+        v2 = call(fib) [x - 1] + call(fib) [x - 2];
+      }
+      return select v1 v2;
+    }
+    "#,
+  )
+  .unwrap();
+  let insert = compile_twscript(
+    r#"
+    graph main(root: schema) {
+      s_insert root.items $ build_table(Item) $ m_insert(id) "a" $ m_insert(name) "a long name" create_map;
+    }
+    "#,
+  )
+  .unwrap();
+  let read = compile_twscript(
+    r#"
+    graph main(root: schema): string {
+      return (point_get root.items "a").name;
+    }
+    "#,
+  )
+  .unwrap();
+  let caught_read = compile_twscript(
+    r#"
+    graph main(root: schema): string {
+      x = try {
+        return (point_get root.items "a").name;
+      } catch (e) {
+        return e;
+      }
+      return x;
+    }
+    "#,
+  )
+  .unwrap();
+
+  let run =
+    |script, limits| run_with_limits(&schema, &plan, &*kv, root_map.clone(), script, limits);
+
+  assert_eq!(run(&fib, ExecLimits::default()).await, None);
+  assert_eq!(
+    run(
+      &fib,
+      ExecLimits {
+        max_recursion_depth: 5,
+        ..Default::default()
+      }
+    )
+    .await,
+    Some(ExecLimit::RecursionDepth(5))
+  );
+  assert_eq!(
+    run(
+      &fib,
+      ExecLimits {
+        max_subgraph_calls: Some(10),
+        ..Default::default()
+      }
+    )
+    .await,
+    Some(ExecLimit::SubgraphCalls(10))
+  );
+  assert_eq!(
+    run(
+      &fib,
+      ExecLimits {
+        timeout: Some(Duration::from_secs(0)),
+        ..Default::default()
+      }
+    )
+    .await,
+    Some(ExecLimit::Timeout(Duration::from_secs(0)))
+  );
+  assert_eq!(
+    run(
+      &insert,
+      ExecLimits {
+        max_value_size: Some(4),
+        ..Default::default()
+      }
+    )
+    .await,
+    Some(ExecLimit::ValueSize(4))
+  );
+  assert_eq!(
+    run(
+      &insert,
+      ExecLimits {
+        max_kv_writes: Some(1),
+        ..Default::default()
+      }
+    )
+    .await,
+    Some(ExecLimit::KvWrites(1))
+  );
+  assert_eq!(run(&insert, ExecLimits::default()).await, None);
+  assert_eq!(
+    run(
+      &read,
+      ExecLimits {
+        max_kv_reads: Some(0),
+        ..Default::default()
+      }
+    )
+    .await,
+    Some(ExecLimit::KvReads(0))
+  );
+
+  // Exceeded limits are not caught by `try`.
+  assert_eq!(
+    run(
+      &caught_read,
+      ExecLimits {
+        max_kv_reads: Some(0),
+        ..Default::default()
+      }
+    )
+    .await,
+    Some(ExecLimit::KvReads(0))
+  );
+  assert_eq!(
+    run(
+      &read,
+      ExecLimits {
+        max_kv_reads: Some(100),
+        max_value_size: Some(100),
+        ..Default::default()
+      }
+    )
+    .await,
+    None
+  );
+}
+
+/// Adds latency to reads and records how many of them were in flight at once.
+struct SlowKv {
+  inner: Box<dyn KeyValueStore>,
+  reads: Arc<ReadCounters>,
+}
+
+struct SlowKvTransaction {
+  inner: Box<dyn KvTransaction>,
+  reads: Arc<ReadCounters>,
+}
+
+#[derive(Default)]
+struct ReadCounters {
+  in_flight: AtomicUsize,
+  max_in_flight: AtomicUsize,
+}
+
+#[async_trait]
+impl KeyValueStore for SlowKv {
+  async fn begin_transaction(&self) -> Result<Box<dyn KvTransaction>> {
+    Ok(Box::new(SlowKvTransaction {
+      inner: self.inner.begin_transaction().await?,
+      reads: self.reads.clone(),
+    }))
+  }
+}
+
+#[async_trait]
+impl KvTransaction for SlowKvTransaction {
+  async fn get(&self, key: &[u8]) -> Result<Option<Vec<u8>>> {
+    let n = self.reads.in_flight.fetch_add(1, Ordering::SeqCst) + 1;
+    self.reads.max_in_flight.fetch_max(n, Ordering::SeqCst);
+    tokio::time::sleep(Duration::from_millis(2)).await;
+    self.reads.in_flight.fetch_sub(1, Ordering::SeqCst);
+    self.inner.get(key).await
+  }
+
+  async fn put(&self, key: &[u8], value: &[u8]) -> Result<()> {
+    self.inner.put(key, value).await
+  }
+
+  async fn delete(&self, key: &[u8]) -> Result<()> {
+    self.inner.delete(key).await
+  }
+
+  async fn delete_range(&self, start: &[u8], end: &[u8]) -> Result<()> {
+    self.inner.delete_range(start, end).await
+  }
+
+  async fn scan_keys(&self, start: &[u8], end: &[u8]) -> Result<Box<dyn KvKeyIterator>> {
+    self.inner.scan_keys(start, end).await
+  }
+
+  async fn commit(self: Box<Self>) -> Result<(), KvError> {
+    self.inner.commit().await
+  }
+}
+
+/// Creates a set of `n` items indexed by score, and returns a script that joins their ids in the
+/// order of their scores.
+async fn setup_scored_items(
+  kv: &dyn KeyValueStore,
+  n: usize,
+) -> (CompiledSchema, StoragePlan, TwScript) {
+  let alloc = Bump::new();
+  let ast = parse(
+    &alloc,
+    r#"
+  type Item {
+    @primary
+    id: string,
+    @index
+    score: int64,
+  }
+  export set<Item> items;
+  "#,
+  )
+  .unwrap();
+  let schema = compile(&ast).unwrap();
+  drop(ast);
+  drop(alloc);
+  let plan = generate_plan_for_schema(&Default::default(), &Default::default(), &schema).unwrap();
+
+  let inserts = (0..n)
+    .map(|i| {
+      format!(
+        r#"s_insert root.items $ build_table(Item) $ m_insert(id) "{}" $ m_insert(score) {} create_map;"#,
+        i,
+        n - i
+      )
+    })
+    .collect::<Vec<_>>()
+    .join("\n");
+  let writer = compile_twscript(&format!("graph main(root: schema) {{ {} }}", inserts)).unwrap();
+  run_with_concurrency(&schema, &plan, kv, &writer, 16).await;
+
+  let reader = compile_twscript(
+    r#"
+  graph main(root: schema): string {
+    return reduce(join) create_map "" $ scan_index(score) root.items;
+  }
+  graph join(ctx: map{}, current: string, item: Item): string {
+    return current + item.id + " ";
+  }
+  "#,
+  )
+  .unwrap();
+  (schema, plan, reader)
+}
+
+async fn run_with_concurrency(
+  schema: &CompiledSchema,
+  plan: &StoragePlan,
+  kv: &dyn KeyValueStore,
+  script: &TwScript,
+  concurrency: usize,
+) -> Option<PrimitiveValue> {
+  let vm = TwVm::new(schema, plan, script).unwrap();
+  let type_info = GlobalTyckContext::new(&vm).unwrap().typeck().unwrap();
+  let mut executor = Executor::new(&vm, kv, &type_info);
+  executor.set_config(ExecConfig {
+    concurrency,
+    ..Default::default()
+  });
+  let root_map = Arc::new(generate_root_map(schema, plan).unwrap());
+  executor
+    .run_graph(0, &[root_map])
+    .await
+    .unwrap()
+    .map(|x| x.unwrap_primitive().clone())
+}
+
+#[tokio::test]
+async fn concurrency_bounds_pipelined_reads() {
+  let _ = pretty_env_logger::try_init();
+  let reads = Arc::new(ReadCounters::default());
+  let kv = SlowKv {
+    inner: create_kv(),
+    reads: reads.clone(),
+  };
+  let (schema, plan, reader) = setup_scored_items(&kv, 20).await;
+
+  // Index entries are scanned with their values, and the fields of the members are read with up
+  // to `concurrency` reads in flight.
+  let summer = compile_twscript(
+    r#"
+  graph main(root: schema): int64 {
+    return s_sum(score) root.items;
+  }
+  "#,
+  )
+  .unwrap();
+
+  reads.max_in_flight.store(0, Ordering::SeqCst);
+  let sequential = run_with_concurrency(&schema, &plan, &kv, &reader, 1).await;
+  let sequential_sum = run_with_concurrency(&schema, &plan, &kv, &summer, 1).await;
+  assert_eq!(reads.max_in_flight.load(Ordering::SeqCst), 1);
+
+  reads.max_in_flight.store(0, Ordering::SeqCst);
+  let pipelined = run_with_concurrency(&schema, &plan, &kv, &reader, 4).await;
+  let pipelined_sum = run_with_concurrency(&schema, &plan, &kv, &summer, 4).await;
+  let max_in_flight = reads.max_in_flight.load(Ordering::SeqCst);
+  assert!(max_in_flight > 1 && max_in_flight <= 4);
+
+  assert_eq!(sequential, pipelined);
+  assert!(sequential.unwrap().unwrap_string().starts_with("19 18 17 "));
+  assert_eq!(sequential_sum, pipelined_sum);
+  assert_eq!(sequential_sum, Some(PrimitiveValue::Int64(210)));
+}
+
+/// Compares the time of an index scan with different concurrency limits, on the backend selected
+/// by the `test-with-*` features:
+///
+/// `cargo test -p rdb-analyzer --features test-with-fdb -- --ignored --nocapture bench_pipelined`
+#[tokio::test]
+#[ignore]
+async fn bench_pipelined_reads() {
+  let kv = create_kv();
+  let (schema, plan, reader) = setup_scored_items(&*kv, 500).await;
+  for concurrency in [1, 4, 16, 64] {
+    let start = Instant::now();
+    for _ in 0..10 {
+      run_with_concurrency(&schema, &plan, &*kv, &reader, concurrency).await;
+    }
+    println!(
+      "concurrency {}: {:?} per run",
+      concurrency,
+      start.elapsed() / 10
+    );
+  }
+}
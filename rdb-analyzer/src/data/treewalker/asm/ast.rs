@@ -5,6 +5,20 @@ use crate::schema::compile::PrimitiveType;
 pub struct Root<'a> {
   pub graphs: Vec<'a, &'a Graph<'a>>,
   pub type_aliases: Vec<'a, &'a TypeAlias<'a>>,
+  pub params: Vec<'a, &'a ScriptParam<'a>>,
+  pub consts: Vec<'a, &'a ScriptConst<'a>>,
+  pub mounts: Vec<'a, &'a str>,
+}
+
+pub struct ScriptConst<'a> {
+  pub name: &'a str,
+  pub value: Literal<'a>,
+}
+
+pub struct ScriptParam<'a> {
+  pub name: &'a str,
+  pub ty: Type<'a>,
+  pub default: Option<Literal<'a>>,
 }
 
 pub struct TypeAlias<'a> {
@@ -15,16 +29,33 @@ pub struct TypeAlias<'a> {
 pub enum Item<'a> {
   Graph(&'a Graph<'a>),
   TypeAlias(&'a TypeAlias<'a>),
+  Params(Vec<'a, &'a ScriptParam<'a>>),
+  Const(&'a ScriptConst<'a>),
+  Mount(&'a str),
 }
 
 pub struct Graph<'a> {
   pub name: &'a str,
+  pub annotations: Vec<'a, GraphAnnotation<'a>>,
   pub exported: bool,
+  pub read_only: bool,
   pub params: Vec<'a, (&'a str, Option<Type<'a>>)>,
   pub return_type: Option<Type<'a>>,
   pub stmts: Vec<'a, Stmt<'a>>,
 }
 
+pub struct GraphAnnotation<'a> {
+  pub name: &'a str,
+  pub args: Vec<'a, (&'a str, AnnotationValue<'a>)>,
+}
+
+pub enum AnnotationValue<'a> {
+  Identifier(&'a str),
+
+  /// Duration in milliseconds.
+  Duration(u64),
+}
+
 pub struct Stmt<'a> {
   pub location: usize,
   pub kind: StmtKind<'a>,
@@ -46,6 +77,15 @@ pub enum StmtKind<'a> {
   Throw {
     value: Expr<'a>,
   },
+  Defer {
+    body: Vec<'a, Stmt<'a>>,
+  },
+  Try {
+    name: Option<&'a str>,
+    body: Vec<'a, Stmt<'a>>,
+    error_name: &'a str,
+    handler: Vec<'a, Stmt<'a>>,
+  },
 }
 
 pub struct Expr<'a> {
@@ -64,6 +104,7 @@ pub enum Type<'a> {
   List(&'a Type<'a>),
   Map(Vec<'a, (&'a str, Type<'a>)>),
   Bool,
+  Json,
   Schema,
 }
 
@@ -72,15 +113,39 @@ pub enum ExprKind<'a> {
   BuildTable(Type<'a>, &'a Expr<'a>),
   BuildSet(&'a Expr<'a>),
   CreateMap,
+  LoadEnv,
   GetField(&'a str, &'a Expr<'a>),
+  OptionalGetField(&'a str, &'a Expr<'a>),
   GetSetElement(&'a Expr<'a>, &'a Expr<'a>),
+  GetSetElements(&'a Expr<'a>, &'a Expr<'a>),
+  GetSetElementByTuple(&'a Expr<'a>, Vec<'a, Expr<'a>>),
   InsertIntoMap(&'a str, &'a Expr<'a>, &'a Expr<'a>),
   InsertIntoTable(&'a str, &'a Expr<'a>, &'a Expr<'a>),
   InsertIntoSet(&'a Expr<'a>, &'a Expr<'a>),
   DeleteFromSet(&'a Expr<'a>, &'a Expr<'a>),
+  ListPush(&'a str, &'a Expr<'a>, &'a Expr<'a>),
+  AtomicAdd(&'a str, &'a Expr<'a>, &'a Expr<'a>),
+  CheckVersion(&'a Expr<'a>, &'a Expr<'a>),
+  ListPop(&'a str, &'a Expr<'a>),
+  ListGet(&'a str, &'a Expr<'a>, &'a Expr<'a>),
+  ListLen(&'a str, &'a Expr<'a>),
+  DictGet(&'a str, &'a Expr<'a>, &'a Expr<'a>),
+  DictPut(&'a str, &'a Expr<'a>, &'a Expr<'a>, &'a Expr<'a>),
+  DictDelete(&'a str, &'a Expr<'a>, &'a Expr<'a>),
+  DictRange(&'a str, &'a Expr<'a>, &'a Expr<'a>, &'a Expr<'a>),
   DeleteFromMap(&'a str, &'a Expr<'a>),
+  JsonParse(&'a Expr<'a>),
+  JsonSerialize(&'a Expr<'a>),
+  JsonGet(Option<Type<'a>>, &'a Expr<'a>, &'a Expr<'a>),
+  MapKeys(&'a Expr<'a>),
+  MapValues(&'a Expr<'a>),
+  MapGetDynamic(&'a Expr<'a>, &'a Expr<'a>),
   Eq(&'a Expr<'a>, &'a Expr<'a>),
   Ne(&'a Expr<'a>, &'a Expr<'a>),
+  Lt(&'a Expr<'a>, &'a Expr<'a>),
+  Le(&'a Expr<'a>, &'a Expr<'a>),
+  Gt(&'a Expr<'a>, &'a Expr<'a>),
+  Ge(&'a Expr<'a>, &'a Expr<'a>),
   And(&'a Expr<'a>, &'a Expr<'a>),
   Or(&'a Expr<'a>, &'a Expr<'a>),
   Not(&'a Expr<'a>),
@@ -88,10 +153,37 @@ pub enum ExprKind<'a> {
   Node(&'a str),
   IsPresent(&'a Expr<'a>),
   IsNull(&'a Expr<'a>),
+  UnwrapOptional(&'a Expr<'a>),
   OrElse(&'a Expr<'a>, &'a Expr<'a>),
+  If(&'a Expr<'a>, &'a Expr<'a>, &'a Expr<'a>),
   Call(&'a str, Vec<'a, Expr<'a>>),
   Add(&'a Expr<'a>, &'a Expr<'a>),
   Sub(&'a Expr<'a>, &'a Expr<'a>),
+  Mul(&'a Expr<'a>, &'a Expr<'a>),
+  Div(&'a Expr<'a>, &'a Expr<'a>),
+  Mod(&'a Expr<'a>, &'a Expr<'a>),
+  Neg(&'a Expr<'a>),
+  ParseDouble(&'a Expr<'a>),
+  FormatDouble(&'a Expr<'a>),
+  StrStartsWith(&'a Expr<'a>, &'a Expr<'a>),
+  StrContains(&'a Expr<'a>, &'a Expr<'a>),
+  StrSubstring(&'a Expr<'a>, &'a Expr<'a>, &'a Expr<'a>),
+  StrToLower(&'a Expr<'a>),
+  StrLength(&'a Expr<'a>),
+  BytesConcat(&'a Expr<'a>, &'a Expr<'a>),
+  BytesSlice(&'a Expr<'a>, &'a Expr<'a>, &'a Expr<'a>),
+  BytesLength(&'a Expr<'a>),
+  BytesToHex(&'a Expr<'a>),
+  BytesFromHex(&'a Expr<'a>),
+  BytesToBase64(&'a Expr<'a>),
+  BytesFromBase64(&'a Expr<'a>),
+  BytesToString(&'a Expr<'a>),
+  BytesFromString(&'a Expr<'a>),
+  TimeNow,
+  TimeAddDays(&'a Expr<'a>, &'a Expr<'a>),
+  TimeStartOfDay(&'a Expr<'a>),
+  TimeDayOfWeek(&'a Expr<'a>),
+  GenId,
   CreateList(Type<'a>),
   Reduce(&'a str, &'a Expr<'a>, &'a Expr<'a>, &'a Expr<'a>),
   RangeReduce(
@@ -102,9 +194,28 @@ pub enum ExprKind<'a> {
     &'a Expr<'a>,
     &'a Expr<'a>,
   ),
+  Loop(
+    &'a str,
+    &'a Expr<'a>,
+    &'a Expr<'a>,
+    &'a Expr<'a>,
+    &'a Expr<'a>,
+  ),
+  ScanIndex(
+    &'a str,
+    Option<&'a Expr<'a>>,
+    bool,
+    Option<(&'a Expr<'a>, &'a Expr<'a>)>,
+    &'a Expr<'a>,
+  ),
+  Count(&'a Expr<'a>),
+  Sum(&'a str, &'a Expr<'a>),
+  Min(&'a str, &'a Expr<'a>),
+  Max(&'a str, &'a Expr<'a>),
   Prepend(&'a Expr<'a>, &'a Expr<'a>),
   Pop(&'a Expr<'a>),
   Head(&'a Expr<'a>),
+  Sort(&'a str, &'a Expr<'a>),
 }
 
 pub enum Literal<'a> {
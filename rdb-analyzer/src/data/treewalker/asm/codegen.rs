@@ -3,7 +3,10 @@ use std::collections::{HashMap, HashSet};
 use super::language::RootParser;
 use super::{ast, state::State};
 use crate::data::treewalker::asm::TwAsmError;
-use crate::data::treewalker::bytecode::{TwGraph, TwGraphNode, TwScript};
+use crate::data::treewalker::bytecode::{
+  TwCacheDirective, TwCacheKey, TwGraph, TwGraphNode, TwScript, TwScriptParam,
+};
+use crate::data::treewalker::optimize::optimize_script;
 use crate::data::treewalker::vm_value::{
   VmConst, VmConstSetValue, VmListType, VmSetType, VmTableType, VmType,
 };
@@ -14,6 +17,7 @@ use anyhow::Result;
 use bumpalo::boxed::Box as BumpBox;
 use bumpalo::Bump;
 
+/// Compiles a script, and optimizes it with `optimize_script`.
 pub fn compile_twscript(input: &str) -> Result<TwScript> {
   let bump = Bump::new();
   let root = parse(&bump, input)?;
@@ -25,7 +29,9 @@ pub fn compile_twscript(input: &str) -> Result<TwScript> {
     vmtype_pool: HashMap::new(),
     const_pool: HashMap::new(),
     type_aliases: HashMap::new(),
+    consts: HashMap::new(),
     root: &root,
+    try_graphs: vec![],
   };
   if let Some(x) = first_duplicate(root.graphs.iter().map(|x| x.name)) {
     return Err(TwAsmError::DuplicateGraph(x.into()).into());
@@ -41,6 +47,40 @@ pub fn compile_twscript(input: &str) -> Result<TwScript> {
     builder.type_aliases.insert(alias.name, vmtype);
   }
 
+  if let Some(x) = first_duplicate(root.consts.iter().map(|x| x.name)) {
+    return Err(TwAsmError::DuplicateConst(x.into()).into());
+  }
+  for c in &root.consts {
+    let value = builder.literal_to_vmconst(&c.value)?;
+    let value = builder.alloc_const(value);
+    builder.consts.insert(c.name, value);
+  }
+
+  if let Some(x) = first_duplicate(root.mounts.iter().copied()) {
+    return Err(TwAsmError::DuplicateMount(x.into()).into());
+  }
+  builder.script.mounts = root.mounts.iter().map(|x| x.to_string()).collect();
+
+  if let Some(x) = first_duplicate(root.params.iter().map(|x| x.name)) {
+    return Err(TwAsmError::DuplicateScriptParam(x.into()).into());
+  }
+  for p in &root.params {
+    let ty = builder.generate_vmtype(&p.ty)?;
+    let ty = builder.alloc_vmtype(ty);
+    let default = match &p.default {
+      Some(x) => {
+        let x = builder.literal_to_vmconst(x)?;
+        Some(builder.alloc_const(x))
+      }
+      None => None,
+    };
+    builder.script.params.push(TwScriptParam {
+      name: p.name.to_string(),
+      ty,
+      default,
+    });
+  }
+
   for g in &root.graphs {
     if let Some(x) = first_duplicate(g.params.iter().map(|x| x.0)) {
       return Err(TwAsmError::DuplicateParam(x.into()).into());
@@ -48,6 +88,7 @@ pub fn compile_twscript(input: &str) -> Result<TwScript> {
     let target = TwGraph {
       name: g.name.to_string(),
       exported: g.exported,
+      read_only: g.read_only,
       nodes: vec![],
       output: None,
       param_types: g
@@ -60,12 +101,17 @@ pub fn compile_twscript(input: &str) -> Result<TwScript> {
             .map(|x| builder.alloc_vmtype(x))
         })
         .collect::<Result<_>>()?,
+      param_names: g.params.iter().map(|(x, _)| x.to_string()).collect(),
       output_type: g
         .return_type
         .as_ref()
         .map(|x| builder.generate_vmtype(x))
         .transpose()?
         .map(|x| builder.alloc_vmtype(x)),
+      cache: generate_cache_directive(g)?,
+      optional_chain: vec![],
+      deferred: vec![],
+      relaxed_isolation: g.annotations.iter().any(|x| x.name == "relaxed_isolation"),
     };
     let output;
     {
@@ -74,6 +120,10 @@ pub fn compile_twscript(input: &str) -> Result<TwScript> {
         builder: &mut builder,
         target,
         condition_stack: vec![],
+        captures: vec![],
+        enclosing: vec![],
+        catch_outputs: vec![],
+        deferred: false,
       };
       for (i, (p, _)) in g.params.iter().enumerate() {
         ctx.push_node((TwGraphNode::LoadParam(i as u32), vec![], None), Some(*p))?;
@@ -85,8 +135,20 @@ pub fn compile_twscript(input: &str) -> Result<TwScript> {
     }
     builder.script.graphs.push(output);
   }
+
+  // Bodies of `try` blocks go after the graphs of the script, in the order they were started.
+  let try_graphs = std::mem::take(&mut builder.try_graphs);
+  builder
+    .script
+    .graphs
+    .extend(try_graphs.into_iter().map(|x| x.unwrap()));
   builder.emit_pools();
-  Ok(builder.script)
+  let mut script = builder.script;
+  for g in &mut script.graphs {
+    propagate_deferred(g);
+  }
+  optimize_script(&mut script);
+  Ok(script)
 }
 
 struct Builder<'a> {
@@ -96,7 +158,13 @@ struct Builder<'a> {
   vmtype_pool: HashMap<BumpBox<'a, VmType<String>>, u32>,
   const_pool: HashMap<VmConst, u32>,
   type_aliases: HashMap<&'a str, VmType<String>>,
+
+  /// Script-level constants, by name. Referenced as nodes when no node of the name is in scope.
+  consts: HashMap<&'a str, u32>,
   root: &'a ast::Root<'a>,
+
+  /// Graphs generated for `try` blocks. `None` while being generated.
+  try_graphs: Vec<Option<TwGraph>>,
 }
 
 struct GraphContext<'a, 'b> {
@@ -104,6 +172,28 @@ struct GraphContext<'a, 'b> {
   builder: &'b mut Builder<'a>,
   target: TwGraph,
   condition_stack: Vec<u32>,
+
+  /// Nodes of the enclosing graph that are passed as the params of `target`, when generating the
+  /// body of a `try` block.
+  captures: Vec<u32>,
+
+  /// Graphs that enclose `target`, innermost last.
+  enclosing: Vec<Scope<'a>>,
+
+  /// Outputs of the `catch` blocks being generated, innermost last.
+  catch_outputs: Vec<Option<u32>>,
+
+  /// Whether the statements being generated are in a `defer` block of `target`.
+  deferred: bool,
+}
+
+/// The state of a graph whose generation is suspended for the body of a `try` block.
+struct Scope<'a> {
+  names: HashMap<&'a str, u32>,
+  target: TwGraph,
+  condition_stack: Vec<u32>,
+  captures: Vec<u32>,
+  catch_outputs: Vec<Option<u32>>,
 }
 
 impl<'a, 'b> GraphContext<'a, 'b> {
@@ -111,10 +201,14 @@ impl<'a, 'b> GraphContext<'a, 'b> {
     match &stmt.kind {
       ast::StmtKind::Return { value } => {
         let node = self.generate_expr(g, None, value)?;
-        if self.target.output.is_some() {
+        let output = match self.catch_outputs.last_mut() {
+          Some(x) => x,
+          None => &mut self.target.output,
+        };
+        if output.is_some() {
           return Err(TwAsmError::DuplicateReturn.into());
         }
-        self.target.output = Some(node);
+        *output = Some(node);
       }
       ast::StmtKind::If {
         precondition,
@@ -142,6 +236,13 @@ impl<'a, 'b> GraphContext<'a, 'b> {
       ast::StmtKind::Node { name, value } => {
         self.generate_expr(g, *name, value)?;
       }
+      ast::StmtKind::Defer { body } => {
+        let outer = std::mem::replace(&mut self.deferred, true);
+        for stmt in body {
+          self.generate_stmt(g, stmt)?;
+        }
+        self.deferred = outer;
+      }
       ast::StmtKind::Throw { value } => {
         let x = self.generate_expr(g, None, value)?;
         self.push_node(
@@ -153,6 +254,132 @@ impl<'a, 'b> GraphContext<'a, 'b> {
           None,
         )?;
       }
+      ast::StmtKind::Try {
+        name,
+        body,
+        error_name,
+        handler,
+      } => {
+        self.generate_try(g, *name, body, error_name, handler)?;
+      }
+    }
+    Ok(())
+  }
+
+  /// Generates the body of a `try` block as a subgraph, run by a `Try` node, and the `catch` block
+  /// in the current graph, conditioned on the error being non-null. If both blocks return, their
+  /// outputs are selected into `name`.
+  fn generate_try(
+    &mut self,
+    g: &ast::Graph<'a>,
+    name: Option<&'a str>,
+    body: &[ast::Stmt<'a>],
+    error_name: &'a str,
+    handler: &[ast::Stmt<'a>],
+  ) -> Result<()> {
+    let subgraph_index = (self.builder.root.graphs.len() + self.builder.try_graphs.len()) as u32;
+    self.builder.try_graphs.push(None);
+    let subgraph = TwGraph {
+      name: format!("{}$try{}", self.target.name, subgraph_index),
+      exported: false,
+      read_only: false,
+      nodes: vec![],
+      output: None,
+      param_types: vec![],
+      param_names: vec![],
+      output_type: None,
+      cache: None,
+      optional_chain: vec![],
+      deferred: vec![],
+      relaxed_isolation: self.target.relaxed_isolation,
+    };
+
+    // Generate the body in a fresh scope. Names of enclosing graphs are captured on first use.
+    let scope = Scope {
+      names: std::mem::take(&mut self.names),
+      target: std::mem::replace(&mut self.target, subgraph),
+      condition_stack: std::mem::take(&mut self.condition_stack),
+      captures: std::mem::take(&mut self.captures),
+      catch_outputs: std::mem::take(&mut self.catch_outputs),
+    };
+    self.enclosing.push(scope);
+    // The whole body runs as one node, deferred or not.
+    let deferred = std::mem::replace(&mut self.deferred, false);
+    for stmt in body {
+      self.generate_stmt(g, stmt)?;
+    }
+    self.deferred = deferred;
+    let scope = self.enclosing.pop().unwrap();
+    self.names = scope.names;
+    self.condition_stack = scope.condition_stack;
+    self.catch_outputs = scope.catch_outputs;
+    let captures = std::mem::replace(&mut self.captures, scope.captures);
+    let subgraph = std::mem::replace(&mut self.target, scope.target);
+    let body_returns = subgraph.output.is_some();
+    self.builder.try_graphs[subgraph_index as usize - self.builder.root.graphs.len()] =
+      Some(subgraph);
+
+    let precondition = self.condition_stack.last().copied();
+    let result = self.push_node(
+      (TwGraphNode::Try(subgraph_index), captures, precondition),
+      None,
+    )?;
+    let error_field = self.builder.alloc_ident("error");
+    let error = self.push_node(
+      (TwGraphNode::GetField(error_field), vec![result], None),
+      None,
+    )?;
+    let succeeded = self.push_node((TwGraphNode::IsNull, vec![error], None), None)?;
+    let failed = self.push_node((TwGraphNode::Not, vec![succeeded], None), None)?;
+
+    let value = if body_returns {
+      let value_field = self.builder.alloc_ident("value");
+      let condition = self.generate_condition(succeeded)?;
+      Some(self.push_node(
+        (
+          TwGraphNode::GetField(value_field),
+          vec![result],
+          Some(condition),
+        ),
+        None,
+      )?)
+    } else {
+      None
+    };
+
+    let condition = self.generate_condition(failed)?;
+    self.condition_stack.push(condition);
+    self.catch_outputs.push(None);
+    if self.names.contains_key(error_name) {
+      return Err(TwAsmError::DuplicateNodeName(error_name.into()).into());
+    }
+    self.names.insert(error_name, error);
+    for stmt in handler {
+      self.generate_stmt(g, stmt)?;
+    }
+    let handler_value = self.catch_outputs.pop().unwrap();
+    self.condition_stack.pop().unwrap();
+
+    match (value, handler_value) {
+      (Some(value), Some(handler_value)) => {
+        // The handler may return a node from outside of it, which must not fire on success.
+        let handler_value = self.push_node(
+          (TwGraphNode::Nop, vec![handler_value], Some(condition)),
+          None,
+        )?;
+        self.push_node(
+          (TwGraphNode::Select, vec![value, handler_value], None),
+          name,
+        )?;
+      }
+      (None, None) => {
+        if let Some(name) = name {
+          return Err(TwAsmError::TryCatchReturnMismatch(name.into()).into());
+        }
+      }
+      _ => {
+        return Err(TwAsmError::TryCatchReturnMismatch(name.unwrap_or("try").into()).into());
+      }
     }
     Ok(())
   }
@@ -189,6 +416,7 @@ impl<'a, 'b> GraphContext<'a, 'b> {
         self.push_node((TwGraphNode::BuildTable(ty), vec![map], precondition), name)?
       }
       K::CreateMap => self.push_node((TwGraphNode::CreateMap, vec![], precondition), name)?,
+      K::LoadEnv => self.push_node((TwGraphNode::LoadEnv, vec![], precondition), name)?,
       K::DeleteFromMap(field, map) => {
         let field = self.builder.alloc_ident(*field);
         let map = self.generate_expr(g, None, *map)?;
@@ -197,6 +425,43 @@ impl<'a, 'b> GraphContext<'a, 'b> {
           name,
         )?
       }
+      K::JsonParse(x) => {
+        let x = self.generate_expr(g, None, *x)?;
+        self.push_node((TwGraphNode::JsonParse, vec![x], precondition), name)?
+      }
+      K::JsonSerialize(x) => {
+        let x = self.generate_expr(g, None, *x)?;
+        self.push_node((TwGraphNode::JsonSerialize, vec![x], precondition), name)?
+      }
+      K::JsonGet(ty, path, doc) => {
+        let ty = match ty {
+          Some(x) => self.builder.generate_vmtype(x)?,
+          None => VmType::Json,
+        };
+        let ty = self.builder.alloc_vmtype(ty);
+        let path = self.generate_expr(g, None, *path)?;
+        let doc = self.generate_expr(g, None, *doc)?;
+        self.push_node(
+          (TwGraphNode::JsonGet(ty), vec![path, doc], precondition),
+          name,
+        )?
+      }
+      K::MapKeys(map) => {
+        let map = self.generate_expr(g, None, *map)?;
+        self.push_node((TwGraphNode::MapKeys, vec![map], precondition), name)?
+      }
+      K::MapValues(map) => {
+        let map = self.generate_expr(g, None, *map)?;
+        self.push_node((TwGraphNode::MapValues, vec![map], precondition), name)?
+      }
+      K::MapGetDynamic(key, map) => {
+        let key = self.generate_expr(g, None, *key)?;
+        let map = self.generate_expr(g, None, *map)?;
+        self.push_node(
+          (TwGraphNode::MapGetDynamic, vec![key, map], precondition),
+          name,
+        )?
+      }
       K::DeleteFromSet(set, selector) => {
         let set = self.generate_expr(g, None, *set)?;
         let selector = self.generate_expr(g, None, *selector)?;
@@ -214,17 +479,21 @@ impl<'a, 'b> GraphContext<'a, 'b> {
         let r = self.generate_expr(g, None, *r)?;
         self.push_node((TwGraphNode::Eq, vec![l, r], precondition), name)?
       }
-      K::GetField(field, table_or_set) => {
+      K::GetField(field, table_or_set) | K::OptionalGetField(field, table_or_set) => {
         let field = self.builder.alloc_ident(*field);
         let table_or_set = self.generate_expr(g, None, *table_or_set)?;
-        self.push_node(
+        let node = self.push_node(
           (
             TwGraphNode::GetField(field),
             vec![table_or_set],
             precondition,
           ),
           name,
-        )?
+        )?;
+
+        // `x.field` fails if `x` is null, and `x?.field` outputs null.
+        self.target.optional_chain[node as usize] = matches!(&expr.kind, K::OptionalGetField(..));
+        node
       }
       K::GetSetElement(set, selector) => {
         let set = self.generate_expr(g, None, *set)?;
@@ -238,6 +507,30 @@ impl<'a, 'b> GraphContext<'a, 'b> {
           name,
         )?
       }
+      K::GetSetElementByTuple(set, components) => {
+        let set = self.generate_expr(g, None, *set)?;
+        let mut in_edges = components
+          .iter()
+          .map(|x| self.generate_expr(g, None, x))
+          .collect::<Result<Vec<_>>>()?;
+        in_edges.push(set);
+        self.push_node(
+          (TwGraphNode::GetSetElementByTuple, in_edges, precondition),
+          name,
+        )?
+      }
+      K::GetSetElements(set, selectors) => {
+        let set = self.generate_expr(g, None, *set)?;
+        let selectors = self.generate_expr(g, None, *selectors)?;
+        self.push_node(
+          (
+            TwGraphNode::GetSetElements,
+            vec![selectors, set],
+            precondition,
+          ),
+          name,
+        )?
+      }
       K::InsertIntoMap(field, v, map) => {
         let field = self.builder.alloc_ident(*field);
         let v = self.generate_expr(g, None, *v)?;
@@ -272,6 +565,119 @@ impl<'a, 'b> GraphContext<'a, 'b> {
           name,
         )?
       }
+      K::ListPush(field, table, v) => {
+        let field = self.builder.alloc_ident(*field);
+        let table = self.generate_expr(g, None, *table)?;
+        let v = self.generate_expr(g, None, *v)?;
+        self.push_node(
+          (TwGraphNode::ListPush(field), vec![v, table], precondition),
+          name,
+        )?
+      }
+      K::AtomicAdd(field, table, delta) => {
+        let field = self.builder.alloc_ident(*field);
+        let table = self.generate_expr(g, None, *table)?;
+        let delta = self.generate_expr(g, None, *delta)?;
+        self.push_node(
+          (
+            TwGraphNode::AtomicAdd(field),
+            vec![delta, table],
+            precondition,
+          ),
+          name,
+        )?
+      }
+      K::CheckVersion(table, version) => {
+        let table = self.generate_expr(g, None, *table)?;
+        let version = self.generate_expr(g, None, *version)?;
+        self.push_node(
+          (
+            TwGraphNode::CheckVersion,
+            vec![version, table],
+            precondition,
+          ),
+          name,
+        )?
+      }
+      K::ListPop(field, table) => {
+        let field = self.builder.alloc_ident(*field);
+        let table = self.generate_expr(g, None, *table)?;
+        self.push_node(
+          (TwGraphNode::ListPop(field), vec![table], precondition),
+          name,
+        )?
+      }
+      K::ListGet(field, table, index) => {
+        let field = self.builder.alloc_ident(*field);
+        let table = self.generate_expr(g, None, *table)?;
+        let index = self.generate_expr(g, None, *index)?;
+        self.push_node(
+          (
+            TwGraphNode::ListGet(field),
+            vec![index, table],
+            precondition,
+          ),
+          name,
+        )?
+      }
+      K::ListLen(field, table) => {
+        let field = self.builder.alloc_ident(*field);
+        let table = self.generate_expr(g, None, *table)?;
+        self.push_node(
+          (TwGraphNode::ListLen(field), vec![table], precondition),
+          name,
+        )?
+      }
+      K::DictGet(field, table, key) => {
+        let field = self.builder.alloc_ident(*field);
+        let table = self.generate_expr(g, None, *table)?;
+        let key = self.generate_expr(g, None, *key)?;
+        self.push_node(
+          (TwGraphNode::DictGet(field), vec![key, table], precondition),
+          name,
+        )?
+      }
+      K::DictPut(field, table, key, v) => {
+        let field = self.builder.alloc_ident(*field);
+        let table = self.generate_expr(g, None, *table)?;
+        let key = self.generate_expr(g, None, *key)?;
+        let v = self.generate_expr(g, None, *v)?;
+        self.push_node(
+          (
+            TwGraphNode::DictPut(field),
+            vec![key, v, table],
+            precondition,
+          ),
+          name,
+        )?
+      }
+      K::DictDelete(field, table, key) => {
+        let field = self.builder.alloc_ident(*field);
+        let table = self.generate_expr(g, None, *table)?;
+        let key = self.generate_expr(g, None, *key)?;
+        self.push_node(
+          (
+            TwGraphNode::DictDelete(field),
+            vec![key, table],
+            precondition,
+          ),
+          name,
+        )?
+      }
+      K::DictRange(field, table, start, end) => {
+        let field = self.builder.alloc_ident(*field);
+        let table = self.generate_expr(g, None, *table)?;
+        let start = self.generate_expr(g, None, *start)?;
+        let end = self.generate_expr(g, None, *end)?;
+        self.push_node(
+          (
+            TwGraphNode::DictRange(field),
+            vec![start, end, table],
+            precondition,
+          ),
+          name,
+        )?
+      }
       K::LoadConst(x) => {
         let vmconst = self.builder.literal_to_vmconst(x)?;
         let x = self.builder.alloc_const(vmconst);
@@ -287,6 +693,26 @@ impl<'a, 'b> GraphContext<'a, 'b> {
         let r = self.generate_expr(g, None, *r)?;
         self.push_node((TwGraphNode::Ne, vec![l, r], precondition), name)?
       }
+      K::Lt(l, r) => {
+        let l = self.generate_expr(g, None, *l)?;
+        let r = self.generate_expr(g, None, *r)?;
+        self.push_node((TwGraphNode::Lt, vec![l, r], precondition), name)?
+      }
+      K::Le(l, r) => {
+        let l = self.generate_expr(g, None, *l)?;
+        let r = self.generate_expr(g, None, *r)?;
+        self.push_node((TwGraphNode::Le, vec![l, r], precondition), name)?
+      }
+      K::Gt(l, r) => {
+        let l = self.generate_expr(g, None, *l)?;
+        let r = self.generate_expr(g, None, *r)?;
+        self.push_node((TwGraphNode::Gt, vec![l, r], precondition), name)?
+      }
+      K::Ge(l, r) => {
+        let l = self.generate_expr(g, None, *l)?;
+        let r = self.generate_expr(g, None, *r)?;
+        self.push_node((TwGraphNode::Ge, vec![l, r], precondition), name)?
+      }
       K::Or(l, r) => {
         let l = self.generate_expr(g, None, *l)?;
         let r = self.generate_expr(g, None, *r)?;
@@ -296,6 +722,10 @@ impl<'a, 'b> GraphContext<'a, 'b> {
         let x = self.generate_expr(g, None, *x)?;
         self.push_node((TwGraphNode::Not, vec![x], precondition), name)?
       }
+      K::UnwrapOptional(x) => {
+        let x = self.generate_expr(g, None, *x)?;
+        self.push_node((TwGraphNode::UnwrapOptional, vec![x], precondition), name)?
+      }
       K::IsPresent(x) => {
         let x = self.generate_expr(g, None, *x)?;
         self.push_node((TwGraphNode::IsPresent, vec![x], precondition), name)?
@@ -304,6 +734,25 @@ impl<'a, 'b> GraphContext<'a, 'b> {
         let x = self.generate_expr(g, None, *x)?;
         self.push_node((TwGraphNode::IsNull, vec![x], precondition), name)?
       }
+      K::If(cond, then, otherwise) => {
+        let cond = self.generate_expr(g, None, *cond)?;
+        let not_cond = self.push_node((TwGraphNode::Not, vec![cond], None), None)?;
+
+        let condition = self.generate_condition(cond)?;
+        self.condition_stack.push(condition);
+        let then = self.generate_expr(g, None, *then)?;
+        self.condition_stack.pop().unwrap();
+
+        let condition = self.generate_condition(not_cond)?;
+        self.condition_stack.push(condition);
+        let otherwise = self.generate_expr(g, None, *otherwise)?;
+        self.condition_stack.pop().unwrap();
+
+        self.push_node(
+          (TwGraphNode::Cond, vec![cond, then, otherwise], precondition),
+          name,
+        )?
+      }
       K::OrElse(l, r) => {
         let l = self.generate_expr(g, None, *l)?;
         let comparator = self.push_node((TwGraphNode::IsNull, vec![l], None), None)?;
@@ -349,6 +798,126 @@ impl<'a, 'b> GraphContext<'a, 'b> {
         let r = self.generate_expr(g, None, *r)?;
         self.push_node((TwGraphNode::Sub, vec![l, r], precondition), name)?
       }
+      K::Mul(l, r) => {
+        let l = self.generate_expr(g, None, *l)?;
+        let r = self.generate_expr(g, None, *r)?;
+        self.push_node((TwGraphNode::Mul, vec![l, r], precondition), name)?
+      }
+      K::Div(l, r) => {
+        let l = self.generate_expr(g, None, *l)?;
+        let r = self.generate_expr(g, None, *r)?;
+        self.push_node((TwGraphNode::Div, vec![l, r], precondition), name)?
+      }
+      K::Mod(l, r) => {
+        let l = self.generate_expr(g, None, *l)?;
+        let r = self.generate_expr(g, None, *r)?;
+        self.push_node((TwGraphNode::Mod, vec![l, r], precondition), name)?
+      }
+      K::Neg(x) => {
+        let x = self.generate_expr(g, None, *x)?;
+        self.push_node((TwGraphNode::Neg, vec![x], precondition), name)?
+      }
+      K::ParseDouble(x) => {
+        let x = self.generate_expr(g, None, *x)?;
+        self.push_node((TwGraphNode::ParseDouble, vec![x], precondition), name)?
+      }
+      K::FormatDouble(x) => {
+        let x = self.generate_expr(g, None, *x)?;
+        self.push_node((TwGraphNode::FormatDouble, vec![x], precondition), name)?
+      }
+      K::StrStartsWith(x, prefix) => {
+        let x = self.generate_expr(g, None, *x)?;
+        let prefix = self.generate_expr(g, None, *prefix)?;
+        self.push_node(
+          (TwGraphNode::StrStartsWith, vec![x, prefix], precondition),
+          name,
+        )?
+      }
+      K::StrContains(x, needle) => {
+        let x = self.generate_expr(g, None, *x)?;
+        let needle = self.generate_expr(g, None, *needle)?;
+        self.push_node(
+          (TwGraphNode::StrContains, vec![x, needle], precondition),
+          name,
+        )?
+      }
+      K::StrSubstring(x, start, len) => {
+        let x = self.generate_expr(g, None, *x)?;
+        let start = self.generate_expr(g, None, *start)?;
+        let len = self.generate_expr(g, None, *len)?;
+        self.push_node(
+          (TwGraphNode::StrSubstring, vec![x, start, len], precondition),
+          name,
+        )?
+      }
+      K::StrToLower(x) => {
+        let x = self.generate_expr(g, None, *x)?;
+        self.push_node((TwGraphNode::StrToLower, vec![x], precondition), name)?
+      }
+      K::StrLength(x) => {
+        let x = self.generate_expr(g, None, *x)?;
+        self.push_node((TwGraphNode::StrLength, vec![x], precondition), name)?
+      }
+      K::BytesConcat(x, y) => {
+        let x = self.generate_expr(g, None, *x)?;
+        let y = self.generate_expr(g, None, *y)?;
+        self.push_node((TwGraphNode::BytesConcat, vec![x, y], precondition), name)?
+      }
+      K::BytesSlice(x, start, len) => {
+        let x = self.generate_expr(g, None, *x)?;
+        let start = self.generate_expr(g, None, *start)?;
+        let len = self.generate_expr(g, None, *len)?;
+        self.push_node(
+          (TwGraphNode::BytesSlice, vec![x, start, len], precondition),
+          name,
+        )?
+      }
+      K::BytesLength(x) => {
+        let x = self.generate_expr(g, None, *x)?;
+        self.push_node((TwGraphNode::BytesLength, vec![x], precondition), name)?
+      }
+      K::BytesToHex(x) => {
+        let x = self.generate_expr(g, None, *x)?;
+        self.push_node((TwGraphNode::BytesToHex, vec![x], precondition), name)?
+      }
+      K::BytesFromHex(x) => {
+        let x = self.generate_expr(g, None, *x)?;
+        self.push_node((TwGraphNode::BytesFromHex, vec![x], precondition), name)?
+      }
+      K::BytesToBase64(x) => {
+        let x = self.generate_expr(g, None, *x)?;
+        self.push_node((TwGraphNode::BytesToBase64, vec![x], precondition), name)?
+      }
+      K::BytesFromBase64(x) => {
+        let x = self.generate_expr(g, None, *x)?;
+        self.push_node((TwGraphNode::BytesFromBase64, vec![x], precondition), name)?
+      }
+      K::BytesToString(x) => {
+        let x = self.generate_expr(g, None, *x)?;
+        self.push_node((TwGraphNode::BytesToString, vec![x], precondition), name)?
+      }
+      K::BytesFromString(x) => {
+        let x = self.generate_expr(g, None, *x)?;
+        self.push_node((TwGraphNode::BytesFromString, vec![x], precondition), name)?
+      }
+      K::TimeNow => self.push_node((TwGraphNode::TimeNow, vec![], precondition), name)?,
+      K::TimeAddDays(x, days) => {
+        let x = self.generate_expr(g, None, *x)?;
+        let days = self.generate_expr(g, None, *days)?;
+        self.push_node(
+          (TwGraphNode::TimeAddDays, vec![x, days], precondition),
+          name,
+        )?
+      }
+      K::TimeStartOfDay(x) => {
+        let x = self.generate_expr(g, None, *x)?;
+        self.push_node((TwGraphNode::TimeStartOfDay, vec![x], precondition), name)?
+      }
+      K::TimeDayOfWeek(x) => {
+        let x = self.generate_expr(g, None, *x)?;
+        self.push_node((TwGraphNode::TimeDayOfWeek, vec![x], precondition), name)?
+      }
+      K::GenId => self.push_node((TwGraphNode::GenId, vec![], precondition), name)?,
 
       K::CreateList(ty) => {
         let ty = self.builder.generate_vmtype(ty)?;
@@ -402,11 +971,70 @@ impl<'a, 'b> GraphContext<'a, 'b> {
           name,
         )?
       }
+      K::Loop(target_graph, range_start, range_end, subgraph_param, init) => {
+        let (i, _) = self
+          .builder
+          .root
+          .graphs
+          .iter()
+          .enumerate()
+          .find(|(_, x)| x.name == *target_graph)
+          .ok_or_else(|| TwAsmError::GraphNotFound(target_graph.to_string()))?;
+        let params = vec![
+          self.generate_expr(g, None, *subgraph_param)?,
+          self.generate_expr(g, None, *init)?,
+          self.generate_expr(g, None, *range_start)?,
+          self.generate_expr(g, None, *range_end)?,
+        ];
+        self.push_node((TwGraphNode::Loop(i as u32), params, precondition), name)?
+      }
+      K::ScanIndex(field, limit, reverse, range, set) => {
+        let field = self.builder.alloc_ident(*field);
+        let mut params = vec![self.generate_expr(g, None, *set)?];
+        if let Some((range_start, range_end)) = range {
+          params.push(self.generate_expr(g, None, *range_start)?);
+          params.push(self.generate_expr(g, None, *range_end)?);
+        }
+        let node = if limit.is_none() && !*reverse {
+          TwGraphNode::ScanIndex(field, range.is_some())
+        } else {
+          params.push(match limit {
+            Some(x) => self.generate_expr(g, None, *x)?,
+            None => {
+              let unlimited = self
+                .builder
+                .alloc_const(VmConst::Null(VmType::Primitive(PrimitiveType::Int64)));
+              self.push_node((TwGraphNode::LoadConst(unlimited), vec![], None), None)?
+            }
+          });
+          TwGraphNode::ScanIndexLimited(field, range.is_some(), *reverse)
+        };
+        self.push_node((node, params, precondition), name)?
+      }
       K::Prepend(l, r) => {
         let l = self.generate_expr(g, None, *l)?;
         let r = self.generate_expr(g, None, *r)?;
         self.push_node((TwGraphNode::PrependToList, vec![l, r], precondition), name)?
       }
+      K::Count(x) => {
+        let x = self.generate_expr(g, None, *x)?;
+        self.push_node((TwGraphNode::Count, vec![x], precondition), name)?
+      }
+      K::Sum(field, x) => {
+        let field = self.builder.alloc_ident(*field);
+        let x = self.generate_expr(g, None, *x)?;
+        self.push_node((TwGraphNode::Sum(field), vec![x], precondition), name)?
+      }
+      K::Min(field, x) => {
+        let field = self.builder.alloc_ident(*field);
+        let x = self.generate_expr(g, None, *x)?;
+        self.push_node((TwGraphNode::Min(field), vec![x], precondition), name)?
+      }
+      K::Max(field, x) => {
+        let field = self.builder.alloc_ident(*field);
+        let x = self.generate_expr(g, None, *x)?;
+        self.push_node((TwGraphNode::Max(field), vec![x], precondition), name)?
+      }
       K::Pop(x) => {
         let x = self.generate_expr(g, None, *x)?;
         self.push_node((TwGraphNode::PopFromList, vec![x], precondition), name)?
@@ -415,6 +1043,21 @@ impl<'a, 'b> GraphContext<'a, 'b> {
         let x = self.generate_expr(g, None, *x)?;
         self.push_node((TwGraphNode::ListHead, vec![x], precondition), name)?
       }
+      K::Sort(target_graph, x) => {
+        let (i, _) = self
+          .builder
+          .root
+          .graphs
+          .iter()
+          .enumerate()
+          .find(|(_, x)| x.name == *target_graph)
+          .ok_or_else(|| TwAsmError::GraphNotFound(target_graph.to_string()))?;
+        let x = self.generate_expr(g, None, *x)?;
+        self.push_node(
+          (TwGraphNode::SortList(i as u32), vec![x], precondition),
+          name,
+        )?
+      }
       K::BuildSet(x) => {
         let x = self.generate_expr(g, None, *x)?;
         self.push_node((TwGraphNode::BuildSet, vec![x], precondition), name)?
@@ -429,6 +1072,11 @@ impl<'a, 'b> GraphContext<'a, 'b> {
     name: Option<&'a str>,
   ) -> Result<u32> {
     let index = self.target.nodes.len() as u32;
+    self
+      .target
+      .optional_chain
+      .push(node.0.is_optional_chained());
+    self.target.deferred.push(self.deferred);
     self.target.nodes.push(node);
     if let Some(name) = name {
       if self.names.contains_key(name) {
@@ -439,14 +1087,89 @@ impl<'a, 'b> GraphContext<'a, 'b> {
     Ok(index)
   }
 
-  fn lookup_node(&self, name: &str) -> Result<u32> {
-    match self.names.get(name) {
-      Some(x) => Ok(*x),
-      None => Err(TwAsmError::NodeNotFound(name.to_string()).into()),
+  fn lookup_node(&mut self, name: &'a str) -> Result<u32> {
+    if let Some(x) = self.names.get(name) {
+      return Ok(*x);
+    }
+
+    // Capture the node from the innermost enclosing graph that has it, through each graph in
+    // between.
+    let depth = match self
+      .enclosing
+      .iter()
+      .rposition(|x| x.names.contains_key(name))
+    {
+      Some(x) => x,
+      None => {
+        let value = *self
+          .builder
+          .consts
+          .get(name)
+          .ok_or_else(|| TwAsmError::NodeNotFound(name.to_string()))?;
+        return self.push_node((TwGraphNode::LoadConst(value), vec![], None), None);
+      }
+    };
+    let mut node = self.enclosing[depth].names[name];
+    let unknown_type = self.builder.alloc_vmtype(VmType::Unknown);
+    for scope in &mut self.enclosing[depth + 1..] {
+      node = capture(
+        &mut scope.target,
+        &mut scope.names,
+        &mut scope.captures,
+        name,
+        node,
+        unknown_type,
+      );
     }
+    Ok(capture(
+      &mut self.target,
+      &mut self.names,
+      &mut self.captures,
+      name,
+      node,
+      unknown_type,
+    ))
+  }
+}
+
+/// Defers the nodes that depend on deferred nodes, e.g. uses of names assigned in a `defer` block
+/// by statements after it.
+fn propagate_deferred(g: &mut TwGraph) {
+  for i in 0..g.nodes.len() {
+    let (_, in_edges, precondition) = &g.nodes[i];
+    if in_edges
+      .iter()
+      .chain(precondition.iter())
+      .any(|x| g.deferred[*x as usize])
+    {
+      g.deferred[i] = true;
+    }
+  }
+  if !g.deferred.contains(&true) {
+    g.deferred.clear();
   }
 }
 
+/// Adds `node` of the enclosing graph as a param of `target`, named `name`.
+fn capture<'a>(
+  target: &mut TwGraph,
+  names: &mut HashMap<&'a str, u32>,
+  captures: &mut Vec<u32>,
+  name: &'a str,
+  node: u32,
+  unknown_type: u32,
+) -> u32 {
+  let index = target.nodes.len() as u32;
+  let load = TwGraphNode::LoadParam(captures.len() as u32);
+  target.optional_chain.push(load.is_optional_chained());
+  target.deferred.push(false);
+  target.nodes.push((load, vec![], None));
+  target.param_types.push(unknown_type);
+  names.insert(name, index);
+  captures.push(node);
+  index
+}
+
 impl<'a> Builder<'a> {
   fn alloc_vmtype(&mut self, ty: VmType<String>) -> u32 {
     if let Some(x) = self.vmtype_pool.get(&ty) {
@@ -530,6 +1253,7 @@ impl<'a> Builder<'a> {
           .collect::<Result<_>>()?,
       ),
       ast::Type::Bool => VmType::Bool,
+      ast::Type::Json => VmType::Json,
       ast::Type::Schema => VmType::Schema,
       ast::Type::List(x) => VmType::List(VmListType {
         ty: Box::new(self.generate_vmtype(*x)?),
@@ -565,6 +1289,48 @@ fn parse<'a, 'b: 'a>(alloc: &'a Bump, input: &'b str) -> Result<ast::Root<'a>> {
   Ok(root)
 }
 
+fn generate_cache_directive(g: &ast::Graph) -> Result<Option<TwCacheDirective>> {
+  if let Some(x) = first_duplicate(g.annotations.iter().map(|x| x.name)) {
+    return Err(TwAsmError::DuplicateGraphAnnotation(x.into()).into());
+  }
+
+  let mut directive = None;
+  for annotation in &g.annotations {
+    match annotation.name {
+      "cache" => {
+        let mut ttl_ms = None;
+        let mut key = TwCacheKey::Params;
+        for (arg, value) in &annotation.args {
+          match (*arg, value) {
+            ("ttl", ast::AnnotationValue::Duration(x)) if ttl_ms.is_none() => ttl_ms = Some(*x),
+            ("key", ast::AnnotationValue::Identifier("params")) => key = TwCacheKey::Params,
+            ("key", ast::AnnotationValue::Identifier("global")) => key = TwCacheKey::Global,
+            _ => {
+              return Err(
+                TwAsmError::InvalidGraphAnnotationArg(annotation.name.into(), arg.to_string())
+                  .into(),
+              )
+            }
+          }
+        }
+        let ttl_ms = ttl_ms.ok_or_else(|| {
+          TwAsmError::InvalidGraphAnnotationArg(annotation.name.into(), "ttl".into())
+        })?;
+        directive = Some(TwCacheDirective { ttl_ms, key });
+      }
+      "relaxed_isolation" => {
+        if let Some((arg, _)) = annotation.args.first() {
+          return Err(
+            TwAsmError::InvalidGraphAnnotationArg(annotation.name.into(), arg.to_string()).into(),
+          );
+        }
+      }
+      _ => return Err(TwAsmError::UnknownGraphAnnotation(annotation.name.into()).into()),
+    }
+  }
+  Ok(directive)
+}
+
 fn format_type_for_table(ty: &ast::Type) -> Result<String> {
   Ok(match ty {
     ast::Type::Primitive(x) => match x {
@@ -0,0 +1,1001 @@
+//! Disassembler from compiled scripts back to the assembly language, for reading stored scripts
+//! and for golden tests of code generation.
+//!
+//! Each node is written as a statement, named after the field it reads where possible and after
+//! its index otherwise. Nodes with a precondition are grouped into `if` blocks on the precondition
+//! node, and `try` blocks are rebuilt from the nodes generated for them. The output assembles to a
+//! script that behaves the same, but not always to the same nodes: the conditions generated for
+//! `else` blocks and for `??` and `if` expressions are written out as nodes of their own.
+
+use std::collections::{BTreeMap, HashMap, HashSet};
+
+use anyhow::Result;
+
+use crate::data::value::PrimitiveValue;
+
+use super::{
+  super::{
+    bytecode::{TwCacheKey, TwGraph, TwGraphNode, TwScript},
+    vm_value::{VmConst, VmType},
+  },
+  TwAsmError,
+};
+
+/// Words that the lexer takes as keywords. Identifiers spelled like them are written in
+/// backticks.
+const KEYWORDS: &[&str] = &[
+  "__ns",
+  "bool",
+  "build_set",
+  "build_table",
+  "bytes",
+  "bytes_concat",
+  "bytes_from_base64",
+  "bytes_from_hex",
+  "bytes_from_string",
+  "bytes_length",
+  "bytes_slice",
+  "bytes_to_base64",
+  "bytes_to_hex",
+  "bytes_to_string",
+  "call",
+  "catch",
+  "const",
+  "create_list",
+  "create_map",
+  "d_delete",
+  "d_get",
+  "d_put",
+  "d_range",
+  "defer",
+  "double",
+  "else",
+  "empty_set",
+  "env",
+  "export",
+  "false",
+  "for",
+  "format_double",
+  "from",
+  "gen_id",
+  "graph",
+  "head",
+  "if",
+  "int64",
+  "is_null",
+  "is_present",
+  "json",
+  "json_get",
+  "json_parse",
+  "json_serialize",
+  "l_get",
+  "l_len",
+  "l_pop",
+  "l_push",
+  "list",
+  "m_delete",
+  "m_get",
+  "m_insert",
+  "m_keys",
+  "m_values",
+  "map",
+  "mount",
+  "null",
+  "param",
+  "parse_double",
+  "point_get",
+  "point_get_many",
+  "pop",
+  "readonly",
+  "reduce",
+  "return",
+  "s_count",
+  "s_delete",
+  "s_insert",
+  "s_max",
+  "s_min",
+  "s_sum",
+  "scan_index",
+  "scan_index_desc",
+  "schema",
+  "select",
+  "set",
+  "sort",
+  "str_contains",
+  "str_length",
+  "str_starts_with",
+  "str_substring",
+  "str_to_lower",
+  "string",
+  "t_add",
+  "t_cas",
+  "t_insert",
+  "throw",
+  "time_add_days",
+  "time_day_of_week",
+  "time_now",
+  "time_start_of_day",
+  "to",
+  "true",
+  "try",
+  "type",
+];
+
+/// Disassembles `script`. Fails on nodes that the assembler never generates, and on constructs
+/// that cannot be written in the assembly language, like table consts.
+pub fn disassemble(script: &TwScript) -> Result<String> {
+  script.validate()?;
+
+  let try_graphs = script
+    .graphs
+    .iter()
+    .flat_map(|g| g.nodes.iter())
+    .filter_map(|(node, _, _)| match node {
+      TwGraphNode::Try(x) => Some(*x as usize),
+      _ => None,
+    })
+    .collect::<HashSet<_>>();
+
+  // Inlining names the copies of graphs like `name$specialized0`.
+  let mut used = HashSet::new();
+  let graph_names = script
+    .graphs
+    .iter()
+    .map(|g| ident(&fresh_name(&mut used, &sanitize(&g.name))))
+    .collect::<Vec<_>>();
+  let d = Disassembler {
+    script,
+    graph_names,
+  };
+
+  let mut w = Writer::default();
+  for x in &script.mounts {
+    w.line(format!("mount {};", serde_json::to_string(x)?));
+  }
+  if !script.params.is_empty() {
+    w.separate();
+    w.open("param {");
+    for p in &script.params {
+      let mut line = format!(
+        "{}: {}",
+        ident(&p.name),
+        format_type(&script.types[p.ty as usize])?
+      );
+      if let Some(x) = p.default {
+        let value = format_literal(&script.consts[x as usize])?.ok_or_else(|| {
+          TwAsmError::NoAssembly(format!("default value of script param `{}`", p.name))
+        })?;
+        line = format!("{} = {}", line, value);
+      }
+      w.line(format!("{},", line));
+    }
+    w.close();
+  }
+  for i in 0..script.graphs.len() {
+    if !try_graphs.contains(&i) {
+      w.separate();
+      d.write_graph(&mut w, i)?;
+    }
+  }
+  Ok(w.out)
+}
+
+struct Disassembler<'a> {
+  script: &'a TwScript,
+  graph_names: Vec<String>,
+}
+
+impl<'a> Disassembler<'a> {
+  fn write_graph(&self, w: &mut Writer, index: usize) -> Result<()> {
+    let g = &self.script.graphs[index];
+    if let Some(x) = &g.cache {
+      let key = match x.key {
+        TwCacheKey::Params => "params",
+        TwCacheKey::Global => "global",
+      };
+      w.line(format!("@cache(ttl = {}ms, key = {})", x.ttl_ms, key));
+    }
+    if g.relaxed_isolation {
+      w.line("@relaxed_isolation");
+    }
+
+    let mut used = HashSet::new();
+    let mut param_names = vec![];
+    let mut params = vec![];
+    for (i, ty) in g.param_types.iter().enumerate() {
+      let name = match g.param_names.get(i) {
+        Some(x) => sanitize(x),
+        None => format!("p{}", i),
+      };
+      let name = ident(&fresh_name(&mut used, &name));
+      params.push(match &self.script.types[*ty as usize] {
+        VmType::Unknown => name.clone(),
+        ty => format!("{}: {}", name, format_type(ty)?),
+      });
+      param_names.push(name);
+    }
+    let output_type = match g.output_type.map(|x| &self.script.types[x as usize]) {
+      None | Some(VmType::Unknown) => String::new(),
+      Some(ty) => format!(": {}", format_type(ty)?),
+    };
+    w.open(format!(
+      "{}{}graph {}({}){} {{",
+      if g.exported { "export " } else { "" },
+      if g.read_only { "readonly " } else { "" },
+      self.graph_names[index],
+      params.join(", "),
+      output_type,
+    ));
+    GraphDisasm::new(self, g, param_names)?.write_body(w, &mut used)?;
+    w.close();
+    Ok(())
+  }
+}
+
+#[derive(Copy, Clone, Debug, Eq, PartialEq)]
+enum Role {
+  /// Written as a statement.
+  Stmt,
+
+  /// A graph param, or a const written as a literal at each use.
+  Inline,
+
+  /// Named by the `try` block it was generated for: the caught error, or the output.
+  Bound,
+
+  /// Generated for a `try` block, and never written.
+  Hidden,
+}
+
+#[derive(Copy, Clone, Debug)]
+struct TryBlock {
+  error: u32,
+
+  /// The condition that the nodes of the catch block are generated under.
+  failed: u32,
+
+  /// The node that selects between the outputs of the try and catch blocks, and the node returned
+  /// by the catch block.
+  output: Option<(u32, u32)>,
+}
+
+/// Disassembly of a graph, or of the body of a `try` block.
+struct GraphDisasm<'a> {
+  d: &'a Disassembler<'a>,
+  g: &'a TwGraph,
+  roles: Vec<Role>,
+
+  /// Names of the nodes written so far, and the literals of inlined consts.
+  names: Vec<Option<String>>,
+
+  tries: BTreeMap<u32, TryBlock>,
+
+  /// The innermost `try` block whose catch block each node belongs to.
+  owner: Vec<Option<u32>>,
+
+  /// Unconditional nodes of catch blocks that only depend on nodes before the `try` block. They
+  /// are written before the `try` block instead, so that they still run on success.
+  hoisted: HashMap<u32, Vec<u32>>,
+
+  /// The nodes of each block: the graph body, or the catch block of a `try` block.
+  blocks: HashMap<Option<u32>, Vec<u32>>,
+
+  needed: Vec<bool>,
+  referenced: Vec<bool>,
+}
+
+impl<'a> GraphDisasm<'a> {
+  fn new(d: &'a Disassembler<'a>, g: &'a TwGraph, param_names: Vec<String>) -> Result<Self> {
+    let n = g.nodes.len();
+    let mut this = Self {
+      d,
+      g,
+      roles: vec![Role::Stmt; n],
+      names: vec![None; n],
+      tries: BTreeMap::new(),
+      owner: vec![None; n],
+      hoisted: HashMap::new(),
+      blocks: HashMap::new(),
+      needed: vec![false; n],
+      referenced: vec![false; n],
+    };
+    let mut users = vec![vec![]; n];
+    for (i, (_, in_edges, _)) in g.nodes.iter().enumerate() {
+      for x in in_edges {
+        users[*x as usize].push(i as u32);
+      }
+    }
+
+    for (i, (node, _, _)) in g.nodes.iter().enumerate() {
+      match node {
+        TwGraphNode::LoadParam(x) => {
+          this.roles[i] = Role::Inline;
+          this.names[i] = Some(
+            param_names
+              .get(*x as usize)
+              .cloned()
+              .ok_or_else(|| this.no_assembly(i as u32))?,
+          );
+        }
+        TwGraphNode::Try(_) => {
+          let block = this.match_try(i as u32, &users)?;
+          this.tries.insert(i as u32, block);
+        }
+        _ => {}
+      }
+    }
+    this.assign_blocks()?;
+    this.inline_consts(&users)?;
+    this.find_needed();
+    Ok(this)
+  }
+
+  fn no_assembly(&self, i: u32) -> anyhow::Error {
+    TwAsmError::NoAssembly(format!("node {} of graph `{}`", i, self.g.name)).into()
+  }
+
+  fn precondition(&self, i: u32) -> Option<u32> {
+    self.g.nodes[i as usize].2
+  }
+
+  /// Matches the nodes that `generate_try` generates around the `Try` node `t`.
+  fn match_try(&mut self, t: u32, users: &[Vec<u32>]) -> Result<TryBlock> {
+    let nodes = &self.g.nodes;
+    let idents = &self.d.script.idents;
+    let field_of_t = |i: u32, name: &str| match &nodes[i as usize] {
+      (TwGraphNode::GetField(x), in_edges, _) => {
+        idents[*x as usize] == name && in_edges.as_slice() == [t]
+      }
+      _ => false,
+    };
+    let find = |node: TwGraphNode, in_edges: &[u32]| {
+      users[in_edges[0] as usize]
+        .iter()
+        .copied()
+        .find(|x| nodes[*x as usize] == (node, in_edges.to_vec(), None))
+    };
+    let under_try = |x: u32| match self.precondition(t) {
+      Some(p) => find(TwGraphNode::And, &[x, p]),
+      None => Some(x),
+    };
+
+    let mismatch = || self.no_assembly(t);
+    let error = users[t as usize]
+      .iter()
+      .copied()
+      .find(|x| field_of_t(*x, "error") && nodes[*x as usize].2.is_none())
+      .ok_or_else(mismatch)?;
+    let succeeded = find(TwGraphNode::IsNull, &[error]).ok_or_else(mismatch)?;
+    let failed = find(TwGraphNode::Not, &[succeeded]).ok_or_else(mismatch)?;
+    let failed = under_try(failed).ok_or_else(mismatch)?;
+    let value = users[t as usize].iter().copied().find(|x| {
+      field_of_t(*x, "value") && nodes[*x as usize].2 == under_try(succeeded) && x != &error
+    });
+    if users[t as usize]
+      .iter()
+      .any(|x| *x != error && Some(*x) != value)
+    {
+      return Err(mismatch());
+    }
+
+    let output = match value {
+      Some(value) => {
+        let (select, nop) = match users[value as usize].as_slice() {
+          [x] => match &nodes[*x as usize] {
+            (TwGraphNode::Select, in_edges, None) if in_edges[0] == value => (*x, in_edges[1]),
+            _ => return Err(mismatch()),
+          },
+          _ => return Err(mismatch()),
+        };
+        let handler = match &nodes[nop as usize] {
+          (TwGraphNode::Nop, in_edges, Some(x))
+            if *x == failed && users[nop as usize].as_slice() == [select] =>
+          {
+            in_edges[0]
+          }
+          _ => return Err(mismatch()),
+        };
+        self.roles[value as usize] = Role::Hidden;
+        self.roles[nop as usize] = Role::Hidden;
+        self.roles[select as usize] = Role::Bound;
+        Some((select, handler))
+      }
+      None => None,
+    };
+    self.roles[error as usize] = Role::Bound;
+    Ok(TryBlock {
+      error,
+      failed,
+      output,
+    })
+  }
+
+  /// Whether the precondition `x` implies `condition`, through conditions generated for nested
+  /// `if` blocks.
+  fn implies(&self, mut x: Option<u32>, condition: u32) -> bool {
+    while let Some(i) = x {
+      if i == condition {
+        return true;
+      }
+      x = match &self.g.nodes[i as usize] {
+        (TwGraphNode::And, in_edges, None) => Some(in_edges[1]),
+        _ => None,
+      };
+    }
+    false
+  }
+
+  fn assign_blocks(&mut self) -> Result<()> {
+    let n = self.g.nodes.len() as u32;
+
+    // The catch block of a `try` block runs up to its last node under the failure condition.
+    // Nested `try` blocks come later, and take over the nodes of their own catch blocks.
+    for (&t, block) in &self.tries {
+      let mut end = block.output.map(|x| x.0).unwrap_or(t);
+      for i in t + 1..n {
+        if self.implies(self.precondition(i), block.failed) {
+          end = i;
+        }
+      }
+      for i in t + 1..=end {
+        self.owner[i as usize] = Some(t);
+      }
+    }
+
+    for i in 0..n {
+      if self.roles[i as usize] != Role::Stmt {
+        continue;
+      }
+      let owner = self.owner[i as usize];
+      match (owner, self.precondition(i)) {
+        (Some(t), Some(p)) if !self.implies(Some(p), self.tries[&t].failed) => {
+          return Err(self.no_assembly(i));
+        }
+        (Some(_), None) if !matches!(self.g.nodes[i as usize].0, TwGraphNode::Try(_)) => {
+          // The outermost `try` block that all params of the node come before.
+          let last_param = self.g.nodes[i as usize].1.iter().max().copied();
+          let mut target = None;
+          let mut t = owner;
+          while let Some(x) = t {
+            if last_param.map(|p| p < x).unwrap_or(true) {
+              target = Some(x);
+            }
+            t = self.owner[x as usize];
+          }
+          if let Some(x) = target {
+            self.hoisted.entry(x).or_default().push(i);
+            continue;
+          }
+        }
+        _ => {}
+      }
+      self.blocks.entry(owner).or_default().push(i);
+    }
+    Ok(())
+  }
+
+  /// Writes consts as literals where each literal, generated under the precondition of its user,
+  /// behaves like the const.
+  fn inline_consts(&mut self, users: &[Vec<u32>]) -> Result<()> {
+    let mut used_as_precondition = vec![false; self.g.nodes.len()];
+    for (_, _, precondition) in &self.g.nodes {
+      if let Some(x) = precondition {
+        used_as_precondition[*x as usize] = true;
+      }
+    }
+    for (i, (node, _, precondition)) in self.g.nodes.iter().enumerate() {
+      let literal = match node {
+        TwGraphNode::LoadConst(x) => match format_literal(&self.d.script.consts[*x as usize])? {
+          Some(x) => x,
+          None => continue,
+        },
+        _ => continue,
+      };
+      let inline = !used_as_precondition[i]
+        && (self.g.output != Some(i as u32) || precondition.is_none())
+        && users[i].iter().all(|x| {
+          self.roles[*x as usize] == Role::Stmt
+            && !matches!(self.g.nodes[*x as usize].0, TwGraphNode::Try(_))
+            && (precondition.is_none() || self.precondition(*x) == *precondition)
+        });
+      if inline {
+        self.roles[i] = Role::Inline;
+        self.names[i] = Some(literal);
+      }
+    }
+    Ok(())
+  }
+
+  /// The `if` blocks that node `i` is written in, inside the block it belongs to: the condition
+  /// of each `if` block, and the node it is written with, outermost first.
+  ///
+  /// In catch blocks, the conditions of nested `if` blocks are unwound down to the failure
+  /// condition, so that the assembler generates them again instead of nesting them further.
+  fn condition_path(&self, i: u32) -> Vec<(u32, u32)> {
+    let mut x = match self.precondition(i) {
+      Some(x) => x,
+      None => return vec![],
+    };
+    let condition = match self.owner[i as usize] {
+      Some(t) => self.tries[&t].failed,
+      None => return vec![(x, x)],
+    };
+    let mut path = vec![];
+    while x != condition {
+      match &self.g.nodes[x as usize] {
+        (TwGraphNode::And, in_edges, None) => {
+          path.push((x, in_edges[0]));
+          x = in_edges[1];
+        }
+        _ => unreachable!(),
+      }
+    }
+    path.reverse();
+    path
+  }
+
+  fn find_needed(&mut self) {
+    // Conditions generated by the assembler are only written if something refers to them.
+    for (i, (node, _, precondition)) in self.g.nodes.iter().enumerate() {
+      self.needed[i] = self.roles[i] == Role::Stmt
+        && !(precondition.is_none()
+          && matches!(
+            node,
+            TwGraphNode::Not | TwGraphNode::IsNull | TwGraphNode::And
+          ));
+    }
+    if let Some(x) = self.g.output {
+      self.referenced[x as usize] = true;
+      if self.roles[x as usize] == Role::Stmt {
+        self.needed[x as usize] = true;
+      }
+    }
+
+    // Try blocks refer to the later nodes that their catch blocks return.
+    loop {
+      let mut changed = false;
+      for i in (0..self.g.nodes.len()).rev() {
+        if !self.needed[i] {
+          continue;
+        }
+        let mut refs = self.g.nodes[i].1.clone();
+        refs.extend(self.condition_path(i as u32).into_iter().map(|x| x.1));
+        if let Some((_, handler)) = self.tries.get(&(i as u32)).and_then(|x| x.output) {
+          refs.push(handler);
+        }
+        for x in refs {
+          let x = x as usize;
+          if !self.referenced[x] || (self.roles[x] == Role::Stmt && !self.needed[x]) {
+            self.referenced[x] = true;
+            self.needed[x] |= self.roles[x] == Role::Stmt;
+            changed = true;
+          }
+        }
+      }
+      if !changed {
+        break;
+      }
+    }
+  }
+
+  fn operand(&self, i: u32) -> Result<String> {
+    self.names[i as usize]
+      .clone()
+      .ok_or_else(|| self.no_assembly(i))
+  }
+
+  fn define(&mut self, used: &mut HashSet<String>, i: u32) -> String {
+    let base = match &self.g.nodes[i as usize].0 {
+      TwGraphNode::GetField(x) => Some(&self.d.script.idents[*x as usize])
+        .filter(|x| is_identifier(x) && !KEYWORDS.contains(&x.as_str()))
+        .cloned(),
+      _ => None,
+    };
+    let name = fresh_name(used, &base.unwrap_or_else(|| format!("v{}", i)));
+    self.names[i as usize] = Some(name.clone());
+    name
+  }
+
+  fn write_body(&mut self, w: &mut Writer, used: &mut HashSet<String>) -> Result<()> {
+    let items = self.blocks.remove(&None).unwrap_or_default();
+    self.write_block(w, used, &items, false)?;
+    if let Some(x) = self.g.output {
+      w.line(format!("return {};", self.operand(x)?));
+    }
+    Ok(())
+  }
+
+  /// Writes the nodes of a block. `deferred` is whether the block is in a `defer` block already.
+  fn write_block(
+    &mut self,
+    w: &mut Writer,
+    used: &mut HashSet<String>,
+    items: &[u32],
+    deferred: bool,
+  ) -> Result<()> {
+    // The open `defer` (`None`) and `if` blocks, outermost first.
+    let mut open: Vec<Option<u32>> = vec![];
+    for &i in items {
+      let hoisted = self.hoisted.remove(&i).unwrap_or_default();
+      for j in hoisted.into_iter().chain(std::iter::once(i)) {
+        if !self.needed[j as usize] {
+          continue;
+        }
+        let mut path = vec![];
+        if !deferred && self.g.is_deferred(j as usize) {
+          path.push((None, "defer {".to_string()));
+        }
+        for (condition, x) in self.condition_path(j) {
+          path.push((Some(condition), format!("if {} {{", self.operand(x)?)));
+        }
+        let common = open
+          .iter()
+          .zip(path.iter())
+          .take_while(|(x, y)| **x == y.0)
+          .count();
+        for _ in common..open.len() {
+          w.close();
+        }
+        open.truncate(common);
+        for (block, header) in path.drain(common..) {
+          w.open(header);
+          open.push(block);
+        }
+        self.write_stmt(w, used, j)?;
+      }
+    }
+    for _ in 0..open.len() {
+      w.close();
+    }
+    Ok(())
+  }
+
+  fn write_stmt(&mut self, w: &mut Writer, used: &mut HashSet<String>, i: u32) -> Result<()> {
+    let (node, in_edges, _) = &self.g.nodes[i as usize];
+    match node {
+      TwGraphNode::Throw => {
+        let value = self.operand(in_edges[0])?;
+        w.line(format!("throw {};", value));
+      }
+      TwGraphNode::Try(x) => self.write_try(w, used, i, *x)?,
+      _ => {
+        let expr = self.format_node(i)?;
+        if self.referenced[i as usize] || node.is_pure() {
+          let name = self.define(used, i);
+          w.line(format!("{} = {};", name, expr));
+        } else {
+          w.line(format!("{};", expr));
+        }
+      }
+    }
+    Ok(())
+  }
+
+  fn write_try(
+    &mut self,
+    w: &mut Writer,
+    used: &mut HashSet<String>,
+    t: u32,
+    subgraph: u32,
+  ) -> Result<()> {
+    let block = self.tries[&t];
+    let captures = self.g.nodes[t as usize]
+      .1
+      .iter()
+      .map(|x| self.operand(*x))
+      .collect::<Result<Vec<_>>>()?;
+    let prefix = match block.output {
+      Some((select, _)) if self.referenced[select as usize] => {
+        format!("{} = ", self.define(used, select))
+      }
+      _ => String::new(),
+    };
+
+    w.open(format!("{}try {{", prefix));
+    let body = &self.d.script.graphs[subgraph as usize];
+    GraphDisasm::new(self.d, body, captures)?.write_body(w, used)?;
+    let error = self.define(used, block.error);
+    w.indent -= 1;
+    w.line(format!("}} catch ({}) {{", error));
+    w.indent += 1;
+    let items = self.blocks.remove(&Some(t)).unwrap_or_default();
+    let deferred = self.g.is_deferred(t as usize);
+    self.write_block(w, used, &items, deferred)?;
+    if let Some((_, handler)) = block.output {
+      w.line(format!("return {};", self.operand(handler)?));
+    }
+    w.close();
+    Ok(())
+  }
+
+  fn format_node(&self, i: u32) -> Result<String> {
+    use TwGraphNode as N;
+
+    let script = self.d.script;
+    let (node, in_edges, _) = &self.g.nodes[i as usize];
+    let p = in_edges
+      .iter()
+      .map(|x| self.operand(*x))
+      .collect::<Result<Vec<_>>>()?;
+    let field = |x: &u32| ident(&script.idents[*x as usize]);
+    let graph = |x: &u32| &self.d.graph_names[*x as usize];
+    let binary = |op: &str| format!("{} {} {}", p[0], op, p[1]);
+    let prefix = |keyword: &str| format!("{} {}", keyword, p.join(" "));
+
+    Ok(match node {
+      N::LoadConst(x) => match &script.consts[*x as usize] {
+        // Doubles have no literals.
+        VmConst::Primitive(PrimitiveValue::Double(x)) => format!(
+          "parse_double {}",
+          serde_json::to_string(&format!("{:?}", f64::from_bits(*x)))?
+        ),
+        x => format_literal(x)?.ok_or_else(|| self.no_assembly(i))?,
+      },
+      N::LoadEnv => "env".into(),
+      N::BuildTable(x) => format!(
+        "build_table({}) {}",
+        format_table_type(&script.idents[*x as usize]),
+        p[0]
+      ),
+      N::BuildSet => prefix("build_set"),
+      N::CreateMap => "create_map".into(),
+      N::CreateList(x) => format!("create_list({})", format_type(&script.types[*x as usize])?),
+      N::PrependToList => binary(":"),
+      N::PopFromList => prefix("pop"),
+      N::ListHead => prefix("head"),
+      N::SortList(g) => format!("sort({}) {}", graph(g), p[0]),
+      N::Reduce(g, false) => format!("reduce({}) {} {} {}", graph(g), p[0], p[1], p[2]),
+      N::Reduce(g, true) => format!(
+        "reduce({}) from {} to {} {} {} {}",
+        graph(g),
+        p[3],
+        p[4],
+        p[0],
+        p[1],
+        p[2]
+      ),
+      N::Loop(g) => format!(
+        "for({}) from {} to {} {} {}",
+        graph(g),
+        p[2],
+        p[3],
+        p[0],
+        p[1]
+      ),
+      N::GetField(x) => {
+        let name = &script.idents[*x as usize];
+        let op = if self.g.is_optional_chained(i as usize) {
+          "?."
+        } else {
+          "."
+        };
+        if is_identifier(name) {
+          format!("{}{}{}", p[0], op, ident(name))
+        } else {
+          // Namespaces are mounted under names that need not be identifiers.
+          match &self.g.nodes[in_edges[0] as usize] {
+            (N::GetField(ns), ns_in_edges, _) if script.idents[*ns as usize] == "__ns" => format!(
+              "{}.__ns({})",
+              self.operand(ns_in_edges[0])?,
+              serde_json::to_string(name)?
+            ),
+            _ => return Err(self.no_assembly(i)),
+          }
+        }
+      }
+      N::GetSetElement => format!("point_get {} {}", p[1], p[0]),
+      N::GetSetElementByTuple => {
+        let (set, components) = p.split_last().unwrap();
+        format!("point_get {} ({})", set, components.join(", "))
+      }
+      N::GetSetElements => format!("point_get_many {} {}", p[1], p[0]),
+      N::InsertIntoMap(x) => format!("m_insert({}) {} {}", field(x), p[0], p[1]),
+      N::InsertIntoTable(x) => format!("t_insert({}) {} {}", field(x), p[1], p[0]),
+      N::InsertIntoSet => format!("s_insert {} {}", p[1], p[0]),
+      N::DeleteFromSet => format!("s_delete {} {}", p[1], p[0]),
+      N::ListPush(x) => format!("l_push({}) {} {}", field(x), p[1], p[0]),
+      N::ListPop(x) => format!("l_pop({}) {}", field(x), p[0]),
+      N::ListGet(x) => format!("l_get({}) {} {}", field(x), p[1], p[0]),
+      N::ListLen(x) => format!("l_len({}) {}", field(x), p[0]),
+      N::AtomicAdd(x) => format!("t_add({}) {} {}", field(x), p[1], p[0]),
+      N::CheckVersion => format!("t_cas {} {}", p[1], p[0]),
+      N::DictGet(x) => format!("d_get({}) {} {}", field(x), p[1], p[0]),
+      N::DictPut(x) => format!("d_put({}) {} {} {}", field(x), p[2], p[0], p[1]),
+      N::DictDelete(x) => format!("d_delete({}) {} {}", field(x), p[1], p[0]),
+      N::DictRange(x) => format!("d_range({}) {} {} {}", field(x), p[2], p[0], p[1]),
+      N::DeleteFromMap(x) => format!("m_delete({}) {}", field(x), p[0]),
+      N::JsonParse => prefix("json_parse"),
+      N::JsonSerialize => prefix("json_serialize"),
+      N::JsonGet(x) => match &script.types[*x as usize] {
+        VmType::Json => prefix("json_get"),
+        ty => format!("json_get<{}> {} {}", format_type(ty)?, p[0], p[1]),
+      },
+      N::MapKeys => prefix("m_keys"),
+      N::MapValues => prefix("m_values"),
+      N::MapGetDynamic => prefix("m_get"),
+      N::Eq => binary("=="),
+      N::Ne => binary("!="),
+      N::Lt => binary("<"),
+      N::Le => binary("<="),
+      N::Gt => binary(">"),
+      N::Ge => binary(">="),
+      N::And => binary("&&"),
+      N::Or => binary("||"),
+      N::Not => format!("!{}", p[0]),
+      N::Select => prefix("select"),
+      N::Cond => format!("if {} {{ {} }} else {{ {} }}", p[0], p[1], p[2]),
+      N::IsPresent => prefix("is_present"),
+      N::IsNull => prefix("is_null"),
+      N::Nop => format!("select {} {}", p[0], p[0]),
+      N::UnwrapOptional => format!("{}!", p[0]),
+      N::Call(g) => format!("call({}) [{}]", graph(g), p.join(", ")),
+      N::Add => binary("+"),
+      N::Sub => binary("-"),
+      N::Mul => binary("*"),
+      N::Div => binary("/"),
+      N::Mod => binary("%"),
+      N::Neg => format!("-{}", p[0]),
+      N::ParseDouble => prefix("parse_double"),
+      N::FormatDouble => prefix("format_double"),
+      N::StrStartsWith => prefix("str_starts_with"),
+      N::StrContains => prefix("str_contains"),
+      N::StrSubstring => prefix("str_substring"),
+      N::StrToLower => prefix("str_to_lower"),
+      N::StrLength => prefix("str_length"),
+      N::BytesConcat => prefix("bytes_concat"),
+      N::BytesSlice => prefix("bytes_slice"),
+      N::BytesLength => prefix("bytes_length"),
+      N::BytesToHex => prefix("bytes_to_hex"),
+      N::BytesFromHex => prefix("bytes_from_hex"),
+      N::BytesToBase64 => prefix("bytes_to_base64"),
+      N::BytesFromBase64 => prefix("bytes_from_base64"),
+      N::BytesToString => prefix("bytes_to_string"),
+      N::BytesFromString => prefix("bytes_from_string"),
+      N::TimeNow => "time_now".into(),
+      N::TimeAddDays => prefix("time_add_days"),
+      N::TimeStartOfDay => prefix("time_start_of_day"),
+      N::TimeDayOfWeek => prefix("time_day_of_week"),
+      N::GenId => "gen_id".into(),
+      N::ScanIndex(x, false) => format!("scan_index({}) {}", field(x), p[0]),
+      N::ScanIndex(x, true) => format!(
+        "scan_index({}) from {} to {} {}",
+        field(x),
+        p[1],
+        p[2],
+        p[0]
+      ),
+      N::ScanIndexLimited(x, range, reverse) => format!(
+        "{}({}, {}){} {}",
+        if *reverse {
+          "scan_index_desc"
+        } else {
+          "scan_index"
+        },
+        field(x),
+        p[p.len() - 1],
+        if *range {
+          format!(" from {} to {}", p[1], p[2])
+        } else {
+          "".into()
+        },
+        p[0]
+      ),
+      N::Count => prefix("s_count"),
+      N::Sum(x) => format!("s_sum({}) {}", field(x), p[0]),
+      N::Min(x) => format!("s_min({}) {}", field(x), p[0]),
+      N::Max(x) => format!("s_max({}) {}", field(x), p[0]),
+      N::LoadParam(_) | N::Try(_) | N::Throw | N::FilterSet(_) => return Err(self.no_assembly(i)),
+    })
+  }
+}
+
+#[derive(Default)]
+struct Writer {
+  out: String,
+  indent: usize,
+}
+
+impl Writer {
+  fn line(&mut self, x: impl AsRef<str>) {
+    for _ in 0..self.indent {
+      self.out.push_str("  ");
+    }
+    self.out.push_str(x.as_ref());
+    self.out.push('\n');
+  }
+
+  fn open(&mut self, x: impl AsRef<str>) {
+    self.line(x);
+    self.indent += 1;
+  }
+
+  fn close(&mut self) {
+    self.indent -= 1;
+    self.line("}");
+  }
+
+  /// Separates top-level items with a blank line.
+  fn separate(&mut self) {
+    if !self.out.is_empty() {
+      self.out.push('\n');
+    }
+  }
+}
+
+/// Formats a const as a literal, if the assembly language has one for it.
+fn format_literal(x: &VmConst) -> Result<Option<String>> {
+  Ok(Some(match x {
+    VmConst::Primitive(PrimitiveValue::Int64(x)) if *x < 0 => format!("({})", x),
+    VmConst::Primitive(PrimitiveValue::Int64(x)) => x.to_string(),
+    VmConst::Primitive(PrimitiveValue::String(x)) => serde_json::to_string(x)?,
+    VmConst::Primitive(PrimitiveValue::Bytes(x)) => format!("h\"{}\"", hex::encode(x)),
+    VmConst::Bool(x) => x.to_string(),
+    VmConst::Null(ty) => format!("null<{}>", format_type(ty)?),
+    VmConst::Set(x) if x.members.is_empty() => {
+      format!("empty_set<{}>", format_table_type(&x.member_ty))
+    }
+    _ => return Ok(None),
+  }))
+}
+
+fn format_type(ty: &VmType<String>) -> Result<String> {
+  Ok(match ty {
+    VmType::Primitive(x) => x.to_string(),
+    VmType::Table(x) => format_table_type(&x.name),
+    VmType::Set(x) => format!("set<{}>", format_type(&x.ty)?),
+    VmType::List(x) => format!("list<{}>", format_type(&x.ty)?),
+    VmType::Map(x) if x.is_empty() => "map {}".into(),
+    VmType::Map(x) => format!(
+      "map {{ {} }}",
+      x.iter()
+        .map(|(k, v)| Ok(format!("{}: {}", ident(k), format_type(v)?)))
+        .collect::<Result<Vec<_>>>()?
+        .join(", ")
+    ),
+    VmType::Bool => "bool".into(),
+    VmType::Json => "json".into(),
+    VmType::Schema => "schema".into(),
+    VmType::Unknown => return Err(TwAsmError::NoAssembly("unknown type".into()).into()),
+  })
+}
+
+/// Table types are named like `Item<>`, with their type arguments.
+fn format_table_type(name: &str) -> String {
+  name.replace("<>", "")
+}
+
+fn is_identifier(x: &str) -> bool {
+  let mut chars = x.chars();
+  matches!(chars.next(), Some(x) if x.is_ascii_alphabetic() || x == '_')
+    && chars.all(|x| x.is_ascii_alphanumeric() || x == '_')
+}
+
+fn ident(x: &str) -> String {
+  if KEYWORDS.contains(&x) {
+    format!("`{}`", x)
+  } else {
+    x.to_string()
+  }
+}
+
+fn sanitize(x: &str) -> String {
+  let x = x
+    .chars()
+    .map(|x| if x.is_ascii_alphanumeric() { x } else { '_' })
+    .collect::<String>();
+  if is_identifier(&x) {
+    x
+  } else {
+    format!("_{}", x)
+  }
+}
+
+fn fresh_name(used: &mut HashSet<String>, base: &str) -> String {
+  if used.insert(base.to_string()) {
+    return base.to_string();
+  }
+  (2..)
+    .map(|i| format!("{}_{}", base, i))
+    .find(|x| used.insert(x.clone()))
+    .unwrap()
+}
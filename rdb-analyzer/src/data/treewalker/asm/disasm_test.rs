@@ -0,0 +1,207 @@
+use bumpalo::Bump;
+
+use crate::{
+  data::treewalker::{bytecode::TwGraphNode, typeck::GlobalTyckContext, vm::TwVm},
+  fixtures::FIXTURES,
+  schema::{compile::compile, grammar::parse},
+  storage_plan::planner::generate_plan_for_schema,
+};
+
+use super::{codegen::compile_twscript, disasm::disassemble};
+
+const SCHEMA: &str = r#"
+type Item {
+  @primary
+  id: string,
+  name: string,
+}
+export set<Item> items;
+"#;
+
+const SCRIPT: &str = r#"
+  param {
+    limit: int64 = 10,
+  }
+  mount "shared";
+  export graph main(root: schema, id: string, flag: bool): int64 {
+    item = point_get root.items id;
+    name = item?.name ?? "";
+    if flag {
+      s_insert root.items $ build_table(Item) $ m_insert(id) id $ m_insert(name) name create_map;
+    } else {
+      throw "no";
+    }
+    n = try {
+      return call(twice) [str_length name];
+    } catch (e) {
+      return if flag { 0 } else { -1 };
+    }
+    return n;
+  }
+  @cache(ttl = 1s)
+  readonly graph twice(`double`: int64): int64 {
+    return `double` * 2;
+  }
+"#;
+
+const DEFER_SCRIPT: &str = r#"
+  export graph main(root: schema, id: string, flag: bool) {
+    if flag {
+      defer {
+        s_delete root.items id;
+      }
+    }
+    s_insert root.items $ build_table(Item) $ m_insert(id) id $ m_insert(name) "" create_map;
+    defer {
+      x = point_get root.items id;
+    }
+    t_insert(name) x "x";
+  }
+"#;
+
+const SCAN_SCRIPT: &str = r#"
+  @relaxed_isolation
+  export graph main(root: schema, n: int64): list<Item> {
+    first = scan_index(id, 2) root.items;
+    rest = scan_index_desc(id) from "b" to null<string> root.items;
+    return scan_index_desc(id, n) $ root.items;
+  }
+"#;
+
+#[test]
+fn golden() {
+  let script = compile_twscript(SCRIPT).unwrap();
+  let expected = r#"mount "shared";
+
+param {
+  limit: int64 = 10,
+}
+
+export graph main(root: schema, id: string, flag: bool): int64 {
+  items = root.items;
+  v4 = point_get items id;
+  name = v4?.name;
+  v6 = is_null name;
+  v7 = !v6;
+  if v6 {
+    v8 = "";
+  }
+  if v7 {
+    v9 = select name name;
+  }
+  v10 = select v8 v9;
+  if flag {
+    items_2 = root.items;
+    v12 = create_map;
+    v13 = m_insert(name) v10 v12;
+    v14 = m_insert(id) id v13;
+    v15 = build_table(Item) v14;
+    s_insert items_2 v15;
+  }
+  v17 = !flag;
+  if v17 {
+    throw "no";
+  }
+  v31 = try {
+    v1 = str_length v10;
+    v2 = call(twice) [v1];
+    return v2;
+  } catch (error) {
+    if flag {
+      v26 = 0;
+    }
+    if v17 {
+      v28 = (-1);
+    }
+    v29 = if flag { v26 } else { v28 };
+    return v29;
+  }
+  return v31;
+}
+
+@cache(ttl = 1000ms, key = params)
+readonly graph twice(`double`: int64): int64 {
+  v2 = `double` * 2;
+  return v2;
+}
+"#;
+  assert_eq!(disassemble(&script).unwrap(), expected);
+}
+
+#[test]
+fn reassemble() {
+  let alloc = Bump::new();
+  let schema = compile(&parse(&alloc, SCHEMA).unwrap()).unwrap();
+  let plan = generate_plan_for_schema(&Default::default(), &Default::default(), &schema).unwrap();
+  let mut cases = vec![
+    (
+      schema.clone(),
+      plan.clone(),
+      SCRIPT.replace("mount \"shared\";", ""),
+    ),
+    (schema.clone(), plan.clone(), DEFER_SCRIPT.to_string()),
+    (schema, plan, SCAN_SCRIPT.to_string()),
+  ];
+  for fixture in FIXTURES {
+    for (_, script) in fixture.scripts {
+      let (schema, plan) = fixture.compile_schema_with_plan().unwrap();
+      cases.push((schema, plan, script.to_string()));
+    }
+  }
+
+  for (schema, plan, code) in &cases {
+    let text = disassemble(&compile_twscript(code).unwrap()).unwrap();
+    let script = compile_twscript(&text).unwrap();
+    let vm = TwVm::new(schema, plan, &script).unwrap();
+    GlobalTyckContext::new(&vm).unwrap().typeck().unwrap();
+
+    // The reassembled script disassembles to itself.
+    let text = disassemble(&script).unwrap();
+    assert_eq!(
+      disassemble(&compile_twscript(&text).unwrap()).unwrap(),
+      text
+    );
+  }
+}
+
+#[test]
+fn defer_blocks() {
+  let script = compile_twscript(DEFER_SCRIPT).unwrap();
+  let expected = r#"export graph main(root: schema, id: string, flag: bool) {
+  defer {
+    if flag {
+      items = root.items;
+      s_delete items id;
+    }
+  }
+  items_2 = root.items;
+  v7 = create_map;
+  v8 = m_insert(name) "" v7;
+  v9 = m_insert(id) id v8;
+  v10 = build_table(Item) v9;
+  s_insert items_2 v10;
+  defer {
+    items_3 = root.items;
+    v13 = point_get items_3 id;
+    t_insert(name) v13 "x";
+  }
+}
+"#;
+  assert_eq!(disassemble(&script).unwrap(), expected);
+}
+
+#[test]
+fn relaxed_isolation() {
+  let script = compile_twscript(SCAN_SCRIPT).unwrap();
+  assert!(script.graphs[0].relaxed_isolation);
+  let text = disassemble(&script).unwrap();
+  assert!(text.starts_with("@relaxed_isolation\nexport graph main("));
+}
+
+#[test]
+fn no_assembly() {
+  let mut script = compile_twscript(SCRIPT).unwrap();
+  script.graphs[1].nodes[1].0 = TwGraphNode::FilterSet(0);
+  let err = disassemble(&script).unwrap_err().to_string();
+  assert_eq!(err, "no assembly for node 1 of graph `twice`");
+}
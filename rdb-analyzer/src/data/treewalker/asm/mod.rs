@@ -2,10 +2,13 @@ use lalrpop_util::lalrpop_mod;
 
 mod ast;
 pub mod codegen;
+pub mod disasm;
 mod state;
 
 #[cfg(test)]
 mod asm_test;
+#[cfg(test)]
+mod disasm_test;
 
 lalrpop_mod!(language, "/data/treewalker/asm/language.rs");
 
@@ -45,4 +48,28 @@ pub enum TwAsmError {
 
   #[error("graph not found: {0}")]
   GraphNotFound(String),
+
+  #[error("unknown graph annotation: {0}")]
+  UnknownGraphAnnotation(String),
+
+  #[error("invalid argument `{1}` to graph annotation `{0}`")]
+  InvalidGraphAnnotationArg(String, String),
+
+  #[error("duplicate graph annotation: {0}")]
+  DuplicateGraphAnnotation(String),
+
+  #[error("either both or neither of the try and catch blocks of `{0}` must return a value")]
+  TryCatchReturnMismatch(String),
+
+  #[error("duplicate script param: {0}")]
+  DuplicateScriptParam(String),
+
+  #[error("duplicate const: {0}")]
+  DuplicateConst(String),
+
+  #[error("duplicate mount: {0}")]
+  DuplicateMount(String),
+
+  #[error("no assembly for {0}")]
+  NoAssembly(String),
 }
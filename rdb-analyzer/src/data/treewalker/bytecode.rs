@@ -3,16 +3,37 @@ use smallvec::{smallvec, SmallVec};
 
 use super::vm_value::{VmConst, VmType};
 
-#[derive(Serialize, Deserialize, Default, Debug)]
+#[derive(Serialize, Deserialize, Default, Debug, Clone)]
 pub struct TwScript {
   pub graphs: Vec<TwGraph>,
   pub entry: u32,
   pub consts: Vec<VmConst>,
   pub idents: Vec<String>,
   pub types: Vec<VmType<String>>,
+
+  /// Named params of the script, from `param { ... }`. Exported graphs receive a param through
+  /// a graph param of the same name.
+  #[serde(default)]
+  pub params: Vec<TwScriptParam>,
+
+  /// Namespaces that the script reads through `root.__ns("name")`, from `mount "name";`. The
+  /// schema of the script must have them mounted (see `data::mount`).
+  #[serde(default)]
+  pub mounts: Vec<String>,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct TwScriptParam {
+  pub name: String,
+
+  /// Type index.
+  pub ty: u32,
+
+  /// Const index of the value used when the param is not given.
+  pub default: Option<u32>,
 }
 
-#[derive(Serialize, Deserialize, Debug)]
+#[derive(Serialize, Deserialize, Debug, Clone)]
 pub struct TwGraph {
   /// Name.
   pub name: String,
@@ -20,9 +41,18 @@ pub struct TwGraph {
   /// Whether this is exported.
   pub exported: bool,
 
+  /// Whether this is declared `readonly`. Such graphs may not reach any effect node, and are run
+  /// in read-only transactions.
+  #[serde(default)]
+  pub read_only: bool,
+
   /// Topologically sorted nodes.
   ///
   /// (node, in_edges, precondition)
+  #[serde(
+    serialize_with = "super::bytecode_format::serialize_nodes",
+    deserialize_with = "super::bytecode_format::deserialize_nodes"
+  )]
   pub nodes: Vec<(TwGraphNode, Vec<u32>, Option<u32>)>,
 
   /// The output value of this graph.
@@ -31,11 +61,71 @@ pub struct TwGraph {
   /// Param types.
   pub param_types: Vec<u32>,
 
+  /// Param names, in the same order as `param_types`. Empty for scripts compiled before this was
+  /// introduced, and for the bodies of `try` blocks.
+  #[serde(default)]
+  pub param_names: Vec<String>,
+
   /// Output type.
   pub output_type: Option<u32>,
+
+  /// Result caching directive, from `@cache(...)`.
+  #[serde(default)]
+  pub cache: Option<TwCacheDirective>,
+
+  /// Whether each node outputs null without running when any of its parameters is null, by node
+  /// index. Empty for scripts compiled before this was introduced, where every node takes the
+  /// default from `TwGraphNode::is_optional_chained`.
+  #[serde(default)]
+  pub optional_chain: Vec<bool>,
+
+  /// Whether each node is deferred, by node index. Deferred nodes start only after all other nodes
+  /// of the same invocation of the graph have completed, and only deferred nodes may depend on
+  /// them. Emitted for `defer` blocks. Empty if no node is deferred.
+  #[serde(default)]
+  pub deferred: Vec<bool>,
+
+  /// Whether this is annotated `@relaxed_isolation`. Set scans of `reduce` and `scan_index` in
+  /// this graph only conflict with concurrent writes to the members they return, instead of the
+  /// whole scanned range, so concurrent inserts into the set don't abort the transaction.
+  #[serde(default)]
+  pub relaxed_isolation: bool,
 }
 
-#[derive(Copy, Clone, Serialize, Deserialize, Debug)]
+impl TwGraph {
+  pub fn is_optional_chained(&self, node_index: usize) -> bool {
+    match self.optional_chain.get(node_index) {
+      Some(x) => *x,
+      None => self.nodes[node_index].0.is_optional_chained(),
+    }
+  }
+
+  pub fn is_deferred(&self, node_index: usize) -> bool {
+    self.deferred.get(node_index).copied().unwrap_or(false)
+  }
+}
+
+#[derive(Clone, Serialize, Deserialize, Debug, Eq, PartialEq)]
+pub struct TwCacheDirective {
+  /// How long a cached output stays valid, in milliseconds.
+  pub ttl_ms: u64,
+
+  /// What cached outputs are keyed on, in addition to the graph itself.
+  pub key: TwCacheKey,
+}
+
+#[derive(Copy, Clone, Serialize, Deserialize, Debug, Eq, PartialEq)]
+pub enum TwCacheKey {
+  /// One cached output per distinct list of parameters.
+  Params,
+
+  /// One cached output for the graph, regardless of parameters.
+  Global,
+}
+
+/// Compiled scripts refer to node kinds by the explicit tags in `bytecode_format`, so new node kinds
+/// also need a tag there.
+#[derive(Copy, Clone, Debug, Eq, PartialEq, Hash)]
 pub enum TwGraphNode {
   /// T
   ///
@@ -47,6 +137,11 @@ pub enum TwGraphNode {
   /// Const param: const_index
   LoadConst(u32),
 
+  /// Map
+  ///
+  /// The environment bindings of the run, with the fields listed in `ExecEnv::FIELDS`.
+  LoadEnv,
+
   /// Map -> Table<T>
   ///
   /// Const param: ident (table_type)
@@ -72,6 +167,16 @@ pub enum TwGraphNode {
   /// List<T> -> T
   ListHead,
 
+  /// List<T> -> List<T>
+  ///
+  /// Subgraph: (T, T) -> bool | int64
+  ///
+  /// Stable sort. The subgraph returns whether its first param goes before the second, either
+  /// directly as a bool or as a negative int64.
+  ///
+  /// Const param: subgraph_index
+  SortList(u32),
+
   /// If has_range: U -> P -> T::PrimaryKeyValue (start_inclusive) -> T::PrimaryKeyValue (end_exclusive) -> (List<T> | Set<T>) -> P
   /// Otherwise: U -> P -> (List<T> | Set<T>) -> P
   ///
@@ -80,6 +185,16 @@ pub enum TwGraphNode {
   /// Const param: (subgraph_index, has_range)
   Reduce(u32, bool),
 
+  /// U -> P -> int64 (start_inclusive) -> int64 (end_exclusive) -> P
+  ///
+  /// Subgraph: (U, P, int64) -> P
+  ///
+  /// Runs the subgraph on each integer of the range in order, like `Reduce`. Iterations do not
+  /// add to the recursion depth. Stops early if the subgraph returns null.
+  ///
+  /// Const param: subgraph_index
+  Loop(u32),
+
   /// (Map | Table<T>) -> T
   ///
   /// Const param: ident
@@ -90,6 +205,18 @@ pub enum TwGraphNode {
   /// Point-get on a set.
   GetSetElement,
 
+  /// T::PrimaryKeyField... -> Set<T> -> T
+  ///
+  /// Point-get on a set with a composite primary key, given the value of every key field in key
+  /// order.
+  GetSetElementByTuple,
+
+  /// List<T::PrimaryKeyValue> -> Set<T> -> List<T>
+  ///
+  /// Batched point-get on a set. Checks for the presence of all members concurrently, and
+  /// returns null in place of each member that is not present.
+  GetSetElements,
+
   /// U (subgraph parameter) -> Set<T> -> T
   ///
   /// Filter the set with the given subgraph.
@@ -122,17 +249,147 @@ pub enum TwGraphNode {
   /// Const param: ident
   DeleteFromSet,
 
+  /// T -> Table -> ()
+  ///
+  /// Appends to the `list<T>` field of a table.
+  /// This is an effect node.
+  ///
+  /// Const param: ident
+  ListPush(u32),
+
+  /// Table -> T
+  ///
+  /// Removes and returns the last member of the `list<T>` field of a table. Null if the list is
+  /// empty.
+  /// This is an effect node.
+  ///
+  /// Const param: ident
+  ListPop(u32),
+
+  /// int64 -> Table -> T
+  ///
+  /// Reads the member at an index of the `list<T>` field of a table, without reading the rest of
+  /// the list. Null if the index is out of range.
+  ///
+  /// Const param: ident
+  ListGet(u32),
+
+  /// Table -> int64
+  ///
+  /// Length of the `list<T>` field of a table.
+  ///
+  /// Const param: ident
+  ListLen(u32),
+
+  /// int64 -> Table -> ()
+  ///
+  /// Atomically adds to the `@counter` field of a table, without reading it. Concurrent adds to
+  /// the same counter don't conflict.
+  /// This is an effect node.
+  ///
+  /// Const param: ident
+  AtomicAdd(u32),
+
+  /// int64 -> Table -> Table
+  ///
+  /// Returns the table if its `@version` field is the given version, and fails with
+  /// `ExecError::VersionConflict` otherwise. Updates that depend on the output run after the
+  /// check.
+  CheckVersion,
+
+  /// K -> Table -> V
+  ///
+  /// Reads the entry keyed K of the `map<K, V>` field of a table, without reading the rest of the
+  /// map. Null if there is no such entry.
+  ///
+  /// Const param: ident
+  DictGet(u32),
+
+  /// K -> V -> Table -> ()
+  ///
+  /// Sets the entry keyed K of the `map<K, V>` field of a table.
+  /// This is an effect node.
+  ///
+  /// Const param: ident
+  DictPut(u32),
+
+  /// K -> Table -> ()
+  ///
+  /// Deletes the entry keyed K of the `map<K, V>` field of a table, if any.
+  /// This is an effect node.
+  ///
+  /// Const param: ident
+  DictDelete(u32),
+
+  /// K -> K -> Table -> List<Map{key: K, value: V}>
+  ///
+  /// The entries of the `map<K, V>` field of a table with keys in `[start, end)`, in key order.
+  ///
+  /// Const param: ident
+  DictRange(u32),
+
   /// Map -> Map
   ///
   /// Const param: ident
   DeleteFromMap(u32),
 
+  /// string -> Json
+  ///
+  /// Fails with `ExecError::InvalidJson` if the string is not valid JSON.
+  JsonParse,
+
+  /// Json -> string
+  JsonSerialize,
+
+  /// string (path) -> Json -> T
+  ///
+  /// Looks up a path like `a.b[0]` in a JSON document. T is either Json, or a primitive type or
+  /// bool that the value is converted to. Null if nothing is at the path or the conversion fails.
+  ///
+  /// Const param: type index of T
+  JsonGet(u32),
+
+  /// Map -> List<string>
+  ///
+  /// Field names in ascending order.
+  MapKeys,
+
+  /// Map -> List<T>
+  ///
+  /// Field values, ordered by field name. All fields must be covariant to a common type.
+  MapValues,
+
+  /// string -> Map -> T
+  ///
+  /// Null if the field is not present. All fields must be covariant to a common type.
+  MapGetDynamic,
+
   /// T -> T -> Bool
+  ///
+  /// Doubles, including those in maps and lists, compare numerically: `0.0` equals `-0.0`, and
+  /// NaN equals nothing, not even itself.
   Eq,
 
   /// T -> T -> Bool
+  ///
+  /// The negation of `Eq`.
   Ne,
 
+  /// T -> T -> Bool
+  ///
+  /// T is one of int64, double and string. Strings compare by their bytes. Comparisons involving
+  /// a NaN evaluate to false.
+  Lt,
+
+  /// T -> T -> Bool
+  Le,
+
+  /// T -> T -> Bool
+  Gt,
+
+  /// T -> T -> Bool
+  Ge,
+
   /// Bool -> Bool -> Bool
   And,
 
@@ -145,8 +402,21 @@ pub enum TwGraphNode {
   /// Fire if either of its parameters are satisfied.
   ///
   /// T -> T -> T
+  ///
+  /// Fires at most once per graph invocation, with the value of the parameter that fired, and
+  /// does not fire if neither did. Both parameters may be the same node. If two distinct nodes
+  /// fire, the run fails under `ExecConfig::strict_select` (the default). Otherwise the value of
+  /// the one that fired first is kept, and candidates that fired at the same time (e.g. before
+  /// the precondition of the select, or in `Scheduler::Topological`) are taken in node order.
   Select,
 
+  /// Bool -> T -> T -> T
+  ///
+  /// The second parameter if the condition is true, or the third if it is false. Only the chosen
+  /// parameter needs to fire, so branches that should not run are preconditioned on the
+  /// condition. Null if the condition is null.
+  Cond,
+
   /// True if this table or set is actually present.
   ///
   /// Always true for fresh values, and true for resident values if its storage key exists.
@@ -162,6 +432,23 @@ pub enum TwGraphNode {
   /// T -> T
   Nop,
 
+  /// T -> T
+  ///
+  /// Fails with `ExecError::NullUnwrapped` if the value is null.
+  UnwrapOptional,
+
+  /// Call subgraph, capturing script errors.
+  ///
+  /// T* -> map { value: R, error: string }
+  ///
+  /// If the subgraph fails with a thrown error or a null unwrap, `value` is null and `error`
+  /// is the message. Otherwise `error` is null. The subgraph must be read-only, so that a failure
+  /// leaves no partial effects behind. Emitted for `try` blocks, and the only reference to its
+  /// subgraph.
+  ///
+  /// Const param: subgraph index
+  Try(u32),
+
   /// Call subgraph.
   ///
   /// T* -> R
@@ -175,8 +462,173 @@ pub enum TwGraphNode {
   /// (int64 -> int64 -> int64) | (double -> double -> double)
   Sub,
 
+  /// (int64 -> int64 -> int64) | (double -> double -> double)
+  Mul,
+
+  /// (int64 -> int64 -> int64) | (double -> double -> double)
+  ///
+  /// Integer division truncates toward zero and fails on a zero divisor. Double division follows
+  /// IEEE 754.
+  Div,
+
+  /// (int64 -> int64 -> int64) | (double -> double -> double)
+  ///
+  /// Remainder of truncating division, with the sign of the dividend. Fails on a zero integer
+  /// divisor.
+  Mod,
+
+  /// (int64 -> int64) | (double -> double)
+  Neg,
+
+  /// string -> double
+  ///
+  /// Accepts decimal and exponent notation, and `NaN`, `inf` and `-inf`. Null if the string is
+  /// not a number.
+  ParseDouble,
+
+  /// double -> string
+  ///
+  /// The shortest decimal representation that parses back to the same double, without exponent
+  /// notation. NaN and the infinities are formatted as `NaN`, `inf` and `-inf`.
+  FormatDouble,
+
+  /// string -> string (prefix) -> Bool
+  StrStartsWith,
+
+  /// string -> string (needle) -> Bool
+  StrContains,
+
+  /// string -> int64 (start) -> int64 (length) -> string
+  ///
+  /// Positions and lengths count Unicode scalar values, and are clamped to the string. Negative
+  /// values are treated as zero.
+  StrSubstring,
+
+  /// string -> string
+  StrToLower,
+
+  /// string -> int64
+  ///
+  /// Number of Unicode scalar values in the string.
+  StrLength,
+
+  /// bytes -> bytes -> bytes
+  BytesConcat,
+
+  /// bytes -> int64 (start) -> int64 (length) -> bytes
+  ///
+  /// Positions and lengths are clamped to the byte array. Negative values are treated as zero.
+  BytesSlice,
+
+  /// bytes -> int64
+  BytesLength,
+
+  /// bytes -> string
+  ///
+  /// Lowercase hex.
+  BytesToHex,
+
+  /// string -> bytes
+  ///
+  /// Accepts either case. Fails with a script error if the string is not valid hex.
+  BytesFromHex,
+
+  /// bytes -> string
+  ///
+  /// Standard base64 with padding.
+  BytesToBase64,
+
+  /// string -> bytes
+  ///
+  /// Fails with a script error if the string is not valid base64.
+  BytesFromBase64,
+
+  /// bytes -> string
+  ///
+  /// Fails with a script error if the bytes are not valid UTF-8.
+  BytesToString,
+
+  /// string -> bytes
+  ///
+  /// The UTF-8 encoding of the string.
+  BytesFromString,
+
+  /// int64
+  ///
+  /// Milliseconds since the Unix epoch, taken once when the graph starts running. All `TimeNow`
+  /// nodes in one run produce the same value.
+  TimeNow,
+
+  /// int64 (millis) -> int64 (days) -> int64
+  TimeAddDays,
+
+  /// int64 (millis) -> int64
+  ///
+  /// Start of the UTC day containing the timestamp.
+  TimeStartOfDay,
+
+  /// int64 (millis) -> int64
+  ///
+  /// ISO 8601 day of the week in UTC, from 1 (Monday) to 7 (Sunday).
+  TimeDayOfWeek,
+
+  /// string
+  ///
+  /// A random UUID, unique within the run. Retries of a transaction produce the same sequence of
+  /// IDs, as long as the `GenId` nodes fire in the same order.
+  GenId,
+
   /// string -> !
   Throw,
+
+  /// If has_range: Set<T> -> T::field (start_inclusive) -> T::field (end_exclusive) -> List<T>
+  /// Otherwise: Set<T> -> List<T>
+  ///
+  /// Lists the members of a set in ascending order of an indexed field, using the index
+  /// keyspace. Members with the same value are ordered by primary key, and members where the
+  /// field is null are skipped. A null range bound is unbounded.
+  ///
+  /// Const param: (ident, has_range)
+  ScanIndex(u32, bool),
+
+  /// Set<T> -> int64
+  ///
+  /// Number of members in a set. Only scans keys.
+  Count,
+
+  /// Set<T> -> T::field
+  ///
+  /// Sum of a numeric field over the members of a set. Null values are skipped, and the sum of an
+  /// empty set is zero.
+  ///
+  /// Const param: ident
+  Sum(u32),
+
+  /// Set<T> -> T::field
+  ///
+  /// Least non-null value of a primitive field over the members of a set, in key order. Null if
+  /// there is no such value. Indexed fields only need to read one index entry.
+  ///
+  /// Const param: ident
+  Min(u32),
+
+  /// Set<T> -> T::field
+  ///
+  /// Like `Min`, but takes the greatest value.
+  ///
+  /// Const param: ident
+  Max(u32),
+
+  /// If has_range: Set<T> -> T::field (start_inclusive) -> T::field (end_exclusive) -> int64 ->
+  /// List<T>
+  /// Otherwise: Set<T> -> int64 -> List<T>
+  ///
+  /// `ScanIndex` in descending order if `reverse`, stopping after the number of members given by
+  /// the last parameter. Only reads the index entries that are returned. A null limit is
+  /// unlimited, and a negative one returns no members.
+  ///
+  /// Const param: (ident, has_range, reverse)
+  ScanIndexLimited(u32, bool, bool),
 }
 
 impl TwGraphNode {
@@ -186,22 +638,113 @@ impl TwGraphNode {
       _ => false,
     }
   }
+  pub fn is_cond(&self) -> bool {
+    match self {
+      Self::Cond => true,
+      _ => false,
+    }
+  }
   pub fn subgraph_references(&self) -> SmallVec<[u32; 1]> {
     match self {
       Self::FilterSet(x) => smallvec![*x],
       Self::Call(x) => smallvec![*x],
+      Self::Try(x) => smallvec![*x],
       Self::Reduce(x, _) => smallvec![*x],
+      Self::Loop(x) => smallvec![*x],
+      Self::SortList(x) => smallvec![*x],
       _ => smallvec![],
     }
   }
 
+  /// Whether this node writes to the underlying store.
+  pub fn is_effect(&self) -> bool {
+    match self {
+      Self::InsertIntoTable(_)
+      | Self::InsertIntoSet
+      | Self::DeleteFromSet
+      | Self::ListPush(_)
+      | Self::ListPop(_)
+      | Self::AtomicAdd(_)
+      | Self::DictPut(_)
+      | Self::DictDelete(_) => true,
+      _ => false,
+    }
+  }
+
+  /// Whether the output of this node only depends on its parameters, without touching the store
+  /// or anything else that changes during a run. Such nodes may still fail, e.g. on a division by zero.
+  pub fn is_pure(&self) -> bool {
+    match self {
+      Self::LoadConst(_)
+      | Self::LoadParam(_)
+      | Self::LoadEnv
+      | Self::CreateMap
+      | Self::CreateList(_)
+      | Self::InsertIntoMap(_)
+      | Self::DeleteFromMap(_)
+      | Self::MapKeys
+      | Self::MapValues
+      | Self::MapGetDynamic
+      | Self::JsonParse
+      | Self::JsonSerialize
+      | Self::JsonGet(_)
+      | Self::Eq
+      | Self::Ne
+      | Self::Lt
+      | Self::Le
+      | Self::Gt
+      | Self::Ge
+      | Self::And
+      | Self::Or
+      | Self::Not
+      | Self::IsNull
+      | Self::Nop
+      | Self::UnwrapOptional
+      | Self::Add
+      | Self::Sub
+      | Self::Mul
+      | Self::Div
+      | Self::Mod
+      | Self::Neg
+      | Self::ParseDouble
+      | Self::FormatDouble
+      | Self::StrStartsWith
+      | Self::StrContains
+      | Self::StrSubstring
+      | Self::StrToLower
+      | Self::StrLength
+      | Self::BytesConcat
+      | Self::BytesSlice
+      | Self::BytesLength
+      | Self::BytesToHex
+      | Self::BytesFromHex
+      | Self::BytesToBase64
+      | Self::BytesFromBase64
+      | Self::BytesToString
+      | Self::BytesFromString
+      | Self::TimeNow
+      | Self::TimeAddDays
+      | Self::TimeStartOfDay
+      | Self::TimeDayOfWeek => true,
+      _ => false,
+    }
+  }
+
+  /// Whether this node is optional-chained by default. Nodes that are not may still get null
+  /// parameters, and handle them on their own.
   pub fn is_optional_chained(&self) -> bool {
     match self {
       TwGraphNode::IsNull
       | TwGraphNode::Nop
+      | TwGraphNode::UnwrapOptional
+      | TwGraphNode::Cond
+      | TwGraphNode::Try(_)
       | TwGraphNode::InsertIntoMap(_)
       | TwGraphNode::DeleteFromMap(_)
       | TwGraphNode::Reduce(_, _)
+      | TwGraphNode::Loop(_)
+      | TwGraphNode::ScanIndex(_, _)
+      | TwGraphNode::ScanIndexLimited(_, _, _)
       | TwGraphNode::Throw => false,
       _ => true,
     }
@@ -0,0 +1,552 @@
+//! Versioned binary format of `TwScript`, for exchanging compiled scripts between processes and
+//! across releases.
+//!
+//! ```text
+//! magic               b"RTWS"
+//! version             u16, little endian
+//! min_reader_version  u16, little endian
+//! section*            kind: u8, length: u32 (little endian), payload: [u8; length]
+//! ```
+//!
+//! Section payloads are MessagePack, with structs encoded as maps keyed by field name. The
+//! sections are the script metadata (entry graph, params and mounts), the pools (consts, idents
+//! and types), and the graphs. Each of them appears exactly once.
+//!
+//! Graph nodes are encoded as arrays of an explicit tag followed by the const params of the node
+//! (see `node_tags!`), so they don't depend on the order of `TwGraphNode`. Other enums, like types
+//! and consts, are still encoded by the position of the variant, so their new variants go at the
+//! end.
+//!
+//! Compatibility rules:
+//!
+//! - A reader accepts data whose `min_reader_version` is not greater than its own
+//!   `FORMAT_VERSION`. It skips sections of unknown kinds, ignores unknown struct fields, and
+//!   rejects unknown node tags.
+//! - Changes that older readers can safely ignore, like a new section or a new field with a
+//!   `#[serde(default)]`, only bump `FORMAT_VERSION`. So does a new node kind, with a new tag:
+//!   older readers fail to decode the graphs section of scripts that use it, and load all other
+//!   scripts.
+//! - Changes that older readers would misread, like a new meaning for an existing field or node
+//!   tag, also raise `MIN_READER_VERSION` to the new `FORMAT_VERSION`. If only some scripts use
+//!   the change, the higher version is only written for those.
+//!
+//! Scripts of format version 2 and earlier, where node kinds were encoded by their position in
+//! `TwGraphNode`, are still read. The tags of those node kinds are their positions at the time.
+//!
+//! Loaded scripts are validated, so that all indices into the pools, graphs and nodes are in
+//! range. They still have to be type checked before running.
+
+use std::{borrow::Cow, convert::TryFrom};
+
+use anyhow::Result;
+use serde::{
+  de::{self, DeserializeOwned, MapAccess, SeqAccess, Visitor},
+  Deserialize, Deserializer, Serialize, Serializer,
+};
+use thiserror::Error;
+
+use super::{
+  bytecode::{TwGraph, TwGraphNode, TwScript, TwScriptParam},
+  vm_value::{VmConst, VmType},
+};
+
+pub const FORMAT_MAGIC: &[u8; 4] = b"RTWS";
+
+/// Version of the format written by this build.
+///
+/// - 2: deferred nodes (`TwGraph::deferred`).
+/// - 3: explicit node tags.
+pub const FORMAT_VERSION: u16 = 3;
+
+/// The oldest reader version that understands what this build writes. Older readers can't decode
+/// node tags.
+pub const MIN_READER_VERSION: u16 = 3;
+
+const SECTION_META: u8 = 1;
+const SECTION_CONSTS: u8 = 2;
+const SECTION_IDENTS: u8 = 3;
+const SECTION_TYPES: u8 = 4;
+const SECTION_GRAPHS: u8 = 5;
+
+#[derive(Error, Debug)]
+pub enum ScriptFormatError {
+  #[error("not a compiled script")]
+  BadMagic,
+
+  #[error("compiled script of format version {0} needs a reader of version {1} or later, but this reader is version {2}")]
+  UnsupportedVersion(u16, u16, u16),
+
+  #[error("compiled script is truncated")]
+  Truncated,
+
+  #[error("duplicate section in compiled script: `{0}`")]
+  DuplicateSection(&'static str),
+
+  #[error("missing section in compiled script: `{0}`")]
+  MissingSection(&'static str),
+
+  #[error("section too large: `{0}`")]
+  SectionTooLarge(&'static str),
+
+  #[error("entry graph index out of bounds")]
+  EntryOob,
+
+  #[error("script param `{0}`: {1} index out of bounds")]
+  ScriptParamIndexOob(String, &'static str),
+
+  #[error("graph `{0}`: {1} index out of bounds")]
+  GraphIndexOob(String, &'static str),
+
+  #[error("graph `{0}`: {1} do not match the graph")]
+  GraphLengthMismatch(String, &'static str),
+
+  #[error("graph `{0}`, node {1}: {2} index out of bounds")]
+  NodeIndexOob(String, usize, &'static str),
+
+  #[error("graph `{0}`, node {1}: depends on a node that is not before it")]
+  NodeNotSorted(String, usize),
+
+  #[error("graph `{0}`, node {1}: depends on a deferred node but is not deferred")]
+  NodeNotDeferred(String, usize),
+}
+
+#[derive(Serialize, Deserialize)]
+struct ScriptMeta<'a> {
+  entry: u32,
+
+  #[serde(default)]
+  params: Cow<'a, [TwScriptParam]>,
+
+  #[serde(default)]
+  mounts: Cow<'a, [String]>,
+}
+
+fn section_name(kind: u8) -> &'static str {
+  match kind {
+    SECTION_META => "meta",
+    SECTION_CONSTS => "consts",
+    SECTION_IDENTS => "idents",
+    SECTION_TYPES => "types",
+    SECTION_GRAPHS => "graphs",
+    _ => "unknown",
+  }
+}
+
+impl TwScript {
+  /// Encodes this script in the versioned binary format. See `bytecode_format`.
+  pub fn to_bytes(&self) -> Result<Vec<u8>> {
+    let mut buf = FORMAT_MAGIC.to_vec();
+    buf.extend_from_slice(&FORMAT_VERSION.to_le_bytes());
+    buf.extend_from_slice(&MIN_READER_VERSION.to_le_bytes());
+    write_section(
+      &mut buf,
+      SECTION_META,
+      &ScriptMeta {
+        entry: self.entry,
+        params: Cow::Borrowed(&self.params),
+        mounts: Cow::Borrowed(&self.mounts),
+      },
+    )?;
+    write_section(&mut buf, SECTION_CONSTS, &self.consts)?;
+    write_section(&mut buf, SECTION_IDENTS, &self.idents)?;
+    write_section(&mut buf, SECTION_TYPES, &self.types)?;
+    write_section(&mut buf, SECTION_GRAPHS, &self.graphs)?;
+    Ok(buf)
+  }
+
+  /// Decodes and validates a script encoded with `to_bytes`, possibly by another release.
+  pub fn from_bytes(data: &[u8]) -> Result<Self> {
+    if data.len() < FORMAT_MAGIC.len() + 4 {
+      return Err(ScriptFormatError::Truncated.into());
+    }
+    let (magic, data) = data.split_at(FORMAT_MAGIC.len());
+    if magic != FORMAT_MAGIC {
+      return Err(ScriptFormatError::BadMagic.into());
+    }
+    let version = u16::from_le_bytes([data[0], data[1]]);
+    let min_reader_version = u16::from_le_bytes([data[2], data[3]]);
+    if min_reader_version > FORMAT_VERSION {
+      return Err(
+        ScriptFormatError::UnsupportedVersion(version, min_reader_version, FORMAT_VERSION).into(),
+      );
+    }
+
+    let mut meta: Option<ScriptMeta> = None;
+    let mut consts: Option<Vec<VmConst>> = None;
+    let mut idents: Option<Vec<String>> = None;
+    let mut types: Option<Vec<VmType<String>>> = None;
+    let mut graphs: Option<Vec<TwGraph>> = None;
+
+    let mut rest = &data[4..];
+    while !rest.is_empty() {
+      if rest.len() < 5 {
+        return Err(ScriptFormatError::Truncated.into());
+      }
+      let kind = rest[0];
+      let len = u32::from_le_bytes([rest[1], rest[2], rest[3], rest[4]]) as usize;
+      rest = &rest[5..];
+      if rest.len() < len {
+        return Err(ScriptFormatError::Truncated.into());
+      }
+      let (payload, next) = rest.split_at(len);
+      rest = next;
+      match kind {
+        SECTION_META => read_section(kind, payload, &mut meta)?,
+        SECTION_CONSTS => read_section(kind, payload, &mut consts)?,
+        SECTION_IDENTS => read_section(kind, payload, &mut idents)?,
+        SECTION_TYPES => read_section(kind, payload, &mut types)?,
+        SECTION_GRAPHS => read_section(kind, payload, &mut graphs)?,
+        _ => log::debug!(
+          "skipping unknown section {} of compiled script version {}",
+          kind,
+          version
+        ),
+      }
+    }
+
+    let meta = required(SECTION_META, meta)?;
+    let script = TwScript {
+      graphs: required(SECTION_GRAPHS, graphs)?,
+      entry: meta.entry,
+      consts: required(SECTION_CONSTS, consts)?,
+      idents: required(SECTION_IDENTS, idents)?,
+      types: required(SECTION_TYPES, types)?,
+      params: meta.params.into_owned(),
+      mounts: meta.mounts.into_owned(),
+    };
+    script.validate()?;
+    Ok(script)
+  }
+
+  /// Checks that all indices in this script are in range, and that the nodes of each graph are
+  /// topologically sorted. Scripts that pass can be type checked and run without panicking on a
+  /// malformed index.
+  pub fn validate(&self) -> Result<(), ScriptFormatError> {
+    if !self.graphs.is_empty() && self.entry as usize >= self.graphs.len() {
+      return Err(ScriptFormatError::EntryOob);
+    }
+    for p in &self.params {
+      if p.ty as usize >= self.types.len() {
+        return Err(ScriptFormatError::ScriptParamIndexOob(
+          p.name.clone(),
+          "type",
+        ));
+      }
+      if let Some(x) = p.default {
+        if x as usize >= self.consts.len() {
+          return Err(ScriptFormatError::ScriptParamIndexOob(
+            p.name.clone(),
+            "const",
+          ));
+        }
+      }
+    }
+    for g in &self.graphs {
+      self.validate_graph(g)?;
+    }
+    Ok(())
+  }
+
+  fn validate_graph(&self, g: &TwGraph) -> Result<(), ScriptFormatError> {
+    use TwGraphNode as N;
+
+    let graph_oob = |what| ScriptFormatError::GraphIndexOob(g.name.clone(), what);
+    if g
+      .param_types
+      .iter()
+      .chain(g.output_type.iter())
+      .any(|x| *x as usize >= self.types.len())
+    {
+      return Err(graph_oob("type"));
+    }
+    if let Some(x) = g.output {
+      if x as usize >= g.nodes.len() {
+        return Err(graph_oob("output"));
+      }
+    }
+    if !g.param_names.is_empty() && g.param_names.len() != g.param_types.len() {
+      return Err(ScriptFormatError::GraphLengthMismatch(
+        g.name.clone(),
+        "param names",
+      ));
+    }
+    if !g.optional_chain.is_empty() && g.optional_chain.len() != g.nodes.len() {
+      return Err(ScriptFormatError::GraphLengthMismatch(
+        g.name.clone(),
+        "optional chain flags",
+      ));
+    }
+    if !g.deferred.is_empty() && g.deferred.len() != g.nodes.len() {
+      return Err(ScriptFormatError::GraphLengthMismatch(
+        g.name.clone(),
+        "deferred flags",
+      ));
+    }
+
+    for (i, (node, in_edges, precondition)) in g.nodes.iter().enumerate() {
+      let node_oob = |what| ScriptFormatError::NodeIndexOob(g.name.clone(), i, what);
+      if in_edges
+        .iter()
+        .chain(precondition.iter())
+        .any(|x| *x as usize >= i)
+      {
+        return Err(ScriptFormatError::NodeNotSorted(g.name.clone(), i));
+      }
+      if !g.is_deferred(i)
+        && in_edges
+          .iter()
+          .chain(precondition.iter())
+          .any(|x| g.is_deferred(*x as usize))
+      {
+        return Err(ScriptFormatError::NodeNotDeferred(g.name.clone(), i));
+      }
+      if node
+        .subgraph_references()
+        .iter()
+        .any(|x| *x as usize >= self.graphs.len())
+      {
+        return Err(node_oob("subgraph"));
+      }
+      let (what, index, len) = match node {
+        N::LoadParam(x) => ("param", *x, g.param_types.len()),
+        N::LoadConst(x) => ("const", *x, self.consts.len()),
+        N::CreateList(x) | N::JsonGet(x) => ("type", *x, self.types.len()),
+        N::BuildTable(x)
+        | N::GetField(x)
+        | N::InsertIntoMap(x)
+        | N::InsertIntoTable(x)
+        | N::ListPush(x)
+        | N::ListPop(x)
+        | N::ListGet(x)
+        | N::ListLen(x)
+        | N::AtomicAdd(x)
+        | N::DictGet(x)
+        | N::DictPut(x)
+        | N::DictDelete(x)
+        | N::DictRange(x)
+        | N::DeleteFromMap(x)
+        | N::ScanIndex(x, _)
+        | N::ScanIndexLimited(x, _, _)
+        | N::Sum(x)
+        | N::Min(x)
+        | N::Max(x) => ("ident", *x, self.idents.len()),
+        _ => continue,
+      };
+      if index as usize >= len {
+        return Err(node_oob(what));
+      }
+    }
+    Ok(())
+  }
+}
+
+fn write_section<T: Serialize + ?Sized>(buf: &mut Vec<u8>, kind: u8, value: &T) -> Result<()> {
+  let payload = rmp_serde::to_vec_named(value)?;
+  let len = u32::try_from(payload.len())
+    .map_err(|_| ScriptFormatError::SectionTooLarge(section_name(kind)))?;
+  buf.push(kind);
+  buf.extend_from_slice(&len.to_le_bytes());
+  buf.extend_from_slice(&payload);
+  Ok(())
+}
+
+fn read_section<T: DeserializeOwned>(kind: u8, payload: &[u8], out: &mut Option<T>) -> Result<()> {
+  if out.is_some() {
+    return Err(ScriptFormatError::DuplicateSection(section_name(kind)).into());
+  }
+  *out = Some(rmp_serde::from_slice(payload)?);
+  Ok(())
+}
+
+fn required<T>(kind: u8, x: Option<T>) -> Result<T> {
+  x.ok_or_else(|| ScriptFormatError::MissingSection(section_name(kind)).into())
+}
+
+type GraphNodes = Vec<(TwGraphNode, Vec<u32>, Option<u32>)>;
+
+/// The nodes of a graph, as `(tag, const params...)` arrays.
+pub(super) fn serialize_nodes<S: Serializer>(
+  nodes: &[(TwGraphNode, Vec<u32>, Option<u32>)],
+  s: S,
+) -> Result<S::Ok, S::Error> {
+  s.collect_seq(
+    nodes
+      .iter()
+      .map(|(node, in_edges, precondition)| (TaggedNode(*node), in_edges, precondition)),
+  )
+}
+
+pub(super) fn deserialize_nodes<'de, D: Deserializer<'de>>(d: D) -> Result<GraphNodes, D::Error> {
+  Ok(
+    Vec::<(TaggedNode, Vec<u32>, Option<u32>)>::deserialize(d)?
+      .into_iter()
+      .map(|(node, in_edges, precondition)| (node.0, in_edges, precondition))
+      .collect(),
+  )
+}
+
+struct TaggedNode(TwGraphNode);
+
+struct TaggedNodeVisitor;
+
+fn next_param<'de, A: SeqAccess<'de>, T: Deserialize<'de>>(seq: &mut A) -> Result<T, A::Error> {
+  seq
+    .next_element()?
+    .ok_or_else(|| de::Error::custom("truncated graph node"))
+}
+
+macro_rules! node_tags {
+  ($($tag:literal => $name:ident $(($($arg:ident: $ty:ty),*))?,)*) => {
+    impl Serialize for TaggedNode {
+      fn serialize<S: Serializer>(&self, s: S) -> Result<S::Ok, S::Error> {
+        match self.0 {
+          $(TwGraphNode::$name $(($($arg),*))? => ($tag as u16, $($($arg,)*)?).serialize(s),)*
+        }
+      }
+    }
+
+    impl<'de> Visitor<'de> for TaggedNodeVisitor {
+      type Value = TaggedNode;
+
+      fn expecting(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        write!(f, "a graph node")
+      }
+
+      fn visit_seq<A: SeqAccess<'de>>(self, mut seq: A) -> Result<TaggedNode, A::Error> {
+        let tag: u16 = next_param(&mut seq)?;
+        match tag {
+          $($tag => {
+            $($(let $arg: $ty = next_param(&mut seq)?;)*)?
+            Ok(TaggedNode(TwGraphNode::$name $(($($arg),*))?))
+          })*
+          _ => Err(de::Error::custom(format_args!("unknown graph node tag {}", tag))),
+        }
+      }
+
+      /// Format version 2 and earlier, where `rmp_serde` wrote `{position: const params}`, with
+      /// the position of the node kind in `TwGraphNode` at that time.
+      fn visit_map<A: MapAccess<'de>>(self, mut map: A) -> Result<TaggedNode, A::Error> {
+        let tag: u16 = map
+          .next_key()?
+          .ok_or_else(|| de::Error::custom("empty graph node"))?;
+        match tag {
+          $($tag => {
+            node_tags!(@legacy_params map $($($arg: $ty),*)?);
+            Ok(TaggedNode(TwGraphNode::$name $(($($arg),*))?))
+          })*
+          _ => Err(de::Error::custom(format_args!("unknown graph node tag {}", tag))),
+        }
+      }
+    }
+  };
+  (@legacy_params $map:ident) => {
+    $map.next_value::<()>()?;
+  };
+  (@legacy_params $map:ident $arg:ident: $ty:ty) => {
+    let $arg: $ty = $map.next_value()?;
+  };
+  (@legacy_params $map:ident $($arg:ident: $ty:ty),+) => {
+    let ($($arg,)+): ($($ty,)+) = $map.next_value()?;
+  };
+}
+
+impl<'de> Deserialize<'de> for TaggedNode {
+  fn deserialize<D: Deserializer<'de>>(d: D) -> Result<Self, D::Error> {
+    d.deserialize_any(TaggedNodeVisitor)
+  }
+}
+
+// Tags of node kinds. Tags are never changed or reused, and a new node kind takes the next unused
+// one. Tags 0 to 89 are the positions that format version 2 and earlier wrote.
+node_tags! {
+  0 => LoadParam(a: u32),
+  1 => LoadConst(a: u32),
+  2 => LoadEnv,
+  3 => BuildTable(a: u32),
+  4 => BuildSet,
+  5 => CreateMap,
+  6 => CreateList(a: u32),
+  7 => PrependToList,
+  8 => PopFromList,
+  9 => ListHead,
+  10 => SortList(a: u32),
+  11 => Reduce(a: u32, b: bool),
+  12 => Loop(a: u32),
+  13 => GetField(a: u32),
+  14 => GetSetElement,
+  15 => GetSetElementByTuple,
+  16 => GetSetElements,
+  17 => FilterSet(a: u32),
+  18 => InsertIntoMap(a: u32),
+  19 => InsertIntoTable(a: u32),
+  20 => InsertIntoSet,
+  21 => DeleteFromSet,
+  22 => ListPush(a: u32),
+  23 => ListPop(a: u32),
+  24 => ListGet(a: u32),
+  25 => ListLen(a: u32),
+  26 => AtomicAdd(a: u32),
+  27 => CheckVersion,
+  28 => DictGet(a: u32),
+  29 => DictPut(a: u32),
+  30 => DictDelete(a: u32),
+  31 => DictRange(a: u32),
+  32 => DeleteFromMap(a: u32),
+  33 => JsonParse,
+  34 => JsonSerialize,
+  35 => JsonGet(a: u32),
+  36 => MapKeys,
+  37 => MapValues,
+  38 => MapGetDynamic,
+  39 => Eq,
+  40 => Ne,
+  41 => Lt,
+  42 => Le,
+  43 => Gt,
+  44 => Ge,
+  45 => And,
+  46 => Or,
+  47 => Not,
+  48 => Select,
+  49 => Cond,
+  50 => IsPresent,
+  51 => IsNull,
+  52 => Nop,
+  53 => UnwrapOptional,
+  54 => Try(a: u32),
+  55 => Call(a: u32),
+  56 => Add,
+  57 => Sub,
+  58 => Mul,
+  59 => Div,
+  60 => Mod,
+  61 => Neg,
+  62 => ParseDouble,
+  63 => FormatDouble,
+  64 => StrStartsWith,
+  65 => StrContains,
+  66 => StrSubstring,
+  67 => StrToLower,
+  68 => StrLength,
+  69 => BytesConcat,
+  70 => BytesSlice,
+  71 => BytesLength,
+  72 => BytesToHex,
+  73 => BytesFromHex,
+  74 => BytesToBase64,
+  75 => BytesFromBase64,
+  76 => BytesToString,
+  77 => BytesFromString,
+  78 => TimeNow,
+  79 => TimeAddDays,
+  80 => TimeStartOfDay,
+  81 => TimeDayOfWeek,
+  82 => GenId,
+  83 => Throw,
+  84 => ScanIndex(a: u32, b: bool),
+  85 => Count,
+  86 => Sum(a: u32),
+  87 => Min(a: u32),
+  88 => Max(a: u32),
+  89 => ScanIndexLimited(a: u32, b: bool, c: bool),
+}
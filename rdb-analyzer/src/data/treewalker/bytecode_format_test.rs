@@ -0,0 +1,238 @@
+use crate::fixtures::FIXTURES;
+
+use super::{
+  asm::codegen::compile_twscript,
+  bytecode::{TwGraphNode, TwScript},
+  bytecode_format::{ScriptFormatError, FORMAT_MAGIC, FORMAT_VERSION, MIN_READER_VERSION},
+  vm_value::VmType,
+};
+
+const SCRIPT: &str = r#"
+  param {
+    limit: int64 = 10,
+  }
+  mount "shared";
+  export graph main(root: schema, limit: int64): int64 {
+    return call(twice) [limit] + 1;
+  }
+  @cache(ttl = 1s)
+  export readonly graph twice(x: int64): int64 {
+    return x * 2;
+  }
+"#;
+
+fn format_error(data: &[u8]) -> ScriptFormatError {
+  TwScript::from_bytes(data)
+    .unwrap_err()
+    .downcast::<ScriptFormatError>()
+    .unwrap()
+}
+
+#[test]
+fn round_trip() {
+  let mut scripts = vec![TwScript::default(), compile_twscript(SCRIPT).unwrap()];
+  for fixture in FIXTURES {
+    for (name, _) in fixture.scripts {
+      scripts.push(fixture.compile_script(name).unwrap());
+    }
+  }
+  for script in &scripts {
+    let data = script.to_bytes().unwrap();
+    assert_eq!(&data[..4], FORMAT_MAGIC);
+    let decoded = TwScript::from_bytes(&data).unwrap();
+    assert_eq!(
+      format!("{:?}", decoded.graphs),
+      format!("{:?}", script.graphs)
+    );
+    assert_eq!(decoded.consts, script.consts);
+    assert_eq!(decoded.idents, script.idents);
+    assert_eq!(decoded.to_bytes().unwrap(), data);
+  }
+}
+
+#[test]
+fn version_rules() {
+  let script = compile_twscript(SCRIPT).unwrap();
+  let data = script.to_bytes().unwrap();
+
+  // A newer writer that adds a section older readers can ignore.
+  let mut newer = data.clone();
+  newer[4..6].copy_from_slice(&(FORMAT_VERSION + 1).to_le_bytes());
+  newer.extend_from_slice(&[200, 3, 0, 0, 0, 1, 2, 3]);
+  let decoded = TwScript::from_bytes(&newer).unwrap();
+  assert_eq!(decoded.to_bytes().unwrap(), data);
+
+  // A newer writer that older readers cannot understand.
+  let mut incompatible = newer.clone();
+  incompatible[6..8].copy_from_slice(&(FORMAT_VERSION + 1).to_le_bytes());
+  assert!(matches!(
+    format_error(&incompatible),
+    ScriptFormatError::UnsupportedVersion(x, y, z)
+      if x == FORMAT_VERSION + 1 && y == FORMAT_VERSION + 1 && z == FORMAT_VERSION
+  ));
+
+  let mut bad_magic = data.clone();
+  bad_magic[0] = b'X';
+  assert!(matches!(
+    format_error(&bad_magic),
+    ScriptFormatError::BadMagic
+  ));
+
+  // Dropping the graphs section, which is the last one.
+  let empty = TwScript::default().to_bytes().unwrap();
+  let graphs_section_len = 5 + 1;
+  assert!(matches!(
+    format_error(&empty[..empty.len() - graphs_section_len]),
+    ScriptFormatError::MissingSection("graphs")
+  ));
+  let mut duplicate = empty.clone();
+  duplicate.extend_from_slice(&empty[empty.len() - graphs_section_len..]);
+  assert!(matches!(
+    format_error(&duplicate),
+    ScriptFormatError::DuplicateSection("graphs")
+  ));
+
+  assert_eq!(data[6..8], MIN_READER_VERSION.to_le_bytes());
+
+  // A node kind from a newer writer. The only node is `[[0, 0], [], nil]`, with tag 0 for
+  // `LoadParam`.
+  let mut unknown_node = compile_twscript("graph main(x: int64): int64 { return x; }")
+    .unwrap()
+    .to_bytes()
+    .unwrap();
+  let node = [0x93, 0x92, 0x00, 0x00, 0x90, 0xc0];
+  let at = unknown_node
+    .windows(node.len())
+    .position(|x| x == node)
+    .unwrap();
+  unknown_node[at + 2] = 0x7f;
+  let err = TwScript::from_bytes(&unknown_node).unwrap_err().to_string();
+  assert!(err.contains("unknown graph node tag 127"), "{}", err);
+
+  // Every truncation is rejected, without panicking.
+  for len in 0..data.len() {
+    assert!(TwScript::from_bytes(&data[..len]).is_err(), "{}", len);
+  }
+}
+
+#[test]
+fn validate_on_load() {
+  let script = compile_twscript(SCRIPT).unwrap();
+  script.validate().unwrap();
+
+  let mut bad_const = script.clone();
+  bad_const.graphs[1].nodes[0].0 = TwGraphNode::LoadConst(100);
+  let mut bad_subgraph = script.clone();
+  for node in &mut bad_subgraph.graphs[0].nodes {
+    if let TwGraphNode::Call(x) = &mut node.0 {
+      *x = 2;
+    }
+  }
+  let mut unsorted = script.clone();
+  unsorted.graphs[0].nodes[0].1.push(0);
+  let mut bad_output = script.clone();
+  bad_output.graphs[1].output = Some(100);
+  let mut bad_param = script.clone();
+  bad_param.params[0].default = Some(100);
+  let mut not_deferred = script.clone();
+  not_deferred.graphs[1].deferred = vec![false; not_deferred.graphs[1].nodes.len()];
+  not_deferred.graphs[1].deferred[0] = true;
+
+  for (script, expected) in [
+    (
+      bad_const,
+      "graph `twice`, node 0: const index out of bounds",
+    ),
+    (bad_subgraph, "subgraph index out of bounds"),
+    (
+      unsorted,
+      "graph `main`, node 0: depends on a node that is not before it",
+    ),
+    (bad_output, "graph `twice`: output index out of bounds"),
+    (bad_param, "script param `limit`: const index out of bounds"),
+    (
+      not_deferred,
+      "depends on a deferred node but is not deferred",
+    ),
+  ] {
+    let err = TwScript::from_bytes(&script.to_bytes().unwrap())
+      .unwrap_err()
+      .to_string();
+    assert!(err.contains(expected), "{}", err);
+  }
+}
+
+#[test]
+fn decode_before_scan_index_limited() {
+  // Written before `ScanIndexLimited` was added, with the aggregate nodes right after
+  // `ScanIndex`.
+  let script =
+    TwScript::from_bytes(include_bytes!("../../fixtures/compiled/aggregates_v2.rtws")).unwrap();
+  let nodes = script.graphs[0]
+    .nodes
+    .iter()
+    .map(|x| x.0)
+    .collect::<Vec<_>>();
+  assert_eq!(
+    nodes,
+    vec![
+      TwGraphNode::LoadParam(0),
+      TwGraphNode::Count,
+      TwGraphNode::Sum(0)
+    ]
+  );
+}
+
+#[test]
+fn decode_committed_script() {
+  // Format version 3, compiled from:
+  //
+  // export graph main(root: schema, limit: int64): map { top: list<Item>, n: int64, total: int64 } {
+  //   top = scan_index_desc(score, limit) from 10 to 40 root.items;
+  //   defer {
+  //     s_insert root.items $ build_table(Item) $ m_insert(id) "a" $ m_insert(score) 1 create_map;
+  //   }
+  //   return m_insert(top) top $ m_insert(n) (s_count root.items) $
+  //     m_insert(total) (s_sum(score) root.items) create_map;
+  // }
+  let data = include_bytes!("../../fixtures/compiled/nodes_v3.rtws");
+  assert_eq!(data[4..6], 3u16.to_le_bytes());
+  let script = TwScript::from_bytes(data).unwrap();
+  let graph = &script.graphs[0];
+  assert_eq!(graph.name, "main");
+  use TwGraphNode as N;
+  assert_eq!(
+    graph.nodes.iter().map(|x| x.0).collect::<Vec<_>>(),
+    vec![
+      N::LoadParam(0),
+      N::LoadParam(1),
+      N::GetField(1),
+      N::LoadConst(0),
+      N::LoadConst(1),
+      N::ScanIndexLimited(0, true, true),
+      N::GetField(1),
+      N::LoadConst(2),
+      N::LoadConst(3),
+      N::CreateMap,
+      N::InsertIntoMap(0),
+      N::InsertIntoMap(3),
+      N::BuildTable(2),
+      N::InsertIntoSet,
+      N::GetField(1),
+      N::Count,
+      N::GetField(1),
+      N::Sum(0),
+      N::CreateMap,
+      N::InsertIntoMap(6),
+      N::InsertIntoMap(5),
+      N::InsertIntoMap(4),
+    ]
+  );
+  assert_eq!(
+    graph.deferred.iter().filter(|x| **x).count(),
+    8,
+    "{:?}",
+    graph.deferred
+  );
+  assert_eq!(script.types[0], VmType::Schema);
+}
@@ -0,0 +1,105 @@
+//! Cursors over sets returned from graphs.
+//!
+//! A graph may return a set of tables stored in the database, e.g. `root.items` or a set field
+//! of a member, without loading it. The caller turns the output into a `SetCursor` and reads the
+//! members page by page with `Executor::read_cursor_page`, each page in its own read-only
+//! transaction.
+//!
+//! A page is continued with a token that encodes the primary key of its last member, so cursors
+//! keep no state between pages. The next page can be read by running the graph again and passing
+//! the token, and sees the members as of that run. Tokens start with a short hash of the set's
+//! key prefix, so that a token of one set is rejected by a cursor over another.
+
+use std::sync::Arc;
+
+use anyhow::Result;
+use sha2::{Digest, Sha256};
+use thiserror::Error;
+
+use crate::data::{pathwalker::PathWalker, value::PrimitiveValue};
+
+use super::{
+  serialize::SerializedVmValue,
+  vm_value::{VmSetValue, VmSetValueKind, VmTableType, VmType, VmValue},
+};
+
+/// Length of the set hash at the start of a token.
+const TOKEN_HASH_LEN: usize = 8;
+
+#[derive(Error, Debug)]
+pub enum CursorError {
+  #[error("invalid cursor token")]
+  InvalidToken,
+}
+
+/// A set of tables stored in the database, to be read page by page.
+#[derive(Clone, Debug)]
+pub struct SetCursor<'a> {
+  pub(crate) walker: Arc<PathWalker<'a>>,
+  pub(crate) member_ty: &'a str,
+}
+
+/// Who the members of a cursor are read for.
+#[derive(Copy, Clone, Debug)]
+pub enum CursorReader<'b> {
+  /// Internal callers. `@acl` annotations are ignored.
+  Trusted,
+
+  /// A caller with an optional role. Fields protected by `@acl` are removed from the members
+  /// unless the role is allowed.
+  Role(Option<&'b str>),
+}
+
+/// A page of members read from a cursor.
+#[derive(Clone, Debug)]
+pub struct CursorPage {
+  /// Members in primary key order, encoded as maps.
+  pub members: Vec<SerializedVmValue>,
+
+  /// Token to pass as `after` to read the next page. `None` if there are no more members.
+  pub next: Option<String>,
+}
+
+impl<'a> SetCursor<'a> {
+  /// A cursor over `value`, or `None` if it is not a set of tables stored in the database. Sets
+  /// built by the graph itself are loaded into the output as usual.
+  pub fn from_value(value: &VmValue<'a>) -> Option<Self> {
+    match value {
+      VmValue::Set(VmSetValue {
+        member_ty: VmType::Table(VmTableType { name }),
+        kind: VmSetValueKind::Resident(walker),
+      }) => Some(Self {
+        walker: walker.clone(),
+        member_ty: name,
+      }),
+      _ => None,
+    }
+  }
+
+  /// Encodes the primary key of a member, in its key component form, into a token.
+  pub(crate) fn encode_token(&self, primary_key: &[u8]) -> Result<String> {
+    let mut raw = self.token_hash()?;
+    raw.extend_from_slice(primary_key);
+    Ok(base64::encode_config(&raw, base64::URL_SAFE_NO_PAD))
+  }
+
+  /// Inverse of `encode_token`. Fails with `CursorError::InvalidToken` if the token was not
+  /// returned by a cursor over the same set.
+  pub(crate) fn decode_token(&self, token: &str) -> Result<Vec<u8>> {
+    let raw = base64::decode_config(token, base64::URL_SAFE_NO_PAD)
+      .map_err(|_| CursorError::InvalidToken)?;
+    if raw.len() < TOKEN_HASH_LEN || raw[..TOKEN_HASH_LEN] != self.token_hash()?[..] {
+      return Err(CursorError::InvalidToken.into());
+    }
+    let primary_key = raw[TOKEN_HASH_LEN..].to_vec();
+    PrimitiveValue::deserialize_from_key_component(&primary_key)
+      .ok_or(CursorError::InvalidToken)?;
+    Ok(primary_key)
+  }
+
+  fn token_hash(&self) -> Result<Vec<u8>> {
+    let mut hasher = Sha256::new();
+    hasher.update(&self.walker.set_fast_scan_prefix()?);
+    Ok(hasher.finalize()[..TOKEN_HASH_LEN].to_vec())
+  }
+}
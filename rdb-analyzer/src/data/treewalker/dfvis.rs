@@ -1,7 +1,8 @@
 use anyhow::Result;
-use rdb_analyzer::data::treewalker::vm::TwVm;
 use serde::Serialize;
 
+use super::{trace::dataflow_node_id, vm::TwVm};
+
 #[derive(Serialize)]
 pub struct VisNode {
   id: usize,
@@ -65,8 +66,9 @@ impl<'a, 'b> Visualizer<'a, 'b> {
   fn visualize_graph(&mut self, graph_index: usize) -> Result<()> {
     let g = &self.vm.script.graphs[graph_index];
     let mut node_id_in_output: Vec<usize> = Vec::with_capacity(g.nodes.len());
-    for (n, in_edges, condition) in &g.nodes {
+    for (i, (n, in_edges, condition)) in g.nodes.iter().enumerate() {
       let id = self.output.nodes.len();
+      debug_assert_eq!(id, dataflow_node_id(self.vm.script, graph_index, i));
       self.output.nodes.push(VisNode {
         id,
         label: format!("{:?}", n),
@@ -127,6 +129,8 @@ impl<'a, 'b> Visualizer<'a, 'b> {
   }
 }
 
+/// Renders the dataflow of all graphs in `vm` as JSON. Node ids are stable, so they can be
+/// referred to by an `ExecTrace`.
 pub fn visualize_df(vm: &TwVm) -> Result<String> {
   let mut vis = Visualizer {
     vm,
@@ -1,34 +1,132 @@
-use std::{collections::BTreeMap, future::Future, pin::Pin, sync::Arc, time::Duration};
+use std::{
+  cmp::Ordering,
+  collections::{BTreeMap, BTreeSet, VecDeque},
+  future::Future,
+  ops::Bound,
+  pin::Pin,
+  sync::{
+    atomic::{AtomicU64, Ordering as AtomicOrdering},
+    Arc, Mutex,
+  },
+  time::{Duration, SystemTime, UNIX_EPOCH},
+};
 
 use anyhow::Result;
 use async_recursion::async_recursion;
+use futures::{stream, StreamExt, TryStreamExt};
 use rand::Rng;
 use rpds::{ListSync, RedBlackTreeMapSync};
 use smallvec::{smallvec, SmallVec};
 
 use crate::{
   data::{
-    kv::{KeyValueStore, KvError, KvTransaction},
-    pathwalker::PathWalker,
+    kv::{decode_counter, encode_counter, KeyValueStore, KvError, KvTransaction, ScanOptions},
+    mount::{mount_key_prefix, MountError, MOUNT_KEY_MARKER, MOUNT_ROOT_FIELD},
+    pathwalker::{PathSegment, PathWalker},
     treewalker::vm_value::{
-      VmListValue, VmMapValue, VmSetType, VmSetValue, VmSetValueKind, VmTableValue,
-      VmTableValueKind, VmType, VmValue,
+      PrimaryKey, VmListValue, VmMapValue, VmSetType, VmSetValue, VmSetValueKind, VmTableValue,
+      VmTableValueKind, VmType, VmValue, VmValueError,
     },
     value::PrimitiveValue,
   },
-  schema::compile::{CompiledSchema, FieldType},
+  schema::compile::{CompiledSchema, FieldAnnotationList, FieldType, PrimitiveType},
   storage_plan::StoragePlan,
 };
 use thiserror::Error;
 
 use super::{
+  acl::table_field_visible,
   bytecode::{TwGraph, TwGraphNode},
+  cursor::{CursorPage, CursorReader, SetCursor},
+  json::{self, JsonPathError},
+  limits::{ExecLimit, ExecLimits, LimitTracker},
+  serialize::{SerializeError, SerializedVmValue, TaggedVmValue, VmValueEncodeConfig},
+  trace::{ExecTrace, TraceRecorder},
   typeck::GlobalTypeInfo,
   vm::TwVm,
 };
 
+#[derive(Clone, Debug)]
 pub struct ExecConfig {
+  /// Max number of nodes of a graph invocation that run at the same time, and of KV reads that
+  /// a node keeps in flight when it reads many keys, e.g. the members found by a `scan_index`.
+  /// Subgraphs invoked by a node get their own budget.
   pub concurrency: usize,
+
+  pub scheduler: Scheduler,
+
+  /// Fail runs in which both candidates of a `Select` node fire, with
+  /// `ExecError::BothSelectCandidatesFired`. Otherwise the candidate that fired first is kept and
+  /// the other one is ignored with a warning. See `TwGraphNode::Select`.
+  pub strict_select: bool,
+}
+
+impl Default for ExecConfig {
+  fn default() -> Self {
+    Self {
+      concurrency: 16,
+      scheduler: Scheduler::Dataflow,
+      strict_select: true,
+    }
+  }
+}
+
+/// Environment bindings of a run, read with `env` in RefineAsm. Fields that the caller does not
+/// know are left empty.
+#[derive(Clone, Debug, Default)]
+pub struct ExecEnv {
+  pub namespace_id: String,
+  pub deployment_id: String,
+  pub query_script_id: String,
+
+  /// Id of the token that authenticated the caller.
+  pub token_id: String,
+
+  /// The role of the caller's token, checked against `@acl` annotations.
+  pub role: String,
+}
+
+impl ExecEnv {
+  /// Fields of the `env` map. All are strings.
+  pub const FIELDS: [&'static str; 5] = [
+    "namespace_id",
+    "deployment_id",
+    "query_script_id",
+    "token_id",
+    "role",
+  ];
+
+  fn to_vm_value<'a>(&self) -> VmValue<'a> {
+    let values = [
+      &self.namespace_id,
+      &self.deployment_id,
+      &self.query_script_id,
+      &self.token_id,
+      &self.role,
+    ];
+    let mut elements = RedBlackTreeMapSync::new_sync();
+    for (k, v) in Self::FIELDS.iter().zip(values.iter()) {
+      elements.insert_mut(
+        *k,
+        Arc::new(VmValue::Primitive(PrimitiveValue::String(v.to_string()))),
+      );
+    }
+    VmValue::Map(VmMapValue { elements })
+  }
+}
+
+/// How the nodes of a graph are scheduled.
+#[derive(Copy, Clone, Debug, Eq, PartialEq)]
+pub enum Scheduler {
+  /// Nodes run as soon as their inputs are ready, with up to `ExecConfig::concurrency` of them
+  /// in flight. Independent nodes may complete in any order. Deferred nodes that are ready wait
+  /// until no other node is running or ready.
+  Dataflow,
+
+  /// Nodes run one by one in topological order, within the task that runs the graph. Runs are
+  /// reproducible, which helps debugging, and small graphs avoid the overhead of a future per
+  /// node. KV reads within a node are still pipelined.
+  Topological,
 }
 
 pub struct Executor<'a, 'b> {
@@ -38,6 +136,58 @@ pub struct Executor<'a, 'b> {
   fire_rule_tables: Vec<FireRuleTable>,
   yield_fn: Option<fn() -> Pin<Box<dyn Future<Output = ()> + Send>>>,
   sleep_fn: Option<fn(Duration) -> Pin<Box<dyn Future<Output = ()> + Send>>>,
+  trace: Option<TraceRecorder>,
+
+  /// Changes made by the current run, if change tracking is enabled.
+  changes: Option<Mutex<Vec<Change>>>,
+
+  /// The value of `TimeNow` nodes in the current run.
+  now_millis: i64,
+
+  /// Randomness for `GenId` nodes. Kept across retries of the same `run_graph` call.
+  id_seed: [u8; 16],
+
+  /// Number of `GenId` nodes fired in the current attempt.
+  id_counter: AtomicU64,
+
+  /// Held across `KvTransaction::atomic_add` calls. Backends without atomic operations read,
+  /// modify and write, which loses adds made concurrently by independent nodes.
+  atomic_add_lock: futures::lock::Mutex<()>,
+
+  retry_policy: RetryPolicy,
+
+  /// Run all graphs in read-only transactions.
+  read_only: bool,
+
+  limits: LimitTracker,
+
+  config: ExecConfig,
+
+  /// The value of `LoadEnv` nodes.
+  env: Arc<VmValue<'a>>,
+}
+
+/// How transactions that fail with `KvError::Conflict` are retried.
+#[derive(Clone, Debug)]
+pub struct RetryPolicy {
+  /// Total number of attempts, including the first one.
+  pub max_attempts: usize,
+
+  /// Upper bound of the delay before the first retry. Doubles on each retry, up to `max_delay`.
+  /// The actual delay is drawn uniformly below the bound.
+  pub base_delay: Duration,
+
+  pub max_delay: Duration,
+}
+
+impl Default for RetryPolicy {
+  fn default() -> Self {
+    Self {
+      max_attempts: 10,
+      base_delay: Duration::from_millis(5),
+      max_delay: Duration::from_millis(200),
+    }
+  }
 }
 
 #[derive(Clone)]
@@ -59,7 +209,7 @@ pub enum ExecError {
   #[error("not yet implemented: {0}")]
   NotImplemented(String),
 
-  #[error("null value unwrappped")]
+  #[error("null value unwrapped")]
   NullUnwrapped,
 
   #[error("operation is not supported on fresh tables or sets")]
@@ -68,8 +218,8 @@ pub enum ExecError {
   #[error("export type not supported")]
   ExportTypeNotSupported,
 
-  #[error("max recursion depth exceeded: {0}")]
-  MaxRecursionDepthExceeded(usize),
+  #[error("limit exceeded: {0}")]
+  LimitExceeded(ExecLimit),
 
   #[error("both select candidates are fired - this is not deterministic and not allowed")]
   BothSelectCandidatesFired,
@@ -85,9 +235,171 @@ pub enum ExecError {
 
   #[error("script thrown null")]
   ScriptThrownNull,
+
+  #[error("unique constraint violated on field `{0}`")]
+  UniqueConstraintViolation(String),
+
+  #[error("not a set of tables: `{0}`")]
+  NotSetOfTables(String),
+
+  #[error("null primary key")]
+  NullPrimaryKey,
+
+  #[error("division by zero")]
+  DivisionByZero,
+
+  #[error("graph is not read-only: `{0}`")]
+  GraphNotReadOnly(String),
+
+  #[error("not a set of tables or a table: `{0}`")]
+  NotSetOrTable(String),
+
+  #[error("invalid row {0}: {1}")]
+  InvalidRow(usize, String),
+
+  #[error("invalid json: {0}")]
+  InvalidJson(String),
+
+  #[error(transparent)]
+  InvalidJsonPath(#[from] JsonPathError),
+
+  #[error("invalid encoded bytes: {0}")]
+  InvalidBytesEncoding(String),
+
+  #[error("version conflict: expected version {0}, found {1}")]
+  VersionConflict(i64, i64),
+
+  #[error("inconsistency detected: {0}")]
+  Inconsistency(String),
+}
+
+impl ExecError {
+  /// Whether this error was raised by the script itself, with `throw`, by unwrapping a null
+  /// value or by passing bad input to a builtin, rather than by the executor or the data store.
+  pub fn is_script_error(&self) -> bool {
+    matches!(
+      self,
+      Self::ScriptThrownError(_)
+        | Self::ScriptThrownNull
+        | Self::NullUnwrapped
+        | Self::InvalidJson(_)
+        | Self::InvalidJsonPath(_)
+        | Self::InvalidBytesEncoding(_)
+    )
+  }
+}
+
+/// A change to the data, recorded by an `Executor` with change tracking enabled.
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub struct Change {
+  /// Path to the changed set or table.
+  pub path: Vec<PathSegment>,
+  pub kind: ChangeKind,
+}
+
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub enum ChangeKind {
+  /// A member with the given primary key was inserted into the set.
+  Insert(PrimitiveValue),
+
+  /// An existing member of the set was replaced.
+  Update(PrimitiveValue),
+
+  /// An existing member of the set was deleted.
+  Delete(PrimitiveValue),
+
+  /// A field of the table was set.
+  SetField(String),
+}
+
+/// The outcome of deleting one member in `Executor::bulk_delete`.
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub enum BulkDeleteOutcome {
+  Deleted,
+  NotFound,
+  Failed(String),
 }
 
-const MAX_RECURSION_DEPTH: usize = 128;
+/// A page of rows read by `Executor::export_rows`.
+#[derive(Clone, Debug)]
+pub struct ExportPage {
+  pub rows: Vec<SerializedVmValue>,
+
+  /// Primary key of the last row, to pass as `after` for the next page. `None` if there are no
+  /// more rows.
+  pub next: Option<SerializedVmValue>,
+}
+
+/// The result of `Executor::fsck`.
+#[derive(Clone, Debug, Default)]
+pub struct FsckReport {
+  pub issues: Vec<FsckIssue>,
+
+  /// Number of set members checked.
+  pub num_members: u64,
+}
+
+/// An inconsistency found by `Executor::fsck`.
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub struct FsckIssue {
+  pub kind: FsckIssueKind,
+
+  /// Dot-separated path from an export to the set, or empty for keys outside of any export.
+  pub path: String,
+
+  /// The key at fault. For `MissingIndexEntry`, the key of the missing entry.
+  pub key: Vec<u8>,
+
+  /// The error for `UndecodableMember`. Empty otherwise.
+  pub detail: String,
+
+  /// Whether the issue was repaired.
+  pub repaired: bool,
+}
+
+#[derive(Copy, Clone, Debug, Eq, PartialEq)]
+pub enum FsckIssueKind {
+  /// A fast scan entry of a set with no member data. Repaired by deleting the entry.
+  MissingMemberData,
+
+  /// Member data of a set with no fast scan entry. Repaired by deleting the data.
+  OrphanMemberData,
+
+  /// A member of a set whose data cannot be decoded. Not repaired.
+  UndecodableMember,
+
+  /// An index entry that a member should have but doesn't. Repaired by adding the entry.
+  MissingIndexEntry,
+
+  /// An index entry that does not belong to any member. Repaired by deleting the entry.
+  DanglingIndexEntry,
+
+  /// A key that is not under any export of the schema. Only deleted if asked for separately, as
+  /// it may belong to a field that another deployment still has.
+  OrphanKey,
+}
+
+/// A set checked by `Executor::fsck`.
+struct FsckSet<'a> {
+  path: String,
+  walker: Arc<PathWalker<'a>>,
+  member_ty: &'a str,
+}
+
+/// The checks of `Executor::fsck` on a set, each a scan over one key range of the set.
+#[derive(Copy, Clone, Debug)]
+enum FsckPass {
+  /// Over the fast scan entries.
+  Members,
+
+  /// Over the member data.
+  MemberData,
+
+  /// Over the index entries.
+  Index,
+}
+
+const MILLIS_PER_DAY: i64 = 86_400_000;
 
 impl<'a, 'b> Executor<'a, 'b> {
   pub fn new(
@@ -106,9 +418,47 @@ impl<'a, 'b> Executor<'a, 'b> {
       fire_rule_tables,
       yield_fn: None,
       sleep_fn: None,
+      trace: None,
+      changes: None,
+      now_millis: current_millis(),
+      id_seed: rand::random(),
+      id_counter: AtomicU64::new(0),
+      atomic_add_lock: futures::lock::Mutex::new(()),
+      retry_policy: RetryPolicy::default(),
+      read_only: false,
+      limits: LimitTracker::new(ExecLimits::default()),
+      config: ExecConfig::default(),
+      env: Arc::new(ExecEnv::default().to_vm_value()),
     }
   }
 
+  /// Records every node that fires in subsequent calls to `run_graph`. Slows down execution.
+  pub fn enable_tracing(&mut self) {
+    self.trace = Some(TraceRecorder::new());
+  }
+
+  /// Takes the trace of the last `run_graph` call, if tracing is enabled.
+  pub fn take_trace(&mut self) -> Option<ExecTrace> {
+    self.trace.take().map(|x| x.into_trace())
+  }
+
+  /// Records the changes made by subsequent calls to `run_graph` and `run_graph_in_transaction`.
+  /// Deletions from `bulk_delete` are not recorded.
+  pub fn enable_change_tracking(&mut self) {
+    self.changes = Some(Mutex::new(vec![]));
+  }
+
+  /// Takes the changes made by the last `run_graph` or `run_graph_in_transaction` call, if change
+  /// tracking is enabled. Empty if the call failed.
+  ///
+  /// Changes made by `run_graph_in_transaction` are only durable once the caller commits.
+  pub fn take_changes(&mut self) -> Option<Vec<Change>> {
+    self
+      .changes
+      .as_mut()
+      .map(|x| std::mem::take(x.get_mut().unwrap()))
+  }
+
   pub fn set_yield_fn(&mut self, f: fn() -> Pin<Box<dyn Future<Output = ()> + Send>>) {
     self.yield_fn = Some(f);
   }
@@ -117,164 +467,1463 @@ impl<'a, 'b> Executor<'a, 'b> {
     self.sleep_fn = Some(f);
   }
 
+  pub fn set_retry_policy(&mut self, policy: RetryPolicy) {
+    self.retry_policy = policy;
+  }
+
+  /// Runs graphs in read-only transactions, as if they were declared `readonly`. `run_graph`
+  /// rejects graphs that are not read-only.
+  pub fn set_read_only(&mut self, read_only: bool) {
+    self.read_only = read_only;
+  }
+
+  pub fn set_limits(&mut self, limits: ExecLimits) {
+    self.limits = LimitTracker::new(limits);
+  }
+
+  pub fn set_config(&mut self, config: ExecConfig) {
+    self.config = config;
+  }
+
+  pub fn set_env(&mut self, env: &ExecEnv) {
+    self.env = Arc::new(env.to_vm_value());
+  }
+
+  fn concurrency(&self) -> usize {
+    self.config.concurrency.max(1)
+  }
+
+  /// Runs `futures` with up to `concurrency` of them in flight, keeping their order.
+  async fn buffered<T>(
+    &self,
+    futures: impl Iterator<Item = impl Future<Output = Result<T>>>,
+  ) -> Result<Vec<T>> {
+    // Collect first, so that the stream does not capture the closure that produces the futures.
+    let futures = futures.collect::<Vec<_>>();
+    stream::iter(futures)
+      .buffered(self.concurrency())
+      .try_collect()
+      .await
+  }
+
   pub async fn run_graph(
     &mut self,
     graph_index: usize,
     graph_params: &[Arc<VmValue<'a>>],
   ) -> Result<Option<Arc<VmValue<'a>>>> {
-    for i in 0..10 {
-      let txn = self.kv.begin_transaction().await?;
-      let ret = self
-        .recursively_run_graph(graph_index, graph_params, 0, &*txn)
-        .await?;
+    // Declared `readonly` graphs are checked by `TwVm::new`.
+    let g = &self.vm.script.graphs[graph_index];
+    if self.read_only && !g.read_only && !self.vm.is_graph_read_only(graph_index) {
+      return Err(ExecError::GraphNotReadOnly(g.name.clone()).into());
+    }
+    let read_only = self.read_only || g.read_only;
+
+    self.id_seed = rand::random();
+    self.limits.start_run();
+    for i in 0..self.retry_policy.max_attempts {
+      // Only keep the trace of the last attempt.
+      if self.trace.is_some() {
+        self.trace = Some(TraceRecorder::new());
+      }
+      self.clear_changes();
+      self.now_millis = current_millis();
+      *self.id_counter.get_mut() = 0;
+      self.limits.start_attempt();
+
+      let txn = if read_only {
+        self.kv.begin_read_only_transaction().await?
+      } else {
+        self.kv.begin_transaction().await?
+      };
+
+      // Reads may also fail with a conflict, e.g. when SQLite cannot get a shared lock.
+      let ret = match self
+        .run_top_level_graph(graph_index, graph_params, &*txn)
+        .await
+      {
+        Ok(x) => x,
+        Err(e) if is_conflict(&e) => {
+          drop(txn);
+          self.wait_after_conflict(i).await;
+          continue;
+        }
+        Err(e) => {
+          self.clear_changes();
+          return Err(e);
+        }
+      };
 
       match txn.commit().await {
         Ok(()) => {
           return Ok(ret);
         }
-        Err(KvError::Conflict) => {
-          if let Some(f) = self.sleep_fn {
-            let delay_ms = rand::thread_rng().gen_range(1..20);
-            log::warn!(
-              "Conflict detected when committing transaction (attempt {}). Waiting for {} ms.",
-              i,
-              delay_ms
-            );
-            f(Duration::from_millis(delay_ms as u64)).await;
-          } else {
-            log::warn!(
-              "Conflict detected when committing transaction (attempt {}).",
-              i
-            );
-          }
+        Err(KvError::Conflict) => self.wait_after_conflict(i).await,
+        Err(x) => {
+          self.clear_changes();
+          return Err(x.into());
         }
-        Err(x) => return Err(x.into()),
       }
     }
+    self.clear_changes();
     Err(ExecError::ConflictAfterRetries.into())
   }
 
-  #[async_recursion]
-  async fn recursively_run_graph(
+  /// Runs a graph in `txn`, which is committed by the caller. Conflicts are not retried.
+  ///
+  /// As with nodes in one graph, graphs run in the same transaction are not guaranteed to see
+  /// each other's writes. The FoundationDB and SQLite backends only apply them on commit.
+  pub async fn run_graph_in_transaction(
+    &mut self,
+    graph_index: usize,
+    graph_params: &[Arc<VmValue<'a>>],
+    txn: &dyn KvTransaction,
+  ) -> Result<Option<Arc<VmValue<'a>>>> {
+    if self.trace.is_some() {
+      self.trace = Some(TraceRecorder::new());
+    }
+    self.clear_changes();
+    self.now_millis = current_millis();
+    self.id_seed = rand::random();
+    *self.id_counter.get_mut() = 0;
+    self.limits.start_run();
+    self.limits.start_attempt();
+    let ret = self
+      .run_top_level_graph(graph_index, graph_params, txn)
+      .await;
+    if ret.is_err() {
+      self.clear_changes();
+    }
+    ret
+  }
+
+  /// Runs a graph from `run_graph` or `run_graph_in_transaction`, counting its KV operations if
+  /// they are limited.
+  async fn run_top_level_graph(
     &self,
     graph_index: usize,
     graph_params: &[Arc<VmValue<'a>>],
-    recursion_depth: usize,
     txn: &dyn KvTransaction,
   ) -> Result<Option<Arc<VmValue<'a>>>> {
-    if recursion_depth >= MAX_RECURSION_DEPTH {
-      return Err(ExecError::MaxRecursionDepthExceeded(recursion_depth).into());
+    if self.limits.limits_kv() {
+      let txn = self.limits.wrap_txn(txn);
+      self
+        .recursively_run_graph(graph_index, graph_params, 0, &txn)
+        .await
+    } else {
+      self
+        .recursively_run_graph(graph_index, graph_params, 0, txn)
+        .await
     }
+  }
 
-    if let Some(f) = self.yield_fn {
-      f().await;
+  fn clear_changes(&mut self) {
+    if let Some(x) = &mut self.changes {
+      x.get_mut().unwrap().clear();
     }
+  }
 
-    let recursion_depth = recursion_depth + 1;
-    let g = &self.vm.script.graphs[graph_index];
-    let type_info = &self.type_info.graphs[graph_index];
-    let fire_rules = &self.fire_rule_tables[graph_index];
-    let mut deps_satisfied: SmallVec<[SmallVec<[Option<Arc<VmValue<'a>>>; 3]>; 16]> = g
-      .nodes
-      .iter()
-      .map(|(_, x, _)| smallvec![None; x.len()])
-      .collect();
-    let mut precondition_satisfied: SmallVec<[bool; 16]> =
-      g.nodes.iter().map(|(_, _, x)| x.is_none()).collect();
+  fn record_change(&self, walker: &PathWalker<'a>, kind: impl FnOnce() -> ChangeKind) {
+    if let Some(x) = &self.changes {
+      x.lock().unwrap().push(Change {
+        path: walker.path(),
+        kind: kind(),
+      });
+    }
+  }
 
-    // The initial batch
-    let mut futures: Vec<
-      Pin<Box<dyn Future<Output = (u32, Result<Option<Arc<VmValue<'a>>>>)> + Send>>,
-    > = vec![];
-    for (i, (n, in_edges, precondition)) in g.nodes.iter().enumerate() {
-      if in_edges.is_empty() && precondition.is_none() {
-        let txn = &*txn;
-        futures.push(Box::pin(async move {
-          (
-            i as u32,
-            self
-              .run_node(
-                n,
-                vec![],
-                txn,
-                graph_params,
-                type_info.nodes[i].as_ref(),
-                recursion_depth,
-              )
-              .await,
-          )
-        }));
+  /// Deletes members of the set at `set_path` by primary key.
+  ///
+  /// `set_path` is a dot-separated path from an export to the set, e.g. `users` or
+  /// `app.users`. Members are deleted `chunk_size` at a time, each chunk in its own transaction,
+  /// so a failed chunk does not roll back the chunks before it. Returns one outcome per key.
+  pub async fn bulk_delete(
+    &self,
+    set_path: &str,
+    keys: &[SerializedVmValue],
+    chunk_size: usize,
+  ) -> Result<Vec<BulkDeleteOutcome>> {
+    let set = self.resolve_set_path(set_path)?;
+    let walker = match &set.kind {
+      VmSetValueKind::Resident(x) => x,
+      VmSetValueKind::Fresh(_) => unreachable!(),
+    };
+    let member_ty = unwrap_enum!(&set.member_ty, VmType::Table(x) => x.name);
+    let primary_key_ty = VmType::Set(VmSetType {
+      ty: Box::new(set.member_ty.clone()),
+    })
+    .set_primary_key(self.vm.schema)
+    .expect("inconsistency: primary key not found for set member")
+    .value_type();
+
+    let mut outcomes = Vec::with_capacity(keys.len());
+    for chunk in keys.chunks(chunk_size.max(1)) {
+      let mut chunk_outcomes = Vec::with_capacity(chunk.len());
+      let mut primary_keys = vec![];
+      for key in chunk {
+        match decode_primary_key(key, &primary_key_ty) {
+          Ok(x) => {
+            chunk_outcomes.push(None);
+            primary_keys.push(x);
+          }
+          Err(e) => chunk_outcomes.push(Some(BulkDeleteOutcome::Failed(e.to_string()))),
+        }
+      }
+
+      let mut results = match self
+        .delete_chunk_from_set(walker, member_ty, &primary_keys)
+        .await
+      {
+        Ok(x) => x,
+        Err(e) => vec![BulkDeleteOutcome::Failed(e.to_string()); primary_keys.len()],
       }
+      .into_iter();
+      outcomes.extend(
+        chunk_outcomes
+          .into_iter()
+          .map(|x| x.unwrap_or_else(|| results.next().unwrap())),
+      );
     }
+    Ok(outcomes)
+  }
 
-    let mut ret: Option<Arc<VmValue<'a>>> = None;
+  /// Deletes the expired members of the set at `set_path`, whose member type has a `@ttl` field.
+  ///
+  /// `set_path` is resolved as in `bulk_delete`. Members are scanned in primary key order,
+  /// `batch_size` at a time, and the expired ones of each batch are deleted in its own
+  /// transaction. Returns the number of deleted members.
+  pub async fn reap_expired(&self, set_path: &str, batch_size: usize) -> Result<u64> {
+    let set = self.resolve_set_path(set_path)?;
+    let walker = match &set.kind {
+      VmSetValueKind::Resident(x) => x,
+      VmSetValueKind::Fresh(_) => unreachable!(),
+    };
+    let member_ty = unwrap_enum!(&set.member_ty, VmType::Table(x) => x.name);
+    if self.vm.schema.types.get(member_ty).unwrap().ttl.is_none() {
+      return Ok(0);
+    }
+    let now = current_millis();
 
+    let range_prefix = walker.set_fast_scan_prefix().unwrap();
+    let mut range_start = range_prefix.clone();
+    let mut range_end = range_prefix.clone();
+    *range_end.last_mut().unwrap() += 1;
+    let mut num_deleted = 0u64;
     loop {
-      if futures.is_empty() {
-        break;
+      let (last_key, n) = self
+        .reap_expired_batch(
+          walker,
+          member_ty,
+          &range_start,
+          &range_end,
+          batch_size.max(1),
+          now,
+        )
+        .await?;
+      num_deleted += n as u64;
+      match last_key {
+        Some(x) => {
+          range_start = x;
+          range_start.push(0x00);
+        }
+        None => break,
       }
-      let ((node_index, result), _, remaining) = futures::future::select_all(futures).await;
-      let result = result?;
-      futures = remaining;
+    }
+    Ok(num_deleted)
+  }
 
-      if Some(node_index) == g.output {
-        ret = result.clone();
-      }
+  /// Deletes the expired members among the first `batch_size` members of a set in
+  /// `[range_start, range_end)` of its fast scan keys. Returns the last scanned key, or `None` if
+  /// the range had no more than `batch_size` members, and the number of deleted members.
+  async fn reap_expired_batch(
+    &self,
+    walker: &Arc<PathWalker<'a>>,
+    member_ty: &'a str,
+    range_start: &[u8],
+    range_end: &[u8],
+    batch_size: usize,
+    now: i64,
+  ) -> Result<(Option<Vec<u8>>, usize)> {
+    let range_prefix = walker.set_fast_scan_prefix().unwrap();
+    for i in 0..self.retry_policy.max_attempts {
+      let txn = self.kv.begin_transaction().await?;
 
-      let to_fire = fire_rules[node_index as usize].as_slice();
-      for item in to_fire {
-        match &item.kind {
-          FireRuleKind::ParamDep(param_position) => {
-            let result = result.as_ref().unwrap_or_else(|| {
-                panic!(
-                  "run_graph: node {} is a parameter dependency of some other nodes but does not produce a value",
-                  node_index
-                )
-              });
+      let mut keys = vec![];
+      let mut it = txn.scan_keys(range_start, range_end).await?;
+      while let Some(k) = it.next().await? {
+        keys.push(k);
+        if keys.len() == batch_size {
+          break;
+        }
+      }
+      drop(it);
 
-            deps_satisfied[item.target_node as usize][*param_position as usize] =
-              Some(result.clone());
-          }
-          FireRuleKind::Precondition => {
-            precondition_satisfied[item.target_node as usize] = match result.as_ref().map(|x| &**x)
-            {
-              Some(VmValue::Bool(x)) => *x,
-              Some(VmValue::Null(_)) => false,
-              None => true,
-              _ => panic!("inconsistency detected: invalid precondition: {:?}", result),
-            };
-          }
+      let mut num_deleted = 0usize;
+      for k in &keys {
+        let primary_key_value = k.strip_prefix(range_prefix.as_slice()).unwrap();
+        let member = VmTableValue {
+          ty: member_ty,
+          kind: VmTableValueKind::Resident(walker.enter_set_raw(primary_key_value).unwrap()),
+        };
+        if self.is_expired(&*txn, &member, now).await? {
+          self
+            .delete_entry_from_set(&*txn, walker, member_ty, primary_key_value)
+            .await?;
+          num_deleted += 1;
         }
       }
 
-      // Do this in another iteration in case that a single source node is connect to a single target node's
-      // multiple parameters.
-      for item in to_fire {
-        let target_node = item.target_node as usize;
-        let node_info = &g.nodes[target_node].0;
+      match txn.commit().await {
+        Ok(()) => {
+          let last_key = if keys.len() == batch_size {
+            keys.pop()
+          } else {
+            None
+          };
+          return Ok((last_key, num_deleted));
+        }
+        Err(KvError::Conflict) => self.wait_after_conflict(i).await,
+        Err(x) => return Err(x.into()),
+      }
+    }
+    Err(ExecError::ConflictAfterRetries.into())
+  }
 
-        // If all deps and the precondition are satisfied...
-        if precondition_satisfied[item.target_node as usize] {
-          if node_info.is_select() {
-            if deps_satisfied[item.target_node as usize].is_empty() {
-              return Err(ExecError::BothSelectCandidatesFired.into());
+  /// Moves all data stored under `source_plan` into the layout of this executor's storage plan.
+  ///
+  /// Both plans must describe the schema of this executor with the same storage keys and differ
+  /// only in their key layout, e.g. after `planner::assign_key_aliases`. The two layouts must not
+  /// share any key prefix.
+  ///
+  /// Members of exported sets are moved `batch_size` at a time, each batch in its own
+  /// transaction. Any other export is moved in a single transaction. An interrupted migration can
+  /// be resumed by calling this again with the same plans. Returns the number of moved set
+  /// members.
+  pub async fn migrate_layout(
+    &self,
+    source_plan: &'a StoragePlan,
+    batch_size: usize,
+  ) -> Result<u64> {
+    let mut num_moved_members = 0u64;
+    for (export_name, export_ty) in &self.vm.schema.exports {
+      let source = PathWalker::from_export(source_plan, export_name)?;
+      let target = PathWalker::from_export(self.vm.storage_plan, export_name)?;
+      match export_ty {
+        FieldType::Set(member_ty) => {
+          let member_ty = match &**member_ty {
+            FieldType::Table(x) => &**x,
+            _ => return Err(ExecError::ExportTypeNotSupported.into()),
+          };
+          loop {
+            let n = self
+              .migrate_set_members(&source, &target, member_ty, batch_size.max(1))
+              .await?;
+            if n == 0 {
+              break;
             }
+            num_moved_members += n as u64;
+          }
+        }
+        FieldType::Table(x) => {
+          let value = Arc::new(VmValue::Table(VmTableValue {
+            ty: x,
+            kind: VmTableValueKind::Resident(source.clone()),
+          }));
+          self.migrate_whole(&source, &target, value).await?;
+        }
+        FieldType::Primitive(_) | FieldType::List(_) | FieldType::Map(..) => {
+          let value = Arc::new(VmValue::Null(VmType::from(export_ty)));
+          self.migrate_whole(&source, &target, value).await?;
+        }
+      }
+      log::info!("Migrated export `{}`.", export_name);
+    }
+    Ok(num_moved_members)
+  }
 
-            if let Some(x) = deps_satisfied[item.target_node as usize]
-              .iter()
-              .find_map(|x| x.as_ref())
-            {
-              let x = x.clone();
+  /// Moves up to `batch_size` members of the set at `source` into the set at `target`. Once the
+  /// source set is empty, deletes what is left of it and returns zero.
+  async fn migrate_set_members(
+    &self,
+    source: &Arc<PathWalker<'a>>,
+    target: &Arc<PathWalker<'a>>,
+    member_ty: &'a str,
+    batch_size: usize,
+  ) -> Result<usize> {
+    for i in 0..self.retry_policy.max_attempts {
+      let txn = self.kv.begin_transaction().await?;
 
-              // Fire only once!
-              deps_satisfied[item.target_node as usize] = smallvec![];
+      let range_prefix = source.set_fast_scan_prefix().unwrap();
+      let mut range_end = range_prefix.clone();
+      *range_end.last_mut().unwrap() += 1;
+      let mut primary_keys = vec![];
+      let mut it = txn.scan_keys(&range_prefix, &range_end).await?;
+      while let Some(k) = it.next().await? {
+        primary_keys.push(k.strip_prefix(range_prefix.as_slice()).unwrap().to_vec());
+        if primary_keys.len() == batch_size {
+          break;
+        }
+      }
+      drop(it);
 
-              futures.push(Box::pin(async move { (target_node as u32, Ok(Some(x))) }))
-            }
-          } else {
-            if deps_satisfied[item.target_node as usize]
-              .iter()
-              .find(|x| x.is_none())
+      if primary_keys.is_empty() {
+        self.delete_subtree(&*txn, source).await?;
+      } else {
+        txn.put(&target.generate_key(), &[]).await?;
+      }
+      for primary_key_value in &primary_keys {
+        let member = Arc::new(VmValue::Table(VmTableValue {
+          ty: member_ty,
+          kind: VmTableValueKind::Resident(source.enter_set_raw(primary_key_value).unwrap()),
+        }));
+        let member = self.load_resident(&*txn, member).await?;
+        self
+          .delete_entry_from_set(&*txn, source, member_ty, primary_key_value)
+          .await?;
+
+        let mut fast_scan_key = target.set_fast_scan_prefix().unwrap();
+        fast_scan_key.extend_from_slice(primary_key_value);
+        txn.put(&fast_scan_key, &[]).await?;
+        let index_fields = self
+          .read_indexed_fields(&*txn, member.unwrap_table(), None)
+          .await?;
+        self
+          .walk_and_insert(
+            &*txn,
+            target.enter_set_raw(primary_key_value).unwrap(),
+            member,
+          )
+          .await?;
+        self
+          .add_index_entries(&*txn, target, member_ty, primary_key_value, &index_fields)
+          .await?;
+      }
+
+      match txn.commit().await {
+        Ok(()) => return Ok(primary_keys.len()),
+        Err(KvError::Conflict) => self.wait_after_conflict(i).await,
+        Err(x) => return Err(x.into()),
+      }
+    }
+    Err(ExecError::ConflictAfterRetries.into())
+  }
+
+  /// Moves `value`, a resident value at `source` or a null placeholder for a primitive, to
+  /// `target` in a single transaction.
+  async fn migrate_whole(
+    &self,
+    source: &Arc<PathWalker<'a>>,
+    target: &Arc<PathWalker<'a>>,
+    value: Arc<VmValue<'a>>,
+  ) -> Result<()> {
+    for i in 0..self.retry_policy.max_attempts {
+      let txn = self.kv.begin_transaction().await?;
+
+      // Already moved, or never written.
+      if !self.anything_stored_under(&*txn, source).await? {
+        return Ok(());
+      }
+
+      let loaded = match &*value {
+        VmValue::Null(VmType::List(x)) if source.node().map => {
+          self.read_map(&*txn, source, (*x.ty).clone()).await?
+        }
+        VmValue::Null(VmType::List(x)) => self.read_list(&*txn, source, (*x.ty).clone()).await?,
+        VmValue::Null(_) => {
+          let raw_data = txn
+            .get(&source.generate_key())
+            .await?
+            .map(|x| decode_primitive_field(source, &x))
+            .transpose()?;
+          raw_data
+            .map(|x| Arc::new(VmValue::Primitive(x)))
+            .unwrap_or_else(|| value.clone())
+        }
+        _ => self.load_resident(&*txn, value.clone()).await?,
+      };
+      self.delete_subtree(&*txn, source).await?;
+      self.walk_and_insert(&*txn, target.clone(), loaded).await?;
+
+      match txn.commit().await {
+        Ok(()) => return Ok(()),
+        Err(KvError::Conflict) => self.wait_after_conflict(i).await,
+        Err(x) => return Err(x.into()),
+      }
+    }
+    Err(ExecError::ConflictAfterRetries.into())
+  }
+
+  /// Checks the stored data of this executor's schema for inconsistencies. Issues in sets are
+  /// repaired if `repair` is set, and keys outside of every export are deleted if
+  /// `delete_orphan_keys` is set.
+  ///
+  /// Every set of tables reachable from an export through table fields is checked for fast scan
+  /// entries without member data and vice versa, members that cannot be decoded, and index
+  /// entries that are missing or don't belong to a member. Sets in set members are only checked
+  /// for decodability, as part of their enclosing member. Keys outside of every export are
+  /// reported as orphaned.
+  ///
+  /// Keys are scanned `batch_size` at a time, each batch in its own transaction, so every issue is
+  /// found and repaired consistently, but the report as a whole is not a snapshot. Both kinds of
+  /// repair delete the data of fields that are not in the schema, so only repair with the latest
+  /// deployment.
+  pub async fn fsck(
+    &self,
+    batch_size: usize,
+    repair: bool,
+    delete_orphan_keys: bool,
+  ) -> Result<FsckReport> {
+    let batch_size = batch_size.max(1);
+    let mut sets = vec![];
+    let mut exact_keys = BTreeSet::new();
+    let mut prefixes = BTreeSet::new();
+    for (export_name, export_ty) in &self.vm.schema.exports {
+      self.collect_fsck_targets(
+        export_name.to_string(),
+        export_ty,
+        PathWalker::from_export(self.vm.storage_plan, export_name)?,
+        &mut sets,
+        &mut exact_keys,
+        &mut prefixes,
+      )?;
+    }
+
+    let mut report = FsckReport::default();
+    for set in &sets {
+      for pass in [FsckPass::Members, FsckPass::MemberData, FsckPass::Index] {
+        let mut range_start = fsck_range_prefix(&set.walker, pass);
+        loop {
+          let last_key = self
+            .fsck_set_batch(set, pass, &range_start, batch_size, repair, &mut report)
+            .await?;
+          match last_key {
+            Some(x) => {
+              range_start = x;
+              range_start.push(0x00);
+            }
+            None => break,
+          }
+        }
+      }
+      log::info!("Checked set `{}`.", set.path);
+    }
+
+    let mut range_start = vec![];
+    loop {
+      let next = self
+        .fsck_orphan_batch(
+          &range_start,
+          &exact_keys,
+          &prefixes,
+          batch_size,
+          delete_orphan_keys,
+          &mut report.issues,
+        )
+        .await?;
+      match next {
+        Some(x) => range_start = x,
+        None => break,
+      }
+    }
+    Ok(report)
+  }
+
+  /// Collects the sets to check below the field at `walker`, and the keys that data of the field
+  /// can be stored at: exact keys of flattened tables, and prefixes of everything else.
+  fn collect_fsck_targets(
+    &self,
+    path: String,
+    ty: &'a FieldType,
+    walker: Arc<PathWalker<'a>>,
+    sets: &mut Vec<FsckSet<'a>>,
+    exact_keys: &mut BTreeSet<Vec<u8>>,
+    prefixes: &mut BTreeSet<Vec<u8>>,
+  ) -> Result<()> {
+    match ty {
+      // Subspace references are not flattened, and are covered by their prefix.
+      FieldType::Table(x) if walker.is_flattened() => {
+        exact_keys.insert(walker.generate_key());
+        let specialized_ty = self.vm.schema.types.get(&**x).unwrap();
+        for (name, (field_ty, _)) in &specialized_ty.fields {
+          self.collect_fsck_targets(
+            format!("{}.{}", path, name),
+            field_ty,
+            walker.enter_field(name)?,
+            sets,
+            exact_keys,
+            prefixes,
+          )?;
+        }
+      }
+      _ => {
+        prefixes.insert(walker.generate_key());
+        if let FieldType::Set(member_ty) = ty {
+          if let FieldType::Table(x) = &**member_ty {
+            sets.push(FsckSet {
+              path,
+              walker,
+              member_ty: &**x,
+            });
+          }
+        }
+      }
+    }
+    Ok(())
+  }
+
+  /// Runs `pass` on the first `batch_size` keys of a set from `range_start`. Returns the last
+  /// scanned key, or `None` if there were no more than `batch_size` keys left.
+  async fn fsck_set_batch(
+    &self,
+    set: &FsckSet<'a>,
+    pass: FsckPass,
+    range_start: &[u8],
+    batch_size: usize,
+    repair: bool,
+    report: &mut FsckReport,
+  ) -> Result<Option<Vec<u8>>> {
+    let range_prefix = fsck_range_prefix(&set.walker, pass);
+    let range_end = prefix_end(&range_prefix);
+    for i in 0..self.retry_policy.max_attempts {
+      let txn = self.kv.begin_transaction().await?;
+
+      let mut keys = vec![];
+      let mut it = txn.scan_keys(range_start, &range_end).await?;
+      while let Some(k) = it.next().await? {
+        keys.push(k);
+        if keys.len() == batch_size {
+          break;
+        }
+      }
+      drop(it);
+
+      let mut issues = vec![];
+      // Member data is scanned in key order, so consecutive keys mostly belong to one member.
+      let mut last_member_prefix: Option<Vec<u8>> = None;
+      for k in &keys {
+        match pass {
+          FsckPass::Members => {
+            let primary_key_value = &k[range_prefix.len()..];
+            self
+              .fsck_member(&*txn, set, primary_key_value, repair, &mut issues)
+              .await?;
+          }
+          FsckPass::MemberData => {
+            if let Some(x) = &last_member_prefix {
+              if k.starts_with(x) {
+                continue;
+              }
+            }
+            last_member_prefix = self
+              .fsck_member_data(&*txn, set, k, repair, &mut issues)
+              .await?;
+          }
+          FsckPass::Index => {
+            self
+              .fsck_index_entry(&*txn, set, k, repair, &mut issues)
+              .await?;
+          }
+        }
+      }
+
+      match txn.commit().await {
+        Ok(()) => {
+          report.issues.extend(issues);
+          if let FsckPass::Members = pass {
+            report.num_members += keys.len() as u64;
+          }
+          let last_key = if keys.len() == batch_size {
+            keys.pop()
+          } else {
+            None
+          };
+          return Ok(last_key);
+        }
+        Err(KvError::Conflict) => self.wait_after_conflict(i).await,
+        Err(x) => return Err(x.into()),
+      }
+    }
+    Err(ExecError::ConflictAfterRetries.into())
+  }
+
+  /// Checks the member `primary_key_value` of a set, found by its fast scan entry.
+  async fn fsck_member(
+    &self,
+    txn: &dyn KvTransaction,
+    set: &FsckSet<'a>,
+    primary_key_value: &[u8],
+    repair: bool,
+    issues: &mut Vec<FsckIssue>,
+  ) -> Result<()> {
+    let mut fast_scan_key = set.walker.set_fast_scan_prefix()?;
+    fast_scan_key.extend_from_slice(primary_key_value);
+
+    let mut data_start_key = set.walker.set_data_prefix()?;
+    data_start_key.extend_from_slice(primary_key_value);
+    data_start_key.push(0x00);
+    let mut data_end_key = data_start_key.clone();
+    *data_end_key.last_mut().unwrap() = 0x01;
+    let mut it = txn.scan_keys(&data_start_key, &data_end_key).await?;
+    let has_data = it.next().await?.is_some();
+    drop(it);
+    if !has_data {
+      if repair {
+        txn.delete(&fast_scan_key).await?;
+      }
+      issues.push(fsck_issue(
+        FsckIssueKind::MissingMemberData,
+        set,
+        fast_scan_key,
+        repair,
+      ));
+      return Ok(());
+    }
+
+    let member = Arc::new(VmValue::Table(VmTableValue {
+      ty: set.member_ty,
+      kind: VmTableValueKind::Resident(set.walker.enter_set_raw(primary_key_value)?),
+    }));
+    if let Err(e) = self.load_resident(txn, member.clone()).await {
+      if !is_decode_error(&e) {
+        return Err(e);
+      }
+      issues.push(FsckIssue {
+        detail: format!("{:#}", e),
+        ..fsck_issue(FsckIssueKind::UndecodableMember, set, fast_scan_key, false)
+      });
+      return Ok(());
+    }
+
+    let fields = self
+      .read_indexed_fields(txn, member.unwrap_table(), None)
+      .await?;
+    for (_, key) in generate_index_keys(&set.walker, primary_key_value, &fields) {
+      if txn.get(&key).await?.as_deref() != Some(primary_key_value) {
+        if repair {
+          txn.put(&key, primary_key_value).await?;
+        }
+        issues.push(fsck_issue(
+          FsckIssueKind::MissingIndexEntry,
+          set,
+          key,
+          repair,
+        ));
+      }
+    }
+    Ok(())
+  }
+
+  /// Checks that the member data at `key` belongs to a member of the set. Returns the prefix of
+  /// the data of that member.
+  async fn fsck_member_data(
+    &self,
+    txn: &dyn KvTransaction,
+    set: &FsckSet<'a>,
+    key: &[u8],
+    repair: bool,
+    issues: &mut Vec<FsckIssue>,
+  ) -> Result<Option<Vec<u8>>> {
+    let data_prefix = set.walker.set_data_prefix()?;
+    let rest = &key[data_prefix.len()..];
+
+    // Member data is at `primary_key + 0x00 + ...`. String primary keys may contain zeros, so
+    // try every split.
+    for (i, _) in rest.iter().enumerate().filter(|(_, x)| **x == 0x00) {
+      let primary_key_value = &rest[..i];
+      if PrimitiveValue::deserialize_from_key_component(primary_key_value).is_none() {
+        continue;
+      }
+      let mut fast_scan_key = set.walker.set_fast_scan_prefix()?;
+      fast_scan_key.extend_from_slice(primary_key_value);
+      if txn.get(&fast_scan_key).await?.is_some() {
+        return Ok(Some(key[..data_prefix.len() + i + 1].to_vec()));
+      }
+    }
+
+    if repair {
+      txn.delete(key).await?;
+    }
+    issues.push(fsck_issue(
+      FsckIssueKind::OrphanMemberData,
+      set,
+      key.to_vec(),
+      repair,
+    ));
+    Ok(None)
+  }
+
+  /// Checks that the index entry at `key` is one of the entries of the member it points to.
+  async fn fsck_index_entry(
+    &self,
+    txn: &dyn KvTransaction,
+    set: &FsckSet<'a>,
+    key: &[u8],
+    repair: bool,
+    issues: &mut Vec<FsckIssue>,
+  ) -> Result<()> {
+    let primary_key_value = txn.get(key).await?.unwrap_or_default();
+    let mut fast_scan_key = set.walker.set_fast_scan_prefix()?;
+    fast_scan_key.extend_from_slice(&primary_key_value);
+
+    let valid = if key.ends_with(&primary_key_value) && txn.get(&fast_scan_key).await?.is_some() {
+      let member = VmTableValue {
+        ty: set.member_ty,
+        kind: VmTableValueKind::Resident(set.walker.enter_set_raw(&primary_key_value)?),
+      };
+      match self.read_indexed_fields(txn, &member, None).await {
+        Ok(fields) => generate_index_keys(&set.walker, &primary_key_value, &fields)
+          .iter()
+          .any(|(_, x)| x == key),
+        // Already reported with the member.
+        Err(e) if is_decode_error(&e) => true,
+        Err(e) => return Err(e),
+      }
+    } else {
+      false
+    };
+
+    if !valid {
+      if repair {
+        txn.delete(key).await?;
+      }
+      issues.push(fsck_issue(
+        FsckIssueKind::DanglingIndexEntry,
+        set,
+        key.to_vec(),
+        repair,
+      ));
+    }
+    Ok(())
+  }
+
+  /// Scans up to `batch_size` keys from `range_start` for keys outside of every export, skipping
+  /// over the prefixes of exported fields, and deletes them if `delete` is set. Returns where to
+  /// continue, or `None` if the scan is done.
+  async fn fsck_orphan_batch(
+    &self,
+    range_start: &[u8],
+    exact_keys: &BTreeSet<Vec<u8>>,
+    prefixes: &BTreeSet<Vec<u8>>,
+    batch_size: usize,
+    delete: bool,
+    issues: &mut Vec<FsckIssue>,
+  ) -> Result<Option<Vec<u8>>> {
+    // No key of the namespace itself starts with the mount marker. See `mount`.
+    let range_end = [MOUNT_KEY_MARKER];
+    for i in 0..self.retry_policy.max_attempts {
+      let txn = self.kv.begin_transaction().await?;
+
+      let mut orphans = vec![];
+      let mut start = range_start.to_vec();
+      let mut num_scanned = 0usize;
+      let next = 'scan: loop {
+        let mut it = txn.scan_keys(&start, &range_end).await?;
+        while let Some(k) = it.next().await? {
+          num_scanned += 1;
+          // Prefixes don't nest, so only the greatest one not after `k` can match.
+          let covering_prefix = prefixes
+            .range::<[u8], _>((Bound::Unbounded, Bound::Included(&k[..])))
+            .next_back()
+            .filter(|x| k.starts_with(x));
+          start = match covering_prefix {
+            Some(x) => prefix_end(x),
+            None => {
+              if !exact_keys.contains(&k) {
+                orphans.push(k.clone());
+              }
+              let mut x = k;
+              x.push(0x00);
+              x
+            }
+          };
+          if num_scanned == batch_size {
+            break 'scan Some(start);
+          }
+          if covering_prefix.is_some() {
+            continue 'scan;
+          }
+        }
+        break None;
+      };
+
+      if delete {
+        for k in &orphans {
+          txn.delete(k).await?;
+        }
+      }
+      match txn.commit().await {
+        Ok(()) => {
+          issues.extend(orphans.into_iter().map(|key| FsckIssue {
+            kind: FsckIssueKind::OrphanKey,
+            path: String::new(),
+            key,
+            detail: String::new(),
+            repaired: delete,
+          }));
+          return Ok(next);
+        }
+        Err(KvError::Conflict) => self.wait_after_conflict(i).await,
+        Err(x) => return Err(x.into()),
+      }
+    }
+    Err(ExecError::ConflictAfterRetries.into())
+  }
+
+  /// Resolves a dot-separated path from an export to a field. Returns `None` if the path does
+  /// not exist in the schema.
+  fn resolve_path(&self, path: &str) -> Result<Option<(&'a FieldType, Arc<PathWalker<'a>>)>> {
+    let mut segments = path.split('.');
+    let export_name = segments.next().unwrap();
+    let mut ty = match self.vm.schema.exports.get(export_name) {
+      Some(x) => x,
+      None => return Ok(None),
+    };
+    let mut walker = PathWalker::from_export(self.vm.storage_plan, export_name)?;
+    for segment in segments {
+      let table_ty = match ty {
+        FieldType::Table(x) => self.vm.schema.types.get(x).unwrap(),
+        _ => return Ok(None),
+      };
+      ty = match table_ty.fields.get(segment) {
+        Some(x) => &x.0,
+        None => return Ok(None),
+      };
+      walker = walker.enter_field(segment)?;
+    }
+    Ok(Some((ty, walker)))
+  }
+
+  /// Resolves a dot-separated path from an export to a set.
+  fn resolve_set_path(&self, set_path: &str) -> Result<VmSetValue<'a>> {
+    let (ty, walker) = self
+      .resolve_path(set_path)?
+      .ok_or_else(|| ExecError::NotSetOfTables(set_path.to_string()))?;
+    match ty {
+      FieldType::Set(x) if matches!(&**x, FieldType::Table(_)) => Ok(VmSetValue {
+        member_ty: VmType::from(&**x),
+        kind: VmSetValueKind::Resident(walker),
+      }),
+      _ => Err(ExecError::NotSetOfTables(set_path.to_string()).into()),
+    }
+  }
+
+  /// Reads up to `limit` members of the set at `path` in primary key order, starting after the
+  /// member with the primary key `after`, or the table at `path` as a single row.
+  ///
+  /// `path` is resolved as in `bulk_delete`. Tables are encoded as maps and sets as lists of
+  /// their members.
+  pub async fn export_rows(
+    &self,
+    path: &str,
+    after: Option<&SerializedVmValue>,
+    limit: usize,
+    config: &VmValueEncodeConfig,
+  ) -> Result<ExportPage> {
+    let (ty, walker) = self
+      .resolve_path(path)?
+      .ok_or_else(|| ExecError::NotSetOrTable(path.to_string()))?;
+    let txn = self.kv.begin_read_only_transaction().await?;
+    match ty {
+      FieldType::Table(x) => {
+        let rows = if after.is_none() {
+          let table = Arc::new(VmValue::Table(VmTableValue {
+            ty: &**x,
+            kind: VmTableValueKind::Resident(walker),
+          }));
+          vec![encode_loaded(
+            &*self.load_resident(&*txn, table).await?,
+            config,
+          )?]
+        } else {
+          vec![]
+        };
+        Ok(ExportPage { rows, next: None })
+      }
+      FieldType::Set(member_ty) => {
+        let member_ty = match &**member_ty {
+          FieldType::Table(x) => &**x,
+          _ => return Err(ExecError::NotSetOrTable(path.to_string()).into()),
+        };
+        let set_ty = VmType::<&'a str>::from(ty);
+        let primary_key_ty = set_ty
+          .set_primary_key(self.vm.schema)
+          .expect("inconsistency: primary key not found for set member")
+          .value_type();
+
+        let after = after
+          .map(|x| decode_primary_key(x, &primary_key_ty))
+          .transpose()?
+          .map(|x| x.serialize_for_key_component());
+        let members = self
+          .read_set_members(&*txn, &walker, member_ty, after.as_deref(), limit)
+          .await?;
+        let mut rows = Vec::with_capacity(members.len());
+        for (_, member) in &members {
+          rows.push(encode_loaded(member, config)?);
+        }
+        let next = match members.last() {
+          Some((x, _)) if members.len() == limit => {
+            let primary_key = PrimitiveValue::deserialize_from_key_component(x)
+              .expect("inconsistency: bad set member key");
+            Some(SerializedVmValue::encode(
+              &VmValue::Primitive(primary_key),
+              config,
+            )?)
+          }
+          _ => None,
+        };
+        Ok(ExportPage { rows, next })
+      }
+      FieldType::Primitive(_) | FieldType::List(_) | FieldType::Map(..) => {
+        Err(ExecError::NotSetOrTable(path.to_string()).into())
+      }
+    }
+  }
+
+  /// Reads up to `limit` members of the set of a cursor in primary key order, starting after the
+  /// member that the token `after` was returned for. Members are encoded as maps.
+  pub async fn read_cursor_page(
+    &self,
+    cursor: &SetCursor<'a>,
+    after: Option<&str>,
+    limit: usize,
+    config: &VmValueEncodeConfig,
+    reader: CursorReader<'_>,
+  ) -> Result<CursorPage> {
+    let after = after.map(|x| cursor.decode_token(x)).transpose()?;
+    let txn = self.kv.begin_read_only_transaction().await?;
+    let members = self
+      .read_set_members(
+        &*txn,
+        &cursor.walker,
+        cursor.member_ty,
+        after.as_deref(),
+        limit,
+      )
+      .await?;
+    let mut encoded = Vec::with_capacity(members.len());
+    for (_, member) in &members {
+      encoded.push(match reader {
+        CursorReader::Trusted => encode_loaded(member, config)?,
+        CursorReader::Role(role) => self.encode_loaded_for_role(member, config, role)?,
+      });
+    }
+    let next = match members.last() {
+      Some((x, _)) if members.len() == limit => Some(cursor.encode_token(x)?),
+      _ => None,
+    };
+    Ok(CursorPage {
+      members: encoded,
+      next,
+    })
+  }
+
+  /// Loads up to `limit` members of the set at `walker` in primary key order, starting after the
+  /// member with the primary key `after`, in its key component form. Returns each member with
+  /// its primary key.
+  async fn read_set_members(
+    &self,
+    txn: &dyn KvTransaction,
+    walker: &Arc<PathWalker<'a>>,
+    member_ty: &'a str,
+    after: Option<&[u8]>,
+    limit: usize,
+  ) -> Result<Vec<(Vec<u8>, Arc<VmValue<'a>>)>> {
+    let prefix = walker.set_fast_scan_prefix()?;
+    let mut start = prefix.clone();
+    if let Some(after) = after {
+      start.extend_from_slice(after);
+      start.push(0x00);
+    }
+    let mut end = prefix.clone();
+    *end.last_mut().unwrap() += 1;
+
+    let options = ScanOptions {
+      limit: Some(limit),
+      ..Default::default()
+    };
+    let mut primary_keys = vec![];
+    let mut it = txn.scan(&start, &end, options).await?;
+    while let Some((k, _)) = it.next().await? {
+      primary_keys.push(k[prefix.len()..].to_vec());
+    }
+    drop(it);
+
+    let mut members = Vec::with_capacity(primary_keys.len());
+    for primary_key_value in primary_keys {
+      let member = Arc::new(VmValue::Table(VmTableValue {
+        ty: member_ty,
+        kind: VmTableValueKind::Resident(walker.enter_set_raw(&primary_key_value)?),
+      }));
+      let member = self.load_resident(txn, member).await?;
+      members.push((primary_key_value, member));
+    }
+    Ok(members)
+  }
+
+  /// Like `encode_loaded`, but leaves out the fields of tables that a caller with `role` may not
+  /// see according to their `@acl` annotations.
+  fn encode_loaded_for_role(
+    &self,
+    v: &VmValue<'a>,
+    config: &VmValueEncodeConfig,
+    role: Option<&str>,
+  ) -> Result<SerializedVmValue> {
+    match v {
+      VmValue::Table(VmTableValue {
+        ty,
+        kind: VmTableValueKind::Fresh(fields),
+      }) => Ok(SerializedVmValue::Tagged(TaggedVmValue::M(
+        fields
+          .iter()
+          .filter(|(k, _)| table_field_visible(self.vm, ty, k, role))
+          .map(|(k, v)| {
+            self
+              .encode_loaded_for_role(v, config, role)
+              .map(|x| (k.to_string(), x))
+          })
+          .collect::<Result<_>>()?,
+      ))),
+      VmValue::Set(VmSetValue {
+        kind: VmSetValueKind::Fresh(members),
+        ..
+      }) => Ok(SerializedVmValue::Tagged(TaggedVmValue::L(
+        members
+          .values()
+          .map(|x| self.encode_loaded_for_role(x, config, role))
+          .collect::<Result<_>>()?,
+      ))),
+      _ => SerializedVmValue::encode(v, config),
+    }
+  }
+
+  /// Writes rows in the format of `export_rows` into the set or table at `path`. Set members
+  /// replace the existing members with the same primary key.
+  ///
+  /// All rows are checked against the schema before anything is written. Rows are then written
+  /// `chunk_size` at a time, each chunk in its own transaction, so a failed chunk does not roll
+  /// back the chunks before it.
+  pub async fn import_rows(
+    &self,
+    path: &str,
+    rows: &[SerializedVmValue],
+    chunk_size: usize,
+  ) -> Result<()> {
+    let (ty, walker) = self
+      .resolve_path(path)?
+      .ok_or_else(|| ExecError::NotSetOrTable(path.to_string()))?;
+    let row_ty = match ty {
+      FieldType::Table(_) => ty,
+      FieldType::Set(x) if matches!(&**x, FieldType::Table(_)) => &**x,
+      _ => return Err(ExecError::NotSetOrTable(path.to_string()).into()),
+    };
+    let primary_key = VmType::<&'a str>::from(ty).set_primary_key(self.vm.schema);
+
+    let values = rows
+      .iter()
+      .enumerate()
+      .map(|(i, row)| {
+        let value = decode_stored(self.vm.schema, row, row_ty)
+          .and_then(|x| match &*x {
+            VmValue::Null(_) => Err(SerializeError::TypeMismatch.into()),
+            _ => Ok(x),
+          })
+          .and_then(|x| match &primary_key {
+            Some(primary_key) if primary_key.value_of_fresh(x.unwrap_table()).is_none() => {
+              Err(ExecError::NullPrimaryKey.into())
+            }
+            _ => Ok(x),
+          });
+        value.map_err(|e| ExecError::InvalidRow(i, e.to_string()).into())
+      })
+      .collect::<Result<Vec<_>>>()?;
+
+    for chunk in values.chunks(chunk_size.max(1)) {
+      self
+        .import_chunk(&walker, ty, primary_key.as_ref(), chunk)
+        .await?;
+    }
+    Ok(())
+  }
+
+  async fn import_chunk(
+    &self,
+    walker: &Arc<PathWalker<'a>>,
+    ty: &'a FieldType,
+    primary_key: Option<&PrimaryKey<'a>>,
+    values: &[Arc<VmValue<'a>>],
+  ) -> Result<()> {
+    for i in 0..self.retry_policy.max_attempts {
+      let txn = self.kv.begin_transaction().await?;
+      for value in values {
+        match (ty, primary_key) {
+          (FieldType::Set(_), Some(primary_key)) => {
+            let member_ty = value.unwrap_table().ty;
+            self
+              .insert_into_set(&*txn, walker, member_ty, primary_key, value.clone())
+              .await?;
+          }
+          _ => {
+            self
+              .walk_and_insert(&*txn, walker.clone(), value.clone())
+              .await?
+          }
+        }
+      }
+
+      match txn.commit().await {
+        Ok(()) => return Ok(()),
+        Err(KvError::Conflict) => self.wait_after_conflict(i).await,
+        Err(x) => return Err(x.into()),
+      }
+    }
+    Err(ExecError::ConflictAfterRetries.into())
+  }
+
+  async fn delete_chunk_from_set(
+    &self,
+    walker: &Arc<PathWalker<'a>>,
+    member_ty: &'a str,
+    primary_keys: &[PrimitiveValue],
+  ) -> Result<Vec<BulkDeleteOutcome>> {
+    for i in 0..self.retry_policy.max_attempts {
+      let txn = self.kv.begin_transaction().await?;
+      let mut outcomes = Vec::with_capacity(primary_keys.len());
+      for primary_key_value in primary_keys {
+        let mut fast_scan_key = walker.set_fast_scan_prefix().unwrap();
+        fast_scan_key.extend_from_slice(&primary_key_value.serialize_for_key_component());
+        if txn.get(&fast_scan_key).await?.is_none() {
+          outcomes.push(BulkDeleteOutcome::NotFound);
+          continue;
+        }
+        self
+          .delete_entry_from_set(
+            &*txn,
+            walker,
+            member_ty,
+            &primary_key_value.serialize_for_key_component(),
+          )
+          .await?;
+        outcomes.push(BulkDeleteOutcome::Deleted);
+      }
+
+      match txn.commit().await {
+        Ok(()) => return Ok(outcomes),
+        Err(KvError::Conflict) => self.wait_after_conflict(i).await,
+        Err(x) => return Err(x.into()),
+      }
+    }
+    Err(ExecError::ConflictAfterRetries.into())
+  }
+
+  async fn wait_after_conflict(&self, attempt: usize) {
+    if let Some(f) = self.sleep_fn {
+      let policy = &self.retry_policy;
+      let bound = policy
+        .base_delay
+        .checked_mul(1u32 << attempt.min(16))
+        .unwrap_or(policy.max_delay)
+        .min(policy.max_delay);
+      let delay = rand::thread_rng().gen_range(Duration::ZERO..=bound);
+      log::warn!(
+        "Conflict detected in transaction (attempt {}). Waiting for {} ms.",
+        attempt,
+        delay.as_millis()
+      );
+      f(delay).await;
+    } else {
+      log::warn!("Conflict detected in transaction (attempt {}).", attempt);
+    }
+  }
+
+  #[async_recursion]
+  async fn recursively_run_graph(
+    &self,
+    graph_index: usize,
+    graph_params: &[Arc<VmValue<'a>>],
+    recursion_depth: usize,
+    txn: &dyn KvTransaction,
+  ) -> Result<Option<Arc<VmValue<'a>>>> {
+    if self.config.scheduler == Scheduler::Topological {
+      return self
+        .run_graph_sequentially(graph_index, graph_params, recursion_depth, txn)
+        .await;
+    }
+
+    self.limits.enter_graph(recursion_depth)?;
+
+    if let Some(f) = self.yield_fn {
+      f().await;
+    }
+
+    let recursion_depth = recursion_depth + 1;
+    let invocation = self
+      .trace
+      .as_ref()
+      .map(|x| x.next_invocation())
+      .unwrap_or(0);
+    let g = &self.vm.script.graphs[graph_index];
+    let type_info = &self.type_info.graphs[graph_index];
+    let fire_rules = &self.fire_rule_tables[graph_index];
+    let mut deps_satisfied: SmallVec<[SmallVec<[Option<Arc<VmValue<'a>>>; 3]>; 16]> = g
+      .nodes
+      .iter()
+      .map(|(_, x, _)| smallvec![None; x.len()])
+      .collect();
+    let mut precondition_satisfied: SmallVec<[bool; 16]> =
+      g.nodes.iter().map(|(_, _, x)| x.is_none()).collect();
+    let mut select_fired: SmallVec<[bool; 16]> = smallvec![false; g.nodes.len()];
+
+    // Nodes that are ready to run. At most `concurrency` of them are moved to `futures` and
+    // polled at a time.
+    let mut ready: VecDeque<
+      Pin<Box<dyn Future<Output = (u32, Result<Option<Arc<VmValue<'a>>>>)> + Send>>,
+    > = VecDeque::new();
+    // Deferred nodes that are ready to run, held back until nothing else is running or ready.
+    let mut deferred = VecDeque::new();
+    let mut futures = vec![];
+    let concurrency = self.concurrency();
+
+    // The initial batch
+    for (i, (_, in_edges, precondition)) in g.nodes.iter().enumerate() {
+      if in_edges.is_empty() && precondition.is_none() {
+        let txn = &*txn;
+        let queue = if g.is_deferred(i) {
+          &mut deferred
+        } else {
+          &mut ready
+        };
+        queue.push_back(Box::pin(async move {
+          (
+            i as u32,
+            self
+              .run_node_traced(
+                (graph_index, i as u32, invocation),
+                vec![],
+                txn,
+                graph_params,
+                recursion_depth,
+              )
+              .await,
+          )
+        }));
+      }
+    }
+
+    let mut ret: Option<Arc<VmValue<'a>>> = None;
+
+    loop {
+      while futures.len() < concurrency {
+        match ready.pop_front() {
+          Some(x) => futures.push(x),
+          None => break,
+        }
+      }
+      if futures.is_empty() {
+        if deferred.is_empty() {
+          break;
+        }
+        // All other nodes have completed, and only deferred nodes depend on deferred ones.
+        ready.append(&mut deferred);
+        continue;
+      }
+      let ((node_index, result), _, remaining) = futures::future::select_all(futures).await;
+      let result = result?;
+      futures = remaining;
+
+      if Some(node_index) == g.output {
+        ret = result.clone();
+      }
+
+      let to_fire = fire_rules[node_index as usize].as_slice();
+      for item in to_fire {
+        match &item.kind {
+          FireRuleKind::ParamDep(param_position) => {
+            let result = result.as_ref().ok_or_else(|| {
+              ExecError::Inconsistency(format!(
+                "node {} is a parameter dependency of some other nodes but does not produce a value",
+                node_index
+              ))
+            })?;
+
+            deps_satisfied[item.target_node as usize][*param_position as usize] =
+              Some(result.clone());
+          }
+          FireRuleKind::Precondition => {
+            precondition_satisfied[item.target_node as usize] = match result.as_ref().map(|x| &**x)
+            {
+              Some(VmValue::Bool(x)) => *x,
+              Some(VmValue::Null(_)) => false,
+              None => true,
+              _ => {
+                return Err(
+                  ExecError::Inconsistency(format!("invalid precondition: {:?}", result)).into(),
+                )
+              }
+            };
+          }
+        }
+      }
+
+      // Do this in another iteration in case that a single source node is connect to a single target node's
+      // multiple parameters.
+      for (i, item) in to_fire.iter().enumerate() {
+        // ... and visit each such target node only once.
+        if to_fire[..i]
+          .iter()
+          .any(|x| x.target_node == item.target_node)
+        {
+          continue;
+        }
+        let target_node = item.target_node as usize;
+        let node_info = &g.nodes[target_node].0;
+        let queue = if g.is_deferred(target_node) {
+          &mut deferred
+        } else {
+          &mut ready
+        };
+
+        // If all deps and the precondition are satisfied...
+        if precondition_satisfied[item.target_node as usize] {
+          if node_info.is_select() {
+            if select_fired[target_node] {
+              // Fire only once! Later candidates are conflicts.
+              self.select_conflict(graph_index, target_node)?;
+              continue;
+            }
+            let in_edges = &g.nodes[target_node].1;
+            let candidates = in_edges
+              .iter()
+              .zip(deps_satisfied[target_node].iter())
+              .filter_map(|(source, x)| Some((*source, x.as_ref()?)));
+            if let Some((source, x)) = candidates.clone().min_by_key(|(source, _)| *source) {
+              // Candidates that fired at the same time, i.e. before the precondition.
+              if candidates.clone().any(|(other, _)| other != source) {
+                self.select_conflict(graph_index, target_node)?;
+              }
+              let x = x.clone();
+              select_fired[target_node] = true;
+              self.trace_forward(graph_index, target_node, invocation, &x);
+              queue.push_back(Box::pin(async move { (target_node as u32, Ok(Some(x))) }))
+            }
+          } else if node_info.is_cond() {
+            let deps = &deps_satisfied[target_node];
+            if let Some(x) = cond_output(
+              deps[0].as_ref(),
+              deps[1].as_ref(),
+              deps[2].as_ref(),
+              type_info.nodes[target_node].as_ref().unwrap(),
+            ) {
+              // Fire only once! The condition does not fire again, so this never becomes
+              // ready again.
+              deps_satisfied[target_node] = smallvec![None; 3];
+
+              self.trace_forward(graph_index, target_node, invocation, &x);
+              queue.push_back(Box::pin(async move { (target_node as u32, Ok(Some(x))) }))
+            }
+          } else {
+            if deps_satisfied[item.target_node as usize]
+              .iter()
+              .find(|x| x.is_none())
               .is_none()
             {
               let params =
@@ -283,16 +1932,15 @@ impl<'a, 'b> Executor<'a, 'b> {
                   .map(|x| x.unwrap())
                   .collect::<Vec<_>>();
               let txn = &*txn;
-              futures.push(Box::pin(async move {
+              queue.push_back(Box::pin(async move {
                 (
                   target_node as u32,
                   self
-                    .run_node(
-                      node_info,
+                    .run_node_traced(
+                      (graph_index, target_node as u32, invocation),
                       params,
                       txn,
                       graph_params,
-                      type_info.nodes[target_node].as_ref(),
                       recursion_depth,
                     )
                     .await,
@@ -306,17 +1954,400 @@ impl<'a, 'b> Executor<'a, 'b> {
     Ok(ret)
   }
 
+  /// Runs a graph by evaluating its nodes one by one in index order, which typeck guarantees to be
+  /// a topological order, with deferred nodes moved to the end.
+  ///
+  /// Fires the same nodes as `recursively_run_graph`, without the per-node futures and fire
+  /// rules: a node runs once all of its parameters have fired and its precondition holds, so only
+  /// the taken branch of an `if` runs, and `Cond` only needs its chosen parameter. The outputs
+  /// are the same, except under a lenient `Select` whose candidates both fire, where the earlier
+  /// node is taken here and the one that finished first there.
+  ///
+  /// Independent nodes are not run concurrently, so this is only used for reducers without
+  /// effects, which are invoked once per member of a possibly very large list or set, and for all
+  /// graphs with `Scheduler::Topological`. `schedulers_agree` in the tests runs both on the same
+  /// graphs.
+  #[async_recursion]
+  async fn run_graph_sequentially(
+    &self,
+    graph_index: usize,
+    graph_params: &[Arc<VmValue<'a>>],
+    recursion_depth: usize,
+    txn: &dyn KvTransaction,
+  ) -> Result<Option<Arc<VmValue<'a>>>> {
+    self.limits.enter_graph(recursion_depth)?;
+
+    if let Some(f) = self.yield_fn {
+      f().await;
+    }
+
+    let recursion_depth = recursion_depth + 1;
+    let invocation = self
+      .trace
+      .as_ref()
+      .map(|x| x.next_invocation())
+      .unwrap_or(0);
+    let g = &self.vm.script.graphs[graph_index];
+    let type_info = &self.type_info.graphs[graph_index];
+
+    // `None` if the node did not fire, `Some(None)` if it fired without producing a value.
+    let mut outputs: SmallVec<[Option<Option<Arc<VmValue<'a>>>>; 16]> =
+      smallvec![None; g.nodes.len()];
+    // Deferred nodes run after all others. They only depend on earlier nodes, and no other node
+    // depends on them.
+    let order = (0..g.nodes.len())
+      .filter(|x| !g.is_deferred(*x))
+      .chain((0..g.nodes.len()).filter(|x| g.is_deferred(*x)));
+    for i in order {
+      let (node, in_edges, precondition) = &g.nodes[i];
+      if let Some(precondition) = precondition {
+        let satisfied = match &outputs[*precondition as usize] {
+          None => false,
+          Some(None) => true,
+          Some(Some(x)) => match &**x {
+            VmValue::Bool(x) => *x,
+            VmValue::Null(_) => false,
+            _ => {
+              return Err(ExecError::Inconsistency(format!("invalid precondition: {:?}", x)).into())
+            }
+          },
+        };
+        if !satisfied {
+          continue;
+        }
+      }
+
+      let output = if node.is_select() {
+        let candidates = in_edges
+          .iter()
+          .filter_map(|x| Some((*x, outputs[*x as usize].as_ref()?)));
+        match candidates.clone().min_by_key(|(source, _)| *source) {
+          Some((source, x)) => {
+            if candidates.clone().any(|(other, _)| other != source) {
+              self.select_conflict(graph_index, i)?;
+            }
+            if let Some(x) = x {
+              self.trace_forward(graph_index, i, invocation, x);
+            }
+            x.clone()
+          }
+          None => continue,
+        }
+      } else if node.is_cond() {
+        let param = |x: u32| outputs[x as usize].as_ref().and_then(|x| x.as_ref());
+        match cond_output(
+          param(in_edges[0]),
+          param(in_edges[1]),
+          param(in_edges[2]),
+          type_info.nodes[i].as_ref().unwrap(),
+        ) {
+          Some(x) => {
+            self.trace_forward(graph_index, i, invocation, &x);
+            Some(x)
+          }
+          None => continue,
+        }
+      } else {
+        let params = match in_edges
+          .iter()
+          .map(|x| outputs[*x as usize].as_ref())
+          .collect::<Option<Vec<_>>>()
+        {
+          Some(x) => x,
+          None => continue,
+        };
+        let params = params
+          .into_iter()
+          .map(|x| {
+            x.clone().ok_or_else(|| {
+              ExecError::Inconsistency(format!(
+                "node {} has a parameter dependency that does not produce a value",
+                i
+              ))
+            })
+          })
+          .collect::<Result<Vec<_>, _>>()?;
+        self
+          .run_node_traced(
+            (graph_index, i as u32, invocation),
+            params,
+            txn,
+            graph_params,
+            recursion_depth,
+          )
+          .await?
+      };
+      outputs[i] = Some(output);
+    }
+
+    Ok(g.output.and_then(|x| outputs[x as usize].clone().flatten()))
+  }
+
+  /// Runs the reducer of a `reduce` node on each member of a set, skipping expired members.
+  ///
+  /// If `relaxed`, the set is scanned without read conflicts, and only the members passed to the
+  /// reducer conflict with concurrent writes. Members inserted concurrently don't.
+  async fn reduce_set(
+    &self,
+    set: &VmSetValue<'a>,
+    subgraph_index: usize,
+    has_range: bool,
+    sequential: bool,
+    relaxed: bool,
+    params: &[Arc<VmValue<'a>>],
+    subgraph_params: &mut [Arc<VmValue<'a>>],
+    recursion_depth: usize,
+    txn: &dyn KvTransaction,
+  ) -> Result<()> {
+    let walker = match &set.kind {
+      VmSetValueKind::Resident(x) => x,
+      _ => return Err(ExecError::FreshTableOrSetNotSupported.into()),
+    };
+    let specialized_ty = match &set.member_ty {
+      VmType::Table(x) => self.vm.schema.types.get(x.name).unwrap(),
+      _ => unreachable!(),
+    };
+    let range_prefix = walker.set_fast_scan_prefix().unwrap();
+    let mut range_start = range_prefix.clone();
+    let mut range_end = range_start.clone();
+    *range_end.last_mut().unwrap() += 1;
+
+    // If we've got a range, update our scan ranges with it...
+    if has_range {
+      let maybe_start = &params[3];
+      let maybe_end = &params[4];
+
+      if !maybe_start.is_null() {
+        range_start
+          .extend_from_slice(&maybe_start.unwrap_primitive().serialize_for_key_component());
+      }
+
+      if !maybe_end.is_null() {
+        // Revert the "all entries" assumption
+        *range_end.last_mut().unwrap() -= 1;
+        range_end.extend_from_slice(&maybe_end.unwrap_primitive().serialize_for_key_component());
+      }
+    }
+
+    log::trace!(
+      "reduce set: scan keys: {} {}",
+      base64::encode(&range_start),
+      base64::encode(&range_end)
+    );
+
+    // Members are listed with a single range read of their fast scan keys, and the next one is
+    // fetched while the reducer runs on the current member.
+    let options = ScanOptions {
+      snapshot: relaxed,
+      ..Default::default()
+    };
+    let mut it = txn.scan(&range_start, &range_end, options).await?;
+    let mut next_key = it.next().await?.map(|x| x.0);
+    while let Some(k) = next_key {
+      if relaxed {
+        txn.add_read_conflict_key(&k).await?;
+      }
+      let k = k.strip_prefix(range_prefix.as_slice()).unwrap();
+      let walker = walker.enter_set_raw(k).unwrap();
+      let member = VmTableValue {
+        ty: &*specialized_ty.name,
+        kind: VmTableValueKind::Resident(walker),
+      };
+      if self.is_expired(txn, &member, self.now_millis).await? {
+        next_key = it.next().await?.map(|x| x.0);
+        continue;
+      }
+      subgraph_params[2] = Arc::new(VmValue::Table(member));
+      let (output, following_key) = futures::future::join(
+        self.run_reducer(
+          sequential,
+          subgraph_index,
+          subgraph_params,
+          recursion_depth,
+          txn,
+        ),
+        it.next(),
+      )
+      .await;
+      let output = output?.expect("inconsistency: ReduceList did not get an output from subgraph");
+      if output.is_null() {
+        break;
+      }
+      subgraph_params[1] = output;
+      next_key = following_key?.map(|x| x.0);
+    }
+    Ok(())
+  }
+
+  /// Runs the subgraph of a `reduce` node for a single member.
+  async fn run_reducer(
+    &self,
+    sequential: bool,
+    graph_index: usize,
+    graph_params: &[Arc<VmValue<'a>>],
+    recursion_depth: usize,
+    txn: &dyn KvTransaction,
+  ) -> Result<Option<Arc<VmValue<'a>>>> {
+    if sequential {
+      self
+        .run_graph_sequentially(graph_index, graph_params, recursion_depth, txn)
+        .await
+    } else {
+      self
+        .recursively_run_graph(graph_index, graph_params, recursion_depth, txn)
+        .await
+    }
+  }
+
+  /// Stable bottom-up merge sort of `members`, with the comparator subgraph at `graph_index`.
+  async fn sort_members(
+    &self,
+    graph_index: usize,
+    mut members: Vec<Arc<VmValue<'a>>>,
+    recursion_depth: usize,
+    txn: &dyn KvTransaction,
+  ) -> Result<Vec<Arc<VmValue<'a>>>> {
+    let sequential = self.trace.is_none() && self.vm.is_graph_read_only(graph_index);
+    let mut width = 1;
+    while width < members.len() {
+      let mut merged = Vec::with_capacity(members.len());
+      for chunk in members.chunks(width * 2) {
+        let (left, right) = chunk.split_at(width.min(chunk.len()));
+        let (mut i, mut j) = (0, 0);
+        while i < left.len() && j < right.len() {
+          // Only take from the right if it strictly goes first, to keep the sort stable.
+          let params = [right[j].clone(), left[i].clone()];
+          let output = self
+            .run_reducer(sequential, graph_index, &params, recursion_depth, txn)
+            .await?;
+          let right_first = match output.as_deref() {
+            Some(VmValue::Bool(x)) => *x,
+            Some(VmValue::Primitive(PrimitiveValue::Int64(x))) => *x < 0,
+            _ => false,
+          };
+          if right_first {
+            merged.push(right[j].clone());
+            j += 1;
+          } else {
+            merged.push(left[i].clone());
+            i += 1;
+          }
+        }
+        merged.extend_from_slice(&left[i..]);
+        merged.extend_from_slice(&right[j..]);
+      }
+      members = merged;
+      width *= 2;
+    }
+    Ok(members)
+  }
+
+  /// Called when more than one candidate of the `Select` node `node_index` fires. Fails the run
+  /// with `ExecConfig::strict_select`, and otherwise keeps the candidate that fired first.
+  fn select_conflict(&self, graph_index: usize, node_index: usize) -> Result<()> {
+    if self.config.strict_select {
+      return Err(ExecError::BothSelectCandidatesFired.into());
+    }
+    log::warn!(
+      "graph `{}`, node {}: both select candidates fired, keeping the first one",
+      self.vm.script.graphs[graph_index].name,
+      node_index
+    );
+    Ok(())
+  }
+
+  /// Records a trace event for a `Select` or `Cond` node, which forwards `x` from one of its
+  /// parameters without running.
+  fn trace_forward(
+    &self,
+    graph_index: usize,
+    node_index: usize,
+    invocation: u64,
+    x: &Arc<VmValue<'a>>,
+  ) {
+    if let Some(trace) = &self.trace {
+      let pending = trace.begin(
+        graph_index as u32,
+        node_index as u32,
+        invocation,
+        std::slice::from_ref(x),
+      );
+      trace.end(
+        self.vm.script,
+        pending,
+        self.type_info.graphs[graph_index].nodes[node_index]
+          .as_ref()
+          .map(|x| x.to_string()),
+        &Ok(Some(x.clone())),
+      );
+    }
+  }
+
+  /// `run_node`, recording a trace event if tracing is enabled.
+  ///
+  /// `location` is (graph index, node index, invocation).
+  async fn run_node_traced(
+    &self,
+    location: (usize, u32, u64),
+    params: Vec<Arc<VmValue<'a>>>,
+    txn: &dyn KvTransaction,
+    graph_params: &[Arc<VmValue<'a>>],
+    recursion_depth: usize,
+  ) -> Result<Option<Arc<VmValue<'a>>>> {
+    let (graph_index, node_index, invocation) = location;
+    let trace = match &self.trace {
+      Some(x) => x,
+      None => {
+        return self
+          .run_node(
+            (graph_index, node_index),
+            params,
+            txn,
+            graph_params,
+            recursion_depth,
+          )
+          .await
+      }
+    };
+    let type_info = self.type_info.graphs[graph_index].nodes[node_index as usize].as_ref();
+    let pending = trace.begin(graph_index as u32, node_index, invocation, &params);
+    let traced_txn = pending.wrap_txn(txn);
+    let result = self
+      .run_node(
+        (graph_index, node_index),
+        params,
+        &traced_txn,
+        graph_params,
+        recursion_depth,
+      )
+      .await;
+    trace.end(
+      self.vm.script,
+      pending,
+      type_info.map(|x| x.to_string()),
+      &result,
+    );
+    result
+  }
+
+  /// `location` is (graph index, node index).
   async fn run_node(
     &self,
-    n: &TwGraphNode,
+    location: (usize, u32),
     params: Vec<Arc<VmValue<'a>>>,
     txn: &dyn KvTransaction,
     graph_params: &[Arc<VmValue<'a>>],
-    type_info: Option<&VmType<&'a str>>,
     recursion_depth: usize,
   ) -> Result<Option<Arc<VmValue<'a>>>> {
+    self.limits.check_deadline()?;
+
+    let (graph_index, node_index) = location;
+    let g = &self.vm.script.graphs[graph_index];
+    let n = &g.nodes[node_index as usize].0;
+    let type_info = self.type_info.graphs[graph_index].nodes[node_index as usize].as_ref();
+
     // Optional chain
-    if n.is_optional_chained() {
+    if g.is_optional_chained(node_index as usize) {
       for (i, p) in params.iter().enumerate() {
         if p.is_null() {
           log::trace!(
@@ -328,28 +2359,25 @@ impl<'a, 'b> Executor<'a, 'b> {
           return Ok(type_info.map(|x| Arc::new(VmValue::Null(x.clone()))));
         }
       }
+    } else if n.is_optional_chained() && params.iter().any(|x| x.is_null()) {
+      // Opted out of optional chaining, and can't take a null.
+      return Err(ExecError::NullUnwrapped.into());
     }
 
     Ok(match n {
       TwGraphNode::BuildSet => {
         let list = unwrap_enum!(&*params[0], VmValue::List(x) => x);
         let mut members = BTreeMap::new();
-        let (primary_key, _) = VmType::Set(VmSetType {
+        let primary_key = VmType::Set(VmSetType {
           ty: Box::new(list.member_ty.clone()),
         })
         .set_primary_key(self.vm.schema)
         .expect("inconsistency: primary key not found");
         for n in &list.node {
-          let primary_key_value = match &n.unwrap_table().kind {
-            VmTableValueKind::Fresh(x) => x
-              .get(primary_key)
-              .unwrap()
-              .unwrap_primitive()
-              .serialize_for_key_component(),
-            _ => {
-              return Err(ExecError::NotImplemented("table copy is not implemented".into()).into())
-            }
-          };
+          let primary_key_value = self
+            .read_primary_key(txn, n.unwrap_table(), &primary_key)
+            .await?
+            .serialize_for_key_component();
           members.insert(primary_key_value.to_vec(), n.clone());
         }
         let set = VmSetValue {
@@ -388,6 +2416,74 @@ impl<'a, 'b> Executor<'a, 'b> {
         elements.remove_mut(key.as_str());
         Some(Arc::new(VmValue::Map(VmMapValue { elements })))
       }
+      TwGraphNode::JsonParse => {
+        let x = params[0].unwrap_primitive().unwrap_string();
+        let doc = serde_json::from_str(x).map_err(|e| ExecError::InvalidJson(e.to_string()))?;
+        Some(Arc::new(VmValue::Json(doc)))
+      }
+      TwGraphNode::JsonSerialize => {
+        let doc = unwrap_enum!(&*params[0], VmValue::Json(x) => x);
+        Some(Arc::new(VmValue::Primitive(PrimitiveValue::String(
+          doc.to_string(),
+        ))))
+      }
+      TwGraphNode::JsonGet(_) => {
+        let path = params[0].unwrap_primitive().unwrap_string();
+        let doc = unwrap_enum!(&*params[1], VmValue::Json(x) => x);
+        let ty = type_info.unwrap();
+        let value = json::lookup(doc, path)
+          .map_err(ExecError::from)?
+          .and_then(|x| match ty {
+            VmType::Json => Some(VmValue::Json(x.clone())),
+            VmType::Bool => x.as_bool().map(VmValue::Bool),
+            VmType::Primitive(PrimitiveType::String) => x
+              .as_str()
+              .map(|x| VmValue::Primitive(PrimitiveValue::String(x.to_string()))),
+            VmType::Primitive(PrimitiveType::Int64) => x
+              .as_i64()
+              .map(|x| VmValue::Primitive(PrimitiveValue::Int64(x))),
+            VmType::Primitive(PrimitiveType::Double) => x
+              .as_f64()
+              .map(|x| VmValue::Primitive(PrimitiveValue::Double(x.to_bits()))),
+            _ => None,
+          });
+        Some(Arc::new(value.unwrap_or_else(|| VmValue::Null(ty.clone()))))
+      }
+      TwGraphNode::MapKeys => {
+        let map = unwrap_enum!(&*params[0], VmValue::Map(x) => &x.elements);
+        let mut node = ListSync::new_sync();
+        for k in map.keys().rev() {
+          node.push_front_mut(Arc::new(VmValue::Primitive(PrimitiveValue::String(
+            k.to_string(),
+          ))));
+        }
+        Some(Arc::new(VmValue::List(VmListValue {
+          member_ty: VmType::Primitive(PrimitiveType::String),
+          node,
+        })))
+      }
+      TwGraphNode::MapValues => {
+        let map = unwrap_enum!(&*params[0], VmValue::Map(x) => &x.elements);
+        let member_ty = unwrap_enum!(type_info.unwrap(), VmType::List(x) => &*x.ty);
+        let mut node = ListSync::new_sync();
+        for v in map.values().rev() {
+          node.push_front_mut(v.clone());
+        }
+        Some(Arc::new(VmValue::List(VmListValue {
+          member_ty: member_ty.clone(),
+          node,
+        })))
+      }
+      TwGraphNode::MapGetDynamic => {
+        let key = params[0].unwrap_primitive().unwrap_string();
+        let map = unwrap_enum!(&*params[1], VmValue::Map(x) => &x.elements);
+        Some(
+          map
+            .get(key.as_str())
+            .cloned()
+            .unwrap_or_else(|| Arc::new(VmValue::Null(type_info.unwrap().clone()))),
+        )
+      }
       TwGraphNode::GetField(key_index) => {
         let key = self.vm.script.idents.get(*key_index as usize).unwrap();
         match &*params[0] {
@@ -402,13 +2498,25 @@ impl<'a, 'b> Executor<'a, 'b> {
           _ => unreachable!(),
         }
       }
-      TwGraphNode::GetSetElement => {
-        let primary_key_value = unwrap_enum!(&*params[0], VmValue::Primitive(x) => x);
-        let set = unwrap_enum!(&*params[1], VmValue::Set(x) => x);
+      TwGraphNode::GetSetElement | TwGraphNode::GetSetElementByTuple => {
+        let (set_param, components) = params.split_last().unwrap();
+        let set = unwrap_enum!(&**set_param, VmValue::Set(x) => x);
         let member_ty = unwrap_enum!(&set.member_ty, VmType::Table(x) => x.name);
+        let primary_key_value = match n {
+          TwGraphNode::GetSetElementByTuple => VmType::<&'a str>::from(&**set_param)
+            .set_primary_key(self.vm.schema)
+            .expect("inconsistency: primary key not found for set member")
+            .value_of(
+              components
+                .iter()
+                .map(|x| x.unwrap_primitive().clone())
+                .collect(),
+            ),
+          _ => components[0].unwrap_primitive().clone(),
+        };
         match &set.kind {
           VmSetValueKind::Resident(walker) => {
-            let walker = walker.enter_set(primary_key_value).unwrap();
+            let walker = walker.enter_set(&primary_key_value).unwrap();
             Some(Arc::new(VmValue::Table(VmTableValue {
               ty: member_ty,
               kind: VmTableValueKind::Resident(walker),
@@ -417,6 +2525,54 @@ impl<'a, 'b> Executor<'a, 'b> {
           VmSetValueKind::Fresh(_) => return Err(ExecError::FreshTableOrSetNotSupported.into()),
         }
       }
+      TwGraphNode::GetSetElements => {
+        let primary_key_values = unwrap_enum!(&*params[0], VmValue::List(x) => x);
+        let set = unwrap_enum!(&*params[1], VmValue::Set(x) => x);
+        let member_ty = unwrap_enum!(&set.member_ty, VmType::Table(x) => x.name);
+        let walker = match &set.kind {
+          VmSetValueKind::Resident(x) => x,
+          VmSetValueKind::Fresh(_) => return Err(ExecError::FreshTableOrSetNotSupported.into()),
+        };
+
+        let members = self
+          .buffered(
+            primary_key_values
+              .node
+              .iter()
+              .map(|primary_key_value| async move {
+                let primary_key_value = match &**primary_key_value {
+                  VmValue::Primitive(x) => x,
+                  _ => return Ok(None),
+                };
+                let mut fast_scan_key = walker.set_fast_scan_prefix().unwrap();
+                fast_scan_key.extend_from_slice(&primary_key_value.serialize_for_key_component());
+                if txn.get(&fast_scan_key).await?.is_none() {
+                  return Ok(None);
+                }
+                let member = VmTableValue {
+                  ty: member_ty,
+                  kind: VmTableValueKind::Resident(walker.enter_set(primary_key_value).unwrap()),
+                };
+                if self.is_expired(txn, &member, self.now_millis).await? {
+                  return Ok(None);
+                }
+                Ok(Some(member))
+              }),
+          )
+          .await?;
+
+        let mut node = ListSync::new_sync();
+        for member in members.into_iter().rev() {
+          node.push_front_mut(Arc::new(match member {
+            Some(x) => VmValue::Table(x),
+            None => VmValue::Null(set.member_ty.clone()),
+          }));
+        }
+        Some(Arc::new(VmValue::List(VmListValue {
+          member_ty: set.member_ty.clone(),
+          node,
+        })))
+      }
       TwGraphNode::InsertIntoMap(key_index) => {
         let value = &params[0];
         let mut elements = match &*params[1] {
@@ -431,25 +2587,24 @@ impl<'a, 'b> Executor<'a, 'b> {
       TwGraphNode::InsertIntoSet => {
         // Effect node
         let value = params[0].clone();
-        let (primary_key, _) = VmType::<&'a str>::from(&*params[1])
+        let primary_key = VmType::<&'a str>::from(&*params[1])
           .set_primary_key(self.vm.schema)
           .expect("inconsistency: primary key not found for set member");
-        let primary_key_value = self
-          .read_table_element(txn, value.unwrap_table(), primary_key)
-          .await?;
         let set = params[1].unwrap_set();
-        let primary_key_value = primary_key_value
-          .unwrap_primitive()
-          .serialize_for_key_component();
+        let member_ty = unwrap_enum!(&set.member_ty, VmType::Table(x) => x.name);
 
         match &set.kind {
           VmSetValueKind::Resident(walker) => {
-            let mut fast_scan_key = walker.set_fast_scan_prefix().unwrap();
-            fast_scan_key.extend_from_slice(&primary_key_value);
-            txn.put(&fast_scan_key, &[]).await?;
-
-            let walker = walker.enter_set_raw(&primary_key_value).unwrap();
-            self.walk_and_insert(txn, walker, value).await?;
+            let (primary_key, replaced) = self
+              .insert_into_set(txn, walker, member_ty, &primary_key, value)
+              .await?;
+            self.record_change(walker, || {
+              if replaced {
+                ChangeKind::Update(primary_key.clone())
+              } else {
+                ChangeKind::Insert(primary_key.clone())
+              }
+            });
           }
           VmSetValueKind::Fresh(_) => {
             return Err(ExecError::FreshTableOrSetNotSupported.into());
@@ -465,8 +2620,48 @@ impl<'a, 'b> Executor<'a, 'b> {
         let table = params[1].unwrap_table();
         match &table.kind {
           VmTableValueKind::Resident(walker) => {
-            let walker = walker.enter_field(key.as_str()).unwrap();
-            self.walk_and_insert(txn, walker, value).await?;
+            // Updating a field of a set member may require index maintenance.
+            let enclosing_set = walker.enclosing_set().filter(|_| {
+              self
+                .vm
+                .schema
+                .types
+                .get(table.ty)
+                .unwrap()
+                .lookup_indexed_field(key.as_str())
+                .is_some()
+            });
+            if let Some((set_walker, primary_key_value)) = enclosing_set {
+              self
+                .remove_index_entries(
+                  txn,
+                  set_walker,
+                  table.ty,
+                  primary_key_value,
+                  Some(key.as_str()),
+                )
+                .await?;
+            }
+
+            let field_walker = walker.enter_field(key.as_str()).unwrap();
+            self
+              .walk_and_insert(txn, field_walker, value.clone())
+              .await?;
+
+            if let Some((set_walker, primary_key_value)) = enclosing_set {
+              self
+                .add_index_entries(
+                  txn,
+                  set_walker,
+                  table.ty,
+                  primary_key_value,
+                  &[(key.as_str(), value)],
+                )
+                .await?;
+            }
+
+            self.record_change(walker, || ChangeKind::SetField(key.clone()));
+            self.bump_version(txn, table).await?;
           }
           VmTableValueKind::Fresh(_) => {
             return Err(ExecError::FreshTableOrSetNotSupported.into());
@@ -474,87 +2669,159 @@ impl<'a, 'b> Executor<'a, 'b> {
         }
         None
       }
+      TwGraphNode::AtomicAdd(key_index) => {
+        // Effect node
+        let key = self.vm.script.idents.get(*key_index as usize).unwrap();
+        let delta = unwrap_enum!(&*params[0], VmValue::Primitive(PrimitiveValue::Int64(x)) => *x);
+        let walker = resident_table_walker(params[1].unwrap_table())?;
+        let field_walker = walker.enter_field(key.as_str()).unwrap();
+        self
+          .atomic_add(txn, &field_walker.generate_key(), delta)
+          .await?;
+        self.record_change(walker, || ChangeKind::SetField(key.clone()));
+        self.bump_version(txn, params[1].unwrap_table()).await?;
+        None
+      }
+      TwGraphNode::CheckVersion => {
+        let expected =
+          unwrap_enum!(&*params[0], VmValue::Primitive(PrimitiveValue::Int64(x)) => *x);
+        Box::pin(self.check_version(txn, params[1].unwrap_table(), expected)).await?;
+        Some(params[1].clone())
+      }
+      TwGraphNode::ListPush(_)
+      | TwGraphNode::ListPop(_)
+      | TwGraphNode::ListGet(_)
+      | TwGraphNode::ListLen(_)
+      | TwGraphNode::DictGet(_)
+      | TwGraphNode::DictPut(_)
+      | TwGraphNode::DictDelete(_)
+      | TwGraphNode::DictRange(_) => {
+        // Out of line and boxed, so that deeply nested calls don't overflow the stack in debug
+        // builds.
+        Box::pin(self.run_list_or_map_node(n, &params, txn)).await?
+      }
       TwGraphNode::LoadConst(const_index) => {
         let value = self.vm.consts[*const_index as usize].clone();
         Some(value)
       }
       TwGraphNode::LoadParam(param_index) => Some(graph_params[*param_index as usize].clone()),
+      TwGraphNode::LoadEnv => Some(self.env.clone()),
       TwGraphNode::DeleteFromSet => {
         let primary_key_value = unwrap_enum!(&*params[0], VmValue::Primitive(x) => x);
         let set = unwrap_enum!(&*params[1], VmValue::Set(x) => x);
+        let member_ty = unwrap_enum!(&set.member_ty, VmType::Table(x) => x.name);
         match &set.kind {
           VmSetValueKind::Resident(walker) => {
+            let raw_primary_key = primary_key_value.serialize_for_key_component();
+            let existed = if self.changes.is_some() {
+              let mut fast_scan_key = walker.set_fast_scan_prefix().unwrap();
+              fast_scan_key.extend_from_slice(&raw_primary_key);
+              txn.get(&fast_scan_key).await?.is_some()
+            } else {
+              false
+            };
             self
-              .delete_entry_from_set(txn, walker, primary_key_value)
+              .delete_entry_from_set(txn, walker, member_ty, &raw_primary_key)
               .await?;
+            if existed {
+              self.record_change(walker, || ChangeKind::Delete(primary_key_value.clone()));
+            }
             None
           }
           VmSetValueKind::Fresh(_) => return Err(ExecError::FreshTableOrSetNotSupported.into()),
         }
       }
-      TwGraphNode::Eq => Some(Arc::new(VmValue::Bool(params[0] == params[1]))),
-      TwGraphNode::Ne => Some(Arc::new(VmValue::Bool(params[0] != params[1]))),
-      TwGraphNode::And => Some(Arc::new(VmValue::Bool(
-        params[0].unwrap_bool() & params[1].unwrap_bool(),
-      ))),
-      TwGraphNode::Or => Some(Arc::new(VmValue::Bool(
-        params[0].unwrap_bool() | params[1].unwrap_bool(),
-      ))),
-      TwGraphNode::Not => Some(Arc::new(VmValue::Bool(!params[0].unwrap_bool()))),
+      TwGraphNode::Eq
+      | TwGraphNode::Ne
+      | TwGraphNode::Lt
+      | TwGraphNode::Le
+      | TwGraphNode::Gt
+      | TwGraphNode::Ge
+      | TwGraphNode::And
+      | TwGraphNode::Or
+      | TwGraphNode::Not
+      | TwGraphNode::IsNull
+      | TwGraphNode::Add
+      | TwGraphNode::Sub
+      | TwGraphNode::Mul
+      | TwGraphNode::Div
+      | TwGraphNode::Mod
+      | TwGraphNode::Neg
+      | TwGraphNode::ParseDouble
+      | TwGraphNode::FormatDouble
+      | TwGraphNode::StrStartsWith
+      | TwGraphNode::StrContains
+      | TwGraphNode::StrSubstring
+      | TwGraphNode::StrToLower
+      | TwGraphNode::StrLength
+      | TwGraphNode::BytesConcat
+      | TwGraphNode::BytesSlice
+      | TwGraphNode::BytesLength
+      | TwGraphNode::BytesToHex
+      | TwGraphNode::BytesFromHex
+      | TwGraphNode::BytesToBase64
+      | TwGraphNode::BytesFromBase64
+      | TwGraphNode::BytesToString
+      | TwGraphNode::BytesFromString
+      | TwGraphNode::TimeAddDays
+      | TwGraphNode::TimeStartOfDay
+      | TwGraphNode::TimeDayOfWeek => eval_pure_node(n, &params)?,
       TwGraphNode::IsPresent => {
-        let walker = match &*params[0] {
-          VmValue::Set(x) => match &x.kind {
-            VmSetValueKind::Fresh(_) => return Ok(Some(Arc::new(VmValue::Bool(true)))),
-            VmSetValueKind::Resident(x) => x,
-          },
-          VmValue::Table(x) => match &x.kind {
-            VmTableValueKind::Fresh(_) => return Ok(Some(Arc::new(VmValue::Bool(true)))),
-            VmTableValueKind::Resident(x) => x,
-          },
-          _ => unreachable!(),
-        };
-        Some(Arc::new(VmValue::Bool(
-          txn.get(&walker.generate_key()).await?.is_some(),
-        )))
+        // Boxed for the same reason as run_list_or_map_node.
+        let present = Box::pin(self.is_present(txn, &params[0])).await?;
+        Some(Arc::new(VmValue::Bool(present)))
       }
-      TwGraphNode::IsNull => Some(Arc::new(VmValue::Bool(params[0].is_null()))),
       TwGraphNode::Nop => Some(params[0].clone()),
+      TwGraphNode::UnwrapOptional => {
+        if params[0].is_null() {
+          return Err(ExecError::NullUnwrapped.into());
+        }
+        Some(params[0].clone())
+      }
       TwGraphNode::Call(subgraph_index) => {
         let output = self
           .recursively_run_graph(*subgraph_index as usize, &params, recursion_depth, txn)
           .await?;
         output
       }
-      TwGraphNode::Add => Some(Arc::new(match (&*params[0], &*params[1]) {
-        (
-          VmValue::Primitive(PrimitiveValue::Int64(l)),
-          VmValue::Primitive(PrimitiveValue::Int64(r)),
-        ) => VmValue::Primitive(PrimitiveValue::Int64(l.wrapping_add(*r))),
-        (
-          VmValue::Primitive(PrimitiveValue::Double(l)),
-          VmValue::Primitive(PrimitiveValue::Double(r)),
-        ) => VmValue::Primitive(PrimitiveValue::Double(
-          (f64::from_bits(*l) + f64::from_bits(*r)).to_bits(),
-        )),
-        (
-          VmValue::Primitive(PrimitiveValue::String(l)),
-          VmValue::Primitive(PrimitiveValue::String(r)),
-        ) => VmValue::Primitive(PrimitiveValue::String(format!("{}{}", l, r))),
-        _ => unreachable!(),
-      })),
-      TwGraphNode::Sub => Some(Arc::new(match (&*params[0], &*params[1]) {
-        (
-          VmValue::Primitive(PrimitiveValue::Int64(l)),
-          VmValue::Primitive(PrimitiveValue::Int64(r)),
-        ) => VmValue::Primitive(PrimitiveValue::Int64(l.wrapping_sub(*r))),
-        (
-          VmValue::Primitive(PrimitiveValue::Double(l)),
-          VmValue::Primitive(PrimitiveValue::Double(r)),
-        ) => VmValue::Primitive(PrimitiveValue::Double(
-          (f64::from_bits(*l) - f64::from_bits(*r)).to_bits(),
-        )),
-        _ => unreachable!(),
-      })),
+      TwGraphNode::Try(subgraph_index) => {
+        let ty = unwrap_enum!(type_info.unwrap(), VmType::Map(x) => x);
+        let string_ty = VmType::Primitive(PrimitiveType::String);
+        let (value, error) = match self
+          .recursively_run_graph(*subgraph_index as usize, &params, recursion_depth, txn)
+          .await
+        {
+          Ok(x) => (x, Arc::new(VmValue::Null(string_ty))),
+          Err(e) => match e.downcast_ref::<ExecError>() {
+            Some(ExecError::ScriptThrownError(msg)) => (
+              None,
+              Arc::new(VmValue::Primitive(PrimitiveValue::String(msg.clone()))),
+            ),
+            Some(x) if x.is_script_error() => (
+              None,
+              Arc::new(VmValue::Primitive(PrimitiveValue::String(x.to_string()))),
+            ),
+            _ => return Err(e),
+          },
+        };
+        let mut elements = RedBlackTreeMapSync::new_sync().insert("error", error);
+        if let Some(value_ty) = ty.get("value") {
+          elements = elements.insert(
+            "value",
+            value.unwrap_or_else(|| Arc::new(VmValue::Null(value_ty.clone()))),
+          );
+        }
+        Some(Arc::new(VmValue::Map(VmMapValue { elements })))
+      }
+      TwGraphNode::TimeNow => Some(Arc::new(VmValue::Primitive(PrimitiveValue::Int64(
+        self.now_millis,
+      )))),
+      TwGraphNode::GenId => {
+        let n = self.id_counter.fetch_add(1, AtomicOrdering::Relaxed);
+        Some(Arc::new(VmValue::Primitive(PrimitiveValue::String(
+          format_uuid(derive_id(&self.id_seed, n)),
+        ))))
+      }
       TwGraphNode::CreateList(member_ty) => {
         let member_ty = self.vm.types.get(*member_ty as usize).unwrap().clone();
         Some(Arc::new(VmValue::List(VmListValue {
@@ -597,7 +2864,30 @@ impl<'a, 'b> Executor<'a, 'b> {
           None => Arc::new(VmValue::Null(list.member_ty.clone())),
         })
       }
+      TwGraphNode::SortList(subgraph_index) => {
+        let list = match &*params[0] {
+          VmValue::List(x) => x,
+          _ => unreachable!(),
+        };
+        let members = self
+          .sort_members(
+            *subgraph_index as usize,
+            list.node.iter().cloned().collect(),
+            recursion_depth,
+            txn,
+          )
+          .await?;
+        let mut node = ListSync::new_sync();
+        for member in members.into_iter().rev() {
+          node.push_front_mut(member);
+        }
+        Some(Arc::new(VmValue::List(VmListValue {
+          member_ty: list.member_ty.clone(),
+          node,
+        })))
+      }
       TwGraphNode::Select => panic!("inconsistency: got select in run_node"),
+      TwGraphNode::Cond => panic!("inconsistency: got cond in run_node"),
       TwGraphNode::FilterSet(_) => {
         return Err(ExecError::NotImplemented(format!("{:?}", n)).into())
       }
@@ -618,12 +2908,18 @@ impl<'a, 'b> Executor<'a, 'b> {
           reduce_init.clone(),
           Arc::new(VmValue::Bool(false)), // placeholder
         ];
+
+        // Traced runs schedule reducers like any other graph, so that traces reflect the
+        // configured scheduler.
+        let sequential =
+          self.trace.is_none() && self.vm.is_graph_read_only(*subgraph_index as usize);
         match &**list_or_set {
           VmValue::List(list) => {
             for n in &list.node {
               subgraph_params[2] = n.clone();
               let output = self
-                .recursively_run_graph(
+                .run_reducer(
+                  sequential,
                   *subgraph_index as usize,
                   &subgraph_params,
                   recursion_depth,
@@ -637,70 +2933,191 @@ impl<'a, 'b> Executor<'a, 'b> {
               subgraph_params[1] = output;
             }
           }
-          VmValue::Set(set) => {
-            let walker = match &set.kind {
-              VmSetValueKind::Resident(x) => x,
-              _ => return Err(ExecError::FreshTableOrSetNotSupported.into()),
-            };
-            let specialized_ty = match &set.member_ty {
-              VmType::Table(x) => self.vm.schema.types.get(x.name).unwrap(),
-              _ => unreachable!(),
-            };
-            let range_prefix = walker.set_fast_scan_prefix().unwrap();
-            let mut range_start = range_prefix.clone();
-            let mut range_end = range_start.clone();
-            *range_end.last_mut().unwrap() += 1;
+          VmValue::Set(set) => {
+            // Out of line and boxed, as with run_list_or_map_node.
+            Box::pin(self.reduce_set(
+              set,
+              *subgraph_index as usize,
+              *has_range,
+              sequential,
+              g.relaxed_isolation,
+              &params,
+              &mut subgraph_params,
+              recursion_depth,
+              txn,
+            ))
+            .await?;
+          }
+          _ => unreachable!(),
+        }
+        Some(subgraph_params[1].clone())
+      }
+      TwGraphNode::Loop(subgraph_index) => {
+        // Only the range is optional-chained, as with `reduce`.
+        if params[2].is_null() || params[3].is_null() {
+          log::trace!("optional chaining a `loop` node because its range is null");
+          return Ok(type_info.map(|x| Arc::new(VmValue::Null(x.clone()))));
+        }
+        let start = unwrap_enum!(&*params[2], VmValue::Primitive(PrimitiveValue::Int64(x)) => *x);
+        let end = unwrap_enum!(&*params[3], VmValue::Primitive(PrimitiveValue::Int64(x)) => *x);
+
+        let mut subgraph_params = vec![
+          params[0].clone(),
+          params[1].clone(),
+          Arc::new(VmValue::Bool(false)), // placeholder
+        ];
+        let sequential =
+          self.trace.is_none() && self.vm.is_graph_read_only(*subgraph_index as usize);
+
+        // Each iteration runs at the same depth, so long loops don't hit the recursion limit.
+        for i in start..end {
+          subgraph_params[2] = Arc::new(VmValue::Primitive(PrimitiveValue::Int64(i)));
+          let output = self
+            .run_reducer(
+              sequential,
+              *subgraph_index as usize,
+              &subgraph_params,
+              recursion_depth,
+              txn,
+            )
+            .await?
+            .expect("inconsistency: Loop did not get an output from subgraph");
+          if output.is_null() {
+            break;
+          }
+          subgraph_params[1] = output;
+        }
+        Some(subgraph_params[1].clone())
+      }
+      TwGraphNode::ScanIndex(key_index, has_range)
+      | TwGraphNode::ScanIndexLimited(key_index, has_range, _) => {
+        let key = self.vm.script.idents.get(*key_index as usize).unwrap();
+        let set = match &*params[0] {
+          VmValue::Set(x) => x,
+          VmValue::Null(_) => return Ok(type_info.map(|x| Arc::new(VmValue::Null(x.clone())))),
+          _ => unreachable!(),
+        };
+        let walker = match &set.kind {
+          VmSetValueKind::Resident(x) => x,
+          _ => return Err(ExecError::FreshTableOrSetNotSupported.into()),
+        };
+        let specialized_ty = match &set.member_ty {
+          VmType::Table(x) => self.vm.schema.types.get(x.name).unwrap(),
+          _ => unreachable!(),
+        };
+        let is_primary = specialized_ty
+          .fields
+          .get(key.as_str())
+          .map(|x| x.1.as_slice().is_primary())
+          .unwrap_or(false);
+
+        // Primary keys are ordered by the fast scan keys, other fields by their index entries.
+        let range_prefix = if is_primary {
+          walker.set_fast_scan_prefix().unwrap()
+        } else {
+          walker.set_index_prefix(key.as_str()).unwrap()
+        };
+        let mut range_start = range_prefix.clone();
+        let mut range_end = range_prefix.clone();
+
+        // Serialized values never start with 0xff.
+        range_end.push(0xff);
+
+        if *has_range {
+          let maybe_start = &params[1];
+          let maybe_end = &params[2];
+
+          // Index entries hold self-delimiting values, fast scan keys plain primary keys.
+          let serialize = |x: &PrimitiveValue| {
+            if is_primary {
+              x.serialize_for_key_component()
+            } else {
+              x.serialize_for_self_delimiting_component()
+            }
+          };
+
+          if !maybe_start.is_null() {
+            range_start.extend_from_slice(&serialize(maybe_start.unwrap_primitive()));
+          }
 
-            // If we've got a range, update our scan ranges with it...
-            if *has_range {
-              let maybe_start = &params[3];
-              let maybe_end = &params[4];
+          if !maybe_end.is_null() {
+            range_end.pop();
+            range_end.extend_from_slice(&serialize(maybe_end.unwrap_primitive()));
+          }
+        }
 
-              if !maybe_start.is_null() {
-                range_start
-                  .extend_from_slice(&maybe_start.unwrap_primitive().serialize_for_key_component());
-              }
+        log::trace!(
+          "scan index: scan keys: {} {}",
+          base64::encode(&range_start),
+          base64::encode(&range_end)
+        );
 
-              if !maybe_end.is_null() {
-                // Revert the "all entries" assumption
-                *range_end.last_mut().unwrap() -= 1;
-                range_end
-                  .extend_from_slice(&maybe_end.unwrap_primitive().serialize_for_key_component());
-              }
-            }
+        let options = match n {
+          TwGraphNode::ScanIndexLimited(_, _, reverse) => ScanOptions {
+            reverse: *reverse,
+            limit: match &*params[params.len() - 1] {
+              VmValue::Primitive(PrimitiveValue::Int64(x)) => Some((*x).max(0) as usize),
+              _ => None,
+            },
+            snapshot: g.relaxed_isolation,
+          },
+          _ => ScanOptions {
+            snapshot: g.relaxed_isolation,
+            ..Default::default()
+          },
+        };
 
-            log::trace!(
-              "reduce set: scan keys: {} {}",
-              base64::encode(&range_start),
-              base64::encode(&range_end)
-            );
-
-            let mut it = txn.scan_keys(&range_start, &range_end).await?;
-            while let Some(k) = it.next().await? {
-              let k = k.strip_prefix(range_prefix.as_slice()).unwrap();
-              let walker = walker.enter_set_raw(k).unwrap();
-              subgraph_params[2] = Arc::new(VmValue::Table(VmTableValue {
-                ty: &*specialized_ty.name,
-                kind: VmTableValueKind::Resident(walker),
-              }));
-              let output = self
-                .recursively_run_graph(
-                  *subgraph_index as usize,
-                  &subgraph_params,
-                  recursion_depth,
-                  txn,
-                )
-                .await?
-                .expect("inconsistency: ReduceList did not get an output from subgraph");
-              if output.is_null() {
-                break;
-              }
-              subgraph_params[1] = output;
-            }
+        // Fast scan keys end with the primary key, and index entries hold the primary key they
+        // point to as their value.
+        let mut primary_keys = vec![];
+        let mut it = txn.scan(&range_start, &range_end, options).await?;
+        while let Some((k, v)) = it.next().await? {
+          if options.snapshot {
+            txn.add_read_conflict_key(&k).await?;
           }
-          _ => unreachable!(),
+          primary_keys.push(if is_primary {
+            k.strip_prefix(range_prefix.as_slice()).unwrap().to_vec()
+          } else {
+            v
+          });
         }
-        Some(subgraph_params[1].clone())
+        drop(it);
+
+        let mut node = ListSync::new_sync();
+        for primary_key_value in primary_keys.into_iter().rev() {
+          node.push_front_mut(Arc::new(VmValue::Table(VmTableValue {
+            ty: &*specialized_ty.name,
+            kind: VmTableValueKind::Resident(walker.enter_set_raw(&primary_key_value).unwrap()),
+          })));
+        }
+        Some(Arc::new(VmValue::List(VmListValue {
+          member_ty: set.member_ty.clone(),
+          node,
+        })))
+      }
+      TwGraphNode::Count => {
+        let set = unwrap_enum!(&*params[0], VmValue::Set(x) => x);
+        let count = match &set.kind {
+          VmSetValueKind::Resident(walker) => {
+            let range_prefix = walker.set_fast_scan_prefix().unwrap();
+            let mut range_end = range_prefix.clone();
+            *range_end.last_mut().unwrap() += 1;
+
+            let mut count = 0i64;
+            let mut it = txn.scan_keys(&range_prefix, &range_end).await?;
+            while it.next().await?.is_some() {
+              count += 1;
+            }
+            count
+          }
+          VmSetValueKind::Fresh(x) => x.len() as i64,
+        };
+        Some(Arc::new(VmValue::Primitive(PrimitiveValue::Int64(count))))
+      }
+      TwGraphNode::Sum(key_index) | TwGraphNode::Min(key_index) | TwGraphNode::Max(key_index) => {
+        let key = self.vm.script.idents.get(*key_index as usize).unwrap();
+        let set = unwrap_enum!(&*params[0], VmValue::Set(x) => x);
+        Some(self.aggregate_set(txn, n, set, key.as_str()).await?)
       }
       TwGraphNode::Throw => {
         let msg = &params[0];
@@ -715,6 +3132,116 @@ impl<'a, 'b> Executor<'a, 'b> {
     })
   }
 
+  /// Computes `Sum`, `Min` or `Max` of the field `key` over the members of a set.
+  async fn aggregate_set(
+    &self,
+    txn: &dyn KvTransaction,
+    n: &TwGraphNode,
+    set: &VmSetValue<'a>,
+    key: &str,
+  ) -> Result<Arc<VmValue<'a>>> {
+    let member_ty = unwrap_enum!(&set.member_ty, VmType::Table(x) => x.name);
+    let specialized_ty = self.vm.schema.types.get(member_ty).unwrap();
+    let (field_ty, annotations) = specialized_ty.fields.get(key).unwrap();
+    let primitive_ty = unwrap_enum!(field_ty, FieldType::Primitive(x) => *x);
+
+    let members: Vec<Arc<VmValue<'a>>> = match &set.kind {
+      VmSetValueKind::Fresh(x) => x.values().cloned().collect(),
+      VmSetValueKind::Resident(walker) => {
+        // Index entries (and fast scan keys, for the primary key) are ordered by value, so the
+        // least and greatest values are at either end of the range.
+        let ordered =
+          !matches!(n, TwGraphNode::Sum(_)) && specialized_ty.lookup_indexed_field(key).is_some();
+        let via_index = ordered && !annotations.as_slice().is_primary();
+        let range_prefix = if via_index {
+          walker.set_index_prefix(key).unwrap()
+        } else {
+          walker.set_fast_scan_prefix().unwrap()
+        };
+        let mut range_end = range_prefix.clone();
+        range_end.push(0xff);
+
+        let options = ScanOptions {
+          reverse: ordered && matches!(n, TwGraphNode::Max(_)),
+          limit: if ordered { Some(1) } else { None },
+          snapshot: false,
+        };
+        let mut primary_keys = vec![];
+        let mut it = txn.scan(&range_prefix, &range_end, options).await?;
+        while let Some((k, v)) = it.next().await? {
+          primary_keys.push(if via_index {
+            v
+          } else {
+            k.strip_prefix(range_prefix.as_slice()).unwrap().to_vec()
+          });
+        }
+        drop(it);
+
+        primary_keys
+          .into_iter()
+          .map(|x| {
+            Arc::new(VmValue::Table(VmTableValue {
+              ty: member_ty,
+              kind: VmTableValueKind::Resident(walker.enter_set_raw(&x).unwrap()),
+            }))
+          })
+          .collect()
+      }
+    };
+
+    let mut result: Option<PrimitiveValue> = None;
+    let values = self
+      .buffered(
+        members
+          .iter()
+          .map(|member| self.read_table_element(txn, member.unwrap_table(), key)),
+      )
+      .await?;
+    for value in values {
+      let value = match &*value {
+        VmValue::Primitive(x) => x.clone(),
+        _ => continue,
+      };
+      result = Some(match result {
+        None => value,
+        Some(current) => match n {
+          TwGraphNode::Sum(_) => match (current, value) {
+            (PrimitiveValue::Int64(l), PrimitiveValue::Int64(r)) => {
+              PrimitiveValue::Int64(l.wrapping_add(r))
+            }
+            (PrimitiveValue::Double(l), PrimitiveValue::Double(r)) => {
+              PrimitiveValue::Double((f64::from_bits(l) + f64::from_bits(r)).to_bits())
+            }
+            _ => unreachable!(),
+          },
+          TwGraphNode::Min(_) => {
+            if value.serialize_for_key_component() < current.serialize_for_key_component() {
+              value
+            } else {
+              current
+            }
+          }
+          TwGraphNode::Max(_) => {
+            if value.serialize_for_key_component() > current.serialize_for_key_component() {
+              value
+            } else {
+              current
+            }
+          }
+          _ => unreachable!(),
+        },
+      });
+    }
+
+    Ok(Arc::new(match (result, n) {
+      (Some(x), _) => VmValue::Primitive(x),
+      (None, TwGraphNode::Sum(_)) => {
+        VmValue::Primitive(PrimitiveValue::default_value_for_type(primitive_ty))
+      }
+      (None, _) => VmValue::Null(VmType::Primitive(primitive_ty)),
+    }))
+  }
+
   async fn read_table_element(
     &self,
     txn: &dyn KvTransaction,
@@ -728,7 +3255,7 @@ impl<'a, 'b> Executor<'a, 'b> {
         .unwrap_or_else(|| panic!("read_table_element: key not found in table: {}", key)),
       VmTableValueKind::Resident(walker) => {
         let specialized_ty = self.vm.schema.types.get(table.ty).unwrap();
-        let (field, _) = specialized_ty.fields.get(key).unwrap();
+        let (field, annotations) = specialized_ty.fields.get(key).unwrap();
         let walker = walker
           .enter_field(key)
           .expect("inconsistency: field not found in table");
@@ -738,95 +3265,610 @@ impl<'a, 'b> Executor<'a, 'b> {
             // This is a primitive type - we cannot defer any more.
             // Let's load from the database.
             let key = walker.generate_key();
-            let raw_data: Option<PrimitiveValue> = txn
+            let raw_data = txn
               .get(&key)
               .await?
-              .map(|x| rmp_serde::from_slice(&x))
+              .map(|x| decode_primitive_field(&walker, &x))
               .transpose()?;
+            // Fields added with a `@default` read as the default until they are set, so that
+            // migrations don't have to backfill them. Versions start at zero.
+            let raw_data = raw_data.or_else(|| {
+              if annotations.as_slice().is_version() {
+                return Some(PrimitiveValue::Int64(0));
+              }
+              annotations
+                .iter()
+                .find_map(|x| x.default_value())
+                .map(PrimitiveValue::from)
+            });
             Arc::new(
               raw_data
                 .map(VmValue::Primitive)
                 .unwrap_or_else(|| VmValue::Null(VmType::from(x))),
             )
-          }
-          FieldType::Set(member_ty) => Arc::new(VmValue::Set(VmSetValue {
-            member_ty: VmType::from(&**member_ty),
-            kind: VmSetValueKind::Resident(walker),
-          })),
-          FieldType::Table(x) => Arc::new(VmValue::Table(VmTableValue {
-            ty: &**x,
-            kind: VmTableValueKind::Resident(walker),
-          })),
+          }
+          FieldType::Set(member_ty) => Arc::new(VmValue::Set(VmSetValue {
+            member_ty: VmType::from(&**member_ty),
+            kind: VmSetValueKind::Resident(walker),
+          })),
+          FieldType::List(member_ty) => {
+            self
+              .read_list(txn, &walker, VmType::from(&**member_ty))
+              .await?
+          }
+          FieldType::Map(..) => {
+            let entry_ty = self.map_entry_type(table.ty, key);
+            // Boxed for the same reason as `run_list_or_map_node`.
+            Box::pin(self.read_map(txn, &walker, entry_ty)).await?
+          }
+          FieldType::Table(x) => Arc::new(VmValue::Table(VmTableValue {
+            ty: &**x,
+            kind: VmTableValueKind::Resident(walker),
+          })),
+        }
+      }
+    })
+  }
+
+  #[async_recursion]
+  async fn walk_and_insert(
+    &self,
+    txn: &dyn KvTransaction,
+    walker: Arc<PathWalker<'a>>,
+    value: Arc<VmValue<'a>>,
+  ) -> Result<()> {
+    match &*value {
+      VmValue::Set(VmSetValue {
+        kind: VmSetValueKind::Resident(_),
+        ..
+      })
+      | VmValue::Table(VmTableValue {
+        kind: VmTableValueKind::Resident(_),
+        ..
+      }) => {
+        // Copy. Load the whole source subtree before writing anything, so that copying a value
+        // into itself or into one of its own descendants is well-defined.
+        let value = self.load_resident(txn, value.clone()).await?;
+        self.walk_and_insert(txn, walker, value).await?;
+      }
+      VmValue::Null(_) if walker.node().list || walker.node().map => {
+        self.delete_subtree(txn, &walker).await?;
+      }
+      VmValue::Null(_) => {
+        txn.delete(&walker.generate_key()).await?;
+      }
+      VmValue::List(x) if walker.node().list => {
+        self.write_list(txn, &walker, x).await?;
+      }
+      VmValue::List(x) if walker.node().map => {
+        self.write_map(txn, &walker, x).await?;
+      }
+      VmValue::Primitive(x) => {
+        let value = encode_primitive_field(&walker, x);
+        txn.put(&walker.generate_key(), &value).await?;
+      }
+      VmValue::Set(x) => {
+        txn.put(&walker.generate_key(), &[]).await?;
+        match &x.kind {
+          VmSetValueKind::Fresh(members) => {
+            // Clear set
+            self.delete_set(txn, &walker).await?;
+
+            // Need to clone this. Otherwise `async_recursion` errors
+            let members = members.clone();
+            for (primary_key_value, member) in members {
+              let mut fast_scan_key = walker.set_fast_scan_prefix().unwrap();
+              fast_scan_key.extend_from_slice(&primary_key_value);
+              txn.put(&fast_scan_key, &[]).await?;
+
+              let member_ty = member.unwrap_table().ty;
+              let index_fields = self
+                .read_indexed_fields(txn, member.unwrap_table(), None)
+                .await?;
+              let member_walker = walker.enter_set_raw(&primary_key_value).unwrap();
+              self.walk_and_insert(txn, member_walker, member).await?;
+              self
+                .add_index_entries(txn, &walker, member_ty, &primary_key_value, &index_fields)
+                .await?;
+            }
+          }
+          VmSetValueKind::Resident(_) => unreachable!(),
+        }
+      }
+      VmValue::Table(x) => match &x.kind {
+        VmTableValueKind::Fresh(fields) => {
+          // The table key and primitive fields are written in one batch.
+          let mut batch = vec![(walker.generate_key(), vec![])];
+
+          // Need to clone this. Otherwise `async_recursion` errors
+          let fields = fields.clone();
+          for (k, v) in fields {
+            let walker = walker.enter_field(k).unwrap();
+            if let VmValue::Primitive(x) = &*v {
+              batch.push((walker.generate_key(), encode_primitive_field(&walker, x)));
+            } else {
+              self.walk_and_insert(txn, walker, v.clone()).await?;
+            }
+          }
+          txn.put_many(&batch).await?;
+        }
+        VmTableValueKind::Resident(_) => unreachable!(),
+      },
+      VmValue::Bool(_) | VmValue::Map(_) | VmValue::List(_) | VmValue::Json(_) => {
+        panic!(
+          "inconsistency: walk_and_insert encountered non-storable type: {:?}",
+          value
+        );
+      }
+    }
+    Ok(())
+  }
+
+  /// Reads a resident table or set into an equivalent fresh value.
+  ///
+  /// Nested tables that are not flattened are only loaded when something is stored under their
+  /// keys. This bounds the recursion on recursive types by the actual data.
+  #[async_recursion]
+  async fn load_resident(
+    &self,
+    txn: &dyn KvTransaction,
+    value: Arc<VmValue<'a>>,
+  ) -> Result<Arc<VmValue<'a>>> {
+    Ok(match &*value {
+      VmValue::Table(table) => {
+        if let VmTableValueKind::Fresh(_) = &table.kind {
+          return Ok(value.clone());
+        }
+        let specialized_ty = self.vm.schema.types.get(table.ty).unwrap();
+        let mut fields: BTreeMap<&'a str, Arc<VmValue<'a>>> = BTreeMap::new();
+        for (name, (field_ty, _)) in &specialized_ty.fields {
+          let field_value = self.read_table_element(txn, table, name).await?;
+          let field_value = match &*field_value {
+            VmValue::Table(VmTableValue {
+              kind: VmTableValueKind::Resident(field_walker),
+              ..
+            }) => {
+              if field_walker.is_flattened() || self.subtree_exists(txn, field_walker).await? {
+                self.load_resident(txn, field_value.clone()).await?
+              } else {
+                Arc::new(VmValue::Null(VmType::from(field_ty)))
+              }
+            }
+            VmValue::Set(_) => self.load_resident(txn, field_value.clone()).await?,
+            _ => field_value,
+          };
+          fields.insert(&**name, field_value);
+        }
+        Arc::new(VmValue::Table(VmTableValue {
+          ty: table.ty,
+          kind: VmTableValueKind::Fresh(fields),
+        }))
+      }
+      VmValue::Set(set) => {
+        let walker = match &set.kind {
+          VmSetValueKind::Resident(x) => x,
+          VmSetValueKind::Fresh(_) => return Ok(value.clone()),
+        };
+        let member_ty = unwrap_enum!(&set.member_ty, VmType::Table(x) => x.name);
+        let range_prefix = walker.set_fast_scan_prefix().unwrap();
+        let mut range_end = range_prefix.clone();
+        *range_end.last_mut().unwrap() += 1;
+
+        let mut primary_keys = vec![];
+        let mut it = txn.scan_keys(&range_prefix, &range_end).await?;
+        while let Some(k) = it.next().await? {
+          primary_keys.push(k.strip_prefix(range_prefix.as_slice()).unwrap().to_vec());
+        }
+        drop(it);
+
+        let mut members = BTreeMap::new();
+        for primary_key_value in primary_keys {
+          let member = Arc::new(VmValue::Table(VmTableValue {
+            ty: member_ty,
+            kind: VmTableValueKind::Resident(walker.enter_set_raw(&primary_key_value).unwrap()),
+          }));
+          let member = self.load_resident(txn, member).await?;
+          if self
+            .is_expired(txn, member.unwrap_table(), self.now_millis)
+            .await?
+          {
+            continue;
+          }
+          members.insert(primary_key_value, member);
+        }
+        Arc::new(VmValue::Set(VmSetValue {
+          member_ty: set.member_ty.clone(),
+          kind: VmSetValueKind::Fresh(members),
+        }))
+      }
+      _ => value.clone(),
+    })
+  }
+
+  async fn subtree_exists(&self, txn: &dyn KvTransaction, walker: &PathWalker<'a>) -> Result<bool> {
+    let start_key = walker.generate_key();
+    let end_key = prefix_end(&start_key);
+    let mut it = txn.scan_keys(&start_key, &end_key).await?;
+    Ok(it.next().await?.is_some())
+  }
+
+  /// Whether anything is stored at or below `walker`, including below flattened nodes.
+  #[async_recursion]
+  async fn anything_stored_under(
+    &self,
+    txn: &dyn KvTransaction,
+    walker: &Arc<PathWalker<'a>>,
+  ) -> Result<bool> {
+    if !walker.is_flattened() {
+      return self.subtree_exists(txn, walker).await;
+    }
+    if txn.get(&walker.generate_key()).await?.is_some() {
+      return Ok(true);
+    }
+    for field_name in walker.node().children.keys() {
+      let child = walker.enter_field(field_name)?;
+      if self.anything_stored_under(txn, &child).await? {
+        return Ok(true);
+      }
+    }
+    Ok(false)
+  }
+
+  /// Deletes everything stored at and below `walker`.
+  #[async_recursion]
+  async fn delete_subtree(
+    &self,
+    txn: &dyn KvTransaction,
+    walker: &Arc<PathWalker<'a>>,
+  ) -> Result<()> {
+    let start_key = walker.generate_key();
+    if !walker.is_flattened() {
+      let end_key = prefix_end(&start_key);
+      txn.delete_range(&start_key, &end_key).await?;
+      return Ok(());
+    }
+
+    // The children of a flattened node are not stored under its key.
+    txn.delete(&start_key).await?;
+    for field_name in walker.node().children.keys() {
+      let child = walker.enter_field(field_name)?;
+      self.delete_subtree(txn, &child).await?;
+    }
+    Ok(())
+  }
+
+  /// Reads the length of the list at `walker`. Zero if the list was never written.
+  async fn read_list_len(&self, txn: &dyn KvTransaction, walker: &PathWalker<'a>) -> Result<u64> {
+    Ok(
+      txn
+        .get(&walker.generate_key())
+        .await?
+        .map(|x| rmp_serde::from_slice(&x))
+        .transpose()?
+        .unwrap_or(0),
+    )
+  }
+
+  /// Reads a member of the list at `walker`. `None` if the member is null.
+  async fn read_list_element(
+    &self,
+    txn: &dyn KvTransaction,
+    walker: &PathWalker<'a>,
+    index: u64,
+  ) -> Result<Option<PrimitiveValue>> {
+    Ok(
+      txn
+        .get(&walker.list_element_key(index)?)
+        .await?
+        .map(|x| rmp_serde::from_slice(&x))
+        .transpose()?,
+    )
+  }
+
+  /// Reads the whole list at `walker`.
+  async fn read_list(
+    &self,
+    txn: &dyn KvTransaction,
+    walker: &PathWalker<'a>,
+    member_ty: VmType<&'a str>,
+  ) -> Result<Arc<VmValue<'a>>> {
+    let len = self.read_list_len(txn, walker).await?;
+    let members = self
+      .buffered((0..len).map(|i| self.read_list_element(txn, walker, i)))
+      .await?;
+    let mut node = ListSync::new_sync();
+    for x in members.into_iter().rev() {
+      node.push_front_mut(Arc::new(
+        x.map(VmValue::Primitive)
+          .unwrap_or_else(|| VmValue::Null(member_ty.clone())),
+      ));
+    }
+    Ok(Arc::new(VmValue::List(VmListValue { member_ty, node })))
+  }
+
+  /// Replaces the list at `walker` with the members of `list`.
+  async fn write_list(
+    &self,
+    txn: &dyn KvTransaction,
+    walker: &Arc<PathWalker<'a>>,
+    list: &VmListValue<'a>,
+  ) -> Result<()> {
+    self.delete_subtree(txn, walker).await?;
+    let mut len = 0u64;
+    for member in list.node.iter() {
+      if let VmValue::Primitive(x) = &**member {
+        txn
+          .put(
+            &walker.list_element_key(len)?,
+            &rmp_serde::to_vec(x).unwrap(),
+          )
+          .await?;
+      }
+      len += 1;
+    }
+    txn
+      .put(&walker.generate_key(), &rmp_serde::to_vec(&len).unwrap())
+      .await?;
+    Ok(())
+  }
+
+  fn list_member_type(&self, table_ty: &'a str, key: &str) -> VmType<&'a str> {
+    let specialized_ty = self.vm.schema.types.get(table_ty).unwrap();
+    match &specialized_ty.fields.get(key).unwrap().0 {
+      FieldType::List(x) => VmType::from(&**x),
+      _ => unreachable!(),
+    }
+  }
+
+  /// Runs the nodes on `list<T>` and `map<K, V>` fields.
+  async fn run_list_or_map_node(
+    &self,
+    n: &TwGraphNode,
+    params: &[Arc<VmValue<'a>>],
+    txn: &dyn KvTransaction,
+  ) -> Result<Option<Arc<VmValue<'a>>>> {
+    Ok(match n {
+      TwGraphNode::ListPush(key_index) => {
+        // Effect node
+        let key = self.vm.script.idents.get(*key_index as usize).unwrap();
+        let walker = resident_table_walker(params[1].unwrap_table())?;
+        let list_walker = walker.enter_field(key.as_str()).unwrap();
+        let len = self.read_list_len(txn, &list_walker).await?;
+        if let VmValue::Primitive(x) = &*params[0] {
+          txn
+            .put(
+              &list_walker.list_element_key(len)?,
+              &rmp_serde::to_vec(x).unwrap(),
+            )
+            .await?;
+        }
+        txn
+          .put(
+            &list_walker.generate_key(),
+            &rmp_serde::to_vec(&(len + 1)).unwrap(),
+          )
+          .await?;
+        self.record_change(walker, || ChangeKind::SetField(key.clone()));
+        self.bump_version(txn, params[1].unwrap_table()).await?;
+        None
+      }
+      TwGraphNode::ListPop(key_index) => {
+        // Effect node
+        let key = self.vm.script.idents.get(*key_index as usize).unwrap();
+        let table = params[0].unwrap_table();
+        let walker = resident_table_walker(table)?;
+        let list_walker = walker.enter_field(key.as_str()).unwrap();
+        let member_ty = self.list_member_type(table.ty, key);
+        let len = self.read_list_len(txn, &list_walker).await?;
+        if len == 0 {
+          Some(Arc::new(VmValue::Null(member_ty)))
+        } else {
+          let element_key = list_walker.list_element_key(len - 1)?;
+          let value = self.read_list_element(txn, &list_walker, len - 1).await?;
+          txn.delete(&element_key).await?;
+          txn
+            .put(
+              &list_walker.generate_key(),
+              &rmp_serde::to_vec(&(len - 1)).unwrap(),
+            )
+            .await?;
+          self.record_change(walker, || ChangeKind::SetField(key.clone()));
+          self.bump_version(txn, table).await?;
+          Some(Arc::new(
+            value
+              .map(VmValue::Primitive)
+              .unwrap_or_else(|| VmValue::Null(member_ty)),
+          ))
+        }
+      }
+      TwGraphNode::ListGet(key_index) => {
+        let key = self.vm.script.idents.get(*key_index as usize).unwrap();
+        let index = unwrap_enum!(&*params[0], VmValue::Primitive(PrimitiveValue::Int64(x)) => *x);
+        let table = params[1].unwrap_table();
+        let list_walker = resident_table_walker(table)?
+          .enter_field(key.as_str())
+          .unwrap();
+        let value = if index >= 0 && (index as u64) < self.read_list_len(txn, &list_walker).await? {
+          self
+            .read_list_element(txn, &list_walker, index as u64)
+            .await?
+        } else {
+          None
+        };
+        Some(Arc::new(value.map(VmValue::Primitive).unwrap_or_else(
+          || VmValue::Null(self.list_member_type(table.ty, key)),
+        )))
+      }
+      TwGraphNode::ListLen(key_index) => {
+        let key = self.vm.script.idents.get(*key_index as usize).unwrap();
+        let list_walker = resident_table_walker(params[0].unwrap_table())?
+          .enter_field(key.as_str())
+          .unwrap();
+        let len = self.read_list_len(txn, &list_walker).await?;
+        Some(Arc::new(VmValue::Primitive(PrimitiveValue::Int64(
+          len as i64,
+        ))))
+      }
+      TwGraphNode::DictGet(key_index) => {
+        let key = self.vm.script.idents.get(*key_index as usize).unwrap();
+        let entry_key = unwrap_enum!(&*params[0], VmValue::Primitive(x) => x);
+        let table = params[1].unwrap_table();
+        let map_walker = resident_table_walker(table)?
+          .enter_field(key.as_str())
+          .unwrap();
+        let value: Option<PrimitiveValue> = txn
+          .get(&map_walker.map_entry_key(entry_key)?)
+          .await?
+          .map(|x| rmp_serde::from_slice(&x))
+          .transpose()?;
+        Some(Arc::new(value.map(VmValue::Primitive).unwrap_or_else(
+          || {
+            let entry_ty = self.map_entry_type(table.ty, key);
+            VmValue::Null(unwrap_enum!(entry_ty, VmType::Map(x) => x.get("value").unwrap().clone()))
+          },
+        )))
+      }
+      TwGraphNode::DictPut(key_index) => {
+        // Effect node
+        let key = self.vm.script.idents.get(*key_index as usize).unwrap();
+        let entry_key = unwrap_enum!(&*params[0], VmValue::Primitive(x) => x);
+        let walker = resident_table_walker(params[2].unwrap_table())?;
+        let map_walker = walker.enter_field(key.as_str()).unwrap();
+        if let VmValue::Primitive(x) = &*params[1] {
+          txn
+            .put(
+              &map_walker.map_entry_key(entry_key)?,
+              &rmp_serde::to_vec(x).unwrap(),
+            )
+            .await?;
         }
+        self.record_change(walker, || ChangeKind::SetField(key.clone()));
+        self.bump_version(txn, params[2].unwrap_table()).await?;
+        None
+      }
+      TwGraphNode::DictDelete(key_index) => {
+        // Effect node
+        let key = self.vm.script.idents.get(*key_index as usize).unwrap();
+        let entry_key = unwrap_enum!(&*params[0], VmValue::Primitive(x) => x);
+        let walker = resident_table_walker(params[1].unwrap_table())?;
+        let map_walker = walker.enter_field(key.as_str()).unwrap();
+        txn.delete(&map_walker.map_entry_key(entry_key)?).await?;
+        self.record_change(walker, || ChangeKind::SetField(key.clone()));
+        self.bump_version(txn, params[1].unwrap_table()).await?;
+        None
+      }
+      TwGraphNode::DictRange(key_index) => {
+        let key = self.vm.script.idents.get(*key_index as usize).unwrap();
+        let start = unwrap_enum!(&*params[0], VmValue::Primitive(x) => x);
+        let end = unwrap_enum!(&*params[1], VmValue::Primitive(x) => x);
+        let table = params[2].unwrap_table();
+        let map_walker = resident_table_walker(table)?
+          .enter_field(key.as_str())
+          .unwrap();
+        let entry_ty = self.map_entry_type(table.ty, key);
+        Some(
+          self
+            .read_map_range(
+              txn,
+              &map_walker,
+              entry_ty,
+              &map_walker.map_entry_key(start)?,
+              &map_walker.map_entry_key(end)?,
+            )
+            .await?,
+        )
       }
+      _ => unreachable!(),
     })
   }
 
-  #[async_recursion]
-  async fn walk_and_insert(
+  /// Reads the whole map at `walker`.
+  async fn read_map(
     &self,
     txn: &dyn KvTransaction,
-    walker: Arc<PathWalker<'a>>,
-    value: Arc<VmValue<'a>>,
-  ) -> Result<()> {
-    match &*value {
-      VmValue::Null(_) => {
-        txn.delete(&walker.generate_key()).await?;
-      }
-      VmValue::Primitive(x) => {
-        let value = rmp_serde::to_vec(x).unwrap();
-        txn.put(&walker.generate_key(), &value).await?;
-      }
-      VmValue::Set(x) => {
-        txn.put(&walker.generate_key(), &[]).await?;
-        match &x.kind {
-          VmSetValueKind::Fresh(members) => {
-            // Clear set
-            self.delete_set(txn, &walker).await?;
-
-            // Need to clone this. Otherwise `async_recursion` errors
-            let members = members.clone();
-            for (primary_key_value, member) in members {
-              let mut fast_scan_key = walker.set_fast_scan_prefix().unwrap();
-              fast_scan_key.extend_from_slice(&primary_key_value);
-              txn.put(&fast_scan_key, &[]).await?;
+    walker: &PathWalker<'a>,
+    entry_ty: VmType<&'a str>,
+  ) -> Result<Arc<VmValue<'a>>> {
+    let start = walker.map_data_prefix()?;
+    let end = prefix_end(&start);
+    self
+      .read_map_range(txn, walker, entry_ty, &start, &end)
+      .await
+  }
 
-              let walker = walker.enter_set_raw(&primary_key_value).unwrap();
-              self.walk_and_insert(txn, walker, member).await?;
-            }
-          }
-          VmSetValueKind::Resident(_) => {
-            return Err(ExecError::NotImplemented("set copy is not implemented".into()).into())
-          }
-        }
-      }
-      VmValue::Table(x) => {
-        txn.put(&walker.generate_key(), &[]).await?;
-        match &x.kind {
-          VmTableValueKind::Fresh(fields) => {
-            // Need to clone this. Otherwise `async_recursion` errors
-            let fields = fields.clone();
-            for (k, v) in fields {
-              let walker = walker.enter_field(k).unwrap();
-              let v = v.clone();
-              self.walk_and_insert(txn, walker, v).await?;
-            }
-          }
-          VmTableValueKind::Resident(_) => {
-            return Err(ExecError::NotImplemented("table copy is not implemented".into()).into())
-          }
-        }
-      }
-      VmValue::Bool(_) | VmValue::Map(_) | VmValue::List(_) => {
-        panic!(
-          "inconsistency: walk_and_insert encountered non-storable type: {:?}",
-          value
-        );
+  /// Reads the entries of the map at `walker` with entry keys in `[start, end)`, as a list of
+  /// `map { key: K, value: V }` in key order.
+  async fn read_map_range(
+    &self,
+    txn: &dyn KvTransaction,
+    walker: &PathWalker<'a>,
+    entry_ty: VmType<&'a str>,
+    start: &[u8],
+    end: &[u8],
+  ) -> Result<Arc<VmValue<'a>>> {
+    let prefix = walker.map_data_prefix()?;
+    let mut entries = vec![];
+    if start < end {
+      let mut it = txn.scan(start, end, ScanOptions::default()).await?;
+      while let Some(x) = it.next().await? {
+        entries.push(x);
       }
     }
+    let mut node = ListSync::new_sync();
+    for (k, v) in entries.into_iter().rev() {
+      let v: PrimitiveValue = rmp_serde::from_slice(&v)?;
+      let k = PrimitiveValue::deserialize_from_key_component(&k[prefix.len()..])
+        .expect("inconsistency: bad map entry key");
+      node.push_front_mut(Arc::new(VmValue::Map(VmMapValue {
+        elements: RedBlackTreeMapSync::new_sync()
+          .insert("key", Arc::new(VmValue::Primitive(k)))
+          .insert("value", Arc::new(VmValue::Primitive(v))),
+      })));
+    }
+    Ok(Arc::new(VmValue::List(VmListValue {
+      member_ty: entry_ty,
+      node,
+    })))
+  }
+
+  /// Replaces the map at `walker` with the `map { key, value }` entries of `list`. Entries with a
+  /// null key or value are skipped, and later entries win over earlier ones with the same key.
+  async fn write_map(
+    &self,
+    txn: &dyn KvTransaction,
+    walker: &Arc<PathWalker<'a>>,
+    list: &VmListValue<'a>,
+  ) -> Result<()> {
+    self.delete_subtree(txn, walker).await?;
+    for entry in list.node.iter() {
+      let entry = match &**entry {
+        VmValue::Map(x) => x,
+        _ => continue,
+      };
+      let (k, v) = match (
+        entry.elements.get("key").map(|x| &**x),
+        entry.elements.get("value").map(|x| &**x),
+      ) {
+        (Some(VmValue::Primitive(k)), Some(VmValue::Primitive(v))) => (k, v),
+        _ => continue,
+      };
+      txn
+        .put(&walker.map_entry_key(k)?, &rmp_serde::to_vec(v).unwrap())
+        .await?;
+    }
     Ok(())
   }
 
+  /// The type of the entries of the `map<K, V>` field `key` of a table, `map { key: K, value: V }`.
+  fn map_entry_type(&self, table_ty: &'a str, key: &str) -> VmType<&'a str> {
+    let specialized_ty = self.vm.schema.types.get(table_ty).unwrap();
+    let field_ty = &specialized_ty.fields.get(key).unwrap().0;
+    match (field_ty, VmType::from(field_ty)) {
+      (FieldType::Map(..), VmType::List(x)) => *x.ty,
+      _ => unreachable!(),
+    }
+  }
+
   async fn delete_set(&self, txn: &dyn KvTransaction, walker: &Arc<PathWalker<'a>>) -> Result<()> {
     let fast_scan_start_key = walker.set_fast_scan_prefix().unwrap();
     let mut fast_scan_end_key = fast_scan_start_key.clone();
@@ -836,10 +3878,16 @@ impl<'a, 'b> Executor<'a, 'b> {
     let mut data_end_key = data_start_key.clone();
     *data_end_key.last_mut().unwrap() += 1;
 
+    let mut index_start_key = walker.generate_key();
+    index_start_key.push(0x02);
+    let mut index_end_key = index_start_key.clone();
+    *index_end_key.last_mut().unwrap() += 1;
+
     txn
       .delete_range(&fast_scan_start_key, &fast_scan_end_key)
       .await?;
     txn.delete_range(&data_start_key, &data_end_key).await?;
+    txn.delete_range(&index_start_key, &index_end_key).await?;
     Ok(())
   }
 
@@ -847,14 +3895,18 @@ impl<'a, 'b> Executor<'a, 'b> {
     &self,
     txn: &dyn KvTransaction,
     walker: &Arc<PathWalker<'a>>,
-    primary_key_value: &PrimitiveValue,
+    member_ty: &'a str,
+    primary_key_value: &[u8],
   ) -> Result<()> {
-    let primary_key_value_raw = primary_key_value.serialize_for_key_component();
+    self
+      .remove_index_entries(txn, walker, member_ty, primary_key_value, None)
+      .await?;
+
     let mut fast_scan_key = walker.set_fast_scan_prefix().unwrap();
-    fast_scan_key.extend_from_slice(&primary_key_value_raw);
+    fast_scan_key.extend_from_slice(primary_key_value);
 
     let mut data_start_key = walker.set_data_prefix().unwrap();
-    data_start_key.extend_from_slice(&primary_key_value_raw);
+    data_start_key.extend_from_slice(primary_key_value);
     data_start_key.push(0x00);
 
     let mut data_end_key = data_start_key.clone();
@@ -864,6 +3916,744 @@ impl<'a, 'b> Executor<'a, 'b> {
     txn.delete_range(&data_start_key, &data_end_key).await?;
     Ok(())
   }
+
+  /// Inserts `value` into the set at `walker`, replacing the member with the same primary key.
+  /// Returns the primary key, and whether a member was replaced if change tracking is enabled.
+  async fn insert_into_set(
+    &self,
+    txn: &dyn KvTransaction,
+    walker: &Arc<PathWalker<'a>>,
+    member_ty: &'a str,
+    primary_key: &PrimaryKey<'a>,
+    value: Arc<VmValue<'a>>,
+  ) -> Result<(PrimitiveValue, bool)> {
+    let primary_key = self
+      .read_primary_key(txn, value.unwrap_table(), primary_key)
+      .await?;
+    let primary_key_value = primary_key.serialize_for_key_component();
+
+    self
+      .remove_index_entries(txn, walker, member_ty, &primary_key_value, None)
+      .await?;
+
+    let mut fast_scan_key = walker.set_fast_scan_prefix().unwrap();
+    fast_scan_key.extend_from_slice(&primary_key_value);
+    let replaced = self.changes.is_some() && txn.get(&fast_scan_key).await?.is_some();
+    txn.put(&fast_scan_key, &[]).await?;
+
+    let index_fields = self
+      .read_indexed_fields(txn, value.unwrap_table(), None)
+      .await?;
+    let member_walker = walker.enter_set_raw(&primary_key_value).unwrap();
+    self.walk_and_insert(txn, member_walker, value).await?;
+
+    self
+      .add_index_entries(txn, walker, member_ty, &primary_key_value, &index_fields)
+      .await?;
+    Ok((primary_key, replaced))
+  }
+
+  /// Whether a set or table is stored, and not expired.
+  async fn is_present(&self, txn: &dyn KvTransaction, value: &VmValue<'a>) -> Result<bool> {
+    let walker = match value {
+      VmValue::Set(x) => match &x.kind {
+        VmSetValueKind::Fresh(_) => return Ok(true),
+        VmSetValueKind::Resident(x) => x,
+      },
+      VmValue::Table(x) => match &x.kind {
+        VmTableValueKind::Fresh(_) => return Ok(true),
+        VmTableValueKind::Resident(x) => x,
+      },
+      _ => unreachable!(),
+    };
+    if txn.get(&walker.generate_key()).await?.is_none() {
+      return Ok(false);
+    }
+    Ok(match value {
+      VmValue::Table(x) => !self.is_expired(txn, x, self.now_millis).await?,
+      _ => true,
+    })
+  }
+
+  /// Whether `member` is of a `@ttl` type and the time in its ttl field is not after `now`.
+  /// Members whose ttl field is null never expire.
+  async fn is_expired(
+    &self,
+    txn: &dyn KvTransaction,
+    member: &VmTableValue<'a>,
+    now: i64,
+  ) -> Result<bool> {
+    let ttl_field = match &self.vm.schema.types.get(member.ty).unwrap().ttl {
+      Some(x) => x,
+      None => return Ok(false),
+    };
+    Ok(
+      match &*self.read_table_element(txn, member, ttl_field).await? {
+        VmValue::Primitive(PrimitiveValue::Int64(x)) => *x <= now,
+        _ => false,
+      },
+    )
+  }
+
+  /// Bumps the `@version` field of a resident table, if its type has one. The field is not read,
+  /// so that concurrent updates don't conflict on it.
+  async fn bump_version(&self, txn: &dyn KvTransaction, table: &VmTableValue<'a>) -> Result<()> {
+    let version_field = match self.vm.schema.types.get(table.ty).unwrap().version_field() {
+      Some(x) => x,
+      None => return Ok(()),
+    };
+    let walker = resident_table_walker(table)?;
+    let key = walker.enter_field(version_field).unwrap().generate_key();
+    self.atomic_add(txn, &key, 1).await
+  }
+
+  async fn atomic_add(&self, txn: &dyn KvTransaction, key: &[u8], delta: i64) -> Result<()> {
+    let _guard = self.atomic_add_lock.lock().await;
+    txn.atomic_add(key, delta).await
+  }
+
+  /// Fails with `ExecError::VersionConflict` if the `@version` field of a table is not
+  /// `expected`.
+  async fn check_version(
+    &self,
+    txn: &dyn KvTransaction,
+    table: &VmTableValue<'a>,
+    expected: i64,
+  ) -> Result<()> {
+    let specialized_ty = self.vm.schema.types.get(table.ty).unwrap();
+    let version_field = specialized_ty.version_field().unwrap();
+    let actual = match &*self.read_table_element(txn, table, version_field).await? {
+      VmValue::Primitive(PrimitiveValue::Int64(x)) => *x,
+      _ => 0,
+    };
+    if actual != expected {
+      return Err(ExecError::VersionConflict(expected, actual).into());
+    }
+    Ok(())
+  }
+
+  /// Reads the primary key value of a set member, packing the fields of a composite key.
+  async fn read_primary_key(
+    &self,
+    txn: &dyn KvTransaction,
+    member: &VmTableValue<'a>,
+    primary_key: &PrimaryKey<'a>,
+  ) -> Result<PrimitiveValue> {
+    let mut components = Vec::with_capacity(primary_key.fields.len());
+    for (name, _) in &primary_key.fields {
+      components.push(
+        self
+          .read_table_element(txn, member, name)
+          .await?
+          .unwrap_primitive()
+          .clone(),
+      );
+    }
+    Ok(primary_key.value_of(components))
+  }
+
+  /// Reads the values of `@unique` and `@index` fields from a set member, or only `only_field`
+  /// if specified.
+  async fn read_indexed_fields(
+    &self,
+    txn: &dyn KvTransaction,
+    member: &VmTableValue<'a>,
+    only_field: Option<&str>,
+  ) -> Result<Vec<(&'a str, Arc<VmValue<'a>>)>> {
+    let specialized_ty = self.vm.schema.types.get(member.ty).unwrap();
+    let mut fields = vec![];
+    for (name, (_, annotations)) in &specialized_ty.fields {
+      if only_field.is_some() && only_field != Some(&**name) {
+        continue;
+      }
+      if !annotations.as_slice().is_unique() && !annotations.as_slice().is_index() {
+        continue;
+      }
+      fields.push((&**name, self.read_table_element(txn, member, name).await?));
+    }
+    Ok(fields)
+  }
+
+  /// Removes the index entries of the currently stored member `primary_key_value` of the set at
+  /// `walker`.
+  async fn remove_index_entries(
+    &self,
+    txn: &dyn KvTransaction,
+    walker: &Arc<PathWalker<'a>>,
+    member_ty: &'a str,
+    primary_key_value: &[u8],
+    only_field: Option<&str>,
+  ) -> Result<()> {
+    let member = VmTableValue {
+      ty: member_ty,
+      kind: VmTableValueKind::Resident(walker.enter_set_raw(primary_key_value).unwrap()),
+    };
+    let fields = self.read_indexed_fields(txn, &member, only_field).await?;
+    for (_, key) in generate_index_keys(walker, primary_key_value, &fields) {
+      txn.delete(&key).await?;
+    }
+    Ok(())
+  }
+
+  /// Adds index entries for the member `primary_key_value` of the set at `walker`.
+  ///
+  /// Fails with `UniqueConstraintViolation` if a `@unique` field value is already taken by
+  /// another member. Entries of the member itself must have been removed before.
+  async fn add_index_entries(
+    &self,
+    txn: &dyn KvTransaction,
+    walker: &Arc<PathWalker<'a>>,
+    member_ty: &'a str,
+    primary_key_value: &[u8],
+    fields: &[(&'a str, Arc<VmValue<'a>>)],
+  ) -> Result<()> {
+    let specialized_ty = self.vm.schema.types.get(member_ty).unwrap();
+    for (name, key) in generate_index_keys(walker, primary_key_value, fields) {
+      let (_, annotations) = specialized_ty.fields.get(name).unwrap();
+      if annotations.as_slice().is_unique() {
+        // All entries with the same value share the prefix up to and including the separator.
+        let start = &key[..key.len() - primary_key_value.len()];
+        let mut end = start.to_vec();
+        *end.last_mut().unwrap() = 0x01;
+        let mut it = txn.scan_keys(start, &end).await?;
+        while let Some(existing) = it.next().await? {
+          if &existing[start.len()..] != primary_key_value {
+            return Err(ExecError::UniqueConstraintViolation(name.to_string()).into());
+          }
+        }
+      }
+      txn.put(&key, primary_key_value).await?;
+    }
+    Ok(())
+  }
+}
+
+fn decode_primary_key(key: &SerializedVmValue, ty: &VmType<&str>) -> Result<PrimitiveValue> {
+  match key.decode(ty)? {
+    VmValue::Primitive(x) => Ok(x),
+    _ => Err(ExecError::NullPrimaryKey.into()),
+  }
+}
+
+/// Encodes a value loaded by `load_resident`, with tables as maps and sets as lists of their
+/// members.
+fn encode_loaded(v: &VmValue, config: &VmValueEncodeConfig) -> Result<SerializedVmValue> {
+  match v {
+    VmValue::Table(VmTableValue {
+      kind: VmTableValueKind::Fresh(fields),
+      ..
+    }) => Ok(SerializedVmValue::Tagged(TaggedVmValue::M(
+      fields
+        .iter()
+        .map(|(k, v)| encode_loaded(v, config).map(|x| (k.to_string(), x)))
+        .collect::<Result<_>>()?,
+    ))),
+    VmValue::Set(VmSetValue {
+      kind: VmSetValueKind::Fresh(members),
+      ..
+    }) => Ok(SerializedVmValue::Tagged(TaggedVmValue::L(
+      members
+        .values()
+        .map(|x| encode_loaded(x, config))
+        .collect::<Result<_>>()?,
+    ))),
+    _ => SerializedVmValue::encode(v, config),
+  }
+}
+
+/// Inverse of `encode_loaded`. Fields missing from maps are null.
+fn decode_stored<'a>(
+  schema: &'a CompiledSchema,
+  v: &SerializedVmValue,
+  ty: &'a FieldType,
+) -> Result<Arc<VmValue<'a>>> {
+  if let SerializedVmValue::Null(_) = v {
+    return Ok(Arc::new(VmValue::Null(VmType::from(ty))));
+  }
+  Ok(Arc::new(match ty {
+    FieldType::Primitive(_) | FieldType::List(_) | FieldType::Map(..) => {
+      v.decode(&VmType::from(ty))?
+    }
+    FieldType::Table(name) => {
+      let specialized_ty = schema.types.get(name).unwrap();
+      let m = match v {
+        SerializedVmValue::Tagged(TaggedVmValue::M(x)) => x,
+        _ => return Err(SerializeError::TypeMismatch.into()),
+      };
+      if let Some(k) = m
+        .keys()
+        .find(|k| !specialized_ty.fields.contains_key(k.as_str()))
+      {
+        return Err(VmValueError::FieldNotFound(k.clone(), name.to_string()).into());
+      }
+      let mut fields = BTreeMap::new();
+      for (field_name, (field_ty, _)) in &specialized_ty.fields {
+        let value = match m.get(&**field_name) {
+          Some(x) => decode_stored(schema, x, field_ty)?,
+          None => Arc::new(VmValue::Null(VmType::from(field_ty))),
+        };
+        fields.insert(&**field_name, value);
+      }
+      VmValue::Table(VmTableValue {
+        ty: &**name,
+        kind: VmTableValueKind::Fresh(fields),
+      })
+    }
+    FieldType::Set(member_ty) => {
+      let list = match v {
+        SerializedVmValue::Tagged(TaggedVmValue::L(x)) => x,
+        _ => return Err(SerializeError::TypeMismatch.into()),
+      };
+      let primary_key = VmType::<&'a str>::from(ty)
+        .set_primary_key(schema)
+        .ok_or_else(|| ExecError::NotSetOfTables(ty.to_string()))?;
+      let mut members = BTreeMap::new();
+      for x in list {
+        let member = decode_stored(schema, x, member_ty)?;
+        let primary_key_value = match &*member {
+          VmValue::Table(x) => primary_key
+            .value_of_fresh(x)
+            .ok_or_else(|| ExecError::NullPrimaryKey)?
+            .serialize_for_key_component()
+            .to_vec(),
+          _ => return Err(ExecError::NullPrimaryKey.into()),
+        };
+        members.insert(primary_key_value, member);
+      }
+      VmValue::Set(VmSetValue {
+        member_ty: VmType::from(&**member_ty),
+        kind: VmSetValueKind::Fresh(members),
+      })
+    }
+  }))
+}
+
+/// Encodes the value of the primitive field at `walker`. `@counter` fields are stored in the
+/// format of `KvTransaction::atomic_add`, and everything else as MessagePack.
+fn encode_primitive_field(walker: &PathWalker, x: &PrimitiveValue) -> Vec<u8> {
+  match x {
+    PrimitiveValue::Int64(x) if walker.node().counter => encode_counter(*x).to_vec(),
+    _ => rmp_serde::to_vec(x).unwrap(),
+  }
+}
+
+/// Inverse of `encode_primitive_field`.
+fn decode_primitive_field(walker: &PathWalker, x: &[u8]) -> Result<PrimitiveValue> {
+  if walker.node().counter {
+    Ok(PrimitiveValue::Int64(decode_counter(x)))
+  } else {
+    Ok(rmp_serde::from_slice(x)?)
+  }
+}
+
+/// Orders two primitive values of the same type. Returns `None` if either side is a NaN.
+/// Evaluates a node whose output only depends on its parameters, e.g. arithmetic and string
+/// operations. Returns `None` for other nodes. Shared by the executor and the optimizer, which folds
+/// nodes with constant parameters.
+pub(super) fn eval_pure_node<'a>(
+  n: &TwGraphNode,
+  params: &[Arc<VmValue<'a>>],
+) -> Result<Option<Arc<VmValue<'a>>>> {
+  Ok(match n {
+    TwGraphNode::Eq => Some(Arc::new(VmValue::Bool(values_eq(&params[0], &params[1])))),
+    TwGraphNode::Ne => Some(Arc::new(VmValue::Bool(!values_eq(&params[0], &params[1])))),
+    TwGraphNode::Lt | TwGraphNode::Le | TwGraphNode::Gt | TwGraphNode::Ge => {
+      let ord = compare_primitives(&params[0], &params[1]);
+      let res = match n {
+        TwGraphNode::Lt => ord == Some(Ordering::Less),
+        TwGraphNode::Le => matches!(ord, Some(Ordering::Less | Ordering::Equal)),
+        TwGraphNode::Gt => ord == Some(Ordering::Greater),
+        TwGraphNode::Ge => matches!(ord, Some(Ordering::Greater | Ordering::Equal)),
+        _ => unreachable!(),
+      };
+      Some(Arc::new(VmValue::Bool(res)))
+    }
+    TwGraphNode::And => Some(Arc::new(VmValue::Bool(
+      params[0].unwrap_bool() & params[1].unwrap_bool(),
+    ))),
+    TwGraphNode::Or => Some(Arc::new(VmValue::Bool(
+      params[0].unwrap_bool() | params[1].unwrap_bool(),
+    ))),
+    TwGraphNode::Not => Some(Arc::new(VmValue::Bool(!params[0].unwrap_bool()))),
+    TwGraphNode::IsNull => Some(Arc::new(VmValue::Bool(params[0].is_null()))),
+    TwGraphNode::Add => Some(Arc::new(match (&*params[0], &*params[1]) {
+      (
+        VmValue::Primitive(PrimitiveValue::Int64(l)),
+        VmValue::Primitive(PrimitiveValue::Int64(r)),
+      ) => VmValue::Primitive(PrimitiveValue::Int64(l.wrapping_add(*r))),
+      (
+        VmValue::Primitive(PrimitiveValue::Double(l)),
+        VmValue::Primitive(PrimitiveValue::Double(r)),
+      ) => VmValue::Primitive(PrimitiveValue::Double(
+        (f64::from_bits(*l) + f64::from_bits(*r)).to_bits(),
+      )),
+      (
+        VmValue::Primitive(PrimitiveValue::String(l)),
+        VmValue::Primitive(PrimitiveValue::String(r)),
+      ) => VmValue::Primitive(PrimitiveValue::String(format!("{}{}", l, r))),
+      _ => unreachable!(),
+    })),
+    TwGraphNode::Sub => Some(Arc::new(match (&*params[0], &*params[1]) {
+      (
+        VmValue::Primitive(PrimitiveValue::Int64(l)),
+        VmValue::Primitive(PrimitiveValue::Int64(r)),
+      ) => VmValue::Primitive(PrimitiveValue::Int64(l.wrapping_sub(*r))),
+      (
+        VmValue::Primitive(PrimitiveValue::Double(l)),
+        VmValue::Primitive(PrimitiveValue::Double(r)),
+      ) => VmValue::Primitive(PrimitiveValue::Double(
+        (f64::from_bits(*l) - f64::from_bits(*r)).to_bits(),
+      )),
+      _ => unreachable!(),
+    })),
+    TwGraphNode::Mul => Some(Arc::new(match (&*params[0], &*params[1]) {
+      (
+        VmValue::Primitive(PrimitiveValue::Int64(l)),
+        VmValue::Primitive(PrimitiveValue::Int64(r)),
+      ) => VmValue::Primitive(PrimitiveValue::Int64(l.wrapping_mul(*r))),
+      (
+        VmValue::Primitive(PrimitiveValue::Double(l)),
+        VmValue::Primitive(PrimitiveValue::Double(r)),
+      ) => VmValue::Primitive(PrimitiveValue::Double(
+        (f64::from_bits(*l) * f64::from_bits(*r)).to_bits(),
+      )),
+      _ => unreachable!(),
+    })),
+    TwGraphNode::Div => Some(Arc::new(match (&*params[0], &*params[1]) {
+      (
+        VmValue::Primitive(PrimitiveValue::Int64(l)),
+        VmValue::Primitive(PrimitiveValue::Int64(r)),
+      ) => {
+        if *r == 0 {
+          return Err(ExecError::DivisionByZero.into());
+        }
+        VmValue::Primitive(PrimitiveValue::Int64(l.wrapping_div(*r)))
+      }
+      (
+        VmValue::Primitive(PrimitiveValue::Double(l)),
+        VmValue::Primitive(PrimitiveValue::Double(r)),
+      ) => VmValue::Primitive(PrimitiveValue::Double(
+        (f64::from_bits(*l) / f64::from_bits(*r)).to_bits(),
+      )),
+      _ => unreachable!(),
+    })),
+    TwGraphNode::Mod => Some(Arc::new(match (&*params[0], &*params[1]) {
+      (
+        VmValue::Primitive(PrimitiveValue::Int64(l)),
+        VmValue::Primitive(PrimitiveValue::Int64(r)),
+      ) => {
+        if *r == 0 {
+          return Err(ExecError::DivisionByZero.into());
+        }
+        VmValue::Primitive(PrimitiveValue::Int64(l.wrapping_rem(*r)))
+      }
+      (
+        VmValue::Primitive(PrimitiveValue::Double(l)),
+        VmValue::Primitive(PrimitiveValue::Double(r)),
+      ) => VmValue::Primitive(PrimitiveValue::Double(
+        (f64::from_bits(*l) % f64::from_bits(*r)).to_bits(),
+      )),
+      _ => unreachable!(),
+    })),
+    TwGraphNode::Neg => Some(Arc::new(match &*params[0] {
+      VmValue::Primitive(PrimitiveValue::Int64(x)) => {
+        VmValue::Primitive(PrimitiveValue::Int64(x.wrapping_neg()))
+      }
+      VmValue::Primitive(PrimitiveValue::Double(x)) => {
+        VmValue::Primitive(PrimitiveValue::Double((-f64::from_bits(*x)).to_bits()))
+      }
+      _ => unreachable!(),
+    })),
+    TwGraphNode::ParseDouble => {
+      let x = unwrap_enum!(&*params[0], VmValue::Primitive(PrimitiveValue::String(x)) => x);
+      Some(Arc::new(match x.parse::<f64>() {
+        Ok(x) => VmValue::Primitive(PrimitiveValue::Double(x.to_bits())),
+        Err(_) => VmValue::Null(VmType::Primitive(PrimitiveType::Double)),
+      }))
+    }
+    TwGraphNode::FormatDouble => {
+      let x = unwrap_enum!(&*params[0], VmValue::Primitive(PrimitiveValue::Double(x)) => *x);
+      Some(Arc::new(VmValue::Primitive(PrimitiveValue::String(
+        f64::from_bits(x).to_string(),
+      ))))
+    }
+    TwGraphNode::StrStartsWith => {
+      let x = unwrap_enum!(&*params[0], VmValue::Primitive(PrimitiveValue::String(x)) => x);
+      let prefix = unwrap_enum!(&*params[1], VmValue::Primitive(PrimitiveValue::String(x)) => x);
+      Some(Arc::new(VmValue::Bool(x.starts_with(prefix.as_str()))))
+    }
+    TwGraphNode::StrContains => {
+      let x = unwrap_enum!(&*params[0], VmValue::Primitive(PrimitiveValue::String(x)) => x);
+      let needle = unwrap_enum!(&*params[1], VmValue::Primitive(PrimitiveValue::String(x)) => x);
+      Some(Arc::new(VmValue::Bool(x.contains(needle.as_str()))))
+    }
+    TwGraphNode::StrSubstring => {
+      let x = unwrap_enum!(&*params[0], VmValue::Primitive(PrimitiveValue::String(x)) => x);
+      let start = unwrap_enum!(&*params[1], VmValue::Primitive(PrimitiveValue::Int64(x)) => *x);
+      let len = unwrap_enum!(&*params[2], VmValue::Primitive(PrimitiveValue::Int64(x)) => *x);
+      let substring = x
+        .chars()
+        .skip(start.max(0) as usize)
+        .take(len.max(0) as usize)
+        .collect::<String>();
+      Some(Arc::new(VmValue::Primitive(PrimitiveValue::String(
+        substring,
+      ))))
+    }
+    TwGraphNode::StrToLower => {
+      let x = unwrap_enum!(&*params[0], VmValue::Primitive(PrimitiveValue::String(x)) => x);
+      Some(Arc::new(VmValue::Primitive(PrimitiveValue::String(
+        x.to_lowercase(),
+      ))))
+    }
+    TwGraphNode::StrLength => {
+      let x = unwrap_enum!(&*params[0], VmValue::Primitive(PrimitiveValue::String(x)) => x);
+      Some(Arc::new(VmValue::Primitive(PrimitiveValue::Int64(
+        x.chars().count() as i64,
+      ))))
+    }
+    TwGraphNode::BytesConcat => {
+      let l = unwrap_enum!(&*params[0], VmValue::Primitive(PrimitiveValue::Bytes(x)) => x);
+      let r = unwrap_enum!(&*params[1], VmValue::Primitive(PrimitiveValue::Bytes(x)) => x);
+      let mut x = Vec::with_capacity(l.len() + r.len());
+      x.extend_from_slice(l);
+      x.extend_from_slice(r);
+      Some(Arc::new(VmValue::Primitive(PrimitiveValue::Bytes(x))))
+    }
+    TwGraphNode::BytesSlice => {
+      let x = unwrap_enum!(&*params[0], VmValue::Primitive(PrimitiveValue::Bytes(x)) => x);
+      let start = unwrap_enum!(&*params[1], VmValue::Primitive(PrimitiveValue::Int64(x)) => *x);
+      let len = unwrap_enum!(&*params[2], VmValue::Primitive(PrimitiveValue::Int64(x)) => *x);
+      let start = (start.max(0) as u64).min(x.len() as u64) as usize;
+      let end = start + (len.max(0) as u64).min((x.len() - start) as u64) as usize;
+      Some(Arc::new(VmValue::Primitive(PrimitiveValue::Bytes(
+        x[start..end].to_vec(),
+      ))))
+    }
+    TwGraphNode::BytesLength => {
+      let x = unwrap_enum!(&*params[0], VmValue::Primitive(PrimitiveValue::Bytes(x)) => x);
+      Some(Arc::new(VmValue::Primitive(PrimitiveValue::Int64(
+        x.len() as i64
+      ))))
+    }
+    TwGraphNode::BytesToHex => {
+      let x = unwrap_enum!(&*params[0], VmValue::Primitive(PrimitiveValue::Bytes(x)) => x);
+      Some(Arc::new(VmValue::Primitive(PrimitiveValue::String(
+        hex::encode(x),
+      ))))
+    }
+    TwGraphNode::BytesFromHex => {
+      let x = unwrap_enum!(&*params[0], VmValue::Primitive(PrimitiveValue::String(x)) => x);
+      let x = hex::decode(x).map_err(|e| ExecError::InvalidBytesEncoding(e.to_string()))?;
+      Some(Arc::new(VmValue::Primitive(PrimitiveValue::Bytes(x))))
+    }
+    TwGraphNode::BytesToBase64 => {
+      let x = unwrap_enum!(&*params[0], VmValue::Primitive(PrimitiveValue::Bytes(x)) => x);
+      Some(Arc::new(VmValue::Primitive(PrimitiveValue::String(
+        base64::encode(x),
+      ))))
+    }
+    TwGraphNode::BytesFromBase64 => {
+      let x = unwrap_enum!(&*params[0], VmValue::Primitive(PrimitiveValue::String(x)) => x);
+      let x = base64::decode(x).map_err(|e| ExecError::InvalidBytesEncoding(e.to_string()))?;
+      Some(Arc::new(VmValue::Primitive(PrimitiveValue::Bytes(x))))
+    }
+    TwGraphNode::BytesToString => {
+      let x = unwrap_enum!(&*params[0], VmValue::Primitive(PrimitiveValue::Bytes(x)) => x);
+      let x =
+        String::from_utf8(x.clone()).map_err(|e| ExecError::InvalidBytesEncoding(e.to_string()))?;
+      Some(Arc::new(VmValue::Primitive(PrimitiveValue::String(x))))
+    }
+    TwGraphNode::BytesFromString => {
+      let x = unwrap_enum!(&*params[0], VmValue::Primitive(PrimitiveValue::String(x)) => x);
+      Some(Arc::new(VmValue::Primitive(PrimitiveValue::Bytes(
+        x.as_bytes().to_vec(),
+      ))))
+    }
+    TwGraphNode::TimeAddDays => {
+      let x = unwrap_enum!(&*params[0], VmValue::Primitive(PrimitiveValue::Int64(x)) => *x);
+      let days = unwrap_enum!(&*params[1], VmValue::Primitive(PrimitiveValue::Int64(x)) => *x);
+      Some(Arc::new(VmValue::Primitive(PrimitiveValue::Int64(
+        x.wrapping_add(days.wrapping_mul(MILLIS_PER_DAY)),
+      ))))
+    }
+    TwGraphNode::TimeStartOfDay => {
+      let x = unwrap_enum!(&*params[0], VmValue::Primitive(PrimitiveValue::Int64(x)) => *x);
+      Some(Arc::new(VmValue::Primitive(PrimitiveValue::Int64(
+        x - x.rem_euclid(MILLIS_PER_DAY),
+      ))))
+    }
+    TwGraphNode::TimeDayOfWeek => {
+      let x = unwrap_enum!(&*params[0], VmValue::Primitive(PrimitiveValue::Int64(x)) => *x);
+      // 1970-01-01 is a Thursday.
+      let days = x.div_euclid(MILLIS_PER_DAY);
+      Some(Arc::new(VmValue::Primitive(PrimitiveValue::Int64(
+        (days + 3).rem_euclid(7) + 1,
+      ))))
+    }
+    _ => None,
+  })
+}
+
+fn compare_primitives(left: &VmValue, right: &VmValue) -> Option<Ordering> {
+  match (left, right) {
+    (
+      VmValue::Primitive(PrimitiveValue::Int64(l)),
+      VmValue::Primitive(PrimitiveValue::Int64(r)),
+    ) => Some(l.cmp(r)),
+    (
+      VmValue::Primitive(PrimitiveValue::Double(l)),
+      VmValue::Primitive(PrimitiveValue::Double(r)),
+    ) => f64::from_bits(*l).partial_cmp(&f64::from_bits(*r)),
+    (
+      VmValue::Primitive(PrimitiveValue::String(l)),
+      VmValue::Primitive(PrimitiveValue::String(r)),
+    ) => Some(l.as_bytes().cmp(r.as_bytes())),
+    _ => unreachable!(),
+  }
+}
+
+/// The output of a `Cond` node, given those of its parameters that have fired, or `None` if it
+/// cannot fire yet.
+fn cond_output<'a>(
+  cond: Option<&Arc<VmValue<'a>>>,
+  then: Option<&Arc<VmValue<'a>>>,
+  otherwise: Option<&Arc<VmValue<'a>>>,
+  ty: &VmType<&'a str>,
+) -> Option<Arc<VmValue<'a>>> {
+  match &**cond? {
+    VmValue::Bool(true) => then.cloned(),
+    VmValue::Bool(false) => otherwise.cloned(),
+    VmValue::Null(_) => Some(Arc::new(VmValue::Null(ty.clone()))),
+    x => panic!("inconsistency detected: invalid condition: {:?}", x),
+  }
+}
+
+/// Structural equality, except that doubles compare numerically.
+fn values_eq(left: &VmValue, right: &VmValue) -> bool {
+  match (left, right) {
+    (
+      VmValue::Primitive(PrimitiveValue::Double(l)),
+      VmValue::Primitive(PrimitiveValue::Double(r)),
+    ) => f64::from_bits(*l) == f64::from_bits(*r),
+    (VmValue::Map(l), VmValue::Map(r)) => {
+      l.elements.size() == r.elements.size()
+        && l
+          .elements
+          .iter()
+          .zip(r.elements.iter())
+          .all(|((lk, lv), (rk, rv))| lk == rk && values_eq(lv, rv))
+    }
+    (VmValue::List(l), VmValue::List(r)) => {
+      l.member_ty == r.member_ty
+        && l.node.len() == r.node.len()
+        && l
+          .node
+          .iter()
+          .zip(r.node.iter())
+          .all(|(l, r)| values_eq(l, r))
+    }
+    _ => left == right,
+  }
+}
+
+fn is_conflict(e: &anyhow::Error) -> bool {
+  matches!(e.downcast_ref::<KvError>(), Some(KvError::Conflict))
+}
+
+fn current_millis() -> i64 {
+  SystemTime::now()
+    .duration_since(UNIX_EPOCH)
+    .map(|x| x.as_millis() as i64)
+    .unwrap_or(0)
+}
+
+/// The `n`-th ID derived from `seed`, as a version 4 UUID.
+///
+/// `n` only touches the low bits, which the version and variant fields leave alone, so different
+/// `n`s always give different IDs.
+fn derive_id(seed: &[u8; 16], n: u64) -> [u8; 16] {
+  let mut id = *seed;
+  let mut low = [0u8; 8];
+  low.copy_from_slice(&id[8..]);
+  let low = u64::from_be_bytes(low) ^ n;
+  id[8..].copy_from_slice(&low.to_be_bytes());
+  id[6] = (id[6] & 0x0f) | 0x40;
+  id[8] = (id[8] & 0x3f) | 0x80;
+  id
+}
+
+fn format_uuid(id: [u8; 16]) -> String {
+  format!(
+    "{}-{}-{}-{}-{}",
+    hex::encode(&id[0..4]),
+    hex::encode(&id[4..6]),
+    hex::encode(&id[6..8]),
+    hex::encode(&id[8..10]),
+    hex::encode(&id[10..16])
+  )
+}
+
+/// The smallest key that is greater than all keys prefixed with `prefix`.
+fn prefix_end(prefix: &[u8]) -> Vec<u8> {
+  let mut end_key = prefix.to_vec();
+  while end_key.last() == Some(&0xffu8) {
+    end_key.pop();
+  }
+  match end_key.last_mut() {
+    Some(x) => *x += 1,
+    None => end_key = vec![0xffu8; prefix.len() + 1],
+  }
+  end_key
+}
+
+/// The prefix of the keys scanned by `pass` on the set at `walker`.
+fn fsck_range_prefix(walker: &PathWalker, pass: FsckPass) -> Vec<u8> {
+  match pass {
+    FsckPass::Members => walker.set_fast_scan_prefix().unwrap(),
+    FsckPass::MemberData => walker.set_data_prefix().unwrap(),
+    FsckPass::Index => {
+      // The index entries of all fields. See `PathWalker::set_index_prefix`.
+      let mut key = walker.generate_key();
+      key.push(0x02);
+      key
+    }
+  }
+}
+
+fn fsck_issue(kind: FsckIssueKind, set: &FsckSet, key: Vec<u8>, repaired: bool) -> FsckIssue {
+  FsckIssue {
+    kind,
+    path: set.path.clone(),
+    key,
+    detail: String::new(),
+    repaired,
+  }
+}
+
+/// Whether `e` is from decoding a stored value.
+fn is_decode_error(e: &anyhow::Error) -> bool {
+  e.downcast_ref::<rmp_serde::decode::Error>().is_some()
+}
+
+fn generate_index_keys<'a>(
+  walker: &PathWalker<'a>,
+  primary_key_value: &[u8],
+  fields: &[(&'a str, Arc<VmValue<'a>>)],
+) -> Vec<(&'a str, Vec<u8>)> {
+  fields
+    .iter()
+    .filter_map(|(name, value)| match &**value {
+      VmValue::Primitive(x) => {
+        let mut key = walker.set_index_prefix(name).unwrap();
+        key.extend_from_slice(&x.serialize_for_self_delimiting_component());
+        key.push(0x00);
+        key.extend_from_slice(primary_key_value);
+        Some((*name, key))
+      }
+      _ => None,
+    })
+    .collect()
 }
 
 fn generate_fire_rules(g: &TwGraph) -> FireRuleTable {
@@ -891,27 +4681,64 @@ pub fn generate_root_map<'a>(
 ) -> Result<VmValue<'a>> {
   let mut m = RedBlackTreeMapSync::new_sync();
   for (field_name, field_ty) in &schema.exports {
-    match field_ty {
-      FieldType::Table(x) => {
-        m.insert_mut(
-          &**field_name,
-          Arc::new(VmValue::Table(VmTableValue {
-            ty: &**x,
-            kind: VmTableValueKind::Resident(PathWalker::from_export(plan, &**field_name).unwrap()),
-          })),
-        );
-      }
-      FieldType::Set(x) => {
-        m.insert_mut(
+    m.insert_mut(
+      &**field_name,
+      Arc::new(export_value(
+        field_ty,
+        PathWalker::from_export(plan, &**field_name)?,
+      )?),
+    );
+  }
+
+  // Mounted namespaces go under `__ns`, keyed by mount name.
+  if !schema.mounts.is_empty() {
+    let mut mounts = RedBlackTreeMapSync::new_sync();
+    for (i, (mount_name, exports)) in schema.mounts.iter().enumerate() {
+      let mount_plan = plan
+        .mounts
+        .get(mount_name)
+        .ok_or_else(|| MountError::MissingPlan(mount_name.to_string()))?;
+      let prefix = mount_key_prefix(i)?;
+      let mut mount = RedBlackTreeMapSync::new_sync();
+      for (field_name, field_ty) in exports {
+        mount.insert_mut(
           &**field_name,
-          Arc::new(VmValue::Set(VmSetValue {
-            member_ty: VmType::from(&**x),
-            kind: VmSetValueKind::Resident(PathWalker::from_export(plan, &**field_name).unwrap()),
-          })),
+          Arc::new(export_value(
+            field_ty,
+            PathWalker::from_mounted_export(mount_plan, &**field_name, &prefix)?,
+          )?),
         );
       }
-      _ => return Err(ExecError::ExportTypeNotSupported.into()),
+      mounts.insert_mut(
+        &**mount_name,
+        Arc::new(VmValue::Map(VmMapValue { elements: mount })),
+      );
     }
+    m.insert_mut(
+      MOUNT_ROOT_FIELD,
+      Arc::new(VmValue::Map(VmMapValue { elements: mounts })),
+    );
   }
   Ok(VmValue::Map(VmMapValue { elements: m }))
 }
+
+fn export_value<'a>(ty: &'a FieldType, walker: Arc<PathWalker<'a>>) -> Result<VmValue<'a>> {
+  match ty {
+    FieldType::Table(x) => Ok(VmValue::Table(VmTableValue {
+      ty: &**x,
+      kind: VmTableValueKind::Resident(walker),
+    })),
+    FieldType::Set(x) => Ok(VmValue::Set(VmSetValue {
+      member_ty: VmType::from(&**x),
+      kind: VmSetValueKind::Resident(walker),
+    })),
+    _ => Err(ExecError::ExportTypeNotSupported.into()),
+  }
+}
+
+fn resident_table_walker<'a, 'b>(table: &'b VmTableValue<'a>) -> Result<&'b Arc<PathWalker<'a>>> {
+  match &table.kind {
+    VmTableValueKind::Resident(walker) => Ok(walker),
+    VmTableValueKind::Fresh(_) => Err(ExecError::FreshTableOrSetNotSupported.into()),
+  }
+}
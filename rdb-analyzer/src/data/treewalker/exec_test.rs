@@ -1,23 +1,40 @@
-use std::sync::Arc;
+use std::sync::{
+  atomic::{AtomicUsize, Ordering},
+  Arc,
+};
 
+use anyhow::Result;
+use async_trait::async_trait;
 use bumpalo::Bump;
 
 use crate::{
   data::{
+    kv::{KeyValueStore, KvError, KvKeyIterator, KvTransaction},
+    pathwalker::{PathSegment, PathWalker},
     treewalker::{
+      asm::codegen::compile_twscript,
       bytecode::{TwGraph, TwGraphNode, TwScript},
-      exec::{generate_root_map, Executor},
-      typeck::GlobalTyckContext,
+      cursor::{CursorError, CursorReader, SetCursor},
+      exec::{
+        generate_root_map, Change, ChangeKind, ExecConfig, ExecError, Executor, FsckIssue,
+        FsckIssueKind, RetryPolicy, Scheduler,
+      },
+      serialize::SerializedVmValue,
+      typeck::{GlobalTyckContext, GlobalTypeInfo},
       vm::TwVm,
       vm_value::{VmConst, VmType},
     },
     value::PrimitiveValue,
   },
+  fixtures,
   schema::{
-    compile::{compile, PrimitiveType},
+    compile::{compile, CompiledSchema, PrimitiveType},
     grammar::parse,
   },
-  storage_plan::{planner::generate_plan_for_schema, StoragePlan},
+  storage_plan::{
+    planner::{assign_key_aliases, generate_plan_for_schema},
+    StoragePlan,
+  },
   test_util::create_kv,
 };
 
@@ -51,6 +68,7 @@ async fn basic_exec() {
     graphs: vec![TwGraph {
       name: "".into(),
       exported: false,
+      read_only: false,
       nodes: vec![
         (TwGraphNode::LoadParam(0), vec![], None),           // 0
         (TwGraphNode::GetField(0), vec![0], None),           // 1
@@ -65,9 +83,16 @@ async fn basic_exec() {
       ],
       output: Some(7),
       output_type: Some(1),
+      cache: None,
+      optional_chain: vec![],
+      deferred: vec![],
+      relaxed_isolation: false,
+      param_names: vec![],
       param_types: vec![0],
     }],
     entry: 0,
+    params: vec![],
+    mounts: vec![],
     consts: vec![VmConst::Primitive(PrimitiveValue::String(
       "test_name".into(),
     ))],
@@ -127,6 +152,7 @@ async fn basic_exec() {
     graphs: vec![TwGraph {
       name: "".into(),
       exported: false,
+      read_only: false,
       nodes: vec![
         (TwGraphNode::LoadParam(0), vec![], None), // 0
         (TwGraphNode::GetField(0), vec![0], None), // 1
@@ -134,9 +160,16 @@ async fn basic_exec() {
       ],
       output: Some(2),
       output_type: Some(1),
+      cache: None,
+      optional_chain: vec![],
+      deferred: vec![],
+      relaxed_isolation: false,
+      param_names: vec![],
       param_types: vec![0],
     }],
     entry: 0,
+    params: vec![],
+    mounts: vec![],
     consts: vec![],
     idents: vec!["some_item".into(), "name".into()],
     types: vec![VmType::Schema, VmType::Primitive(PrimitiveType::String)],
@@ -183,6 +216,7 @@ async fn set_queries() {
     graphs: vec![TwGraph {
       name: "".into(),
       exported: false,
+      read_only: false,
       nodes: vec![
         (TwGraphNode::LoadParam(0), vec![], None),         // 0
         (TwGraphNode::LoadConst(0), vec![], None),         // 1
@@ -196,9 +230,16 @@ async fn set_queries() {
       ],
       output: None,
       output_type: None,
+      cache: None,
+      optional_chain: vec![],
+      deferred: vec![],
+      relaxed_isolation: false,
+      param_names: vec![],
       param_types: vec![0],
     }],
     entry: 0,
+    params: vec![],
+    mounts: vec![],
     consts: vec![
       VmConst::Primitive(PrimitiveValue::String("test_id".into())),
       VmConst::Primitive(PrimitiveValue::String("test_name".into())),
@@ -225,6 +266,7 @@ async fn set_queries() {
     graphs: vec![TwGraph {
       name: "".into(),
       exported: false,
+      read_only: false,
       nodes: vec![
         (TwGraphNode::LoadParam(0), vec![], None),      // 0
         (TwGraphNode::LoadConst(0), vec![], None),      // 1
@@ -234,9 +276,16 @@ async fn set_queries() {
       ],
       output: Some(4),
       output_type: Some(1),
+      cache: None,
+      optional_chain: vec![],
+      deferred: vec![],
+      relaxed_isolation: false,
+      param_names: vec![],
       param_types: vec![0],
     }],
     entry: 0,
+    params: vec![],
+    mounts: vec![],
     consts: vec![VmConst::Primitive(PrimitiveValue::String("test_id".into()))],
     idents: vec!["some_item".into(), "name".into()],
     types: vec![VmType::Schema, VmType::Primitive(PrimitiveType::String)],
@@ -258,6 +307,7 @@ async fn set_queries() {
     graphs: vec![TwGraph {
       name: "".into(),
       exported: false,
+      read_only: false,
       nodes: vec![
         (TwGraphNode::LoadParam(0), vec![], None),      // 0
         (TwGraphNode::LoadConst(0), vec![], None),      // 1
@@ -266,9 +316,16 @@ async fn set_queries() {
       ],
       output: None,
       output_type: None,
+      cache: None,
+      optional_chain: vec![],
+      deferred: vec![],
+      relaxed_isolation: false,
+      param_names: vec![],
       param_types: vec![0],
     }],
     entry: 0,
+    params: vec![],
+    mounts: vec![],
     consts: vec![VmConst::Primitive(PrimitiveValue::String("test_id".into()))],
     idents: vec!["some_item".into(), "name".into()],
     types: vec![
@@ -288,6 +345,7 @@ async fn set_queries() {
     graphs: vec![TwGraph {
       name: "".into(),
       exported: false,
+      read_only: false,
       nodes: vec![
         (TwGraphNode::LoadParam(0), vec![], None),      // 0
         (TwGraphNode::LoadConst(0), vec![], None),      // 1
@@ -297,9 +355,16 @@ async fn set_queries() {
       ],
       output: Some(4),
       output_type: Some(1),
+      cache: None,
+      optional_chain: vec![],
+      deferred: vec![],
+      relaxed_isolation: false,
+      param_names: vec![],
       param_types: vec![0],
     }],
     entry: 0,
+    params: vec![],
+    mounts: vec![],
     consts: vec![VmConst::Primitive(PrimitiveValue::String("test_id".into()))],
     idents: vec!["some_item".into(), "name".into()],
     types: vec![VmType::Schema, VmType::Primitive(PrimitiveType::String)],
@@ -317,3 +382,1929 @@ async fn set_queries() {
     _ => unreachable!(),
   };
 }
+
+#[tokio::test]
+async fn index_maintenance() {
+  let _ = pretty_env_logger::try_init();
+  let fixture = fixtures::get("indexed").unwrap();
+  let (schema, plan) = fixture.compile_schema_with_plan().unwrap();
+  let kv = create_kv();
+
+  for name in ["insert", "update", "delete_and_replace"].iter() {
+    let script = fixture.compile_script(name).unwrap();
+    let vm = TwVm::new(&schema, &plan, &script).unwrap();
+    let type_info = GlobalTyckContext::new(&vm).unwrap().typeck().unwrap();
+    let mut executor = Executor::new(&vm, &*kv, &type_info);
+    executor
+      .run_graph(0, &[Arc::new(generate_root_map(&schema, &plan).unwrap())])
+      .await
+      .unwrap();
+  }
+
+  let walker = PathWalker::from_export(&plan, "items").unwrap();
+  let txn = kv.begin_transaction().await.unwrap();
+  for (field, expected) in [
+    (
+      "name",
+      vec![
+        (PrimitiveValue::String("w".into()), "c"),
+        (PrimitiveValue::String("z".into()), "a"),
+      ],
+    ),
+    (
+      "code",
+      vec![
+        (PrimitiveValue::Int64(1), "a"),
+        (PrimitiveValue::Int64(3), "c"),
+      ],
+    ),
+  ]
+  .iter()
+  {
+    let prefix = walker.set_index_prefix(field).unwrap();
+    let mut end = prefix.clone();
+    end.push(0xff);
+    let mut it = txn.scan_keys(&prefix, &end).await.unwrap();
+    let mut keys = vec![];
+    while let Some(k) = it.next().await.unwrap() {
+      keys.push(k);
+    }
+    let expected = expected
+      .iter()
+      .map(|(value, id)| {
+        let mut k = prefix.clone();
+        k.extend_from_slice(&value.serialize_for_self_delimiting_component());
+        k.push(0x00);
+        k.extend_from_slice(&PrimitiveValue::String(id.to_string()).serialize_for_key_component());
+        k
+      })
+      .collect::<Vec<_>>();
+    assert_eq!(keys, expected);
+  }
+}
+
+#[tokio::test]
+async fn change_tracking() {
+  let _ = pretty_env_logger::try_init();
+  let fixture = fixtures::get("indexed").unwrap();
+  let (schema, plan) = fixture.compile_schema_with_plan().unwrap();
+  let kv = create_kv();
+  let items = vec![PathSegment::Field("items".into())];
+  let item = |id: &str| {
+    let mut path = items.clone();
+    path.push(PathSegment::Member(PrimitiveValue::String(id.into())));
+    path
+  };
+  let key = |id: &str| PrimitiveValue::String(id.into());
+
+  for (name, expected) in [
+    (
+      "insert",
+      vec![
+        ChangeKind::Insert(key("a")),
+        ChangeKind::Insert(key("b")),
+        ChangeKind::Insert(key("c")),
+      ]
+      .into_iter()
+      .map(|kind| Change {
+        path: items.clone(),
+        kind,
+      })
+      .collect::<Vec<_>>(),
+    ),
+    (
+      "update",
+      vec![Change {
+        path: item("a"),
+        kind: ChangeKind::SetField("name".into()),
+      }],
+    ),
+    (
+      "delete_and_replace",
+      vec![
+        Change {
+          path: items.clone(),
+          kind: ChangeKind::Delete(key("b")),
+        },
+        Change {
+          path: items.clone(),
+          kind: ChangeKind::Update(key("c")),
+        },
+      ],
+    ),
+  ]
+  .iter()
+  {
+    let script = fixture.compile_script(name).unwrap();
+    let vm = TwVm::new(&schema, &plan, &script).unwrap();
+    let type_info = GlobalTyckContext::new(&vm).unwrap().typeck().unwrap();
+    let mut executor = Executor::new(&vm, &*kv, &type_info);
+    executor.enable_change_tracking();
+    executor
+      .run_graph(0, &[Arc::new(generate_root_map(&schema, &plan).unwrap())])
+      .await
+      .unwrap();
+
+    // Nodes without dependencies between them fire in any order.
+    let mut changes = executor.take_changes().unwrap();
+    changes.sort_by_key(|x| format!("{:?}", x));
+    assert_eq!(&changes, expected);
+    assert!(executor.take_changes().unwrap().is_empty());
+  }
+}
+
+#[tokio::test]
+async fn export_import_rows() {
+  let _ = pretty_env_logger::try_init();
+  let alloc = Bump::new();
+  let ast = parse(
+    &alloc,
+    r#"
+  type Item {
+    @primary
+    id: string,
+    @index
+    v: int64,
+  }
+  type Config {
+    name: string,
+    tags: set<Item>,
+  }
+  export set<Item> items;
+  export Config config;
+  "#,
+  )
+  .unwrap();
+  let schema = compile(&ast).unwrap();
+  drop(ast);
+  drop(alloc);
+  let plan = generate_plan_for_schema(&Default::default(), &Default::default(), &schema).unwrap();
+  let kv = create_kv();
+  let script = TwScript::default();
+  let vm = TwVm::new(&schema, &plan, &script).unwrap();
+  let type_info = GlobalTyckContext::new(&vm).unwrap().typeck().unwrap();
+  let executor = Executor::new(&vm, &*kv, &type_info);
+  let rows =
+    |x: serde_json::Value| -> Vec<SerializedVmValue> { serde_json::from_value(x).unwrap() };
+
+  let items = rows(serde_json::json!([
+    { "M": { "id": "c", "v": "3" } },
+    { "M": { "id": "a", "v": "1" } },
+    { "M": { "id": "b" } },
+  ]));
+  executor.import_rows("items", &items, 2).await.unwrap();
+  let config = rows(serde_json::json!([
+    { "M": { "name": "x", "tags": { "L": [{ "M": { "id": "t", "v": "0" } }] } } },
+  ]));
+  executor.import_rows("config", &config, 100).await.unwrap();
+
+  let mut exported = vec![];
+  let mut after = None;
+  loop {
+    let page = executor
+      .export_rows("items", after.as_ref(), 2, &Default::default())
+      .await
+      .unwrap();
+    exported.extend(page.rows);
+    after = match page.next {
+      Some(x) => Some(x),
+      None => break,
+    };
+  }
+  assert_eq!(
+    serde_json::to_value(&exported).unwrap(),
+    serde_json::json!([
+      { "M": { "id": "a", "v": "1" } },
+      { "M": { "id": "b", "v": null } },
+      { "M": { "id": "c", "v": "3" } },
+    ])
+  );
+  let page = executor
+    .export_rows("config", None, 2, &Default::default())
+    .await
+    .unwrap();
+  assert!(page.next.is_none());
+  assert_eq!(
+    serde_json::to_value(&page.rows).unwrap(),
+    serde_json::json!([
+      { "M": { "name": "x", "tags": { "L": [{ "M": { "id": "t", "v": "0" } }] } } },
+    ])
+  );
+  assert!(executor
+    .export_rows("items.v", None, 2, &Default::default())
+    .await
+    .is_err());
+
+  // Invalid rows are rejected before anything is written.
+  for (i, bad) in [
+    serde_json::json!([{ "M": { "id": "d" } }, { "M": { "id": "e", "w": "1" } }]),
+    serde_json::json!([{ "M": { "id": "d" } }, { "M": { "v": "1" } }]),
+    serde_json::json!([{ "M": { "id": "d" } }, { "M": { "id": "e", "v": true } }]),
+  ]
+  .iter()
+  .enumerate()
+  {
+    match executor
+      .import_rows("items", &rows(bad.clone()), 1)
+      .await
+      .unwrap_err()
+      .downcast_ref::<ExecError>()
+    {
+      Some(ExecError::InvalidRow(1, _)) => {}
+      x => panic!("unexpected error for case {}: {:?}", i, x),
+    }
+  }
+  let page = executor
+    .export_rows("items", None, 100, &Default::default())
+    .await
+    .unwrap();
+  assert_eq!(page.rows.len(), 3);
+}
+
+#[tokio::test]
+async fn set_cursor() {
+  let _ = pretty_env_logger::try_init();
+  let alloc = Bump::new();
+  let ast = parse(
+    &alloc,
+    r#"
+  type Item {
+    @primary
+    id: string,
+    v: int64,
+    @acl("admin")
+    secret: string,
+  }
+  export set<Item> items;
+  export set<Item> others;
+  "#,
+  )
+  .unwrap();
+  let schema = compile(&ast).unwrap();
+  drop(ast);
+  drop(alloc);
+  let plan = generate_plan_for_schema(&Default::default(), &Default::default(), &schema).unwrap();
+  let kv = create_kv();
+  let script = compile_twscript(
+    r#"
+    graph main(root: schema): set<Item> {
+      return root.items;
+    }
+    graph one(root: schema): map { id: string } {
+      return m_insert(id) "a" create_map;
+    }
+    graph others(root: schema): set<Item> {
+      return root.others;
+    }
+    "#,
+  )
+  .unwrap();
+  let vm = TwVm::new(&schema, &plan, &script).unwrap();
+  let type_info = GlobalTyckContext::new(&vm).unwrap().typeck().unwrap();
+  let mut executor = Executor::new(&vm, &*kv, &type_info);
+  let rows: Vec<SerializedVmValue> = serde_json::from_value(serde_json::json!([
+    { "M": { "id": "c", "v": "3", "secret": "z" } },
+    { "M": { "id": "a", "v": "1", "secret": "x" } },
+    { "M": { "id": "b", "v": "2", "secret": "y" } },
+  ]))
+  .unwrap();
+  executor.import_rows("items", &rows, 100).await.unwrap();
+
+  let root = Arc::new(generate_root_map(&schema, &plan).unwrap());
+  let output = executor
+    .run_graph(0, &[root.clone()])
+    .await
+    .unwrap()
+    .unwrap();
+  let cursor = SetCursor::from_value(&output).unwrap();
+
+  let mut members = vec![];
+  let mut after = None;
+  loop {
+    let page = executor
+      .read_cursor_page(
+        &cursor,
+        after.as_deref(),
+        2,
+        &Default::default(),
+        CursorReader::Role(None),
+      )
+      .await
+      .unwrap();
+    members.extend(page.members);
+    after = match page.next {
+      Some(x) => Some(x),
+      None => break,
+    };
+  }
+  assert_eq!(
+    serde_json::to_value(&members).unwrap(),
+    serde_json::json!([
+      { "M": { "id": "a", "v": "1" } },
+      { "M": { "id": "b", "v": "2" } },
+      { "M": { "id": "c", "v": "3" } },
+    ])
+  );
+
+  let page = executor
+    .read_cursor_page(
+      &cursor,
+      None,
+      1,
+      &Default::default(),
+      CursorReader::Role(Some("admin")),
+    )
+    .await
+    .unwrap();
+  assert_eq!(
+    serde_json::to_value(&page.members).unwrap(),
+    serde_json::json!([{ "M": { "id": "a", "v": "1", "secret": "x" } }])
+  );
+  let page = executor
+    .read_cursor_page(
+      &cursor,
+      page.next.as_deref(),
+      1,
+      &Default::default(),
+      CursorReader::Trusted,
+    )
+    .await
+    .unwrap();
+  assert_eq!(
+    serde_json::to_value(&page.members).unwrap(),
+    serde_json::json!([{ "M": { "id": "b", "v": "2", "secret": "y" } }])
+  );
+
+  assert!(executor
+    .read_cursor_page(
+      &cursor,
+      Some("!"),
+      1,
+      &Default::default(),
+      CursorReader::Trusted
+    )
+    .await
+    .is_err());
+
+  // Tokens are bound to the set they were returned for.
+  let other_output = executor
+    .run_graph(2, &[root.clone()])
+    .await
+    .unwrap()
+    .unwrap();
+  let other_cursor = SetCursor::from_value(&other_output).unwrap();
+  let err = executor
+    .read_cursor_page(
+      &other_cursor,
+      page.next.as_deref(),
+      1,
+      &Default::default(),
+      CursorReader::Trusted,
+    )
+    .await
+    .unwrap_err();
+  assert!(matches!(
+    err.downcast::<CursorError>().unwrap(),
+    CursorError::InvalidToken
+  ));
+
+  let output = executor.run_graph(1, &[root]).await.unwrap().unwrap();
+  assert!(SetCursor::from_value(&output).is_none());
+}
+
+#[tokio::test]
+async fn fsck() {
+  let _ = pretty_env_logger::try_init();
+  let alloc = Bump::new();
+  let ast = parse(
+    &alloc,
+    r#"
+  type Item {
+    @primary
+    id: string,
+    @index
+    v: int64,
+  }
+  type Config {
+    name: string,
+    tags: set<Item>,
+  }
+  export set<Item> items;
+  export Config config;
+  "#,
+  )
+  .unwrap();
+  let schema = compile(&ast).unwrap();
+  drop(ast);
+  drop(alloc);
+  let plan = generate_plan_for_schema(&Default::default(), &Default::default(), &schema).unwrap();
+  let kv = create_kv();
+  let script = TwScript::default();
+  let vm = TwVm::new(&schema, &plan, &script).unwrap();
+  let type_info = GlobalTyckContext::new(&vm).unwrap().typeck().unwrap();
+  let executor = Executor::new(&vm, &*kv, &type_info);
+  let rows =
+    |x: serde_json::Value| -> Vec<SerializedVmValue> { serde_json::from_value(x).unwrap() };
+  executor
+    .import_rows(
+      "items",
+      &rows(serde_json::json!([
+        { "M": { "id": "a", "v": "1" } },
+        { "M": { "id": "b", "v": "2" } },
+        { "M": { "id": "c", "v": "3" } },
+      ])),
+      100,
+    )
+    .await
+    .unwrap();
+  executor
+    .import_rows(
+      "config",
+      &rows(serde_json::json!([
+        { "M": { "name": "x", "tags": { "L": [{ "M": { "id": "t", "v": "0" } }] } } },
+      ])),
+      100,
+    )
+    .await
+    .unwrap();
+
+  let report = executor.fsck(2, false, false).await.unwrap();
+  assert_eq!(report.num_members, 4);
+  assert!(report.issues.is_empty(), "{:?}", report.issues);
+
+  let items = PathWalker::from_export(&plan, "items").unwrap();
+  let member_key = |id: &str| PrimitiveValue::String(id.to_string()).serialize_for_key_component();
+  let fast_scan_key = |id: &str| {
+    let mut key = items.set_fast_scan_prefix().unwrap();
+    key.extend_from_slice(&member_key(id));
+    key
+  };
+  let index_key = |id: &str, v: i64| {
+    let mut key = items.set_index_prefix("v").unwrap();
+    key.extend_from_slice(&PrimitiveValue::Int64(v).serialize_for_self_delimiting_component());
+    key.push(0x00);
+    key.extend_from_slice(&member_key(id));
+    key
+  };
+  let field_key = |id: &str, field: &str| {
+    items
+      .enter_set(&PrimitiveValue::String(id.to_string()))
+      .unwrap()
+      .enter_field(field)
+      .unwrap()
+      .generate_key()
+  };
+
+  let txn = kv.begin_transaction().await.unwrap();
+  let mut data_prefix = items.set_data_prefix().unwrap();
+  data_prefix.extend_from_slice(&member_key("a"));
+  data_prefix.push(0x00);
+  let mut data_end = data_prefix.clone();
+  data_end.push(0xff);
+  let mut orphan_data_keys = vec![];
+  let mut it = txn.scan_keys(&data_prefix, &data_end).await.unwrap();
+  while let Some(k) = it.next().await.unwrap() {
+    orphan_data_keys.push(k);
+  }
+  drop(it);
+  drop(txn);
+  assert!(orphan_data_keys.contains(&field_key("a", "v")));
+
+  // `a` loses its fast scan entry, `b` can't be decoded, `c` loses its index entry, and `z` has
+  // no data.
+  let txn = kv.begin_transaction().await.unwrap();
+  txn.delete(&fast_scan_key("a")).await.unwrap();
+  txn.put(&field_key("b", "v"), &[0xc1]).await.unwrap();
+  txn.delete(&index_key("c", 3)).await.unwrap();
+  txn.put(&fast_scan_key("z"), &[]).await.unwrap();
+  txn.put(b"orphan", b"").await.unwrap();
+  txn.commit().await.unwrap();
+
+  let mut expected = vec![
+    (FsckIssueKind::UndecodableMember, fast_scan_key("b")),
+    (FsckIssueKind::MissingIndexEntry, index_key("c", 3)),
+    (FsckIssueKind::MissingMemberData, fast_scan_key("z")),
+    (FsckIssueKind::DanglingIndexEntry, index_key("a", 1)),
+    (FsckIssueKind::OrphanKey, b"orphan".to_vec()),
+  ];
+  expected.extend(
+    orphan_data_keys
+      .into_iter()
+      .map(|x| (FsckIssueKind::OrphanMemberData, x)),
+  );
+  expected.sort_by(|a, b| a.1.cmp(&b.1));
+  let summarize = |issues: &[FsckIssue]| -> Vec<(FsckIssueKind, Vec<u8>)> {
+    let mut out: Vec<_> = issues.iter().map(|x| (x.kind, x.key.clone())).collect();
+    out.sort_by(|a, b| a.1.cmp(&b.1));
+    out
+  };
+
+  let report = executor.fsck(2, false, false).await.unwrap();
+  assert_eq!(summarize(&report.issues), expected);
+  assert!(report.issues.iter().all(|x| !x.repaired));
+  for issue in &report.issues {
+    match issue.kind {
+      FsckIssueKind::OrphanKey => assert_eq!(issue.path, ""),
+      FsckIssueKind::UndecodableMember => assert!(!issue.detail.is_empty()),
+      _ => assert_eq!(issue.path, "items"),
+    }
+  }
+
+  let report = executor.fsck(2, true, false).await.unwrap();
+  assert_eq!(summarize(&report.issues), expected);
+  assert_eq!(
+    report
+      .issues
+      .iter()
+      .filter(|x| !x.repaired)
+      .map(|x| x.kind)
+      .collect::<Vec<_>>(),
+    vec![FsckIssueKind::UndecodableMember, FsckIssueKind::OrphanKey]
+  );
+
+  // Keys outside of every export are only deleted when asked for.
+  let report = executor.fsck(2, false, true).await.unwrap();
+  assert_eq!(
+    report
+      .issues
+      .iter()
+      .map(|x| (x.kind, x.repaired))
+      .collect::<Vec<_>>(),
+    vec![
+      (FsckIssueKind::UndecodableMember, false),
+      (FsckIssueKind::OrphanKey, true)
+    ]
+  );
+
+  // Only the member that can't be decoded is left.
+  let report = executor.fsck(2, true, true).await.unwrap();
+  assert_eq!(report.num_members, 3);
+  assert_eq!(
+    summarize(&report.issues),
+    vec![(FsckIssueKind::UndecodableMember, fast_scan_key("b"))]
+  );
+  let txn = kv.begin_transaction().await.unwrap();
+  assert_eq!(
+    txn.get(&index_key("c", 3)).await.unwrap(),
+    Some(member_key("c").to_vec())
+  );
+}
+
+#[tokio::test]
+async fn unique_constraint() {
+  let _ = pretty_env_logger::try_init();
+  let alloc = Bump::new();
+  let ast = parse(
+    &alloc,
+    r#"
+  type Item {
+    @primary
+    id: string,
+    @unique
+    code: int64,
+  }
+  export set<Item> items;
+  "#,
+  )
+  .unwrap();
+  let schema = compile(&ast).unwrap();
+  drop(ast);
+  drop(alloc);
+  let plan = generate_plan_for_schema(&Default::default(), &Default::default(), &schema).unwrap();
+  let kv = create_kv();
+
+  for (code, violation) in [
+    (
+      r#"
+    graph main(root: schema): bool {
+      s_insert root.items $ build_table(Item) $ m_insert(id) "a" $ m_insert(code) 1 create_map;
+      s_insert root.items $ build_table(Item) $ m_insert(id) "b" $ m_insert(code) 2 create_map;
+      return is_present $ point_get root.items "c";
+    }
+    "#,
+      false,
+    ),
+    (
+      r#"
+    graph main(root: schema): bool {
+      s_insert root.items $ build_table(Item) $ m_insert(id) "c" $ m_insert(code) 1 create_map;
+      return true;
+    }
+    "#,
+      true,
+    ),
+    (
+      r#"
+    graph main(root: schema): bool {
+      t_insert(code) (point_get root.items "b") 1;
+      return true;
+    }
+    "#,
+      true,
+    ),
+    (
+      r#"
+    graph main(root: schema): bool {
+      s_insert root.items $ build_table(Item) $ m_insert(id) "a" $ m_insert(code) 1 create_map;
+      t_insert(code) (point_get root.items "b") 3;
+      return is_present $ point_get root.items "c";
+    }
+    "#,
+      false,
+    ),
+  ]
+  .iter()
+  {
+    let script = compile_twscript(code).unwrap();
+    let vm = TwVm::new(&schema, &plan, &script).unwrap();
+    let type_info = GlobalTyckContext::new(&vm).unwrap().typeck().unwrap();
+    let mut executor = Executor::new(&vm, &*kv, &type_info);
+    let output = executor
+      .run_graph(0, &[Arc::new(generate_root_map(&schema, &plan).unwrap())])
+      .await;
+    if *violation {
+      match output.unwrap_err().downcast_ref::<ExecError>() {
+        Some(ExecError::UniqueConstraintViolation(field)) => assert_eq!(field, "code"),
+        x => panic!("unexpected error: {:?}", x),
+      }
+    } else {
+      // The failed insertion of "c" must not be committed.
+      let output = output.unwrap().unwrap();
+      assert!(matches!(&*output, VmValue::Bool(false)));
+    }
+  }
+}
+
+#[tokio::test]
+async fn unique_strings_with_nul() {
+  let _ = pretty_env_logger::try_init();
+  let alloc = Bump::new();
+  let ast = parse(
+    &alloc,
+    r#"
+  type Item {
+    @primary
+    id: string,
+    @unique
+    name: string,
+  }
+  export set<Item> items;
+  "#,
+  )
+  .unwrap();
+  let schema = compile(&ast).unwrap();
+  drop(ast);
+  drop(alloc);
+  let plan = generate_plan_for_schema(&Default::default(), &Default::default(), &schema).unwrap();
+  let kv = create_kv();
+
+  let script = compile_twscript(
+    r#"
+    graph main(root: schema, id: string, name: string) {
+      s_insert root.items $ build_table(Item) $ m_insert(id) id $ m_insert(name) name create_map;
+    }
+    "#,
+  )
+  .unwrap();
+  let vm = TwVm::new(&schema, &plan, &script).unwrap();
+  let type_info = GlobalTyckContext::new(&vm).unwrap().typeck().unwrap();
+  let root = Arc::new(generate_root_map(&schema, &plan).unwrap());
+  let string = |x: &str| Arc::new(VmValue::Primitive(PrimitiveValue::String(x.into())));
+
+  // "a\0b" must neither take "a" nor sort between the entries of "a".
+  for (id, name) in [("x", "a\0b"), ("y", "a"), ("z", "ab")] {
+    Executor::new(&vm, &*kv, &type_info)
+      .run_graph(0, &[root.clone(), string(id), string(name)])
+      .await
+      .unwrap();
+  }
+  let err = Executor::new(&vm, &*kv, &type_info)
+    .run_graph(0, &[root.clone(), string("w"), string("a\0b")])
+    .await
+    .unwrap_err();
+  assert!(matches!(
+    err.downcast_ref::<ExecError>(),
+    Some(ExecError::UniqueConstraintViolation(_))
+  ));
+
+  let walker = PathWalker::from_export(&plan, "items").unwrap();
+  let prefix = walker.set_index_prefix("name").unwrap();
+  let mut end = prefix.clone();
+  end.push(0xff);
+  let txn = kv.begin_transaction().await.unwrap();
+  let mut it = txn.scan_keys(&prefix, &end).await.unwrap();
+  let mut primary_keys = vec![];
+  while let Some(k) = it.next().await.unwrap() {
+    primary_keys.push(txn.get(&k).await.unwrap().unwrap());
+  }
+  assert_eq!(
+    primary_keys,
+    ["y", "x", "z"]
+      .iter()
+      .map(|x| PrimitiveValue::String(x.to_string())
+        .serialize_for_key_component()
+        .to_vec())
+      .collect::<Vec<_>>()
+  );
+}
+
+#[tokio::test]
+async fn migrate_to_key_aliases() {
+  let _ = pretty_env_logger::try_init();
+  let alloc = Bump::new();
+  let ast = parse(
+    &alloc,
+    r#"
+  type Item {
+    @primary
+    id: string,
+    @index
+    name: string,
+    @unique
+    code: int64,
+    tags: set<Tag>,
+  }
+  type Tag {
+    @primary
+    value: string,
+  }
+  type Config {
+    title: string,
+    tags: set<Tag>,
+  }
+  export set<Item> items;
+  export Config config;
+  "#,
+  )
+  .unwrap();
+  let schema = compile(&ast).unwrap();
+  drop(ast);
+  drop(alloc);
+  let plan = generate_plan_for_schema(&Default::default(), &Default::default(), &schema).unwrap();
+  let mut aliased_plan = plan.clone();
+  assign_key_aliases(&mut aliased_plan).unwrap();
+  let kv = create_kv();
+
+  let write_script = compile_twscript(
+    r#"
+    graph main(root: schema) {
+      s_insert root.items $ build_table(Item) $ m_insert(id) "a" $ m_insert(name) "x" $ m_insert(code) 1 $
+        m_insert(tags) empty_set<Tag> create_map;
+      s_insert root.items $ build_table(Item) $ m_insert(id) "b" $ m_insert(name) "y" $ m_insert(code) 2 create_map;
+      s_insert root.items $ build_table(Item) $ m_insert(id) "c" $ m_insert(name) "y" create_map;
+      t_insert(title) root.config "hello";
+      s_insert root.config.tags $ build_table(Tag) $ m_insert(value) "t2" create_map;
+    }
+    "#,
+  )
+  .unwrap();
+  let write_nested_script = compile_twscript(
+    r#"
+    graph main(root: schema) {
+      s_insert (point_get root.items "a").tags $ build_table(Tag) $ m_insert(value) "t1" create_map;
+    }
+    "#,
+  )
+  .unwrap();
+  let read_script = compile_twscript(
+    r#"
+    type ItemMap = map {
+      name: string,
+      code: int64,
+      tag: string,
+    };
+    graph main(root: schema): map {
+      a: ItemMap,
+      b: ItemMap,
+      c: ItemMap,
+      title: string,
+      config_tag: string,
+      count: int64,
+    } {
+      return m_insert(a) (call(item_map) [point_get root.items "a"])
+        $ m_insert(b) (call(item_map) [point_get root.items "b"])
+        $ m_insert(c) (call(item_map) [point_get root.items "c"])
+        $ m_insert(title) root.config.title
+        $ m_insert(config_tag) (point_get root.config.tags "t2").value
+        $ m_insert(count) (s_count root.items)
+        create_map;
+    }
+    graph item_map(item: Item): ItemMap {
+      return m_insert(name) item.name
+        $ m_insert(code) item.code
+        $ m_insert(tag) (point_get item.tags "t1").value
+        create_map;
+    }
+    "#,
+  )
+  .unwrap();
+
+  for script in [&write_script, &write_nested_script] {
+    let vm = TwVm::new(&schema, &plan, script).unwrap();
+    let type_info = GlobalTyckContext::new(&vm).unwrap().typeck().unwrap();
+    let mut executor = Executor::new(&vm, &*kv, &type_info);
+    executor
+      .run_graph(0, &[Arc::new(generate_root_map(&schema, &plan).unwrap())])
+      .await
+      .unwrap();
+  }
+
+  let before = run_read_script(&schema, &plan, &read_script, &*kv).await;
+  assert!(before.contains("hello"));
+
+  let vm = TwVm::new(&schema, &aliased_plan, &read_script).unwrap();
+  let type_info = GlobalTyckContext::new(&vm).unwrap().typeck().unwrap();
+  let executor = Executor::new(&vm, &*kv, &type_info);
+  // Use small batches, so that sets are migrated over several transactions.
+  assert_eq!(executor.migrate_layout(&plan, 2).await.unwrap(), 3);
+  assert_eq!(executor.migrate_layout(&plan, 2).await.unwrap(), 0);
+
+  let after = run_read_script(&schema, &aliased_plan, &read_script, &*kv).await;
+  assert_eq!(before, after);
+
+  // Nothing is left in the old layout, and index entries have been moved.
+  let txn = kv.begin_transaction().await.unwrap();
+  let mut it = txn.scan_keys(&[], &[0xffu8]).await.unwrap();
+  while let Some(k) = it.next().await.unwrap() {
+    assert_ne!(k[0], 0x01, "key not migrated: {:?}", k);
+  }
+  drop(it);
+  let walker = PathWalker::from_export(&aliased_plan, "items").unwrap();
+  let prefix = walker.set_index_prefix("name").unwrap();
+  let mut end = prefix.clone();
+  end.push(0xff);
+  let mut it = txn.scan_keys(&prefix, &end).await.unwrap();
+  let mut n = 0;
+  while it.next().await.unwrap().is_some() {
+    n += 1;
+  }
+  assert_eq!(n, 3);
+}
+
+#[tokio::test]
+async fn retry_on_read_conflict() {
+  let _ = pretty_env_logger::try_init();
+  let fixture = fixtures::get("items").unwrap();
+  let (schema, plan) = fixture.compile_schema_with_plan().unwrap();
+  let script = fixture.compile_script("read").unwrap();
+  let vm = TwVm::new(&schema, &plan, &script).unwrap();
+  let type_info = GlobalTyckContext::new(&vm).unwrap().typeck().unwrap();
+  let root = Arc::new(generate_root_map(&schema, &plan).unwrap());
+
+  let kv = FlakyKv {
+    inner: create_kv(),
+    remaining_failures: Arc::new(AtomicUsize::new(3)),
+  };
+  let mut executor = Executor::new(&vm, &kv, &type_info);
+  executor.run_graph(0, &[root.clone()]).await.unwrap();
+  assert_eq!(kv.remaining_failures.load(Ordering::Relaxed), 0);
+
+  kv.remaining_failures.store(100, Ordering::Relaxed);
+  executor.set_retry_policy(RetryPolicy {
+    max_attempts: 2,
+    ..Default::default()
+  });
+  let e = executor.run_graph(0, &[root]).await.unwrap_err();
+  assert!(matches!(
+    e.downcast_ref::<ExecError>(),
+    Some(ExecError::ConflictAfterRetries)
+  ));
+}
+
+/// Fails the first `remaining_failures` reads with a conflict.
+struct FlakyKv {
+  inner: Box<dyn KeyValueStore>,
+  remaining_failures: Arc<AtomicUsize>,
+}
+
+struct FlakyTxn {
+  inner: Box<dyn KvTransaction>,
+  remaining_failures: Arc<AtomicUsize>,
+}
+
+#[async_trait]
+impl KeyValueStore for FlakyKv {
+  async fn begin_transaction(&self) -> Result<Box<dyn KvTransaction>> {
+    Ok(Box::new(FlakyTxn {
+      inner: self.inner.begin_transaction().await?,
+      remaining_failures: self.remaining_failures.clone(),
+    }))
+  }
+}
+
+#[async_trait]
+impl KvTransaction for FlakyTxn {
+  async fn get(&self, key: &[u8]) -> Result<Option<Vec<u8>>> {
+    if self
+      .remaining_failures
+      .fetch_update(Ordering::Relaxed, Ordering::Relaxed, |x| x.checked_sub(1))
+      .is_ok()
+    {
+      return Err(KvError::Conflict.into());
+    }
+    self.inner.get(key).await
+  }
+  async fn put(&self, key: &[u8], value: &[u8]) -> Result<()> {
+    self.inner.put(key, value).await
+  }
+  async fn delete(&self, key: &[u8]) -> Result<()> {
+    self.inner.delete(key).await
+  }
+  async fn delete_range(&self, start: &[u8], end: &[u8]) -> Result<()> {
+    self.inner.delete_range(start, end).await
+  }
+  async fn scan_keys(&self, start: &[u8], end: &[u8]) -> Result<Box<dyn KvKeyIterator>> {
+    self.inner.scan_keys(start, end).await
+  }
+  async fn commit(self: Box<Self>) -> Result<(), KvError> {
+    self.inner.commit().await
+  }
+}
+
+#[tokio::test]
+async fn field_defaults() {
+  let _ = pretty_env_logger::try_init();
+  let alloc = Bump::new();
+  let old_schema = compile(
+    &parse(
+      &alloc,
+      r#"
+  type Item {
+    @primary
+    id: string,
+  }
+  export set<Item> items;
+  "#,
+    )
+    .unwrap(),
+  )
+  .unwrap();
+  let new_schema = compile(
+    &parse(
+      &alloc,
+      r#"
+  type Item {
+    @primary
+    id: string,
+    @default(3)
+    count: int64,
+    @default(-1.5)
+    ratio: double,
+    @default("none")
+    label: string,
+  }
+  export set<Item> items;
+  "#,
+    )
+    .unwrap(),
+  )
+  .unwrap();
+  let old_plan =
+    generate_plan_for_schema(&Default::default(), &Default::default(), &old_schema).unwrap();
+  let new_plan = generate_plan_for_schema(&old_plan, &old_schema, &new_schema).unwrap();
+  let kv = create_kv();
+
+  let old_write_script = compile_twscript(
+    r#"
+    graph main(root: schema) {
+      s_insert root.items $ build_table(Item) $ m_insert(id) "a" create_map;
+    }
+    "#,
+  )
+  .unwrap();
+  let new_write_script = compile_twscript(
+    r#"
+    graph main(root: schema) {
+      s_insert root.items $ build_table(Item) $ m_insert(id) "b" $ m_insert(count) 7 create_map;
+    }
+    "#,
+  )
+  .unwrap();
+  let read_script = compile_twscript(
+    r#"
+    graph main(root: schema): map {
+      a_count: int64,
+      a_ratio: double,
+      a_label: string,
+      b_count: int64,
+    } {
+      a = point_get root.items "a";
+      b = point_get root.items "b";
+      return m_insert(a_count) a.count $ m_insert(a_ratio) a.ratio $ m_insert(a_label) a.label $
+        m_insert(b_count) b.count create_map;
+    }
+    "#,
+  )
+  .unwrap();
+  for (schema, plan, script) in [
+    (&old_schema, &old_plan, &old_write_script),
+    (&new_schema, &new_plan, &new_write_script),
+  ] {
+    let vm = TwVm::new(schema, plan, script).unwrap();
+    let type_info = GlobalTyckContext::new(&vm).unwrap().typeck().unwrap();
+    Executor::new(&vm, &*kv, &type_info)
+      .run_graph(0, &[Arc::new(generate_root_map(schema, plan).unwrap())])
+      .await
+      .unwrap();
+  }
+  assert_eq!(
+    run_read_script(&new_schema, &new_plan, &read_script, &*kv).await,
+    r#"Tagged(M({"a_count": String("3"), "a_label": String("none"), "a_ratio": String("-1.5"), "b_count": String("7")}))"#
+  );
+}
+
+#[tokio::test]
+async fn persisted_lists() {
+  let _ = pretty_env_logger::try_init();
+  let alloc = Bump::new();
+  let schema = compile(
+    &parse(
+      &alloc,
+      r#"
+  type Item {
+    @primary
+    id: string,
+    tags: list<string>,
+    scores: list<int64>,
+  }
+  export set<Item> items;
+  "#,
+    )
+    .unwrap(),
+  )
+  .unwrap();
+  let plan = generate_plan_for_schema(&Default::default(), &Default::default(), &schema).unwrap();
+  let kv = create_kv();
+
+  let insert_script = compile_twscript(
+    r#"
+    graph main(root: schema) {
+      s_insert root.items $ build_table(Item) $ m_insert(id) "a" $
+        m_insert(scores) (3 : 1 : 2 : create_list(int64)) create_map;
+    }
+    "#,
+  )
+  .unwrap();
+  let push_script = compile_twscript(
+    r#"
+    graph main(root: schema, tag: string) {
+      l_push(tags) (point_get root.items "a") tag;
+    }
+    "#,
+  )
+  .unwrap();
+  let pop_script = compile_twscript(
+    r#"
+    graph main(root: schema): string {
+      return l_pop(tags) (point_get root.items "a");
+    }
+    "#,
+  )
+  .unwrap();
+  let read_script = compile_twscript(
+    r#"
+    graph main(root: schema): map {
+      tags: list<string>,
+      scores: list<int64>,
+      second_tag: string,
+      out_of_range: string,
+      len: int64,
+    } {
+      a = point_get root.items "a";
+      return m_insert(tags) a.tags $ m_insert(scores) a.scores $
+        m_insert(second_tag) (l_get(tags) a 1) $ m_insert(out_of_range) (l_get(tags) a 5) $
+        m_insert(len) (l_len(tags) a) create_map;
+    }
+    "#,
+  )
+  .unwrap();
+
+  run_script(&schema, &plan, &*kv, &insert_script, vec![]).await;
+  for tag in ["x", "y", "z"] {
+    let tag = Arc::new(VmValue::Primitive(PrimitiveValue::String(tag.into())));
+    run_script(&schema, &plan, &*kv, &push_script, vec![tag]).await;
+  }
+  assert_eq!(
+    run_script(&schema, &plan, &*kv, &read_script, vec![])
+      .await
+      .unwrap(),
+    r#"Tagged(M({"len": String("3"), "out_of_range": Null(None), "scores": Tagged(L([String("3"), String("1"), String("2")])), "second_tag": String("y"), "tags": Tagged(L([String("x"), String("y"), String("z")]))}))"#
+  );
+
+  assert_eq!(
+    run_script(&schema, &plan, &*kv, &pop_script, vec![])
+      .await
+      .unwrap(),
+    r#"String("z")"#
+  );
+  assert_eq!(
+    run_script(&schema, &plan, &*kv, &pop_script, vec![])
+      .await
+      .unwrap(),
+    r#"String("y")"#
+  );
+  assert_eq!(
+    run_script(&schema, &plan, &*kv, &read_script, vec![])
+      .await
+      .unwrap(),
+    r#"Tagged(M({"len": String("1"), "out_of_range": Null(None), "scores": Tagged(L([String("3"), String("1"), String("2")])), "second_tag": Null(None), "tags": Tagged(L([String("x")]))}))"#
+  );
+  assert_eq!(
+    run_script(&schema, &plan, &*kv, &pop_script, vec![])
+      .await
+      .unwrap(),
+    r#"String("x")"#
+  );
+  assert_eq!(
+    run_script(&schema, &plan, &*kv, &pop_script, vec![])
+      .await
+      .unwrap(),
+    "Null(None)"
+  );
+}
+
+#[tokio::test]
+async fn persisted_maps() {
+  let _ = pretty_env_logger::try_init();
+  let alloc = Bump::new();
+  let schema = compile(
+    &parse(
+      &alloc,
+      r#"
+  type Item {
+    @primary
+    id: string,
+    prices: map<string, int64>,
+  }
+  export set<Item> items;
+  "#,
+    )
+    .unwrap(),
+  )
+  .unwrap();
+  let plan = generate_plan_for_schema(&Default::default(), &Default::default(), &schema).unwrap();
+  let kv = create_kv();
+
+  let insert_script = compile_twscript(
+    r#"
+    type Entry = map { key: string, value: int64 };
+    graph main(root: schema) {
+      s_insert root.items $ build_table(Item) $ m_insert(id) "a" $
+        m_insert(prices) (
+          (m_insert(key) "b" $ m_insert(value) 2 create_map) :
+          (m_insert(key) "a" $ m_insert(value) 1 create_map) :
+          create_list(Entry)
+        ) create_map;
+    }
+    "#,
+  )
+  .unwrap();
+  let put_script = compile_twscript(
+    r#"
+    graph main(root: schema, key: string, value: int64) {
+      d_put(prices) (point_get root.items "a") key value;
+    }
+    "#,
+  )
+  .unwrap();
+  let delete_script = compile_twscript(
+    r#"
+    graph main(root: schema, key: string) {
+      d_delete(prices) (point_get root.items "a") key;
+    }
+    "#,
+  )
+  .unwrap();
+  let read_script = compile_twscript(
+    r#"
+    type Entry = map { key: string, value: int64 };
+    graph main(root: schema): map {
+      prices: list<Entry>,
+      b: int64,
+      missing: int64,
+      range: list<Entry>,
+    } {
+      a = point_get root.items "a";
+      return m_insert(prices) a.prices $ m_insert(b) (d_get(prices) a "b") $
+        m_insert(missing) (d_get(prices) a "x") $
+        m_insert(range) (d_range(prices) a "b" "d") create_map;
+    }
+    "#,
+  )
+  .unwrap();
+
+  run_script(&schema, &plan, &*kv, &insert_script, vec![]).await;
+  assert_eq!(
+    run_script(&schema, &plan, &*kv, &read_script, vec![])
+      .await
+      .unwrap(),
+    r#"Tagged(M({"b": String("2"), "missing": Null(None), "prices": Tagged(L([Tagged(M({"key": String("a"), "value": String("1")})), Tagged(M({"key": String("b"), "value": String("2")}))])), "range": Tagged(L([Tagged(M({"key": String("b"), "value": String("2")}))]))}))"#
+  );
+
+  for (key, value) in [("c", 3), ("b", 20), ("d", 4)] {
+    let params = vec![
+      Arc::new(VmValue::Primitive(PrimitiveValue::String(key.into()))),
+      Arc::new(VmValue::Primitive(PrimitiveValue::Int64(value))),
+    ];
+    run_script(&schema, &plan, &*kv, &put_script, params).await;
+  }
+  let key = Arc::new(VmValue::Primitive(PrimitiveValue::String("a".into())));
+  run_script(&schema, &plan, &*kv, &delete_script, vec![key]).await;
+  assert_eq!(
+    run_script(&schema, &plan, &*kv, &read_script, vec![])
+      .await
+      .unwrap(),
+    r#"Tagged(M({"b": String("20"), "missing": Null(None), "prices": Tagged(L([Tagged(M({"key": String("b"), "value": String("20")})), Tagged(M({"key": String("c"), "value": String("3")})), Tagged(M({"key": String("d"), "value": String("4")}))])), "range": Tagged(L([Tagged(M({"key": String("b"), "value": String("20")})), Tagged(M({"key": String("c"), "value": String("3")}))]))}))"#
+  );
+}
+
+#[tokio::test]
+async fn composite_primary_keys() {
+  let _ = pretty_env_logger::try_init();
+  let alloc = Bump::new();
+  let schema = compile(
+    &parse(
+      &alloc,
+      r#"
+  type Item {
+    @primary(1)
+    seq: int64,
+    @primary(0)
+    tenant: string,
+    name: string,
+  }
+  export set<Item> items;
+  "#,
+    )
+    .unwrap(),
+  )
+  .unwrap();
+  let plan = generate_plan_for_schema(&Default::default(), &Default::default(), &schema).unwrap();
+  let kv = create_kv();
+
+  let insert_script = compile_twscript(
+    r#"
+    graph main(root: schema, tenant: string, seq: int64, name: string) {
+      s_insert root.items $ build_table(Item) $ m_insert(tenant) tenant $ m_insert(seq) seq $
+        m_insert(name) name create_map;
+    }
+    "#,
+  )
+  .unwrap();
+  let read_script = compile_twscript(
+    r#"
+    graph main(root: schema): map { a2: string, b1: string, all: string, missing: bool } {
+      return m_insert(a2) (point_get root.items ("a", 2)).name $
+        m_insert(b1) (point_get root.items ("b", 1)).name $
+        m_insert(all) (reduce(join) create_map "" root.items) $
+        m_insert(missing) (is_present $ point_get root.items ("a", 3)) create_map;
+    }
+    graph join(ctx: map{}, current: string, item: Item): string {
+      return current + item.name;
+    }
+    "#,
+  )
+  .unwrap();
+
+  for (tenant, seq, name) in [
+    ("b", 1, "w"),
+    ("a", 10, "z"),
+    ("a", 2, "y"),
+    ("a", -1, "x"),
+    ("a", 2, "Y"),
+  ] {
+    let params = vec![
+      Arc::new(VmValue::Primitive(PrimitiveValue::String(tenant.into()))),
+      Arc::new(VmValue::Primitive(PrimitiveValue::Int64(seq))),
+      Arc::new(VmValue::Primitive(PrimitiveValue::String(name.into()))),
+    ];
+    run_script(&schema, &plan, &*kv, &insert_script, params).await;
+  }
+  assert_eq!(
+    run_script(&schema, &plan, &*kv, &read_script, vec![])
+      .await
+      .unwrap(),
+    r#"Tagged(M({"a2": String("Y"), "all": String("xYzw"), "b1": String("w"), "missing": Bool(false)}))"#
+  );
+}
+
+#[tokio::test]
+async fn ttl_expiry() {
+  let _ = pretty_env_logger::try_init();
+  let alloc = Bump::new();
+  let schema = compile(
+    &parse(
+      &alloc,
+      r#"
+  @ttl("expires_at")
+  type Item {
+    @primary
+    id: string,
+    expires_at: int64,
+  }
+  export set<Item> items;
+  "#,
+    )
+    .unwrap(),
+  )
+  .unwrap();
+  let plan = generate_plan_for_schema(&Default::default(), &Default::default(), &schema).unwrap();
+  let kv = create_kv();
+
+  let insert_script = compile_twscript(
+    r#"
+    graph main(root: schema) {
+      s_insert root.items $ build_table(Item) $ m_insert(id) "a" $ m_insert(expires_at) 1 create_map;
+      s_insert root.items $ build_table(Item) $ m_insert(id) "b" $
+        m_insert(expires_at) (time_now + 3600000) create_map;
+      s_insert root.items $ build_table(Item) $ m_insert(id) "c" $
+        m_insert(expires_at) null<int64> create_map;
+      s_insert root.items $ build_table(Item) $ m_insert(id) "d" $ m_insert(expires_at) 2 create_map;
+    }
+    "#,
+  )
+  .unwrap();
+  let read_script = compile_twscript(
+    r#"
+    graph main(root: schema): map { all: string, a: bool, b: bool, many: string } {
+      many = point_get_many root.items ("a" : "c" : create_list(string));
+      return m_insert(all) (reduce(join) create_map "" root.items) $
+        m_insert(a) (is_present $ point_get root.items "a") $
+        m_insert(b) (is_present $ point_get root.items "b") $
+        m_insert(many) (if is_null $ head many { (head $ pop many)?.id } else { "" }) create_map;
+    }
+    graph join(ctx: map{}, current: string, item: Item): string {
+      return current + item.id;
+    }
+    "#,
+  )
+  .unwrap();
+
+  run_script(&schema, &plan, &*kv, &insert_script, vec![]).await;
+  let expected =
+    r#"Tagged(M({"a": Bool(false), "all": String("bc"), "b": Bool(true), "many": String("c")}))"#;
+  assert_eq!(
+    run_script(&schema, &plan, &*kv, &read_script, vec![])
+      .await
+      .unwrap(),
+    expected
+  );
+
+  let vm = TwVm::new(&schema, &plan, &read_script).unwrap();
+  let type_info = GlobalTyckContext::new(&vm).unwrap().typeck().unwrap();
+  let executor = Executor::new(&vm, &*kv, &type_info);
+  // Batches of one, so that every member is checked in its own transaction.
+  assert_eq!(executor.reap_expired("items", 1).await.unwrap(), 2);
+  assert_eq!(executor.reap_expired("items", 1).await.unwrap(), 0);
+  assert_eq!(
+    run_script(&schema, &plan, &*kv, &read_script, vec![])
+      .await
+      .unwrap(),
+    expected
+  );
+}
+
+#[tokio::test]
+async fn counters() {
+  let _ = pretty_env_logger::try_init();
+  let alloc = Bump::new();
+  let schema = compile(
+    &parse(
+      &alloc,
+      r#"
+  type Item {
+    @primary
+    id: string,
+    @counter
+    views: int64,
+  }
+  export set<Item> items;
+  "#,
+    )
+    .unwrap(),
+  )
+  .unwrap();
+  let plan = generate_plan_for_schema(&Default::default(), &Default::default(), &schema).unwrap();
+  let kv = create_kv();
+
+  let insert_script = compile_twscript(
+    r#"
+    graph main(root: schema) {
+      s_insert root.items $ build_table(Item) $ m_insert(id) "a" $ m_insert(views) 5 create_map;
+      s_insert root.items $ build_table(Item) $ m_insert(id) "b" $
+        m_insert(views) null<int64> create_map;
+    }
+    "#,
+  )
+  .unwrap();
+  let add_script = compile_twscript(
+    r#"
+    graph main(root: schema) {
+      t_add(views) (point_get root.items "a") 3;
+      t_add(views) (point_get root.items "b") (-2);
+    }
+    "#,
+  )
+  .unwrap();
+  let read_script = compile_twscript(
+    r#"
+    graph main(root: schema): map { a: int64, b: int64 } {
+      return m_insert(a) (point_get root.items "a").views $
+        m_insert(b) (point_get root.items "b").views create_map;
+    }
+    "#,
+  )
+  .unwrap();
+
+  run_script(&schema, &plan, &*kv, &insert_script, vec![]).await;
+  for _ in 0..3 {
+    run_script(&schema, &plan, &*kv, &add_script, vec![]).await;
+  }
+  assert_eq!(
+    run_script(&schema, &plan, &*kv, &read_script, vec![])
+      .await
+      .unwrap(),
+    r#"Tagged(M({"a": String("14"), "b": String("-6")}))"#
+  );
+
+  // Only `@counter` fields can be added to.
+  let script = compile_twscript(
+    r#"
+    graph main(root: schema) {
+      t_add(id) (point_get root.items "a") 1;
+    }
+    "#,
+  )
+  .unwrap();
+  let vm = TwVm::new(&schema, &plan, &script).unwrap();
+  assert!(GlobalTyckContext::new(&vm)
+    .unwrap()
+    .typeck()
+    .unwrap_err()
+    .to_string()
+    .contains("is not a counter"));
+}
+
+#[tokio::test]
+async fn version_fields() {
+  let _ = pretty_env_logger::try_init();
+  let alloc = Bump::new();
+  let schema = compile(
+    &parse(
+      &alloc,
+      r#"
+  type Item {
+    @primary
+    id: string,
+    name: string,
+    tags: list<string>,
+    @version
+    version: int64,
+  }
+  export set<Item> items;
+  "#,
+    )
+    .unwrap(),
+  )
+  .unwrap();
+  let plan = generate_plan_for_schema(&Default::default(), &Default::default(), &schema).unwrap();
+  let kv = create_kv();
+
+  let insert_script = compile_twscript(
+    r#"
+    graph main(root: schema) {
+      s_insert root.items $ build_table(Item) $ m_insert(id) "a" $ m_insert(name) "x" $
+        m_insert(tags) create_list(string) $ m_insert(version) null<int64> create_map;
+    }
+    "#,
+  )
+  .unwrap();
+  let update_script = compile_twscript(
+    r#"
+    graph main(root: schema, version: int64) {
+      item = t_cas (point_get root.items "a") version;
+      t_insert(name) item "y";
+      l_push(tags) item "z";
+    }
+    "#,
+  )
+  .unwrap();
+  let read_script = compile_twscript(
+    r#"
+    graph main(root: schema): int64 {
+      return (point_get root.items "a").version;
+    }
+    "#,
+  )
+  .unwrap();
+  let version =
+    |x: i64| -> Vec<Arc<VmValue>> { vec![Arc::new(VmValue::Primitive(PrimitiveValue::Int64(x)))] };
+
+  run_script(&schema, &plan, &*kv, &insert_script, vec![]).await;
+  assert_eq!(
+    run_script(&schema, &plan, &*kv, &read_script, vec![])
+      .await
+      .unwrap(),
+    r#"String("0")"#
+  );
+
+  // Each update bumps the version.
+  run_script(&schema, &plan, &*kv, &update_script, version(0)).await;
+  assert_eq!(
+    run_script(&schema, &plan, &*kv, &read_script, vec![])
+      .await
+      .unwrap(),
+    r#"String("2")"#
+  );
+
+  // A stale version fails the whole transaction.
+  let vm = TwVm::new(&schema, &plan, &update_script).unwrap();
+  let type_info = GlobalTyckContext::new(&vm).unwrap().typeck().unwrap();
+  let mut executor = Executor::new(&vm, &*kv, &type_info);
+  let mut params = vec![Arc::new(generate_root_map(&schema, &plan).unwrap())];
+  params.extend(version(0));
+  match executor
+    .run_graph(0, &params)
+    .await
+    .unwrap_err()
+    .downcast_ref()
+  {
+    Some(ExecError::VersionConflict(0, 2)) => {}
+    x => panic!("unexpected error: {:?}", x),
+  }
+  assert_eq!(
+    run_script(&schema, &plan, &*kv, &read_script, vec![])
+      .await
+      .unwrap(),
+    r#"String("2")"#
+  );
+
+  // Versions are managed by the executor.
+  let script = compile_twscript(
+    r#"
+    graph main(root: schema) {
+      t_insert(version) (point_get root.items "a") 1;
+    }
+    "#,
+  )
+  .unwrap();
+  let vm = TwVm::new(&schema, &plan, &script).unwrap();
+  assert!(GlobalTyckContext::new(&vm).unwrap().typeck().is_err());
+}
+
+#[tokio::test]
+async fn select_semantics() {
+  let _ = pretty_env_logger::try_init();
+  let alloc = Bump::new();
+  let schema = compile(
+    &parse(
+      &alloc,
+      r#"
+  type Item {
+    @primary
+    id: string,
+  }
+  export set<Item> items;
+  "#,
+    )
+    .unwrap(),
+  )
+  .unwrap();
+  let plan = generate_plan_for_schema(&Default::default(), &Default::default(), &schema).unwrap();
+  let kv = create_kv();
+  let script = compile_twscript(
+    r#"
+    graph main(root: schema, a: bool, b: bool): int64 {
+      if a {
+        x = 1;
+      }
+      if b {
+        y = 2;
+      }
+      return select x y;
+    }
+    graph same(root: schema, a: bool, b: bool): int64 {
+      if a {
+        x = 1;
+      }
+      return select x x;
+    }
+    "#,
+  )
+  .unwrap();
+  let vm = TwVm::new(&schema, &plan, &script).unwrap();
+  let type_info = GlobalTyckContext::new(&vm).unwrap().typeck().unwrap();
+  let int = |x| Some(Arc::new(VmValue::Primitive(PrimitiveValue::Int64(x))));
+
+  for scheduler in [Scheduler::Dataflow, Scheduler::Topological] {
+    for strict_select in [true, false] {
+      let config = ExecConfig {
+        scheduler,
+        strict_select,
+        ..Default::default()
+      };
+      let run = |graph, a, b| run_select(&vm, &type_info, &*kv, config.clone(), graph, a, b);
+
+      // Exactly one candidate fires, or none and neither does the select.
+      assert_eq!(run(0, true, false).await.unwrap(), int(1));
+      assert_eq!(run(0, false, true).await.unwrap(), int(2));
+      assert_eq!(run(0, false, false).await.unwrap(), None);
+
+      // Both params of the select are the same node, which fires once.
+      assert_eq!(run(1, true, true).await.unwrap(), int(1));
+      assert_eq!(run(1, false, true).await.unwrap(), None);
+
+      let both = run(0, true, true).await;
+      if strict_select {
+        assert!(matches!(
+          both.unwrap_err().downcast::<ExecError>().unwrap(),
+          ExecError::BothSelectCandidatesFired
+        ));
+      } else if scheduler == Scheduler::Topological {
+        assert_eq!(both.unwrap(), int(1));
+      } else {
+        let both = both.unwrap();
+        assert!(both == int(1) || both == int(2), "{:?}", both);
+      }
+    }
+  }
+}
+
+#[tokio::test]
+async fn schedulers_agree() {
+  let _ = pretty_env_logger::try_init();
+  let alloc = Bump::new();
+  let schema = compile(
+    &parse(
+      &alloc,
+      r#"
+  type Item {
+    @primary
+    id: string,
+    v: int64,
+  }
+  export set<Item> items;
+  "#,
+    )
+    .unwrap(),
+  )
+  .unwrap();
+  let plan = generate_plan_for_schema(&Default::default(), &Default::default(), &schema).unwrap();
+  let scripts = [
+    r#"
+    graph main(root: schema, n: int64): int64 {
+      if n < 3 {
+        if n == 0 {
+          a = 100;
+        } else {
+          b = call(fib) [n + 10];
+        }
+        x = select a b;
+      } else {
+        y = if n < 5 { n + n } else { call(fib) [n] };
+      }
+      return select x y;
+    }
+    graph fib(x: int64): int64 {
+      if x == 1 || x == 2 {
+        v1 = 1;
+      } else {
+        v2 = call(fib) [x - 1] + call(fib) [x - 2];
+      }
+      return select v1 v2;
+    }
+    "#,
+    r#"
+    graph main(root: schema, n: int64): string {
+      s = if n == 2 { "" } else { "x" };
+      x = try {
+        if s == "" {
+          throw "empty";
+        }
+        return s + s;
+      } catch (e) {
+        return "caught: " + e;
+      }
+      return x;
+    }
+    "#,
+    r#"
+    graph main(root: schema, n: int64): int64 {
+      return for(count_until) from 0 to (n + n + n) n 0;
+    }
+    graph count_until(limit: int64, current: int64, i: int64): int64 {
+      if i == limit + 2 {
+        r1 = null<int64>;
+      } else {
+        r2 = current + i;
+      }
+      return select r1 r2;
+    }
+    "#,
+    r#"
+    graph main(root: schema, n: int64) {
+      id = if n == 0 || n == 2 || n == 4 { "even" } else { "odd" };
+      if n < 4 {
+        s_insert root.items $ build_table(Item) $ m_insert(id) id $ m_insert(v) n create_map;
+      } else {
+        s_delete root.items "odd";
+      }
+    }
+    "#,
+    r#"
+    graph main(root: schema, n: int64): int64 {
+      return reduce(sum) create_map n root.items;
+    }
+    graph sum(_unused: map{}, current: int64, item: Item): int64 {
+      return current + item.v;
+    }
+    "#,
+  ];
+  let scripts = scripts
+    .iter()
+    .map(|x| compile_twscript(x).unwrap())
+    .collect::<Vec<_>>();
+
+  let mut outputs: Vec<Vec<Option<String>>> = vec![];
+  for scheduler in [Scheduler::Dataflow, Scheduler::Topological] {
+    let kv = create_kv();
+    let mut scheduler_outputs = vec![];
+    for n in 0..6 {
+      for script in &scripts {
+        let vm = TwVm::new(&schema, &plan, script).unwrap();
+        let type_info = GlobalTyckContext::new(&vm).unwrap().typeck().unwrap();
+        let mut executor = Executor::new(&vm, &*kv, &type_info);
+        executor.set_config(ExecConfig {
+          scheduler,
+          ..Default::default()
+        });
+        let output = executor
+          .run_graph(
+            0,
+            &[
+              Arc::new(generate_root_map(&schema, &plan).unwrap()),
+              Arc::new(VmValue::Primitive(PrimitiveValue::Int64(n))),
+            ],
+          )
+          .await
+          .unwrap();
+        scheduler_outputs.push(output.map(|x| {
+          format!(
+            "{:?}",
+            SerializedVmValue::encode(&*x, &Default::default()).unwrap()
+          )
+        }));
+      }
+    }
+    outputs.push(scheduler_outputs);
+  }
+  assert_eq!(outputs[0], outputs[1]);
+  assert_eq!(outputs[0].iter().filter(|x| x.is_some()).count(), 4 * 6);
+}
+
+#[tokio::test]
+async fn shared_layouts() {
+  let _ = pretty_env_logger::try_init();
+  let alloc = Bump::new();
+  let schema = compile(
+    &parse(
+      &alloc,
+      r#"
+  type User {
+    @primary
+    id: string,
+    home: Address,
+    work: Address,
+  }
+  type Address {
+    city: string,
+    tags: set<Tag>,
+  }
+  type Tag {
+    @primary
+    id: string,
+  }
+  export set<User> users;
+  export Address office;
+  "#,
+    )
+    .unwrap(),
+  )
+  .unwrap();
+  let empty_plan = StoragePlan {
+    share_layouts: true,
+    ..Default::default()
+  };
+  let plan = generate_plan_for_schema(&empty_plan, &Default::default(), &schema).unwrap();
+  assert_eq!(
+    plan.shared_layouts.keys().map(|x| &**x).collect::<Vec<_>>(),
+    vec!["Address<>"]
+  );
+  let kv = create_kv();
+
+  let write_script = compile_twscript(
+    r#"
+    graph main(root: schema) {
+      s_insert root.users $ build_table(User) $ m_insert(id) "a" create_map;
+      s_insert root.users $ build_table(User) $ m_insert(id) "b" create_map;
+      t_insert(city) (point_get root.users "a").home "x";
+      t_insert(city) (point_get root.users "a").work "y";
+      t_insert(city) (point_get root.users "b").home "z";
+      t_insert(city) root.office "w";
+      s_insert (point_get root.users "a").work.tags $ build_table(Tag) $ m_insert(id) "t" create_map;
+    }
+    "#,
+  )
+  .unwrap();
+  let read_script = compile_twscript(
+    r#"
+    graph main(root: schema): map {
+      a_home: string,
+      a_work: string,
+      b_home: string,
+      office: string,
+      a_home_tag: bool,
+      a_work_tag: bool,
+    } {
+      a = point_get root.users "a";
+      b = point_get root.users "b";
+      return m_insert(a_home) a.home.city $ m_insert(a_work) a.work.city $
+        m_insert(b_home) b.home.city $ m_insert(office) root.office.city $
+        m_insert(a_home_tag) (is_present $ point_get a.home.tags "t") $
+        m_insert(a_work_tag) (is_present $ point_get a.work.tags "t") create_map;
+    }
+    "#,
+  )
+  .unwrap();
+  run_script(&schema, &plan, &*kv, &write_script, vec![]).await;
+  assert_eq!(
+    run_read_script(&schema, &plan, &read_script, &*kv).await,
+    r#"Tagged(M({"a_home": String("x"), "a_home_tag": Bool(false), "a_work": String("y"), "a_work_tag": Bool(true), "b_home": String("z"), "office": String("w")}))"#
+  );
+}
+
+async fn run_select<'a>(
+  vm: &TwVm<'a>,
+  type_info: &GlobalTypeInfo<'a>,
+  kv: &dyn KeyValueStore,
+  config: ExecConfig,
+  graph_index: usize,
+  a: bool,
+  b: bool,
+) -> Result<Option<Arc<VmValue<'a>>>> {
+  let mut executor = Executor::new(vm, kv, type_info);
+  executor.set_config(config);
+  executor
+    .run_graph(
+      graph_index,
+      &[
+        Arc::new(generate_root_map(vm.schema, vm.storage_plan).unwrap()),
+        Arc::new(VmValue::Bool(a)),
+        Arc::new(VmValue::Bool(b)),
+      ],
+    )
+    .await
+}
+
+async fn run_script<'a>(
+  schema: &'a CompiledSchema,
+  plan: &'a StoragePlan,
+  kv: &dyn KeyValueStore,
+  script: &'a TwScript,
+  params: Vec<Arc<VmValue<'a>>>,
+) -> Option<String> {
+  let vm = TwVm::new(schema, plan, script).unwrap();
+  let type_info = GlobalTyckContext::new(&vm).unwrap().typeck().unwrap();
+  let mut graph_params = vec![Arc::new(generate_root_map(schema, plan).unwrap())];
+  graph_params.extend(params);
+  let output = Executor::new(&vm, kv, &type_info)
+    .run_graph(0, &graph_params)
+    .await
+    .unwrap();
+  output.map(|x| {
+    format!(
+      "{:?}",
+      SerializedVmValue::encode(&*x, &Default::default()).unwrap()
+    )
+  })
+}
+
+async fn run_read_script(
+  schema: &CompiledSchema,
+  plan: &StoragePlan,
+  script: &TwScript,
+  kv: &dyn KeyValueStore,
+) -> String {
+  let vm = TwVm::new(schema, plan, script).unwrap();
+  let type_info = GlobalTyckContext::new(&vm).unwrap().typeck().unwrap();
+  let mut executor = Executor::new(&vm, kv, &type_info);
+  let output = executor
+    .run_graph(0, &[Arc::new(generate_root_map(schema, plan).unwrap())])
+    .await
+    .unwrap()
+    .unwrap();
+  format!(
+    "{:?}",
+    SerializedVmValue::encode(&*output, &Default::default()).unwrap()
+  )
+}
@@ -0,0 +1,401 @@
+//! Inlining of calls to small subgraphs, and specialization of subgraphs on the constant params of
+//! calls.
+//!
+//! Unlike the passes in `optimize`, these need the type info of the script: a call outputs null
+//! without running its subgraph if any of its params is null, which an inlined body does not do,
+//! so only calls whose params are never null are inlined. They run when a script is loaded against
+//! a schema, and the result is type checked again.
+
+use std::collections::HashMap;
+
+use anyhow::Result;
+
+use crate::{schema::compile::CompiledSchema, storage_plan::StoragePlan};
+
+use super::{
+  bytecode::{TwGraph, TwGraphNode, TwScript},
+  optimize::{optimize_graph, optimize_script},
+  typeck::GlobalTyckContext,
+  vm::TwVm,
+  vm_value::VmConst,
+};
+
+#[derive(Clone, Debug)]
+pub struct InlineConfig {
+  /// Max number of nodes of a subgraph that is inlined into its callers. Zero disables inlining.
+  pub max_nodes: usize,
+
+  /// Max number of specialized copies of subgraphs added to a script.
+  pub max_specializations: usize,
+}
+
+impl Default for InlineConfig {
+  fn default() -> Self {
+    Self {
+      max_nodes: 16,
+      max_specializations: 64,
+    }
+  }
+}
+
+#[derive(Default, Debug, Copy, Clone, Eq, PartialEq)]
+pub struct InlineStats {
+  /// Specialized copies of subgraphs added to the script.
+  pub specialized: usize,
+
+  /// Calls replaced with the body of their subgraph.
+  pub inlined: usize,
+}
+
+/// Inlines calls to small subgraphs, and points calls with constant params to copies of their
+/// subgraphs with the params bound, in place. Only the calls in the graphs that `script` starts
+/// with are rewritten, so one run inlines a single level of calls.
+///
+/// Fails if `script` does not type check. If the rewritten script does not, `script` is left as
+/// it is.
+pub fn inline_script(
+  script: &mut TwScript,
+  schema: &CompiledSchema,
+  plan: &StoragePlan,
+  config: &InlineConfig,
+) -> Result<InlineStats> {
+  let nullable = {
+    let vm = TwVm::new(schema, plan, script)?;
+    let type_info = GlobalTyckContext::new(&vm)?.typeck()?;
+    type_info
+      .graphs
+      .into_iter()
+      .map(|x| x.nullable)
+      .collect::<Vec<_>>()
+  };
+
+  let mut rewritten = script.clone();
+  let stats = inline_calls(&mut rewritten, &nullable, config);
+  if stats == InlineStats::default() {
+    return Ok(stats);
+  }
+  optimize_script(&mut rewritten);
+
+  let typeck_result = TwVm::new(schema, plan, &rewritten)
+    .and_then(|vm| GlobalTyckContext::new(&vm)?.typeck().map(|_| ()));
+  if let Err(e) = typeck_result {
+    log::warn!(
+      "inlined script does not type check, keeping the original: {:?}",
+      e
+    );
+    return Ok(InlineStats::default());
+  }
+  log::debug!("inlined script: {:?}", stats);
+  *script = rewritten;
+  Ok(stats)
+}
+
+/// `nullable` is the nullability of each node, by graph, from the type info of `script`.
+fn inline_calls(
+  script: &mut TwScript,
+  nullable: &[Vec<bool>],
+  config: &InlineConfig,
+) -> InlineStats {
+  let mut stats = InlineStats::default();
+  let mut specializations: HashMap<(u32, Vec<Option<u32>>), u32> = HashMap::new();
+  for (caller, nullable) in nullable.iter().enumerate() {
+    for i in 0..script.graphs[caller].nodes.len() {
+      let g = &script.graphs[caller];
+      let (subgraph, in_edges, precondition) = match &g.nodes[i] {
+        (TwGraphNode::Call(x), in_edges, precondition) => (*x, in_edges, *precondition),
+        _ => continue,
+      };
+      let bound = in_edges
+        .iter()
+        .map(|x| bindable_const(g, &script.consts, *x, precondition))
+        .collect::<Vec<_>>();
+      // Bodies of `try` blocks must only be referenced by one node.
+      if bound.iter().all(|x| x.is_none())
+        || script.graphs[subgraph as usize]
+          .nodes
+          .iter()
+          .any(|x| matches!(x.0, TwGraphNode::Try(_)))
+      {
+        continue;
+      }
+      let key = (subgraph, bound);
+      let target = match specializations.get(&key) {
+        Some(x) => *x,
+        None => {
+          if specializations.len() >= config.max_specializations {
+            continue;
+          }
+          let x = specialize(script, subgraph, &key.1);
+          specializations.insert(key.clone(), x);
+          stats.specialized += 1;
+          x
+        }
+      };
+      let (node, in_edges, _) = &mut script.graphs[caller].nodes[i];
+      *node = TwGraphNode::Call(target);
+      *in_edges = in_edges
+        .iter()
+        .zip(key.1.iter())
+        .filter(|(_, bound)| bound.is_none())
+        .map(|(x, _)| *x)
+        .collect();
+    }
+
+    if config.max_nodes != 0 {
+      stats.inlined += inline_into(script, caller, nullable, config);
+    }
+  }
+  stats
+}
+
+/// The const index of `node`, if it is a constant that can be bound to a param of a call with the
+/// given precondition: the constant must fire whenever the call does, and never be null.
+fn bindable_const(
+  g: &TwGraph,
+  consts: &[VmConst],
+  node: u32,
+  call_precondition: Option<u32>,
+) -> Option<u32> {
+  match &g.nodes[node as usize] {
+    (TwGraphNode::LoadConst(x), _, precondition)
+      if precondition.is_none() || *precondition == call_precondition =>
+    {
+      match &consts[*x as usize] {
+        VmConst::Primitive(_) | VmConst::Bool(_) => Some(*x),
+        _ => None,
+      }
+    }
+    _ => None,
+  }
+}
+
+/// Adds a copy of `subgraph` with the params in `bound` replaced by those constants, and returns
+/// its index.
+fn specialize(script: &mut TwScript, subgraph: u32, bound: &[Option<u32>]) -> u32 {
+  let source = &script.graphs[subgraph as usize];
+  let mut param_index = vec![];
+  let mut next = 0u32;
+  for x in bound {
+    param_index.push(next);
+    if x.is_none() {
+      next += 1;
+    }
+  }
+  let unbound = |i: usize| bound[i].is_none();
+  let mut g = TwGraph {
+    name: format!("{}$specialized{}", source.name, script.graphs.len()),
+    exported: false,
+    read_only: source.read_only,
+    nodes: source
+      .nodes
+      .iter()
+      .map(|(node, in_edges, precondition)| {
+        let node = match *node {
+          TwGraphNode::LoadParam(x) => match bound[x as usize] {
+            Some(c) => TwGraphNode::LoadConst(c),
+            None => TwGraphNode::LoadParam(param_index[x as usize]),
+          },
+          x => x,
+        };
+        (node, in_edges.clone(), *precondition)
+      })
+      .collect(),
+    output: source.output,
+    param_types: source
+      .param_types
+      .iter()
+      .enumerate()
+      .filter(|(i, _)| unbound(*i))
+      .map(|(_, x)| *x)
+      .collect(),
+    param_names: source
+      .param_names
+      .iter()
+      .enumerate()
+      .filter(|(i, _)| unbound(*i))
+      .map(|(_, x)| x.clone())
+      .collect(),
+    output_type: source.output_type,
+    cache: None,
+    optional_chain: source.optional_chain.clone(),
+    deferred: source.deferred.clone(),
+    relaxed_isolation: source.relaxed_isolation,
+  };
+  optimize_graph(&mut g, &mut script.consts);
+  script.graphs.push(g);
+  (script.graphs.len() - 1) as u32
+}
+
+/// Replaces the calls in `caller` that can be inlined with the bodies of their subgraphs. Returns
+/// the number of inlined calls.
+fn inline_into(
+  script: &mut TwScript,
+  caller: usize,
+  nullable: &[bool],
+  config: &InlineConfig,
+) -> usize {
+  let g = &script.graphs[caller];
+  let inlined = (0..g.nodes.len())
+    .map(|i| can_inline(script, caller, i, nullable, config))
+    .collect::<Vec<_>>();
+  if !inlined.iter().any(|x| *x) {
+    return 0;
+  }
+
+  let mut nodes = Vec::with_capacity(g.nodes.len());
+  let mut optional_chain = Vec::with_capacity(g.nodes.len());
+  let mut deferred = Vec::with_capacity(g.nodes.len());
+  let mut new_index: Vec<Option<u32>> = vec![None; g.nodes.len()];
+  let remap = |new_index: &[Option<u32>], x: u32| {
+    new_index[x as usize].expect("inconsistency: use of an inlined call without output")
+  };
+  for (i, (node, in_edges, precondition)) in g.nodes.iter().enumerate() {
+    let in_edges = in_edges
+      .iter()
+      .map(|x| remap(&new_index, *x))
+      .collect::<Vec<_>>();
+    let precondition = precondition.map(|x| remap(&new_index, x));
+    if !inlined[i] {
+      new_index[i] = Some(nodes.len() as u32);
+      nodes.push((*node, in_edges, precondition));
+      optional_chain.push(g.is_optional_chained(i));
+      deferred.push(g.is_deferred(i));
+      continue;
+    }
+
+    // Nodes of the body without a precondition get the one of the call. Params go through a
+    // `Nop` with that precondition, so that nothing in the body runs unless the call would.
+    let subgraph = &script.graphs[node.subgraph_references()[0] as usize];
+    let mut body_index = vec![0u32; subgraph.nodes.len()];
+    for (j, (node, body_in_edges, body_precondition)) in subgraph.nodes.iter().enumerate() {
+      let (node, body_in_edges, body_precondition) = match node {
+        TwGraphNode::LoadParam(x) => match precondition {
+          Some(_) if is_used(subgraph, j as u32) => {
+            (TwGraphNode::Nop, vec![in_edges[*x as usize]], precondition)
+          }
+          _ => {
+            body_index[j] = in_edges[*x as usize];
+            continue;
+          }
+        },
+        _ => (
+          *node,
+          body_in_edges
+            .iter()
+            .map(|x| body_index[*x as usize])
+            .collect(),
+          body_precondition
+            .map(|x| body_index[x as usize])
+            .or(precondition),
+        ),
+      };
+      body_index[j] = nodes.len() as u32;
+      nodes.push((node, body_in_edges, body_precondition));
+      optional_chain.push(match node {
+        TwGraphNode::Nop => TwGraphNode::Nop.is_optional_chained(),
+        _ => subgraph.is_optional_chained(j),
+      });
+      deferred.push(g.is_deferred(i));
+    }
+    new_index[i] = subgraph.output.map(|x| body_index[x as usize]);
+  }
+
+  let count = inlined.iter().filter(|x| **x).count();
+  let output = g.output.map(|x| remap(&new_index, x));
+  let g = &mut script.graphs[caller];
+  g.nodes = nodes;
+  g.optional_chain = optional_chain;
+  if !g.deferred.is_empty() {
+    g.deferred = deferred;
+  }
+  g.output = output;
+  count
+}
+
+fn is_used(g: &TwGraph, node: u32) -> bool {
+  g.output == Some(node)
+    || g
+      .nodes
+      .iter()
+      .any(|(_, in_edges, precondition)| in_edges.contains(&node) || *precondition == Some(node))
+}
+
+/// Whether node `i` of `caller` is a call that can be inlined:
+///
+/// - the subgraph is small, is not the caller, calls no other graph, and defers no nodes until the
+///   rest of its body has run;
+/// - both are `@relaxed_isolation`, or neither is;
+/// - the params of the call are never null, and surely fire if the precondition of the call is
+///   satisfied, so that the body runs exactly when the call would;
+/// - the output of the call is not used if the subgraph has none.
+fn can_inline(
+  script: &TwScript,
+  caller: usize,
+  i: usize,
+  nullable: &[bool],
+  config: &InlineConfig,
+) -> bool {
+  let g = &script.graphs[caller];
+  let (subgraph, in_edges, precondition) = match &g.nodes[i] {
+    (TwGraphNode::Call(x), in_edges, precondition) => (*x as usize, in_edges, *precondition),
+    _ => return false,
+  };
+  let body = &script.graphs[subgraph];
+  if subgraph == caller
+    || body.nodes.len() > config.max_nodes
+    || !body.deferred.is_empty()
+    || body.relaxed_isolation != g.relaxed_isolation
+    || in_edges.len() != body.param_types.len()
+    || body.nodes.iter().any(|(node, in_edges, precondition)| {
+      !node.subgraph_references().is_empty()
+        || (matches!(node, TwGraphNode::LoadParam(_))
+          && (!in_edges.is_empty() || precondition.is_some()))
+    })
+  {
+    return false;
+  }
+  if body.output.is_none() && is_used(g, i as u32) {
+    return false;
+  }
+  let mut memo = HashMap::new();
+  in_edges
+    .iter()
+    .all(|x| !nullable[*x as usize] && fires_with(g, *x, precondition, &mut memo))
+}
+
+/// Whether `node` surely fires once a node with precondition `precondition` may run: all nodes it
+/// depends on fire, and its own precondition, if any, is implied by `precondition`.
+fn fires_with(
+  g: &TwGraph,
+  node: u32,
+  precondition: Option<u32>,
+  memo: &mut HashMap<u32, bool>,
+) -> bool {
+  if let Some(x) = memo.get(&node) {
+    return *x;
+  }
+  let (n, in_edges, node_precondition) = &g.nodes[node as usize];
+  let x = !n.is_select()
+    && !n.is_cond()
+    && match node_precondition {
+      Some(x) => implies(g, precondition, *x),
+      None => true,
+    }
+    && in_edges
+      .iter()
+      .all(|x| fires_with(g, *x, precondition, memo));
+  memo.insert(node, x);
+  x
+}
+
+/// Whether precondition `p` being satisfied implies that `q` is, following the preconditions of
+/// the nodes that `p` goes through.
+fn implies(g: &TwGraph, mut p: Option<u32>, q: u32) -> bool {
+  while let Some(x) = p {
+    if x == q {
+      return true;
+    }
+    p = g.nodes[x as usize].2;
+  }
+  false
+}
@@ -0,0 +1,263 @@
+use std::sync::Arc;
+
+use bumpalo::Bump;
+
+use crate::{
+  data::value::PrimitiveValue,
+  schema::{
+    compile::{compile, CompiledSchema},
+    grammar::parse,
+  },
+  storage_plan::{planner::generate_plan_for_schema, StoragePlan},
+  test_util::create_kv,
+};
+
+use super::{
+  asm::codegen::compile_twscript,
+  bytecode::{TwGraphNode, TwScript},
+  exec::{generate_root_map, Executor},
+  inline::{inline_script, InlineConfig, InlineStats},
+  typeck::GlobalTyckContext,
+  vm::TwVm,
+  vm_value::VmValue,
+};
+
+const SCHEMA: &str = r#"
+type Item {
+  @primary
+  id: string,
+  name: string,
+}
+export set<Item> items;
+"#;
+
+fn load_schema() -> (CompiledSchema, StoragePlan) {
+  let alloc = Bump::new();
+  let schema = compile(&parse(&alloc, SCHEMA).unwrap()).unwrap();
+  let plan = generate_plan_for_schema(&Default::default(), &Default::default(), &schema).unwrap();
+  (schema, plan)
+}
+
+fn calls(script: &TwScript, graph: &str) -> usize {
+  script
+    .graphs
+    .iter()
+    .find(|x| x.name == graph)
+    .unwrap()
+    .nodes
+    .iter()
+    .filter(|x| matches!(x.0, TwGraphNode::Call(_)))
+    .count()
+}
+
+/// Runs the exported graph `graph` of each script in turn against the same store, and returns
+/// the outputs.
+async fn run(
+  schema: &CompiledSchema,
+  plan: &StoragePlan,
+  scripts: &[&TwScript],
+  graph: &str,
+  params: &[Arc<VmValue<'static>>],
+) -> Vec<Option<VmValue<'static>>> {
+  let kv = create_kv();
+  let mut outputs = vec![];
+  for script in scripts {
+    let vm = TwVm::new(schema, plan, script).unwrap();
+    let type_info = GlobalTyckContext::new(&vm).unwrap().typeck().unwrap();
+    let mut graph_params = vec![Arc::new(generate_root_map(schema, plan).unwrap())];
+    graph_params.extend(params.iter().cloned());
+    let output = Executor::new(&vm, &*kv, &type_info)
+      .run_graph(
+        vm.lookup_exported_graph_by_name(graph).unwrap(),
+        &graph_params,
+      )
+      .await
+      .unwrap();
+    outputs.push(output.map(|x| match &*x {
+      VmValue::Primitive(x) => VmValue::Primitive(x.clone()),
+      VmValue::Bool(x) => VmValue::Bool(*x),
+      x => panic!("unexpected output: {:?}", x),
+    }));
+  }
+  outputs
+}
+
+fn string(x: &str) -> VmValue<'static> {
+  VmValue::Primitive(PrimitiveValue::String(x.to_string()))
+}
+
+#[tokio::test]
+async fn inline_and_specialize() {
+  let _ = pretty_env_logger::try_init();
+  let (schema, plan) = load_schema();
+  let code = r#"
+    export graph main(root: schema, x: int64): int64 {
+      return call(add) [x, 1] + call(add) [x, 2] + call(add) [x, x];
+    }
+    graph add(a: int64, b: int64): int64 {
+      return a * 10 + b;
+    }
+  "#;
+  let original = compile_twscript(code).unwrap();
+  let mut script = compile_twscript(code).unwrap();
+  let stats = inline_script(&mut script, &schema, &plan, &Default::default()).unwrap();
+  assert_eq!(
+    stats,
+    InlineStats {
+      specialized: 2,
+      inlined: 3,
+    }
+  );
+  assert_eq!(calls(&script, "main"), 0);
+
+  let params = [Arc::new(VmValue::Primitive(PrimitiveValue::Int64(3)))];
+  let outputs = run(&schema, &plan, &[&original, &script], "main", &params).await;
+  assert_eq!(
+    outputs[0],
+    Some(VmValue::Primitive(PrimitiveValue::Int64(96)))
+  );
+  assert_eq!(outputs[0], outputs[1]);
+
+  // Specialization alone.
+  let mut script = compile_twscript(code).unwrap();
+  let config = InlineConfig {
+    max_nodes: 0,
+    ..Default::default()
+  };
+  let stats = inline_script(&mut script, &schema, &plan, &config).unwrap();
+  assert_eq!(stats.inlined, 0);
+  assert_eq!(calls(&script, "main"), 3);
+  let outputs = run(&schema, &plan, &[&script], "main", &params).await;
+  assert_eq!(
+    outputs[0],
+    Some(VmValue::Primitive(PrimitiveValue::Int64(96)))
+  );
+}
+
+#[tokio::test]
+async fn inlined_calls_keep_conditions() {
+  let _ = pretty_env_logger::try_init();
+  let (schema, plan) = load_schema();
+  let code = r#"
+    export graph put(root: schema, id: string, flag: bool) {
+      name = id + "!";
+      if flag {
+        call(insert) [root.items, id, name];
+      }
+    }
+    export graph name(root: schema, id: string): string {
+      return (point_get root.items id)?.name ?? "";
+    }
+    export graph check(root: schema, x: int64): int64 {
+      if x < 0 {
+        call(fail) [x];
+      }
+      return x;
+    }
+    graph fail(x: int64) {
+      throw "negative";
+    }
+    graph insert(items: set<Item>, id: string, name: string) {
+      s_insert items $ build_table(Item) $ m_insert(id) id $ m_insert(name) name create_map;
+    }
+  "#;
+  let mut script = compile_twscript(code).unwrap();
+  let stats = inline_script(&mut script, &schema, &plan, &Default::default()).unwrap();
+  assert_eq!(stats.inlined, 2);
+  assert_eq!(calls(&script, "put"), 0);
+  assert_eq!(calls(&script, "check"), 0);
+
+  let kv = create_kv();
+  let vm = TwVm::new(&schema, &plan, &script).unwrap();
+  let type_info = GlobalTyckContext::new(&vm).unwrap().typeck().unwrap();
+  let root = Arc::new(generate_root_map(&schema, &plan).unwrap());
+  let mut executor = Executor::new(&vm, &*kv, &type_info);
+  let put = vm.lookup_exported_graph_by_name("put").unwrap();
+  let name = vm.lookup_exported_graph_by_name("name").unwrap();
+  for (id, flag) in [("a", false), ("b", true)] {
+    executor
+      .run_graph(
+        put,
+        &[
+          root.clone(),
+          Arc::new(string(id)),
+          Arc::new(VmValue::Bool(flag)),
+        ],
+      )
+      .await
+      .unwrap();
+  }
+  for (id, expected) in [("a", ""), ("b", "b!")] {
+    let output = executor
+      .run_graph(name, &[root.clone(), Arc::new(string(id))])
+      .await
+      .unwrap()
+      .unwrap();
+    assert_eq!(*output, string(expected));
+  }
+
+  let check = vm.lookup_exported_graph_by_name("check").unwrap();
+  let int64 = |x| Arc::new(VmValue::Primitive(PrimitiveValue::Int64(x)));
+  let output = executor
+    .run_graph(check, &[root.clone(), int64(1)])
+    .await
+    .unwrap()
+    .unwrap();
+  assert_eq!(*output, *int64(1));
+  let err = executor
+    .run_graph(check, &[root.clone(), int64(-1)])
+    .await
+    .unwrap_err();
+  assert_eq!(err.to_string(), "script thrown error: `negative`");
+}
+
+#[test]
+fn calls_not_inlined() {
+  let (schema, plan) = load_schema();
+
+  // The param may be null, in which case the call outputs null without running.
+  let mut script = compile_twscript(
+    r#"
+    export graph main(root: schema, id: string): string {
+      return call(greet) [(point_get root.items id)?.name] ?? "";
+    }
+    graph greet(name: string): string {
+      return "hello " + name;
+    }
+    "#,
+  )
+  .unwrap();
+  let stats = inline_script(&mut script, &schema, &plan, &Default::default()).unwrap();
+  assert_eq!(stats, InlineStats::default());
+
+  // Recursive, and over the budget.
+  let code = r#"
+    export graph main(root: schema, x: int64): int64 {
+      return call(fib) [x] + call(big) [x];
+    }
+    graph fib(x: int64): int64 {
+      if x == 1 || x == 2 {
+        v1 = 1;
+      } else {
+        v2 = call(fib) [x - 1] + call(fib) [x - 2];
+      }
+      return select v1 v2;
+    }
+    graph big(x: int64): int64 {
+      return x + 1 + x + 2 + x + 3 + x + 4 + x + 5 + x + 6 + x + 7 + x + 8 + x + 9;
+    }
+  "#;
+  let mut script = compile_twscript(code).unwrap();
+  let stats = inline_script(&mut script, &schema, &plan, &Default::default()).unwrap();
+  assert_eq!(stats, InlineStats::default());
+  assert_eq!(calls(&script, "main"), 2);
+
+  let mut script = compile_twscript(code).unwrap();
+  let config = InlineConfig {
+    max_nodes: 64,
+    ..Default::default()
+  };
+  let stats = inline_script(&mut script, &schema, &plan, &config).unwrap();
+  assert_eq!(stats.inlined, 1);
+  assert_eq!(calls(&script, "main"), 1);
+}
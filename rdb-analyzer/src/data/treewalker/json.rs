@@ -0,0 +1,70 @@
+use serde_json::Value;
+use thiserror::Error;
+
+#[derive(Error, Debug)]
+pub enum JsonPathError {
+  #[error("invalid json path: `{0}`")]
+  InvalidPath(String),
+}
+
+#[derive(Debug, Eq, PartialEq)]
+enum PathSegment<'a> {
+  Key(&'a str),
+  Index(usize),
+}
+
+/// Parses a path like `a.b[0]`. The empty path refers to the whole document.
+fn parse_path(path: &str) -> Result<Vec<PathSegment<'_>>, JsonPathError> {
+  let invalid = || JsonPathError::InvalidPath(path.to_string());
+  let bytes = path.as_bytes();
+  let mut segments = vec![];
+  if path.is_empty() {
+    return Ok(segments);
+  }
+
+  let mut i = 0;
+  loop {
+    let start = i;
+    while i < bytes.len() && bytes[i] != b'.' && bytes[i] != b'[' {
+      i += 1;
+    }
+    if i > start {
+      segments.push(PathSegment::Key(&path[start..i]));
+    } else if start != 0 || bytes[i] != b'[' {
+      // Only the first segment may be a bare index.
+      return Err(invalid());
+    }
+
+    while i < bytes.len() && bytes[i] == b'[' {
+      let end = path[i..].find(']').ok_or_else(invalid)? + i;
+      let index = path[i + 1..end].parse().map_err(|_| invalid())?;
+      segments.push(PathSegment::Index(index));
+      i = end + 1;
+    }
+
+    if i == bytes.len() {
+      break;
+    }
+    if bytes[i] != b'.' {
+      return Err(invalid());
+    }
+    i += 1;
+  }
+  Ok(segments)
+}
+
+/// Looks up `path` in `doc`. Returns `None` if nothing is at the path.
+pub fn lookup<'v>(doc: &'v Value, path: &str) -> Result<Option<&'v Value>, JsonPathError> {
+  let mut current = doc;
+  for segment in parse_path(path)? {
+    let next = match segment {
+      PathSegment::Key(x) => current.as_object().and_then(|o| o.get(x)),
+      PathSegment::Index(x) => current.as_array().and_then(|a| a.get(x)),
+    };
+    current = match next {
+      Some(x) => x,
+      None => return Ok(None),
+    };
+  }
+  Ok(Some(current))
+}
@@ -0,0 +1,45 @@
+use serde_json::json;
+
+use super::{
+  json::lookup,
+  serialize::SerializedVmValue,
+  vm_value::{VmType, VmValue},
+};
+
+#[test]
+fn json_paths() {
+  let doc = json!({
+    "a": { "b": [10, { "c": "x" }] },
+    "d.e": 1,
+  });
+  assert_eq!(lookup(&doc, "").unwrap(), Some(&doc));
+  assert_eq!(lookup(&doc, "a.b[0]").unwrap(), Some(&json!(10)));
+  assert_eq!(lookup(&doc, "a.b[1].c").unwrap(), Some(&json!("x")));
+  assert_eq!(lookup(&doc, "a.b[2]").unwrap(), None);
+  assert_eq!(lookup(&doc, "a.c").unwrap(), None);
+  assert_eq!(lookup(&doc, "a[0]").unwrap(), None);
+  assert_eq!(lookup(&json!([[1, 2]]), "[0][1]").unwrap(), Some(&json!(2)));
+
+  for path in &[".a", "a.", "a..b", "a[", "a[x]", "a[0]b", "a[-1]"] {
+    assert!(lookup(&doc, path).is_err(), "{}", path);
+  }
+}
+
+#[test]
+fn json_decode() {
+  let v: SerializedVmValue = serde_json::from_value(json!({
+    "M": { "a": { "L": [1, 2.5, "x", null] }, "b": false }
+  }))
+  .unwrap();
+  assert_eq!(
+    v.decode(&VmType::Json).unwrap(),
+    VmValue::Json(json!({ "a": [1, 2.5, "x", null], "b": false }))
+  );
+  assert_eq!(
+    v.decode(&VmType::Json)
+      .map(|x| SerializedVmValue::encode(&x, &Default::default()).unwrap())
+      .map(|x| serde_json::to_value(&x).unwrap())
+      .unwrap(),
+    serde_json::to_value(&v).unwrap()
+  );
+}
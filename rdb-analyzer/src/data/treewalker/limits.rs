@@ -0,0 +1,332 @@
+use std::{
+  fmt,
+  sync::{
+    atomic::{AtomicU64, Ordering},
+    Arc,
+  },
+  time::{Duration, Instant},
+};
+
+use anyhow::Result;
+use async_trait::async_trait;
+
+use crate::data::kv::{KvError, KvIterator, KvKeyIterator, KvTransaction, ScanOptions};
+
+use super::exec::ExecError;
+
+/// Limits on the resources used by a graph run through `Executor::run_graph` or
+/// `Executor::run_graph_in_transaction`. A run that exceeds a limit fails with
+/// `ExecError::LimitExceeded`.
+///
+/// Counters are reset on each attempt, so retries after a conflict get the full budget again.
+/// The timeout covers all attempts.
+#[derive(Clone, Debug)]
+pub struct ExecLimits {
+  /// Wall-clock time of the run. Checked before each node fires, so a single slow KV operation
+  /// may overrun it.
+  pub timeout: Option<Duration>,
+
+  /// Max number of KV reads. Each key returned by a scan counts as one read.
+  pub max_kv_reads: Option<u64>,
+
+  /// Max number of KV writes, including deletions.
+  pub max_kv_writes: Option<u64>,
+
+  /// Max number of subgraph invocations, including one per member visited by a `reduce` and one
+  /// per iteration of a `for`.
+  pub max_subgraph_calls: Option<u64>,
+
+  /// Max size of a single value read from or written to the KV store, in bytes.
+  pub max_value_size: Option<usize>,
+
+  pub max_recursion_depth: usize,
+}
+
+impl Default for ExecLimits {
+  fn default() -> Self {
+    Self {
+      timeout: None,
+      max_kv_reads: None,
+      max_kv_writes: None,
+      max_subgraph_calls: None,
+      max_value_size: None,
+      max_recursion_depth: 128,
+    }
+  }
+}
+
+impl ExecLimits {
+  /// Takes the stricter of each limit of `self` and `other`.
+  pub fn tighten(&self, other: &ExecLimits) -> ExecLimits {
+    fn min<T: Ord + Copy>(a: Option<T>, b: Option<T>) -> Option<T> {
+      match (a, b) {
+        (Some(a), Some(b)) => Some(a.min(b)),
+        (a, b) => a.or(b),
+      }
+    }
+    ExecLimits {
+      timeout: min(self.timeout, other.timeout),
+      max_kv_reads: min(self.max_kv_reads, other.max_kv_reads),
+      max_kv_writes: min(self.max_kv_writes, other.max_kv_writes),
+      max_subgraph_calls: min(self.max_subgraph_calls, other.max_subgraph_calls),
+      max_value_size: min(self.max_value_size, other.max_value_size),
+      max_recursion_depth: self.max_recursion_depth.min(other.max_recursion_depth),
+    }
+  }
+}
+
+/// The limit that a run exceeded, with its configured value.
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub enum ExecLimit {
+  Timeout(Duration),
+  KvReads(u64),
+  KvWrites(u64),
+  SubgraphCalls(u64),
+  ValueSize(usize),
+  RecursionDepth(usize),
+}
+
+impl ExecLimit {
+  /// A stable name for the limit, for structured error responses.
+  pub fn name(&self) -> &'static str {
+    match self {
+      Self::Timeout(_) => "timeout",
+      Self::KvReads(_) => "kv_reads",
+      Self::KvWrites(_) => "kv_writes",
+      Self::SubgraphCalls(_) => "subgraph_calls",
+      Self::ValueSize(_) => "value_size",
+      Self::RecursionDepth(_) => "recursion_depth",
+    }
+  }
+}
+
+impl fmt::Display for ExecLimit {
+  fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+    match self {
+      Self::Timeout(x) => write!(f, "timeout of {} ms", x.as_millis()),
+      Self::KvReads(x) => write!(f, "{} kv reads", x),
+      Self::KvWrites(x) => write!(f, "{} kv writes", x),
+      Self::SubgraphCalls(x) => write!(f, "{} subgraph calls", x),
+      Self::ValueSize(x) => write!(f, "value size of {} bytes", x),
+      Self::RecursionDepth(x) => write!(f, "recursion depth of {}", x),
+    }
+  }
+}
+
+/// Tracks the usage of one run against its limits.
+pub(crate) struct LimitTracker {
+  limits: ExecLimits,
+  deadline: Option<Instant>,
+  subgraph_calls: AtomicU64,
+  kv: Arc<KvUsage>,
+}
+
+/// Shared with the key iterators of scans, which outlive the borrow of the transaction.
+struct KvUsage {
+  reads: AtomicU64,
+  writes: AtomicU64,
+  max_reads: Option<u64>,
+  max_writes: Option<u64>,
+  max_value_size: Option<usize>,
+}
+
+impl LimitTracker {
+  pub fn new(limits: ExecLimits) -> Self {
+    let kv = Arc::new(KvUsage {
+      reads: AtomicU64::new(0),
+      writes: AtomicU64::new(0),
+      max_reads: limits.max_kv_reads,
+      max_writes: limits.max_kv_writes,
+      max_value_size: limits.max_value_size,
+    });
+    Self {
+      limits,
+      deadline: None,
+      subgraph_calls: AtomicU64::new(0),
+      kv,
+    }
+  }
+
+  /// Starts the timeout of a run.
+  pub fn start_run(&mut self) {
+    self.deadline = self.limits.timeout.map(|x| Instant::now() + x);
+  }
+
+  pub fn start_attempt(&mut self) {
+    *self.subgraph_calls.get_mut() = 0;
+    self.kv.reads.store(0, Ordering::Relaxed);
+    self.kv.writes.store(0, Ordering::Relaxed);
+  }
+
+  pub fn check_deadline(&self) -> Result<()> {
+    match (self.deadline, self.limits.timeout) {
+      (Some(deadline), Some(timeout)) if Instant::now() >= deadline => {
+        Err(exceeded(ExecLimit::Timeout(timeout)))
+      }
+      _ => Ok(()),
+    }
+  }
+
+  /// Called before running a graph at `recursion_depth`, where 0 is the top-level graph.
+  pub fn enter_graph(&self, recursion_depth: usize) -> Result<()> {
+    if recursion_depth >= self.limits.max_recursion_depth {
+      return Err(exceeded(ExecLimit::RecursionDepth(
+        self.limits.max_recursion_depth,
+      )));
+    }
+    if recursion_depth > 0 {
+      count(
+        &self.subgraph_calls,
+        self.limits.max_subgraph_calls,
+        ExecLimit::SubgraphCalls,
+      )?;
+    }
+    self.check_deadline()
+  }
+
+  /// Whether KV operations need to go through `wrap_txn`.
+  pub fn limits_kv(&self) -> bool {
+    self.kv.max_reads.is_some() || self.kv.max_writes.is_some() || self.kv.max_value_size.is_some()
+  }
+
+  pub fn wrap_txn<'a>(&self, txn: &'a dyn KvTransaction) -> LimitedKvTransaction<'a> {
+    LimitedKvTransaction {
+      inner: txn,
+      kv: self.kv.clone(),
+    }
+  }
+}
+
+impl KvUsage {
+  fn record_read(&self, value_len: usize) -> Result<()> {
+    count(&self.reads, self.max_reads, ExecLimit::KvReads)?;
+    self.check_value_size(value_len)
+  }
+
+  fn record_write(&self, value_len: usize) -> Result<()> {
+    count(&self.writes, self.max_writes, ExecLimit::KvWrites)?;
+    self.check_value_size(value_len)
+  }
+
+  fn check_value_size(&self, value_len: usize) -> Result<()> {
+    match self.max_value_size {
+      Some(x) if value_len > x => Err(exceeded(ExecLimit::ValueSize(x))),
+      _ => Ok(()),
+    }
+  }
+}
+
+fn count(counter: &AtomicU64, limit: Option<u64>, kind: fn(u64) -> ExecLimit) -> Result<()> {
+  let n = counter.fetch_add(1, Ordering::Relaxed) + 1;
+  match limit {
+    Some(x) if n > x => Err(exceeded(kind(x))),
+    _ => Ok(()),
+  }
+}
+
+fn exceeded(limit: ExecLimit) -> anyhow::Error {
+  ExecError::LimitExceeded(limit).into()
+}
+
+/// A transaction that counts KV operations against the limits of a run.
+pub(crate) struct LimitedKvTransaction<'a> {
+  inner: &'a dyn KvTransaction,
+  kv: Arc<KvUsage>,
+}
+
+struct LimitedKvKeyIterator {
+  inner: Box<dyn KvKeyIterator>,
+  kv: Arc<KvUsage>,
+}
+
+struct LimitedKvIterator {
+  inner: Box<dyn KvIterator>,
+  kv: Arc<KvUsage>,
+}
+
+#[async_trait]
+impl<'a> KvTransaction for LimitedKvTransaction<'a> {
+  async fn get(&self, key: &[u8]) -> Result<Option<Vec<u8>>> {
+    let value = self.inner.get(key).await?;
+    self
+      .kv
+      .record_read(value.as_ref().map(|x| x.len()).unwrap_or(0))?;
+    Ok(value)
+  }
+
+  async fn put(&self, key: &[u8], value: &[u8]) -> Result<()> {
+    self.kv.record_write(value.len())?;
+    self.inner.put(key, value).await
+  }
+
+  async fn put_many(&self, entries: &[(Vec<u8>, Vec<u8>)]) -> Result<()> {
+    for (_, value) in entries {
+      self.kv.record_write(value.len())?;
+    }
+    self.inner.put_many(entries).await
+  }
+
+  async fn delete(&self, key: &[u8]) -> Result<()> {
+    self.kv.record_write(0)?;
+    self.inner.delete(key).await
+  }
+
+  async fn delete_range(&self, start: &[u8], end: &[u8]) -> Result<()> {
+    self.kv.record_write(0)?;
+    self.inner.delete_range(start, end).await
+  }
+
+  async fn atomic_add(&self, key: &[u8], delta: i64) -> Result<()> {
+    self.kv.record_write(8)?;
+    self.inner.atomic_add(key, delta).await
+  }
+
+  async fn scan_keys(&self, start: &[u8], end: &[u8]) -> Result<Box<dyn KvKeyIterator>> {
+    Ok(Box::new(LimitedKvKeyIterator {
+      inner: self.inner.scan_keys(start, end).await?,
+      kv: self.kv.clone(),
+    }))
+  }
+
+  async fn add_read_conflict_key(&self, key: &[u8]) -> Result<()> {
+    self.inner.add_read_conflict_key(key).await
+  }
+
+  async fn scan(
+    &self,
+    start: &[u8],
+    end: &[u8],
+    options: ScanOptions,
+  ) -> Result<Box<dyn KvIterator>> {
+    Ok(Box::new(LimitedKvIterator {
+      inner: self.inner.scan(start, end, options).await?,
+      kv: self.kv.clone(),
+    }))
+  }
+
+  async fn commit(self: Box<Self>) -> Result<(), KvError> {
+    unreachable!("the transaction of a run is committed by the executor")
+  }
+}
+
+#[async_trait]
+impl KvKeyIterator for LimitedKvKeyIterator {
+  async fn next(&mut self) -> Result<Option<Vec<u8>>> {
+    let key = self.inner.next().await?;
+    if key.is_some() {
+      self.kv.record_read(0)?;
+    }
+    Ok(key)
+  }
+}
+
+#[async_trait]
+impl KvIterator for LimitedKvIterator {
+  async fn next(&mut self) -> Result<Option<(Vec<u8>, Vec<u8>)>> {
+    let entry = self.inner.next().await?;
+    if let Some((_, value)) = &entry {
+      self.kv.record_read(value.len())?;
+    }
+    Ok(entry)
+  }
+}
@@ -1,13 +1,41 @@
+pub mod acl;
 pub mod asm;
 pub mod bytecode;
+pub mod bytecode_format;
+pub mod cursor;
+pub mod dfvis;
 pub mod exec;
+pub mod inline;
+pub mod json;
+pub mod limits;
+pub mod optimize;
 pub mod serialize;
+pub mod trace;
 pub mod typeck;
+pub mod viz;
 pub mod vm;
 pub mod vm_value;
 
 #[cfg(test)]
 mod typeck_test;
 
+#[cfg(test)]
+mod bytecode_format_test;
+
 #[cfg(test)]
 mod exec_test;
+
+#[cfg(test)]
+mod inline_test;
+
+#[cfg(test)]
+mod json_test;
+
+#[cfg(test)]
+mod optimize_test;
+
+#[cfg(test)]
+mod sim_test;
+
+#[cfg(test)]
+mod viz_test;
@@ -0,0 +1,265 @@
+//! Optimizations on compiled scripts: constant folding, deduplication of identical nodes, and
+//! removal of unused nodes.
+//!
+//! Scripts are optimized right after they are compiled, before they are type checked against a
+//! schema. So the passes must not hide type errors: only nodes whose constant parameters have the
+//! types that the node accepts are folded, and unused nodes are only removed if they take no
+//! parameters or have an identical copy that is kept.
+
+use std::{
+  collections::{hash_map::Entry, HashMap},
+  sync::Arc,
+};
+
+use crate::schema::compile::PrimitiveType;
+
+use super::{
+  bytecode::{TwGraph, TwGraphNode, TwScript},
+  exec::eval_pure_node,
+  vm_value::{VmConst, VmValue},
+};
+
+#[derive(Default, Debug, Copy, Clone, Eq, PartialEq)]
+pub struct OptimizeStats {
+  /// Nodes replaced with a `LoadConst` of their output.
+  pub folded: usize,
+
+  /// Nodes replaced with an identical node.
+  pub deduplicated: usize,
+
+  /// Nodes removed, including deduplicated ones.
+  pub removed: usize,
+}
+
+/// Optimizes all graphs of `script` in place. The optimized graphs produce the same outputs and
+/// effects, and fail in the same cases.
+pub fn optimize_script(script: &mut TwScript) -> OptimizeStats {
+  let mut stats = OptimizeStats::default();
+  for g in &mut script.graphs {
+    let x = optimize_graph(g, &mut script.consts);
+    stats.folded += x.folded;
+    stats.deduplicated += x.deduplicated;
+    stats.removed += x.removed;
+  }
+  log::debug!("optimized script: {:?}", stats);
+  stats
+}
+
+/// Optimizes a single graph in place, adding the constants it folds to `consts`.
+pub(super) fn optimize_graph(g: &mut TwGraph, consts: &mut Vec<VmConst>) -> OptimizeStats {
+  let folded = fold_constants(g, consts);
+  let (replacement, deduplicated) = deduplicate(g);
+  let removed = remove_unused(g, &replacement);
+  OptimizeStats {
+    folded,
+    deduplicated,
+    removed,
+  }
+}
+
+/// Replaces pure nodes whose parameters are all unconditional constants with a constant.
+fn fold_constants(g: &mut TwGraph, consts: &mut Vec<VmConst>) -> usize {
+  let mut folded = 0;
+  for i in 0..g.nodes.len() {
+    let (node, in_edges, _) = &g.nodes[i];
+    if in_edges.is_empty() || !node.is_pure() {
+      continue;
+    }
+    let params = match in_edges
+      .iter()
+      .map(|x| match &g.nodes[*x as usize] {
+        (TwGraphNode::LoadConst(x), _, None) => match &consts[*x as usize] {
+          VmConst::Primitive(x) => Some(VmValue::Primitive(x.clone())),
+          VmConst::Bool(x) => Some(VmValue::Bool(*x)),
+          _ => None,
+        },
+        _ => None,
+      })
+      .collect::<Option<Vec<_>>>()
+    {
+      Some(x) => x,
+      None => continue,
+    };
+    if !accepts_params(node, &params) {
+      continue;
+    }
+    let params = params.into_iter().map(Arc::new).collect::<Vec<_>>();
+
+    // Failures are left to the run, where they are reported like any other.
+    let output = match eval_pure_node(node, &params) {
+      Ok(Some(x)) => match &*x {
+        VmValue::Primitive(x) => VmConst::Primitive(x.clone()),
+        VmValue::Bool(x) => VmConst::Bool(*x),
+        _ => continue,
+      },
+      _ => continue,
+    };
+    let const_index = match consts.iter().position(|x| *x == output) {
+      Some(x) => x,
+      None => {
+        consts.push(output);
+        consts.len() - 1
+      }
+    };
+    g.nodes[i].0 = TwGraphNode::LoadConst(const_index as u32);
+    g.nodes[i].1.clear();
+    folded += 1;
+  }
+  folded
+}
+
+/// Whether `node` accepts `params`, as the type checker would see it. Constant parameters are
+/// never null.
+fn accepts_params(node: &TwGraphNode, params: &[VmValue]) -> bool {
+  use PrimitiveType::{Bytes, Double, Int64, String};
+  use TwGraphNode as N;
+
+  let types = params
+    .iter()
+    .map(|x| match x {
+      VmValue::Primitive(x) => Some(x.get_type()),
+      _ => None,
+    })
+    .collect::<Vec<_>>();
+  match (node, types.as_slice()) {
+    (N::Eq | N::Ne, [l, r]) => l == r,
+    (N::Lt | N::Le | N::Gt | N::Ge, [Some(l), Some(r)]) => {
+      l == r && matches!(l, Int64 | Double | String)
+    }
+    (N::And | N::Or, [None, None]) | (N::Not, [None]) | (N::IsNull, [_]) => true,
+    (N::Add, [Some(l), Some(r)]) => l == r && matches!(l, Int64 | Double | String),
+    (N::Sub | N::Mul | N::Div | N::Mod, [Some(l), Some(r)]) => {
+      l == r && matches!(l, Int64 | Double)
+    }
+    (N::Neg, [Some(Int64 | Double)]) => true,
+    (N::FormatDouble, [Some(Double)]) => true,
+    (
+      N::ParseDouble
+      | N::StrToLower
+      | N::StrLength
+      | N::BytesFromHex
+      | N::BytesFromBase64
+      | N::BytesFromString,
+      [Some(String)],
+    ) => true,
+    (N::StrStartsWith | N::StrContains, [Some(String), Some(String)]) => true,
+    (N::StrSubstring, [Some(String), Some(Int64), Some(Int64)]) => true,
+    (N::BytesConcat, [Some(Bytes), Some(Bytes)]) => true,
+    (N::BytesSlice, [Some(Bytes), Some(Int64), Some(Int64)]) => true,
+    (N::BytesLength | N::BytesToHex | N::BytesToBase64 | N::BytesToString, [Some(Bytes)]) => true,
+    (N::TimeAddDays, [Some(Int64), Some(Int64)]) => true,
+    (N::TimeStartOfDay | N::TimeDayOfWeek, [Some(Int64)]) => true,
+    _ => false,
+  }
+}
+
+/// A node with its params and precondition, and whether it is optional chained and deferred.
+type NodeKey = (TwGraphNode, Vec<u32>, Option<u32>, bool, bool);
+
+/// Points the users of each pure node to the first identical node, with the same parameters,
+/// precondition and flags. Returns the node that each node is replaced with, and the number of replaced
+/// nodes.
+fn deduplicate(g: &mut TwGraph) -> (Vec<u32>, usize) {
+  let mut replacement = (0..g.nodes.len() as u32).collect::<Vec<_>>();
+  let mut seen: HashMap<NodeKey, u32> = HashMap::new();
+  let mut deduplicated = 0;
+  for i in 0..g.nodes.len() {
+    let optional_chained = g.is_optional_chained(i);
+    let deferred = g.is_deferred(i);
+    let (node, in_edges, precondition) = &mut g.nodes[i];
+    for x in in_edges.iter_mut().chain(precondition.iter_mut()) {
+      *x = replacement[*x as usize];
+    }
+    if !node.is_pure() {
+      continue;
+    }
+    match seen.entry((
+      *node,
+      in_edges.clone(),
+      *precondition,
+      optional_chained,
+      deferred,
+    )) {
+      Entry::Occupied(x) => {
+        replacement[i] = *x.get();
+        deduplicated += 1;
+      }
+      Entry::Vacant(x) => {
+        x.insert(i as u32);
+      }
+    }
+  }
+  if let Some(output) = &mut g.output {
+    *output = replacement[*output as usize];
+  }
+  (replacement, deduplicated)
+}
+
+/// Removes nodes that nothing depends on, if they are replaced by `deduplicate` or are pure nodes
+/// without parameters. Other nodes are kept, so that they are still type checked and run for their
+/// effects and failures.
+fn remove_unused(g: &mut TwGraph, replacement: &[u32]) -> usize {
+  let mut used = g
+    .nodes
+    .iter()
+    .enumerate()
+    .map(|(i, (node, in_edges, _))| {
+      replacement[i] == i as u32 && !(node.is_pure() && in_edges.is_empty())
+    })
+    .collect::<Vec<_>>();
+  if let Some(output) = g.output {
+    used[output as usize] = true;
+  }
+
+  // Nodes only depend on earlier nodes.
+  for i in (0..g.nodes.len()).rev() {
+    if used[i] {
+      let (_, in_edges, precondition) = &g.nodes[i];
+      for x in in_edges.iter().chain(precondition.iter()) {
+        used[*x as usize] = true;
+      }
+    }
+  }
+
+  let mut new_index = vec![u32::MAX; g.nodes.len()];
+  let mut next = 0u32;
+  for (i, x) in used.iter().enumerate() {
+    if *x {
+      new_index[i] = next;
+      next += 1;
+    }
+  }
+  let removed = g.nodes.len() - next as usize;
+  if removed == 0 {
+    return 0;
+  }
+
+  let mut i = 0;
+  g.nodes.retain(|_| {
+    i += 1;
+    used[i - 1]
+  });
+  for (_, in_edges, precondition) in &mut g.nodes {
+    for x in in_edges.iter_mut().chain(precondition.iter_mut()) {
+      *x = new_index[*x as usize];
+    }
+  }
+  if let Some(output) = &mut g.output {
+    *output = new_index[*output as usize];
+  }
+  if !g.optional_chain.is_empty() {
+    let mut i = 0;
+    g.optional_chain.retain(|_| {
+      i += 1;
+      used[i - 1]
+    });
+  }
+  if !g.deferred.is_empty() {
+    let mut i = 0;
+    g.deferred.retain(|_| {
+      i += 1;
+      used[i - 1]
+    });
+  }
+  removed
+}
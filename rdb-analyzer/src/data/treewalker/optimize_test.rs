@@ -0,0 +1,179 @@
+use bumpalo::Bump;
+
+use crate::{
+  data::value::PrimitiveValue,
+  schema::{compile::compile, grammar::parse},
+  storage_plan::planner::generate_plan_for_schema,
+};
+
+use super::{
+  asm::codegen::compile_twscript,
+  bytecode::{TwGraphNode, TwScript},
+  typeck::GlobalTyckContext,
+  vm::TwVm,
+  vm_value::VmConst,
+};
+
+fn count(script: &TwScript, f: impl Fn(&TwGraphNode) -> bool) -> usize {
+  script.graphs[0].nodes.iter().filter(|x| f(&x.0)).count()
+}
+
+fn typeck(script: &TwScript) -> anyhow::Result<()> {
+  let alloc = Bump::new();
+  let schema = compile(&parse(&alloc, "").unwrap()).unwrap();
+  let plan = generate_plan_for_schema(&Default::default(), &Default::default(), &schema).unwrap();
+  let vm = TwVm::new(&schema, &plan, script)?;
+  GlobalTyckContext::new(&vm)?.typeck()?;
+  Ok(())
+}
+
+#[test]
+fn fold_constants() {
+  let script = compile_twscript(
+    r#"
+    graph main(root: schema): int64 {
+      return 7 * 3 - 10 / 3 % 2 + -(4);
+    }
+    "#,
+  )
+  .unwrap();
+  let g = &script.graphs[0];
+  assert_eq!(g.nodes.len(), 1);
+  let const_index = match g.nodes[0] {
+    (TwGraphNode::LoadConst(x), ref in_edges, None) if in_edges.is_empty() => x,
+    ref x => panic!("unexpected node: {:?}", x),
+  };
+  assert_eq!(
+    script.consts[const_index as usize],
+    VmConst::Primitive(PrimitiveValue::Int64(16))
+  );
+  assert_eq!(g.output, Some(0));
+  typeck(&script).unwrap();
+
+  // Bools and strings.
+  let script = compile_twscript(
+    r#"
+    graph main(root: schema): bool {
+      return "ab" + "c" == "abc" && !(1 + 1 < 2 * 2);
+    }
+    "#,
+  )
+  .unwrap();
+  assert_eq!(script.graphs[0].nodes.len(), 1);
+  assert!(script.consts.contains(&VmConst::Bool(false)));
+}
+
+#[test]
+fn keep_failing_and_ill_typed_nodes() {
+  let script = compile_twscript(
+    r#"
+    graph main(root: schema): int64 {
+      return 1 / 0;
+    }
+    "#,
+  )
+  .unwrap();
+  assert_eq!(count(&script, |x| matches!(x, TwGraphNode::Div)), 1);
+
+  // Unused nodes that take parameters are kept, and still type checked.
+  for code in &[
+    r#"
+    graph main(root: schema): int64 {
+      return 1 + "a";
+    }
+    "#,
+    r#"
+    graph main(root: schema): int64 {
+      x = 1 + "a";
+      return 1;
+    }
+    "#,
+    r#"
+    graph main(root: schema): bool {
+      return 1 == "a";
+    }
+    "#,
+  ] {
+    let script = compile_twscript(code).unwrap();
+    assert_eq!(
+      count(&script, |x| matches!(x, TwGraphNode::Add | TwGraphNode::Eq)),
+      1,
+      "{}",
+      code
+    );
+    assert!(typeck(&script).is_err(), "{}", code);
+  }
+}
+
+#[test]
+fn deduplicate_pure_nodes() {
+  let script = compile_twscript(
+    r#"
+    graph main(root: schema, x: int64): int64 {
+      a = x + 1;
+      b = x + 1;
+      return a * b;
+    }
+    "#,
+  )
+  .unwrap();
+  assert_eq!(count(&script, |x| matches!(x, TwGraphNode::Add)), 1);
+  assert_eq!(
+    count(&script, |x| matches!(x, TwGraphNode::LoadConst(_))),
+    1
+  );
+  let g = &script.graphs[0];
+  let mul = g
+    .nodes
+    .iter()
+    .find(|x| matches!(x.0, TwGraphNode::Mul))
+    .unwrap();
+  assert_eq!(mul.1[0], mul.1[1]);
+  typeck(&script).unwrap();
+
+  // Nodes under different conditions are not merged.
+  let script = compile_twscript(
+    r#"
+    graph main(root: schema, x: int64): int64 {
+      if x > 0 {
+        r1 = x + 1;
+      } else {
+        r2 = x + 1;
+      }
+      return select r1 r2;
+    }
+    "#,
+  )
+  .unwrap();
+  assert_eq!(count(&script, |x| matches!(x, TwGraphNode::Add)), 2);
+  typeck(&script).unwrap();
+}
+
+#[test]
+fn remove_unused_nodes() {
+  let script = compile_twscript(
+    r#"
+    graph main(root: schema, x: int64): int64 {
+      unused_map = create_map;
+      unused_const = "abc";
+      unused_sum = 1 + 2;
+      return x;
+    }
+    "#,
+  )
+  .unwrap();
+  let g = &script.graphs[0];
+  assert!(
+    g.nodes
+      .iter()
+      .all(|x| matches!(x.0, TwGraphNode::LoadParam(_))),
+    "{:?}",
+    g.nodes
+  );
+  assert_eq!(g.nodes.len(), 1);
+  assert_eq!(
+    g.nodes[g.output.unwrap() as usize].0,
+    TwGraphNode::LoadParam(1)
+  );
+  typeck(&script).unwrap();
+}
@@ -4,7 +4,10 @@ use anyhow::Result;
 
 use crate::{
   data::{
-    treewalker::vm_value::{VmListValue, VmMapValue},
+    treewalker::{
+      acl::ValueAcl,
+      vm_value::{VmListValue, VmMapValue},
+    },
     value::PrimitiveValue,
   },
   schema::compile::PrimitiveType,
@@ -32,7 +35,7 @@ pub enum SerializeError {
   MissingRequiredField(String),
 }
 
-#[derive(Serialize, Deserialize, Debug)]
+#[derive(Serialize, Deserialize, Debug, Clone)]
 #[serde(untagged)]
 pub enum SerializedVmValue {
   String(String),
@@ -44,7 +47,7 @@ pub enum SerializedVmValue {
   Tagged(TaggedVmValue),
 }
 
-#[derive(Serialize, Deserialize, Debug)]
+#[derive(Serialize, Deserialize, Debug, Clone)]
 pub enum TaggedVmValue {
   M(BTreeMap<String, SerializedVmValue>),
   L(Vec<SerializedVmValue>),
@@ -57,7 +60,7 @@ pub struct VmValueEncodeConfig {
   pub enable_double: bool,
 }
 
-#[derive(Serialize, Deserialize, Debug)]
+#[derive(Serialize, Deserialize, Debug, Clone)]
 pub enum Never {}
 
 impl SerializedVmValue {
@@ -114,6 +117,48 @@ impl SerializedVmValue {
     }
   }
 
+  /// The value of `field` of a map, or `None` if it is missing or null.
+  pub fn try_get_field(&self, field: &str) -> Result<Option<&SerializedVmValue>> {
+    Ok(match self.try_unwrap_map(&[])?.get(field) {
+      Some(Self::Null(_)) | None => None,
+      Some(x) => Some(x),
+    })
+  }
+  pub fn try_get_required_field(&self, field: &str) -> Result<&SerializedVmValue> {
+    self
+      .try_get_field(field)?
+      .ok_or_else(|| SerializeError::MissingRequiredField(field.to_string()).into())
+  }
+
+  /// Like `try_unwrap_int64`, but also accepts the string encoding that is used unless
+  /// `VmValueEncodeConfig::enable_int64` is set.
+  pub fn try_to_int64(&self) -> Result<i64> {
+    match self {
+      Self::Int64(x) => Ok(*x),
+      Self::String(x) => Ok(x.parse()?),
+      _ => Err(SerializeError::UnwrapTypeMismatch.into()),
+    }
+  }
+
+  /// Accepts any encoding of a double, like `decode`.
+  pub fn try_to_double(&self) -> Result<f64> {
+    match self {
+      Self::Double(x) => Ok(*x),
+      Self::Int64(x) => Ok(*x as f64),
+      Self::String(x) => Ok(x.parse()?),
+      _ => Err(SerializeError::UnwrapTypeMismatch.into()),
+    }
+  }
+
+  /// Accepts raw bytes and base64 strings, like `decode`.
+  pub fn try_to_bytes(&self) -> Result<Vec<u8>> {
+    match self {
+      Self::Bytes(x) => Ok(x.clone()),
+      Self::String(x) => Ok(base64::decode(x)?),
+      _ => Err(SerializeError::UnwrapTypeMismatch.into()),
+    }
+  }
+
   pub fn encode(v: &VmValue, config: &VmValueEncodeConfig) -> Result<Self> {
     match v {
       VmValue::Map(x) => Ok(Self::Tagged(TaggedVmValue::M(
@@ -156,6 +201,7 @@ impl SerializedVmValue {
           .collect::<Result<_>>()?;
         Ok(Self::Tagged(TaggedVmValue::L(out)))
       }
+      VmValue::Json(x) => Ok(Self::from_json(x)),
       _ => {
         log::debug!("encode: unserializable: {:?}", v);
         Err(SerializeError::Unserializable.into())
@@ -163,6 +209,81 @@ impl SerializedVmValue {
     }
   }
 
+  /// Like `encode`, but strips the parts of the value that a caller with `role` is not allowed
+  /// to see according to `acl`.
+  ///
+  /// Map fields are removed, and other values are replaced with null.
+  pub fn encode_with_acl(
+    v: &VmValue,
+    config: &VmValueEncodeConfig,
+    acl: &ValueAcl,
+    role: Option<&str>,
+  ) -> Result<Self> {
+    if !acl.is_visible_to(role) {
+      return Ok(Self::Null(None));
+    }
+    match v {
+      VmValue::Map(x) => {
+        let mut out = BTreeMap::new();
+        for (k, v) in x.elements.iter() {
+          let v = match acl.fields.get(k) {
+            Some(field_acl) if !field_acl.is_visible_to(role) => continue,
+            Some(field_acl) => Self::encode_with_acl(&**v, config, field_acl, role)?,
+            None => Self::encode(&**v, config)?,
+          };
+          out.insert(k.to_string(), v);
+        }
+        Ok(Self::Tagged(TaggedVmValue::M(out)))
+      }
+      VmValue::List(x) => {
+        let out = x
+          .node
+          .iter()
+          .map(|x| Self::encode_with_acl(&**x, config, acl, role))
+          .collect::<Result<_>>()?;
+        Ok(Self::Tagged(TaggedVmValue::L(out)))
+      }
+      _ => Self::encode(v, config),
+    }
+  }
+
+  /// Numbers in JSON documents are always encoded as numbers.
+  fn from_json(x: &serde_json::Value) -> Self {
+    use serde_json::Value as J;
+    match x {
+      J::Null => Self::Null(None),
+      J::Bool(x) => Self::Bool(*x),
+      J::Number(x) => match x.as_i64() {
+        Some(x) => Self::Int64(x),
+        None => Self::Double(x.as_f64().unwrap_or_default()),
+      },
+      J::String(x) => Self::String(x.clone()),
+      J::Array(x) => Self::Tagged(TaggedVmValue::L(x.iter().map(Self::from_json).collect())),
+      J::Object(x) => Self::Tagged(TaggedVmValue::M(
+        x.iter()
+          .map(|(k, v)| (k.clone(), Self::from_json(v)))
+          .collect(),
+      )),
+    }
+  }
+
+  /// Bytes are converted to base64 strings.
+  pub fn to_json(&self) -> serde_json::Value {
+    use serde_json::Value as J;
+    match self {
+      Self::String(x) => J::String(x.clone()),
+      Self::Bool(x) => J::Bool(*x),
+      Self::Bytes(x) => J::String(base64::encode(x)),
+      Self::Int64(x) => J::from(*x),
+      Self::Double(x) => J::from(*x),
+      Self::Null(_) => J::Null,
+      Self::Tagged(TaggedVmValue::L(x)) => J::Array(x.iter().map(Self::to_json).collect()),
+      Self::Tagged(TaggedVmValue::M(x)) => {
+        J::Object(x.iter().map(|(k, v)| (k.clone(), v.to_json())).collect())
+      }
+    }
+  }
+
   pub fn decode<'a>(&self, ty: &VmType<&'a str>) -> Result<VmValue<'a>> {
     use SerializedVmValue as S;
     match (self, ty) {
@@ -192,6 +313,7 @@ impl SerializedVmValue {
         Ok(VmValue::List(res))
       }
       (S::Null(None), _) => Ok(VmValue::Null(ty.clone())),
+      (_, VmType::Json) => Ok(VmValue::Json(self.to_json())),
       (S::Bool(x), VmType::Bool) => Ok(VmValue::Bool(*x)),
       (S::String(x), VmType::Primitive(PrimitiveType::String)) => {
         Ok(VmValue::Primitive(PrimitiveValue::String(x.clone())))
@@ -208,9 +330,9 @@ impl SerializedVmValue {
       (S::Double(x), VmType::Primitive(PrimitiveType::Int64)) => {
         Ok(VmValue::Primitive(PrimitiveValue::Int64(*x as i64)))
       }
-      (S::String(x), VmType::Primitive(PrimitiveType::Double)) => {
-        Ok(VmValue::Primitive(PrimitiveValue::Double(x.parse()?)))
-      }
+      (S::String(x), VmType::Primitive(PrimitiveType::Double)) => Ok(VmValue::Primitive(
+        PrimitiveValue::Double(x.parse::<f64>()?.to_bits()),
+      )),
       (S::Int64(x), VmType::Primitive(PrimitiveType::Double)) => Ok(VmValue::Primitive(
         PrimitiveValue::Double((*x as f64).to_bits()),
       )),
@@ -0,0 +1,302 @@
+//! Randomized runs of concurrent clients against `SimKv`, checking the invariants of the stored
+//! data afterwards.
+//!
+//! Failures print the seed. Set `RDB_SIM_SEED` to run only that seed.
+
+use std::{
+  collections::{BTreeMap, BTreeSet},
+  sync::Arc,
+};
+
+use futures::future::join_all;
+use rand::{rngs::StdRng, Rng, SeedableRng};
+
+use crate::{
+  data::{
+    kv::{KeyValueStore, KvError},
+    pathwalker::PathWalker,
+    treewalker::{
+      asm::codegen::compile_twscript,
+      exec::{generate_root_map, ExecError, Executor, RetryPolicy},
+      typeck::{GlobalTyckContext, GlobalTypeInfo},
+      vm::TwVm,
+      vm_value::VmValue,
+    },
+    value::PrimitiveValue,
+  },
+  fixtures,
+  kv_backend::sim_kv::{FaultConfig, SimKv},
+  schema::compile::CompiledSchema,
+  storage_plan::StoragePlan,
+};
+
+const SCRIPT: &str = r#"
+export graph put(root: schema, id: string, name: string, code: int64) {
+  s_insert root.items $ build_table(Item) $ m_insert(id) id $ m_insert(name) name $ m_insert(code) code create_map;
+}
+export graph rename(root: schema, id: string, name: string) {
+  item = point_get root.items id;
+  if is_present item {
+    t_insert(name) item name;
+  }
+}
+export graph recode(root: schema, id: string, code: int64) {
+  item = point_get root.items id;
+  if is_present item {
+    t_insert(code) item code;
+  }
+}
+export graph delete(root: schema, id: string) {
+  s_delete root.items id;
+}
+export readonly graph names(root: schema): string {
+  return reduce(join) create_map "" $ scan_index(name) root.items;
+}
+graph join(ctx: map{}, current: string, item: Item): string {
+  return current + item.name;
+}
+"#;
+
+const SEEDS: u64 = 16;
+const CLIENTS: usize = 4;
+const OPS_PER_CLIENT: usize = 40;
+
+fn faults() -> FaultConfig {
+  FaultConfig {
+    read_conflict: 0.02,
+    partial_scan: 0.05,
+    commit_conflict: 0.05,
+    commit_unknown: 0.05,
+    max_yields: 3,
+  }
+}
+
+#[tokio::test]
+async fn simulate() {
+  let _ = pretty_env_logger::try_init();
+  let fixture = fixtures::get("indexed").unwrap();
+  let (schema, plan) = fixture.compile_schema_with_plan().unwrap();
+  let seeds = match std::env::var("RDB_SIM_SEED") {
+    Ok(x) => vec![x.parse::<u64>().expect("bad RDB_SIM_SEED")],
+    Err(_) => (0..SEEDS).collect(),
+  };
+  for seed in seeds {
+    let (log, dump) = run_simulation(&schema, &plan, seed).await;
+    assert!(
+      log.iter().any(|x| x.ends_with(": ok")),
+      "seed {}: nothing committed",
+      seed
+    );
+    assert!(!dump.is_empty(), "seed {}: empty store", seed);
+  }
+}
+
+#[tokio::test]
+async fn simulation_is_deterministic() {
+  let _ = pretty_env_logger::try_init();
+  let fixture = fixtures::get("indexed").unwrap();
+  let (schema, plan) = fixture.compile_schema_with_plan().unwrap();
+  let first = run_simulation(&schema, &plan, 42).await;
+  let second = run_simulation(&schema, &plan, 42).await;
+  assert!(first == second, "runs with the same seed diverged");
+}
+
+/// Runs `CLIENTS` clients with random operations concurrently, and checks the invariants of the
+/// store. Returns the outcomes of all operations and the final contents of the store, which only
+/// depend on `seed` and the storage plan.
+async fn run_simulation(
+  schema: &CompiledSchema,
+  plan: &StoragePlan,
+  seed: u64,
+) -> (Vec<String>, Vec<(Vec<u8>, Vec<u8>)>) {
+  let script = compile_twscript(SCRIPT).unwrap();
+  let vm = TwVm::new(schema, plan, &script).unwrap();
+  let type_info = GlobalTyckContext::new(&vm).unwrap().typeck().unwrap();
+  let kv = SimKv::new(seed, faults());
+
+  let logs = join_all((0..CLIENTS).map(|i| {
+    run_client(
+      &vm,
+      &type_info,
+      &kv,
+      seed.wrapping_mul(CLIENTS as u64).wrapping_add(i as u64),
+    )
+  }))
+  .await;
+  let log = logs
+    .into_iter()
+    .enumerate()
+    .flat_map(|(i, x)| x.into_iter().map(move |x| format!("[{}] {}", i, x)))
+    .collect::<Vec<_>>();
+
+  let kv = kv.fault_free();
+  check_invariants(&vm, &type_info, &kv, seed).await;
+  (log, kv.dump())
+}
+
+async fn run_client<'a>(
+  vm: &TwVm<'a>,
+  type_info: &GlobalTypeInfo<'a>,
+  kv: &dyn KeyValueStore,
+  seed: u64,
+) -> Vec<String> {
+  let mut rng = StdRng::seed_from_u64(seed);
+  let root = Arc::new(generate_root_map(vm.schema, vm.storage_plan).unwrap());
+  let mut executor = Executor::new(vm, kv, type_info);
+  executor.set_retry_policy(RetryPolicy {
+    max_attempts: 5,
+    ..Default::default()
+  });
+
+  let mut log = vec![];
+  for _ in 0..OPS_PER_CLIENT {
+    let id = string(["a", "b", "c", "d", "e", "f"][rng.gen_range(0..6)]);
+    let name = string(["p", "q", "r", "s"][rng.gen_range(0..4)]);
+    let code = Arc::new(VmValue::Primitive(PrimitiveValue::Int64(
+      rng.gen_range(0..8),
+    )));
+    let (graph, params) = match rng.gen_range(0..10) {
+      0..=3 => ("put", vec![id, name, code]),
+      4..=5 => ("rename", vec![id, name]),
+      6 => ("recode", vec![id, code]),
+      7 => ("delete", vec![id]),
+      _ => ("names", vec![]),
+    };
+    let desc = format!(
+      "{}({})",
+      graph,
+      params
+        .iter()
+        .map(|x| format!("{:?}", x))
+        .collect::<Vec<_>>()
+        .join(", ")
+    );
+    let mut graph_params = vec![root.clone()];
+    graph_params.extend(params);
+    let graph_index = vm.lookup_exported_graph_by_name(graph).unwrap();
+    let outcome = match executor.run_graph(graph_index, &graph_params).await {
+      Ok(Some(x)) => {
+        // Index scans read from a snapshot, so members come in the order of their names.
+        let names = match &*x {
+          VmValue::Primitive(PrimitiveValue::String(x)) => x.clone(),
+          x => panic!("seed {}: unexpected output {:?}", seed, x),
+        };
+        let mut sorted = names.chars().collect::<Vec<_>>();
+        sorted.sort();
+        assert_eq!(
+          names,
+          sorted.into_iter().collect::<String>(),
+          "seed {}: index scan out of order",
+          seed
+        );
+        format!("ok {:?}", names)
+      }
+      Ok(None) => "ok".to_string(),
+      Err(e) => match (e.downcast_ref::<ExecError>(), e.downcast_ref::<KvError>()) {
+        (Some(ExecError::ConflictAfterRetries), _) => "conflict".to_string(),
+        (Some(ExecError::UniqueConstraintViolation(_)), _) => "unique".to_string(),
+        (_, Some(KvError::CommitStateUnknown)) => "unknown".to_string(),
+        _ => panic!("seed {}: {} failed: {:?}", seed, desc, e),
+      },
+    };
+    log.push(format!("{}: {}", desc, outcome));
+  }
+  log
+}
+
+/// Checks that:
+///
+/// - every member in the fast scan keys of `items` has data, and all data belongs to a member;
+/// - the index entries of `name` and `code` are exactly those of the current members;
+/// - no two members share a `code`, which is `@unique`.
+async fn check_invariants<'a>(
+  vm: &TwVm<'a>,
+  type_info: &GlobalTypeInfo<'a>,
+  kv: &SimKv,
+  seed: u64,
+) {
+  let walker = PathWalker::from_export(vm.storage_plan, "items").unwrap();
+  let fast_scan_prefix = walker.set_fast_scan_prefix().unwrap();
+  let data_prefix = walker.set_data_prefix().unwrap();
+  let index_prefixes = [
+    walker.set_index_prefix("name").unwrap(),
+    walker.set_index_prefix("code").unwrap(),
+  ];
+  let dump = kv.dump();
+
+  let members = dump
+    .iter()
+    .filter_map(|(k, _)| k.strip_prefix(fast_scan_prefix.as_slice()))
+    .collect::<BTreeSet<_>>();
+  let mut members_with_data = BTreeSet::new();
+  for (k, _) in &dump {
+    let rest = match k.strip_prefix(data_prefix.as_slice()) {
+      Some(x) => x,
+      None => continue,
+    };
+    let member = members
+      .iter()
+      .find(|pk| rest.len() > pk.len() && rest.starts_with(pk) && rest[pk.len()] == 0x00)
+      .unwrap_or_else(|| panic!("seed {}: data without a member: {:?}", seed, k));
+    members_with_data.insert(*member);
+  }
+  assert_eq!(
+    members, members_with_data,
+    "seed {}: members without data",
+    seed
+  );
+
+  let rows = Executor::new(vm, kv, type_info)
+    .export_rows("items", None, usize::MAX, &Default::default())
+    .await
+    .unwrap()
+    .rows;
+  assert_eq!(rows.len(), members.len(), "seed {}", seed);
+  let mut expected = BTreeMap::new();
+  let mut codes = BTreeSet::new();
+  for row in &rows {
+    let id = PrimitiveValue::String(
+      row
+        .try_get_required_field("id")
+        .unwrap()
+        .try_unwrap_string()
+        .unwrap()
+        .clone(),
+    );
+    let pk = id.serialize_for_key_component().to_vec();
+    let name = row
+      .try_get_field("name")
+      .unwrap()
+      .map(|x| PrimitiveValue::String(x.try_unwrap_string().unwrap().clone()));
+    let code = row
+      .try_get_field("code")
+      .unwrap()
+      .filter(|x| x.check_nonnull().is_ok())
+      .map(|x| PrimitiveValue::Int64(x.try_to_int64().unwrap()));
+    if let Some(PrimitiveValue::Int64(x)) = &code {
+      assert!(codes.insert(*x), "seed {}: duplicate code {}", seed, x);
+    }
+    for (prefix, value) in index_prefixes.iter().zip([name, code].iter()) {
+      if let Some(value) = value {
+        let mut key = prefix.clone();
+        key.extend_from_slice(&value.serialize_for_self_delimiting_component());
+        key.push(0x00);
+        key.extend_from_slice(&pk);
+        expected.insert(key, pk.clone());
+      }
+    }
+  }
+  let actual = dump
+    .into_iter()
+    .filter(|(k, _)| index_prefixes.iter().any(|x| k.starts_with(x)))
+    .collect::<BTreeMap<_, _>>();
+  assert!(
+    actual == expected,
+    "seed {}: index entries do not match the members",
+    seed
+  );
+}
+
+fn string<'a>(x: &str) -> Arc<VmValue<'a>> {
+  Arc::new(VmValue::Primitive(PrimitiveValue::String(x.to_string())))
+}
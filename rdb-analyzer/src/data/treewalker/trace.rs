@@ -0,0 +1,339 @@
+use std::{
+  sync::{
+    atomic::{AtomicU64, Ordering},
+    Arc, Mutex,
+  },
+  time::{Duration, Instant},
+};
+
+use anyhow::Result;
+use async_trait::async_trait;
+use serde::Serialize;
+
+use crate::data::kv::{KvError, KvIterator, KvKeyIterator, KvTransaction, ScanOptions};
+
+use super::{bytecode::TwScript, vm_value::VmValue};
+
+/// Max length of a value summary, in characters.
+const MAX_SUMMARY_LEN: usize = 64;
+
+/// A trace of one graph execution, recorded by an `Executor` with tracing enabled.
+#[derive(Serialize, Debug, Default)]
+pub struct ExecTrace {
+  /// Nodes in the order they fired.
+  pub events: Vec<TraceEvent>,
+}
+
+#[derive(Serialize, Debug)]
+pub struct TraceEvent {
+  /// Position in the order nodes fired in.
+  pub seq: u64,
+
+  /// The id of the node in the dataflow visualization, as assigned by `dataflow_node_id`.
+  pub id: usize,
+
+  /// Graph index.
+  pub graph: u32,
+
+  /// Node index in the graph.
+  pub node: u32,
+
+  /// Distinguishes multiple invocations of the same graph.
+  pub invocation: u64,
+
+  /// Summaries of the input values.
+  pub inputs: Vec<String>,
+
+  /// Summary of the output value, if any.
+  pub output: Option<String>,
+
+  /// Output type as inferred by typeck.
+  pub output_type: Option<String>,
+
+  /// Error message, if the node failed.
+  pub error: Option<String>,
+
+  /// Time since the start of the trace, in microseconds.
+  pub start_us: u64,
+
+  /// Time taken by the node, in microseconds. Includes subgraph calls.
+  pub duration_us: u64,
+
+  /// KV operations issued by the node, including those of subgraph calls. Each key returned by
+  /// a scan counts as one operation.
+  pub kv_ops: u64,
+
+  /// Bytes of keys and values read by the node, including those of subgraph calls.
+  pub bytes_read: u64,
+}
+
+impl ExecTrace {
+  /// Removes the summaries of input and output values, keeping timings and KV usage.
+  pub fn strip_values(&mut self) {
+    for event in &mut self.events {
+      event.inputs.clear();
+      event.output = None;
+    }
+  }
+}
+
+pub(crate) struct TraceRecorder {
+  start: Instant,
+  state: Mutex<TraceState>,
+}
+
+#[derive(Default)]
+struct TraceState {
+  next_seq: u64,
+  next_invocation: u64,
+  trace: ExecTrace,
+}
+
+pub(crate) struct PendingEvent {
+  seq: u64,
+  graph: u32,
+  node: u32,
+  invocation: u64,
+  inputs: Vec<String>,
+  start: Instant,
+  kv: Arc<KvCounters>,
+}
+
+#[derive(Default)]
+struct KvCounters {
+  ops: AtomicU64,
+  bytes_read: AtomicU64,
+}
+
+impl KvCounters {
+  fn record(&self, bytes_read: usize) {
+    self.ops.fetch_add(1, Ordering::Relaxed);
+    self
+      .bytes_read
+      .fetch_add(bytes_read as u64, Ordering::Relaxed);
+  }
+}
+
+/// A transaction that counts the KV operations of a node into its pending event.
+pub(crate) struct TracedKvTransaction<'a> {
+  inner: &'a dyn KvTransaction,
+  kv: Arc<KvCounters>,
+}
+
+struct TracedKvKeyIterator {
+  inner: Box<dyn KvKeyIterator>,
+  kv: Arc<KvCounters>,
+}
+
+struct TracedKvIterator {
+  inner: Box<dyn KvIterator>,
+  kv: Arc<KvCounters>,
+}
+
+impl PendingEvent {
+  /// Wraps the transaction that the node runs in.
+  pub fn wrap_txn<'a>(&self, txn: &'a dyn KvTransaction) -> TracedKvTransaction<'a> {
+    TracedKvTransaction {
+      inner: txn,
+      kv: self.kv.clone(),
+    }
+  }
+}
+
+#[async_trait]
+impl<'a> KvTransaction for TracedKvTransaction<'a> {
+  async fn get(&self, key: &[u8]) -> Result<Option<Vec<u8>>> {
+    let value = self.inner.get(key).await?;
+    self
+      .kv
+      .record(value.as_ref().map(|x| key.len() + x.len()).unwrap_or(0));
+    Ok(value)
+  }
+
+  async fn put(&self, key: &[u8], value: &[u8]) -> Result<()> {
+    self.kv.record(0);
+    self.inner.put(key, value).await
+  }
+
+  async fn put_many(&self, entries: &[(Vec<u8>, Vec<u8>)]) -> Result<()> {
+    for _ in entries {
+      self.kv.record(0);
+    }
+    self.inner.put_many(entries).await
+  }
+
+  async fn delete(&self, key: &[u8]) -> Result<()> {
+    self.kv.record(0);
+    self.inner.delete(key).await
+  }
+
+  async fn delete_range(&self, start: &[u8], end: &[u8]) -> Result<()> {
+    self.kv.record(0);
+    self.inner.delete_range(start, end).await
+  }
+
+  async fn atomic_add(&self, key: &[u8], delta: i64) -> Result<()> {
+    self.kv.record(0);
+    self.inner.atomic_add(key, delta).await
+  }
+
+  async fn scan_keys(&self, start: &[u8], end: &[u8]) -> Result<Box<dyn KvKeyIterator>> {
+    Ok(Box::new(TracedKvKeyIterator {
+      inner: self.inner.scan_keys(start, end).await?,
+      kv: self.kv.clone(),
+    }))
+  }
+
+  async fn add_read_conflict_key(&self, key: &[u8]) -> Result<()> {
+    self.inner.add_read_conflict_key(key).await
+  }
+
+  async fn scan(
+    &self,
+    start: &[u8],
+    end: &[u8],
+    options: ScanOptions,
+  ) -> Result<Box<dyn KvIterator>> {
+    Ok(Box::new(TracedKvIterator {
+      inner: self.inner.scan(start, end, options).await?,
+      kv: self.kv.clone(),
+    }))
+  }
+
+  async fn commit(self: Box<Self>) -> Result<(), KvError> {
+    unreachable!("nodes do not commit transactions")
+  }
+}
+
+#[async_trait]
+impl KvKeyIterator for TracedKvKeyIterator {
+  async fn next(&mut self) -> Result<Option<Vec<u8>>> {
+    let key = self.inner.next().await?;
+    if let Some(x) = &key {
+      self.kv.record(x.len());
+    }
+    Ok(key)
+  }
+}
+
+#[async_trait]
+impl KvIterator for TracedKvIterator {
+  async fn next(&mut self) -> Result<Option<(Vec<u8>, Vec<u8>)>> {
+    let entry = self.inner.next().await?;
+    if let Some((key, value)) = &entry {
+      self.kv.record(key.len() + value.len());
+    }
+    Ok(entry)
+  }
+}
+
+impl TraceRecorder {
+  pub fn new() -> Self {
+    Self {
+      start: Instant::now(),
+      state: Mutex::new(TraceState::default()),
+    }
+  }
+
+  pub fn next_invocation(&self) -> u64 {
+    let mut st = self.state.lock().unwrap();
+    let x = st.next_invocation;
+    st.next_invocation += 1;
+    x
+  }
+
+  /// Called when a node fires.
+  pub fn begin(
+    &self,
+    graph: u32,
+    node: u32,
+    invocation: u64,
+    inputs: &[Arc<VmValue>],
+  ) -> PendingEvent {
+    let mut st = self.state.lock().unwrap();
+    let seq = st.next_seq;
+    st.next_seq += 1;
+    PendingEvent {
+      seq,
+      graph,
+      node,
+      invocation,
+      inputs: inputs.iter().map(|x| summarize_value(x)).collect(),
+      start: Instant::now(),
+      kv: Arc::new(KvCounters::default()),
+    }
+  }
+
+  /// Called when a node completes.
+  pub fn end(
+    &self,
+    script: &TwScript,
+    pending: PendingEvent,
+    output_type: Option<String>,
+    result: &Result<Option<Arc<VmValue>>>,
+  ) {
+    let (output, error) = match result {
+      Ok(x) => (x.as_ref().map(|x| summarize_value(x)), None),
+      Err(e) => (None, Some(e.to_string())),
+    };
+    let event = TraceEvent {
+      seq: pending.seq,
+      id: dataflow_node_id(script, pending.graph as usize, pending.node as usize),
+      graph: pending.graph,
+      node: pending.node,
+      invocation: pending.invocation,
+      inputs: pending.inputs,
+      output,
+      output_type,
+      error,
+      start_us: micros(pending.start.duration_since(self.start)),
+      duration_us: micros(pending.start.elapsed()),
+      kv_ops: pending.kv.ops.load(Ordering::Relaxed),
+      bytes_read: pending.kv.bytes_read.load(Ordering::Relaxed),
+    };
+    self.state.lock().unwrap().trace.events.push(event);
+  }
+
+  pub fn into_trace(self) -> ExecTrace {
+    let mut trace = self.state.into_inner().unwrap().trace;
+    trace.events.sort_by_key(|x| x.seq);
+    trace
+  }
+}
+
+fn micros(x: Duration) -> u64 {
+  x.as_micros() as u64
+}
+
+/// The id of a node in the dataflow visualization: graphs take ids `0..graphs.len()`, followed
+/// by the nodes of each graph in order.
+pub fn dataflow_node_id(script: &TwScript, graph_index: usize, node_index: usize) -> usize {
+  script.graphs.len()
+    + script.graphs[..graph_index]
+      .iter()
+      .map(|x| x.nodes.len())
+      .sum::<usize>()
+    + node_index
+}
+
+/// A short, human-readable description of a value.
+pub fn summarize_value(v: &VmValue) -> String {
+  let s = match v {
+    VmValue::Primitive(x) => format!("{}", x),
+    VmValue::Bool(x) => format!("{}", x),
+    VmValue::Null(ty) => format!("null<{}>", ty),
+    VmValue::Table(x) => format!("table<{}>", x.ty),
+    VmValue::Set(x) => format!("set<{}>", x.member_ty),
+    VmValue::List(x) => format!("list<{}>(len={})", x.member_ty, x.node.len()),
+    VmValue::Map(x) => format!(
+      "map{{{}}}",
+      x.elements.keys().copied().collect::<Vec<_>>().join(", ")
+    ),
+    VmValue::Json(x) => format!("json({})", x),
+  };
+  if s.chars().count() > MAX_SUMMARY_LEN {
+    format!("{}...", s.chars().take(MAX_SUMMARY_LEN).collect::<String>())
+  } else {
+    s
+  }
+}
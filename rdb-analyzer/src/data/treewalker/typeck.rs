@@ -11,12 +11,18 @@ use thiserror::Error;
 use crate::{
   data::treewalker::{
     bytecode::TwGraphNode,
-    vm_value::{VmListType, VmSetType, VmTableType},
+    exec::ExecEnv,
+    vm_value::{VmListType, VmSetType, VmTableType, VmValue},
   },
-  schema::compile::{FieldAnnotationList, PrimitiveType},
+  schema::compile::{FieldAnnotationList, FieldType, PrimitiveType},
 };
 
-use super::{bytecode::TwGraph, vm::TwVm, vm_value::VmType};
+use super::{
+  acl::{compute_output_acls, ValueAcl},
+  bytecode::TwGraph,
+  vm::TwVm,
+  vm_value::VmType,
+};
 
 #[derive(Error, Debug)]
 pub enum TypeckError {
@@ -50,6 +56,10 @@ pub enum TypeckError {
   NotMap(String),
   #[error("type `{0}` is not a table")]
   NotTable(String),
+  #[error("cannot convert json values to `{0}`")]
+  UnsupportedJsonConversion(String),
+  #[error("fields of map type `{0}` have no common type")]
+  NoCommonMapValueType(String),
   #[error("type `{0}` is not a map or table")]
   NotMapOrTable(String),
   #[error("type `{0}` is not a set")]
@@ -86,30 +96,63 @@ pub enum TypeckError {
   ParamCountMismatch(&'static str, u32, u32),
   #[error("select type mismatch: `{0}` != `{1}`")]
   SelectTypeMismatch(String, String),
+  #[error("conditional branch types are not covariant: `{0}` and `{1}`")]
+  CondBranchTypeMismatch(String, String),
+  #[error("default value of script param `{0}` does not match its type")]
+  ScriptParamDefaultTypeMismatch(String),
+  #[error("param `{0}` of graph `{1}` does not match the type of the script param")]
+  ScriptParamTypeMismatch(String, String),
   #[error("presence check on an unsuppported type: `{0}`")]
   PresenceCheckOnUnsupportedType(String),
   #[error("bad binop operands: `{0}` and `{1}`")]
   BadBinopOperands(String, String),
+  #[error("bad unary operand: `{0}`")]
+  BadUnaryOperand(String),
   #[error("invalid list prepend: list=`{0}` value=`{1}`")]
   InvalidListPrepend(String, String),
   #[error("cannot build set from a list of non-table member type: `{0}`")]
   CannotBuildSetFromList(String),
   #[error("not a list: `{0}`")]
   NotList(String),
+  #[error("field `{0}` of table `{1}` is not a list")]
+  FieldNotList(String, Arc<str>),
+  #[error("field `{0}` of table `{1}` is not a map")]
+  FieldNotMap(String, Arc<str>),
+  #[error("field `{0}` of table `{1}` is not a counter")]
+  FieldNotCounter(String, Arc<str>),
+  #[error("table `{0}` has no version field")]
+  NoVersionField(Arc<str>),
   #[error("not a list or set: `{0}`")]
   NotListOrSet(String),
+  #[error("expecting bool or int64 output from a comparator subgraph, got `{0}`")]
+  BadComparatorOutput(String),
   #[error("missing output from a reduce function")]
   MissingOutputFromReduce,
   #[error("cannot insert primary key into a table")]
   CannotInsertPrimaryKey,
+  #[error("cannot insert into a version field, which is bumped on every update of the table")]
+  CannotInsertVersion,
   #[error("range reduce used on a non-set type")]
   RangeReduceOnNonSet,
+  #[error("index scan used on a non-set type: `{0}`")]
+  ScanIndexOnNonSet(String),
+  #[error("field `{0}` of type `{1}` is not indexed")]
+  FieldNotIndexed(String, String),
+  #[error("cannot aggregate over field `{0}` of type `{1}`")]
+  BadAggregateField(String, String),
+  #[error("node `{0}` may get a null parameter but is not optional-chained")]
+  NullableParamNotChained(String),
+  #[error("try block in graph `{0}` has effects")]
+  EffectInTryBlock(String),
 }
 
 pub struct GlobalTyckContext<'a, 'b> {
   vm: &'b TwVm<'a>,
   scc_post_order: Vec<HashSet<u32>>,
   subgraph_expected_param_types: Vec<Vec<HashSet<VmType<&'a str>>>>,
+
+  /// Bodies of `try` blocks, which are typechecked along with the graph that runs them.
+  try_bodies: HashSet<u32>,
 }
 
 #[derive(Debug)]
@@ -121,6 +164,15 @@ pub struct GlobalTypeInfo<'a> {
 pub struct GraphTypeInfo<'a> {
   pub params: Vec<VmType<&'a str>>,
   pub nodes: Vec<Option<VmType<&'a str>>>,
+
+  /// Whether each node may output null.
+  pub nullable: Vec<bool>,
+
+  /// Whether the output may be null.
+  pub output_nullable: bool,
+
+  /// Visibility of the output, derived from `@acl` annotations on the fields it reads.
+  pub output_acl: ValueAcl<'a>,
 }
 
 impl<'a, 'b> GlobalTyckContext<'a, 'b> {
@@ -141,8 +193,12 @@ impl<'a, 'b> GlobalTyckContext<'a, 'b> {
       .collect();
 
     // Build the call graph.
+    let mut try_bodies = HashSet::new();
     for (i, g) in vm.script.graphs.iter().enumerate() {
       for (n, _, _) in &g.nodes {
+        if let TwGraphNode::Try(x) = n {
+          try_bodies.insert(*x);
+        }
         for r in n.subgraph_references() {
           vm.script
             .graphs
@@ -167,10 +223,13 @@ impl<'a, 'b> GlobalTyckContext<'a, 'b> {
       vm,
       scc_post_order: all_sccs,
       subgraph_expected_param_types,
+      try_bodies,
     })
   }
 
   pub fn typeck(&mut self) -> Result<GlobalTypeInfo<'a>> {
+    self.check_script_params()?;
+
     let mut type_info = GlobalTypeInfo {
       graphs: (0..self.vm.script.graphs.len())
         .map(|_| GraphTypeInfo::default())
@@ -181,10 +240,21 @@ impl<'a, 'b> GlobalTyckContext<'a, 'b> {
     for scc in self.scc_post_order.iter().rev() {
       let mut subgraph_expected_param_types_sink: HashMap<u32, Vec<HashSet<VmType<&'a str>>>> =
         HashMap::new();
+      let mut try_bodies_sink: Vec<(usize, GraphTypeInfo<'a>)> = vec![];
       for i in scc {
+        if self.try_bodies.contains(i) {
+          continue;
+        }
         log::trace!("typeck: scc {:p}, subgraph {}", scc, i);
-        type_info.graphs[*i as usize] =
-          self.typeck_graph(*i as usize, &mut subgraph_expected_param_types_sink)?;
+        type_info.graphs[*i as usize] = self.typeck_graph(
+          *i as usize,
+          None,
+          &mut subgraph_expected_param_types_sink,
+          &mut try_bodies_sink,
+        )?;
+      }
+      for (i, info) in try_bodies_sink {
+        type_info.graphs[i] = info;
       }
 
       for (i, x) in subgraph_expected_param_types_sink {
@@ -197,13 +267,48 @@ impl<'a, 'b> GlobalTyckContext<'a, 'b> {
         }
       }
     }
+
+    // Callees before callers, so that the nullability of their outputs is known.
+    for scc in &self.scc_post_order {
+      for i in scc {
+        let nullable = self.infer_nullability(*i as usize, &type_info.graphs)?;
+        let info = &mut type_info.graphs[*i as usize];
+        info.output_nullable = self.vm.script.graphs[*i as usize]
+          .output
+          .map(|x| nullable[x as usize])
+          .unwrap_or(false);
+        info.nullable = nullable;
+      }
+    }
+
+    let output_acls = compute_output_acls(self.vm, &self.scc_post_order, &type_info);
+    for ((g, info), acl) in self
+      .vm
+      .script
+      .graphs
+      .iter()
+      .zip(type_info.graphs.iter_mut())
+      .zip(output_acls)
+    {
+      if g.exported && !acl.is_unrestricted() {
+        log::warn!(
+          "graph `{}` returns fields protected by @acl - results may be filtered for some callers",
+          g.name
+        );
+      }
+      info.output_acl = acl;
+    }
     Ok(type_info)
   }
 
+  /// Typechecks the graph at `graph_index`. Param types are resolved from the calls to the graph,
+  /// unless given in `param_types`.
   fn typeck_graph(
     &self,
     graph_index: usize,
+    param_types: Option<Vec<VmType<&'a str>>>,
     subgraph_expected_param_types_sink: &mut HashMap<u32, Vec<HashSet<VmType<&'a str>>>>,
+    try_bodies_sink: &mut Vec<(usize, GraphTypeInfo<'a>)>,
   ) -> Result<GraphTypeInfo<'a>> {
     let vm = self.vm;
     let g = &self.vm.script.graphs[graph_index];
@@ -229,8 +334,20 @@ impl<'a, 'b> GlobalTyckContext<'a, 'b> {
       .collect::<Option<Vec<_>>>()
       .ok_or_else(|| TypeckError::ParamTypeIndexOob)?;
 
+    if let Some(x) = param_types {
+      if x.len() != params.len() {
+        return Err(
+          TypeckError::ParamCountMismatch("Try", x.len() as u32, params.len() as u32).into(),
+        );
+      }
+      params = x;
+    }
+
     // Resolve param types
     for (i, p) in params.iter_mut().enumerate() {
+      if self.try_bodies.contains(&(graph_index as u32)) {
+        break;
+      }
       let expected = &self.subgraph_expected_param_types[graph_index][i];
 
       // Step 1: Param type inference
@@ -359,19 +476,12 @@ impl<'a, 'b> GlobalTyckContext<'a, 'b> {
         TwGraphNode::DeleteFromSet => {
           let [primary_key_value_ty, set_ty] = validate_in_edges::<2>(node, in_edges, &types)?;
           let set_member_ty = extract_set_element_type(set_ty)?;
-          let (key, _) = set_ty.set_primary_key(vm.schema).unwrap();
           match set_member_ty {
             VmType::Table(x) => {
-              let table_ty = vm
-                .schema
-                .types
-                .get(x.name)
+              let primary_key = set_ty
+                .set_primary_key(vm.schema)
                 .ok_or_else(|| TypeckError::TableTypeNotFound(x.name.to_string()))?;
-              let (field_ty, _) = table_ty.fields.get(key).ok_or_else(|| {
-                TypeckError::FieldNotPresentInTable(key.to_string(), table_ty.name.clone())
-              })?;
-              let field_ty = VmType::from(field_ty);
-              ensure_covariant(&field_ty, primary_key_value_ty)?;
+              ensure_covariant(&primary_key.value_type(), primary_key_value_ty)?;
               None
             }
             _ => return Err(TypeckError::NotTable(format!("{:?}", set_member_ty)).into()),
@@ -393,6 +503,50 @@ impl<'a, 'b> GlobalTyckContext<'a, 'b> {
             _ => return Err(TypeckError::NotMap(format!("{:?}", map_ty)).into()),
           }
         }
+        TwGraphNode::JsonParse => {
+          let [x] = validate_in_edges::<1>(node, in_edges, &types)?;
+          ensure_type_eq(&VmType::Primitive(PrimitiveType::String), x)?;
+          Some(VmType::Json)
+        }
+        TwGraphNode::JsonSerialize => {
+          let [x] = validate_in_edges::<1>(node, in_edges, &types)?;
+          ensure_type_eq(&VmType::Json, x)?;
+          Some(VmType::Primitive(PrimitiveType::String))
+        }
+        TwGraphNode::JsonGet(ty) => {
+          let [path, doc] = validate_in_edges::<2>(node, in_edges, &types)?;
+          ensure_type_eq(&VmType::Primitive(PrimitiveType::String), path)?;
+          ensure_type_eq(&VmType::Json, doc)?;
+          let ty = vm
+            .types
+            .get(*ty as usize)
+            .ok_or_else(|| TypeckError::TypeIndexOob)?;
+          match ty {
+            VmType::Json | VmType::Bool | VmType::Primitive(_) => {}
+            _ => return Err(TypeckError::UnsupportedJsonConversion(format!("{:?}", ty)).into()),
+          }
+          Some(ty.clone())
+        }
+        TwGraphNode::MapKeys => {
+          let [map_ty] = validate_in_edges::<1>(node, in_edges, &types)?;
+          if !matches!(map_ty, VmType::Map(_)) {
+            return Err(TypeckError::NotMap(format!("{:?}", map_ty)).into());
+          }
+          Some(VmType::List(VmListType {
+            ty: Box::new(VmType::Primitive(PrimitiveType::String)),
+          }))
+        }
+        TwGraphNode::MapValues => {
+          let [map_ty] = validate_in_edges::<1>(node, in_edges, &types)?;
+          Some(VmType::List(VmListType {
+            ty: Box::new(map_value_type(map_ty)?),
+          }))
+        }
+        TwGraphNode::MapGetDynamic => {
+          let [key_ty, map_ty] = validate_in_edges::<2>(node, in_edges, &types)?;
+          ensure_type_eq(&VmType::Primitive(PrimitiveType::String), key_ty)?;
+          Some(map_value_type(map_ty)?)
+        }
         TwGraphNode::GetField(key_index) => {
           let [map_or_table_ty] = validate_in_edges::<1>(node, in_edges, &types)?;
           let key = vm
@@ -428,24 +582,59 @@ impl<'a, 'b> GlobalTyckContext<'a, 'b> {
         TwGraphNode::GetSetElement => {
           let [primary_key_value_ty, set_ty] = validate_in_edges::<2>(node, in_edges, &types)?;
           let set_member_ty = extract_set_element_type(set_ty)?;
-          let (key, _) = set_ty.set_primary_key(vm.schema).unwrap();
           match set_member_ty {
             VmType::Table(x) => {
-              let table_ty = vm
-                .schema
-                .types
-                .get(x.name)
+              let primary_key = set_ty
+                .set_primary_key(vm.schema)
                 .ok_or_else(|| TypeckError::TableTypeNotFound(x.name.to_string()))?;
-              let (field_ty, _) = table_ty.fields.get(key).ok_or_else(|| {
-                TypeckError::FieldNotPresentInTable(key.to_string(), table_ty.name.clone())
-              })?;
-              let field_ty = VmType::from(field_ty);
-              ensure_covariant(&field_ty, primary_key_value_ty)?;
+              ensure_covariant(&primary_key.value_type(), primary_key_value_ty)?;
               Some(set_member_ty.clone())
             }
             _ => return Err(TypeckError::NotTable(format!("{:?}", set_member_ty)).into()),
           }
         }
+        TwGraphNode::GetSetElementByTuple => {
+          let (set_edge, component_edges) = in_edges
+            .split_last()
+            .ok_or_else(|| TypeckError::InEdgeCountMismatch(2, format!("{:?}", node), 0))?;
+          let set_ty = ensure_type(types[*set_edge as usize].as_ref())?;
+          let set_member_ty = extract_set_element_type(set_ty)?;
+          let primary_key = set_ty
+            .set_primary_key(vm.schema)
+            .ok_or_else(|| TypeckError::NotTable(format!("{:?}", set_member_ty)))?;
+          if primary_key.fields.len() != component_edges.len() {
+            return Err(
+              TypeckError::InEdgeCountMismatch(
+                primary_key.fields.len() + 1,
+                format!("{:?}", node),
+                in_edges.len(),
+              )
+              .into(),
+            );
+          }
+          for ((_, field_ty), edge) in primary_key.fields.iter().zip(component_edges) {
+            ensure_covariant(
+              &VmType::from(*field_ty),
+              ensure_type(types[*edge as usize].as_ref())?,
+            )?;
+          }
+          Some(set_member_ty.clone())
+        }
+        TwGraphNode::GetSetElements => {
+          let [primary_key_values_ty, set_ty] = validate_in_edges::<2>(node, in_edges, &types)?;
+          let set_member_ty = extract_set_element_type(set_ty)?;
+          let primary_key_value_ty = match primary_key_values_ty {
+            VmType::List(x) => &*x.ty,
+            _ => return Err(TypeckError::NotList(format!("{:?}", primary_key_values_ty)).into()),
+          };
+          let primary_key = set_ty
+            .set_primary_key(vm.schema)
+            .ok_or_else(|| TypeckError::NotTable(format!("{:?}", set_member_ty)))?;
+          ensure_covariant(&primary_key.value_type(), primary_key_value_ty)?;
+          Some(VmType::List(VmListType {
+            ty: Box::new(set_member_ty.clone()),
+          }))
+        }
         TwGraphNode::FilterSet(subgraph_index) => {
           let [subgraph_param, set_ty] = validate_in_edges::<2>(node, in_edges, &types)?;
           let set_member_ty = extract_set_element_type(set_ty)?;
@@ -513,15 +702,93 @@ impl<'a, 'b> GlobalTyckContext<'a, 'b> {
                 .ok_or_else(|| {
                   TypeckError::FieldNotPresentInTable(key.clone(), table_ty.name.clone())
                 })?;
-              if field_annotations.as_slice().is_primary() {
+              if field_annotations.as_slice().is_in_primary_key() {
                 return Err(TypeckError::CannotInsertPrimaryKey.into());
               }
+              if field_annotations.as_slice().is_version() {
+                return Err(TypeckError::CannotInsertVersion.into());
+              }
               ensure_covariant(&field_ty, value_ty)?;
               None
             }
             _ => return Err(TypeckError::NotTable(format!("{:?}", table_ty)).into()),
           }
         }
+        TwGraphNode::ListPush(key_index) => {
+          let [value_ty, table_ty] = validate_in_edges::<2>(node, in_edges, &types)?;
+          let member_ty = list_field_member_type(vm, table_ty, *key_index)?;
+          ensure_covariant(&member_ty, value_ty)?;
+          None
+        }
+        TwGraphNode::ListPop(key_index) => {
+          let [table_ty] = validate_in_edges::<1>(node, in_edges, &types)?;
+          Some(list_field_member_type(vm, table_ty, *key_index)?)
+        }
+        TwGraphNode::ListGet(key_index) => {
+          let [index_ty, table_ty] = validate_in_edges::<2>(node, in_edges, &types)?;
+          ensure_type_eq(&VmType::Primitive(PrimitiveType::Int64), index_ty)?;
+          Some(list_field_member_type(vm, table_ty, *key_index)?)
+        }
+        TwGraphNode::ListLen(key_index) => {
+          let [table_ty] = validate_in_edges::<1>(node, in_edges, &types)?;
+          list_field_member_type(vm, table_ty, *key_index)?;
+          Some(VmType::Primitive(PrimitiveType::Int64))
+        }
+        TwGraphNode::AtomicAdd(key_index) => {
+          let [delta_ty, table_ty] = validate_in_edges::<2>(node, in_edges, &types)?;
+          ensure_type_eq(&VmType::Primitive(PrimitiveType::Int64), delta_ty)?;
+          let (_, key, table_name) = table_field_type(vm, table_ty, *key_index)?;
+          let (_, annotations) = &vm.schema.types[table_name].fields[key.as_str()];
+          if !annotations.as_slice().is_counter() {
+            return Err(TypeckError::FieldNotCounter(key.clone(), table_name.clone()).into());
+          }
+          None
+        }
+        TwGraphNode::CheckVersion => {
+          let [version_ty, table_ty] = validate_in_edges::<2>(node, in_edges, &types)?;
+          ensure_type_eq(&VmType::Primitive(PrimitiveType::Int64), version_ty)?;
+          match table_ty {
+            VmType::Table(x) => {
+              let specialized_ty = vm
+                .schema
+                .types
+                .get(x.name)
+                .ok_or_else(|| TypeckError::TableTypeNotFound(x.name.to_string()))?;
+              if specialized_ty.version_field().is_none() {
+                return Err(TypeckError::NoVersionField(specialized_ty.name.clone()).into());
+              }
+            }
+            _ => return Err(TypeckError::NotTable(format!("{:?}", table_ty)).into()),
+          }
+          Some(table_ty.clone())
+        }
+        TwGraphNode::DictGet(key_index) => {
+          let [key_ty, table_ty] = validate_in_edges::<2>(node, in_edges, &types)?;
+          let (map_key_ty, map_value_ty) = map_field_types(vm, table_ty, *key_index)?;
+          ensure_type_eq(&map_key_ty, key_ty)?;
+          Some(map_value_ty)
+        }
+        TwGraphNode::DictPut(key_index) => {
+          let [key_ty, value_ty, table_ty] = validate_in_edges::<3>(node, in_edges, &types)?;
+          let (map_key_ty, map_value_ty) = map_field_types(vm, table_ty, *key_index)?;
+          ensure_type_eq(&map_key_ty, key_ty)?;
+          ensure_covariant(&map_value_ty, value_ty)?;
+          None
+        }
+        TwGraphNode::DictDelete(key_index) => {
+          let [key_ty, table_ty] = validate_in_edges::<2>(node, in_edges, &types)?;
+          let (map_key_ty, _) = map_field_types(vm, table_ty, *key_index)?;
+          ensure_type_eq(&map_key_ty, key_ty)?;
+          None
+        }
+        TwGraphNode::DictRange(key_index) => {
+          let [start_ty, end_ty, table_ty] = validate_in_edges::<3>(node, in_edges, &types)?;
+          let (map_key_ty, _) = map_field_types(vm, table_ty, *key_index)?;
+          ensure_type_eq(&map_key_ty, start_ty)?;
+          ensure_type_eq(&map_key_ty, end_ty)?;
+          let (field_ty, _, _) = table_field_type(vm, table_ty, *key_index)?;
+          Some(VmType::from(field_ty))
+        }
         TwGraphNode::LoadConst(const_index) => {
           validate_in_edges::<0>(node, in_edges, &types)?;
           let const_value = vm
@@ -530,6 +797,14 @@ impl<'a, 'b> GlobalTyckContext<'a, 'b> {
             .ok_or_else(|| TypeckError::ConstIndexOob)?;
           Some(VmType::from(&**const_value))
         }
+        TwGraphNode::LoadEnv => {
+          validate_in_edges::<0>(node, in_edges, &types)?;
+          let mut fields = RedBlackTreeMapSync::new_sync();
+          for x in ExecEnv::FIELDS.iter() {
+            fields.insert_mut(*x, VmType::Primitive(PrimitiveType::String));
+          }
+          Some(VmType::Map(fields))
+        }
         TwGraphNode::LoadParam(param_index) => {
           if *param_index as usize >= params.len() {
             return Err(TypeckError::ParamIndexOob.into());
@@ -546,6 +821,25 @@ impl<'a, 'b> GlobalTyckContext<'a, 'b> {
           ensure_covariant(left, right)?;
           Some(VmType::Bool)
         }
+        TwGraphNode::Lt | TwGraphNode::Le | TwGraphNode::Gt | TwGraphNode::Ge => {
+          let [l, r] = validate_in_edges::<2>(node, in_edges, &types)?;
+          match (l, r) {
+            (VmType::Primitive(PrimitiveType::Int64), VmType::Primitive(PrimitiveType::Int64))
+            | (
+              VmType::Primitive(PrimitiveType::Double),
+              VmType::Primitive(PrimitiveType::Double),
+            )
+            | (
+              VmType::Primitive(PrimitiveType::String),
+              VmType::Primitive(PrimitiveType::String),
+            ) => Some(VmType::Bool),
+            _ => {
+              return Err(
+                TypeckError::BadBinopOperands(format!("{:?}", l), format!("{:?}", r)).into(),
+              )
+            }
+          }
+        }
         TwGraphNode::And | TwGraphNode::Or => {
           let [left, right] = validate_in_edges::<2>(node, in_edges, &types)?;
           ensure_type_eq(left, &VmType::Bool)?;
@@ -566,6 +860,23 @@ impl<'a, 'b> GlobalTyckContext<'a, 'b> {
           }
           Some(left.clone())
         }
+        TwGraphNode::Cond => {
+          let [cond, then, otherwise] = validate_in_edges::<3>(node, in_edges, &types)?;
+          ensure_type_eq(cond, &VmType::Bool)?;
+          if then.is_covariant_from(otherwise) {
+            Some(then.clone())
+          } else if otherwise.is_covariant_from(then) {
+            Some(otherwise.clone())
+          } else {
+            return Err(
+              TypeckError::CondBranchTypeMismatch(
+                format!("{:?}", then),
+                format!("{:?}", otherwise),
+              )
+              .into(),
+            );
+          }
+        }
         TwGraphNode::IsPresent => {
           let [x] = validate_in_edges::<1>(node, in_edges, &types)?;
           match x {
@@ -579,7 +890,7 @@ impl<'a, 'b> GlobalTyckContext<'a, 'b> {
           let [_] = validate_in_edges::<1>(node, in_edges, &types)?;
           Some(VmType::Bool)
         }
-        TwGraphNode::Nop => {
+        TwGraphNode::Nop | TwGraphNode::UnwrapOptional => {
           let [x] = validate_in_edges::<1>(node, in_edges, &types)?;
           Some(x.clone())
         }
@@ -599,6 +910,36 @@ impl<'a, 'b> GlobalTyckContext<'a, 'b> {
             .and_then(|x| vm.script.types.get(x as usize).map(VmType::<&'a str>::from));
           output
         }
+        TwGraphNode::Try(subgraph_index) => {
+          let param_types = in_edges
+            .iter()
+            .map(|x| ensure_type(types[*x as usize].as_ref()).map(|x| x.clone()))
+            .collect::<Result<Vec<_>, TypeckError>>()?;
+          let body = vm
+            .script
+            .graphs
+            .get(*subgraph_index as usize)
+            .ok_or_else(|| TypeckError::SubgraphIndexOob)?;
+
+          // A failed body is not rolled back, so it must not have effects.
+          if !vm.is_graph_read_only(*subgraph_index as usize) {
+            return Err(TypeckError::EffectInTryBlock(g.name.clone()).into());
+          }
+
+          let info = self.typeck_graph(
+            *subgraph_index as usize,
+            Some(param_types),
+            subgraph_expected_param_types_sink,
+            try_bodies_sink,
+          )?;
+          let mut output = RedBlackTreeMapSync::new_sync()
+            .insert("error", VmType::Primitive(PrimitiveType::String));
+          if let Some(x) = body.output.and_then(|x| info.nodes[x as usize].clone()) {
+            output = output.insert("value", x);
+          }
+          try_bodies_sink.push((*subgraph_index as usize, info));
+          Some(VmType::Map(output))
+        }
         TwGraphNode::Add => {
           let [l, r] = validate_in_edges::<2>(node, in_edges, &types)?;
           match (l, r) {
@@ -620,7 +961,7 @@ impl<'a, 'b> GlobalTyckContext<'a, 'b> {
             }
           }
         }
-        TwGraphNode::Sub => {
+        TwGraphNode::Sub | TwGraphNode::Mul | TwGraphNode::Div | TwGraphNode::Mod => {
           let [l, r] = validate_in_edges::<2>(node, in_edges, &types)?;
           match (l, r) {
             (VmType::Primitive(PrimitiveType::Int64), VmType::Primitive(PrimitiveType::Int64)) => {
@@ -637,6 +978,95 @@ impl<'a, 'b> GlobalTyckContext<'a, 'b> {
             }
           }
         }
+        TwGraphNode::Neg => {
+          let [x] = validate_in_edges::<1>(node, in_edges, &types)?;
+          match x {
+            VmType::Primitive(PrimitiveType::Int64) | VmType::Primitive(PrimitiveType::Double) => {
+              Some(x.clone())
+            }
+            _ => return Err(TypeckError::BadUnaryOperand(format!("{:?}", x)).into()),
+          }
+        }
+        TwGraphNode::ParseDouble => {
+          let [x] = validate_in_edges::<1>(node, in_edges, &types)?;
+          ensure_type_eq(x, &VmType::Primitive(PrimitiveType::String))?;
+          Some(VmType::Primitive(PrimitiveType::Double))
+        }
+        TwGraphNode::FormatDouble => {
+          let [x] = validate_in_edges::<1>(node, in_edges, &types)?;
+          ensure_type_eq(x, &VmType::Primitive(PrimitiveType::Double))?;
+          Some(VmType::Primitive(PrimitiveType::String))
+        }
+        TwGraphNode::StrStartsWith | TwGraphNode::StrContains => {
+          let [x, y] = validate_in_edges::<2>(node, in_edges, &types)?;
+          ensure_type_eq(x, &VmType::Primitive(PrimitiveType::String))?;
+          ensure_type_eq(y, &VmType::Primitive(PrimitiveType::String))?;
+          Some(VmType::Bool)
+        }
+        TwGraphNode::StrSubstring => {
+          let [x, start, len] = validate_in_edges::<3>(node, in_edges, &types)?;
+          ensure_type_eq(x, &VmType::Primitive(PrimitiveType::String))?;
+          ensure_type_eq(start, &VmType::Primitive(PrimitiveType::Int64))?;
+          ensure_type_eq(len, &VmType::Primitive(PrimitiveType::Int64))?;
+          Some(VmType::Primitive(PrimitiveType::String))
+        }
+        TwGraphNode::StrToLower => {
+          let [x] = validate_in_edges::<1>(node, in_edges, &types)?;
+          ensure_type_eq(x, &VmType::Primitive(PrimitiveType::String))?;
+          Some(VmType::Primitive(PrimitiveType::String))
+        }
+        TwGraphNode::StrLength => {
+          let [x] = validate_in_edges::<1>(node, in_edges, &types)?;
+          ensure_type_eq(x, &VmType::Primitive(PrimitiveType::String))?;
+          Some(VmType::Primitive(PrimitiveType::Int64))
+        }
+        TwGraphNode::BytesConcat => {
+          let [x, y] = validate_in_edges::<2>(node, in_edges, &types)?;
+          ensure_type_eq(x, &VmType::Primitive(PrimitiveType::Bytes))?;
+          ensure_type_eq(y, &VmType::Primitive(PrimitiveType::Bytes))?;
+          Some(VmType::Primitive(PrimitiveType::Bytes))
+        }
+        TwGraphNode::BytesSlice => {
+          let [x, start, len] = validate_in_edges::<3>(node, in_edges, &types)?;
+          ensure_type_eq(x, &VmType::Primitive(PrimitiveType::Bytes))?;
+          ensure_type_eq(start, &VmType::Primitive(PrimitiveType::Int64))?;
+          ensure_type_eq(len, &VmType::Primitive(PrimitiveType::Int64))?;
+          Some(VmType::Primitive(PrimitiveType::Bytes))
+        }
+        TwGraphNode::BytesLength => {
+          let [x] = validate_in_edges::<1>(node, in_edges, &types)?;
+          ensure_type_eq(x, &VmType::Primitive(PrimitiveType::Bytes))?;
+          Some(VmType::Primitive(PrimitiveType::Int64))
+        }
+        TwGraphNode::BytesToHex | TwGraphNode::BytesToBase64 | TwGraphNode::BytesToString => {
+          let [x] = validate_in_edges::<1>(node, in_edges, &types)?;
+          ensure_type_eq(x, &VmType::Primitive(PrimitiveType::Bytes))?;
+          Some(VmType::Primitive(PrimitiveType::String))
+        }
+        TwGraphNode::BytesFromHex | TwGraphNode::BytesFromBase64 | TwGraphNode::BytesFromString => {
+          let [x] = validate_in_edges::<1>(node, in_edges, &types)?;
+          ensure_type_eq(x, &VmType::Primitive(PrimitiveType::String))?;
+          Some(VmType::Primitive(PrimitiveType::Bytes))
+        }
+        TwGraphNode::TimeNow => {
+          validate_in_edges::<0>(node, in_edges, &types)?;
+          Some(VmType::Primitive(PrimitiveType::Int64))
+        }
+        TwGraphNode::GenId => {
+          validate_in_edges::<0>(node, in_edges, &types)?;
+          Some(VmType::Primitive(PrimitiveType::String))
+        }
+        TwGraphNode::TimeAddDays => {
+          let [x, days] = validate_in_edges::<2>(node, in_edges, &types)?;
+          ensure_type_eq(x, &VmType::Primitive(PrimitiveType::Int64))?;
+          ensure_type_eq(days, &VmType::Primitive(PrimitiveType::Int64))?;
+          Some(VmType::Primitive(PrimitiveType::Int64))
+        }
+        TwGraphNode::TimeStartOfDay | TwGraphNode::TimeDayOfWeek => {
+          let [x] = validate_in_edges::<1>(node, in_edges, &types)?;
+          ensure_type_eq(x, &VmType::Primitive(PrimitiveType::Int64))?;
+          Some(VmType::Primitive(PrimitiveType::Int64))
+        }
         TwGraphNode::PrependToList => {
           let [value, list] = validate_in_edges::<2>(node, in_edges, &types)?;
           match list {
@@ -665,6 +1095,31 @@ impl<'a, 'b> GlobalTyckContext<'a, 'b> {
             }
           }
         }
+        TwGraphNode::SortList(subgraph_index) => {
+          let [list] = validate_in_edges::<1>(node, in_edges, &types)?;
+          let member_ty = match list {
+            VmType::List(x) => (*x.ty).clone(),
+            _ => {
+              return Err(TypeckError::NotList(format!("{:?}", list)).into());
+            }
+          };
+          let subgraph = self.validate_subgraph_call(
+            "SortList",
+            *subgraph_index,
+            subgraph_expected_param_types_sink,
+            vec![member_ty.clone(), member_ty],
+          )?;
+          let output = subgraph
+            .output_type
+            .and_then(|x| vm.script.types.get(x as usize).map(VmType::<&'a str>::from));
+          match output {
+            Some(VmType::Bool) | Some(VmType::Primitive(PrimitiveType::Int64)) => {}
+            _ => {
+              return Err(TypeckError::BadComparatorOutput(format!("{:?}", output)).into());
+            }
+          }
+          Some(list.clone())
+        }
         TwGraphNode::Reduce(subgraph_index, has_range) => {
           let subgraph_param;
           let reduce_init;
@@ -676,10 +1131,10 @@ impl<'a, 'b> GlobalTyckContext<'a, 'b> {
             reduce_init = reduce_init_;
             list_or_set_ty = list_or_set_ty_;
 
-            let (_, primary_key_ty) = list_or_set_ty
+            let primary_key_ty = list_or_set_ty
               .set_primary_key(vm.schema)
-              .ok_or_else(|| TypeckError::RangeReduceOnNonSet)?;
-            let primary_key_ty = VmType::from(primary_key_ty);
+              .ok_or_else(|| TypeckError::RangeReduceOnNonSet)?
+              .value_type();
             ensure_type_eq(&primary_key_ty, start_key)?;
             ensure_type_eq(&primary_key_ty, end_key)?;
           } else {
@@ -711,11 +1166,139 @@ impl<'a, 'b> GlobalTyckContext<'a, 'b> {
           ensure_covariant(reduce_init, &output)?;
           Some(output.clone())
         }
+        TwGraphNode::Loop(subgraph_index) => {
+          let [subgraph_param, init, range_start, range_end] =
+            validate_in_edges::<4>(node, in_edges, &types)?;
+          let int64 = VmType::Primitive(PrimitiveType::Int64);
+          ensure_type_eq(&int64, range_start)?;
+          ensure_type_eq(&int64, range_end)?;
+          let subgraph = self.validate_subgraph_call(
+            "Loop",
+            *subgraph_index,
+            subgraph_expected_param_types_sink,
+            vec![subgraph_param.clone(), init.clone(), int64],
+          )?;
+          let output = subgraph
+            .output_type
+            .and_then(|x| vm.script.types.get(x as usize).map(VmType::<&'a str>::from))
+            .ok_or_else(|| TypeckError::MissingOutputFromReduce)?;
+          ensure_covariant(init, &output)?;
+          Some(output)
+        }
         TwGraphNode::Throw => {
           let [msg] = validate_in_edges::<1>(node, in_edges, &types)?;
           ensure_type_eq(&VmType::Primitive(PrimitiveType::String), msg)?;
           None
         }
+        TwGraphNode::Count => {
+          let [set] = validate_in_edges::<1>(node, in_edges, &types)?;
+          match set {
+            VmType::Set(_) => Some(VmType::Primitive(PrimitiveType::Int64)),
+            _ => return Err(TypeckError::NotSet(format!("{:?}", set)).into()),
+          }
+        }
+        TwGraphNode::Sum(key_index) | TwGraphNode::Min(key_index) | TwGraphNode::Max(key_index) => {
+          let [set] = validate_in_edges::<1>(node, in_edges, &types)?;
+          let member_ty = match set {
+            VmType::Set(x) => match &*x.ty {
+              VmType::Table(x) => x.name,
+              _ => return Err(TypeckError::NotTable(format!("{:?}", x.ty)).into()),
+            },
+            _ => return Err(TypeckError::NotSet(format!("{:?}", set)).into()),
+          };
+          let key = vm
+            .script
+            .idents
+            .get(*key_index as usize)
+            .ok_or_else(|| TypeckError::IdentIndexOob)?;
+          let table_ty = vm
+            .schema
+            .types
+            .get(member_ty)
+            .ok_or_else(|| TypeckError::TableTypeNotFound(member_ty.to_string()))?;
+          let field_ty = table_ty
+            .fields
+            .get(key.as_str())
+            .map(|x| VmType::from(&x.0))
+            .ok_or_else(|| {
+              TypeckError::FieldNotPresentInTable(key.clone(), table_ty.name.clone())
+            })?;
+          let ok = match (node, &field_ty) {
+            (
+              TwGraphNode::Sum(_),
+              VmType::Primitive(PrimitiveType::Int64) | VmType::Primitive(PrimitiveType::Double),
+            ) => true,
+            (TwGraphNode::Sum(_), _) => false,
+            (_, VmType::Primitive(_)) => true,
+            _ => false,
+          };
+          if !ok {
+            return Err(TypeckError::BadAggregateField(key.clone(), member_ty.to_string()).into());
+          }
+          Some(field_ty)
+        }
+        TwGraphNode::ScanIndex(key_index, has_range)
+        | TwGraphNode::ScanIndexLimited(key_index, has_range, _) => {
+          let set;
+          let mut range = None;
+          let mut limit = None;
+          match (
+            *has_range,
+            matches!(node, TwGraphNode::ScanIndexLimited(..)),
+          ) {
+            (false, false) => {
+              let [set_] = validate_in_edges::<1>(node, in_edges, &types)?;
+              set = set_;
+            }
+            (true, false) => {
+              let [set_, start_key, end_key] = validate_in_edges::<3>(node, in_edges, &types)?;
+              set = set_;
+              range = Some((start_key, end_key));
+            }
+            (false, true) => {
+              let [set_, limit_] = validate_in_edges::<2>(node, in_edges, &types)?;
+              set = set_;
+              limit = Some(limit_);
+            }
+            (true, true) => {
+              let [set_, start_key, end_key, limit_] =
+                validate_in_edges::<4>(node, in_edges, &types)?;
+              set = set_;
+              range = Some((start_key, end_key));
+              limit = Some(limit_);
+            }
+          }
+          if let Some(limit) = limit {
+            ensure_type_eq(&VmType::Primitive(PrimitiveType::Int64), limit)?;
+          }
+          let member_ty = match set {
+            VmType::Set(x) => match &*x.ty {
+              VmType::Table(x) => x.name,
+              _ => return Err(TypeckError::ScanIndexOnNonSet(format!("{:?}", set)).into()),
+            },
+            _ => return Err(TypeckError::ScanIndexOnNonSet(format!("{:?}", set)).into()),
+          };
+          let key = vm
+            .script
+            .idents
+            .get(*key_index as usize)
+            .ok_or_else(|| TypeckError::IdentIndexOob)?;
+          let field = vm
+            .schema
+            .types
+            .get(member_ty)
+            .and_then(|x| x.lookup_indexed_field(key))
+            .ok_or_else(|| TypeckError::FieldNotIndexed(key.clone(), member_ty.to_string()))?;
+          if let Some((start_key, end_key)) = range {
+            let field_ty = VmType::from(field.ty);
+            ensure_type_eq(&field_ty, start_key)?;
+            ensure_type_eq(&field_ty, end_key)?;
+          }
+          match set {
+            VmType::Set(x) => Some(VmType::List(VmListType { ty: x.ty.clone() })),
+            _ => unreachable!(),
+          }
+        }
       };
       types.push(ty);
     }
@@ -732,6 +1315,7 @@ impl<'a, 'b> GlobalTyckContext<'a, 'b> {
     match (output_type, actual_output_ty) {
       (Some(a), Some(b)) => ensure_covariant(a, b)?,
       (None, None) => {}
+      (None, Some(_)) if self.try_bodies.contains(&(graph_index as u32)) => {}
       _ => {
         return Err(
           TypeckError::OutputTypeMismatch(
@@ -746,9 +1330,113 @@ impl<'a, 'b> GlobalTyckContext<'a, 'b> {
     Ok(GraphTypeInfo {
       nodes: types,
       params,
+
+      // Computed after all graphs are typechecked.
+      nullable: vec![],
+      output_nullable: false,
+
+      // Computed after all graphs are typechecked.
+      output_acl: Default::default(),
     })
   }
 
+  /// Infers which nodes of a graph may output null, and checks that nodes that are not
+  /// optional-chained only get null parameters if they handle them.
+  ///
+  /// Nulls come from null constants, primitive fields of tables that are not set and have no
+  /// `@default`, empty lists and aggregates over empty sets, and propagate through optional
+  /// chaining, `select`, `reduce` and calls. Graph parameters are assumed to be non-null, as are outputs of graphs in the same
+  /// strongly connected component of the call graph, which are not inferred yet.
+  fn infer_nullability(
+    &self,
+    graph_index: usize,
+    graphs: &[GraphTypeInfo<'a>],
+  ) -> Result<Vec<bool>> {
+    let g = &self.vm.script.graphs[graph_index];
+    let types = &graphs[graph_index].nodes;
+    let mut nullable: Vec<bool> = Vec::with_capacity(g.nodes.len());
+    for (i, (node, in_edges, _)) in g.nodes.iter().enumerate() {
+      let nullable_param = in_edges.iter().any(|x| nullable[*x as usize]);
+      let chained = g.is_optional_chained(i);
+      if nullable_param && !chained && node.is_optional_chained() {
+        return Err(TypeckError::NullableParamNotChained(format!("{:?}", node)).into());
+      }
+
+      let x = match node {
+        TwGraphNode::LoadConst(x) => matches!(&*self.vm.consts[*x as usize], VmValue::Null(_)),
+        TwGraphNode::GetField(key_index) => match &types[in_edges[0] as usize] {
+          Some(VmType::Table(table_ty)) if matches!(types[i], Some(VmType::Primitive(_))) => {
+            let key = &self.vm.script.idents[*key_index as usize];
+            !self
+              .vm
+              .schema
+              .types
+              .get(table_ty.name)
+              .and_then(|x| x.fields.get(key.as_str()))
+              .map(|x| {
+                x.1
+                  .iter()
+                  .any(|x| x.default_value().is_some() || x.is_version())
+              })
+              .unwrap_or(false)
+          }
+          _ => false,
+        },
+        TwGraphNode::ListHead
+        | TwGraphNode::MapGetDynamic
+        | TwGraphNode::JsonGet(_)
+        | TwGraphNode::ParseDouble
+        | TwGraphNode::PopFromList
+        | TwGraphNode::FilterSet(_)
+        | TwGraphNode::ListPop(_)
+        | TwGraphNode::ListGet(_)
+        | TwGraphNode::DictGet(_)
+        | TwGraphNode::Min(_)
+        | TwGraphNode::Max(_) => true,
+        TwGraphNode::Select | TwGraphNode::Nop | TwGraphNode::Cond => nullable_param,
+        TwGraphNode::Reduce(_, _) | TwGraphNode::Loop(_) => nullable[in_edges[1] as usize],
+        TwGraphNode::Call(x) => graphs[*x as usize].output_nullable,
+        _ => false,
+      };
+      nullable.push(x || (chained && nullable_param));
+    }
+    Ok(nullable)
+  }
+
+  /// Checks that the defaults of script params match their types, and that exported graphs
+  /// receive them through params of the same types.
+  fn check_script_params(&self) -> Result<()> {
+    let vm = self.vm;
+    let mut types = HashMap::new();
+    for p in &vm.script.params {
+      let ty = vm
+        .types
+        .get(p.ty as usize)
+        .ok_or_else(|| TypeckError::ParamTypeIndexOob)?;
+      if let Some(x) = p.default {
+        let value = vm
+          .consts
+          .get(x as usize)
+          .ok_or_else(|| TypeckError::ConstIndexOob)?;
+        if !ty.is_covariant_from(&VmType::from(&**value)) {
+          return Err(TypeckError::ScriptParamDefaultTypeMismatch(p.name.clone()).into());
+        }
+      }
+      types.insert(p.name.as_str(), ty);
+    }
+
+    for g in vm.script.graphs.iter().filter(|g| g.exported) {
+      for (name, ty) in g.param_names.iter().zip(g.param_types.iter()) {
+        if let Some(expected) = types.get(name.as_str()) {
+          if vm.types.get(*ty as usize) != Some(*expected) {
+            return Err(TypeckError::ScriptParamTypeMismatch(name.clone(), g.name.clone()).into());
+          }
+        }
+      }
+    }
+    Ok(())
+  }
+
   fn validate_subgraph_call(
     &self,
     opname: &'static str,
@@ -811,6 +1499,19 @@ fn ensure_type<'a, 'b>(x: Option<&'b VmType<&'a str>>) -> Result<&'b VmType<&'a
   }
 }
 
+/// The common type of the fields of a map, to which all of them are covariant.
+fn map_value_type<'a>(map_ty: &VmType<&'a str>) -> Result<VmType<&'a str>> {
+  let fields = match map_ty {
+    VmType::Map(x) => x,
+    _ => return Err(TypeckError::NotMap(format!("{:?}", map_ty)).into()),
+  };
+  fields
+    .values()
+    .find(|candidate| fields.values().all(|x| candidate.is_covariant_from(x)))
+    .cloned()
+    .ok_or_else(|| TypeckError::NoCommonMapValueType(format!("{:?}", map_ty)).into())
+}
+
 fn ensure_covariant<'a>(dst: &VmType<&'a str>, src: &VmType<&'a str>) -> Result<()> {
   if dst.is_covariant_from(src) {
     Ok(())
@@ -827,6 +1528,55 @@ fn ensure_type_eq<'a>(dst: &VmType<&'a str>, src: &VmType<&'a str>) -> Result<()
   }
 }
 
+/// The member type of the `list<T>` field `key_index` of a table.
+fn list_field_member_type<'a>(
+  vm: &TwVm<'a>,
+  table_ty: &VmType<&'a str>,
+  key_index: u32,
+) -> Result<VmType<&'a str>> {
+  match table_field_type(vm, table_ty, key_index)? {
+    (FieldType::List(x), _, _) => Ok(VmType::from(&**x)),
+    (_, key, table_name) => Err(TypeckError::FieldNotList(key.clone(), table_name.clone()).into()),
+  }
+}
+
+/// The key and value types of the `map<K, V>` field `key_index` of a table.
+fn map_field_types<'a>(
+  vm: &TwVm<'a>,
+  table_ty: &VmType<&'a str>,
+  key_index: u32,
+) -> Result<(VmType<&'a str>, VmType<&'a str>)> {
+  match table_field_type(vm, table_ty, key_index)? {
+    (FieldType::Map(k, v), _, _) => Ok((VmType::from(&**k), VmType::from(&**v))),
+    (_, key, table_name) => Err(TypeckError::FieldNotMap(key.clone(), table_name.clone()).into()),
+  }
+}
+
+/// The type of the field `key_index` of a table, along with the name of the field and the table.
+fn table_field_type<'a>(
+  vm: &TwVm<'a>,
+  table_ty: &VmType<&'a str>,
+  key_index: u32,
+) -> Result<(&'a FieldType, &'a String, &'a Arc<str>)> {
+  let key = vm
+    .script
+    .idents
+    .get(key_index as usize)
+    .ok_or_else(|| TypeckError::IdentIndexOob)?;
+  let table_ty = match table_ty {
+    VmType::Table(x) => vm
+      .schema
+      .types
+      .get(x.name)
+      .ok_or_else(|| TypeckError::TableTypeNotFound(x.name.to_string()))?,
+    _ => return Err(TypeckError::NotTable(format!("{:?}", table_ty)).into()),
+  };
+  match table_ty.fields.get(key.as_str()) {
+    Some((ty, _)) => Ok((ty, key, &table_ty.name)),
+    None => Err(TypeckError::FieldNotPresentInTable(key.clone(), table_ty.name.clone()).into()),
+  }
+}
+
 fn extract_list_element_type<'a, 'b>(x: &'b VmType<&'a str>) -> Result<&'b VmType<&'a str>> {
   match x {
     VmType::List(x) => Ok(&*x.ty),
@@ -4,6 +4,7 @@ use rpds::RedBlackTreeMapSync;
 use crate::{
   data::{
     treewalker::{
+      asm::codegen::compile_twscript,
       bytecode::{TwGraph, TwGraphNode},
       typeck::GlobalTyckContext,
       vm::TwVm,
@@ -20,51 +21,7 @@ use crate::{
 
 use super::{bytecode::TwScript, vm_value::VmTableType};
 
-const SIMPLE_SCHEMA: &str = r#"
-type Item<T> {
-  inner: T,
-  inner2: T,
-  @primary
-  something_else: string,
-}
-type Duration<T> {
-  start: T,
-  end: T,
-}
-type Recursive<T> {
-  inner: Recursive<T>,
-}
-type BinaryTree<T> {
-  left: BinaryTree<T>,
-  right: BinaryTree<T>,
-  value: T,
-}
-
-type TrinaryTree<T> {
-  left: TrinaryTree<T>,
-  middle: TrinaryTree<T>,
-  right: TrinaryTree<T>,
-  value: T,
-}
-
-type InternalSet {
-  @primary
-  key: bytes,
-  s: set<Wrapper<int64>>,
-}
-
-type Wrapper<T> {
-  @primary
-  value: T,
-}
-
-export set<Item<Duration<int64>>> items;
-export Recursive<int64> item;
-export BinaryTree<int64> a_binary_tree;
-export InternalSet an_internal_set;
-export set<InternalSet> nested_internal_sets;
-export TrinaryTree<int64> a_trinary_tree;
-"#;
+const SIMPLE_SCHEMA: &str = include_str!("../../fixtures/simple/schema.rschema");
 
 /*
 fn root_map<'a>(schema: &'a CompiledSchema, plan: &'a StoragePlan) -> VmValue<'a> {
@@ -98,6 +55,7 @@ fn basic_typeck() {
     graphs: vec![TwGraph {
       name: "".into(),
       exported: false,
+      read_only: false,
       nodes: vec![
         (TwGraphNode::LoadParam(0), vec![], None), // 0
         (TwGraphNode::GetField(0), vec![0], None), // 1
@@ -107,9 +65,16 @@ fn basic_typeck() {
       ],
       output: Some(4),
       output_type: Some(1),
+      cache: None,
+      optional_chain: vec![],
+      deferred: vec![],
+      relaxed_isolation: false,
+      param_names: vec![],
       param_types: vec![0],
     }],
     entry: 0,
+    params: vec![],
+    mounts: vec![],
     consts: vec![],
     idents: vec![
       "a_trinary_tree".into(),
@@ -137,6 +102,7 @@ fn filter_set() {
       TwGraph {
         name: "".into(),
         exported: false,
+        read_only: false,
         nodes: vec![
           (TwGraphNode::LoadParam(0), vec![], None),     // 0
           (TwGraphNode::GetField(0), vec![0], None),     // 1
@@ -145,20 +111,33 @@ fn filter_set() {
         ],
         output: Some(3),
         output_type: Some(1),
+        cache: None,
+        optional_chain: vec![],
+        deferred: vec![],
+        relaxed_isolation: false,
+        param_names: vec![],
         param_types: vec![0],
       },
       TwGraph {
         name: "".into(),
         exported: false,
+        read_only: false,
         nodes: vec![
           (TwGraphNode::LoadConst(0), vec![], None), // 0
         ],
         output: Some(0),
         output_type: Some(2),
+        cache: None,
+        optional_chain: vec![],
+        deferred: vec![],
+        relaxed_isolation: false,
+        param_names: vec![],
         param_types: vec![3, 3],
       },
     ],
     entry: 0,
+    params: vec![],
+    mounts: vec![],
     consts: vec![
       VmConst::Bool(true),
       VmConst::Null(VmType::Primitive(PrimitiveType::Int64)),
@@ -195,6 +174,7 @@ fn basic_typeck_fail_unknown_name() {
     graphs: vec![TwGraph {
       name: "".into(),
       exported: false,
+      read_only: false,
       nodes: vec![
         (TwGraphNode::LoadParam(0), vec![], None), // 0
         (TwGraphNode::GetField(0), vec![0], None), // 1
@@ -204,9 +184,16 @@ fn basic_typeck_fail_unknown_name() {
       ],
       output: Some(4),
       output_type: Some(1),
+      cache: None,
+      optional_chain: vec![],
+      deferred: vec![],
+      relaxed_isolation: false,
+      param_names: vec![],
       param_types: vec![0],
     }],
     entry: 0,
+    params: vec![],
+    mounts: vec![],
     consts: vec![],
     idents: vec![
       "a_trinary_tree".into(),
@@ -240,6 +227,7 @@ fn basic_typeck_output_type_mismatch() {
     graphs: vec![TwGraph {
       name: "".into(),
       exported: false,
+      read_only: false,
       nodes: vec![
         (TwGraphNode::LoadParam(0), vec![], None), // 0
         (TwGraphNode::GetField(0), vec![0], None), // 1
@@ -249,9 +237,16 @@ fn basic_typeck_output_type_mismatch() {
       ],
       output: Some(4),
       output_type: Some(1),
+      cache: None,
+      optional_chain: vec![],
+      deferred: vec![],
+      relaxed_isolation: false,
+      param_names: vec![],
       param_types: vec![0],
     }],
     entry: 0,
+    params: vec![],
+    mounts: vec![],
     consts: vec![],
     idents: vec![
       "a_trinary_tree".into(),
@@ -296,6 +291,7 @@ fn typeck_set_point_get() {
     graphs: vec![TwGraph {
       name: "".into(),
       exported: false,
+      read_only: false,
       nodes: vec![
         (TwGraphNode::LoadParam(0), vec![], None),         // 0
         (TwGraphNode::LoadConst(0), vec![], None),         // 1
@@ -309,9 +305,16 @@ fn typeck_set_point_get() {
       ],
       output: Some(8),
       output_type: Some(1),
+      cache: None,
+      optional_chain: vec![],
+      deferred: vec![],
+      relaxed_isolation: false,
+      param_names: vec![],
       param_types: vec![0],
     }],
     entry: 0,
+    params: vec![],
+    mounts: vec![],
     consts: vec![VmConst::Primitive(PrimitiveValue::String("test".into()))],
     idents: vec![
       "items".into(),
@@ -325,3 +328,61 @@ fn typeck_set_point_get() {
   let vm = TwVm::new(&schema, &plan, &script).unwrap();
   GlobalTyckContext::new(&vm).unwrap().typeck().unwrap();
 }
+
+#[test]
+fn select_typeck() {
+  let alloc = Bump::new();
+  let schema = compile(
+    &parse(
+      &alloc,
+      r#"
+  type Item {
+    @primary
+    id: string,
+  }
+  export set<Item> items;
+  "#,
+    )
+    .unwrap(),
+  )
+  .unwrap();
+  let plan = generate_plan_for_schema(&Default::default(), &Default::default(), &schema).unwrap();
+
+  let script = compile_twscript(
+    r#"
+    graph main(a: bool): int64 {
+      if a {
+        x = null<int64>;
+      }
+      y = 1;
+      return select x y;
+    }
+    "#,
+  )
+  .unwrap();
+  let vm = TwVm::new(&schema, &plan, &script).unwrap();
+  let type_info = GlobalTyckContext::new(&vm).unwrap().typeck().unwrap();
+  let output = script.graphs[0].output.unwrap() as usize;
+  assert_eq!(
+    type_info.graphs[0].nodes[output],
+    Some(VmType::Primitive(PrimitiveType::Int64))
+  );
+  // Either candidate may be null, so may the select.
+  assert!(type_info.graphs[0].nullable[output]);
+
+  let script = compile_twscript(
+    r#"
+    graph main(a: bool): int64 {
+      if a {
+        x = 1;
+      }
+      y = "";
+      return select x y;
+    }
+    "#,
+  )
+  .unwrap();
+  let vm = TwVm::new(&schema, &plan, &script).unwrap();
+  let err = GlobalTyckContext::new(&vm).unwrap().typeck().unwrap_err();
+  assert!(err.to_string().contains("select type mismatch"), "{}", err);
+}
@@ -0,0 +1,170 @@
+//! Graphviz rendering of the graphs of a script, in the DOT language.
+//!
+//! Each graph is a cluster with an entry node, followed by one node per dataflow node, labeled with
+//! the node index, its operation and, once type checked, its output type. Nodes are named after
+//! their ids in `dfvis`, so that execution traces can be matched against the rendering.
+//!
+//! Edges:
+//!
+//! - Solid: params, labeled with their position.
+//! - Dashed: preconditions, and edges from the graph entry to nodes without params or
+//!   preconditions.
+//! - Dotted and red: subgraphs that a node runs.
+
+use std::fmt::Write;
+
+use anyhow::Result;
+
+use super::{
+  bytecode::TwGraphNode, trace::dataflow_node_id, typeck::GlobalTypeInfo, vm::TwVm,
+  vm_value::VmConst,
+};
+
+/// Renders all graphs of `vm` as a DOT digraph. With `type_info`, nodes are labeled with their
+/// output types, suffixed with `?` where the output may be null.
+pub fn visualize_dot(vm: &TwVm, type_info: Option<&GlobalTypeInfo>) -> Result<String> {
+  let script = vm.script;
+  let mut out = String::new();
+  writeln!(out, "digraph script {{")?;
+  writeln!(out, "  node [shape=box, fontname=\"monospace\"];")?;
+  for (graph_index, g) in script.graphs.iter().enumerate() {
+    let graph_type_info = type_info.and_then(|x| x.graphs.get(graph_index));
+    writeln!(out, "  subgraph cluster_{} {{", graph_index)?;
+    writeln!(out, "    label={};", quote(&g.name))?;
+    let mut entry_label = format!("graph {}", g.name);
+    if g.exported {
+      entry_label = format!("export {}", entry_label);
+    }
+    writeln!(
+      out,
+      "    n{} [shape=diamond, label={}];",
+      graph_index,
+      quote(&entry_label)
+    )?;
+
+    for (i, (node, _, _)) in g.nodes.iter().enumerate() {
+      let mut label = format!("{}: {}", i, node_label(vm, node));
+      if let Some(ty) = graph_type_info
+        .and_then(|x| x.nodes.get(i))
+        .and_then(|x| x.as_ref())
+      {
+        let nullable = graph_type_info
+          .and_then(|x| x.nullable.get(i))
+          .copied()
+          .unwrap_or(false);
+        write!(label, "\n{}{}", ty, if nullable { "?" } else { "" })?;
+      }
+      let style = if g.output == Some(i as u32) {
+        ", peripheries=2"
+      } else {
+        ""
+      };
+      writeln!(
+        out,
+        "    n{} [label={}{}];",
+        dataflow_node_id(script, graph_index, i),
+        quote(&label),
+        style
+      )?;
+    }
+    writeln!(out, "  }}")?;
+
+    for (i, (node, in_edges, precondition)) in g.nodes.iter().enumerate() {
+      let id = dataflow_node_id(script, graph_index, i);
+      for (param_index, x) in in_edges.iter().enumerate() {
+        writeln!(
+          out,
+          "  n{} -> n{} [label=\"{}\"];",
+          dataflow_node_id(script, graph_index, *x as usize),
+          id,
+          param_index
+        )?;
+      }
+      if let Some(x) = precondition {
+        writeln!(
+          out,
+          "  n{} -> n{} [style=dashed];",
+          dataflow_node_id(script, graph_index, *x as usize),
+          id
+        )?;
+      }
+      if in_edges.is_empty() && precondition.is_none() {
+        writeln!(out, "  n{} -> n{} [style=dashed];", graph_index, id)?;
+      }
+      for subgraph in node.subgraph_references() {
+        writeln!(
+          out,
+          "  n{} -> n{} [style=dotted, color=red, constraint=false];",
+          id, subgraph
+        )?;
+      }
+    }
+  }
+  writeln!(out, "}}")?;
+  Ok(out)
+}
+
+/// The operation of a node, with the identifiers, consts, types and graphs it refers to resolved.
+fn node_label(vm: &TwVm, node: &TwGraphNode) -> String {
+  use TwGraphNode as N;
+
+  let script = vm.script;
+  let debug = format!("{:?}", node);
+  let name = debug.split('(').next().unwrap_or_default();
+  let ident = |x: &u32| script.idents[*x as usize].clone();
+  let graph = |x: &u32| script.graphs[*x as usize].name.clone();
+  let arg = match node {
+    N::LoadConst(x) => match &script.consts[*x as usize] {
+      VmConst::Primitive(x) => format!("{}", x),
+      VmConst::Bool(x) => format!("{}", x),
+      VmConst::Null(ty) => format!("null<{}>", ty),
+      VmConst::Table(x) => format!("table<{}>", x.ty),
+      VmConst::Set(x) => format!("set<{}>", x.member_ty),
+    },
+    N::LoadParam(x) => format!("{}", x),
+    N::CreateList(x) | N::JsonGet(x) => format!("{}", script.types[*x as usize]),
+    N::BuildTable(x)
+    | N::GetField(x)
+    | N::InsertIntoMap(x)
+    | N::InsertIntoTable(x)
+    | N::ListPush(x)
+    | N::ListPop(x)
+    | N::ListGet(x)
+    | N::ListLen(x)
+    | N::AtomicAdd(x)
+    | N::DictGet(x)
+    | N::DictPut(x)
+    | N::DictDelete(x)
+    | N::DictRange(x)
+    | N::DeleteFromMap(x)
+    | N::Sum(x)
+    | N::Min(x)
+    | N::Max(x) => ident(x),
+    N::ScanIndex(x, range) => format!("{}{}", ident(x), if *range { ", range" } else { "" }),
+    N::ScanIndexLimited(x, range, reverse) => format!(
+      "{}{}{}",
+      ident(x),
+      if *range { ", range" } else { "" },
+      if *reverse { ", desc" } else { "" }
+    ),
+    N::SortList(x) | N::Loop(x) | N::FilterSet(x) | N::Try(x) | N::Call(x) => graph(x),
+    N::Reduce(x, range) => format!("{}{}", graph(x), if *range { ", range" } else { "" }),
+    _ => return name.to_string(),
+  };
+  format!("{}({})", name, arg)
+}
+
+fn quote(x: &str) -> String {
+  let mut out = String::with_capacity(x.len() + 2);
+  out.push('"');
+  for c in x.chars() {
+    match c {
+      '"' => out.push_str("\\\""),
+      '\\' => out.push_str("\\\\"),
+      '\n' => out.push_str("\\n"),
+      c => out.push(c),
+    }
+  }
+  out.push('"');
+  out
+}
@@ -0,0 +1,76 @@
+use bumpalo::Bump;
+
+use crate::{
+  schema::{compile::compile, grammar::parse},
+  storage_plan::planner::generate_plan_for_schema,
+};
+
+use super::{
+  asm::codegen::compile_twscript, typeck::GlobalTyckContext, viz::visualize_dot, vm::TwVm,
+};
+
+#[test]
+fn basic() {
+  let alloc = Bump::new();
+  let schema = compile(
+    &parse(
+      &alloc,
+      r#"
+      type Item {
+        @primary
+        id: string,
+        name: string,
+      }
+      export set<Item> items;
+      "#,
+    )
+    .unwrap(),
+  )
+  .unwrap();
+  let plan = generate_plan_for_schema(&Default::default(), &Default::default(), &schema).unwrap();
+  let script = compile_twscript(
+    r#"
+    export graph name(root: schema, id: string, flag: bool): string {
+      if flag {
+        n = call(get) [root.items, id];
+      }
+      return n;
+    }
+    graph get(items: set<Item>, id: string): string {
+      return (point_get items id).name;
+    }
+    "#,
+  )
+  .unwrap();
+  let vm = TwVm::new(&schema, &plan, &script).unwrap();
+  let type_info = GlobalTyckContext::new(&vm).unwrap().typeck().unwrap();
+
+  let dot = visualize_dot(&vm, None).unwrap();
+  let lines = dot.lines().map(|x| x.trim()).collect::<Vec<_>>();
+  for expected in [
+    "digraph script {",
+    "n0 [shape=diamond, label=\"export graph name\"];",
+    "n1 [shape=diamond, label=\"graph get\"];",
+    "n2 [label=\"0: LoadParam(0)\"];",
+    "n5 [label=\"3: GetField(items)\"];",
+    "n6 [label=\"4: Call(get)\", peripheries=2];",
+    "n2 -> n5 [label=\"0\"];",
+    "n4 -> n6 [style=dashed];",
+    "n6 -> n1 [style=dotted, color=red, constraint=false];",
+    "n0 -> n2 [style=dashed];",
+  ] {
+    assert!(lines.contains(&expected), "{}\n{}", expected, dot);
+  }
+
+  let dot = visualize_dot(&vm, Some(&type_info)).unwrap();
+  assert!(
+    dot.contains("n6 [label=\"4: Call(get)\\nstring?\", peripheries=2];"),
+    "{}",
+    dot
+  );
+  assert!(
+    dot.contains("n10 [label=\"3: GetField(name)\\nstring?\", peripheries=2];"),
+    "{}",
+    dot
+  );
+}
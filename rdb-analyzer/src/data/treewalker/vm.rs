@@ -1,4 +1,7 @@
-use std::{collections::HashMap, sync::Arc};
+use std::{
+  collections::{HashMap, HashSet},
+  sync::Arc,
+};
 
 use anyhow::Result;
 
@@ -14,6 +17,12 @@ use thiserror::Error;
 pub enum VmError {
   #[error("exported graph not found: `{0}`")]
   ExportedGraphNotFound(String),
+
+  #[error("graph `{0}` is not read-only and cannot be cached")]
+  CacheOnEffectfulGraph(String),
+
+  #[error("graph `{0}` is declared readonly but is not read-only")]
+  EffectInReadOnlyGraph(String),
 }
 
 pub struct TwVm<'a> {
@@ -49,14 +58,25 @@ impl<'a> TwVm<'a> {
       }
     }
 
-    Ok(Self {
+    let vm = Self {
       schema,
       storage_plan,
       script,
       consts,
       types,
       exported_graph_name_index,
-    })
+    };
+
+    for (i, g) in script.graphs.iter().enumerate() {
+      if g.cache.is_some() && !vm.is_graph_read_only(i) {
+        return Err(VmError::CacheOnEffectfulGraph(g.name.clone()).into());
+      }
+      if g.read_only && !vm.is_graph_read_only(i) {
+        return Err(VmError::EffectInReadOnlyGraph(g.name.clone()).into());
+      }
+    }
+
+    Ok(vm)
   }
 
   pub fn lookup_exported_graph_by_name(&self, name: &str) -> Result<usize> {
@@ -68,4 +88,23 @@ impl<'a> TwVm<'a> {
         .ok_or_else(|| VmError::ExportedGraphNotFound(name.into()))?,
     )
   }
+
+  /// Whether neither the graph at `graph_index` nor any subgraph reachable from it contains an
+  /// effect node.
+  pub fn is_graph_read_only(&self, graph_index: usize) -> bool {
+    let mut visited = HashSet::new();
+    let mut stack = vec![graph_index];
+    while let Some(index) = stack.pop() {
+      if !visited.insert(index) {
+        continue;
+      }
+      for (node, _, _) in &self.script.graphs[index].nodes {
+        if node.is_effect() {
+          return false;
+        }
+        stack.extend(node.subgraph_references().iter().map(|x| *x as usize));
+      }
+    }
+    true
+  }
 }
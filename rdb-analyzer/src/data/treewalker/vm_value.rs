@@ -5,8 +5,8 @@ use std::{collections::BTreeMap, fmt::Display, sync::Arc};
 use thiserror::Error;
 
 use crate::{
-  data::{pathwalker::PathWalker, value::PrimitiveValue},
-  schema::compile::{CompiledSchema, FieldAnnotationList, FieldType, PrimitiveType},
+  data::{mount::MOUNT_ROOT_FIELD, pathwalker::PathWalker, value::PrimitiveValue},
+  schema::compile::{CompiledSchema, FieldType, PrimitiveType},
 };
 
 #[derive(Debug, PartialEq)]
@@ -24,6 +24,9 @@ pub enum VmValue<'a> {
   Null(VmType<&'a str>),
 
   List(VmListValue<'a>),
+
+  /// VM-only
+  Json(serde_json::Value),
 }
 
 #[derive(Debug, PartialEq)]
@@ -32,6 +35,55 @@ pub struct VmListValue<'a> {
   pub node: ListSync<Arc<VmValue<'a>>>,
 }
 
+/// The fields that make up the primary key of a set member type, in key order.
+#[derive(Debug, Clone)]
+pub struct PrimaryKey<'a> {
+  pub fields: Vec<(&'a str, &'a FieldType)>,
+}
+
+impl<'a> PrimaryKey<'a> {
+  /// Whether the key is made of several `@primary(n)` fields.
+  pub fn is_composite(&self) -> bool {
+    self.fields.len() > 1
+  }
+
+  /// Type of the values that address members of the set. Composite keys are addressed by their
+  /// packed form.
+  pub fn value_type(&self) -> VmType<&'a str> {
+    if self.is_composite() {
+      VmType::Primitive(PrimitiveType::Bytes)
+    } else {
+      VmType::from(self.fields[0].1)
+    }
+  }
+
+  /// Builds the key value from the values of the key fields, in key order.
+  pub fn value_of(&self, mut components: Vec<PrimitiveValue>) -> PrimitiveValue {
+    if self.is_composite() {
+      PrimitiveValue::pack_composite_key(&components)
+    } else {
+      components.pop().unwrap()
+    }
+  }
+
+  /// Key value of a fresh table. `None` if any of the key fields is null.
+  pub fn value_of_fresh(&self, table: &VmTableValue<'a>) -> Option<PrimitiveValue> {
+    let fields = match &table.kind {
+      VmTableValueKind::Fresh(x) => x,
+      VmTableValueKind::Resident(_) => return None,
+    };
+    let components = self
+      .fields
+      .iter()
+      .map(|(name, _)| match fields.get(name).map(|x| &**x) {
+        Some(VmValue::Primitive(x)) => Some(x.clone()),
+        _ => None,
+      })
+      .collect::<Option<Vec<_>>>()?;
+    Some(self.value_of(components))
+  }
+}
+
 #[derive(Debug, PartialEq)]
 pub struct VmTableValue<'a> {
   pub ty: &'a str,
@@ -76,6 +128,9 @@ pub enum VmType<K: Clone + Ord + PartialOrd + Eq + PartialEq> {
   /// VM-only
   Map(RedBlackTreeMapSync<K, VmType<K>>),
 
+  /// VM-only
+  Json,
+
   /// An unknown type. Placeholder for unfinished type inference.
   Unknown,
 
@@ -101,6 +156,7 @@ impl<K: AsRef<str> + Clone + Ord + PartialOrd + Eq + PartialEq> Display for VmTy
       VmType::List(x) => write!(f, "list<{}>", x.ty),
       VmType::Set(x) => write!(f, "set<{}>", x.ty),
       VmType::Schema => write!(f, "schema"),
+      VmType::Json => write!(f, "json"),
     }
   }
 }
@@ -146,6 +202,7 @@ impl<
       ),
       VmType::Unknown => VmType::Unknown,
       VmType::Schema => VmType::Schema,
+      VmType::Json => VmType::Json,
     }
   }
 }
@@ -158,6 +215,24 @@ impl<'a, T: From<&'a str> + Clone + Ord + PartialOrd + Eq + PartialEq> From<&'a
     for (field_name, field_ty) in &that.exports {
       m.insert_mut(T::from(&**field_name), VmType::<T>::from(field_ty));
     }
+    if !that.mounts.is_empty() {
+      let mounts = that
+        .mounts
+        .iter()
+        .map(|(mount_name, exports)| {
+          (
+            T::from(&**mount_name),
+            VmType::Map(
+              exports
+                .iter()
+                .map(|(k, v)| (T::from(&**k), VmType::<T>::from(v)))
+                .collect(),
+            ),
+          )
+        })
+        .collect();
+      m.insert_mut(T::from(MOUNT_ROOT_FIELD), VmType::Map(mounts));
+    }
     VmType::Map(m)
   }
 }
@@ -179,6 +254,7 @@ impl<'a> From<&VmValue<'a>> for VmType<&'a str> {
       ),
       VmValue::Null(x) => x.clone(),
       VmValue::List(x) => x.member_ty.clone(),
+      VmValue::Json(_) => VmType::Json,
     }
   }
 }
@@ -195,6 +271,18 @@ impl<'a, T: From<&'a str> + Clone + Ord + PartialOrd + Eq + PartialEq> From<&'a
       FieldType::Set(x) => VmType::Set(VmSetType {
         ty: Box::new(VmType::from(&**x)),
       }),
+      FieldType::List(x) => VmType::List(VmListType {
+        ty: Box::new(VmType::from(&**x)),
+      }),
+
+      // A list of `map { key: K, value: V }` entries in key order.
+      FieldType::Map(k, v) => VmType::List(VmListType {
+        ty: Box::new(VmType::Map(
+          RedBlackTreeMapSync::new_sync()
+            .insert(T::from("key"), VmType::from(&**k))
+            .insert(T::from("value"), VmType::from(&**v)),
+        )),
+      }),
     }
   }
 }
@@ -224,15 +312,21 @@ impl<'a> VmType<&'a str> {
     }
   }
 
-  pub fn set_primary_key(&self, schema: &'a CompiledSchema) -> Option<(&'a str, &'a FieldType)> {
+  pub fn set_primary_key(&self, schema: &'a CompiledSchema) -> Option<PrimaryKey<'a>> {
     match self {
       VmType::Set(x) => match &*x.ty {
         VmType::Table(x) => {
           let specialized_ty = schema.types.get(x.name)?;
-          specialized_ty
-            .fields
-            .iter()
-            .find_map(|(name, (ty, ann))| ann.as_slice().is_primary().then(|| (&**name, ty)))
+          let fields = specialized_ty
+            .primary_key()
+            .into_iter()
+            .map(|(name, ty)| (&**name, ty))
+            .collect::<Vec<_>>();
+          if fields.is_empty() {
+            None
+          } else {
+            Some(PrimaryKey { fields })
+          }
         }
         _ => None,
       },
@@ -261,6 +355,7 @@ impl<'a> VmType<&'a str> {
         kind: VmTableValueKind::Fresh(BTreeMap::new()),
       }),
       VmType::Unknown => return None,
+      VmType::Json => VmValue::Json(serde_json::Value::Null),
     }))
   }
 }
@@ -350,7 +445,7 @@ impl<'a> VmValue<'a> {
         let member_ty = VmType::Table(VmTableType {
           name: &*member_ty.name,
         });
-        let (primary_key, _) = VmType::Set(VmSetType {
+        let primary_key = VmType::Set(VmSetType {
           ty: Box::new(member_ty.clone()),
         })
         .set_primary_key(schema)
@@ -370,14 +465,10 @@ impl<'a> VmValue<'a> {
           }
 
           // XXX: We checked covariance above but is it enough?
-          let primary_key_value = match &member.unwrap_table().kind {
-            VmTableValueKind::Fresh(x) => x
-              .get(primary_key)
-              .unwrap()
-              .unwrap_primitive()
-              .serialize_for_key_component(),
-            _ => unreachable!(),
-          };
+          let primary_key_value = primary_key
+            .value_of_fresh(member.unwrap_table())
+            .ok_or_else(|| VmValueError::MissingPrimaryKey)?
+            .serialize_for_key_component();
           members.insert(primary_key_value.to_vec(), Arc::new(member));
         }
         Ok(Self::Set(VmSetValue {
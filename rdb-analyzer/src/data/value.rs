@@ -4,7 +4,7 @@ use byteorder::{BigEndian, ByteOrder};
 use serde::{Deserialize, Serialize};
 use smallvec::{smallvec, SmallVec};
 
-use crate::schema::compile::PrimitiveType;
+use crate::schema::compile::{DefaultValue, PrimitiveType};
 
 #[derive(Serialize, Deserialize)]
 pub enum PackedValue {
@@ -40,6 +40,17 @@ impl Display for PrimitiveValue {
   }
 }
 
+impl From<&DefaultValue> for PrimitiveValue {
+  fn from(x: &DefaultValue) -> Self {
+    match x {
+      DefaultValue::Int64(x) => Self::Int64(*x),
+      DefaultValue::Double(x) => Self::Double(*x),
+      DefaultValue::String(x) => Self::String(x.clone()),
+      DefaultValue::Bytes(x) => Self::Bytes(x.clone()),
+    }
+  }
+}
+
 impl PrimitiveValue {
   pub fn get_type(&self) -> PrimitiveType {
     match self {
@@ -60,21 +71,7 @@ impl PrimitiveValue {
   /// https://activesphere.com/blog/2018/08/17/order-preserving-serialization
   pub fn serialize_for_key_component(&self) -> SmallVec<[u8; 9]> {
     match self {
-      PrimitiveValue::Bytes(x) => SmallVec::from_iter(
-        std::iter::once(0x01u8)
-          .chain(
-            x.iter()
-              .map(|&x| -> SmallVec<[u8; 2]> {
-                if x == 0 {
-                  smallvec![0x00, 0xff]
-                } else {
-                  smallvec![x]
-                }
-              })
-              .flatten(),
-          )
-          .chain([0x00u8].iter().copied()),
-      ),
+      PrimitiveValue::Bytes(x) => escape_and_terminate(0x01, x),
       PrimitiveValue::String(x) => {
         SmallVec::from_iter(std::iter::once(0x02u8).chain(x.as_bytes().iter().copied()))
       }
@@ -100,6 +97,99 @@ impl PrimitiveValue {
     }
   }
 
+  /// Packs the values of a composite primary key into a single value that addresses the set
+  /// member. Every component is self-delimiting, so that packed keys sort in the order of their
+  /// components.
+  pub fn pack_composite_key(components: &[PrimitiveValue]) -> Self {
+    let mut out = vec![];
+    for x in components {
+      out.extend_from_slice(&x.serialize_for_self_delimiting_component());
+    }
+    PrimitiveValue::Bytes(out)
+  }
+
+  /// Like `serialize_for_key_component`, but strings are escaped and terminated the same way as
+  /// bytes, so that no serialized value is a prefix of another. For values followed by more key
+  /// bytes, e.g. the components of a composite key or the value of an index entry.
+  pub fn serialize_for_self_delimiting_component(&self) -> SmallVec<[u8; 9]> {
+    match self {
+      PrimitiveValue::String(x) => escape_and_terminate(0x02, x.as_bytes()),
+      _ => self.serialize_for_key_component(),
+    }
+  }
+
+  /// Inverse of `pack_composite_key`.
+  pub fn unpack_composite_key(mut x: &[u8]) -> Option<Vec<Self>> {
+    let mut out = vec![];
+    while let Some(&tag) = x.first() {
+      let len = match tag {
+        0x01 | 0x02 => {
+          let mut i = 1;
+          loop {
+            match (*x.get(i)?, x.get(i + 1)) {
+              (0x00, Some(0xff)) => i += 2,
+              (0x00, _) => break i + 1,
+              _ => i += 1,
+            }
+          }
+        }
+        0x03 | 0x04 => 9,
+        _ => return None,
+      };
+      if x.len() < len {
+        return None;
+      }
+      let (component, rest) = x.split_at(len);
+      out.push(if tag == 0x02 {
+        // Strings are escaped the same way as bytes within a composite key.
+        let mut component = component.to_vec();
+        component[0] = 0x01;
+        match Self::deserialize_from_key_component(&component)? {
+          PrimitiveValue::Bytes(x) => PrimitiveValue::String(String::from_utf8(x).ok()?),
+          _ => unreachable!(),
+        }
+      } else {
+        Self::deserialize_from_key_component(component)?
+      });
+      x = rest;
+    }
+    Some(out)
+  }
+
+  /// Inverse of `serialize_for_key_component`. Returns `None` if `x` is not a single serialized
+  /// value.
+  pub fn deserialize_from_key_component(x: &[u8]) -> Option<Self> {
+    let (&tag, body) = x.split_first()?;
+    match tag {
+      0x01 => {
+        let mut out = Vec::with_capacity(body.len());
+        let mut it = body.iter().copied();
+        loop {
+          match it.next()? {
+            0x00 => match it.next() {
+              Some(0xff) => out.push(0x00),
+              Some(_) => return None,
+              None => return Some(PrimitiveValue::Bytes(out)),
+            },
+            b => out.push(b),
+          }
+        }
+      }
+      0x02 => String::from_utf8(body.to_vec())
+        .ok()
+        .map(PrimitiveValue::String),
+      0x03 if body.len() == 8 => Some(PrimitiveValue::Int64(
+        (BigEndian::read_u64(body) ^ TOP_BIT) as i64,
+      )),
+      0x04 if body.len() == 8 => {
+        let x = BigEndian::read_u64(body);
+        let x = if x & TOP_BIT != 0 { x ^ TOP_BIT } else { !x };
+        Some(PrimitiveValue::Double(x))
+      }
+      _ => None,
+    }
+  }
+
   #[cfg(test)]
   pub fn example_value_for_type(ty: PrimitiveType) -> Self {
     match ty {
@@ -119,3 +209,22 @@ impl PrimitiveValue {
     }
   }
 }
+
+/// `tag`, then `x` with every 0x00 escaped as 0x00 0xff, then a terminating 0x00.
+fn escape_and_terminate(tag: u8, x: &[u8]) -> SmallVec<[u8; 9]> {
+  SmallVec::from_iter(
+    std::iter::once(tag)
+      .chain(
+        x.iter()
+          .map(|&x| -> SmallVec<[u8; 2]> {
+            if x == 0 {
+              smallvec![0x00, 0xff]
+            } else {
+              smallvec![x]
+            }
+          })
+          .flatten(),
+      )
+      .chain([0x00u8].iter().copied()),
+  )
+}
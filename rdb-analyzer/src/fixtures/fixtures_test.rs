@@ -0,0 +1,27 @@
+use crate::data::treewalker::{typeck::GlobalTyckContext, vm::TwVm};
+
+use super::FIXTURES;
+
+#[test]
+fn all_fixtures_typecheck() {
+  let _ = pretty_env_logger::try_init();
+  for fixture in FIXTURES {
+    let (schema, plan) = fixture.compile_schema_with_plan().unwrap();
+    for (name, _) in fixture.scripts {
+      let script = fixture.compile_script(name).unwrap();
+      let vm = TwVm::new(&schema, &plan, &script).unwrap();
+      GlobalTyckContext::new(&vm)
+        .unwrap()
+        .typeck()
+        .unwrap_or_else(|e| panic!("{}/{}: {:?}", fixture.name, name, e));
+    }
+  }
+}
+
+#[test]
+fn lookup() {
+  let fixture = super::get("items").unwrap();
+  assert!(fixture.script("read").is_ok());
+  assert!(fixture.script("missing").is_err());
+  assert!(super::get("missing").is_err());
+}
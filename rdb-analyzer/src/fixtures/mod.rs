@@ -0,0 +1,105 @@
+//! The canonical schemas and scripts used by this crate's own tests.
+//!
+//! Enabled with the `fixtures` feature, so that other tools can be tested against the same
+//! examples.
+
+use anyhow::Result;
+use bumpalo::Bump;
+use thiserror::Error;
+
+use crate::{
+  data::treewalker::{asm::codegen::compile_twscript, bytecode::TwScript},
+  schema::{
+    compile::{compile, CompiledSchema},
+    grammar::parse,
+  },
+  storage_plan::{planner::generate_plan_for_schema, StoragePlan},
+};
+
+#[cfg(test)]
+mod fixtures_test;
+
+#[derive(Error, Debug)]
+pub enum FixtureError {
+  #[error("fixture not found: `{0}`")]
+  FixtureNotFound(String),
+
+  #[error("script not found in fixture `{0}`: `{1}`")]
+  ScriptNotFound(&'static str, String),
+}
+
+/// A schema, together with scripts written against it.
+pub struct Fixture {
+  pub name: &'static str,
+  pub schema: &'static str,
+
+  /// Named scripts in the assembly language. Each has a `main` graph taking the schema root as
+  /// its first parameter.
+  pub scripts: &'static [(&'static str, &'static str)],
+}
+
+pub static FIXTURES: &[Fixture] = &[
+  Fixture {
+    name: "simple",
+    schema: include_str!("simple/schema.rschema"),
+    scripts: &[],
+  },
+  Fixture {
+    name: "items",
+    schema: include_str!("items/schema.rschema"),
+    scripts: &[
+      ("write_item", include_str!("items/write_item.rasm")),
+      (
+        "write_many_items",
+        include_str!("items/write_many_items.rasm"),
+      ),
+      ("read", include_str!("items/read.rasm")),
+    ],
+  },
+  Fixture {
+    name: "indexed",
+    schema: include_str!("indexed/schema.rschema"),
+    scripts: &[
+      ("insert", include_str!("indexed/insert.rasm")),
+      ("update", include_str!("indexed/update.rasm")),
+      (
+        "delete_and_replace",
+        include_str!("indexed/delete_and_replace.rasm"),
+      ),
+    ],
+  },
+];
+
+/// Looks up a fixture by name.
+pub fn get(name: &str) -> Result<&'static Fixture> {
+  FIXTURES
+    .iter()
+    .find(|x| x.name == name)
+    .ok_or_else(|| FixtureError::FixtureNotFound(name.to_string()).into())
+}
+
+impl Fixture {
+  pub fn compile_schema(&self) -> Result<CompiledSchema> {
+    compile(&parse(&Bump::new(), self.schema)?)
+  }
+
+  /// Compiles the schema and generates a fresh storage plan for it.
+  pub fn compile_schema_with_plan(&self) -> Result<(CompiledSchema, StoragePlan)> {
+    let schema = self.compile_schema()?;
+    let plan = generate_plan_for_schema(&Default::default(), &Default::default(), &schema)?;
+    Ok((schema, plan))
+  }
+
+  pub fn script(&self, name: &str) -> Result<&'static str> {
+    self
+      .scripts
+      .iter()
+      .find(|(x, _)| *x == name)
+      .map(|(_, x)| *x)
+      .ok_or_else(|| FixtureError::ScriptNotFound(self.name, name.to_string()).into())
+  }
+
+  pub fn compile_script(&self, name: &str) -> Result<TwScript> {
+    compile_twscript(self.script(name)?)
+  }
+}
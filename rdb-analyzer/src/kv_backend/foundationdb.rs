@@ -1,10 +1,14 @@
 use std::sync::Arc;
 
-use crate::data::kv::{KeyValueStore, KvError, KvKeyIterator, KvTransaction};
+use crate::data::kv::{
+  KeyValueStore, KvError, KvIterator, KvKeyIterator, KvTransaction, ScanOptions,
+};
 use anyhow::Result;
 use async_trait::async_trait;
 use foundationdb::{
-  future::FdbValues, options::TransactionOption, Database, KeySelector, RangeOption, Transaction,
+  future::FdbValues,
+  options::{ConflictRangeType, MutationType, TransactionOption},
+  Database, FdbError, KeySelector, RangeOption, Transaction,
 };
 
 pub struct FdbKvStore {
@@ -15,6 +19,9 @@ pub struct FdbKvStore {
 pub struct FdbTxn {
   inner: Arc<Transaction>,
   prefix: Arc<[u8]>,
+
+  /// Snapshot reads add no read conflict ranges.
+  snapshot: bool,
 }
 
 impl FdbKvStore {
@@ -29,6 +36,16 @@ impl FdbKvStore {
 #[async_trait]
 impl KeyValueStore for FdbKvStore {
   async fn begin_transaction(&self) -> Result<Box<dyn KvTransaction>> {
+    self.begin(false)
+  }
+
+  async fn begin_read_only_transaction(&self) -> Result<Box<dyn KvTransaction>> {
+    self.begin(true)
+  }
+}
+
+impl FdbKvStore {
+  fn begin(&self, snapshot: bool) -> Result<Box<dyn KvTransaction>> {
     let txn = self.db.create_trx()?;
 
     // Required for RefineDB execution semantics
@@ -37,6 +54,7 @@ impl KeyValueStore for FdbKvStore {
     Ok(Box::new(FdbTxn {
       inner: Arc::new(txn),
       prefix: self.prefix.clone(),
+      snapshot,
     }))
   }
 }
@@ -51,7 +69,11 @@ impl KvTransaction for FdbTxn {
       .copied()
       .collect::<Vec<_>>();
     log::trace!("get {}", base64::encode(&k));
-    let res = self.inner.get(&k, false).await?;
+    let res = self
+      .inner
+      .get(&k, self.snapshot)
+      .await
+      .map_err(map_read_error)?;
     Ok(res.map(|x| x.to_vec()))
   }
 
@@ -67,6 +89,17 @@ impl KvTransaction for FdbTxn {
     Ok(())
   }
 
+  async fn put_many(&self, entries: &[(Vec<u8>, Vec<u8>)]) -> Result<()> {
+    let mut k = self.prefix.to_vec();
+    for (key, value) in entries {
+      k.truncate(self.prefix.len());
+      k.extend_from_slice(key);
+      log::trace!("put {} {}", base64::encode(&k), base64::encode(value));
+      self.inner.set(&k, value);
+    }
+    Ok(())
+  }
+
   async fn delete(&self, k: &[u8]) -> Result<()> {
     let k = self
       .prefix
@@ -79,6 +112,20 @@ impl KvTransaction for FdbTxn {
     Ok(())
   }
 
+  async fn atomic_add(&self, k: &[u8], delta: i64) -> Result<()> {
+    let k = self
+      .prefix
+      .iter()
+      .chain(k.iter())
+      .copied()
+      .collect::<Vec<_>>();
+    log::trace!("atomic add {} {}", base64::encode(&k), delta);
+    self
+      .inner
+      .atomic_op(&k, &delta.to_le_bytes(), MutationType::Add);
+    Ok(())
+  }
+
   async fn scan_keys(&self, start: &[u8], end: &[u8]) -> Result<Box<dyn KvKeyIterator>> {
     let start = self
       .prefix
@@ -100,9 +147,61 @@ impl KvTransaction for FdbTxn {
       values: None,
       range,
       iteration: 1,
+      snapshot: self.snapshot,
     }))
   }
 
+  async fn scan(
+    &self,
+    start: &[u8],
+    end: &[u8],
+    options: ScanOptions,
+  ) -> Result<Box<dyn KvIterator>> {
+    let start = self
+      .prefix
+      .iter()
+      .chain(start.iter())
+      .copied()
+      .collect::<Vec<_>>();
+    let end = self
+      .prefix
+      .iter()
+      .chain(end.iter())
+      .copied()
+      .collect::<Vec<_>>();
+
+    let mut range: RangeOption = (start..end).into();
+    range.reverse = options.reverse;
+    Ok(Box::new(FdbKvIterator {
+      txn: self.inner.clone(),
+      prefix: self.prefix.clone(),
+      values: None,
+      range,
+      iteration: 1,
+      snapshot: self.snapshot || options.snapshot,
+      remaining: options.limit,
+    }))
+  }
+
+  async fn add_read_conflict_key(&self, k: &[u8]) -> Result<()> {
+    // Snapshot transactions don't track read conflicts at all.
+    if self.snapshot {
+      return Ok(());
+    }
+    let mut k = self
+      .prefix
+      .iter()
+      .chain(k.iter())
+      .copied()
+      .collect::<Vec<_>>();
+    let begin_len = k.len();
+    k.push(0x00);
+    self
+      .inner
+      .add_conflict_range(&k[..begin_len], &k, ConflictRangeType::Read)?;
+    Ok(())
+  }
+
   async fn delete_range(&self, start: &[u8], end: &[u8]) -> Result<()> {
     let start = self
       .prefix
@@ -151,6 +250,7 @@ pub struct FdbIterator {
   values: Option<(FdbValues, usize)>,
   range: RangeOption<'static>,
   iteration: usize,
+  snapshot: bool,
 }
 
 #[async_trait]
@@ -160,8 +260,9 @@ impl KvKeyIterator for FdbIterator {
       log::trace!("get_range iteration {}", self.iteration);
       let values = self
         .txn
-        .get_range(&self.range, self.iteration, false)
-        .await?;
+        .get_range(&self.range, self.iteration, self.snapshot)
+        .await
+        .map_err(map_read_error)?;
       if values.len() == 0 {
         return Ok(None);
       }
@@ -184,3 +285,71 @@ impl KvKeyIterator for FdbIterator {
     Ok(Some(key))
   }
 }
+
+pub struct FdbKvIterator {
+  txn: Arc<Transaction>,
+  prefix: Arc<[u8]>,
+  values: Option<(FdbValues, usize)>,
+  range: RangeOption<'static>,
+  iteration: usize,
+  snapshot: bool,
+
+  /// Number of entries left to return, if limited.
+  remaining: Option<usize>,
+}
+
+#[async_trait]
+impl KvIterator for FdbKvIterator {
+  async fn next(&mut self) -> Result<Option<(Vec<u8>, Vec<u8>)>> {
+    // A zero limit means no limit to FoundationDB.
+    if self.remaining == Some(0) {
+      return Ok(None);
+    }
+
+    if self.values.is_none() {
+      log::trace!("get_range iteration {}", self.iteration);
+      self.range.limit = self.remaining;
+      let values = self
+        .txn
+        .get_range(&self.range, self.iteration, self.snapshot)
+        .await
+        .map_err(map_read_error)?;
+      if values.len() == 0 {
+        return Ok(None);
+      }
+      self.iteration += 1;
+      self.values = Some((values, 0));
+    }
+
+    let (values, value_index) = self.values.as_mut().unwrap();
+    let raw_key = values[*value_index].key();
+    let key = raw_key.strip_prefix(&*self.prefix).unwrap().to_vec();
+    let value = values[*value_index].value().to_vec();
+    if *value_index + 1 == values.len() {
+      if self.range.reverse {
+        self.range.end = KeySelector::first_greater_or_equal(raw_key.to_vec());
+      } else {
+        self.range.begin = KeySelector::first_greater_than(raw_key.to_vec());
+      }
+      self.values = None;
+    } else {
+      *value_index += 1;
+    }
+    if let Some(x) = &mut self.remaining {
+      *x -= 1;
+    }
+
+    log::trace!("got key: {}", base64::encode(&key));
+
+    Ok(Some((key, value)))
+  }
+}
+
+/// Reads fail with retryable errors such as `transaction_too_old` in long transactions.
+fn map_read_error(e: FdbError) -> anyhow::Error {
+  if e.is_retryable() {
+    KvError::Conflict.into()
+  } else {
+    e.into()
+  }
+}
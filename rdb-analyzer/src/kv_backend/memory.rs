@@ -0,0 +1,407 @@
+use std::{
+  collections::{BTreeMap, VecDeque},
+  sync::{Arc, Mutex},
+  time::{Duration, Instant},
+};
+
+use async_trait::async_trait;
+use rpds::RedBlackTreeMapSync;
+
+use crate::data::kv::{
+  decode_counter, encode_counter, KeyValueStore, KvError, KvIterator, KvKeyIterator, KvTransaction,
+  ScanOptions, VecKvIterator,
+};
+use anyhow::Result;
+
+/// An in-memory store with serializable transactions.
+///
+/// Transactions read from a snapshot taken when they begin. On commit, a transaction that has
+/// written anything fails with `KvError::Conflict` if any key or range it has read was written
+/// by a transaction committed after its snapshot.
+///
+/// If `ttl` is set, a key is evicted once `ttl` has passed since it was last written. Evictions
+/// don't cause conflicts.
+#[derive(Clone)]
+pub struct MemoryKvStore {
+  store: Arc<Mutex<Store>>,
+  prefix: Arc<[u8]>,
+}
+
+struct Store {
+  data: RedBlackTreeMapSync<Vec<u8>, Entry>,
+
+  /// Sequence number of the last commit.
+  seq: u64,
+
+  /// What each commit newer than the oldest open transaction wrote, by sequence number.
+  recent_writes: VecDeque<(u64, Vec<KeyRange>)>,
+
+  /// Number of open transactions by snapshot sequence number.
+  open_snapshots: BTreeMap<u64, usize>,
+
+  ttl: Option<Duration>,
+
+  /// Written keys in the order of their expiry. Only used with `ttl`.
+  expiry_queue: VecDeque<(Instant, Vec<u8>)>,
+}
+
+#[derive(Clone)]
+struct Entry {
+  value: Vec<u8>,
+  expiry: Option<Instant>,
+}
+
+/// A single key if `end` is `None`, or the range `[start, end)`.
+#[derive(Clone, Debug)]
+struct KeyRange {
+  start: Vec<u8>,
+  end: Option<Vec<u8>>,
+}
+
+pub struct MemoryKvTxn {
+  store: Arc<Mutex<Store>>,
+  prefix: Arc<[u8]>,
+  snapshot: RedBlackTreeMapSync<Vec<u8>, Entry>,
+  snapshot_seq: u64,
+  reads: Mutex<Vec<KeyRange>>,
+  log: Mutex<Vec<ModOp>>,
+}
+
+enum ModOp {
+  Put(Vec<u8>, Vec<u8>),
+  Delete(Vec<u8>),
+  DeleteRange(Vec<u8>, Vec<u8>),
+
+  /// Applied to the latest committed value on commit, like FoundationDB's atomic add.
+  Add(Vec<u8>, i64),
+}
+
+struct MemoryKvIterator {
+  keys: std::vec::IntoIter<Vec<u8>>,
+}
+
+impl MemoryKvStore {
+  pub fn new(ttl: Option<Duration>) -> Self {
+    Self {
+      store: Arc::new(Mutex::new(Store {
+        data: RedBlackTreeMapSync::new_sync(),
+        seq: 0,
+        recent_writes: VecDeque::new(),
+        open_snapshots: BTreeMap::new(),
+        ttl,
+        expiry_queue: VecDeque::new(),
+      })),
+      prefix: Arc::from(&[][..]),
+    }
+  }
+
+  /// A view of the same store with all keys prefixed with `prefix`.
+  pub fn with_prefix(&self, prefix: &[u8]) -> Self {
+    Self {
+      store: self.store.clone(),
+      prefix: self
+        .prefix
+        .iter()
+        .copied()
+        .chain(prefix.iter().copied())
+        .collect::<Vec<_>>()
+        .into(),
+    }
+  }
+}
+
+#[async_trait]
+impl KeyValueStore for MemoryKvStore {
+  async fn begin_transaction(&self) -> Result<Box<dyn KvTransaction>> {
+    let mut store = self.store.lock().unwrap();
+    store.evict_expired();
+    let snapshot_seq = store.seq;
+    *store.open_snapshots.entry(snapshot_seq).or_default() += 1;
+    Ok(Box::new(MemoryKvTxn {
+      store: self.store.clone(),
+      prefix: self.prefix.clone(),
+      snapshot: store.data.clone(),
+      snapshot_seq,
+      reads: Mutex::new(vec![]),
+      log: Mutex::new(vec![]),
+    }))
+  }
+}
+
+impl MemoryKvTxn {
+  fn prefixed(&self, key: &[u8]) -> Vec<u8> {
+    self
+      .prefix
+      .iter()
+      .copied()
+      .chain(key.iter().copied())
+      .collect()
+  }
+}
+
+#[async_trait]
+impl KvTransaction for MemoryKvTxn {
+  async fn get(&self, key: &[u8]) -> Result<Option<Vec<u8>>> {
+    let key = self.prefixed(key);
+    let now = Instant::now();
+    let value = self
+      .snapshot
+      .get(&key)
+      .filter(|x| !x.is_expired(now))
+      .map(|x| x.value.clone());
+    self.reads.lock().unwrap().push(KeyRange {
+      start: key,
+      end: None,
+    });
+    Ok(value)
+  }
+
+  async fn put(&self, key: &[u8], value: &[u8]) -> Result<()> {
+    let key = self.prefixed(key);
+    self
+      .log
+      .lock()
+      .unwrap()
+      .push(ModOp::Put(key, value.to_vec()));
+    Ok(())
+  }
+
+  async fn atomic_add(&self, key: &[u8], delta: i64) -> Result<()> {
+    let key = self.prefixed(key);
+    self.log.lock().unwrap().push(ModOp::Add(key, delta));
+    Ok(())
+  }
+
+  async fn delete(&self, key: &[u8]) -> Result<()> {
+    let key = self.prefixed(key);
+    self.log.lock().unwrap().push(ModOp::Delete(key));
+    Ok(())
+  }
+
+  async fn delete_range(&self, start: &[u8], end: &[u8]) -> Result<()> {
+    let start = self.prefixed(start);
+    let end = self.prefixed(end);
+    self
+      .log
+      .lock()
+      .unwrap()
+      .push(ModOp::DeleteRange(start, end));
+    Ok(())
+  }
+
+  async fn scan_keys(&self, start: &[u8], end: &[u8]) -> Result<Box<dyn KvKeyIterator>> {
+    let start = self.prefixed(start);
+    let end = self.prefixed(end);
+    let now = Instant::now();
+    let keys = if start < end {
+      self
+        .snapshot
+        .range(start.clone()..end.clone())
+        .filter(|(_, v)| !v.is_expired(now))
+        .map(|(k, _)| k[self.prefix.len()..].to_vec())
+        .collect::<Vec<_>>()
+    } else {
+      vec![]
+    };
+    self.reads.lock().unwrap().push(KeyRange {
+      start,
+      end: Some(end),
+    });
+    Ok(Box::new(MemoryKvIterator {
+      keys: keys.into_iter(),
+    }))
+  }
+
+  async fn scan(
+    &self,
+    start: &[u8],
+    end: &[u8],
+    options: ScanOptions,
+  ) -> Result<Box<dyn KvIterator>> {
+    let start = self.prefixed(start);
+    let end = self.prefixed(end);
+    let now = Instant::now();
+    let entries = if start < end {
+      let range = self.snapshot.range(start.clone()..end.clone());
+      let range: Box<dyn Iterator<Item = _>> = if options.reverse {
+        Box::new(range.rev())
+      } else {
+        Box::new(range)
+      };
+      range
+        .filter(|(_, v)| !v.is_expired(now))
+        .take(options.limit.unwrap_or(usize::MAX))
+        .map(|(k, v)| (k[self.prefix.len()..].to_vec(), v.value.clone()))
+        .collect::<Vec<_>>()
+    } else {
+      vec![]
+    };
+    if !options.snapshot {
+      self.reads.lock().unwrap().push(KeyRange {
+        start,
+        end: Some(end),
+      });
+    }
+    Ok(Box::new(VecKvIterator::new(entries)))
+  }
+
+  async fn add_read_conflict_key(&self, key: &[u8]) -> Result<()> {
+    let key = self.prefixed(key);
+    self.reads.lock().unwrap().push(KeyRange {
+      start: key,
+      end: None,
+    });
+    Ok(())
+  }
+
+  async fn commit(self: Box<Self>) -> Result<(), KvError> {
+    let log = std::mem::replace(&mut *self.log.lock().unwrap(), vec![]);
+    if log.is_empty() {
+      return Ok(());
+    }
+
+    let mut store = self.store.lock().unwrap();
+    let reads = self.reads.lock().unwrap();
+    for (seq, writes) in &store.recent_writes {
+      if *seq <= self.snapshot_seq {
+        continue;
+      }
+      if writes.iter().any(|w| reads.iter().any(|r| w.overlaps(r))) {
+        return Err(KvError::Conflict);
+      }
+    }
+
+    let now = Instant::now();
+    let expiry = store.ttl.map(|x| now + x);
+    let mut writes = Vec::with_capacity(log.len());
+    for op in log {
+      match op {
+        ModOp::Put(key, value) => {
+          if let Some(expiry) = expiry {
+            store.expiry_queue.push_back((expiry, key.clone()));
+          }
+          store.data.insert_mut(key.clone(), Entry { value, expiry });
+          writes.push(KeyRange {
+            start: key,
+            end: None,
+          });
+        }
+        ModOp::Add(key, delta) => {
+          let current = store
+            .data
+            .get(&key)
+            .filter(|x| !x.is_expired(now))
+            .map(|x| decode_counter(&x.value))
+            .unwrap_or(0);
+          if let Some(expiry) = expiry {
+            store.expiry_queue.push_back((expiry, key.clone()));
+          }
+          store.data.insert_mut(
+            key.clone(),
+            Entry {
+              value: encode_counter(current.wrapping_add(delta)).to_vec(),
+              expiry,
+            },
+          );
+          writes.push(KeyRange {
+            start: key,
+            end: None,
+          });
+        }
+        ModOp::Delete(key) => {
+          store.data.remove_mut(&key);
+          writes.push(KeyRange {
+            start: key,
+            end: None,
+          });
+        }
+        ModOp::DeleteRange(start, end) => {
+          if start < end {
+            let keys = store
+              .data
+              .range(start.clone()..end.clone())
+              .map(|(k, _)| k.clone())
+              .collect::<Vec<_>>();
+            for key in keys {
+              store.data.remove_mut(&key);
+            }
+          }
+          writes.push(KeyRange {
+            start,
+            end: Some(end),
+          });
+        }
+      }
+    }
+    store.seq += 1;
+    let seq = store.seq;
+    store.recent_writes.push_back((seq, writes));
+    Ok(())
+  }
+}
+
+impl Drop for MemoryKvTxn {
+  fn drop(&mut self) {
+    let mut store = self.store.lock().unwrap();
+    let count = store.open_snapshots.get_mut(&self.snapshot_seq).unwrap();
+    *count -= 1;
+    if *count == 0 {
+      store.open_snapshots.remove(&self.snapshot_seq);
+    }
+
+    // Commits that every open transaction has already seen can't cause conflicts any more.
+    let oldest = store
+      .open_snapshots
+      .keys()
+      .next()
+      .copied()
+      .unwrap_or(store.seq);
+    while let Some((seq, _)) = store.recent_writes.front() {
+      if *seq > oldest {
+        break;
+      }
+      store.recent_writes.pop_front();
+    }
+  }
+}
+
+impl Store {
+  fn evict_expired(&mut self) {
+    let now = Instant::now();
+    while let Some((expiry, _)) = self.expiry_queue.front() {
+      if *expiry > now {
+        break;
+      }
+      let (expiry, key) = self.expiry_queue.pop_front().unwrap();
+
+      // The key may have been written again since.
+      if self.data.get(&key).and_then(|x| x.expiry) == Some(expiry) {
+        self.data.remove_mut(&key);
+      }
+    }
+  }
+}
+
+impl Entry {
+  fn is_expired(&self, now: Instant) -> bool {
+    self.expiry.map(|x| x <= now).unwrap_or(false)
+  }
+}
+
+impl KeyRange {
+  fn overlaps(&self, other: &KeyRange) -> bool {
+    match (&self.end, &other.end) {
+      (None, None) => self.start == other.start,
+      (None, Some(end)) => self.start >= other.start && self.start < *end,
+      (Some(end), None) => other.start >= self.start && other.start < *end,
+      (Some(end), Some(other_end)) => self.start < *other_end && other.start < *end,
+    }
+  }
+}
+
+#[async_trait]
+impl KvKeyIterator for MemoryKvIterator {
+  async fn next(&mut self) -> Result<Option<Vec<u8>>> {
+    Ok(self.keys.next())
+  }
+}
@@ -0,0 +1,212 @@
+use std::time::Duration;
+
+use crate::data::kv::{decode_counter, KeyValueStore, KvError, KvKeyIterator, ScanOptions};
+
+use super::memory::MemoryKvStore;
+
+async fn collect_keys(mut it: Box<dyn KvKeyIterator>) -> Vec<Vec<u8>> {
+  let mut keys = vec![];
+  while let Some(k) = it.next().await.unwrap() {
+    keys.push(k);
+  }
+  keys
+}
+
+#[tokio::test]
+async fn basic_ops() {
+  let kv = MemoryKvStore::new(None);
+  let txn = kv.begin_transaction().await.unwrap();
+  txn.put(b"a", b"1").await.unwrap();
+  txn.put(b"b", b"2").await.unwrap();
+  txn.put(b"c", b"3").await.unwrap();
+  txn.commit().await.unwrap();
+
+  let txn = kv.begin_transaction().await.unwrap();
+  assert_eq!(txn.get(b"a").await.unwrap(), Some(b"1".to_vec()));
+  assert_eq!(
+    collect_keys(txn.scan_keys(b"b", b"d").await.unwrap()).await,
+    vec![b"b".to_vec(), b"c".to_vec()]
+  );
+  txn.delete(b"a").await.unwrap();
+  txn.delete_range(b"b", b"c").await.unwrap();
+  txn.commit().await.unwrap();
+
+  let txn = kv.begin_transaction().await.unwrap();
+  assert_eq!(
+    collect_keys(txn.scan_keys(b"", b"\xff").await.unwrap()).await,
+    vec![b"c".to_vec()]
+  );
+}
+
+#[tokio::test]
+async fn scan_entries() {
+  let kv = MemoryKvStore::new(None).with_prefix(b"p");
+  let txn = kv.begin_transaction().await.unwrap();
+  txn.put(b"a", b"1").await.unwrap();
+  txn.put(b"b", b"2").await.unwrap();
+  txn.put(b"c", b"3").await.unwrap();
+  txn.commit().await.unwrap();
+
+  let txn = kv.begin_transaction().await.unwrap();
+  let mut it = txn
+    .scan(
+      b"a",
+      b"c",
+      ScanOptions {
+        reverse: true,
+        limit: Some(5),
+        snapshot: false,
+      },
+    )
+    .await
+    .unwrap();
+  assert_eq!(
+    it.next().await.unwrap(),
+    Some((b"b".to_vec(), b"2".to_vec()))
+  );
+  assert_eq!(
+    it.next().await.unwrap(),
+    Some((b"a".to_vec(), b"1".to_vec()))
+  );
+  assert_eq!(it.next().await.unwrap(), None);
+
+  // The scanned range conflicts with keys written into it.
+  let other = kv.begin_transaction().await.unwrap();
+  other.put(b"ab", b"x").await.unwrap();
+  other.commit().await.unwrap();
+  txn.put(b"z", b"y").await.unwrap();
+  assert!(matches!(txn.commit().await, Err(KvError::Conflict)));
+}
+
+#[tokio::test]
+async fn snapshot_scan_conflicts() {
+  let kv = MemoryKvStore::new(None);
+  let txn = kv.begin_transaction().await.unwrap();
+  txn.put(b"a", b"1").await.unwrap();
+  txn.put(b"b", b"2").await.unwrap();
+  txn.commit().await.unwrap();
+
+  let snapshot = ScanOptions {
+    snapshot: true,
+    ..Default::default()
+  };
+
+  // Keys inserted into a snapshot scan don't conflict.
+  let t1 = kv.begin_transaction().await.unwrap();
+  let t2 = kv.begin_transaction().await.unwrap();
+  t1.scan(b"a", b"c", snapshot).await.unwrap();
+  t1.add_read_conflict_key(b"a").await.unwrap();
+  t1.put(b"z", b"1").await.unwrap();
+  t2.put(b"ab", b"1").await.unwrap();
+  t2.commit().await.unwrap();
+  t1.commit().await.unwrap();
+
+  // Writes to its conflict keys do.
+  let t1 = kv.begin_transaction().await.unwrap();
+  let t2 = kv.begin_transaction().await.unwrap();
+  t1.scan(b"a", b"c", snapshot).await.unwrap();
+  t1.add_read_conflict_key(b"a").await.unwrap();
+  t1.put(b"z", b"2").await.unwrap();
+  t2.put(b"a", b"3").await.unwrap();
+  t2.commit().await.unwrap();
+  assert!(matches!(t1.commit().await, Err(KvError::Conflict)));
+}
+
+#[tokio::test]
+async fn prefixes_are_isolated() {
+  let kv = MemoryKvStore::new(None);
+  let a = kv.with_prefix(b"a");
+  let b = kv.with_prefix(b"b");
+
+  let txn = a.begin_transaction().await.unwrap();
+  txn.put(b"x", b"1").await.unwrap();
+  txn.commit().await.unwrap();
+
+  let txn = b.begin_transaction().await.unwrap();
+  assert_eq!(txn.get(b"x").await.unwrap(), None);
+  assert!(collect_keys(txn.scan_keys(b"", b"\xff").await.unwrap())
+    .await
+    .is_empty());
+
+  let txn = a.begin_transaction().await.unwrap();
+  assert_eq!(
+    collect_keys(txn.scan_keys(b"", b"\xff").await.unwrap()).await,
+    vec![b"x".to_vec()]
+  );
+}
+
+#[tokio::test]
+async fn conflicts() {
+  let kv = MemoryKvStore::new(None);
+
+  // Read-write conflict on a single key.
+  let t1 = kv.begin_transaction().await.unwrap();
+  let t2 = kv.begin_transaction().await.unwrap();
+  t1.get(b"k").await.unwrap();
+  t1.put(b"k", b"1").await.unwrap();
+  t2.get(b"k").await.unwrap();
+  t2.put(b"k", b"2").await.unwrap();
+  t1.commit().await.unwrap();
+  assert!(matches!(t2.commit().await, Err(KvError::Conflict)));
+
+  // A key inserted into a scanned range.
+  let t1 = kv.begin_transaction().await.unwrap();
+  let t2 = kv.begin_transaction().await.unwrap();
+  t1.scan_keys(b"r", b"s").await.unwrap();
+  t1.put(b"other", b"1").await.unwrap();
+  t2.put(b"r1", b"1").await.unwrap();
+  t2.commit().await.unwrap();
+  assert!(matches!(t1.commit().await, Err(KvError::Conflict)));
+
+  // Disjoint reads and writes don't conflict.
+  let t1 = kv.begin_transaction().await.unwrap();
+  let t2 = kv.begin_transaction().await.unwrap();
+  t1.get(b"x").await.unwrap();
+  t1.put(b"x", b"1").await.unwrap();
+  t2.get(b"y").await.unwrap();
+  t2.put(b"y", b"1").await.unwrap();
+  t1.commit().await.unwrap();
+  t2.commit().await.unwrap();
+
+  // Transactions without writes always commit.
+  let t1 = kv.begin_transaction().await.unwrap();
+  let t2 = kv.begin_transaction().await.unwrap();
+  t1.get(b"x").await.unwrap();
+  t2.put(b"x", b"2").await.unwrap();
+  t2.commit().await.unwrap();
+  t1.commit().await.unwrap();
+}
+
+#[tokio::test]
+async fn atomic_adds_dont_conflict() {
+  let kv = MemoryKvStore::new(None);
+  let a = kv.begin_transaction().await.unwrap();
+  let b = kv.begin_transaction().await.unwrap();
+  a.atomic_add(b"n", 1).await.unwrap();
+  a.atomic_add(b"n", 2).await.unwrap();
+  b.atomic_add(b"n", 10).await.unwrap();
+  a.commit().await.unwrap();
+  b.commit().await.unwrap();
+
+  let txn = kv.begin_transaction().await.unwrap();
+  assert_eq!(decode_counter(&txn.get(b"n").await.unwrap().unwrap()), 13);
+}
+
+#[tokio::test]
+async fn ttl_eviction() {
+  let kv = MemoryKvStore::new(Some(Duration::from_millis(50)));
+  let txn = kv.begin_transaction().await.unwrap();
+  txn.put(b"a", b"1").await.unwrap();
+  txn.commit().await.unwrap();
+
+  let txn = kv.begin_transaction().await.unwrap();
+  assert_eq!(txn.get(b"a").await.unwrap(), Some(b"1".to_vec()));
+  drop(txn);
+
+  tokio::time::sleep(Duration::from_millis(100)).await;
+  let txn = kv.begin_transaction().await.unwrap();
+  assert_eq!(txn.get(b"a").await.unwrap(), None);
+  assert!(collect_keys(txn.scan_keys(b"", b"\xff").await.unwrap())
+    .await
+    .is_empty());
+}
@@ -10,7 +10,10 @@ use async_trait::async_trait;
 use rpds::RedBlackTreeMapSync;
 use tokio::sync::Mutex;
 
-use crate::data::kv::{KeyValueStore, KvError, KvKeyIterator, KvTransaction};
+use crate::data::kv::{
+  decode_counter, encode_counter, KeyValueStore, KvError, KvIterator, KvKeyIterator, KvTransaction,
+  ScanOptions, VecKvIterator,
+};
 use anyhow::Result;
 
 /// A mocked KV store that simulates MVCC with snapshot isolation.
@@ -105,6 +108,31 @@ impl KvTransaction for MockTransaction {
     Ok(())
   }
 
+  async fn atomic_add(&self, key: &[u8], delta: i64) -> Result<()> {
+    log::trace!(
+      "[txn {}] atomic_add {} {}",
+      self.id,
+      base64::encode(key),
+      delta
+    );
+    // Applied to the write buffer, which unlike `get` sees the writes of this transaction.
+    let mut buffer = self.buffer.lock().await;
+    let mut modified = self.modified.lock().await;
+    let (value, version) = buffer.get(key).cloned().unwrap_or_default();
+    let current = value.map(|x| decode_counter(&x)).unwrap_or(0);
+    buffer.insert_mut(
+      key.to_vec(),
+      (
+        Some(encode_counter(current.wrapping_add(delta)).to_vec()),
+        version + 1,
+      ),
+    );
+    if !modified.contains_key(key) {
+      modified.insert(key.to_vec(), version);
+    }
+    Ok(())
+  }
+
   async fn scan_keys(&self, start: &[u8], end: &[u8]) -> Result<Box<dyn KvKeyIterator>> {
     Ok(Box::new(MockIterator {
       map: self.buffer.lock().await.clone(),
@@ -113,6 +141,30 @@ impl KvTransaction for MockTransaction {
     }))
   }
 
+  // Reads from the snapshot like `get`, so the writes of this transaction are not seen.
+  async fn scan(
+    &self,
+    start: &[u8],
+    end: &[u8],
+    options: ScanOptions,
+  ) -> Result<Box<dyn KvIterator>> {
+    let entries = if start < end {
+      let range = self.read_buffer.range(start.to_vec()..end.to_vec());
+      let range: Box<dyn Iterator<Item = _>> = if options.reverse {
+        Box::new(range.rev())
+      } else {
+        Box::new(range)
+      };
+      range
+        .filter_map(|(k, v)| v.0.as_ref().map(|v| (k.clone(), v.clone())))
+        .take(options.limit.unwrap_or(usize::MAX))
+        .collect::<Vec<_>>()
+    } else {
+      vec![]
+    };
+    Ok(Box::new(VecKvIterator::new(entries)))
+  }
+
   async fn commit(self: Box<Self>) -> Result<(), KvError> {
     let buffer = self.buffer.into_inner();
     let modified = self.modified.into_inner();
@@ -4,5 +4,16 @@ pub mod foundationdb;
 #[cfg(feature = "sqlite-backend")]
 pub mod sqlite;
 
+#[cfg(feature = "rocksdb-backend")]
+pub mod rocksdb;
+
+pub mod memory;
+
 #[cfg(test)]
 pub mod mock_kv;
+
+#[cfg(test)]
+pub mod sim_kv;
+
+#[cfg(test)]
+mod memory_test;
@@ -0,0 +1,270 @@
+use std::sync::{Arc, Mutex};
+
+use crate::data::kv::{
+  decode_counter, encode_counter, KeyValueStore, KvError, KvIterator, KvKeyIterator, KvTransaction,
+  ScanOptions, VecKvIterator,
+};
+use anyhow::Result;
+use async_trait::async_trait;
+use rocksdb::{
+  ErrorKind, OptimisticTransactionDB, OptimisticTransactionOptions, Options, Transaction,
+  WriteOptions,
+};
+
+/// A store backed by an embedded RocksDB database, using optimistic transactions.
+///
+/// Reads of individual keys are checked for conflicts on commit. Scanned ranges are not, so a
+/// key inserted concurrently into a range that a transaction has scanned does not make the
+/// transaction fail.
+pub struct RocksdbKvStore {
+  db: Arc<OptimisticTransactionDB>,
+  prefix: Arc<[u8]>,
+}
+
+pub struct RocksdbKvTxn {
+  // Declared before `_db` so that it is dropped first.
+  inner: Mutex<Option<Transaction<'static, OptimisticTransactionDB>>>,
+  log: Mutex<Vec<ModOp>>,
+  prefix: Arc<[u8]>,
+  _db: Arc<OptimisticTransactionDB>,
+}
+
+enum ModOp {
+  Put(Vec<u8>, Vec<u8>),
+  Delete(Vec<u8>),
+  DeleteRange(Vec<u8>, Vec<u8>),
+  Add(Vec<u8>, i64),
+}
+
+/// Opens or creates the database at `path`.
+pub fn open_db(path: &str) -> Result<Arc<OptimisticTransactionDB>> {
+  let mut opts = Options::default();
+  opts.create_if_missing(true);
+  Ok(Arc::new(OptimisticTransactionDB::open(&opts, path)?))
+}
+
+impl RocksdbKvStore {
+  pub fn new(db: Arc<OptimisticTransactionDB>, prefix: &[u8]) -> Self {
+    Self {
+      db,
+      prefix: Arc::from(prefix),
+    }
+  }
+}
+
+#[async_trait]
+impl KeyValueStore for RocksdbKvStore {
+  async fn begin_transaction(&self) -> Result<Box<dyn KvTransaction>> {
+    // Conflicts are checked against the snapshot taken here, instead of against the first read
+    // of each key.
+    let mut txn_opts = OptimisticTransactionOptions::default();
+    txn_opts.set_snapshot(true);
+    let txn = self.db.transaction_opt(&WriteOptions::default(), &txn_opts);
+
+    // Safe because `RocksdbKvTxn` keeps the database alive and drops the transaction first.
+    let txn = unsafe {
+      std::mem::transmute::<
+        Transaction<'_, OptimisticTransactionDB>,
+        Transaction<'static, OptimisticTransactionDB>,
+      >(txn)
+    };
+    Ok(Box::new(RocksdbKvTxn {
+      inner: Mutex::new(Some(txn)),
+      log: Mutex::new(vec![]),
+      prefix: self.prefix.clone(),
+      _db: self.db.clone(),
+    }))
+  }
+}
+
+impl RocksdbKvTxn {
+  fn prefixed(&self, key: &[u8]) -> Vec<u8> {
+    self
+      .prefix
+      .iter()
+      .copied()
+      .chain(key.iter().copied())
+      .collect()
+  }
+}
+
+#[async_trait]
+impl KvTransaction for RocksdbKvTxn {
+  async fn get(&self, key: &[u8]) -> Result<Option<Vec<u8>>> {
+    let key = self.prefixed(key);
+    let inner = self.inner.lock().unwrap();
+
+    // Writes are only applied on commit, so they are not seen here. Same as the FoundationDB and
+    // SQLite backends.
+    inner
+      .as_ref()
+      .unwrap()
+      .get_for_update(&key, true)
+      .map_err(map_error)
+  }
+
+  async fn put(&self, key: &[u8], value: &[u8]) -> Result<()> {
+    let key = self.prefixed(key);
+    self
+      .log
+      .lock()
+      .unwrap()
+      .push(ModOp::Put(key, value.to_vec()));
+    Ok(())
+  }
+
+  async fn delete(&self, key: &[u8]) -> Result<()> {
+    let key = self.prefixed(key);
+    self.log.lock().unwrap().push(ModOp::Delete(key));
+    Ok(())
+  }
+
+  async fn delete_range(&self, start: &[u8], end: &[u8]) -> Result<()> {
+    let start = self.prefixed(start);
+    let end = self.prefixed(end);
+    self
+      .log
+      .lock()
+      .unwrap()
+      .push(ModOp::DeleteRange(start, end));
+    Ok(())
+  }
+
+  // Applied at commit, after the writes before it. Reads don't see writes of the same
+  // transaction, so reading the counter here would lose earlier adds.
+  async fn atomic_add(&self, key: &[u8], delta: i64) -> Result<()> {
+    let key = self.prefixed(key);
+    self.log.lock().unwrap().push(ModOp::Add(key, delta));
+    Ok(())
+  }
+
+  async fn scan_keys(&self, start: &[u8], end: &[u8]) -> Result<Box<dyn KvKeyIterator>> {
+    let start = self.prefixed(start);
+    let end = self.prefixed(end);
+    let inner = self.inner.lock().unwrap();
+    let keys = scan_range(inner.as_ref().unwrap(), &start, &end)?
+      .into_iter()
+      .map(|x| x[self.prefix.len()..].to_vec())
+      .collect::<Vec<_>>();
+    Ok(Box::new(RocksdbKvIterator {
+      keys: keys.into_iter(),
+    }))
+  }
+
+  // Like `scan_keys`, the scanned range is not checked for conflicts, so `options.snapshot` has
+  // nothing to turn off.
+  async fn scan(
+    &self,
+    start: &[u8],
+    end: &[u8],
+    options: ScanOptions,
+  ) -> Result<Box<dyn KvIterator>> {
+    let start = self.prefixed(start);
+    let end = self.prefixed(end);
+    let inner = self.inner.lock().unwrap();
+    let limit = options.limit.unwrap_or(usize::MAX);
+    let mut entries = vec![];
+    let mut it = inner.as_ref().unwrap().raw_iterator();
+    if options.reverse {
+      it.seek_for_prev(&end);
+    } else {
+      it.seek(&start);
+    }
+    while entries.len() < limit {
+      let (key, value) = match (it.key(), it.value()) {
+        (Some(k), Some(v)) => (k, v),
+        _ => break,
+      };
+      if options.reverse {
+        if key < start.as_slice() {
+          break;
+        }
+        // `seek_for_prev` stops at `end` itself if it is present.
+        if key < end.as_slice() {
+          entries.push((key[self.prefix.len()..].to_vec(), value.to_vec()));
+        }
+        it.prev();
+      } else {
+        if key >= end.as_slice() {
+          break;
+        }
+        entries.push((key[self.prefix.len()..].to_vec(), value.to_vec()));
+        it.next();
+      }
+    }
+    it.status().map_err(map_error)?;
+    Ok(Box::new(VecKvIterator::new(entries)))
+  }
+
+  async fn commit(self: Box<Self>) -> Result<(), KvError> {
+    let txn = self.inner.lock().unwrap().take().unwrap();
+    let log = std::mem::replace(&mut *self.log.lock().unwrap(), vec![]);
+    let res = (|| -> Result<()> {
+      for op in log {
+        match op {
+          ModOp::Put(key, value) => txn.put(&key, &value).map_err(map_error)?,
+          ModOp::Delete(key) => txn.delete(&key).map_err(map_error)?,
+          ModOp::DeleteRange(start, end) => {
+            for key in scan_range(&txn, &start, &end)? {
+              txn.delete(&key).map_err(map_error)?;
+            }
+          }
+          ModOp::Add(key, delta) => {
+            let current = txn.get_for_update(&key, true).map_err(map_error)?;
+            let value = current
+              .map(|x| decode_counter(&x))
+              .unwrap_or(0)
+              .wrapping_add(delta);
+            txn.put(&key, &encode_counter(value)).map_err(map_error)?;
+          }
+        }
+      }
+      txn.commit().map_err(map_error)
+    })();
+    res.map_err(|e| {
+      if let Some(KvError::Conflict) = e.downcast_ref::<KvError>() {
+        return KvError::Conflict;
+      }
+      log::error!("rocksdb commit error: {:?}", e);
+      KvError::CommitStateUnknown
+    })
+  }
+}
+
+/// Returns the keys in `[start, end)`, in order.
+fn scan_range(
+  txn: &Transaction<'_, OptimisticTransactionDB>,
+  start: &[u8],
+  end: &[u8],
+) -> Result<Vec<Vec<u8>>> {
+  let mut keys = vec![];
+  let mut it = txn.raw_iterator();
+  it.seek(start);
+  while let Some(key) = it.key() {
+    if key >= end {
+      break;
+    }
+    keys.push(key.to_vec());
+    it.next();
+  }
+  it.status().map_err(map_error)?;
+  Ok(keys)
+}
+
+fn map_error(e: rocksdb::Error) -> anyhow::Error {
+  match e.kind() {
+    ErrorKind::Busy | ErrorKind::TryAgain => KvError::Conflict.into(),
+    _ => e.into(),
+  }
+}
+
+pub struct RocksdbKvIterator {
+  keys: std::vec::IntoIter<Vec<u8>>,
+}
+
+#[async_trait]
+impl KvKeyIterator for RocksdbKvIterator {
+  async fn next(&mut self) -> Result<Option<Vec<u8>>> {
+    Ok(self.keys.next())
+  }
+}
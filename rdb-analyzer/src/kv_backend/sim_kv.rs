@@ -0,0 +1,290 @@
+//! A KV store for deterministic simulation tests.
+//!
+//! Transactions are serializable. Reads go to a snapshot taken when the transaction begins, and a
+//! commit fails with a conflict if a key or range it read has been written since. As in the
+//! SQLite and FoundationDB backends, writes are buffered and only applied on commit.
+//!
+//! Faults are drawn from a single seeded RNG: conflicts on reads, scans that fail halfway, commits
+//! that fail with a conflict or end in an unknown state, and random yields to the scheduler that
+//! interleave concurrent transactions. Runs with the same seed are only reproducible if all
+//! transactions are driven from a single thread, e.g. by `join_all` in a current-thread runtime.
+
+use std::{
+  collections::VecDeque,
+  sync::{Arc, Mutex},
+};
+
+use anyhow::Result;
+use async_trait::async_trait;
+use rand::{rngs::StdRng, Rng, SeedableRng};
+use rpds::RedBlackTreeMapSync;
+
+use crate::data::kv::{KeyValueStore, KvError, KvKeyIterator, KvTransaction};
+
+/// Probabilities of the faults injected by `SimKv`.
+#[derive(Clone, Debug, Default)]
+pub struct FaultConfig {
+  /// A `get` or `scan_keys` fails with `KvError::Conflict`.
+  pub read_conflict: f64,
+
+  /// A call to `next` on a scan iterator fails with `KvError::Conflict`.
+  pub partial_scan: f64,
+
+  /// A commit fails with `KvError::Conflict` without applying anything.
+  pub commit_conflict: f64,
+
+  /// A commit fails with `KvError::CommitStateUnknown`. The writes are applied or not, at random.
+  pub commit_unknown: f64,
+
+  /// Each operation yields to the scheduler up to this many times before it runs.
+  pub max_yields: u32,
+}
+
+/// A value, or `None` for a deleted key, and the version of the commit that wrote it.
+type Versioned = (Option<Vec<u8>>, u64);
+
+type VersionedMap = RedBlackTreeMapSync<Vec<u8>, Versioned>;
+
+pub struct SimKv {
+  state: Arc<SimState>,
+  faults: FaultConfig,
+}
+
+struct SimState {
+  store: Mutex<SimStore>,
+  rng: Mutex<StdRng>,
+}
+
+/// Committed data. Deleted keys are kept as tombstones, so that commits can detect deletions of
+/// keys read by the transaction.
+struct SimStore {
+  data: VersionedMap,
+  version: u64,
+}
+
+pub struct SimTransaction {
+  state: Arc<SimState>,
+  faults: FaultConfig,
+  snapshot: VersionedMap,
+  read_version: u64,
+
+  /// Key ranges read by the transaction, checked for conflicts on commit.
+  reads: Mutex<Vec<(Vec<u8>, Vec<u8>)>>,
+  writes: Mutex<Vec<SimWrite>>,
+}
+
+enum SimWrite {
+  Put(Vec<u8>, Vec<u8>),
+  Delete(Vec<u8>),
+  DeleteRange(Vec<u8>, Vec<u8>),
+}
+
+struct SimIterator {
+  state: Arc<SimState>,
+  partial_scan: f64,
+  keys: VecDeque<Vec<u8>>,
+}
+
+impl SimKv {
+  pub fn new(seed: u64, faults: FaultConfig) -> Self {
+    SimKv {
+      state: Arc::new(SimState {
+        store: Mutex::new(SimStore {
+          data: RedBlackTreeMapSync::new_sync(),
+          version: 0,
+        }),
+        rng: Mutex::new(StdRng::seed_from_u64(seed)),
+      }),
+      faults,
+    }
+  }
+
+  /// A view of the same data that never injects faults, e.g. for checking invariants after a
+  /// run.
+  pub fn fault_free(&self) -> SimKv {
+    SimKv {
+      state: self.state.clone(),
+      faults: FaultConfig::default(),
+    }
+  }
+
+  /// All committed key-value pairs, in key order.
+  pub fn dump(&self) -> Vec<(Vec<u8>, Vec<u8>)> {
+    self
+      .state
+      .store
+      .lock()
+      .unwrap()
+      .data
+      .iter()
+      .filter_map(|(k, (v, _))| v.as_ref().map(|v| (k.clone(), v.clone())))
+      .collect()
+  }
+}
+
+impl SimState {
+  fn fault(&self, probability: f64) -> bool {
+    probability > 0.0 && self.rng.lock().unwrap().gen_bool(probability)
+  }
+
+  async fn delay(&self, max_yields: u32) {
+    if max_yields == 0 {
+      return;
+    }
+    let n = self.rng.lock().unwrap().gen_range(0..=max_yields);
+    for _ in 0..n {
+      tokio::task::yield_now().await;
+    }
+  }
+}
+
+#[async_trait]
+impl KeyValueStore for SimKv {
+  async fn begin_transaction(&self) -> Result<Box<dyn KvTransaction>> {
+    self.state.delay(self.faults.max_yields).await;
+    let store = self.state.store.lock().unwrap();
+    Ok(Box::new(SimTransaction {
+      state: self.state.clone(),
+      faults: self.faults.clone(),
+      snapshot: store.data.clone(),
+      read_version: store.version,
+      reads: Mutex::new(vec![]),
+      writes: Mutex::new(vec![]),
+    }))
+  }
+}
+
+impl SimTransaction {
+  async fn before_read(&self) -> Result<()> {
+    self.state.delay(self.faults.max_yields).await;
+    if self.state.fault(self.faults.read_conflict) {
+      return Err(KvError::Conflict.into());
+    }
+    Ok(())
+  }
+}
+
+#[async_trait]
+impl KvTransaction for SimTransaction {
+  async fn get(&self, key: &[u8]) -> Result<Option<Vec<u8>>> {
+    self.before_read().await?;
+    let end = key.iter().copied().chain(std::iter::once(0x00u8)).collect();
+    self.reads.lock().unwrap().push((key.to_vec(), end));
+    Ok(self.snapshot.get(key).and_then(|x| x.0.clone()))
+  }
+
+  async fn put(&self, key: &[u8], value: &[u8]) -> Result<()> {
+    self
+      .writes
+      .lock()
+      .unwrap()
+      .push(SimWrite::Put(key.to_vec(), value.to_vec()));
+    Ok(())
+  }
+
+  async fn delete(&self, key: &[u8]) -> Result<()> {
+    self
+      .writes
+      .lock()
+      .unwrap()
+      .push(SimWrite::Delete(key.to_vec()));
+    Ok(())
+  }
+
+  async fn delete_range(&self, start: &[u8], end: &[u8]) -> Result<()> {
+    self
+      .writes
+      .lock()
+      .unwrap()
+      .push(SimWrite::DeleteRange(start.to_vec(), end.to_vec()));
+    Ok(())
+  }
+
+  async fn scan_keys(&self, start: &[u8], end: &[u8]) -> Result<Box<dyn KvKeyIterator>> {
+    self.before_read().await?;
+    self
+      .reads
+      .lock()
+      .unwrap()
+      .push((start.to_vec(), end.to_vec()));
+    let keys = range(&self.snapshot, start, end)
+      .filter(|(_, (v, _))| v.is_some())
+      .map(|(k, _)| k.clone())
+      .collect();
+    Ok(Box::new(SimIterator {
+      state: self.state.clone(),
+      partial_scan: self.faults.partial_scan,
+      keys,
+    }))
+  }
+
+  async fn commit(self: Box<Self>) -> Result<(), KvError> {
+    self.state.delay(self.faults.max_yields).await;
+    let read_version = self.read_version;
+    let writes = self.writes.into_inner().unwrap();
+    let reads = self.reads.into_inner().unwrap();
+    if writes.is_empty() {
+      return Ok(());
+    }
+    if self.state.fault(self.faults.commit_conflict) {
+      return Err(KvError::Conflict);
+    }
+
+    let mut store = self.state.store.lock().unwrap();
+    for (start, end) in &reads {
+      if range(&store.data, start, end).any(|(_, (_, version))| *version > read_version) {
+        return Err(KvError::Conflict);
+      }
+    }
+
+    let unknown = self.state.fault(self.faults.commit_unknown);
+    if unknown && self.state.fault(0.5) {
+      return Err(KvError::CommitStateUnknown);
+    }
+
+    store.version += 1;
+    let version = store.version;
+    for write in writes {
+      match write {
+        SimWrite::Put(k, v) => store.data.insert_mut(k, (Some(v), version)),
+        SimWrite::Delete(k) => store.data.insert_mut(k, (None, version)),
+        SimWrite::DeleteRange(start, end) => {
+          let keys = range(&store.data, &start, &end)
+            .filter(|(_, (v, _))| v.is_some())
+            .map(|(k, _)| k.clone())
+            .collect::<Vec<_>>();
+          for k in keys {
+            store.data.insert_mut(k, (None, version));
+          }
+        }
+      }
+    }
+
+    if unknown {
+      Err(KvError::CommitStateUnknown)
+    } else {
+      Ok(())
+    }
+  }
+}
+
+#[async_trait]
+impl KvKeyIterator for SimIterator {
+  async fn next(&mut self) -> Result<Option<Vec<u8>>> {
+    if !self.keys.is_empty() && self.state.fault(self.partial_scan) {
+      return Err(KvError::Conflict.into());
+    }
+    Ok(self.keys.pop_front())
+  }
+}
+
+fn range<'a>(
+  map: &'a VersionedMap,
+  start: &[u8],
+  end: &[u8],
+) -> Box<dyn Iterator<Item = (&'a Vec<u8>, &'a Versioned)> + 'a> {
+  if start >= end {
+    return Box::new(std::iter::empty());
+  }
+  Box::new(map.range(start.to_vec()..end.to_vec()))
+}
@@ -1,11 +1,14 @@
 use std::{pin::Pin, sync::Arc};
 
-use crate::data::kv::{KeyValueStore, KvError, KvKeyIterator, KvTransaction};
+use crate::data::kv::{
+  decode_counter, encode_counter, KeyValueStore, KvError, KvIterator, KvKeyIterator, KvTransaction,
+  ScanOptions, VecKvIterator,
+};
 use anyhow::Result;
 use async_trait::async_trait;
 use r2d2::{Pool, PooledConnection};
 use r2d2_sqlite::SqliteConnectionManager;
-use rusqlite::{named_params, OptionalExtension, Transaction};
+use rusqlite::{named_params, params, ErrorCode, OptionalExtension, ToSql, Transaction};
 use std::future::Future;
 use thiserror::Error;
 use tokio::{
@@ -123,6 +126,7 @@ enum ModOp {
   Put(Vec<u8>, Vec<u8>),
   Delete(Vec<u8>),
   DeleteRange(Vec<u8>, Vec<u8>),
+  Add(Vec<u8>, i64),
 }
 
 async fn txn_worker(
@@ -168,7 +172,22 @@ impl SqliteKvTxn {
       Ok(_) => rx.await.unwrap_or_else(|e| Err(anyhow::Error::from(e))),
       Err(_) => Err(anyhow::Error::from(SqliteKvError::Interrupted)),
     };
-    res
+
+    // Let the caller retry if another connection holds the lock.
+    res.map_err(|e| {
+      if is_busy(&e) {
+        KvError::Conflict.into()
+      } else {
+        e
+      }
+    })
+  }
+}
+
+fn is_busy(e: &anyhow::Error) -> bool {
+  match e.downcast_ref::<rusqlite::Error>() {
+    Some(rusqlite::Error::SqliteFailure(x, _)) => x.code == ErrorCode::DatabaseBusy,
+    _ => false,
   }
 }
 
@@ -206,6 +225,22 @@ impl KvTransaction for SqliteKvTxn {
     Ok(())
   }
 
+  async fn put_many(&self, entries: &[(Vec<u8>, Vec<u8>)]) -> Result<()> {
+    let ops = entries.iter().map(|(key, value)| {
+      ModOp::Put(
+        self
+          .prefix
+          .iter()
+          .copied()
+          .chain(key.iter().copied())
+          .collect::<Vec<_>>(),
+        value.clone(),
+      )
+    });
+    self.log.lock().await.extend(ops);
+    Ok(())
+  }
+
   async fn delete(&self, key: &[u8]) -> Result<()> {
     let key = self
       .prefix
@@ -234,6 +269,19 @@ impl KvTransaction for SqliteKvTxn {
     Ok(())
   }
 
+  // Applied at commit, after the writes before it. Reads don't see writes of the same
+  // transaction, so reading the counter here would lose earlier adds.
+  async fn atomic_add(&self, key: &[u8], delta: i64) -> Result<()> {
+    let key = self
+      .prefix
+      .iter()
+      .copied()
+      .chain(key.iter().copied())
+      .collect::<Vec<_>>();
+    self.log.lock().await.push(ModOp::Add(key, delta));
+    Ok(())
+  }
+
   async fn scan_keys(&self, start: &[u8], end: &[u8]) -> Result<Box<dyn KvKeyIterator>> {
     let start = self
       .prefix
@@ -266,20 +314,69 @@ impl KvTransaction for SqliteKvTxn {
       .await
   }
 
+  async fn scan(
+    &self,
+    start: &[u8],
+    end: &[u8],
+    options: ScanOptions,
+  ) -> Result<Box<dyn KvIterator>> {
+    let start = self
+      .prefix
+      .iter()
+      .copied()
+      .chain(start.iter().copied())
+      .collect::<Vec<_>>();
+    let end = self
+      .prefix
+      .iter()
+      .copied()
+      .chain(end.iter().copied())
+      .collect::<Vec<_>>();
+    let table = self.table.clone();
+    let prefix_len = self.prefix.len();
+
+    // A negative limit means no limit in SQLite.
+    let limit = options.limit.map(|x| x as i64).unwrap_or(-1);
+    self
+      .run(move |txn| {
+        let mut stmt = txn.as_mut().unwrap().prepare_cached(&format!(
+          "select k, v from {} where k >= ? and k < ? order by k {} limit ?",
+          table,
+          if options.reverse { "desc" } else { "asc" }
+        ))?;
+        let entries: Vec<(Vec<u8>, Vec<u8>)> = stmt
+          .query_map(params![start, end, limit], |x| Ok((x.get(0)?, x.get(1)?)))?
+          .map(|x| x.map_err(anyhow::Error::from))
+          .collect::<Result<_>>()?;
+        Ok(Box::new(VecKvIterator::new(
+          entries
+            .into_iter()
+            .map(|(k, v)| (k[prefix_len..].to_vec(), v))
+            .collect(),
+        )) as Box<dyn KvIterator>)
+      })
+      .await
+  }
+
   async fn commit(self: Box<Self>) -> Result<(), KvError> {
     let log = std::mem::replace(&mut *self.log.try_lock().unwrap(), vec![]);
     let table = self.table.clone();
     self
       .run(move |txn| {
         let txn = txn.take().unwrap();
-        for op in log {
+        let mut log = log.into_iter().peekable();
+        while let Some(op) = log.next() {
           match op {
             ModOp::Put(key, value) => {
-              let mut stmt = txn.prepare_cached(&format!(
-                "insert into {} (k, v) values(:k, :v) on conflict(k) do update set v = :v",
-                table
-              ))?;
-              stmt.execute(named_params! { ":k": &key, ":v": &value })?;
+              // Consecutive puts are written with one statement per batch.
+              let mut batch = vec![(key, value)];
+              while batch.len() < MAX_PUTS_PER_STATEMENT {
+                match log.next_if(|x| matches!(x, ModOp::Put(..))) {
+                  Some(ModOp::Put(key, value)) => batch.push((key, value)),
+                  _ => break,
+                }
+              }
+              put_batch(&txn, &table, &batch)?;
             }
             ModOp::Delete(key) => {
               let mut stmt = txn.prepare_cached(&format!("delete from {} where k = ?", table))?;
@@ -290,6 +387,15 @@ impl KvTransaction for SqliteKvTxn {
                 txn.prepare_cached(&format!("delete from {} where k >= ? and k < ?", table))?;
               stmt.execute(&[&start, &end])?;
             }
+            ModOp::Add(key, delta) => {
+              let mut stmt = txn.prepare_cached(&format!("select v from {} where k = ?", table))?;
+              let current: Option<Vec<u8>> = stmt.query_row(&[&key], |x| x.get(0)).optional()?;
+              let value = current
+                .map(|x| decode_counter(&x))
+                .unwrap_or(0)
+                .wrapping_add(delta);
+              put_batch(&txn, &table, &[(key, encode_counter(value).to_vec())])?;
+            }
           }
         }
         txn.commit()?;
@@ -297,17 +403,8 @@ impl KvTransaction for SqliteKvTxn {
       })
       .await
       .map_err(|e| {
-        if let Some(x) = e.downcast_ref::<rusqlite::Error>() {
-          match x {
-            rusqlite::Error::SqliteFailure(_, reason) => {
-              if let Some(reason) = reason {
-                if reason == "database is locked" {
-                  return KvError::Conflict;
-                }
-              }
-            }
-            _ => {}
-          }
+        if let Some(KvError::Conflict) = e.downcast_ref::<KvError>() {
+          return KvError::Conflict;
         }
         log::error!("sqlite commit error: {:?}", e);
         KvError::CommitStateUnknown
@@ -315,6 +412,34 @@ impl KvTransaction for SqliteKvTxn {
   }
 }
 
+/// Keeps the number of bound parameters of a statement below SQLite's default limit of 999.
+const MAX_PUTS_PER_STATEMENT: usize = 256;
+
+fn put_batch(txn: &Transaction, table: &str, batch: &[(Vec<u8>, Vec<u8>)]) -> Result<()> {
+  if let [(key, value)] = batch {
+    let mut stmt = txn.prepare_cached(&format!(
+      "insert into {} (k, v) values(:k, :v) on conflict(k) do update set v = :v",
+      table
+    ))?;
+    stmt.execute(named_params! { ":k": key, ":v": value })?;
+    return Ok(());
+  }
+
+  // Rows are upserted in order, so the last put to a key wins.
+  let mut stmt = txn.prepare_cached(&format!(
+    "insert into {} (k, v) values {} on conflict(k) do update set v = excluded.v",
+    table,
+    vec!["(?, ?)"; batch.len()].join(", ")
+  ))?;
+  let mut params: Vec<&dyn ToSql> = Vec::with_capacity(batch.len() * 2);
+  for (key, value) in batch {
+    params.push(key);
+    params.push(value);
+  }
+  stmt.execute(&*params)?;
+  Ok(())
+}
+
 pub struct SqliteKvIterator {
   keys: Vec<Vec<u8>>,
 }
@@ -1,6 +1,9 @@
 #[macro_use]
 mod util;
+pub mod codegen;
 pub mod data;
+#[cfg(any(test, feature = "fixtures"))]
+pub mod fixtures;
 pub mod kv_backend;
 pub mod schema;
 pub mod storage_plan;
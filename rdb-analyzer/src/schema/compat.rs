@@ -0,0 +1,195 @@
+use std::fmt::Display;
+
+use super::compile::{
+  CompiledSchema, FieldAnnotation, FieldAnnotationList, FieldType, SpecializedType,
+};
+
+/// A change between two versions of a schema. Paths are dot-separated from an export, e.g.
+/// `items.name`, and refer to the new schema except for removed fields.
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub struct SchemaChange {
+  pub path: String,
+  pub kind: ChangeKind,
+}
+
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub enum ChangeKind {
+  Added,
+  Removed,
+
+  /// Renamed with `@rename_from` from the contained name.
+  Renamed(String),
+
+  /// From the first type to the second.
+  TypeChanged(String, String),
+
+  /// An `@index` or `@unique` annotation was added.
+  IndexAdded,
+
+  /// An `@index` or `@unique` annotation was removed.
+  IndexRemoved,
+
+  /// The field became or stopped being the primary key.
+  PrimaryKeyChanged,
+
+  /// The field became or stopped being a `@counter`, which is stored differently.
+  CounterChanged,
+
+  /// The field became or stopped being a `@version`, which is stored differently.
+  VersionChanged,
+}
+
+impl SchemaChange {
+  /// Whether existing data becomes unreachable after the change.
+  pub fn is_lossy(&self) -> bool {
+    match self.kind {
+      ChangeKind::Removed
+      | ChangeKind::TypeChanged(..)
+      | ChangeKind::PrimaryKeyChanged
+      | ChangeKind::CounterChanged
+      | ChangeKind::VersionChanged => true,
+      ChangeKind::Added
+      | ChangeKind::Renamed(_)
+      | ChangeKind::IndexAdded
+      | ChangeKind::IndexRemoved => false,
+    }
+  }
+}
+
+impl Display for SchemaChange {
+  fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+    match &self.kind {
+      ChangeKind::Added => write!(f, "`{}`: added", self.path),
+      ChangeKind::Removed => write!(f, "`{}`: removed", self.path),
+      ChangeKind::Renamed(from) => write!(f, "`{}`: renamed from `{}`", self.path, from),
+      ChangeKind::TypeChanged(from, to) => {
+        write!(
+          f,
+          "`{}`: type changed from `{}` to `{}`",
+          self.path, from, to
+        )
+      }
+      ChangeKind::IndexAdded => write!(f, "`{}`: index added", self.path),
+      ChangeKind::IndexRemoved => write!(f, "`{}`: index removed", self.path),
+      ChangeKind::PrimaryKeyChanged => write!(f, "`{}`: primary key changed", self.path),
+      ChangeKind::CounterChanged => write!(f, "`{}`: counter added or removed", self.path),
+      ChangeKind::VersionChanged => write!(f, "`{}`: version added or removed", self.path),
+    }
+  }
+}
+
+/// Lists the changes from `old` to `new`, matching fields by name or by `@rename_from` like the
+/// storage planner does. Recursive types are compared up to where they recur.
+pub fn check(old: &CompiledSchema, new: &CompiledSchema) -> Vec<SchemaChange> {
+  let mut ctx = CompatContext {
+    old,
+    new,
+    stack: vec![],
+    changes: vec![],
+  };
+  for (name, new_ty) in &new.exports {
+    match old.exports.get(name) {
+      Some(old_ty) => ctx.compare_field(name, (old_ty, &[]), (new_ty, &[])),
+      None => ctx.push(name, ChangeKind::Added),
+    }
+  }
+  for name in old.exports.keys() {
+    if !new.exports.contains_key(name) {
+      ctx.push(name, ChangeKind::Removed);
+    }
+  }
+  ctx.changes
+}
+
+struct CompatContext<'a> {
+  old: &'a CompiledSchema,
+  new: &'a CompiledSchema,
+
+  /// Pairs of old and new table types being compared.
+  stack: Vec<(&'a str, &'a str)>,
+  changes: Vec<SchemaChange>,
+}
+
+impl<'a> CompatContext<'a> {
+  fn push(&mut self, path: &str, kind: ChangeKind) {
+    self.changes.push(SchemaChange {
+      path: path.to_string(),
+      kind,
+    });
+  }
+
+  fn compare_field(
+    &mut self,
+    path: &str,
+    old: (&'a FieldType, &'a [FieldAnnotation]),
+    new: (&'a FieldType, &'a [FieldAnnotation]),
+  ) {
+    let is_indexed = |x: &[FieldAnnotation]| x.is_index() || x.is_unique();
+    match (is_indexed(old.1), is_indexed(new.1)) {
+      (false, true) => self.push(path, ChangeKind::IndexAdded),
+      (true, false) => self.push(path, ChangeKind::IndexRemoved),
+      _ => {}
+    }
+    if old.1.is_primary() != new.1.is_primary()
+      || old.1.primary_key_component() != new.1.primary_key_component()
+    {
+      self.push(path, ChangeKind::PrimaryKeyChanged);
+    }
+    if old.1.is_counter() != new.1.is_counter() {
+      self.push(path, ChangeKind::CounterChanged);
+    }
+    if old.1.is_version() != new.1.is_version() {
+      self.push(path, ChangeKind::VersionChanged);
+    }
+
+    match (old.0, new.0) {
+      (FieldType::Primitive(x), FieldType::Primitive(y)) if x == y => {}
+      (FieldType::List(x), FieldType::List(y)) if x == y => {}
+      (FieldType::Map(xk, xv), FieldType::Map(yk, yv)) if xk == yk && xv == yv => {}
+      (FieldType::Set(x), FieldType::Set(y)) => self.compare_field(path, (x, &[]), (y, &[])),
+      (FieldType::Table(x), FieldType::Table(y)) => {
+        if self.stack.contains(&(&**x, &**y)) {
+          return;
+        }
+        let (old_ty, new_ty) = match (self.old.types.get(x), self.new.types.get(y)) {
+          (Some(a), Some(b)) => (a, b),
+          _ => return,
+        };
+        self.stack.push((x, y));
+        self.compare_table(path, old_ty, new_ty);
+        self.stack.pop();
+      }
+      (x, y) => self.push(path, ChangeKind::TypeChanged(x.to_string(), y.to_string())),
+    }
+  }
+
+  fn compare_table(&mut self, path: &str, old: &'a SpecializedType, new: &'a SpecializedType) {
+    let mut matched = vec![];
+    for (name, (ty, annotations)) in &new.fields {
+      let field_path = format!("{}.{}", path, name);
+      let renamed_from = annotations.iter().filter_map(|x| match x {
+        FieldAnnotation::RenameFrom(x) => Some(x.as_str()),
+        _ => None,
+      });
+      let old_name = std::iter::once(&**name)
+        .chain(renamed_from)
+        .find(|x| old.fields.contains_key(*x) && !matched.contains(x));
+      match old_name {
+        Some(old_name) => {
+          matched.push(old_name);
+          if old_name != &**name {
+            self.push(&field_path, ChangeKind::Renamed(old_name.to_string()));
+          }
+          let (old_ty, old_annotations) = &old.fields[old_name];
+          self.compare_field(&field_path, (old_ty, old_annotations), (ty, annotations));
+        }
+        None => self.push(&field_path, ChangeKind::Added),
+      }
+    }
+    for name in old.fields.keys() {
+      if !matched.contains(&&**name) {
+        self.push(&format!("{}.{}", path, name), ChangeKind::Removed);
+      }
+    }
+  }
+}
@@ -0,0 +1,170 @@
+use bumpalo::Bump;
+
+use super::{
+  compat::{check, ChangeKind, SchemaChange},
+  compile::compile,
+  grammar::parse,
+};
+
+fn check_str(old: &str, new: &str) -> Vec<SchemaChange> {
+  let alloc = Bump::new();
+  let old = compile(&parse(&alloc, old).unwrap()).unwrap();
+  let new = compile(&parse(&alloc, new).unwrap()).unwrap();
+  check(&old, &new)
+}
+
+fn change(path: &str, kind: ChangeKind) -> SchemaChange {
+  SchemaChange {
+    path: path.to_string(),
+    kind,
+  }
+}
+
+#[test]
+fn compat_identical() {
+  let _ = pretty_env_logger::try_init();
+  let schema = r#"
+    type Item {
+      @primary
+      id: string,
+      @index
+      name: string,
+      children: set<Item>,
+    }
+    export set<Item> items;
+  "#;
+  assert_eq!(check_str(schema, schema), vec![]);
+}
+
+#[test]
+fn compat_fields() {
+  let _ = pretty_env_logger::try_init();
+  let changes = check_str(
+    r#"
+    type Item {
+      @primary
+      id: string,
+      name: string,
+      count: int64,
+      legacy: bytes,
+      tags: set<Tag>,
+    }
+    type Tag {
+      @primary
+      id: string,
+      @unique
+      label: string,
+    }
+    export set<Item> items;
+    export int64 counter;
+  "#,
+    r#"
+    type Item {
+      @primary
+      id: string,
+      @rename_from("name")
+      @index
+      title: string,
+      count: string,
+      tags: set<Tag>,
+      created_at: int64,
+    }
+    type Tag {
+      @primary
+      id: string,
+      label: string,
+    }
+    export set<Item> items;
+    export string greeting;
+  "#,
+  );
+  assert_eq!(
+    changes,
+    vec![
+      change("greeting", ChangeKind::Added),
+      change(
+        "items.count",
+        ChangeKind::TypeChanged("int64".into(), "string".into())
+      ),
+      change("items.created_at", ChangeKind::Added),
+      change("items.tags.label", ChangeKind::IndexRemoved),
+      change("items.title", ChangeKind::Renamed("name".into())),
+      change("items.title", ChangeKind::IndexAdded),
+      change("items.legacy", ChangeKind::Removed),
+      change("counter", ChangeKind::Removed),
+    ]
+  );
+  let lossy = changes
+    .iter()
+    .filter(|x| x.is_lossy())
+    .map(|x| x.to_string())
+    .collect::<Vec<_>>();
+  assert_eq!(
+    lossy,
+    vec![
+      "`items.count`: type changed from `int64` to `string`",
+      "`items.legacy`: removed",
+      "`counter`: removed",
+    ]
+  );
+}
+
+#[test]
+fn compat_primary_key() {
+  let _ = pretty_env_logger::try_init();
+  let changes = check_str(
+    r#"
+    type Item {
+      @primary
+      id: string,
+      name: string,
+    }
+    export set<Item> items;
+  "#,
+    r#"
+    type Item {
+      id: string,
+      @primary
+      name: string,
+    }
+    export set<Item> items;
+  "#,
+  );
+  assert_eq!(
+    changes,
+    vec![
+      change("items.id", ChangeKind::PrimaryKeyChanged),
+      change("items.name", ChangeKind::PrimaryKeyChanged),
+    ]
+  );
+  assert!(changes.iter().all(|x| x.is_lossy()));
+}
+
+#[test]
+fn compat_counter() {
+  let _ = pretty_env_logger::try_init();
+  let changes = check_str(
+    r#"
+    type Item {
+      @primary
+      id: string,
+      views: int64,
+    }
+    export set<Item> items;
+  "#,
+    r#"
+    type Item {
+      @primary
+      id: string,
+      @counter
+      views: int64,
+    }
+    export set<Item> items;
+  "#,
+  );
+  assert_eq!(
+    changes,
+    vec![change("items.views", ChangeKind::CounterChanged)]
+  );
+  assert!(changes[0].is_lossy());
+}
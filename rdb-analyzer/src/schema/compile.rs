@@ -1,6 +1,8 @@
 use std::collections::BTreeMap;
+use std::collections::BTreeSet;
 use std::collections::HashMap;
 use std::fmt::Display;
+use std::io::Write;
 use std::sync::Arc;
 
 use anyhow::Result;
@@ -44,15 +46,59 @@ pub enum SchemaCompileError {
   #[error("sets must have exactly one table type parameter")]
   BadSetTypeParameter,
 
+  #[error("lists must have exactly one primitive type parameter")]
+  BadListTypeParameter,
+
+  #[error("maps must have exactly two primitive type parameters, the key and the value")]
+  BadMapTypeParameter,
+
   #[error("unknown annotation on field `{0}` of type `{1}`: `{2}`")]
   UnknownAnnotationOnField(String, String, String),
 
   #[error("field `{0}` of type `{1}`: indexes are only allowed on primitive fields")]
   IndexOnNonPrimitiveField(String, String),
 
+  #[error("field `{0}` of type `{1}`: defaults are only allowed on primitive fields")]
+  DefaultOnNonPrimitiveField(String, String),
+
+  #[error("field `{0}` of type `{1}`: default does not match type `{2}`")]
+  DefaultTypeMismatch(String, String, PrimitiveType),
+
+  #[error("field `{0}` of type `{1}`: the primary key cannot have a default")]
+  DefaultOnPrimaryKey(String, String),
+
+  #[error("field `{0}` of type `{1}`: counters must be int64 fields")]
+  CounterOnNonInt64Field(String, String),
+
+  #[error("field `{0}` of type `{1}`: counters cannot be keys, indexed, or have a default")]
+  BadCounterAnnotations(String, String),
+
+  #[error("field `{0}` of type `{1}`: versions must be int64 fields")]
+  VersionOnNonInt64Field(String, String),
+
+  #[error(
+    "field `{0}` of type `{1}`: versions cannot be keys, indexed, counters, or have a default"
+  )]
+  BadVersionAnnotations(String, String),
+
+  #[error("type `{0}` has multiple version fields")]
+  MultipleVersionFields(String),
+
   #[error("type `{0}` has multiple primary keys")]
   MultiplePrimaryKeys(String),
 
+  #[error("type `{0}`: primary key position {1} is used by more than one field")]
+  DuplicatePrimaryKeyPosition(String, u32),
+
+  #[error("type `{0}`: composite primary keys must have at least two fields")]
+  CompositePrimaryKeyTooShort(String),
+
+  #[error("type `{0}`: the ttl field `{1}` must be an int64 field")]
+  BadTtlField(String, String),
+
+  #[error("type `{0}`: `@ttl` takes the name of a field and may only appear once")]
+  BadTtlAnnotation(String),
+
   #[error("type name must start with an upper-case letter: `{0}`")]
   TypeNameMustStartWithUpperCaseLetter(String),
 }
@@ -87,10 +133,29 @@ static PRIMITIVE_TYPES: phf::Map<&'static str, PrimitiveType> = phf::phf_map! {
   "bytes" => PrimitiveType::Bytes,
 };
 
-#[derive(Debug, Default, Serialize, Deserialize)]
+#[derive(Debug, Default, Clone, Serialize, Deserialize)]
 pub struct CompiledSchema {
   pub types: BTreeMap<Arc<str>, SpecializedType>,
   pub exports: BTreeMap<Arc<str>, FieldType>,
+
+  /// Exports of namespaces mounted read-only into this one, by mount name. Only present in
+  /// schemas built for query execution.
+  ///
+  /// See `data::mount`.
+  #[serde(default, skip_serializing_if = "BTreeMap::is_empty")]
+  pub mounts: BTreeMap<Arc<str>, BTreeMap<Arc<str>, FieldType>>,
+}
+
+impl CompiledSchema {
+  pub fn serialize_compressed(&self) -> Result<Vec<u8>> {
+    let serialized = rmp_serde::to_vec_named(self)?;
+    let mut buf = Vec::new();
+    snap::write::FrameEncoder::new(&mut buf).write_all(&serialized)?;
+    Ok(buf)
+  }
+  pub fn deserialize_compressed(data: &[u8]) -> Result<Self> {
+    Ok(rmp_serde::from_read(snap::read::FrameDecoder::new(data))?)
+  }
 }
 
 impl Display for CompiledSchema {
@@ -110,6 +175,7 @@ pub fn compile<'a>(input: &ast::Schema<'a>) -> Result<CompiledSchema> {
   let mut result = CompiledSchema {
     types: BTreeMap::new(),
     exports: BTreeMap::new(),
+    mounts: BTreeMap::new(),
   };
 
   for item in &input.items {
@@ -132,6 +198,11 @@ pub fn compile<'a>(input: &ast::Schema<'a>) -> Result<CompiledSchema> {
 pub struct SpecializedType {
   pub name: Arc<str>,
   pub fields: BTreeMap<Arc<str>, (FieldType, Vec<FieldAnnotation>)>,
+
+  /// The `@ttl` field: members of sets of this type expire once the time in this field, in
+  /// milliseconds since the Unix epoch, has passed.
+  #[serde(default, skip_serializing_if = "Option::is_none")]
+  pub ttl: Option<Arc<str>>,
 }
 
 pub struct IndexedField<'a> {
@@ -141,6 +212,35 @@ pub struct IndexedField<'a> {
 }
 
 impl SpecializedType {
+  /// Fields of the primary key in key order. Empty if the type has no primary key.
+  pub fn primary_key(&self) -> Vec<(&Arc<str>, &FieldType)> {
+    let mut components = self
+      .fields
+      .iter()
+      .filter_map(|(name, (ty, ann))| {
+        if ann.as_slice().is_primary() {
+          Some((0, name, ty))
+        } else {
+          ann
+            .as_slice()
+            .primary_key_component()
+            .map(|x| (x, name, ty))
+        }
+      })
+      .collect::<Vec<_>>();
+    components.sort_by_key(|x| x.0);
+    components.into_iter().map(|x| (x.1, x.2)).collect()
+  }
+
+  /// The `@version` field, if any.
+  pub fn version_field(&self) -> Option<&Arc<str>> {
+    self
+      .fields
+      .iter()
+      .find(|(_, (_, annotations))| annotations.as_slice().is_version())
+      .map(|(name, _)| name)
+  }
+
   pub fn lookup_indexed_field<'a>(&'a self, name: &str) -> Option<IndexedField<'a>> {
     self
       .fields
@@ -162,12 +262,77 @@ pub enum FieldAnnotation {
   Unique,
   Index,
   RenameFrom(String),
+
+  /// Only callers with one of these roles may see this field.
+  Acl(Vec<String>),
+
+  /// Value read from the field while it is not set.
+  Default(DefaultValue),
+
+  /// Part of a composite primary key, at the given position.
+  PrimaryKeyComponent(u32),
+
+  /// An int64 field updated with atomic adds. Stored as a little-endian integer instead of
+  /// MessagePack, so that adds don't have to read the field.
+  Counter,
+
+  /// An int64 field bumped by the executor on every update of the table, for optimistic
+  /// concurrency control with `t_cas`. Stored like a `Counter`.
+  Version,
+}
+
+/// The `@default` of a primitive field, already converted to the type of the field.
+#[derive(Debug, Clone, Eq, PartialEq, Serialize, Deserialize)]
+pub enum DefaultValue {
+  Int64(i64),
+
+  /// Bits of an `f64`.
+  Double(u64),
+  String(String),
+  Bytes(Vec<u8>),
+}
+
+impl DefaultValue {
+  fn from_literal(x: &Literal, ty: PrimitiveType) -> Option<Self> {
+    Some(match (x, ty) {
+      (Literal::Integer(x), PrimitiveType::Int64) => Self::Int64(*x),
+      (Literal::Integer(x), PrimitiveType::Double) => Self::Double((*x as f64).to_bits()),
+      (Literal::Double(x), PrimitiveType::Double) => Self::Double(x.to_bits()),
+      (Literal::String(x), PrimitiveType::String) => Self::String(x.to_string()),
+      (Literal::Bytes(x), PrimitiveType::Bytes) => Self::Bytes(x.to_vec()),
+      _ => return None,
+    })
+  }
+}
+
+impl Display for DefaultValue {
+  fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+    match self {
+      Self::Int64(x) => write!(f, "{}", x),
+      Self::Double(x) => write!(f, "{}", format_double(f64::from_bits(*x))),
+      Self::String(x) => write!(f, "{}", serde_json::to_string(x).unwrap()),
+      Self::Bytes(x) => write!(f, "h\"{}\"", hex::encode(x)),
+    }
+  }
+}
+
+/// Formats a double so that it parses back as a double literal.
+pub(crate) fn format_double(x: f64) -> String {
+  if x.fract() == 0.0 {
+    format!("{:.1}", x)
+  } else {
+    x.to_string()
+  }
 }
 
 pub trait FieldAnnotationList {
   fn is_primary(&self) -> bool;
+  fn is_in_primary_key(&self) -> bool;
+  fn primary_key_component(&self) -> Option<u32>;
   fn is_unique(&self) -> bool;
   fn is_index(&self) -> bool;
+  fn is_counter(&self) -> bool;
+  fn is_version(&self) -> bool;
 }
 
 impl FieldAnnotationList for &[FieldAnnotation] {
@@ -175,6 +340,14 @@ impl FieldAnnotationList for &[FieldAnnotation] {
     self.iter().find(|x| x.is_primary()).is_some()
   }
 
+  fn is_in_primary_key(&self) -> bool {
+    self.is_primary() || self.primary_key_component().is_some()
+  }
+
+  fn primary_key_component(&self) -> Option<u32> {
+    self.iter().find_map(|x| x.primary_key_component())
+  }
+
   fn is_unique(&self) -> bool {
     self.iter().find(|x| x.is_unique()).is_some()
   }
@@ -182,6 +355,14 @@ impl FieldAnnotationList for &[FieldAnnotation] {
   fn is_index(&self) -> bool {
     self.iter().find(|x| x.is_index()).is_some()
   }
+
+  fn is_counter(&self) -> bool {
+    self.iter().find(|x| x.is_counter()).is_some()
+  }
+
+  fn is_version(&self) -> bool {
+    self.iter().find(|x| x.is_version()).is_some()
+  }
 }
 
 impl FieldAnnotation {
@@ -203,6 +384,36 @@ impl FieldAnnotation {
       _ => false,
     }
   }
+  pub fn is_counter(&self) -> bool {
+    match self {
+      FieldAnnotation::Counter => true,
+      _ => false,
+    }
+  }
+  pub fn is_version(&self) -> bool {
+    match self {
+      FieldAnnotation::Version => true,
+      _ => false,
+    }
+  }
+  pub fn primary_key_component(&self) -> Option<u32> {
+    match self {
+      FieldAnnotation::PrimaryKeyComponent(x) => Some(*x),
+      _ => None,
+    }
+  }
+  pub fn acl_roles(&self) -> Option<&[String]> {
+    match self {
+      FieldAnnotation::Acl(x) => Some(x),
+      _ => None,
+    }
+  }
+  pub fn default_value(&self) -> Option<&DefaultValue> {
+    match self {
+      FieldAnnotation::Default(x) => Some(x),
+      _ => None,
+    }
+  }
 }
 
 impl Display for FieldAnnotation {
@@ -212,6 +423,18 @@ impl Display for FieldAnnotation {
       Self::Unique => write!(f, "@unique"),
       Self::Index => write!(f, "@index"),
       Self::RenameFrom(x) => write!(f, "@rename_from({})", serde_json::to_string(x).unwrap()),
+      Self::Acl(x) => write!(
+        f,
+        "@acl({})",
+        x.iter()
+          .map(|x| serde_json::to_string(x).unwrap())
+          .collect::<Vec<_>>()
+          .join(", ")
+      ),
+      Self::Default(x) => write!(f, "@default({})", x),
+      Self::PrimaryKeyComponent(x) => write!(f, "@primary({})", x),
+      Self::Counter => write!(f, "@counter"),
+      Self::Version => write!(f, "@version"),
     }
   }
 }
@@ -221,6 +444,12 @@ pub enum FieldType {
   Table(Arc<str>),
   Primitive(PrimitiveType),
   Set(Box<FieldType>),
+
+  /// An ordered list of primitive values that may have duplicates.
+  List(Box<FieldType>),
+
+  /// Primitive values keyed by primitive values, ordered by key.
+  Map(Box<FieldType>, Box<FieldType>),
 }
 
 impl Display for FieldType {
@@ -229,12 +458,17 @@ impl Display for FieldType {
       Self::Table(x) => write!(f, "{}", x),
       Self::Primitive(x) => write!(f, "{}", x),
       Self::Set(x) => write!(f, "set<{}>", x),
+      Self::List(x) => write!(f, "list<{}>", x),
+      Self::Map(k, v) => write!(f, "map<{}, {}>", k, v),
     }
   }
 }
 
 impl Display for SpecializedType {
   fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+    if let Some(x) = &self.ttl {
+      write!(f, "@ttl({})\n", serde_json::to_string(&**x).unwrap())?;
+    }
     write!(f, "type {} {{\n", self.name)?;
     for (k, (ty, annotations)) in &self.fields {
       write!(f, "  ")?;
@@ -309,7 +543,7 @@ impl<'a> TypeResolutionContext<'a> {
       return Ok(FieldType::Primitive(*ty));
     }
 
-    // The only special case, `set`...
+    // The special cases, `set`, `list` and `map`...
     if id.0 == "set" {
       if args.len() != 1 {
         return Err(SchemaCompileError::BadSetTypeParameter.into());
@@ -320,6 +554,23 @@ impl<'a> TypeResolutionContext<'a> {
         return Err(SchemaCompileError::BadSetTypeParameter.into());
       }
     }
+    if id.0 == "list" {
+      if args.len() != 1 {
+        return Err(SchemaCompileError::BadListTypeParameter.into());
+      }
+      if let FieldType::Primitive(_) = &args[0] {
+        return Ok(FieldType::List(Box::new(args[0].clone())));
+      } else {
+        return Err(SchemaCompileError::BadListTypeParameter.into());
+      }
+    }
+    if id.0 == "map" {
+      if let [k @ FieldType::Primitive(_), v @ FieldType::Primitive(_)] = &args[..] {
+        return Ok(FieldType::Map(Box::new(k.clone()), Box::new(v.clone())));
+      } else {
+        return Err(SchemaCompileError::BadMapTypeParameter.into());
+      }
+    }
 
     let ty = self
       .unresolved
@@ -361,6 +612,7 @@ impl<'a> TypeResolutionContext<'a> {
       SpecializedType {
         name: repr.clone(),
         fields: BTreeMap::new(),
+        ttl: None,
       },
     );
 
@@ -388,15 +640,59 @@ impl<'a> TypeResolutionContext<'a> {
           ("primary", []) => {
             annotations.push(FieldAnnotation::PrimaryKey);
           }
+          ("primary", [Literal::Integer(x)]) if *x >= 0 && *x <= u32::MAX as i64 => {
+            annotations.push(FieldAnnotation::PrimaryKeyComponent(*x as u32));
+          }
           ("unique", []) => {
             annotations.push(FieldAnnotation::Unique);
           }
           ("index", []) => {
             annotations.push(FieldAnnotation::Index);
           }
+          ("counter", []) => {
+            annotations.push(FieldAnnotation::Counter);
+          }
+          ("version", []) => {
+            annotations.push(FieldAnnotation::Version);
+          }
           ("rename_from", [Literal::String(x)]) => {
             annotations.push(FieldAnnotation::RenameFrom(x.to_string()));
           }
+          ("acl", roles)
+            if !roles.is_empty() && roles.iter().all(|x| matches!(x, Literal::String(_))) =>
+          {
+            annotations.push(FieldAnnotation::Acl(
+              roles
+                .iter()
+                .map(|x| match x {
+                  Literal::String(x) => x.to_string(),
+                  _ => unreachable!(),
+                })
+                .collect(),
+            ));
+          }
+          ("default", [value]) => {
+            let primitive_ty = match &field_ty {
+              FieldType::Primitive(x) => *x,
+              _ => {
+                return Err(
+                  SchemaCompileError::DefaultOnNonPrimitiveField(
+                    x.name.0.to_string(),
+                    ty.name.0.to_string(),
+                  )
+                  .into(),
+                )
+              }
+            };
+            let value = DefaultValue::from_literal(value, primitive_ty).ok_or_else(|| {
+              SchemaCompileError::DefaultTypeMismatch(
+                x.name.0.to_string(),
+                ty.name.0.to_string(),
+                primitive_ty,
+              )
+            })?;
+            annotations.push(FieldAnnotation::Default(value));
+          }
           _ => {
             return Err(
               SchemaCompileError::UnknownAnnotationOnField(
@@ -414,7 +710,9 @@ impl<'a> TypeResolutionContext<'a> {
       // Rule 1: Currently, a primary/unique/non-unique index is only allowed on primitive fields.
       if annotations
         .iter()
-        .find(|x| x.is_primary() || x.is_unique() || x.is_index())
+        .find(|x| {
+          x.is_primary() || x.primary_key_component().is_some() || x.is_unique() || x.is_index()
+        })
         .is_some()
       {
         match field_ty {
@@ -430,23 +728,115 @@ impl<'a> TypeResolutionContext<'a> {
           }
         }
       }
+      if annotations.as_slice().is_in_primary_key()
+        && annotations.iter().any(|x| x.default_value().is_some())
+      {
+        return Err(
+          SchemaCompileError::DefaultOnPrimaryKey(x.name.0.to_string(), ty.name.0.to_string())
+            .into(),
+        );
+      }
+      if annotations.as_slice().is_counter() {
+        if field_ty != FieldType::Primitive(PrimitiveType::Int64) {
+          return Err(
+            SchemaCompileError::CounterOnNonInt64Field(x.name.0.to_string(), ty.name.0.to_string())
+              .into(),
+          );
+        }
+        if annotations.iter().any(|x| {
+          x.is_primary()
+            || x.primary_key_component().is_some()
+            || x.is_unique()
+            || x.is_index()
+            || x.default_value().is_some()
+        }) {
+          return Err(
+            SchemaCompileError::BadCounterAnnotations(x.name.0.to_string(), ty.name.0.to_string())
+              .into(),
+          );
+        }
+      }
+      if annotations.as_slice().is_version() {
+        if field_ty != FieldType::Primitive(PrimitiveType::Int64) {
+          return Err(
+            SchemaCompileError::VersionOnNonInt64Field(x.name.0.to_string(), ty.name.0.to_string())
+              .into(),
+          );
+        }
+        if annotations.iter().any(|x| {
+          x.is_primary()
+            || x.primary_key_component().is_some()
+            || x.is_unique()
+            || x.is_index()
+            || x.is_counter()
+            || x.default_value().is_some()
+        }) {
+          return Err(
+            SchemaCompileError::BadVersionAnnotations(x.name.0.to_string(), ty.name.0.to_string())
+              .into(),
+          );
+        }
+      }
       fields.insert(Arc::from(x.name.0), (field_ty, annotations));
     }
 
-    // Validation: At most one primary key
+    if fields
+      .values()
+      .filter(|(_, annotations)| annotations.as_slice().is_version())
+      .count()
+      > 1
+    {
+      return Err(SchemaCompileError::MultipleVersionFields(ty.name.0.to_string()).into());
+    }
+
+    // Validation: At most one primary key, either a single `@primary` field or a composite key
+    // made of two or more `@primary(n)` fields at distinct positions.
     {
       let mut primary_key_count = 0usize;
+      let mut component_positions = BTreeSet::new();
       for (_, (_, annotations)) in &fields {
         if annotations.as_slice().is_primary() {
           primary_key_count += 1;
         }
+        for x in annotations.iter().filter_map(|x| x.primary_key_component()) {
+          if !component_positions.insert(x) {
+            return Err(
+              SchemaCompileError::DuplicatePrimaryKeyPosition(ty.name.0.to_string(), x).into(),
+            );
+          }
+        }
+      }
+      if !component_positions.is_empty() {
+        primary_key_count += 1;
       }
       if primary_key_count > 1 {
         return Err(SchemaCompileError::MultiplePrimaryKeys(ty.name.0.to_string()).into());
       }
+      if component_positions.len() == 1 {
+        return Err(SchemaCompileError::CompositePrimaryKeyTooShort(ty.name.0.to_string()).into());
+      }
+    }
+
+    let mut ttl: Option<Arc<str>> = None;
+    for ann in &ty.annotations {
+      match (ann.name.0, ann.args.as_slice()) {
+        ("ttl", [Literal::String(x)]) if ttl.is_none() => {
+          let (name, _) = fields
+            .get_key_value(*x)
+            .filter(|(_, (ty, _))| matches!(ty, FieldType::Primitive(PrimitiveType::Int64)))
+            .ok_or_else(|| SchemaCompileError::BadTtlField(ty.name.0.to_string(), x.to_string()))?;
+          ttl = Some(name.clone());
+        }
+        ("ttl", _) => {
+          return Err(SchemaCompileError::BadTtlAnnotation(ty.name.0.to_string()).into());
+        }
+        _ => {}
+      }
     }
 
-    self.resolved.get_mut(&repr).unwrap().fields = fields;
+    let resolved = self.resolved.get_mut(&repr).unwrap();
+    resolved.fields = fields;
+    resolved.ttl = ttl;
 
     Ok(FieldType::Table(repr))
   }
@@ -1,6 +1,9 @@
 use bumpalo::Bump;
 
-use super::{compile::compile, grammar::parse};
+use super::{
+  compile::{compile, CompiledSchema, DefaultValue, FieldAnnotationList},
+  grammar::parse,
+};
 
 #[test]
 fn test_compile_simple() {
@@ -102,6 +105,88 @@ fn no_primitive_types_in_set() {
   assert!(compile(&ast).is_err());
 }
 
+#[test]
+fn list_type_parameters() {
+  let _ = pretty_env_logger::try_init();
+  let alloc = Bump::new();
+  let ast = parse(
+    &alloc,
+    r#"
+    type Item {
+      @primary
+      id: string,
+      tags: list<string>,
+    }
+    export set<Item> items;
+  "#,
+  )
+  .unwrap();
+  let output = compile(&ast).unwrap();
+  assert_eq!(
+    output.types.get("Item<>").unwrap().fields["tags"]
+      .0
+      .to_string(),
+    "list<string>"
+  );
+
+  for ty in ["list<Item>", "list<list<int64>>", "list<int64, string>"] {
+    let source = format!(
+      "type Item {{ @primary id: string, x: {}, }} export set<Item> items;",
+      ty
+    );
+    let ast = parse(&alloc, &source).unwrap();
+    let err = compile(&ast).unwrap_err().to_string();
+    assert!(
+      err.contains("exactly one primitive type parameter"),
+      "{}",
+      err
+    );
+  }
+}
+
+#[test]
+fn map_type_parameters() {
+  let _ = pretty_env_logger::try_init();
+  let alloc = Bump::new();
+  let ast = parse(
+    &alloc,
+    r#"
+    type Item {
+      @primary
+      id: string,
+      prices: map<string, double>,
+    }
+    export set<Item> items;
+  "#,
+  )
+  .unwrap();
+  let output = compile(&ast).unwrap();
+  assert_eq!(
+    output.types.get("Item<>").unwrap().fields["prices"]
+      .0
+      .to_string(),
+    "map<string, double>"
+  );
+
+  for ty in [
+    "map<string>",
+    "map<string, Item>",
+    "map<list<int64>, int64>",
+  ] {
+    let source = format!(
+      "type Item {{ @primary id: string, x: {}, }} export set<Item> items;",
+      ty
+    );
+    let ast = parse(&alloc, &source).unwrap();
+    let err = compile(&ast).unwrap_err().to_string();
+    assert!(
+      err.contains("exactly two primitive type parameters"),
+      "{}",
+      err
+    );
+  }
+}
+
 #[test]
 fn primary_keys() {
   let _ = pretty_env_logger::try_init();
@@ -139,3 +224,333 @@ fn at_most_one_primary_key() {
     .to_string()
     .contains("has multiple primary keys"));
 }
+
+#[test]
+fn composite_primary_keys() {
+  let _ = pretty_env_logger::try_init();
+  let alloc = Bump::new();
+  let ast = parse(
+    &alloc,
+    r#"
+    type Item {
+      @primary(1) b: int64,
+      @primary(0) a: string,
+      c: string,
+    }
+    export set<Item> items;
+  "#,
+  )
+  .unwrap();
+  let output = compile(&ast).unwrap();
+  let key = output
+    .types
+    .get("Item<>")
+    .unwrap()
+    .primary_key()
+    .into_iter()
+    .map(|x| x.0.to_string())
+    .collect::<Vec<_>>();
+  assert_eq!(key, vec!["a", "b"]);
+
+  for (fields, error) in [
+    (
+      "@primary(0) a: string, @primary(0) b: int64,",
+      "used by more than one field",
+    ),
+    ("@primary(0) a: string, b: int64,", "at least two fields"),
+    (
+      "@primary(0) a: string, @primary(1) b: int64, @primary c: string,",
+      "has multiple primary keys",
+    ),
+    (
+      "@primary(0) a: string, @primary(1) b: list<int64>,",
+      "only allowed on primitive fields",
+    ),
+  ] {
+    let source = format!("type Item {{ {} }} export set<Item> items;", fields);
+    let ast = parse(&alloc, &source).unwrap();
+    assert!(
+      compile(&ast).unwrap_err().to_string().contains(error),
+      "{}",
+      fields
+    );
+  }
+}
+
+#[test]
+fn ttl_annotation() {
+  let _ = pretty_env_logger::try_init();
+  let alloc = Bump::new();
+  let ast = parse(
+    &alloc,
+    r#"
+    @ttl("expires_at")
+    type Item {
+      @primary id: string,
+      expires_at: int64,
+    }
+    export set<Item> items;
+  "#,
+  )
+  .unwrap();
+  let output = compile(&ast).unwrap();
+  assert_eq!(
+    output.types.get("Item<>").unwrap().ttl.as_deref(),
+    Some("expires_at")
+  );
+
+  for (annotations, error) in [
+    (r#"@ttl("missing")"#, "must be an int64 field"),
+    (r#"@ttl("id")"#, "must be an int64 field"),
+    (r#"@ttl(1)"#, "takes the name of a field"),
+    (
+      r#"@ttl("expires_at") @ttl("expires_at")"#,
+      "takes the name of a field",
+    ),
+  ] {
+    let source = format!(
+      "{} type Item {{ @primary id: string, expires_at: int64 }} export set<Item> items;",
+      annotations
+    );
+    let ast = parse(&alloc, &source).unwrap();
+    assert!(
+      compile(&ast).unwrap_err().to_string().contains(error),
+      "{}",
+      annotations
+    );
+  }
+}
+
+#[test]
+fn counter_annotation() {
+  let _ = pretty_env_logger::try_init();
+  let alloc = Bump::new();
+  let ast = parse(
+    &alloc,
+    r#"
+    type Item {
+      @primary id: string,
+      @counter views: int64,
+    }
+    export set<Item> items;
+  "#,
+  )
+  .unwrap();
+  let output = compile(&ast).unwrap();
+  let (_, annotations) = &output.types.get("Item<>").unwrap().fields["views"];
+  assert!(annotations.as_slice().is_counter());
+
+  for (field, error) in [
+    ("@counter views: string", "must be int64 fields"),
+    ("@counter views: list<int64>", "must be int64 fields"),
+    ("@counter @index views: int64", "cannot be keys"),
+    ("@counter @unique views: int64", "cannot be keys"),
+    ("@counter @default(1) views: int64", "cannot be keys"),
+  ] {
+    let source = format!(
+      "type Item {{ @primary id: string, {} }} export set<Item> items;",
+      field
+    );
+    let ast = parse(&alloc, &source).unwrap();
+    assert!(
+      compile(&ast).unwrap_err().to_string().contains(error),
+      "{}",
+      field
+    );
+  }
+}
+
+#[test]
+fn version_annotation() {
+  let _ = pretty_env_logger::try_init();
+  let alloc = Bump::new();
+  let ast = parse(
+    &alloc,
+    r#"
+    type Item {
+      @primary id: string,
+      @version version: int64,
+    }
+    export set<Item> items;
+  "#,
+  )
+  .unwrap();
+  let output = compile(&ast).unwrap();
+  assert_eq!(
+    output
+      .types
+      .get("Item<>")
+      .unwrap()
+      .version_field()
+      .map(|x| &**x),
+    Some("version")
+  );
+
+  for (fields, error) in [
+    ("@version version: string", "must be int64 fields"),
+    ("@version @counter version: int64", "cannot be keys"),
+    ("@version @index version: int64", "cannot be keys"),
+    (
+      "@version a: int64, @version b: int64",
+      "multiple version fields",
+    ),
+  ] {
+    let source = format!(
+      "type Item {{ @primary id: string, {} }} export set<Item> items;",
+      fields
+    );
+    let ast = parse(&alloc, &source).unwrap();
+    assert!(
+      compile(&ast).unwrap_err().to_string().contains(error),
+      "{}",
+      fields
+    );
+  }
+}
+
+#[test]
+fn acl_annotations() {
+  let _ = pretty_env_logger::try_init();
+  let alloc = Bump::new();
+  let ast = parse(
+    &alloc,
+    r#"
+    type Item {
+      @acl("admin", "hr") email: string,
+    }
+    export Item item;
+  "#,
+  )
+  .unwrap();
+  let output = compile(&ast).unwrap();
+  let (_, annotations) = output
+    .types
+    .get("Item<>")
+    .unwrap()
+    .fields
+    .get("email")
+    .unwrap();
+  assert_eq!(
+    annotations[0].acl_roles().unwrap(),
+    &["admin".to_string(), "hr".to_string()]
+  );
+
+  for bad in [r#"@acl email: string,"#, r#"@acl(1) email: string,"#] {
+    let ast = parse(
+      &alloc,
+      &format!(
+        r#"
+    type Item {{
+      {}
+    }}
+    export Item item;
+  "#,
+        bad
+      ),
+    )
+    .unwrap();
+    assert!(compile(&ast)
+      .unwrap_err()
+      .to_string()
+      .starts_with("unknown annotation on field"));
+  }
+}
+
+#[test]
+fn default_annotations() {
+  let _ = pretty_env_logger::try_init();
+  let alloc = Bump::new();
+  let ast = parse(
+    &alloc,
+    r#"
+    type Item {
+      @default(-2) count: int64,
+      @default(1) ratio: double,
+      @default("x") name: string,
+      @default(h"00ff") data: bytes,
+    }
+    export Item item;
+  "#,
+  )
+  .unwrap();
+  let output = compile(&ast).unwrap();
+  let fields = &output.types.get("Item<>").unwrap().fields;
+  let default_of = |name: &str| {
+    fields.get(name).unwrap().1[0]
+      .default_value()
+      .unwrap()
+      .clone()
+  };
+  assert_eq!(default_of("count"), DefaultValue::Int64(-2));
+  assert_eq!(default_of("ratio"), DefaultValue::Double(1.0f64.to_bits()));
+  assert_eq!(default_of("name"), DefaultValue::String("x".into()));
+  assert_eq!(default_of("data"), DefaultValue::Bytes(vec![0x00, 0xff]));
+  assert_eq!(
+    fields.get("ratio").unwrap().1[0].to_string(),
+    "@default(1.0)"
+  );
+
+  for (bad, error) in [
+    (
+      r#"@default("x") count: int64,"#,
+      "default does not match type `int64`",
+    ),
+    (
+      r#"@default(1.5) count: int64,"#,
+      "default does not match type `int64`",
+    ),
+    (
+      r#"@default(1) inner: Item,"#,
+      "defaults are only allowed on primitive fields",
+    ),
+    (
+      r#"@primary @default("x") id: string,"#,
+      "the primary key cannot have a default",
+    ),
+    (
+      r#"@default(1, 2) count: int64,"#,
+      "unknown annotation on field",
+    ),
+  ] {
+    let ast = parse(
+      &alloc,
+      &format!(
+        r#"
+    type Item {{
+      {}
+    }}
+    export Item item;
+  "#,
+        bad
+      ),
+    )
+    .unwrap();
+    let err = compile(&ast).unwrap_err().to_string();
+    assert!(err.contains(error), "{}", err);
+  }
+}
+
+#[test]
+fn compressed_roundtrip() {
+  let _ = pretty_env_logger::try_init();
+  let alloc = Bump::new();
+  let ast = parse(
+    &alloc,
+    r#"
+    type Item {
+      @primary
+      id: string,
+      @index
+      value: int64,
+      children: set<Item>,
+    }
+    export set<Item> items;
+  "#,
+  )
+  .unwrap();
+  let output = compile(&ast).unwrap();
+  let serialized = output.serialize_compressed().unwrap();
+  let deserialized = CompiledSchema::deserialize_compressed(&serialized).unwrap();
+  assert_eq!(output.to_string(), deserialized.to_string());
+  assert_eq!(serialized, deserialized.serialize_compressed().unwrap());
+}
@@ -0,0 +1,241 @@
+use std::fmt::Write;
+
+use anyhow::Result;
+use bumpalo::Bump;
+
+use super::compile::format_double;
+use super::grammar::{
+  ast::{Annotation, Literal, Schema, SchemaItem, TypeExpr},
+  parse,
+};
+
+const INDENT: &str = "  ";
+
+/// Formats a schema in the canonical style, keeping its comments and the type parameters of
+/// generic types.
+///
+/// Comments on their own lines stay before the type, field or export that follows them, and
+/// comments after code stay at the end of its line.
+pub fn format_schema(source: &str) -> Result<String> {
+  let alloc = Bump::new();
+  let schema = parse(&alloc, source)?;
+  let lexed = lex(source);
+  let mut f = Formatter {
+    out: String::new(),
+    comments: &lexed.comments,
+    next_comment: 0,
+  };
+  f.format(&schema, &lexed.closing_braces);
+  Ok(f.out)
+}
+
+struct Comment<'a> {
+  start: usize,
+  text: &'a str,
+
+  /// Whether there is code before the comment on its line.
+  trailing: bool,
+}
+
+struct Lexed<'a> {
+  comments: Vec<Comment<'a>>,
+
+  /// Positions of the `}` outside comments and string literals.
+  closing_braces: Vec<usize>,
+}
+
+/// Finds the comments and closing braces of a schema. The grammar drops comments, so they are
+/// located from the source text instead.
+fn lex(source: &str) -> Lexed<'_> {
+  let bytes = source.as_bytes();
+  let mut comments = vec![];
+  let mut closing_braces = vec![];
+  let mut code_on_line = false;
+  let mut i = 0;
+  while i < bytes.len() {
+    match bytes[i] {
+      b'"' => {
+        i += 1;
+        while i < bytes.len() && bytes[i] != b'"' {
+          i += if bytes[i] == b'\\' { 2 } else { 1 };
+        }
+        i += 1;
+        code_on_line = true;
+      }
+      b'/' if bytes.get(i + 1) == Some(&b'/') || bytes.get(i + 1) == Some(&b'*') => {
+        let end = if bytes[i + 1] == b'/' {
+          source[i..]
+            .find('\n')
+            .map(|x| i + x)
+            .unwrap_or(source.len())
+        } else {
+          source[i + 2..]
+            .find("*/")
+            .map(|x| i + 2 + x + 2)
+            .unwrap_or(source.len())
+        };
+        comments.push(Comment {
+          start: i,
+          text: source[i..end].trim_end(),
+          trailing: code_on_line,
+        });
+        i = end;
+      }
+      b'\n' => {
+        code_on_line = false;
+        i += 1;
+      }
+      b'}' => {
+        closing_braces.push(i);
+        code_on_line = true;
+        i += 1;
+      }
+      x => {
+        if !x.is_ascii_whitespace() {
+          code_on_line = true;
+        }
+        i += 1;
+      }
+    }
+  }
+  Lexed {
+    comments,
+    closing_braces,
+  }
+}
+
+struct Formatter<'a> {
+  out: String,
+  comments: &'a [Comment<'a>],
+  next_comment: usize,
+}
+
+impl<'a> Formatter<'a> {
+  fn format(&mut self, schema: &Schema, closing_braces: &[usize]) {
+    let mut prev_was_export = None;
+    for item in &schema.items {
+      let (location, is_export) = match item {
+        SchemaItem::Type(x) => (x.location, false),
+        SchemaItem::Export(x) => (x.location, true),
+      };
+      self.trailing_comments_before(location);
+      if let Some(prev_was_export) = prev_was_export {
+        if !(prev_was_export && is_export) {
+          self.out.push('\n');
+        }
+      }
+      prev_was_export = Some(is_export);
+      self.comments_before(location, "");
+
+      match item {
+        SchemaItem::Type(x) => {
+          for ann in &x.annotations {
+            self.annotation(ann);
+            self.out.push('\n');
+          }
+          write!(self.out, "type {}", x.name.0).unwrap();
+          if !x.generics.is_empty() {
+            let generics = x.generics.iter().map(|x| x.0).collect::<Vec<_>>();
+            write!(self.out, "<{}>", generics.join(", ")).unwrap();
+          }
+          self.out.push_str(" {\n");
+          for field in &x.fields {
+            self.comments_before(field.location, INDENT);
+            for ann in &field.annotations {
+              self.out.push_str(INDENT);
+              self.annotation(ann);
+              self.out.push('\n');
+            }
+            write!(self.out, "{}{}: ", INDENT, field.name.0).unwrap();
+            self.type_expr(&field.value);
+            self.out.push_str(",\n");
+          }
+          let end = closing_braces
+            .iter()
+            .copied()
+            .find(|&b| b > x.location)
+            .unwrap_or(usize::MAX);
+          self.comments_before(end, INDENT);
+          self.out.push_str("}\n");
+        }
+        SchemaItem::Export(x) => {
+          self.out.push_str("export ");
+          self.type_expr(&x.ty);
+          writeln!(self.out, " {};", x.table_name.0).unwrap();
+        }
+      }
+    }
+    self.trailing_comments_before(usize::MAX);
+    if !schema.items.is_empty() && self.next_comment < self.comments.len() {
+      self.out.push('\n');
+    }
+    self.comments_before(usize::MAX, "");
+  }
+
+  /// Appends the trailing comments before `pos` to the last line, up to the first comment on its
+  /// own line.
+  fn trailing_comments_before(&mut self, pos: usize) {
+    while let Some(c) = self.comments.get(self.next_comment) {
+      if c.start >= pos || !c.trailing {
+        break;
+      }
+      self.append_to_last_line(c.text);
+      self.next_comment += 1;
+    }
+  }
+
+  /// Emits the comments before `pos`, each on its own line unless trailing.
+  fn comments_before(&mut self, pos: usize, indent: &str) {
+    while let Some(c) = self.comments.get(self.next_comment) {
+      if c.start >= pos {
+        break;
+      }
+      if c.trailing && !self.out.is_empty() {
+        self.append_to_last_line(c.text);
+      } else {
+        writeln!(self.out, "{}{}", indent, c.text).unwrap();
+      }
+      self.next_comment += 1;
+    }
+  }
+
+  fn append_to_last_line(&mut self, text: &str) {
+    if self.out.ends_with('\n') {
+      self.out.pop();
+    }
+    writeln!(self.out, " {}", text).unwrap();
+  }
+
+  fn annotation(&mut self, ann: &Annotation) {
+    write!(self.out, "@{}", ann.name.0).unwrap();
+    if !ann.args.is_empty() {
+      let args = ann.args.iter().map(format_literal).collect::<Vec<_>>();
+      write!(self.out, "({})", args.join(", ")).unwrap();
+    }
+  }
+
+  fn type_expr(&mut self, e: &TypeExpr) {
+    match e {
+      TypeExpr::Unit(x) => self.out.push_str(x.0),
+      TypeExpr::Specialize(x, args) => {
+        write!(self.out, "{}<", x.0).unwrap();
+        for (i, arg) in args.iter().enumerate() {
+          if i != 0 {
+            self.out.push_str(", ");
+          }
+          self.type_expr(arg);
+        }
+        self.out.push('>');
+      }
+    }
+  }
+}
+
+fn format_literal(x: &Literal) -> String {
+  match x {
+    Literal::Integer(x) => x.to_string(),
+    Literal::Double(x) => format_double(*x),
+    Literal::String(x) => serde_json::to_string(x).unwrap(),
+    Literal::Bytes(x) => format!("h\"{}\"", hex::encode(x)),
+  }
+}
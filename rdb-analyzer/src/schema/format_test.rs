@@ -0,0 +1,61 @@
+use super::format::format_schema;
+
+#[test]
+fn format_canonical() {
+  let _ = pretty_env_logger::try_init();
+  let source = r#"
+// Items of the store.
+type   Item<T>{
+  @primary id:string, // Unique.
+  /* The
+     payload. */
+  inner : Wrapper< T,string >,
+  @rename_from("old") @acl("admin","ops") secret: bytes
+  // Nothing after this.
+}
+type Wrapper<A, B> { a: A, b: B, }
+export set<Item<int64>> items;  export Wrapper<int64, string> w;
+// End.
+"#;
+  let expected = r#"// Items of the store.
+type Item<T> {
+  @primary
+  id: string, // Unique.
+  /* The
+     payload. */
+  inner: Wrapper<T, string>,
+  @rename_from("old")
+  @acl("admin", "ops")
+  secret: bytes,
+  // Nothing after this.
+}
+
+type Wrapper<A, B> {
+  a: A,
+  b: B,
+}
+
+export set<Item<int64>> items;
+export Wrapper<int64, string> w;
+
+// End.
+"#;
+  let formatted = format_schema(source).unwrap();
+  assert_eq!(formatted, expected);
+  assert_eq!(format_schema(&formatted).unwrap(), formatted);
+}
+
+#[test]
+fn format_keeps_literals() {
+  let _ = pretty_env_logger::try_init();
+  let source = r#"
+type A {
+  @x(1, "a//b", h"00ff")
+  f: int64,
+}
+export A a;
+"#;
+  let formatted = format_schema(source).unwrap();
+  assert!(formatted.contains(r#"@x(1, "a//b", h"00ff")"#));
+  assert_eq!(format_schema(&formatted).unwrap(), formatted);
+}
@@ -44,6 +44,7 @@ pub struct Identifier<'a>(pub &'a str);
 
 pub enum Literal<'a> {
   Integer(i64),
+  Double(f64),
   String(&'a str),
   Bytes(&'a [u8]),
 }
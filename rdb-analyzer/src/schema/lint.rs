@@ -0,0 +1,123 @@
+use std::{fmt::Display, sync::Arc};
+
+use super::compile::{CompiledSchema, FieldAnnotationList, FieldType};
+
+/// Nesting of tables and sets below an export beyond which `DeepNesting` is reported.
+pub const MAX_NESTING_DEPTH: usize = 8;
+
+/// A foot-gun found in a compiled schema. Paths are dot-separated from an export, e.g.
+/// `items.children`.
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub enum LintWarning {
+  /// Planning fails on sets of a type without a primary key.
+  SetMemberWithoutPrimaryKey { path: String, ty: Arc<str> },
+
+  /// The members of a set have no `@index` or `@unique` fields, so lookups by anything but the
+  /// primary key scan the whole set.
+  SetMemberWithoutIndex { path: String, ty: Arc<str> },
+
+  /// Each level of nesting adds a storage key to the keys of everything below it. Recursive
+  /// types are followed up to where they recur.
+  DeepNesting { path: String, depth: usize },
+}
+
+impl Display for LintWarning {
+  fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+    match self {
+      Self::SetMemberWithoutPrimaryKey { path, ty } => write!(
+        f,
+        "set `{}`: member type `{}` has no primary key, planning will fail",
+        path, ty
+      ),
+      Self::SetMemberWithoutIndex { path, ty } => write!(
+        f,
+        "set `{}`: member type `{}` has no indexed fields, lookups other than by primary key scan the whole set",
+        path, ty
+      ),
+      Self::DeepNesting { path, depth } => write!(
+        f,
+        "`{}` is nested {} levels deep (more than {})",
+        path, depth, MAX_NESTING_DEPTH
+      ),
+    }
+  }
+}
+
+/// Analyzes a compiled schema for foot-guns. The schema is valid regardless of the warnings.
+pub fn lint(schema: &CompiledSchema) -> Vec<LintWarning> {
+  let mut ctx = LintContext {
+    schema,
+    stack: vec![],
+    warnings: vec![],
+  };
+  for (name, ty) in &schema.exports {
+    ctx.lint_field(ty, name, 1);
+  }
+  ctx.warnings
+}
+
+struct LintContext<'a> {
+  schema: &'a CompiledSchema,
+
+  /// Table types being visited, to stop where a recursive type recurs.
+  stack: Vec<&'a str>,
+  warnings: Vec<LintWarning>,
+}
+
+impl<'a> LintContext<'a> {
+  fn lint_field(&mut self, ty: &'a FieldType, path: &str, depth: usize) {
+    if matches!(
+      ty,
+      FieldType::Primitive(_) | FieldType::List(_) | FieldType::Map(..)
+    ) {
+      return;
+    }
+    if depth > MAX_NESTING_DEPTH {
+      self.warnings.push(LintWarning::DeepNesting {
+        path: path.to_string(),
+        depth,
+      });
+      return;
+    }
+
+    match ty {
+      FieldType::Primitive(_) | FieldType::List(_) | FieldType::Map(..) => {}
+      FieldType::Set(member) => {
+        if let FieldType::Table(name) = &**member {
+          if let Some(member_ty) = self.schema.types.get(name) {
+            let fields = member_ty.fields.values();
+            if !fields.clone().any(|x| x.1.as_slice().is_in_primary_key()) {
+              self.warnings.push(LintWarning::SetMemberWithoutPrimaryKey {
+                path: path.to_string(),
+                ty: name.clone(),
+              });
+            } else if !fields.clone().any(|x| {
+              let annotations = x.1.as_slice();
+              annotations.is_index() || annotations.is_unique()
+            }) {
+              self.warnings.push(LintWarning::SetMemberWithoutIndex {
+                path: path.to_string(),
+                ty: name.clone(),
+              });
+            }
+          }
+        }
+        self.lint_field(member, path, depth + 1);
+      }
+      FieldType::Table(name) => {
+        if self.stack.contains(&&**name) {
+          return;
+        }
+        let table_ty = match self.schema.types.get(name) {
+          Some(x) => x,
+          None => return,
+        };
+        self.stack.push(name);
+        for (field_name, (field_ty, _)) in &table_ty.fields {
+          self.lint_field(field_ty, &format!("{}.{}", path, field_name), depth + 1);
+        }
+        self.stack.pop();
+      }
+    }
+  }
+}
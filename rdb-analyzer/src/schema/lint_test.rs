@@ -0,0 +1,84 @@
+use bumpalo::Bump;
+
+use super::{
+  compile::compile,
+  grammar::parse,
+  lint::{lint, LintWarning, MAX_NESTING_DEPTH},
+};
+
+fn lint_str(schema: &str) -> Vec<LintWarning> {
+  let alloc = Bump::new();
+  let ast = parse(&alloc, schema).unwrap();
+  lint(&compile(&ast).unwrap())
+}
+
+#[test]
+fn lint_set_members() {
+  let _ = pretty_env_logger::try_init();
+  let warnings = lint_str(
+    r#"
+    type Item {
+      @primary
+      id: string,
+      @index
+      name: string,
+      tags: set<Tag>,
+    }
+    type Tag {
+      @primary
+      id: string,
+    }
+    type Unkeyed {
+      name: string,
+    }
+    export set<Item> items;
+    export set<Unkeyed> unkeyed;
+  "#,
+  );
+  assert_eq!(
+    warnings,
+    vec![
+      LintWarning::SetMemberWithoutIndex {
+        path: "items.tags".into(),
+        ty: "Tag<>".into(),
+      },
+      LintWarning::SetMemberWithoutPrimaryKey {
+        path: "unkeyed".into(),
+        ty: "Unkeyed<>".into(),
+      },
+    ]
+  );
+}
+
+#[test]
+fn lint_deep_nesting() {
+  let _ = pretty_env_logger::try_init();
+
+  // Recursion stops where the type recurs.
+  assert!(lint_str(
+    r#"
+    type Node {
+      value: int64,
+      next: Node,
+    }
+    export Node list;
+  "#,
+  )
+  .is_empty());
+
+  let warnings = lint_str(
+    r#"
+    type Wrap<T> {
+      inner: T,
+    }
+    export Wrap<Wrap<Wrap<Wrap<Wrap<Wrap<Wrap<Wrap<Wrap<int64>>>>>>>>> deep;
+  "#,
+  );
+  assert_eq!(
+    warnings,
+    vec![LintWarning::DeepNesting {
+      path: format!("deep{}", ".inner".repeat(MAX_NESTING_DEPTH)),
+      depth: MAX_NESTING_DEPTH + 1,
+    }]
+  );
+}
@@ -1,5 +1,17 @@
+pub mod compat;
 pub mod compile;
+pub mod format;
 pub mod grammar;
+pub mod lint;
+
+#[cfg(test)]
+mod compat_test;
 
 #[cfg(test)]
 mod compile_test;
+
+#[cfg(test)]
+mod format_test;
+
+#[cfg(test)]
+mod lint_test;
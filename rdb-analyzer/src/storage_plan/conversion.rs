@@ -18,6 +18,22 @@ impl From<&StoragePlan<StorageKey>> for StoragePlan<String> {
         .iter()
         .map(|(k, v)| (k.clone(), StorageNode::<String>::from(v)))
         .collect(),
+      key_aliases: that
+        .key_aliases
+        .iter()
+        .map(|(k, v)| (base64::encode(k), *v))
+        .collect(),
+      mounts: that
+        .mounts
+        .iter()
+        .map(|(k, v)| (k.clone(), Self::from(v)))
+        .collect(),
+      share_layouts: that.share_layouts,
+      shared_layouts: that
+        .shared_layouts
+        .iter()
+        .map(|(k, v)| (k.clone(), StorageNode::<String>::from(v)))
+        .collect(),
     }
   }
 }
@@ -34,6 +50,9 @@ impl From<&StorageNode<StorageKey>> for StorageNode<String> {
         .iter()
         .map(|(k, v)| (k.clone(), Self::from(v)))
         .collect(),
+      list: that.list,
+      map: that.map,
+      counter: that.counter,
     }
   }
 }
@@ -48,6 +67,22 @@ impl TryFrom<&StoragePlan<String>> for StoragePlan<StorageKey> {
         .iter()
         .map(|(k, v)| StorageNode::<StorageKey>::try_from(v).map(|v| (k.clone(), v)))
         .collect::<Result<_, StorageKeyConversionError>>()?,
+      key_aliases: that
+        .key_aliases
+        .iter()
+        .map(|(k, v)| decode_storage_key(k).map(|k| (k, *v)))
+        .collect::<Result<_, StorageKeyConversionError>>()?,
+      mounts: that
+        .mounts
+        .iter()
+        .map(|(k, v)| Self::try_from(v).map(|v| (k.clone(), v)))
+        .collect::<Result<_, StorageKeyConversionError>>()?,
+      share_layouts: that.share_layouts,
+      shared_layouts: that
+        .shared_layouts
+        .iter()
+        .map(|(k, v)| StorageNode::<StorageKey>::try_from(v).map(|v| (k.clone(), v)))
+        .collect::<Result<_, StorageKeyConversionError>>()?,
     })
   }
 }
@@ -57,12 +92,7 @@ impl TryFrom<&StorageNode<String>> for StorageNode<StorageKey> {
 
   fn try_from(that: &StorageNode<String>) -> Result<Self, Self::Error> {
     Ok(Self {
-      key: base64::decode(&that.key)
-        .map_err(|_| StorageKeyConversionError::Base64Decode)
-        .and_then(|x| {
-          x.try_into()
-            .map_err(|_| StorageKeyConversionError::Base64Decode)
-        })?,
+      key: decode_storage_key(&that.key)?,
       flattened: that.flattened,
       subspace_reference: that
         .subspace_reference
@@ -85,6 +115,18 @@ impl TryFrom<&StorageNode<String>> for StorageNode<StorageKey> {
         .iter()
         .map(|(k, v)| Self::try_from(v).map(|v| (k.clone(), v)))
         .collect::<Result<_, StorageKeyConversionError>>()?,
+      list: that.list,
+      map: that.map,
+      counter: that.counter,
     })
   }
 }
+
+fn decode_storage_key(key: &str) -> Result<StorageKey, StorageKeyConversionError> {
+  base64::decode(key)
+    .map_err(|_| StorageKeyConversionError::Base64Decode)
+    .and_then(|x| {
+      x.try_into()
+        .map_err(|_| StorageKeyConversionError::Base64Decode)
+    })
+}
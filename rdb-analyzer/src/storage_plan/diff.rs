@@ -0,0 +1,111 @@
+use std::collections::{BTreeMap, BTreeSet};
+
+use super::{StorageKey, StoragePlan};
+
+/// The difference between two storage plans, by field. Fields are dot-separated paths from an
+/// export, as returned by `StoragePlan::field_keys`, and are matched by their location in
+/// `StoragePlan::field_locations` rather than by storage key alone.
+#[derive(Clone, Debug, Default, Eq, PartialEq)]
+pub struct StoragePlanDiff {
+  /// Fields at the same path and with the same storage key in both plans.
+  pub unchanged: Vec<String>,
+
+  /// Fields with a storage key that is not in the old plan.
+  pub added: Vec<PlanDiffNode>,
+
+  /// Fields of the old plan whose storage key is not in the new plan. Their data is kept in the
+  /// store but no longer reachable.
+  pub removed: Vec<PlanDiffNode>,
+
+  /// Fields whose storage key is at a different path in the new plan, e.g. after
+  /// `@rename_from`. Their data is kept.
+  pub moved: Vec<MovedPlanDiffNode>,
+
+  /// Fields at the same path but with a different storage key, e.g. after a type change. The
+  /// data under the old key is no longer reachable.
+  pub rekeyed: Vec<RekeyedPlanDiffNode>,
+}
+
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub struct PlanDiffNode {
+  pub path: String,
+  pub key: StorageKey,
+}
+
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub struct MovedPlanDiffNode {
+  pub old_path: String,
+  pub new_path: String,
+  pub key: StorageKey,
+}
+
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub struct RekeyedPlanDiffNode {
+  pub path: String,
+  pub old_key: StorageKey,
+  pub new_key: StorageKey,
+}
+
+impl StoragePlanDiff {
+  pub fn is_empty(&self) -> bool {
+    self.added.is_empty()
+      && self.removed.is_empty()
+      && self.moved.is_empty()
+      && self.rekeyed.is_empty()
+  }
+}
+
+/// Computes the difference from `old` to `new`. Each list is sorted by path, or by new path for
+/// `moved`.
+pub fn diff_plans(old: &StoragePlan, new: &StoragePlan) -> StoragePlanDiff {
+  // Fields are identified by location rather than by key, since fields of a shared layout have
+  // the same key at several paths.
+  let old_keys = old.field_locations();
+  let new_keys = new.field_locations();
+  let old_paths = old_keys
+    .iter()
+    .map(|(path, location)| (location, path.as_str()))
+    .collect::<BTreeMap<_, _>>();
+  let new_key_set = new_keys.values().collect::<BTreeSet<_>>();
+
+  let mut diff = StoragePlanDiff::default();
+  let mut rekeyed_paths = BTreeSet::new();
+  for (path, location) in &new_keys {
+    let key = location.last().unwrap();
+    if let Some(old_path) = old_paths.get(location) {
+      if *old_path == path.as_str() {
+        diff.unchanged.push(path.clone());
+      } else {
+        diff.moved.push(MovedPlanDiffNode {
+          old_path: old_path.to_string(),
+          new_path: path.clone(),
+          key: *key,
+        });
+      }
+      continue;
+    }
+    match old_keys.get(path) {
+      Some(old_location) if !new_key_set.contains(old_location) => {
+        rekeyed_paths.insert(path.as_str());
+        diff.rekeyed.push(RekeyedPlanDiffNode {
+          path: path.clone(),
+          old_key: *old_location.last().unwrap(),
+          new_key: *key,
+        });
+      }
+      _ => diff.added.push(PlanDiffNode {
+        path: path.clone(),
+        key: *key,
+      }),
+    }
+  }
+  for (path, location) in &old_keys {
+    if !new_key_set.contains(location) && !rekeyed_paths.contains(path.as_str()) {
+      diff.removed.push(PlanDiffNode {
+        path: path.clone(),
+        key: *location.last().unwrap(),
+      });
+    }
+  }
+  diff
+}
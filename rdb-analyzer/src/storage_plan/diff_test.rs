@@ -0,0 +1,154 @@
+use bumpalo::Bump;
+
+use crate::schema::{
+  compile::{compile, CompiledSchema},
+  grammar::parse,
+};
+
+use super::{
+  diff::{diff_plans, MovedPlanDiffNode, PlanDiffNode, RekeyedPlanDiffNode},
+  planner::generate_plan_for_schema,
+  StoragePlan,
+};
+
+fn compile_schema(text: &str) -> CompiledSchema {
+  let alloc = Bump::new();
+  let ast = parse(&alloc, text).unwrap();
+  compile(&ast).unwrap()
+}
+
+#[test]
+fn diff_fields() {
+  let _ = pretty_env_logger::try_init();
+  let old_schema = compile_schema(
+    r#"
+  type Item {
+    @primary
+    id: string,
+    a: int64,
+    b: int64,
+    c: int64,
+  }
+  export set<Item> items;
+  "#,
+  );
+  let new_schema = compile_schema(
+    r#"
+  type Item {
+    @primary
+    id: string,
+    @rename_from("a")
+    a2: int64,
+    b: string,
+    d: int64,
+  }
+  export set<Item> items;
+  "#,
+  );
+  let old_plan =
+    generate_plan_for_schema(&Default::default(), &Default::default(), &old_schema).unwrap();
+  let new_plan = generate_plan_for_schema(&old_plan, &old_schema, &new_schema).unwrap();
+  let old_keys = old_plan.field_keys();
+  let new_keys = new_plan.field_keys();
+
+  let diff = diff_plans(&old_plan, &new_plan);
+  assert_eq!(diff.unchanged, vec!["items", "items.id"]);
+  assert_eq!(
+    diff.added,
+    vec![PlanDiffNode {
+      path: "items.d".into(),
+      key: new_keys["items.d"],
+    }]
+  );
+  assert_eq!(
+    diff.removed,
+    vec![PlanDiffNode {
+      path: "items.c".into(),
+      key: old_keys["items.c"],
+    }]
+  );
+  assert_eq!(
+    diff.moved,
+    vec![MovedPlanDiffNode {
+      old_path: "items.a".into(),
+      new_path: "items.a2".into(),
+      key: old_keys["items.a"],
+    }]
+  );
+  assert_eq!(
+    diff.rekeyed,
+    vec![RekeyedPlanDiffNode {
+      path: "items.b".into(),
+      old_key: old_keys["items.b"],
+      new_key: new_keys["items.b"],
+    }]
+  );
+  assert!(!diff.is_empty());
+  assert!(diff_plans(&new_plan, &new_plan).is_empty());
+}
+
+#[test]
+fn diff_shared_layouts() {
+  let _ = pretty_env_logger::try_init();
+  let old_schema = compile_schema(
+    r#"
+  type Address {
+    city: string,
+  }
+  type Company {
+    hq: Address,
+    branch: Address,
+  }
+  export Company company;
+  "#,
+  );
+  let new_schema = compile_schema(
+    r#"
+  type Address {
+    city: string,
+  }
+  type Company {
+    hq: Address,
+    branch: Address,
+    mail: Address,
+  }
+  export Company company;
+  "#,
+  );
+  let empty_plan = StoragePlan {
+    share_layouts: true,
+    ..Default::default()
+  };
+  let old_plan = generate_plan_for_schema(&empty_plan, &Default::default(), &old_schema).unwrap();
+  let new_plan = generate_plan_for_schema(&old_plan, &old_schema, &new_schema).unwrap();
+  let new_keys = new_plan.field_keys();
+
+  // `company.mail.city` has the key of `company.hq.city`, but not its data.
+  let diff = diff_plans(&old_plan, &new_plan);
+  assert_eq!(
+    diff.unchanged,
+    vec![
+      "company",
+      "company.branch",
+      "company.branch.city",
+      "company.hq",
+      "company.hq.city"
+    ]
+  );
+  assert_eq!(
+    diff.added,
+    vec![
+      PlanDiffNode {
+        path: "company.mail".into(),
+        key: new_keys["company.mail"],
+      },
+      PlanDiffNode {
+        path: "company.mail.city".into(),
+        key: new_keys["company.mail.city"],
+      },
+    ]
+  );
+  assert!(diff.moved.is_empty());
+  assert!(diff.removed.is_empty());
+  assert!(diff.rekeyed.is_empty());
+}
@@ -0,0 +1,480 @@
+//! Property tests of plan migrations, over random schemas and random edits to them.
+
+use std::{
+  collections::{BTreeMap, BTreeSet},
+  convert::TryFrom,
+};
+
+use bumpalo::Bump;
+use proptest::prelude::*;
+
+use crate::{
+  schema::{
+    compile::{compile, CompiledSchema, FieldAnnotation, FieldType},
+    grammar::parse,
+  },
+  storage_plan::{StorageKey, StorageNode, StoragePlan},
+};
+
+use super::planner::{assign_key_aliases, generate_plan_for_schema};
+
+#[derive(Copy, Clone, Debug)]
+enum Prim {
+  Int64,
+  Double,
+  String,
+  Bytes,
+}
+
+/// Type of a field. Type references are taken modulo the number of types in the schema.
+#[derive(Clone, Debug)]
+enum Ty {
+  Prim(Prim),
+  Counter,
+  Table(usize),
+  Set(usize),
+  List(Prim),
+  Map(Prim),
+}
+
+#[derive(Clone, Debug)]
+struct Field {
+  name: String,
+  ty: Ty,
+  rename_from: Option<String>,
+}
+
+#[derive(Clone, Debug)]
+struct TypeModel {
+  primary: Prim,
+  fields: Vec<Field>,
+}
+
+#[derive(Clone, Debug)]
+struct SchemaModel {
+  types: Vec<TypeModel>,
+  exports: Vec<Field>,
+
+  /// Names are never reused, so that a field is only ever matched with an old one through its
+  /// name or an explicit `@rename_from`.
+  next_name: usize,
+}
+
+#[derive(Clone, Debug)]
+enum EditKind {
+  AddField,
+  RemoveField,
+  ChangeField,
+  RenameField,
+  AddExport,
+  RemoveExport,
+  ChangeExport,
+}
+
+#[derive(Clone, Debug)]
+struct Edit {
+  kind: EditKind,
+  target: usize,
+  index: usize,
+  ty: Ty,
+}
+
+fn prim() -> impl Strategy<Value = Prim> {
+  prop_oneof![
+    Just(Prim::Int64),
+    Just(Prim::Double),
+    Just(Prim::String),
+    Just(Prim::Bytes),
+  ]
+}
+
+fn field_ty() -> impl Strategy<Value = Ty> {
+  prop_oneof![
+    4 => prim().prop_map(Ty::Prim),
+    1 => Just(Ty::Counter),
+    2 => any::<usize>().prop_map(Ty::Table),
+    2 => any::<usize>().prop_map(Ty::Set),
+    1 => prim().prop_map(Ty::List),
+    1 => prim().prop_map(Ty::Map),
+  ]
+}
+
+fn export_ty() -> impl Strategy<Value = Ty> {
+  prop_oneof![
+    1 => prim().prop_map(Ty::Prim),
+    2 => any::<usize>().prop_map(Ty::Table),
+    2 => any::<usize>().prop_map(Ty::Set),
+  ]
+}
+
+fn schema_model() -> impl Strategy<Value = SchemaModel> {
+  let ty = (
+    prop_oneof![Just(Prim::Int64), Just(Prim::String), Just(Prim::Bytes)],
+    prop::collection::vec(field_ty(), 0..5),
+  );
+  (
+    prop::collection::vec(ty, 1..4),
+    prop::collection::vec(export_ty(), 1..4),
+  )
+    .prop_map(|(types, exports)| {
+      let mut model = SchemaModel {
+        types: vec![],
+        exports: vec![],
+        next_name: 0,
+      };
+      for (primary, fields) in types {
+        let fields = fields.into_iter().map(|x| model.field(x)).collect();
+        model.types.push(TypeModel { primary, fields });
+      }
+      for ty in exports {
+        let export = model.field(ty);
+        model.exports.push(export);
+      }
+      model
+    })
+}
+
+fn edit() -> impl Strategy<Value = Edit> {
+  let kind = prop_oneof![
+    3 => Just(EditKind::AddField),
+    2 => Just(EditKind::RemoveField),
+    3 => Just(EditKind::ChangeField),
+    2 => Just(EditKind::RenameField),
+    1 => Just(EditKind::AddExport),
+    1 => Just(EditKind::RemoveExport),
+    1 => Just(EditKind::ChangeExport),
+  ];
+  (kind, any::<usize>(), any::<usize>(), field_ty()).prop_map(|(kind, target, index, ty)| Edit {
+    kind,
+    target,
+    index,
+    ty,
+  })
+}
+
+impl SchemaModel {
+  fn field(&mut self, ty: Ty) -> Field {
+    self.next_name += 1;
+    Field {
+      name: format!("f{}", self.next_name),
+      ty,
+      rename_from: None,
+    }
+  }
+
+  fn apply(&mut self, edit: &Edit) {
+    let target = edit.target % self.types.len();
+    let ty = match (&edit.kind, &edit.ty) {
+      // Exports cannot be counters, lists or maps.
+      (EditKind::AddExport | EditKind::ChangeExport, Ty::Counter | Ty::List(_) | Ty::Map(_)) => {
+        Ty::Prim(Prim::Int64)
+      }
+      (_, x) => x.clone(),
+    };
+    match edit.kind {
+      EditKind::AddField => {
+        let field = self.field(ty);
+        self.types[target].fields.push(field);
+      }
+      EditKind::AddExport => {
+        let export = self.field(ty);
+        self.exports.push(export);
+      }
+      EditKind::RemoveField | EditKind::ChangeField | EditKind::RenameField => {
+        let name = self.field(Ty::Counter).name;
+        let fields = &mut self.types[target].fields;
+        if fields.is_empty() {
+          return;
+        }
+        let index = edit.index % fields.len();
+        match edit.kind {
+          EditKind::RemoveField => {
+            fields.remove(index);
+          }
+          EditKind::ChangeField => fields[index].ty = ty,
+          _ => {
+            let old_name = std::mem::replace(&mut fields[index].name, name);
+            fields[index].rename_from = Some(old_name);
+          }
+        }
+      }
+      EditKind::RemoveExport | EditKind::ChangeExport => {
+        // Schemas without exports are valid, but not interesting.
+        if self.exports.len() < 2 {
+          return;
+        }
+        let index = edit.index % self.exports.len();
+        match edit.kind {
+          EditKind::RemoveExport => {
+            self.exports.remove(index);
+          }
+          _ => self.exports[index].ty = ty,
+        }
+      }
+    }
+  }
+
+  fn render_ty(&self, ty: &Ty) -> String {
+    match ty {
+      Ty::Prim(x) => prim_name(*x).to_string(),
+      Ty::Counter => "int64".to_string(),
+      Ty::Table(x) => format!("T{}", x % self.types.len()),
+      Ty::Set(x) => format!("set<T{}>", x % self.types.len()),
+      Ty::List(x) => format!("list<{}>", prim_name(*x)),
+      Ty::Map(x) => format!("map<string, {}>", prim_name(*x)),
+    }
+  }
+
+  fn render(&self) -> String {
+    let mut out = String::new();
+    for (i, ty) in self.types.iter().enumerate() {
+      out.push_str(&format!(
+        "type T{} {{\n  @primary\n  id: {},\n",
+        i,
+        prim_name(ty.primary)
+      ));
+      for field in &ty.fields {
+        if let Some(x) = &field.rename_from {
+          out.push_str(&format!("  @rename_from({:?})\n", x));
+        }
+        if matches!(field.ty, Ty::Counter) {
+          out.push_str("  @counter\n");
+        }
+        out.push_str(&format!(
+          "  {}: {},\n",
+          field.name,
+          self.render_ty(&field.ty)
+        ));
+      }
+      out.push_str("}\n");
+    }
+    for export in &self.exports {
+      out.push_str(&format!(
+        "export {} {};\n",
+        self.render_ty(&export.ty),
+        export.name
+      ));
+    }
+    out
+  }
+
+  fn compile(&self) -> CompiledSchema {
+    let text = self.render();
+    let alloc = Bump::new();
+    let ast = parse(&alloc, &text).unwrap_or_else(|e| panic!("{}\n{}", e, text));
+    compile(&ast).unwrap_or_else(|e| panic!("{}\n{}", e, text))
+  }
+}
+
+fn prim_name(x: Prim) -> &'static str {
+  match x {
+    Prim::Int64 => "int64",
+    Prim::Double => "double",
+    Prim::String => "string",
+    Prim::Bytes => "bytes",
+  }
+}
+
+/// A storage node with the type of the field it stores.
+struct NodeInfo {
+  key: StorageKey,
+  ty: FieldType,
+  counter: bool,
+  rename_from: Vec<String>,
+}
+
+/// Collects the storage nodes of `plan` by path. Members of a set are at the path of the set
+/// followed by `[]`.
+fn collect_nodes(schema: &CompiledSchema, plan: &StoragePlan) -> BTreeMap<String, NodeInfo> {
+  fn walk(
+    schema: &CompiledSchema,
+    node: &StorageNode,
+    ty: &FieldType,
+    annotations: &[FieldAnnotation],
+    path: String,
+    out: &mut BTreeMap<String, NodeInfo>,
+  ) {
+    match ty {
+      FieldType::Set(member) => walk(
+        schema,
+        node.set.as_ref().unwrap(),
+        member,
+        &[],
+        format!("{}[]", path),
+        out,
+      ),
+      // Recursive references have no children.
+      FieldType::Table(name) if node.subspace_reference.is_none() => {
+        for (field_name, (field_ty, field_annotations)) in &schema.types[name].fields {
+          walk(
+            schema,
+            &node.children[field_name],
+            field_ty,
+            field_annotations,
+            format!("{}.{}", path, field_name),
+            out,
+          );
+        }
+      }
+      _ => {}
+    }
+    out.insert(
+      path,
+      NodeInfo {
+        key: node.key,
+        ty: ty.clone(),
+        counter: node.counter,
+        rename_from: annotations
+          .iter()
+          .filter_map(|x| match x {
+            FieldAnnotation::RenameFrom(x) => Some(x.clone()),
+            _ => None,
+          })
+          .collect(),
+      },
+    );
+  }
+
+  let mut out = BTreeMap::new();
+  for (name, ty) in &schema.exports {
+    walk(
+      schema,
+      &plan.nodes[name],
+      ty,
+      &[],
+      name.to_string(),
+      &mut out,
+    );
+  }
+  out
+}
+
+/// Checks that a field keeps its key in the migration from `old_plan` to `new_plan` if it, and
+/// every field on its path, keeps its type and is found under the same name or the name in its
+/// `@rename_from`.
+fn check_migration(
+  old_schema: &CompiledSchema,
+  old_plan: &StoragePlan,
+  new_schema: &CompiledSchema,
+  new_plan: &StoragePlan,
+) {
+  let old_nodes = collect_nodes(old_schema, old_plan);
+  let new_nodes = collect_nodes(new_schema, new_plan);
+
+  // Parents sort before their children.
+  let mut kept = BTreeMap::<&str, &str>::new();
+  for (path, node) in &new_nodes {
+    let old_path = if let Some(set_path) = path.strip_suffix("[]") {
+      kept.get(set_path).map(|x| format!("{}[]", x))
+    } else if let Some(dot) = path.rfind('.') {
+      let (parent, name) = (&path[..dot], &path[dot + 1..]);
+      kept.get(parent).and_then(|old_parent| {
+        std::iter::once(name)
+          .chain(node.rename_from.iter().map(|x| x.as_str()))
+          .map(|x| format!("{}.{}", old_parent, x))
+          .find(|x| old_nodes.contains_key(x))
+      })
+    } else {
+      Some(path.clone())
+    };
+    let (old_path, old_node) = match old_path.and_then(|x| old_nodes.get_key_value(&x)) {
+      Some(x) => x,
+      None => continue,
+    };
+    if old_node.ty == node.ty && old_node.counter == node.counter {
+      assert_eq!(
+        old_node.key, node.key,
+        "key of `{}` (`{}` before) not kept",
+        path, old_path
+      );
+      kept.insert(path, old_path);
+    }
+  }
+}
+
+/// Checks that keys of `new_plan` are unique, are only shared with `old_plan`, a plan it was
+/// derived from, for fields of the same type, and keep their aliases if aliasing is enabled.
+fn check_key_reuse(
+  old_schema: &CompiledSchema,
+  old_plan: &StoragePlan,
+  new_schema: &CompiledSchema,
+  new_plan: &StoragePlan,
+) {
+  let old_nodes = collect_nodes(old_schema, old_plan);
+  let new_nodes = collect_nodes(new_schema, new_plan);
+  let old_types = old_nodes
+    .iter()
+    .map(|(path, x)| (x.key, (path, x)))
+    .collect::<BTreeMap<_, _>>();
+  let mut keys = BTreeSet::new();
+  for (path, node) in &new_nodes {
+    assert!(keys.insert(node.key), "key of `{}` used twice", path);
+    if let Some((old_path, old_node)) = old_types.get(&node.key) {
+      assert!(
+        old_node.ty == node.ty && old_node.counter == node.counter,
+        "key of `{}` reused for `{}` with a different type",
+        old_path,
+        path
+      );
+    }
+  }
+
+  if !old_plan.key_aliases.is_empty() {
+    for (key, alias) in &old_plan.key_aliases {
+      assert_eq!(new_plan.key_aliases.get(key), Some(alias));
+    }
+    for node in new_nodes.values() {
+      assert!(new_plan.key_aliases.contains_key(&node.key));
+    }
+    let aliases = new_plan.key_aliases.values().collect::<BTreeSet<_>>();
+    assert_eq!(aliases.len(), new_plan.key_aliases.len());
+  }
+}
+
+fn check_round_trip(plan: &StoragePlan) {
+  let compressed = plan.serialize_compressed().unwrap();
+  let decompressed = StoragePlan::deserialize_compressed(&compressed).unwrap();
+  assert_eq!(decompressed.serialize_compressed().unwrap(), compressed);
+
+  let yaml = serde_yaml::to_string(&StoragePlan::<String>::from(plan)).unwrap();
+  let parsed: StoragePlan<String> = serde_yaml::from_str(&yaml).unwrap();
+  let parsed = StoragePlan::try_from(&parsed).unwrap();
+  assert_eq!(parsed.serialize_compressed().unwrap(), compressed);
+  assert_eq!(parsed.to_string(), plan.to_string());
+}
+
+proptest! {
+  #[test]
+  fn migrations_preserve_keys(
+    model in schema_model(),
+    edits1 in prop::collection::vec(edit(), 0..6),
+    edits2 in prop::collection::vec(edit(), 0..6),
+    aliases in any::<bool>(),
+  ) {
+    let _ = pretty_env_logger::try_init();
+    let mut model = model;
+    let schema0 = model.compile();
+    let mut plan0 = generate_plan_for_schema(&Default::default(), &Default::default(), &schema0).unwrap();
+    if aliases {
+      assign_key_aliases(&mut plan0).unwrap();
+    }
+    check_round_trip(&plan0);
+
+    let mut schemas = vec![schema0];
+    let mut plans = vec![plan0];
+    for edits in [edits1, edits2] {
+      for edit in &edits {
+        model.apply(edit);
+      }
+      let schema = model.compile();
+      let plan = generate_plan_for_schema(plans.last().unwrap(), schemas.last().unwrap(), &schema).unwrap();
+      check_round_trip(&plan);
+      check_migration(schemas.last().unwrap(), plans.last().unwrap(), &schema, &plan);
+      for (old_schema, old_plan) in schemas.iter().zip(plans.iter()) {
+        check_key_reuse(old_schema, old_plan, &schema, &plan);
+      }
+      schemas.push(schema);
+      plans.push(plan);
+    }
+  }
+}
@@ -3,16 +3,51 @@ use serde::{Deserialize, Serialize};
 use std::{collections::BTreeMap, fmt::Display, io::Write, sync::Arc};
 
 pub mod conversion;
+pub mod diff;
 pub mod planner;
 
+#[cfg(test)]
+mod diff_test;
+#[cfg(test)]
+mod migration_test;
 #[cfg(test)]
 mod planner_test;
 
 pub type StorageKey = [u8; 12];
 
+/// A short replacement for a storage key in composed keys. Encoded big-endian.
+pub type KeyAlias = u16;
+
 #[derive(Default, Clone, Serialize, Deserialize)]
-pub struct StoragePlan<SK = StorageKey> {
+pub struct StoragePlan<SK: Ord = StorageKey> {
   pub nodes: BTreeMap<Arc<str>, StorageNode<SK>>,
+
+  /// Aliases of storage keys. When non-empty, composed keys use the alias of each storage key in
+  /// place of the key itself, and every key in `nodes` must have one.
+  ///
+  /// See `planner::assign_key_aliases`.
+  #[serde(default, skip_serializing_if = "BTreeMap::is_empty")]
+  pub key_aliases: BTreeMap<SK, KeyAlias>,
+
+  /// Plans of namespaces mounted read-only into this one. Only present in plans built for query
+  /// execution.
+  ///
+  /// See `data::mount`.
+  #[serde(default, skip_serializing_if = "BTreeMap::is_empty")]
+  pub mounts: BTreeMap<Arc<str>, StoragePlan<SK>>,
+
+  /// Whether the planner shares one layout between the table fields of a repeated type. Once
+  /// enabled, sharing stays enabled for all later plans.
+  ///
+  /// See `planner::generate_plan_for_schema`.
+  #[serde(default, skip_serializing_if = "std::ops::Not::not")]
+  pub share_layouts: bool,
+
+  /// Layouts shared by the table fields of repeated types, by type name. A field with a shared
+  /// layout is a subspace reference to the key of the layout, which is resolved here when it is
+  /// not the key of an enclosing node.
+  #[serde(default, skip_serializing_if = "BTreeMap::is_empty")]
+  pub shared_layouts: BTreeMap<Arc<str>, StorageNode<SK>>,
 }
 
 #[derive(Clone, Debug, Serialize, Deserialize)]
@@ -22,6 +57,19 @@ pub struct StorageNode<SK = StorageKey> {
   pub subspace_reference: Option<SK>,
   pub set: Option<Box<StorageNode<SK>>>,
   pub children: BTreeMap<Arc<str>, StorageNode<SK>>,
+
+  /// Whether this is a `list<T>`. See `PathWalker::list_element_key`.
+  #[serde(default, skip_serializing_if = "std::ops::Not::not")]
+  pub list: bool,
+
+  /// Whether this is a `map<K, V>`. See `PathWalker::map_entry_key`.
+  #[serde(default, skip_serializing_if = "std::ops::Not::not")]
+  pub map: bool,
+
+  /// Whether this is a `@counter` or `@version` field, stored as a little-endian integer. See
+  /// `kv::encode_counter`.
+  #[serde(default, skip_serializing_if = "std::ops::Not::not")]
+  pub counter: bool,
 }
 
 impl StoragePlan {
@@ -34,6 +82,77 @@ impl StoragePlan {
   pub fn deserialize_compressed(data: &[u8]) -> Result<Self> {
     Ok(rmp_serde::from_read(snap::read::FrameDecoder::new(data))?)
   }
+
+  /// Returns the storage key of each field in the plan, by dot-separated path from an export,
+  /// e.g. `items.name`. Fields of set members are under the path of the set, and fields of
+  /// shared layouts are under the path of each field that uses the layout.
+  pub fn field_keys(&self) -> BTreeMap<String, StorageKey> {
+    self
+      .field_locations()
+      .into_iter()
+      .map(|(path, mut location)| (path, location.pop().unwrap()))
+      .collect()
+  }
+
+  /// Like `field_keys`, but each storage key is preceded by the keys of the fields with a shared
+  /// layout that the field is in. Fields have the same data if and only if they have the same
+  /// location.
+  pub fn field_locations(&self) -> BTreeMap<String, Vec<StorageKey>> {
+    let mut out = BTreeMap::new();
+    for (name, node) in &self.nodes {
+      node.collect_field_locations(self, name.to_string(), &mut vec![], &mut vec![], &mut out);
+    }
+    out
+  }
+
+  /// The shared layout with the storage key `key`.
+  pub fn shared_layout(&self, key: &StorageKey) -> Option<&StorageNode> {
+    self.shared_layouts.values().find(|x| x.key == *key)
+  }
+}
+
+impl StorageNode {
+  /// `sites` are the keys of the enclosing fields with a shared layout, and `layouts` the keys of
+  /// their layouts, so that mutually recursive layouts are expanded once on each path.
+  fn collect_field_locations(
+    &self,
+    plan: &StoragePlan,
+    path: String,
+    sites: &mut Vec<StorageKey>,
+    layouts: &mut Vec<StorageKey>,
+    out: &mut BTreeMap<String, Vec<StorageKey>>,
+  ) {
+    let mut location = sites.clone();
+    location.push(self.key);
+    out.insert(path.clone(), location);
+
+    let node = self.set.as_deref().unwrap_or(self);
+    let layout = node
+      .subspace_reference
+      .filter(|x| !layouts.contains(x))
+      .and_then(|x| plan.shared_layout(&x));
+    let children = match layout {
+      Some(layout) => {
+        sites.push(self.key);
+        layouts.push(layout.key);
+        &layout.children
+      }
+      None => &node.children,
+    };
+    for (child_name, child_node) in children {
+      child_node.collect_field_locations(
+        plan,
+        format!("{}.{}", path, child_name),
+        sites,
+        layouts,
+        out,
+      );
+    }
+    if layout.is_some() {
+      sites.pop();
+      layouts.pop();
+    }
+  }
 }
 
 impl Display for StoragePlan {
@@ -41,6 +160,12 @@ impl Display for StoragePlan {
     for (node_name, node) in &self.nodes {
       write!(f, "top-level node: {}{}", node_name, node)?;
     }
+    for (type_name, node) in &self.shared_layouts {
+      write!(f, "shared layout: {}{}", type_name, node)?;
+    }
+    for (key, alias) in &self.key_aliases {
+      write!(f, "key alias: {} {:04x}\n", hex::encode(key), alias)?;
+    }
     Ok(())
   }
 }
@@ -58,6 +183,15 @@ impl StorageNode {
       },
       if self.flattened { " flattened" } else { "" },
     )?;
+    if self.list {
+      write!(f, " list")?;
+    }
+    if self.map {
+      write!(f, " map")?;
+    }
+    if self.counter {
+      write!(f, " counter")?;
+    }
     write!(f, "\n")?;
 
     match &self.set {
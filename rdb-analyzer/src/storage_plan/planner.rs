@@ -1,5 +1,6 @@
 use std::{
-  collections::{BTreeMap, HashMap, HashSet},
+  collections::{BTreeMap, BTreeSet, HashMap, HashSet},
+  convert::TryFrom,
   sync::Arc,
   time::{SystemTime, UNIX_EPOCH},
 };
@@ -8,9 +9,10 @@ use anyhow::Result;
 use byteorder::{BigEndian, ByteOrder};
 use rand::RngCore;
 
+use crate::data::mount::MOUNT_KEY_MARKER;
 use crate::schema::compile::{CompiledSchema, FieldAnnotation, FieldAnnotationList, FieldType};
 
-use super::{StorageKey, StorageNode, StoragePlan};
+use super::{KeyAlias, StorageKey, StorageNode, StoragePlan};
 use thiserror::Error;
 
 #[derive(Error, Debug)]
@@ -20,6 +22,9 @@ pub enum PlannerError {
 
   #[error("set member type `{0}` has no primary key")]
   SetMemberTypeWithoutPrimaryKey(Arc<str>),
+
+  #[error("key aliases exhausted")]
+  KeyAliasesExhausted,
 }
 
 struct PlanState<'a> {
@@ -28,6 +33,31 @@ struct PlanState<'a> {
   recursive_types: HashSet<Arc<str>>,
   set_member_types: HashSet<Arc<str>>,
   fields_in_stack: HashMap<Arc<str>, StorageKey>,
+
+  /// Types whose table fields share a layout. Empty if layout sharing is disabled.
+  shared_types: HashSet<Arc<str>>,
+
+  /// Shared layouts of the old plan.
+  old_layouts: HashMap<Arc<str>, OldTreePoint<'a>>,
+
+  /// Keys of the shared layouts generated or being generated.
+  layout_keys: HashMap<Arc<str>, StorageKey>,
+
+  /// Shared layouts of the new plan.
+  layouts: BTreeMap<Arc<str>, StorageNode>,
+}
+
+/// Where a field is generated.
+#[derive(Copy, Clone, Eq, PartialEq)]
+enum FieldSite {
+  /// An export or a set member. Always generated inline.
+  Root,
+
+  /// A field of a table. Uses a shared layout if its type has one.
+  Field,
+
+  /// The root of a shared layout.
+  Layout,
 }
 
 /// A point on the old tree.
@@ -71,12 +101,18 @@ impl<'a> OldTreePoint<'a> {
   fn validate_type(
     self,
     expected_ty: &FieldType,
-    _expected_annotations: &[FieldAnnotation],
+    expected_annotations: &[FieldAnnotation],
   ) -> Option<Self> {
     if self.ty != expected_ty {
       return None;
     }
 
+    // Counters and versions are encoded differently from other int64 fields.
+    if self.node.counter != (expected_annotations.is_counter() || expected_annotations.is_version())
+    {
+      return None;
+    }
+
     Some(self)
   }
 
@@ -167,12 +203,66 @@ pub fn generate_plan_for_schema(
     set_member_types.len()
   );
 
+  // Shared layouts of the old plan are only reused if their type still exists in the old schema.
+  let old_layout_types = old_plan
+    .shared_layouts
+    .keys()
+    .filter(|x| old_schema.types.contains_key(*x))
+    .map(|x| (x.clone(), FieldType::Table(x.clone())))
+    .collect::<BTreeMap<_, _>>();
+  let old_layouts = old_plan
+    .shared_layouts
+    .iter()
+    .filter_map(|(name, node)| {
+      old_layout_types.get(name).map(|ty| {
+        (
+          name.clone(),
+          OldTreePoint {
+            name,
+            ty,
+            _annotations: &[],
+            node,
+          },
+        )
+      })
+    })
+    .collect::<HashMap<_, _>>();
+
+  // Collect types with more than one table field. A type keeps its shared layout while it has
+  // any table field.
+  let mut shared_types: HashSet<Arc<str>> = HashSet::new();
+  if old_plan.share_layouts {
+    let mut num_sites: HashMap<Arc<str>, usize> = HashMap::new();
+    for export_field in schema.exports.values() {
+      count_table_fields(
+        export_field,
+        schema,
+        FieldSite::Root,
+        &mut HashSet::new(),
+        &mut num_sites,
+      )?;
+    }
+    shared_types = num_sites
+      .into_iter()
+      .filter(|(name, n)| *n > 1 || (*n == 1 && old_layouts.contains_key(name)))
+      .map(|(name, _)| name)
+      .collect();
+  }
+  log::debug!(
+    "collected {} types with a shared layout",
+    shared_types.len()
+  );
+
   let mut plan_st = PlanState {
     old_schema,
     used_storage_keys: HashSet::new(),
     recursive_types,
     fields_in_stack: HashMap::new(),
     set_member_types,
+    shared_types,
+    old_layouts,
+    layout_keys: HashMap::new(),
+    layouts: BTreeMap::new(),
   };
 
   // Deduplicate also against storage keys used in the previous plan.
@@ -181,7 +271,11 @@ pub fn generate_plan_for_schema(
   // versions, but in that case the storage key generation mechanism should be enough
   // to prevent duplicates. (unless we generate a lot of schemas within a single
   // millisecond?)
-  for (_, node) in &old_plan.nodes {
+  for node in old_plan
+    .nodes
+    .values()
+    .chain(old_plan.shared_layouts.values())
+  {
     collect_storage_keys(node, &mut plan_st.used_storage_keys);
   }
   log::debug!(
@@ -190,6 +284,10 @@ pub fn generate_plan_for_schema(
   );
   let mut plan = StoragePlan {
     nodes: BTreeMap::new(),
+    key_aliases: old_plan.key_aliases.clone(),
+    mounts: BTreeMap::new(),
+    share_layouts: old_plan.share_layouts,
+    shared_layouts: BTreeMap::new(),
   };
 
   for (export_name, export_field) in &schema.exports {
@@ -206,12 +304,62 @@ pub fn generate_plan_for_schema(
       })
       .and_then(|x| x.validate_type(export_field, &[]));
 
-    let node = generate_field(&mut plan_st, schema, export_field, &[], old_point)?;
+    let node = generate_field(
+      &mut plan_st,
+      schema,
+      export_field,
+      &[],
+      old_point,
+      FieldSite::Root,
+    )?;
     plan.nodes.insert(export_name.clone(), node);
   }
+  plan.shared_layouts = std::mem::take(&mut plan_st.layouts);
+
+  // Once enabled, aliasing stays enabled for all later plans.
+  if !plan.key_aliases.is_empty() {
+    assign_key_aliases(&mut plan)?;
+  }
   Ok(plan)
 }
 
+/// Assigns a key alias to every storage key in `plan` that does not have one yet, enabling key
+/// aliasing if it was disabled.
+///
+/// New aliases are allocated above all existing ones, so an alias is never reused even after its
+/// storage key is retired. Aliases with the leading byte `0x01` are skipped: this is the leading
+/// byte of every storage key generated before 2039, and skipping it keeps aliased keys disjoint
+/// from data written under plans without aliases. The leading byte `0xff` is reserved for keys of
+/// mounted namespaces (see `data::mount`).
+pub fn assign_key_aliases(plan: &mut StoragePlan) -> Result<()> {
+  let mut keys = BTreeSet::new();
+  for node in plan.nodes.values().chain(plan.shared_layouts.values()) {
+    collect_storage_keys(node, &mut keys);
+  }
+
+  let mut next_alias = plan
+    .key_aliases
+    .values()
+    .max()
+    .map(|x| u32::from(*x) + 1)
+    .unwrap_or(0);
+  for key in keys {
+    if plan.key_aliases.contains_key(&key) {
+      continue;
+    }
+    if next_alias >> 8 == 0x01 {
+      next_alias = 0x0200;
+    }
+    let alias = KeyAlias::try_from(next_alias)
+      .ok()
+      .filter(|x| x >> 8 != MOUNT_KEY_MARKER as u16)
+      .ok_or_else(|| PlannerError::KeyAliasesExhausted)?;
+    plan.key_aliases.insert(key, alias);
+    next_alias += 1;
+  }
+  Ok(())
+}
+
 /// The `old_point` parameter must be validated to match `field` before being passed to this function.
 fn generate_field(
   plan_st: &mut PlanState,
@@ -219,6 +367,7 @@ fn generate_field(
   field: &FieldType,
   annotations: &[FieldAnnotation],
   old_point: Option<OldTreePoint>,
+  site: FieldSite,
 ) -> Result<StorageNode> {
   match field {
     FieldType::Table(table_name) => {
@@ -234,9 +383,39 @@ fn generate_field(
           subspace_reference: Some(key),
           set: None,
           children: BTreeMap::new(),
+          list: false,
+          map: false,
+          counter: false,
         });
       }
 
+      // Then, whether this field can use a shared layout. A field of the old plan that was
+      // generated inline stays inline, to keep its data.
+      if site == FieldSite::Field && plan_st.shared_types.contains(table_name) {
+        let old_layout_key = plan_st.old_layouts.get(table_name).map(|x| x.node.key);
+        let uses_layout = match old_point {
+          Some(x) => {
+            x.node.subspace_reference.is_some() && x.node.subspace_reference == old_layout_key
+          }
+          None => true,
+        };
+        if uses_layout {
+          let layout_key = ensure_layout(plan_st, schema, table_name)?;
+          return Ok(StorageNode {
+            key: old_point
+              .map(|x| x.node.key)
+              .unwrap_or_else(|| rand_storage_key(plan_st)),
+            flattened: false,
+            subspace_reference: Some(layout_key),
+            set: None,
+            children: BTreeMap::new(),
+            list: false,
+            map: false,
+            counter: false,
+          });
+        }
+      }
+
       let ty = schema
         .types
         .get(table_name)
@@ -248,6 +427,10 @@ fn generate_field(
         .map(|x| x.node.key)
         .unwrap_or_else(|| rand_storage_key(plan_st));
 
+      if site == FieldSite::Layout {
+        plan_st.layout_keys.insert(table_name.clone(), storage_key);
+      }
+
       if plan_st.recursive_types.contains(table_name) {
         is_recursive_type = true;
         plan_st
@@ -282,6 +465,7 @@ fn generate_field(
           &subfield.1 .0,
           &subfield.1 .1,
           subfield_old_point,
+          FieldSite::Field,
         ) {
           Ok(x) => {
             children.insert(subfield.0.clone(), x);
@@ -290,7 +474,7 @@ fn generate_field(
             return Err(e);
           }
         }
-        has_primary_key |= annotations.as_slice().is_primary();
+        has_primary_key |= annotations.as_slice().is_in_primary_key();
       }
 
       if is_recursive_type {
@@ -307,6 +491,9 @@ fn generate_field(
         subspace_reference: None,
         set: None,
         children,
+        list: false,
+        map: false,
+        counter: false,
       })
     }
     FieldType::Primitive(_) => {
@@ -319,6 +506,9 @@ fn generate_field(
         subspace_reference: None,
         set: None,
         children: BTreeMap::new(),
+        list: false,
+        map: false,
+        counter: annotations.is_counter() || annotations.is_version(),
       })
     }
     FieldType::Set(x) => {
@@ -331,6 +521,7 @@ fn generate_field(
         old_point
           .and_then(|x| x.reduce_set())
           .and_then(|y| y.validate_type(x, annotations)),
+        FieldSite::Root,
       )?;
       Ok(StorageNode {
         key: old_point
@@ -340,11 +531,74 @@ fn generate_field(
         subspace_reference: None,
         set: Some(Box::new(inner)),
         children: BTreeMap::new(),
+        list: false,
+        map: false,
+        counter: false,
+      })
+    }
+    FieldType::List(_) => {
+      // Members are stored under the list's key by index. See `PathWalker::list_element_key`.
+      Ok(StorageNode {
+        key: old_point
+          .map(|x| x.node.key)
+          .unwrap_or_else(|| rand_storage_key(plan_st)),
+        flattened: false,
+        subspace_reference: None,
+        set: None,
+        children: BTreeMap::new(),
+        list: true,
+        map: false,
+        counter: false,
+      })
+    }
+    FieldType::Map(..) => {
+      // Entries are stored under the map's key by key. See `PathWalker::map_entry_key`.
+      Ok(StorageNode {
+        key: old_point
+          .map(|x| x.node.key)
+          .unwrap_or_else(|| rand_storage_key(plan_st)),
+        flattened: false,
+        subspace_reference: None,
+        set: None,
+        children: BTreeMap::new(),
+        list: false,
+        map: true,
+        counter: false,
       })
     }
   }
 }
 
+/// Returns the key of the shared layout of `table_name`, generating the layout if it does not
+/// exist yet.
+fn ensure_layout(
+  plan_st: &mut PlanState,
+  schema: &CompiledSchema,
+  table_name: &Arc<str>,
+) -> Result<StorageKey> {
+  if let Some(&key) = plan_st.layout_keys.get(table_name) {
+    return Ok(key);
+  }
+
+  // A layout does not depend on where it is used, so it cannot reference enclosing fields.
+  let old_point = plan_st.old_layouts.get(table_name).copied();
+  let fields_in_stack = std::mem::take(&mut plan_st.fields_in_stack);
+  let layout = generate_field(
+    plan_st,
+    schema,
+    &FieldType::Table(table_name.clone()),
+    &[],
+    old_point,
+    FieldSite::Layout,
+  );
+  plan_st.fields_in_stack = fields_in_stack;
+  let layout = layout?;
+
+  let key = layout.key;
+  plan_st.layouts.insert(table_name.clone(), layout);
+  Ok(key)
+}
+
 fn rand_storage_key(st: &mut PlanState) -> StorageKey {
   loop {
     let now = SystemTime::now()
@@ -367,8 +621,8 @@ fn rand_storage_key(st: &mut PlanState) -> StorageKey {
   }
 }
 
-fn collect_storage_keys(node: &StorageNode, sink: &mut HashSet<StorageKey>) {
-  sink.insert(node.key);
+fn collect_storage_keys<S: Extend<StorageKey>>(node: &StorageNode, sink: &mut S) {
+  sink.extend(Some(node.key));
   if let Some(x) = &node.set {
     collect_storage_keys(x, sink);
   }
@@ -377,6 +631,46 @@ fn collect_storage_keys(node: &StorageNode, sink: &mut HashSet<StorageKey>) {
   }
 }
 
+/// Counts the table fields of each type reachable from `ty`. Types are only counted once inside
+/// another table field, since that field may use a shared layout. Recursive fields are not
+/// counted.
+fn count_table_fields(
+  ty: &FieldType,
+  schema: &CompiledSchema,
+  site: FieldSite,
+  state: &mut HashSet<Arc<str>>,
+  sink: &mut HashMap<Arc<str>, usize>,
+) -> Result<()> {
+  match ty {
+    FieldType::Set(x) => count_table_fields(x, schema, FieldSite::Root, state, sink),
+    FieldType::Primitive(_) | FieldType::List(_) | FieldType::Map(..) => Ok(()),
+    FieldType::Table(table_name) => {
+      if state.contains(table_name) {
+        return Ok(());
+      }
+      if site == FieldSite::Field {
+        let n = sink.entry(table_name.clone()).or_default();
+        *n += 1;
+        if *n > 1 {
+          return Ok(());
+        }
+      }
+
+      let specialized_ty = schema
+        .types
+        .get(table_name)
+        .ok_or_else(|| PlannerError::MissingType(table_name.clone()))?;
+
+      state.insert(table_name.clone());
+      for (_, (field, _)) in &specialized_ty.fields {
+        count_table_fields(field, schema, FieldSite::Field, state, sink)?;
+      }
+      state.remove(table_name);
+      Ok(())
+    }
+  }
+}
+
 fn collect_special_types(
   ty: &FieldType,
   schema: &CompiledSchema,
@@ -397,7 +691,7 @@ fn collect_special_types(
         set_member_types_sink,
       )
     }
-    FieldType::Primitive(_) => Ok(()),
+    FieldType::Primitive(_) | FieldType::List(_) | FieldType::Map(..) => Ok(()),
     FieldType::Table(table_name) => {
       // if a cycle is detected...
       if state.insert(table_name.clone()) == false {
@@ -1,4 +1,4 @@
-use std::convert::TryFrom;
+use std::{collections::BTreeSet, convert::TryFrom};
 
 use bumpalo::Bump;
 use console::Style;
@@ -6,56 +6,12 @@ use similar::{ChangeTag, TextDiff};
 
 use crate::{
   schema::{compile::compile, grammar::parse},
-  storage_plan::StoragePlan,
+  storage_plan::{StorageKey, StorageNode, StoragePlan},
 };
 
-use super::planner::generate_plan_for_schema;
+use super::planner::{assign_key_aliases, generate_plan_for_schema};
 
-const SIMPLE_SCHEMA: &str = r#"
-type Item<T> {
-  inner: T,
-  inner2: T,
-  @primary
-  something_else: string,
-}
-type Duration<T> {
-  start: T,
-  end: T,
-}
-type Recursive<T> {
-  inner: Recursive<T>,
-}
-type BinaryTree<T> {
-  left: BinaryTree<T>,
-  right: BinaryTree<T>,
-  value: T,
-}
-
-type TrinaryTree<T> {
-  left: TrinaryTree<T>,
-  middle: TrinaryTree<T>,
-  right: TrinaryTree<T>,
-  value: T,
-}
-
-type InternalSet {
-  @primary
-  key: bytes,
-  s: set<Wrapper<int64>>,
-}
-
-type Wrapper<T> {
-  @primary
-  value: T,
-}
-
-export set<Item<Duration<int64>>> items;
-export Recursive<int64> item;
-export BinaryTree<int64> a_binary_tree;
-export InternalSet an_internal_set;
-export set<InternalSet> nested_internal_sets;
-export TrinaryTree<int64> a_trinary_tree;
-"#;
+const SIMPLE_SCHEMA: &str = include_str!("../fixtures/simple/schema.rschema");
 
 #[test]
 fn test_planner_simple() {
@@ -193,6 +149,21 @@ fn test_many_binary_trees() {
     "test_many_binary_trees: serialized size of plan: {}",
     plan.serialize_compressed().unwrap().len()
   );
+
+  let empty_plan = StoragePlan {
+    share_layouts: true,
+    ..Default::default()
+  };
+  let shared_plan = generate_plan_for_schema(&empty_plan, &Default::default(), &output).unwrap();
+  println!(
+    "test_many_binary_trees: serialized size of plan with shared layouts: {}",
+    shared_plan.serialize_compressed().unwrap().len()
+  );
+  assert_eq!(
+    rmp_serde::to_vec_named(&generate_plan_for_schema(&shared_plan, &output, &output).unwrap())
+      .unwrap(),
+    rmp_serde::to_vec_named(&shared_plan).unwrap()
+  );
 }
 
 #[test]
@@ -452,3 +423,271 @@ fn primitive_exports() {
   let plan = generate_plan_for_schema(&Default::default(), &Default::default(), &output).unwrap();
   println!("{}", plan);
 }
+
+#[test]
+fn key_aliases() {
+  let _ = pretty_env_logger::try_init();
+  let alloc = Bump::new();
+  let ast = parse(&alloc, SIMPLE_SCHEMA).unwrap();
+  let schema = compile(&ast).unwrap();
+  let mut plan =
+    generate_plan_for_schema(&Default::default(), &Default::default(), &schema).unwrap();
+  assert!(plan.key_aliases.is_empty());
+  assign_key_aliases(&mut plan).unwrap();
+
+  let mut keys = BTreeSet::new();
+  fn collect(node: &StorageNode, keys: &mut BTreeSet<StorageKey>) {
+    keys.insert(node.key);
+    node.set.iter().for_each(|x| collect(x, keys));
+    node.children.values().for_each(|x| collect(x, keys));
+  }
+  plan.nodes.values().for_each(|x| collect(x, &mut keys));
+  assert_eq!(
+    plan.key_aliases.keys().copied().collect::<BTreeSet<_>>(),
+    keys
+  );
+  let aliases = plan.key_aliases.values().copied().collect::<BTreeSet<_>>();
+  assert_eq!(aliases.len(), keys.len());
+
+  // Aliases are kept across plan generations, and the YAML representation round-trips.
+  let plan2 = generate_plan_for_schema(&plan, &schema, &schema).unwrap();
+  assert_eq!(plan2.key_aliases, plan.key_aliases);
+  let plan3 = StoragePlan::try_from(&StoragePlan::<String>::from(&plan2)).unwrap();
+  assert_eq!(plan3.key_aliases, plan.key_aliases);
+
+  // Retired keys keep their aliases, and new keys get fresh ones.
+  let ast = parse(
+    &alloc,
+    r#"
+    type Item {
+      a: int64,
+    }
+    export Item data;
+  "#,
+  )
+  .unwrap();
+  let schema2 = compile(&ast).unwrap();
+  let plan4 = generate_plan_for_schema(&plan, &schema, &schema2).unwrap();
+  let data = plan4.nodes.get("data").unwrap();
+  assert!(plan
+    .key_aliases
+    .iter()
+    .all(|(k, v)| plan4.key_aliases[k] == *v));
+  let max_alias = *aliases.iter().next_back().unwrap();
+  assert!(plan4.key_aliases[&data.key] > max_alias);
+  assert!(plan4.key_aliases[&data.children["a"].key] > max_alias);
+}
+
+#[test]
+fn key_aliases_skip_legacy_prefix() {
+  let mut plan = StoragePlan::default();
+  for i in 0..300u16 {
+    let mut key = [0u8; 12];
+    key[..2].copy_from_slice(&i.to_be_bytes());
+    plan.nodes.insert(
+      format!("e{}", i).into(),
+      StorageNode {
+        key,
+        flattened: false,
+        subspace_reference: None,
+        set: None,
+        children: Default::default(),
+        list: false,
+        map: false,
+        counter: false,
+      },
+    );
+  }
+  assign_key_aliases(&mut plan).unwrap();
+  assert!(plan.key_aliases.values().all(|x| x >> 8 != 0x01));
+  assert_eq!(
+    *plan.key_aliases.values().max().unwrap(),
+    0x0200 + 300 - 256 - 1
+  );
+}
+
+#[test]
+fn field_keys() {
+  let _ = pretty_env_logger::try_init();
+  let compile_schema = |text: &str| {
+    let alloc = Bump::new();
+    let ast = parse(&alloc, text).unwrap();
+    compile(&ast).unwrap()
+  };
+  let old_schema = compile_schema(
+    r#"
+  type Item {
+    @primary
+    id: string,
+    name: string,
+    tags: set<Tag>,
+  }
+  type Tag {
+    @primary
+    id: string,
+  }
+  export set<Item> items;
+  "#,
+  );
+  let new_schema = compile_schema(
+    r#"
+  type Item {
+    @primary
+    id: string,
+    title: string,
+    tags: set<Tag>,
+  }
+  type Tag {
+    @primary
+    id: string,
+  }
+  export set<Item> items;
+  "#,
+  );
+  let old_plan =
+    generate_plan_for_schema(&Default::default(), &Default::default(), &old_schema).unwrap();
+  let new_plan = generate_plan_for_schema(&old_plan, &old_schema, &new_schema).unwrap();
+
+  let old_keys = old_plan.field_keys();
+  let new_keys = new_plan.field_keys();
+  assert_eq!(
+    old_keys.keys().map(|x| x.as_str()).collect::<Vec<_>>(),
+    vec![
+      "items",
+      "items.id",
+      "items.name",
+      "items.tags",
+      "items.tags.id"
+    ]
+  );
+  assert_eq!(
+    new_keys.keys().map(|x| x.as_str()).collect::<Vec<_>>(),
+    vec![
+      "items",
+      "items.id",
+      "items.tags",
+      "items.tags.id",
+      "items.title"
+    ]
+  );
+  for path in ["items", "items.id", "items.tags", "items.tags.id"] {
+    assert_eq!(old_keys[path], new_keys[path]);
+  }
+  assert!(!old_keys.values().any(|x| *x == new_keys["items.title"]));
+}
+
+#[test]
+fn shared_layouts() {
+  let _ = pretty_env_logger::try_init();
+  let compile_schema = |text: &str| {
+    let alloc = Bump::new();
+    let ast = parse(&alloc, text).unwrap();
+    compile(&ast).unwrap()
+  };
+  let old_schema = compile_schema(
+    r#"
+  type Address {
+    street: string,
+    city: string,
+    geo: Point,
+  }
+  type Point {
+    lat: int64,
+    lng: int64,
+  }
+  type User {
+    @primary
+    id: string,
+    home: Address,
+    work: Address,
+    billing: Address,
+  }
+  type Company {
+    hq: Address,
+    branch: Address,
+  }
+  export set<User> users;
+  export Company company;
+  export Address default_address;
+  "#,
+  );
+  let new_schema = compile_schema(
+    r#"
+  type Address {
+    street: string,
+    city: string,
+    zip: string,
+    geo: Point,
+  }
+  type Point {
+    lat: int64,
+    lng: int64,
+  }
+  type User {
+    @primary
+    id: string,
+    home: Address,
+    work: Address,
+    billing: Address,
+  }
+  type Company {
+    hq: Address,
+    branch: Address,
+    mail: Address,
+  }
+  export set<User> users;
+  export Company company;
+  export Address default_address;
+  "#,
+  );
+  let empty_plan = StoragePlan {
+    share_layouts: true,
+    ..Default::default()
+  };
+  let inline_plan =
+    generate_plan_for_schema(&Default::default(), &Default::default(), &old_schema).unwrap();
+  let plan = generate_plan_for_schema(&empty_plan, &Default::default(), &old_schema).unwrap();
+  println!("{}", plan);
+  assert!(inline_plan.shared_layouts.is_empty());
+  assert_eq!(
+    plan.shared_layouts.keys().map(|x| &**x).collect::<Vec<_>>(),
+    vec!["Address<>", "Point<>"]
+  );
+  assert!(
+    plan.serialize_compressed().unwrap().len() < inline_plan.serialize_compressed().unwrap().len()
+  );
+  assert_eq!(
+    rmp_serde::to_vec_named(&generate_plan_for_schema(&plan, &old_schema, &old_schema).unwrap())
+      .unwrap(),
+    rmp_serde::to_vec_named(&plan).unwrap()
+  );
+
+  // Every `Address` field has the same layout, and so does `Point` in the layout and in the
+  // export.
+  let keys = plan.field_keys();
+  let locations = plan.field_locations();
+  assert_eq!(keys.len(), inline_plan.field_keys().len());
+  assert_eq!(keys["users.home.city"], keys["company.hq.city"]);
+  assert_ne!(keys["users.home.city"], keys["default_address.city"]);
+  assert_ne!(locations["users.home.city"], locations["users.work.city"]);
+
+  // Layout and field keys are kept across migrations.
+  let new_plan = generate_plan_for_schema(&plan, &old_schema, &new_schema).unwrap();
+  let new_keys = new_plan.field_keys();
+  assert!(new_plan.share_layouts);
+  assert_eq!(
+    new_plan.shared_layouts["Address<>"].key,
+    plan.shared_layouts["Address<>"].key
+  );
+  for (path, key) in &keys {
+    assert_eq!(new_keys[path], *key);
+  }
+  assert_eq!(new_keys["company.mail.city"], keys["company.hq.city"]);
+  assert_eq!(
+    rmp_serde::to_vec_named(
+      &generate_plan_for_schema(&new_plan, &new_schema, &new_schema).unwrap()
+    )
+    .unwrap(),
+    rmp_serde::to_vec_named(&new_plan).unwrap()
+  );
+}
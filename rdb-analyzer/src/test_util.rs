@@ -26,7 +26,11 @@ fn ensure_fdb_ready() {
   });
 }
 
-#[cfg(not(any(feature = "test-with-fdb", feature = "test-with-sqlite")))]
+#[cfg(not(any(
+  feature = "test-with-fdb",
+  feature = "test-with-sqlite",
+  feature = "test-with-rocksdb"
+)))]
 pub fn create_kv() -> Box<dyn KeyValueStore> {
   use crate::kv_backend::mock_kv::MockKv;
   Box::new(MockKv::new())
@@ -78,3 +82,26 @@ pub fn create_kv() -> Box<dyn KeyValueStore> {
     &isolation_id,
   ))
 }
+
+#[cfg(feature = "test-with-rocksdb")]
+pub fn create_kv() -> Box<dyn KeyValueStore> {
+  use crate::kv_backend::rocksdb::{open_db, RocksdbKvStore};
+  use rand::RngCore;
+  use rocksdb::OptimisticTransactionDB;
+  use std::{sync::Arc, time::SystemTime};
+
+  lazy_static::lazy_static! {
+    static ref TEMP_DIR: String = format!(
+      "{}/rdb-test-rocksdb-{}-{}",
+      std::env::temp_dir().to_string_lossy(),
+      SystemTime::now().duration_since(SystemTime::UNIX_EPOCH).unwrap().as_millis(),
+      std::process::id(),
+    );
+    static ref DB: Arc<OptimisticTransactionDB> = open_db(&*TEMP_DIR).unwrap();
+  }
+
+  let mut isolation_id = [0u8; 16];
+  rand::thread_rng().fill_bytes(&mut isolation_id[..]);
+
+  Box::new(RocksdbKvStore::new(DB.clone(), &isolation_id))
+}
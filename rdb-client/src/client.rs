@@ -0,0 +1,264 @@
+use std::{future::Future, sync::Arc, time::Duration};
+
+use anyhow::Result;
+use rdb_analyzer::data::treewalker::serialize::SerializedVmValue;
+use rdb_proto::{
+  proto::{
+    rdb_control_client::RdbControlClient, CreateQueryScriptRequest, DescribeSchemaReply,
+    DescribeSchemaRequest, InvokeGraphRequest, ListDeploymentRequest,
+  },
+  tonic::{
+    metadata::{Ascii, MetadataValue},
+    transport::{Channel, Endpoint},
+    Request, Status,
+  },
+};
+use thiserror::Error;
+
+use crate::{pool::ChannelPool, retry::RetryPolicy};
+
+#[derive(Error, Debug)]
+pub enum ClientError {
+  #[error("invalid token")]
+  InvalidToken,
+
+  #[error("namespace `{0}` has no deployment")]
+  NoDeployment(String),
+
+  #[error("namespace `{0}` not found")]
+  NamespaceNotFound(String),
+}
+
+#[derive(Clone, Debug)]
+pub struct ClientConfig {
+  /// Sent as `Authorization: Bearer <token>` with every request.
+  pub token: Option<String>,
+
+  /// Number of connections to the server.
+  pub pool_size: usize,
+  pub retry: RetryPolicy,
+
+  /// Timeout of each attempt of a request.
+  pub timeout: Option<Duration>,
+}
+
+impl Default for ClientConfig {
+  fn default() -> Self {
+    Self {
+      token: None,
+      pool_size: 4,
+      retry: RetryPolicy::default(),
+      timeout: None,
+    }
+  }
+}
+
+/// A connection pool to an `rdb-server`. Cheap to clone.
+#[derive(Clone)]
+pub struct Client {
+  inner: Arc<ClientInner>,
+}
+
+struct ClientInner {
+  pool: ChannelPool,
+  authorization: Option<MetadataValue<Ascii>>,
+  retry: RetryPolicy,
+}
+
+impl Client {
+  /// Connects to the gRPC endpoint of the server, e.g. `http://127.0.0.1:8080`.
+  pub async fn connect(endpoint: &str, config: ClientConfig) -> Result<Self> {
+    let authorization = match &config.token {
+      Some(token) => Some(
+        MetadataValue::from_str(&format!("Bearer {}", token))
+          .map_err(|_| ClientError::InvalidToken)?,
+      ),
+      None => None,
+    };
+    let mut endpoint = Endpoint::from_shared(endpoint.to_string())?;
+    if let Some(timeout) = config.timeout {
+      endpoint = endpoint.timeout(timeout);
+    }
+    Ok(Self {
+      inner: Arc::new(ClientInner {
+        pool: ChannelPool::new(&endpoint, config.pool_size)?,
+        authorization,
+        retry: config.retry,
+      }),
+    })
+  }
+
+  pub fn namespace(&self, id: &str) -> Namespace {
+    Namespace {
+      client: self.clone(),
+      id: id.to_string(),
+    }
+  }
+
+  /// The generated gRPC client on a connection of the pool, for RPCs without a wrapper here.
+  /// Requests sent with it are authorized but not retried; see `call` for that.
+  pub fn control(&self) -> RdbControlClient<Channel> {
+    let channel = self.inner.pool.get();
+    match &self.inner.authorization {
+      Some(authorization) => {
+        let authorization = authorization.clone();
+        RdbControlClient::with_interceptor(
+          channel,
+          move |mut req: Request<()>| -> Result<Request<()>, Status> {
+            req
+              .metadata_mut()
+              .insert("authorization", authorization.clone());
+            Ok(req)
+          },
+        )
+      }
+      None => RdbControlClient::new(channel),
+    }
+  }
+
+  /// Runs an RPC with the retry policy of the client. `f` is called once per attempt, each time
+  /// with a client on the next connection of the pool.
+  pub async fn call<T, F, Fut>(&self, mut f: F) -> Result<T>
+  where
+    F: FnMut(RdbControlClient<Channel>) -> Fut,
+    Fut: Future<Output = Result<T, Status>>,
+  {
+    Ok(self.inner.retry.run(|| f(self.control())).await?)
+  }
+}
+
+/// A namespace on the server.
+#[derive(Clone)]
+pub struct Namespace {
+  client: Client,
+  id: String,
+}
+
+impl Namespace {
+  pub fn id(&self) -> &str {
+    &self.id
+  }
+
+  pub fn deployment(&self, id: &str) -> Deployment {
+    Deployment {
+      namespace: self.clone(),
+      id: id.to_string(),
+    }
+  }
+
+  /// The most recently created deployment of the namespace.
+  pub async fn latest_deployment(&self) -> Result<Deployment> {
+    let req = ListDeploymentRequest {
+      namespace_id: self.id.clone(),
+    };
+    let res = self
+      .client
+      .call(|mut c| {
+        let req = req.clone();
+        async move { c.list_deployment(req).await }
+      })
+      .await?;
+    let latest = res
+      .into_inner()
+      .deployments
+      .into_iter()
+      .max_by_key(|x| x.create_time)
+      .ok_or_else(|| ClientError::NoDeployment(self.id.clone()))?;
+    Ok(self.deployment(&latest.id))
+  }
+
+  /// Runs `graph` of the query script `script` and returns its output.
+  ///
+  /// `params` are the parameters of the graph in order. A `schema` parameter takes
+  /// `SerializedVmValue::Null(None)`.
+  pub async fn query(
+    &self,
+    script: &str,
+    graph: &str,
+    params: &[SerializedVmValue],
+  ) -> Result<SerializedVmValue> {
+    let req = InvokeGraphRequest {
+      namespace_id: self.id.clone(),
+      query_script_id: script.to_string(),
+      graph_name: graph.to_string(),
+      params: serde_json::to_string(params)?,
+      ..Default::default()
+    };
+    let res = self
+      .client
+      .call(|mut c| {
+        let req = req.clone();
+        async move { c.invoke_graph(req).await }
+      })
+      .await?;
+    Ok(serde_json::from_str(&res.get_ref().output)?)
+  }
+}
+
+/// A deployment of a namespace.
+#[derive(Clone)]
+pub struct Deployment {
+  namespace: Namespace,
+  id: String,
+}
+
+impl Deployment {
+  pub fn id(&self) -> &str {
+    &self.id
+  }
+
+  pub fn namespace(&self) -> &Namespace {
+    &self.namespace
+  }
+
+  /// Creates the query script `id` on this deployment, replacing any query script with the same
+  /// id.
+  pub async fn create_query_script(&self, id: &str, script: &str) -> Result<()> {
+    let req = CreateQueryScriptRequest {
+      namespace_id: self.namespace.id.clone(),
+      id: id.to_string(),
+      associated_deployment: self.id.clone(),
+      script: script.to_string(),
+    };
+    let res = self
+      .namespace
+      .client
+      .call(|mut c| {
+        let req = req.clone();
+        async move { c.create_query_script(req).await }
+      })
+      .await?;
+    if !res.get_ref().created {
+      return Err(ClientError::NamespaceNotFound(self.namespace.id.clone()).into());
+    }
+    Ok(())
+  }
+
+  /// The types, exports and storage keys of this deployment.
+  pub async fn describe_schema(&self) -> Result<DescribeSchemaReply> {
+    let req = DescribeSchemaRequest {
+      namespace_id: self.namespace.id.clone(),
+      deployment_id: self.id.clone(),
+    };
+    let res = self
+      .namespace
+      .client
+      .call(|mut c| {
+        let req = req.clone();
+        async move { c.describe_schema(req).await }
+      })
+      .await?;
+    Ok(res.into_inner())
+  }
+
+  /// Runs `graph` of the query script `script`. Query scripts always run against the deployment
+  /// they were created on; see `Namespace::query`.
+  pub async fn query(
+    &self,
+    script: &str,
+    graph: &str,
+    params: &[SerializedVmValue],
+  ) -> Result<SerializedVmValue> {
+    self.namespace.query(script, graph, params).await
+  }
+}
@@ -0,0 +1,38 @@
+//! Client for the RefineDB control API.
+//!
+//! ```no_run
+//! # async fn example() -> anyhow::Result<()> {
+//! use rdb_client::{Client, ClientConfig, SerializedVmValue};
+//!
+//! let client = Client::connect(
+//!   "http://127.0.0.1:8080",
+//!   ClientConfig {
+//!     token: Some("secret".into()),
+//!     ..Default::default()
+//!   },
+//! )
+//! .await?;
+//! let output = client
+//!   .namespace("app")
+//!   .deployment("d1")
+//!   .query(
+//!     "qs",
+//!     "get_user",
+//!     &[
+//!       SerializedVmValue::Null(None),
+//!       SerializedVmValue::String("u1".into()),
+//!     ],
+//!   )
+//!   .await?;
+//! # Ok(())
+//! # }
+//! ```
+
+pub mod client;
+pub mod pool;
+pub mod retry;
+
+pub use client::{Client, ClientConfig, ClientError, Deployment, Namespace};
+pub use rdb_analyzer::data::treewalker::serialize::SerializedVmValue;
+pub use rdb_proto;
+pub use retry::RetryPolicy;
@@ -0,0 +1,37 @@
+use std::sync::atomic::{AtomicUsize, Ordering};
+
+use anyhow::Result;
+use rdb_proto::tonic::transport::{Channel, Endpoint};
+
+/// A fixed set of connections to the server, handed out round-robin.
+///
+/// A single HTTP/2 connection multiplexes requests but is limited by the concurrent stream limit
+/// of the server, so busy clients spread requests over several. Each channel reconnects on its
+/// own after a connection failure.
+pub struct ChannelPool {
+  channels: Vec<Channel>,
+  next: AtomicUsize,
+}
+
+impl ChannelPool {
+  /// Creates `size` channels to `endpoint`, at least one. Each connects on first use.
+  pub fn new(endpoint: &Endpoint, size: usize) -> Result<Self> {
+    let mut channels = Vec::with_capacity(size.max(1));
+    for _ in 0..size.max(1) {
+      channels.push(endpoint.connect_lazy()?);
+    }
+    Ok(Self {
+      channels,
+      next: AtomicUsize::new(0),
+    })
+  }
+
+  pub fn get(&self) -> Channel {
+    let index = self.next.fetch_add(1, Ordering::Relaxed) % self.channels.len();
+    self.channels[index].clone()
+  }
+
+  pub fn size(&self) -> usize {
+    self.channels.len()
+  }
+}
@@ -0,0 +1,94 @@
+use std::{future::Future, time::Duration};
+
+use rand::Rng;
+use rdb_proto::tonic::{Code, Status};
+
+/// How failed requests are retried.
+#[derive(Clone, Debug)]
+pub struct RetryPolicy {
+  /// Attempts per request, including the first one. `1` disables retries.
+  pub max_attempts: u32,
+
+  /// Upper bound of the delay before the first retry. The bound doubles on each retry, and the
+  /// delay is picked at random below it.
+  pub initial_backoff: Duration,
+  pub max_backoff: Duration,
+}
+
+impl Default for RetryPolicy {
+  fn default() -> Self {
+    Self {
+      max_attempts: 4,
+      initial_backoff: Duration::from_millis(50),
+      max_backoff: Duration::from_secs(2),
+    }
+  }
+}
+
+/// Whether a request that failed with `status` may succeed if sent again.
+///
+/// - `UNAVAILABLE`, and `UNKNOWN` which tonic reports for failed connections: the server could
+///   not be reached. `rdb-server` itself never returns `UNKNOWN`.
+/// - `ABORTED`: the transaction of the query conflicted with another one and was not committed.
+///   A `t_cas` version conflict is also reported as `ABORTED`, and fails again on every attempt.
+/// - `RESOURCE_EXHAUSTED` with a `retry-after` header: the namespace is rate limited. Exhausted
+///   quotas and query limits have no `retry-after` and are not retried.
+pub fn is_transient(status: &Status) -> bool {
+  match status.code() {
+    Code::Unavailable | Code::Unknown | Code::Aborted => true,
+    Code::ResourceExhausted => retry_after(status).is_some(),
+    _ => false,
+  }
+}
+
+fn retry_after(status: &Status) -> Option<Duration> {
+  let secs: u64 = status
+    .metadata()
+    .get("retry-after")?
+    .to_str()
+    .ok()?
+    .parse()
+    .ok()?;
+  Some(Duration::from_secs(secs))
+}
+
+impl RetryPolicy {
+  /// Runs `f` until it succeeds, fails with a status that is not transient, or runs out of
+  /// attempts.
+  pub async fn run<T, F, Fut>(&self, mut f: F) -> Result<T, Status>
+  where
+    F: FnMut() -> Fut,
+    Fut: Future<Output = Result<T, Status>>,
+  {
+    let mut backoff = self.initial_backoff;
+    let mut attempt = 1u32;
+    loop {
+      let status = match f().await {
+        Ok(x) => return Ok(x),
+        Err(e) => e,
+      };
+      if attempt >= self.max_attempts || !is_transient(&status) {
+        return Err(status);
+      }
+
+      let mut delay =
+        Duration::from_millis(rand::thread_rng().gen_range(0..=backoff.as_millis() as u64));
+      if let Some(x) = retry_after(&status) {
+        // Don't wait out a long rate limit window inside a single request.
+        if x > self.max_backoff {
+          return Err(status);
+        }
+        delay = delay.max(x);
+      }
+      log::debug!(
+        "retrying after {:?} (attempt {}): {}",
+        delay,
+        attempt,
+        status
+      );
+      tokio::time::sleep(delay).await;
+      backoff = (backoff * 2).min(self.max_backoff);
+      attempt += 1;
+    }
+  }
+}
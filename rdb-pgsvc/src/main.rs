@@ -1,4 +1,3 @@
-mod dfvis;
 mod memkv;
 mod query;
 
@@ -6,13 +5,13 @@ use std::{ffi::CStr, os::raw::c_char, panic::AssertUnwindSafe, ptr::NonNull, syn
 
 use anyhow::Result;
 use bumpalo::Bump;
-use dfvis::visualize_df;
 use memkv::MemKv;
 use query::{get_vm_graphs, run_vm_query, VmGraphQuery};
 use rdb_analyzer::{
   data::treewalker::{
     asm::codegen::compile_twscript,
     bytecode::TwScript,
+    dfvis::visualize_df,
     typeck::{GlobalTyckContext, GlobalTypeInfo},
     vm::TwVm,
   },
@@ -78,6 +77,28 @@ pub unsafe extern "C" fn rdb_dfasm(source: *const c_char) -> Option<Box<TwScript
   })
 }
 
+/// Encodes a script in the versioned binary format. The returned buffer of `*len` bytes is
+/// allocated with `malloc`.
+#[no_mangle]
+pub extern "C" fn rdb_twscript_to_bytes(script: &TwScript, len: &mut usize) -> Option<NonNull<u8>> {
+  wrap("rdb_twscript_to_bytes", || {
+    let data = script.to_bytes()?;
+    *len = data.len();
+    Ok(mkbytes(&data))
+  })
+}
+
+#[no_mangle]
+pub unsafe extern "C" fn rdb_twscript_from_bytes(
+  data: *const u8,
+  len: usize,
+) -> Option<Box<TwScript>> {
+  wrap("rdb_twscript_from_bytes", || {
+    let data = std::slice::from_raw_parts(data, len);
+    Ok(Box::new(TwScript::from_bytes(data)?))
+  })
+}
+
 #[no_mangle]
 pub extern "C" fn rdb_vm_create<'a>(
   schema: &'a CompiledSchema,
@@ -175,6 +196,18 @@ fn wrap<T>(name: &str, x: impl FnOnce() -> Result<T>) -> Option<T> {
   }
 }
 
+fn mkbytes(s: &[u8]) -> NonNull<u8> {
+  unsafe {
+    // At least one byte, so that empty buffers are not null.
+    let p = libc::malloc(s.len().max(1));
+    if p.is_null() {
+      panic!("mkbytes: malloc failed");
+    }
+    std::slice::from_raw_parts_mut(p as *mut u8, s.len()).copy_from_slice(s);
+    NonNull::new_unchecked(p as *mut u8)
+  }
+}
+
 fn mkcstr(s: &str) -> NonNull<c_char> {
   let s = s.as_bytes();
   unsafe {
@@ -7,7 +7,9 @@ use std::{
 };
 
 use async_trait::async_trait;
-use rdb_analyzer::data::kv::{KeyValueStore, KvError, KvKeyIterator, KvTransaction};
+use rdb_analyzer::data::kv::{
+  KeyValueStore, KvError, KvIterator, KvKeyIterator, KvTransaction, ScanOptions, VecKvIterator,
+};
 use rpds::RedBlackTreeMapSync;
 use std::sync::Mutex;
 
@@ -119,6 +121,30 @@ impl KvTransaction for MemTransaction {
     }))
   }
 
+  // Reads from the snapshot like `get`, so the writes of this transaction are not seen.
+  async fn scan(
+    &self,
+    start: &[u8],
+    end: &[u8],
+    options: ScanOptions,
+  ) -> Result<Box<dyn KvIterator>> {
+    let entries = if start < end {
+      let range = self.read_buffer.range(start.to_vec()..end.to_vec());
+      let range: Box<dyn Iterator<Item = _>> = if options.reverse {
+        Box::new(range.rev())
+      } else {
+        Box::new(range)
+      };
+      range
+        .filter_map(|(k, v)| v.0.as_ref().map(|v| (k.clone(), v.clone())))
+        .take(options.limit.unwrap_or(usize::MAX))
+        .collect::<Vec<_>>()
+    } else {
+      vec![]
+    };
+    Ok(Box::new(VecKvIterator::new(entries)))
+  }
+
   async fn commit(self: Box<Self>) -> Result<(), KvError> {
     let buffer = self.buffer.into_inner().unwrap();
     let modified = self.modified.into_inner().unwrap();
@@ -1 +1,4 @@
+// The stream types of server-streaming rpcs are named after the rpcs, e.g. `watchNamespacesStream`.
+#![allow(non_camel_case_types)]
+
 tonic::include_proto!("rdbrpc");
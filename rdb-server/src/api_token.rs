@@ -0,0 +1,121 @@
+use std::collections::BTreeMap;
+
+use anyhow::Result;
+use serde::{Deserialize, Serialize};
+use thiserror::Error;
+
+use crate::{
+  exec_core::ExecContext,
+  explorer::token_id,
+  httpapi::load_exec_ctx,
+  rate_limit::check_token_rate_limit,
+  sysquery::{lookup_api_token, ApiToken},
+};
+
+/// API tokens grant access to an allowlist of graphs in a namespace. Each graph is granted either
+/// read-only or effectful access, so that tokens handed out to untrusted clients cannot write
+/// unless explicitly allowed to.
+///
+/// Tokens are generated and stored the same way as explorer tokens: only the SHA-256 hash of a
+/// token is stored, and is used as the token id.
+#[derive(Error, Debug)]
+pub enum ApiTokenError {
+  #[error("graph not allowed for this token: `{0}`")]
+  GraphNotAllowed(String),
+
+  #[error("this token only allows read-only access to graph `{0}`, which is not read-only")]
+  GraphNotReadOnly(String),
+
+  #[error("invalid graph name: `{0}` (expecting `query_script_id/graph_name`)")]
+  InvalidGraphName(String),
+
+  #[error("graph granted more than once: `{0}`")]
+  DuplicateGrant(String),
+}
+
+#[derive(Copy, Clone, Debug, Eq, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum GraphPermission {
+  /// The graph may only be run while it is read-only.
+  ReadOnly,
+
+  /// The graph may be run even if it writes.
+  Effectful,
+}
+
+/// The name a graph is granted by. Graph names must be identifiers, as in query scripts.
+pub fn graph_full_name(query_script_id: &str, graph_name: &str) -> Result<String> {
+  let full_name = format!("{}/{}", query_script_id, graph_name);
+  if query_script_id.is_empty() || query_script_id.contains('/') || !is_identifier(graph_name) {
+    return Err(ApiTokenError::InvalidGraphName(full_name).into());
+  }
+  Ok(full_name)
+}
+
+fn is_identifier(x: &str) -> bool {
+  let mut chars = x.chars();
+  match chars.next() {
+    Some(c) if c.is_ascii_alphabetic() || c == '_' => {}
+    _ => return false,
+  }
+  chars.all(|c| c.is_ascii_alphanumeric() || c == '_')
+}
+
+/// Builds the `graph_permissions` of a new token from `(query_script_id, graph_name, permission)`
+/// grants. Fails on malformed graph names and on graphs granted more than once, so that only
+/// well-formed permissions are stored.
+pub fn graph_permissions_from_grants<'a>(
+  grants: impl IntoIterator<Item = (&'a str, &'a str, GraphPermission)>,
+) -> Result<BTreeMap<String, GraphPermission>> {
+  let mut graph_permissions = BTreeMap::new();
+  for (query_script_id, graph_name, permission) in grants {
+    let full_name = graph_full_name(query_script_id, graph_name)?;
+    if graph_permissions
+      .insert(full_name.clone(), permission)
+      .is_some()
+    {
+      return Err(ApiTokenError::DuplicateGrant(full_name).into());
+    }
+  }
+  Ok(graph_permissions)
+}
+
+impl ApiToken {
+  /// Checks that this token may run `graph_name` of the loaded query script.
+  ///
+  /// Read-only permissions are checked against the loaded graph rather than when the token is
+  /// issued, since the query script may have been replaced since then.
+  pub fn check_allowed(
+    &self,
+    exec_ctx: &ExecContext,
+    query_script_id: &str,
+    graph_name: &str,
+  ) -> Result<()> {
+    let full_name = graph_full_name(query_script_id, graph_name)?;
+    let permission = match self.graph_permissions.get(&full_name) {
+      Some(x) => *x,
+      None => return Err(ApiTokenError::GraphNotAllowed(full_name).into()),
+    };
+    if permission == GraphPermission::ReadOnly {
+      let graph_index = exec_ctx.vm().lookup_exported_graph_by_name(graph_name)?;
+      if !exec_ctx.vm().is_graph_read_only(graph_index) {
+        return Err(ApiTokenError::GraphNotReadOnly(full_name).into());
+      }
+    }
+    Ok(())
+  }
+}
+
+/// Looks up the secret `token` in a namespace and checks that it may run the graph.
+pub async fn authorize_api_token(
+  namespace_id: &str,
+  query_script_id: &str,
+  graph_name: &str,
+  token: &str,
+) -> Result<()> {
+  let token_id = token_id(token);
+  check_token_rate_limit(namespace_id, &token_id)?;
+  let token = lookup_api_token(namespace_id, &token_id).await?;
+  let exec_ctx = load_exec_ctx(namespace_id, query_script_id).await?;
+  token.check_allowed(&exec_ctx, query_script_id, graph_name)
+}
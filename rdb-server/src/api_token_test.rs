@@ -0,0 +1,59 @@
+use crate::api_token::{graph_permissions_from_grants, ApiTokenError, GraphPermission};
+
+#[test]
+fn grants() {
+  let permissions = graph_permissions_from_grants(vec![
+    ("blog", "list_posts", GraphPermission::ReadOnly),
+    ("blog", "add_post", GraphPermission::Effectful),
+    ("admin", "_reset", GraphPermission::Effectful),
+  ])
+  .unwrap();
+  assert_eq!(
+    permissions.into_iter().collect::<Vec<_>>(),
+    vec![
+      ("admin/_reset".to_string(), GraphPermission::Effectful),
+      ("blog/add_post".to_string(), GraphPermission::Effectful),
+      ("blog/list_posts".to_string(), GraphPermission::ReadOnly),
+    ]
+  );
+  assert!(graph_permissions_from_grants(vec![]).unwrap().is_empty());
+}
+
+#[test]
+fn malformed_grants() {
+  for (query_script_id, graph_name) in [
+    ("", "main"),
+    ("a/b", "main"),
+    ("blog", ""),
+    ("blog", "a/b"),
+    ("blog", "1st"),
+    ("blog", "list posts"),
+  ] {
+    let e = graph_permissions_from_grants(vec![(
+      query_script_id,
+      graph_name,
+      GraphPermission::ReadOnly,
+    )])
+    .unwrap_err();
+    assert!(
+      matches!(
+        e.downcast_ref::<ApiTokenError>(),
+        Some(ApiTokenError::InvalidGraphName(_))
+      ),
+      "{}/{}: {:?}",
+      query_script_id,
+      graph_name,
+      e
+    );
+  }
+
+  let e = graph_permissions_from_grants(vec![
+    ("blog", "list_posts", GraphPermission::ReadOnly),
+    ("blog", "list_posts", GraphPermission::Effectful),
+  ])
+  .unwrap_err();
+  assert!(matches!(
+    e.downcast_ref::<ApiTokenError>(),
+    Some(ApiTokenError::DuplicateGrant(x)) if x == "blog/list_posts"
+  ));
+}
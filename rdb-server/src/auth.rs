@@ -0,0 +1,285 @@
+use std::{
+  collections::{BTreeSet, HashMap},
+  sync::{Arc, RwLock, Weak},
+  time::Duration,
+};
+
+use anyhow::Result;
+use rdb_proto::tonic::{metadata::MetadataValue, Request, Status};
+use serde::{Deserialize, Serialize};
+use thiserror::Error;
+use tokio::time::sleep;
+
+use crate::{exec::Caller, explorer::token_id, state::get_state, sysquery::list_tokens};
+
+/// Metadata key under which the gRPC interceptor passes the scope of a request to the handlers.
+/// Any value sent by the client is replaced.
+const SCOPE_METADATA_KEY: &str = "x-rdb-auth-scope-bin";
+
+#[derive(Error, Debug)]
+pub enum AuthError {
+  #[error("missing or malformed authorization")]
+  BadAuthorization,
+
+  #[error("invalid token")]
+  InvalidToken,
+
+  #[error("namespace not allowed for this token: `{0}`")]
+  NamespaceNotAllowed(String),
+
+  #[error("this operation requires the root token")]
+  RootRequired,
+
+  #[error("mounting namespace not allowed for this token: `{0}`")]
+  MountNotAllowed(String),
+
+  #[error("request not authenticated")]
+  MissingScope,
+}
+
+/// What an authenticated request may access.
+#[derive(Clone, Debug, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum AuthScope {
+  /// All namespaces, and management of namespaces and tokens. Granted to the root token, and to
+  /// all requests if authentication is disabled. Has no role.
+  Root,
+
+  /// Only the listed namespaces. Query scripts created with this scope may mount the namespaces
+  /// in `mounts` read-only.
+  Namespaces {
+    namespaces: BTreeSet<String>,
+    mounts: BTreeSet<String>,
+
+    /// Id of the token.
+    token_id: String,
+
+    /// The role checked against `@acl` field annotations, set when the token is created.
+    role: Option<String>,
+  },
+}
+
+impl AuthScope {
+  /// The caller that graphs are run for. Fields protected by `@acl` are only visible to the roles
+  /// they list, so the root token sees none of them.
+  pub fn caller(&self) -> Caller {
+    match self {
+      Self::Root => Caller::default(),
+      Self::Namespaces { token_id, role, .. } => Caller {
+        token_id: token_id.clone(),
+        role: role.clone(),
+      },
+    }
+  }
+
+  pub fn allows_namespace(&self, namespace_id: &str) -> bool {
+    match self {
+      Self::Root => true,
+      Self::Namespaces { namespaces, .. } => namespaces.contains(namespace_id),
+    }
+  }
+
+  pub fn allows_mount(&self, namespace_id: &str) -> bool {
+    match self {
+      Self::Root => true,
+      Self::Namespaces { mounts, .. } => mounts.contains(namespace_id),
+    }
+  }
+
+  pub fn check_mount(&self, namespace_id: &str) -> Result<()> {
+    if self.allows_mount(namespace_id) {
+      Ok(())
+    } else {
+      Err(AuthError::MountNotAllowed(namespace_id.to_string()).into())
+    }
+  }
+
+  pub fn check_namespace(&self, namespace_id: &str) -> Result<()> {
+    if self.allows_namespace(namespace_id) {
+      Ok(())
+    } else {
+      Err(AuthError::NamespaceNotAllowed(namespace_id.to_string()).into())
+    }
+  }
+
+  pub fn check_root(&self) -> Result<()> {
+    match self {
+      Self::Root => Ok(()),
+      Self::Namespaces { .. } => Err(AuthError::RootRequired.into()),
+    }
+  }
+}
+
+/// Bearer tokens accepted by the gRPC and HTTP servers.
+///
+/// Tokens are stored in the system schema by the SHA-256 hash of their secret, and kept in
+/// memory so that they can be checked without a transaction. Tokens created or revoked through
+/// this server take effect immediately; those created or revoked through other servers take
+/// effect on the next refresh.
+///
+/// Only the server-wide `Token`s of the system schema are kept here. Explorer tokens and API
+/// tokens belong to a single namespace: they are stored in its `explorer_tokens` and `api_tokens`
+/// sets, looked up in the system store on each request, and deleted along with the namespace.
+/// They stay in their own tables since each kind grants something different (namespaces, graphs
+/// of the explorer, or individual graphs of query scripts) and is checked on a different path.
+///
+/// `Token`s refer to namespaces by id, so the deletion of a namespace first removes it from every
+/// token, in the system store and here (`remove_namespace`). A namespace created later with the
+/// same id is then not reachable with older tokens. If the server stops before the namespace is
+/// deleted, tokens lose access to a namespace that still exists, and never the other way around.
+pub struct TokenRegistry {
+  /// `None` if authentication is disabled.
+  root_token_id: Option<String>,
+  tokens: RwLock<HashMap<String, AuthScope>>,
+  refresh_interval: Duration,
+}
+
+impl TokenRegistry {
+  pub fn new(root_token: Option<&str>, refresh_interval: Duration) -> Arc<Self> {
+    let me = Arc::new(Self {
+      root_token_id: root_token.map(token_id),
+      tokens: RwLock::new(HashMap::new()),
+      refresh_interval,
+    });
+    if me.enabled() {
+      let me_weak = Arc::downgrade(&me);
+      tokio::spawn(async move {
+        Self::refresh_loop(me_weak).await;
+      });
+    }
+    me
+  }
+
+  pub fn enabled(&self) -> bool {
+    self.root_token_id.is_some()
+  }
+
+  /// Checks the value of an `Authorization: Bearer <token>` header.
+  pub fn authenticate(&self, authorization: Option<&str>) -> Result<AuthScope> {
+    let root_token_id = match &self.root_token_id {
+      Some(x) => x,
+      None => return Ok(AuthScope::Root),
+    };
+    let token = authorization
+      .and_then(|x| x.strip_prefix("Bearer "))
+      .map(|x| x.trim())
+      .filter(|x| !x.is_empty())
+      .ok_or(AuthError::BadAuthorization)?;
+    let id = token_id(token);
+    if id == *root_token_id {
+      return Ok(AuthScope::Root);
+    }
+    self
+      .tokens
+      .read()
+      .unwrap()
+      .get(&id)
+      .cloned()
+      .ok_or_else(|| AuthError::InvalidToken.into())
+  }
+
+  pub fn insert(
+    &self,
+    id: String,
+    namespaces: BTreeSet<String>,
+    mounts: BTreeSet<String>,
+    role: Option<String>,
+  ) {
+    self.tokens.write().unwrap().insert(
+      id.clone(),
+      AuthScope::Namespaces {
+        namespaces,
+        mounts,
+        token_id: id,
+        role,
+      },
+    );
+  }
+
+  pub fn remove(&self, id: &str) {
+    self.tokens.write().unwrap().remove(id);
+  }
+
+  /// Removes a namespace from the scope of every token in memory.
+  pub fn remove_namespace(&self, namespace_id: &str) {
+    for scope in self.tokens.write().unwrap().values_mut() {
+      if let AuthScope::Namespaces {
+        namespaces, mounts, ..
+      } = scope
+      {
+        namespaces.remove(namespace_id);
+        mounts.remove(namespace_id);
+      }
+    }
+  }
+
+  /// Replaces the tokens in memory with those in the system schema.
+  pub async fn refresh(&self) -> Result<()> {
+    let tokens = list_tokens()
+      .await?
+      .into_iter()
+      .map(|x| {
+        (
+          x.id.clone(),
+          AuthScope::Namespaces {
+            namespaces: x.namespaces.into_iter().collect(),
+            mounts: x.mounts.into_iter().collect(),
+            token_id: x.id,
+            role: x.role,
+          },
+        )
+      })
+      .collect();
+    *self.tokens.write().unwrap() = tokens;
+    Ok(())
+  }
+
+  async fn refresh_loop(me: Weak<Self>) {
+    loop {
+      let refresh_interval = match me.upgrade() {
+        Some(x) => x.refresh_interval,
+        None => break,
+      };
+      sleep(refresh_interval).await;
+      let me = match me.upgrade() {
+        Some(x) => x,
+        None => {
+          log::warn!("token registry: exiting");
+          break;
+        }
+      };
+      if let Err(e) = me.refresh().await {
+        log::error!("token registry: cannot refresh tokens: {:?}", e);
+      }
+    }
+  }
+}
+
+/// Authenticates a gRPC request and attaches its scope, to be read with `request_scope`.
+pub fn grpc_interceptor(mut req: Request<()>) -> Result<Request<()>, Status> {
+  let authorization = match req.metadata().get("authorization") {
+    Some(x) => Some(
+      x.to_str()
+        .map_err(|_| Status::unauthenticated(AuthError::BadAuthorization.to_string()))?,
+    ),
+    None => None,
+  };
+  let scope = get_state()
+    .token_registry
+    .authenticate(authorization)
+    .map_err(|e| Status::unauthenticated(e.to_string()))?;
+  let scope = serde_json::to_vec(&scope).map_err(|e| Status::internal(e.to_string()))?;
+  req
+    .metadata_mut()
+    .insert_bin(SCOPE_METADATA_KEY, MetadataValue::from_bytes(&scope));
+  Ok(req)
+}
+
+pub fn request_scope<T>(req: &Request<T>) -> Result<AuthScope> {
+  let scope = req
+    .metadata()
+    .get_bin(SCOPE_METADATA_KEY)
+    .ok_or(AuthError::MissingScope)?
+    .to_bytes()?;
+  Ok(serde_json::from_slice(&scope)?)
+}
@@ -0,0 +1,24 @@
+use std::time::Duration;
+
+use crate::{auth::TokenRegistry, explorer::token_id};
+
+#[tokio::test]
+async fn remove_namespace() {
+  let registry = TokenRegistry::new(Some("root"), Duration::from_secs(3600));
+  registry.insert(
+    token_id("t1"),
+    vec!["a".to_string(), "b".to_string()].into_iter().collect(),
+    vec!["a".to_string()].into_iter().collect(),
+    None,
+  );
+  registry.remove_namespace("a");
+
+  let scope = registry.authenticate(Some("Bearer t1")).unwrap();
+  assert!(!scope.allows_namespace("a"));
+  assert!(!scope.allows_mount("a"));
+  assert!(scope.allows_namespace("b"));
+  assert!(registry
+    .authenticate(Some("Bearer root"))
+    .unwrap()
+    .allows_namespace("a"));
+}
@@ -0,0 +1,272 @@
+use std::sync::Mutex;
+
+use anyhow::Result;
+use async_trait::async_trait;
+use rdb_analyzer::data::kv::{
+  encode_counter, KeyValueStore, KvError, KvIterator, KvKeyIterator, KvTransaction, ScanOptions,
+};
+use rdb_proto::{
+  prost::Message,
+  proto::{changelog_op, ChangelogEntry, ChangelogOp},
+};
+
+use crate::{state::get_state, sysquery::ns_to_kv_prefix_with_appended_zero, util::current_millis};
+
+// Keys under the prefix of a namespace:
+//
+// 0x00 + key - data
+// 0x01 0x00 - sequence number of the next changelog entry, as big-endian u64
+// 0x01 0x01 + seq - changelog entry, as an encoded `ChangelogEntry`
+const DATA_PREFIX: u8 = 0x00;
+const CHANGELOG_HEAD_KEY: &[u8] = &[0x01, 0x00];
+const CHANGELOG_ENTRY_PREFIX: &[u8] = &[0x01, 0x01];
+
+pub const DEFAULT_TAIL_LIMIT: usize = 100;
+pub const MAX_TAIL_LIMIT: usize = 10000;
+
+/// Opens the data store of a namespace, appending the writes of each committed transaction to
+/// the changelog of the namespace in the same transaction.
+///
+/// All writing transactions on the namespace conflict with each other on the changelog head.
+pub fn open_with_changelog(kv_prefix_with_appended_zero: &[u8]) -> Box<dyn KeyValueStore> {
+  let (zero, ns_prefix) = kv_prefix_with_appended_zero
+    .split_last()
+    .expect("open_with_changelog: empty prefix");
+  assert_eq!(*zero, DATA_PREFIX);
+  with_changelog((get_state().data_store_generator)(ns_prefix))
+}
+
+/// Wraps the store of a namespace, as `open_with_changelog` does.
+pub fn with_changelog(inner: Box<dyn KeyValueStore>) -> Box<dyn KeyValueStore> {
+  Box::new(ChangelogKvStore { inner })
+}
+
+/// Reads up to `limit` changelog entries of a namespace, starting from `from_seq`. Also returns
+/// the sequence number to continue from.
+pub async fn tail_changelog(
+  namespace_id: &str,
+  from_seq: u64,
+  limit: usize,
+) -> Result<(Vec<ChangelogEntry>, u64)> {
+  let mut ns_prefix = ns_to_kv_prefix_with_appended_zero(namespace_id).await?;
+  ns_prefix.pop();
+  let kv = (get_state().data_store_generator)(&ns_prefix);
+  read_changelog(&*kv, from_seq, limit).await
+}
+
+/// Like `tail_changelog`, on the unwrapped store of a namespace.
+pub async fn read_changelog(
+  kv: &dyn KeyValueStore,
+  from_seq: u64,
+  limit: usize,
+) -> Result<(Vec<ChangelogEntry>, u64)> {
+  let txn = kv.begin_read_only_transaction().await?;
+
+  let start = entry_key(from_seq);
+  let mut end = CHANGELOG_ENTRY_PREFIX.to_vec();
+  *end.last_mut().unwrap() += 1;
+  let mut it = txn.scan_keys(&start, &end).await?;
+  let mut entries = vec![];
+  while entries.len() < limit {
+    let key = match it.next().await? {
+      Some(x) => x,
+      None => break,
+    };
+    if let Some(value) = txn.get(&key).await? {
+      entries.push(ChangelogEntry::decode(value.as_slice())?);
+    }
+  }
+  let next_seq = entries.last().map(|x| x.seq + 1).unwrap_or(from_seq);
+  Ok((entries, next_seq))
+}
+
+fn entry_key(seq: u64) -> Vec<u8> {
+  let mut key = CHANGELOG_ENTRY_PREFIX.to_vec();
+  key.extend_from_slice(&seq.to_be_bytes());
+  key
+}
+
+fn data_key(key: &[u8]) -> Vec<u8> {
+  let mut x = Vec::with_capacity(key.len() + 1);
+  x.push(DATA_PREFIX);
+  x.extend_from_slice(key);
+  x
+}
+
+struct ChangelogKvStore {
+  inner: Box<dyn KeyValueStore>,
+}
+
+struct ChangelogKvTransaction {
+  inner: Box<dyn KvTransaction>,
+
+  /// Written to the changelog on commit.
+  ops: Mutex<Vec<ChangelogOp>>,
+}
+
+struct ChangelogKvKeyIterator {
+  inner: Box<dyn KvKeyIterator>,
+}
+
+struct ChangelogKvIterator {
+  inner: Box<dyn KvIterator>,
+}
+
+#[async_trait]
+impl KeyValueStore for ChangelogKvStore {
+  async fn begin_transaction(&self) -> Result<Box<dyn KvTransaction>> {
+    Ok(Box::new(ChangelogKvTransaction {
+      inner: self.inner.begin_transaction().await?,
+      ops: Mutex::new(vec![]),
+    }))
+  }
+
+  async fn begin_read_only_transaction(&self) -> Result<Box<dyn KvTransaction>> {
+    Ok(Box::new(ChangelogKvTransaction {
+      inner: self.inner.begin_read_only_transaction().await?,
+      ops: Mutex::new(vec![]),
+    }))
+  }
+}
+
+#[async_trait]
+impl KvTransaction for ChangelogKvTransaction {
+  async fn get(&self, key: &[u8]) -> Result<Option<Vec<u8>>> {
+    self.inner.get(&data_key(key)).await
+  }
+
+  async fn put(&self, key: &[u8], value: &[u8]) -> Result<()> {
+    self.inner.put(&data_key(key), value).await?;
+    self.log(changelog_op::Kind::Put, key, value);
+    Ok(())
+  }
+
+  async fn put_many(&self, entries: &[(Vec<u8>, Vec<u8>)]) -> Result<()> {
+    let data_entries = entries
+      .iter()
+      .map(|(key, value)| (data_key(key), value.clone()))
+      .collect::<Vec<_>>();
+    self.inner.put_many(&data_entries).await?;
+    for (key, value) in entries {
+      self.log(changelog_op::Kind::Put, key, value);
+    }
+    Ok(())
+  }
+
+  async fn delete(&self, key: &[u8]) -> Result<()> {
+    self.inner.delete(&data_key(key)).await?;
+    self.log(changelog_op::Kind::Delete, key, &[]);
+    Ok(())
+  }
+
+  async fn delete_range(&self, start: &[u8], end: &[u8]) -> Result<()> {
+    self
+      .inner
+      .delete_range(&data_key(start), &data_key(end))
+      .await?;
+    self.log(changelog_op::Kind::DeleteRange, start, end);
+    Ok(())
+  }
+
+  // Logged as the delta rather than the resulting value, which this transaction doesn't read.
+  async fn atomic_add(&self, key: &[u8], delta: i64) -> Result<()> {
+    self.inner.atomic_add(&data_key(key), delta).await?;
+    self.log(changelog_op::Kind::Add, key, &encode_counter(delta));
+    Ok(())
+  }
+
+  async fn scan_keys(&self, start: &[u8], end: &[u8]) -> Result<Box<dyn KvKeyIterator>> {
+    Ok(Box::new(ChangelogKvKeyIterator {
+      inner: self
+        .inner
+        .scan_keys(&data_key(start), &data_key(end))
+        .await?,
+    }))
+  }
+
+  async fn add_read_conflict_key(&self, key: &[u8]) -> Result<()> {
+    self.inner.add_read_conflict_key(&data_key(key)).await
+  }
+
+  async fn scan(
+    &self,
+    start: &[u8],
+    end: &[u8],
+    options: ScanOptions,
+  ) -> Result<Box<dyn KvIterator>> {
+    Ok(Box::new(ChangelogKvIterator {
+      inner: self
+        .inner
+        .scan(&data_key(start), &data_key(end), options)
+        .await?,
+    }))
+  }
+
+  async fn commit(self: Box<Self>) -> Result<(), KvError> {
+    let ops = std::mem::take(&mut *self.ops.lock().unwrap());
+    if !ops.is_empty() {
+      self.append(ops).await.map_err(|e| {
+        if let Some(KvError::Conflict) = e.downcast_ref::<KvError>() {
+          KvError::Conflict
+        } else {
+          log::error!("changelog: cannot append entry: {:?}", e);
+          KvError::CommitStateUnknown
+        }
+      })?;
+    }
+    self.inner.commit().await
+  }
+}
+
+impl ChangelogKvTransaction {
+  fn log(&self, kind: changelog_op::Kind, key: &[u8], value: &[u8]) {
+    self.ops.lock().unwrap().push(ChangelogOp {
+      kind: kind as i32,
+      key: key.to_vec(),
+      value: value.to_vec(),
+    });
+  }
+
+  async fn append(&self, ops: Vec<ChangelogOp>) -> Result<()> {
+    let seq = match self.inner.get(CHANGELOG_HEAD_KEY).await? {
+      Some(x) if x.len() == 8 => {
+        let mut buf = [0u8; 8];
+        buf.copy_from_slice(&x);
+        u64::from_be_bytes(buf)
+      }
+      _ => 0,
+    };
+    let entry = ChangelogEntry {
+      seq,
+      commit_time: current_millis(),
+      ops,
+    };
+    let mut value = Vec::with_capacity(entry.encoded_len());
+    entry.encode(&mut value)?;
+    self.inner.put(&entry_key(seq), &value).await?;
+    self
+      .inner
+      .put(CHANGELOG_HEAD_KEY, &(seq + 1).to_be_bytes())
+      .await
+  }
+}
+
+#[async_trait]
+impl KvKeyIterator for ChangelogKvKeyIterator {
+  async fn next(&mut self) -> Result<Option<Vec<u8>>> {
+    Ok(self.inner.next().await?.map(|mut x| {
+      x.remove(0);
+      x
+    }))
+  }
+}
+
+#[async_trait]
+impl KvIterator for ChangelogKvIterator {
+  async fn next(&mut self) -> Result<Option<(Vec<u8>, Vec<u8>)>> {
+    Ok(self.inner.next().await?.map(|(mut key, value)| {
+      key.remove(0);
+      (key, value)
+    }))
+  }
+}
@@ -0,0 +1,54 @@
+use rdb_analyzer::{
+  data::kv::{decode_counter, KvError, KvTransaction},
+  kv_backend::memory::MemoryKvStore,
+};
+use rdb_proto::proto::changelog_op;
+
+use crate::changelog::{read_changelog, with_changelog};
+
+#[tokio::test]
+async fn concurrent_adds() {
+  let store = MemoryKvStore::new(None);
+  let kv = with_changelog(Box::new(store.with_prefix(b"")));
+
+  // Both transactions start before either commits.
+  let txns: Vec<Box<dyn KvTransaction>> = vec![
+    kv.begin_transaction().await.unwrap(),
+    kv.begin_transaction().await.unwrap(),
+  ];
+  let deltas = [1i64, 2];
+  for (txn, delta) in txns.iter().zip(deltas.iter()) {
+    txn.atomic_add(b"counter", *delta).await.unwrap();
+  }
+
+  // Transactions conflict on the changelog head, so the one that loses is retried.
+  for (txn, delta) in txns.into_iter().zip(deltas.iter()) {
+    match txn.commit().await {
+      Ok(()) => {}
+      Err(KvError::Conflict) => {
+        let txn = kv.begin_transaction().await.unwrap();
+        txn.atomic_add(b"counter", *delta).await.unwrap();
+        txn.commit().await.unwrap();
+      }
+      Err(e) => panic!("unexpected commit error: {:?}", e),
+    }
+  }
+
+  let txn = kv.begin_read_only_transaction().await.unwrap();
+  let value = txn.get(b"counter").await.unwrap().unwrap();
+  assert_eq!(decode_counter(&value), 3);
+
+  let (entries, next_seq) = read_changelog(&store, 0, 10).await.unwrap();
+  assert_eq!(next_seq, 2);
+  let mut logged = entries
+    .iter()
+    .flat_map(|x| x.ops.iter())
+    .map(|op| {
+      assert_eq!(op.kind, changelog_op::Kind::Add as i32);
+      assert_eq!(op.key, b"counter");
+      decode_counter(&op.value)
+    })
+    .collect::<Vec<_>>();
+  logged.sort_unstable();
+  assert_eq!(logged, deltas);
+}
@@ -0,0 +1,200 @@
+//! The log of control events: namespaces and deployments that were created or deleted.
+//!
+//! Events are stored in the `control_events` set of the system schema, numbered from 1. The graphs
+//! of `sys.rasm` that create or delete a namespace or deployment append its event in the same
+//! transaction, so an event is recorded if and only if its change commits.
+//!
+//! `watchNamespaces` and `watchDeployments` stream the events to clients: a watch reads the log
+//! from where it left off whenever a change is made through this server, and every
+//! `POLL_INTERVAL` to pick up changes made through other servers.
+
+use std::time::Duration;
+
+use anyhow::Result;
+use rdb_analyzer::data::treewalker::serialize::{SerializedVmValue, VmValueEncodeConfig};
+use rdb_proto::proto::{control_event, ControlEvent};
+use thiserror::Error;
+use tokio::sync::{mpsc, watch};
+
+use crate::{auth::AuthScope, state::get_state};
+
+const FIRST_SEQ: u64 = 1;
+const POLL_INTERVAL: Duration = Duration::from_secs(1);
+const READ_BATCH_SIZE: usize = 100;
+const WATCH_BUFFER_SIZE: usize = 16;
+
+#[derive(Error, Debug)]
+pub enum ControlEventError {
+  #[error("unknown control event kind: {0}")]
+  UnknownKind(String),
+}
+
+pub struct ControlEventLog {
+  /// Signalled after each change made through this server.
+  appended: watch::Sender<()>,
+
+  /// Kept so that signals are not dropped while no watch is running.
+  appended_rx: watch::Receiver<()>,
+}
+
+/// The events that a watch sends.
+pub enum WatchFilter {
+  /// Events of the namespaces that a scope allows.
+  Namespaces(AuthScope),
+
+  /// Events of the deployments of a namespace, and the deletion of the namespace, which ends the
+  /// watch.
+  Deployments(String),
+}
+
+impl WatchFilter {
+  /// Whether to send an event, and whether the watch ends after it.
+  fn check(&self, event: &ControlEvent) -> (bool, bool) {
+    match self {
+      Self::Namespaces(scope) => (
+        event.deployment_id.is_empty() && scope.allows_namespace(&event.namespace_id),
+        false,
+      ),
+      Self::Deployments(namespace_id) => {
+        if event.namespace_id != *namespace_id {
+          return (false, false);
+        }
+        if !event.deployment_id.is_empty() {
+          return (true, false);
+        }
+        let deleted = event.kind == control_event::Kind::Deleted as i32;
+        (deleted, deleted)
+      }
+    }
+  }
+}
+
+impl ControlEventLog {
+  pub fn new() -> Self {
+    let (appended, appended_rx) = watch::channel(());
+    Self {
+      appended,
+      appended_rx,
+    }
+  }
+
+  /// Wakes up the watches on this server, after a change whose event was appended has committed.
+  pub fn notify(&self) {
+    let _ = self.appended.send(());
+  }
+
+  /// The sequence number of the next event.
+  pub async fn next_seq(&self) -> Result<u64> {
+    let st = get_state();
+    let res = st
+      .system_schema
+      .exec_ctx
+      .run_exported_graph(
+        &*st.system_store,
+        "next_control_event_seq",
+        &[SerializedVmValue::Null(None)],
+        &ENCODE_CONFIG,
+      )
+      .await?;
+    Ok((res.try_unwrap_int64()? as u64).max(FIRST_SEQ))
+  }
+
+  /// Reads up to `limit` events, starting from `from_seq`.
+  async fn read(&self, from_seq: u64, limit: usize) -> Result<Vec<ControlEvent>> {
+    let st = get_state();
+    let res = st
+      .system_schema
+      .exec_ctx
+      .run_exported_graph(
+        &*st.system_store,
+        "list_control_events",
+        &[
+          SerializedVmValue::Null(None),
+          SerializedVmValue::String(format!("{}", from_seq)),
+          SerializedVmValue::String(format!("{}", from_seq + limit as u64)),
+        ],
+        &ENCODE_CONFIG,
+      )
+      .await?;
+    let mut events = res
+      .try_unwrap_list()?
+      .iter()
+      .map(decode_event)
+      .collect::<Result<Vec<_>>>()?;
+
+    // The list is folded in reverse.
+    events.sort_by_key(|x| x.seq);
+    Ok(events)
+  }
+
+  /// Starts sending the events that pass `filter`, from `from_seq` on, or from the next event if
+  /// `from_seq` is 0. The watch stops when the receiver is dropped, or after sending an error.
+  pub fn watch(
+    &'static self,
+    filter: WatchFilter,
+    from_seq: u64,
+  ) -> mpsc::Receiver<Result<ControlEvent>> {
+    let (tx, rx) = mpsc::channel(WATCH_BUFFER_SIZE);
+    let mut appended = self.appended_rx.clone();
+    tokio::spawn(async move {
+      let mut seq = from_seq;
+      loop {
+        let events = match self.read_from(&mut seq).await {
+          Ok(x) => x,
+          Err(e) => {
+            let _ = tx.send(Err(e)).await;
+            return;
+          }
+        };
+        let caught_up = events.len() < READ_BATCH_SIZE;
+        for event in events {
+          seq = event.seq + 1;
+          let (send, end) = filter.check(&event);
+          if send && tx.send(Ok(event)).await.is_err() {
+            return;
+          }
+          if end {
+            return;
+          }
+        }
+        if caught_up {
+          tokio::select! {
+            _ = tx.closed() => return,
+            _ = appended.changed() => {}
+            _ = tokio::time::sleep(POLL_INTERVAL) => {}
+          }
+        }
+      }
+    });
+    rx
+  }
+
+  async fn read_from(&self, seq: &mut u64) -> Result<Vec<ControlEvent>> {
+    if *seq == 0 {
+      *seq = self.next_seq().await?;
+    }
+    self.read(*seq, READ_BATCH_SIZE).await
+  }
+}
+
+const ENCODE_CONFIG: VmValueEncodeConfig = VmValueEncodeConfig {
+  enable_bytes: false,
+  enable_int64: true,
+  enable_double: false,
+};
+
+fn decode_event(x: &SerializedVmValue) -> Result<ControlEvent> {
+  let m = x.try_unwrap_map(&["seq", "time", "kind", "namespace_id", "deployment_id"])?;
+  let kind = match m.get("kind").unwrap().try_unwrap_string()?.as_str() {
+    "created" => control_event::Kind::Created,
+    "deleted" => control_event::Kind::Deleted,
+    x => return Err(ControlEventError::UnknownKind(x.to_string()).into()),
+  };
+  Ok(ControlEvent {
+    seq: m.get("seq").unwrap().try_unwrap_int64()? as u64,
+    time: m.get("time").unwrap().try_unwrap_int64()? as u64,
+    kind: kind as i32,
+    namespace_id: m.get("namespace_id").unwrap().try_unwrap_string()?.clone(),
+    deployment_id: m.get("deployment_id").unwrap().try_unwrap_string()?.clone(),
+  })
+}
@@ -1,21 +1,35 @@
-use std::{panic::AssertUnwindSafe, sync::Arc, time::Duration};
+use std::{
+  collections::BTreeMap, future::Future, panic::AssertUnwindSafe, sync::Arc, time::Duration,
+};
 
 use anyhow::Result;
 use futures::FutureExt;
+use maplit::btreemap;
 use rdb_analyzer::data::{
-  kv::KeyValueStore,
+  kv::{KeyValueStore, KvTransaction},
   treewalker::{
-    exec::Executor,
-    serialize::{SerializedVmValue, VmValueEncodeConfig},
+    cursor::{CursorReader, SetCursor},
+    exec::{
+      BulkDeleteOutcome, Change, ExecEnv, ExecError as VmExecError, Executor, ExportPage,
+      FsckReport,
+    },
+    limits::{ExecLimit, ExecLimits},
+    serialize::{SerializedVmValue, TaggedVmValue, VmValueEncodeConfig},
+    trace::ExecTrace,
     vm_value::VmType,
   },
 };
 use tokio::{task::yield_now, time::sleep};
 
-use crate::exec_core::ExecContext;
+use crate::{exec_core::ExecContext, state::get_state};
 use thiserror::Error;
 
-const QUERY_TIMEOUT: Duration = Duration::from_secs(5);
+/// Time limit of graphs run with `run_exported_graph`, which are not subject to the configured
+/// query limits.
+const INTERNAL_QUERY_TIMEOUT: Duration = Duration::from_secs(5);
+
+const DEFAULT_PAGE_SIZE: usize = 100;
+const MAX_PAGE_SIZE: usize = 1000;
 
 #[derive(Error, Debug)]
 pub enum ExecError {
@@ -25,8 +39,58 @@ pub enum ExecError {
   #[error("param count mismatch: expected {0}, got {1}")]
   ParamCountMismatch(usize, usize),
 
-  #[error("query timeout")]
-  Timeout,
+  #[error("invalid param {0}: expected `{1}`: {2}")]
+  InvalidParam(usize, String, String),
+
+  #[error("only read-only graphs can be traced: `{0}`")]
+  TraceGraphNotReadOnly(String),
+
+  #[error("unknown param: {0}")]
+  UnknownParam(String),
+
+  #[error("missing param: {0}")]
+  MissingParam(String),
+}
+
+/// The page of members to return when a graph returns a set stored in the database.
+///
+/// The output is then `{"members": [...], "next": token}` instead of the set itself, where
+/// `next` is null on the last page. See `rdb_analyzer::data::treewalker::cursor`.
+#[derive(Clone, Debug, Default)]
+pub struct PageRequest {
+  /// The `next` token of the previous page. The first page is returned if not set.
+  pub after: Option<String>,
+
+  /// Members per page. Defaults to `DEFAULT_PAGE_SIZE`, and is capped at `MAX_PAGE_SIZE`.
+  pub page_size: Option<usize>,
+}
+
+impl PageRequest {
+  pub fn is_first_page(&self) -> bool {
+    self.after.is_none() && self.page_size.is_none()
+  }
+}
+
+/// The token that a graph is run for. Graphs read it through `env`.
+#[derive(Clone, Debug, Default, Eq, PartialEq, Hash)]
+pub struct Caller {
+  /// Id of the token (see `explorer::token_id`). Empty for the root token, and for all requests
+  /// if authentication is disabled.
+  pub token_id: String,
+
+  /// The role of the token, checked against `@acl` annotations.
+  pub role: Option<String>,
+}
+
+/// Who the output of a graph is serialized for.
+#[derive(Copy, Clone, Debug)]
+pub enum OutputAudience<'a> {
+  /// Internal callers. `@acl` annotations are ignored.
+  Trusted,
+
+  /// A caller authenticated by a token. Fields protected by `@acl` are stripped from the output
+  /// unless the role of the token is allowed.
+  Caller(&'a Caller),
 }
 
 impl ExecContext {
@@ -37,16 +101,264 @@ impl ExecContext {
     params: &[SerializedVmValue],
     serialization_config: &VmValueEncodeConfig,
   ) -> Result<SerializedVmValue> {
-    let run_fut =
-      AssertUnwindSafe(self.run_exported_graph_inner(kv, name, params, serialization_config))
-        .catch_unwind();
-    let timeout_fut = sleep(QUERY_TIMEOUT);
-    tokio::select! {
-      res = run_fut => {
-        res.unwrap_or_else(|_| Err(ExecError::GraphExecutorPanic.into()))
-      }
-      _ = timeout_fut => Err(ExecError::Timeout.into()),
+    self
+      .run_exported_graph_for(
+        kv,
+        name,
+        params,
+        serialization_config,
+        OutputAudience::Trusted,
+        None,
+        &ExecLimits {
+          timeout: Some(INTERNAL_QUERY_TIMEOUT),
+          ..Default::default()
+        },
+        &Default::default(),
+      )
+      .await
+  }
+
+  /// Runs an exported graph. If `changes` is provided, the changes made by the graph are
+  /// appended to it. `page` selects the members returned if the output is a stored set.
+  pub async fn run_exported_graph_for(
+    &self,
+    kv: &dyn KeyValueStore,
+    name: &str,
+    params: &[SerializedVmValue],
+    serialization_config: &VmValueEncodeConfig,
+    audience: OutputAudience<'_>,
+    changes: Option<&mut Vec<Change>>,
+    limits: &ExecLimits,
+    page: &PageRequest,
+  ) -> Result<SerializedVmValue> {
+    let (output, _) = guard_execution(
+      limits,
+      self.run_exported_graph_inner(
+        kv,
+        name,
+        params,
+        serialization_config,
+        audience,
+        false,
+        None,
+        changes,
+        limits,
+        page,
+      ),
+    )
+    .await?;
+    Ok(output)
+  }
+
+  /// Runs an exported graph in an explicit transaction, without committing it. If `changes` is
+  /// provided, the changes made by the graph are appended to it.
+  ///
+  /// A stored set returned by the graph is read outside of the transaction, so its first page
+  /// does not include the writes of the transaction.
+  pub async fn run_exported_graph_in_txn(
+    &self,
+    kv: &dyn KeyValueStore,
+    txn: &dyn KvTransaction,
+    name: &str,
+    params: &[SerializedVmValue],
+    serialization_config: &VmValueEncodeConfig,
+    audience: OutputAudience<'_>,
+    changes: Option<&mut Vec<Change>>,
+    limits: &ExecLimits,
+  ) -> Result<SerializedVmValue> {
+    let (output, _) = guard_execution(
+      limits,
+      self.run_exported_graph_inner(
+        kv,
+        name,
+        params,
+        serialization_config,
+        audience,
+        false,
+        Some(txn),
+        changes,
+        limits,
+        &Default::default(),
+      ),
+    )
+    .await?;
+    Ok(output)
+  }
+
+  /// Inserts null in place of the parameters of an exported graph that are of the `schema`
+  /// pseudo-type, so that callers only pass the others.
+  pub fn with_schema_params(
+    &self,
+    name: &str,
+    params: Vec<SerializedVmValue>,
+  ) -> Result<Vec<SerializedVmValue>> {
+    let graph_index = self.vm().lookup_exported_graph_by_name(name)?;
+    let raw_param_types = &self.vm().script.graphs[graph_index].param_types;
+    let num_schema_params = raw_param_types
+      .iter()
+      .filter(|x| matches!(self.vm().types[**x as usize], VmType::Schema))
+      .count();
+    if raw_param_types.len() - num_schema_params != params.len() {
+      return Err(
+        ExecError::ParamCountMismatch(raw_param_types.len() - num_schema_params, params.len())
+          .into(),
+      );
     }
+    let mut params = params.into_iter();
+    Ok(
+      raw_param_types
+        .iter()
+        .map(|x| match self.vm().types[*x as usize] {
+          VmType::Schema => SerializedVmValue::Null(None),
+          _ => params.next().unwrap(),
+        })
+        .collect(),
+    )
+  }
+
+  /// Like `with_schema_params`, but takes parameters by name. Parameters left out fall back to
+  /// the defaults declared in the `param` block of the script.
+  pub fn with_named_params(
+    &self,
+    name: &str,
+    mut params: BTreeMap<String, SerializedVmValue>,
+  ) -> Result<Vec<SerializedVmValue>> {
+    let vm = self.vm();
+    let graph = &vm.script.graphs[vm.lookup_exported_graph_by_name(name)?];
+    if let Some(x) = params.keys().find(|x| !graph.param_names.contains(x)) {
+      return Err(ExecError::UnknownParam(x.clone()).into());
+    }
+    graph
+      .param_names
+      .iter()
+      .zip(graph.param_types.iter())
+      .map(|(name, ty)| {
+        if matches!(vm.types[*ty as usize], VmType::Schema) {
+          return Ok(SerializedVmValue::Null(None));
+        }
+        if let Some(x) = params.remove(name) {
+          return Ok(x);
+        }
+        let default = vm
+          .script
+          .params
+          .iter()
+          .find(|x| x.name == *name)
+          .and_then(|x| x.default)
+          .ok_or_else(|| ExecError::MissingParam(name.clone()))?;
+        SerializedVmValue::encode(
+          &vm.consts[default as usize],
+          &VmValueEncodeConfig {
+            enable_bytes: true,
+            enable_double: true,
+            enable_int64: true,
+          },
+        )
+      })
+      .collect()
+  }
+
+  /// Runs an exported graph with tracing enabled, for debugging.
+  ///
+  /// Tracing is for inspecting results. Don't let it become a way to write data.
+  pub async fn trace_exported_graph(
+    &self,
+    kv: &dyn KeyValueStore,
+    name: &str,
+    params: &[SerializedVmValue],
+    serialization_config: &VmValueEncodeConfig,
+    audience: OutputAudience<'_>,
+    limits: &ExecLimits,
+  ) -> Result<(SerializedVmValue, ExecTrace)> {
+    let graph_index = self.vm().lookup_exported_graph_by_name(name)?;
+    if !self.vm().is_graph_read_only(graph_index) {
+      return Err(ExecError::TraceGraphNotReadOnly(name.to_string()).into());
+    }
+    let (output, trace) = guard_execution(
+      limits,
+      self.run_exported_graph_inner(
+        kv,
+        name,
+        params,
+        serialization_config,
+        audience,
+        true,
+        None,
+        None,
+        limits,
+        &Default::default(),
+      ),
+    )
+    .await?;
+    Ok((output, trace.unwrap_or_default()))
+  }
+
+  /// Deletes members of a set by primary key. See `Executor::bulk_delete`.
+  pub async fn bulk_delete(
+    &self,
+    kv: &dyn KeyValueStore,
+    set_path: &str,
+    keys: &[SerializedVmValue],
+    chunk_size: usize,
+  ) -> Result<Vec<BulkDeleteOutcome>> {
+    let mut executor = Executor::new(self.vm(), kv, self.type_info());
+    executor.set_sleep_fn(|x| Box::pin(sleep(x)));
+    executor.set_retry_policy(get_state().retry_policy.clone());
+    AssertUnwindSafe(executor.bulk_delete(set_path, keys, chunk_size))
+      .catch_unwind()
+      .await
+      .unwrap_or_else(|_| Err(ExecError::GraphExecutorPanic.into()))
+  }
+
+  /// Reads a page of rows from a set or a table. See `Executor::export_rows`.
+  pub async fn export_rows(
+    &self,
+    kv: &dyn KeyValueStore,
+    path: &str,
+    after: Option<&SerializedVmValue>,
+    limit: usize,
+    serialization_config: &VmValueEncodeConfig,
+  ) -> Result<ExportPage> {
+    let executor = Executor::new(self.vm(), kv, self.type_info());
+    AssertUnwindSafe(executor.export_rows(path, after, limit, serialization_config))
+      .catch_unwind()
+      .await
+      .unwrap_or_else(|_| Err(ExecError::GraphExecutorPanic.into()))
+  }
+
+  /// Writes rows into a set or a table. See `Executor::import_rows`.
+  pub async fn import_rows(
+    &self,
+    kv: &dyn KeyValueStore,
+    path: &str,
+    rows: &[SerializedVmValue],
+    chunk_size: usize,
+  ) -> Result<()> {
+    let mut executor = Executor::new(self.vm(), kv, self.type_info());
+    executor.set_sleep_fn(|x| Box::pin(sleep(x)));
+    executor.set_retry_policy(get_state().retry_policy.clone());
+    AssertUnwindSafe(executor.import_rows(path, rows, chunk_size))
+      .catch_unwind()
+      .await
+      .unwrap_or_else(|_| Err(ExecError::GraphExecutorPanic.into()))
+  }
+
+  /// Checks the stored data for inconsistencies, repairing them if `repair` is set and deleting
+  /// orphan keys if `delete_orphan_keys` is set. See `Executor::fsck`.
+  pub async fn fsck(
+    &self,
+    kv: &dyn KeyValueStore,
+    batch_size: usize,
+    repair: bool,
+    delete_orphan_keys: bool,
+  ) -> Result<FsckReport> {
+    let mut executor = Executor::new(self.vm(), kv, self.type_info());
+    executor.set_sleep_fn(|x| Box::pin(sleep(x)));
+    executor.set_retry_policy(get_state().retry_policy.clone());
+    AssertUnwindSafe(executor.fsck(batch_size, repair, delete_orphan_keys))
+      .catch_unwind()
+      .await
+      .unwrap_or_else(|_| Err(ExecError::GraphExecutorPanic.into()))
   }
 
   async fn run_exported_graph_inner(
@@ -55,7 +367,13 @@ impl ExecContext {
     name: &str,
     params: &[SerializedVmValue],
     serialization_config: &VmValueEncodeConfig,
-  ) -> Result<SerializedVmValue> {
+    audience: OutputAudience<'_>,
+    tracing: bool,
+    txn: Option<&dyn KvTransaction>,
+    changes: Option<&mut Vec<Change>>,
+    limits: &ExecLimits,
+    page: &PageRequest,
+  ) -> Result<(SerializedVmValue, Option<ExecTrace>)> {
     let graph_index = self.vm().lookup_exported_graph_by_name(name)?;
     let param_types = &self.type_info().graphs[graph_index].params;
 
@@ -72,20 +390,118 @@ impl ExecContext {
     let mut executor = Executor::new(self.vm(), kv, self.type_info());
     executor.set_yield_fn(|| Box::pin(yield_now()));
     executor.set_sleep_fn(|x| Box::pin(sleep(x)));
+    executor.set_retry_policy(get_state().retry_policy.clone());
+    executor.set_limits(limits.clone());
+    executor.set_config(get_state().exec_config.clone());
+    match audience {
+      OutputAudience::Caller(caller) => executor.set_env(&ExecEnv {
+        token_id: caller.token_id.clone(),
+        role: caller.role.clone().unwrap_or_default(),
+        ..self.env().clone()
+      }),
+      OutputAudience::Trusted => executor.set_env(self.env()),
+    }
+
+    // Read-only graphs don't need conflict tracking, even if not declared `readonly`.
+    executor.set_read_only(self.vm().is_graph_read_only(graph_index));
+    if tracing {
+      executor.enable_tracing();
+    }
+    if changes.is_some() {
+      executor.enable_change_tracking();
+    }
     let params = params
       .iter()
       .zip(param_types)
       .zip(raw_param_types)
-      .map(|((v, ty), raw_ty)| match raw_ty {
+      .enumerate()
+      .map(|(i, ((v, ty), raw_ty))| match raw_ty {
         VmType::Schema => Ok(self.root_map().clone()),
-        _ => v.decode(ty).map(Arc::new),
+        _ => v
+          .decode(ty)
+          .map(Arc::new)
+          .map_err(|e| ExecError::InvalidParam(i, ty.to_string(), e.to_string()).into()),
       })
       .collect::<Result<Vec<_>>>()?;
-    let output = executor
-      .run_graph(graph_index, &params)
-      .await?
-      .map(|x| SerializedVmValue::encode(&*x, serialization_config))
-      .transpose()?;
-    Ok(output.unwrap_or_else(|| SerializedVmValue::Null(None)))
+    let output = match txn {
+      Some(txn) => {
+        executor
+          .run_graph_in_transaction(graph_index, &params, txn)
+          .await?
+      }
+      None => executor.run_graph(graph_index, &params).await?,
+    };
+    if let Some(changes) = changes {
+      changes.extend(executor.take_changes().unwrap());
+    }
+    let output_acl = &self.type_info().graphs[graph_index].output_acl;
+    let output = match output.as_deref().and_then(SetCursor::from_value) {
+      Some(_) if matches!(audience, OutputAudience::Caller(caller) if !output_acl.is_visible_to(caller.role.as_deref())) => {
+        Some(SerializedVmValue::Null(None))
+      }
+      Some(cursor) => {
+        let reader = match audience {
+          OutputAudience::Trusted => CursorReader::Trusted,
+          OutputAudience::Caller(caller) => CursorReader::Role(caller.role.as_deref()),
+        };
+        let cursor_page = executor
+          .read_cursor_page(
+            &cursor,
+            page.after.as_deref(),
+            page
+              .page_size
+              .unwrap_or(DEFAULT_PAGE_SIZE)
+              .clamp(1, MAX_PAGE_SIZE),
+            serialization_config,
+            reader,
+          )
+          .await?;
+        Some(SerializedVmValue::Tagged(TaggedVmValue::M(btreemap! {
+          "members".to_string() => SerializedVmValue::Tagged(TaggedVmValue::L(cursor_page.members)),
+          "next".to_string() => match cursor_page.next {
+            Some(x) => SerializedVmValue::String(x),
+            None => SerializedVmValue::Null(None),
+          },
+        })))
+      }
+      None => output
+        .map(|x| match audience {
+          OutputAudience::Trusted => SerializedVmValue::encode(&*x, serialization_config),
+          OutputAudience::Caller(caller) => SerializedVmValue::encode_with_acl(
+            &*x,
+            serialization_config,
+            output_acl,
+            caller.role.as_deref(),
+          ),
+        })
+        .transpose()?,
+    };
+    Ok((
+      output.unwrap_or_else(|| SerializedVmValue::Null(None)),
+      executor.take_trace(),
+    ))
+  }
+}
+
+/// Runs a graph execution future, turning panics into errors.
+///
+/// The executor only checks the timeout between nodes. This also enforces it while a node is
+/// waiting, e.g. on a slow KV operation.
+async fn guard_execution<T>(
+  limits: &ExecLimits,
+  fut: impl Future<Output = Result<T>>,
+) -> Result<T> {
+  let run_fut = AssertUnwindSafe(fut).catch_unwind();
+  let timeout_fut = async {
+    match limits.timeout {
+      Some(x) => sleep(x).await,
+      None => futures::future::pending().await,
+    }
+  };
+  tokio::select! {
+    res = run_fut => {
+      res.unwrap_or_else(|_| Err(ExecError::GraphExecutorPanic.into()))
+    }
+    _ = timeout_fut => Err(VmExecError::LimitExceeded(ExecLimit::Timeout(limits.timeout.unwrap())).into()),
   }
 }
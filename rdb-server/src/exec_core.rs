@@ -5,7 +5,8 @@ use rdb_analyzer::{
   data::treewalker::{
     asm::codegen::compile_twscript,
     bytecode::TwScript,
-    exec::generate_root_map,
+    exec::{generate_root_map, ExecEnv},
+    inline::{inline_script, InlineConfig},
     typeck::{GlobalTyckContext, GlobalTypeInfo},
     vm::TwVm,
     vm_value::VmValue,
@@ -14,6 +15,8 @@ use rdb_analyzer::{
   storage_plan::StoragePlan,
 };
 
+use crate::state::get_state;
+
 pub struct SchemaContext {
   pub schema: CompiledSchema,
   pub plan: StoragePlan,
@@ -22,6 +25,8 @@ pub struct SchemaContext {
 pub struct ExecContext {
   _schema_ctx: Arc<SchemaContext>,
   _script: Box<TwScript>,
+  size_estimate: usize,
+  env: ExecEnv,
   dangerous: ManuallyDrop<DangerousExecContext<'static>>,
 }
 
@@ -33,7 +38,54 @@ struct DangerousExecContext<'a> {
 
 impl ExecContext {
   pub fn load(schema_ctx: Arc<SchemaContext>, script: &str) -> Result<Self> {
-    let script = Box::new(compile_twscript(script)?);
+    Self::load_compiled(schema_ctx, compile_twscript(script)?, script.len())
+  }
+
+  /// Like `load`, with an explicit inlining config. For scripts loaded before the server state is
+  /// set.
+  pub fn load_with_inline_config(
+    schema_ctx: Arc<SchemaContext>,
+    script: &str,
+    inline_config: &InlineConfig,
+  ) -> Result<Self> {
+    Self::load_compiled_with_inline_config(
+      schema_ctx,
+      compile_twscript(script)?,
+      script.len(),
+      inline_config,
+    )
+  }
+
+  /// Like `load`, with the script already compiled from `source_len` bytes of source. Scripts
+  /// from `compile_twscript` are already optimized, and calls are inlined here, where the schema
+  /// is known.
+  pub fn load_compiled(
+    schema_ctx: Arc<SchemaContext>,
+    script: TwScript,
+    source_len: usize,
+  ) -> Result<Self> {
+    Self::load_compiled_with_inline_config(
+      schema_ctx,
+      script,
+      source_len,
+      &get_state().inline_config,
+    )
+  }
+
+  fn load_compiled_with_inline_config(
+    schema_ctx: Arc<SchemaContext>,
+    mut script: TwScript,
+    source_len: usize,
+    inline_config: &InlineConfig,
+  ) -> Result<Self> {
+    inline_script(
+      &mut script,
+      &schema_ctx.schema,
+      &schema_ctx.plan,
+      inline_config,
+    )?;
+    let script = Box::new(script);
+    let size_estimate = source_len + rmp_serde::to_vec(&*script)?.len();
     let vm = TwVm::new(&schema_ctx.schema, &schema_ctx.plan, &*script)?;
     let type_info = GlobalTyckContext::new(&vm)?.typeck()?;
     let root_map = Arc::new(generate_root_map(&schema_ctx.schema, &schema_ctx.plan)?);
@@ -48,10 +100,23 @@ impl ExecContext {
     Ok(Self {
       _schema_ctx: schema_ctx,
       _script: script,
+      size_estimate,
+      env: ExecEnv::default(),
       dangerous: dangerous_ctx,
     })
   }
 
+  /// Sets the environment bindings that graphs of the script read with `env`. The role is filled
+  /// in for each run.
+  pub fn with_env(mut self, env: ExecEnv) -> Self {
+    self.env = env;
+    self
+  }
+
+  pub fn env(&self) -> &ExecEnv {
+    &self.env
+  }
+
   pub fn vm<'a>(&'a self) -> &'a TwVm<'a> {
     &self.dangerous.vm
   }
@@ -60,6 +125,12 @@ impl ExecContext {
     &self.dangerous.type_info
   }
 
+  /// Rough memory footprint of the query script, in bytes. The schema is shared between query
+  /// scripts and not included.
+  pub fn size_estimate(&self) -> usize {
+    self.size_estimate
+  }
+
   pub fn root_map<'a>(&'a self) -> &Arc<VmValue<'a>> {
     &self.dangerous.root_map
   }
@@ -0,0 +1,104 @@
+use std::collections::HashSet;
+
+use anyhow::Result;
+use rand::RngCore;
+use rdb_analyzer::data::treewalker::serialize::{SerializedVmValue, TaggedVmValue};
+use sha2::{Digest, Sha256};
+use thiserror::Error;
+
+use crate::sysquery::ExplorerToken;
+
+/// Explorer tokens grant read-only access to an allowlist of graphs in a namespace, with
+/// configured fields redacted from the results.
+///
+/// Only the SHA-256 hash of a token is stored in the system schema, and is used as the token id.
+#[derive(Error, Debug)]
+pub enum ExplorerError {
+  #[error("missing or malformed authorization header")]
+  BadAuthorization,
+
+  #[error("graph not allowed for this token: `{0}`")]
+  GraphNotAllowed(String),
+
+  #[error("graph is not read-only: `{0}`")]
+  GraphNotReadOnly(String),
+
+  #[error("invalid allowed graph: `{0}` (expecting `query_script_id/graph_name`)")]
+  InvalidAllowedGraph(String),
+}
+
+/// Generates a new secret token, returning `(id, token)`.
+pub fn generate_token() -> (String, String) {
+  let mut raw = [0u8; 32];
+  rand::thread_rng().fill_bytes(&mut raw);
+  let token = hex::encode(raw);
+  (token_id(&token), token)
+}
+
+pub fn token_id(token: &str) -> String {
+  let mut hasher = Sha256::new();
+  hasher.update(token.as_bytes());
+  hex::encode(&hasher.finalize()[..])
+}
+
+/// Extracts the token from an `Authorization: Bearer <token>` header value.
+pub fn parse_authorization(header: &str) -> Result<&str> {
+  header
+    .strip_prefix("Bearer ")
+    .map(|x| x.trim())
+    .filter(|x| !x.is_empty())
+    .ok_or_else(|| ExplorerError::BadAuthorization.into())
+}
+
+pub fn validate_allowed_graphs(allowed_graphs: &[String]) -> Result<()> {
+  for x in allowed_graphs {
+    match x.split_once('/') {
+      Some((qs, graph)) if !qs.is_empty() && !graph.is_empty() => {}
+      _ => return Err(ExplorerError::InvalidAllowedGraph(x.clone()).into()),
+    }
+  }
+  Ok(())
+}
+
+impl ExplorerToken {
+  pub fn check_allowed(&self, query_script_id: &str, graph_name: &str) -> Result<()> {
+    let full_name = format!("{}/{}", query_script_id, graph_name);
+    if self.allowed_graphs.contains(&full_name) {
+      Ok(())
+    } else {
+      Err(ExplorerError::GraphNotAllowed(full_name).into())
+    }
+  }
+
+  /// Replaces the values of all map fields named in `redacted_fields` with null, at any depth.
+  pub fn redact(&self, value: &mut SerializedVmValue) {
+    let fields = self
+      .redacted_fields
+      .iter()
+      .map(|x| x.as_str())
+      .collect::<HashSet<_>>();
+    if !fields.is_empty() {
+      redact_fields(value, &fields);
+    }
+  }
+}
+
+fn redact_fields(value: &mut SerializedVmValue, fields: &HashSet<&str>) {
+  match value {
+    SerializedVmValue::Tagged(TaggedVmValue::M(m)) => {
+      for (k, v) in m.iter_mut() {
+        if fields.contains(k.as_str()) {
+          *v = SerializedVmValue::Null(None);
+        } else {
+          redact_fields(v, fields);
+        }
+      }
+    }
+    SerializedVmValue::Tagged(TaggedVmValue::L(l)) => {
+      for v in l.iter_mut() {
+        redact_fields(v, fields);
+      }
+    }
+    _ => {}
+  }
+}
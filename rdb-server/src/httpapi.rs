@@ -1,25 +1,50 @@
-use std::{fmt::Debug, net::ToSocketAddrs, sync::Arc};
+use std::{collections::BTreeMap, fmt::Debug, net::ToSocketAddrs, sync::Arc, time::Duration};
 
 use anyhow::Result;
-use bumpalo::Bump;
 use bytes::Bytes;
-use rdb_analyzer::{
-  data::treewalker::serialize::{SerializedVmValue, VmValueEncodeConfig},
-  schema::{compile::compile, grammar::parse},
-  storage_plan::StoragePlan,
+use futures::{SinkExt, StreamExt};
+use rdb_analyzer::data::{
+  kv::KeyValueStore,
+  mount::MountError,
+  treewalker::{
+    cursor::CursorError,
+    exec::{ExecEnv, ExecError},
+    limits::ExecLimits,
+    serialize::{SerializedVmValue, VmValueEncodeConfig},
+    trace::ExecTrace,
+  },
 };
+use serde::Deserialize;
+use tokio::sync::broadcast::{self, error::RecvError};
 use warp::{
+  http::{header::RETRY_AFTER, HeaderValue, StatusCode},
   hyper::{Body, Response},
   reject::Reject,
-  reply::Json,
-  Filter, Rejection,
+  reply::{Json, WithStatus},
+  ws::{Message, WebSocket, Ws},
+  Filter, Rejection, Reply,
 };
 
 use crate::{
-  exec_core::{ExecContext, SchemaContext},
+  api_token::{authorize_api_token, ApiTokenError},
+  auth::{AuthError, AuthScope, TokenRegistry},
+  exec::{Caller, ExecError as ServerExecError, OutputAudience, PageRequest},
+  exec_core::ExecContext,
+  explorer::{parse_authorization, token_id, ExplorerError},
+  kv_profile::{KvOpCounts, ProfiledKvStore, KV_OPS_HEADER},
+  metering::{open_query_store, MeteringError},
+  metrics::{encode_metrics, QUERY_DURATION},
+  mount::load_query_script,
   query_cache::QueryCacheKey,
+  rate_limit::{check_namespace_rate_limit, RateLimitError},
+  result_cache::ResultCacheKey,
+  routing::{Route, ShadowQuery},
   state::get_state,
-  sysquery::{lookup_deployment, lookup_query_script, ns_to_kv_prefix_with_appended_zero},
+  subscriptions::ChangeEvent,
+  sysquery::{
+    lookup_explorer_token, lookup_query_script, ns_to_kv_prefix_with_appended_zero, QueryScript,
+    SysQueryError,
+  },
 };
 
 struct ApiReject(anyhow::Error);
@@ -39,11 +64,133 @@ impl Debug for ApiReject {
 
 impl Reject for ApiReject {}
 
+/// Body of `POST /v1/query/{namespace}/{script}/{graph}`.
+#[derive(Deserialize)]
+#[serde(deny_unknown_fields)]
+struct V1QueryRequest {
+  /// Graph parameters, either as a list in declaration order or as an object keyed by name.
+  /// Parameters of the `schema` type are left out. Each is decoded according to the declared
+  /// type of its parameter.
+  #[serde(default)]
+  params: V1Params,
+
+  #[serde(default)]
+  encoding: V1Encoding,
+
+  /// Run the graph with tracing enabled and return the trace along with the output. Only
+  /// allowed for read-only graphs.
+  #[serde(default)]
+  trace: bool,
+
+  #[serde(default)]
+  limits: V1Limits,
+
+  /// If the graph returns a set stored in the database, the `next` token of the previous page
+  /// of its members.
+  #[serde(default)]
+  after: Option<String>,
+
+  /// Members per page of a returned set.
+  #[serde(default)]
+  page_size: Option<usize>,
+}
+
+impl V1QueryRequest {
+  fn page(&self) -> PageRequest {
+    PageRequest {
+      after: self.after.clone(),
+      page_size: self.page_size,
+    }
+  }
+}
+
+#[derive(Deserialize)]
+#[serde(untagged)]
+enum V1Params {
+  Positional(Vec<SerializedVmValue>),
+
+  /// Named parameters. Those left out take the defaults declared in the script.
+  Named(BTreeMap<String, SerializedVmValue>),
+}
+
+impl Default for V1Params {
+  fn default() -> Self {
+    Self::Positional(vec![])
+  }
+}
+
+impl V1Params {
+  fn into_graph_params(
+    self,
+    exec_ctx: &ExecContext,
+    graph_name: &str,
+  ) -> Result<Vec<SerializedVmValue>> {
+    match self {
+      Self::Positional(x) => exec_ctx.with_schema_params(graph_name, x),
+      Self::Named(x) => exec_ctx.with_named_params(graph_name, x),
+    }
+  }
+}
+
+/// Limits of the query. Each only takes effect if stricter than the limit configured on the
+/// server.
+#[derive(Deserialize, Default)]
+#[serde(deny_unknown_fields)]
+struct V1Limits {
+  timeout_ms: Option<u64>,
+  max_kv_reads: Option<u64>,
+  max_kv_writes: Option<u64>,
+  max_subgraph_calls: Option<u64>,
+  max_value_size: Option<usize>,
+  max_recursion_depth: Option<usize>,
+}
+
+impl From<&V1Limits> for ExecLimits {
+  fn from(x: &V1Limits) -> Self {
+    Self {
+      timeout: x.timeout_ms.map(Duration::from_millis),
+      max_kv_reads: x.max_kv_reads,
+      max_kv_writes: x.max_kv_writes,
+      max_subgraph_calls: x.max_subgraph_calls,
+      max_value_size: x.max_value_size,
+      max_recursion_depth: x.max_recursion_depth.unwrap_or(usize::MAX),
+    }
+  }
+}
+
+/// How values in the result are encoded. Each is encoded as a string unless enabled.
+#[derive(Deserialize, Default)]
+#[serde(deny_unknown_fields)]
+struct V1Encoding {
+  /// Encode `int64` as JSON numbers. Values beyond 2^53 may lose precision in some clients.
+  #[serde(default)]
+  int64: bool,
+
+  /// Encode `double` as JSON numbers.
+  #[serde(default)]
+  double: bool,
+
+  /// Encode `bytes` as arrays of numbers instead of base64 strings.
+  #[serde(default)]
+  bytes: bool,
+}
+
+impl From<&V1Encoding> for VmValueEncodeConfig {
+  fn from(x: &V1Encoding) -> Self {
+    Self {
+      enable_bytes: x.bytes,
+      enable_int64: x.int64,
+      enable_double: x.double,
+    }
+  }
+}
+
 pub async fn run_http_server(addr: impl ToSocketAddrs) -> ! {
   let query_route_json = warp::path("query")
     .and(warp::path::param()) // namespace
     .and(warp::path::param()) // query script id
     .and(warp::path::param()) // name of the graph
+    .and(with_auth())
     .and(warp::filters::header::exact(
       "Content-Type",
       "application/json",
@@ -55,6 +202,7 @@ pub async fn run_http_server(addr: impl ToSocketAddrs) -> ! {
     .and(warp::path::param()) // namespace
     .and(warp::path::param()) // query script id
     .and(warp::path::param()) // name of the graph
+    .and(with_auth())
     .and(warp::filters::header::exact(
       "Content-Type",
       "application/x-msgpack",
@@ -62,7 +210,53 @@ pub async fn run_http_server(addr: impl ToSocketAddrs) -> ! {
     .and(warp::body::content_length_limit(1024 * 256))
     .and(warp::body::bytes())
     .and_then(invoke_query_msgpack);
-  let routes = warp::post().and(query_route_json.or(query_route_msgpack));
+  let query_route_v1 = warp::path!("v1" / "query" / String / String / String)
+    .and(with_auth())
+    .and(warp::filters::header::exact(
+      "Content-Type",
+      "application/json",
+    ))
+    .and(warp::body::content_length_limit(1024 * 256))
+    .and(warp::body::json())
+    .and_then(invoke_query_v1);
+  let invoke_route_v1 = warp::path!("v1" / "invoke" / String / String / String)
+    .and(warp::filters::header::header("Authorization"))
+    .and(warp::filters::header::exact(
+      "Content-Type",
+      "application/json",
+    ))
+    .and(warp::body::content_length_limit(1024 * 256))
+    .and(warp::body::json())
+    .and_then(invoke_graph_v1);
+  let explore_route = warp::path("explore")
+    .and(warp::path::param()) // namespace
+    .and(warp::path::param()) // query script id
+    .and(warp::path::param()) // name of the graph
+    .and(warp::filters::header::header("Authorization"))
+    .and(warp::filters::header::exact(
+      "Content-Type",
+      "application/json",
+    ))
+    .and(warp::body::content_length_limit(1024 * 256))
+    .and(warp::body::json())
+    .and_then(invoke_explore);
+  let metrics_route = warp::path!("metrics")
+    .and(with_auth())
+    .and_then(get_metrics);
+  let subscribe_route = warp::path!("v1" / "subscribe" / String / String)
+    .and(with_auth())
+    .and(warp::ws())
+    .and_then(subscribe);
+  let routes = warp::post()
+    .and(
+      query_route_json
+        .or(query_route_msgpack)
+        .or(query_route_v1)
+        .or(invoke_route_v1)
+        .or(explore_route),
+    )
+    .or(warp::get().and(subscribe_route.or(metrics_route)))
+    .recover(handle_rejection);
   let addr = addr
     .to_socket_addrs()
     .unwrap()
@@ -72,21 +266,325 @@ pub async fn run_http_server(addr: impl ToSocketAddrs) -> ! {
   unreachable!()
 }
 
+/// Authenticates a request by its `Authorization` header. All requests are allowed if
+/// authentication is disabled.
+fn with_auth() -> impl Filter<Extract = (AuthScope,), Error = Rejection> + Clone {
+  with_auth_from(get_state().token_registry.clone())
+}
+
+/// Authenticates a request by its `Authorization` header. Everything about the caller, including
+/// the role checked against `@acl` annotations, comes from the token.
+pub fn with_auth_from(
+  registry: Arc<TokenRegistry>,
+) -> impl Filter<Extract = (AuthScope,), Error = Rejection> + Clone {
+  warp::filters::header::optional::<String>("Authorization").and_then(
+    move |authorization: Option<String>| {
+      let registry = registry.clone();
+      async move {
+        registry
+          .authenticate(authorization.as_deref())
+          .map_err(|e| warp::reject::custom(ApiReject::new(e)))
+      }
+    },
+  )
+}
+
+/// Turns invalid params, constraint violations and transaction conflicts into structured errors.
+/// Other rejections are left to warp.
+async fn handle_rejection(err: Rejection) -> Result<Response<Body>, Rejection> {
+  if let Some(e) = err
+    .find::<ApiReject>()
+    .and_then(|ApiReject(e)| e.downcast_ref::<RateLimitError>())
+  {
+    let mut res = warp::reply::with_status(
+      warp::reply::json(&serde_json::json!({
+        "error": "rate_limited",
+        "message": e.to_string(),
+      })),
+      StatusCode::TOO_MANY_REQUESTS,
+    )
+    .into_response();
+    res
+      .headers_mut()
+      .insert(RETRY_AFTER, HeaderValue::from(e.retry_after_secs()));
+    return Ok(res);
+  }
+  map_rejection(err).await.map(|x| x.into_response())
+}
+
+async fn map_rejection(err: Rejection) -> Result<WithStatus<Json>, Rejection> {
+  if let Some(ApiReject(e)) = err.find::<ApiReject>() {
+    match e.downcast_ref::<ExecError>() {
+      Some(e @ ExecError::LimitExceeded(limit)) => {
+        return Ok(warp::reply::with_status(
+          warp::reply::json(&serde_json::json!({
+            "error": "limit_exceeded",
+            "limit": limit.name(),
+            "message": e.to_string(),
+          })),
+          StatusCode::UNPROCESSABLE_ENTITY,
+        ));
+      }
+      Some(e @ ExecError::UniqueConstraintViolation(_)) => {
+        return Ok(warp::reply::with_status(
+          warp::reply::json(&serde_json::json!({
+            "error": "constraint_violation",
+            "message": e.to_string(),
+          })),
+          StatusCode::CONFLICT,
+        ));
+      }
+      Some(e @ ExecError::VersionConflict(expected, actual)) => {
+        return Ok(warp::reply::with_status(
+          warp::reply::json(&serde_json::json!({
+            "error": "version_conflict",
+            "expected": expected,
+            "actual": actual,
+            "message": e.to_string(),
+          })),
+          StatusCode::CONFLICT,
+        ));
+      }
+      Some(e) if e.is_script_error() => {
+        return Ok(warp::reply::with_status(
+          warp::reply::json(&serde_json::json!({
+            "error": "script_error",
+            "message": e.to_string(),
+          })),
+          StatusCode::UNPROCESSABLE_ENTITY,
+        ));
+      }
+      Some(e @ ExecError::ConflictAfterRetries) => {
+        return Ok(warp::reply::with_status(
+          warp::reply::json(&serde_json::json!({
+            "error": "conflict",
+            "message": e.to_string(),
+          })),
+          StatusCode::SERVICE_UNAVAILABLE,
+        ));
+      }
+      _ => {}
+    }
+    if let Some(
+      e @ (ServerExecError::ParamCountMismatch(..)
+      | ServerExecError::InvalidParam(..)
+      | ServerExecError::UnknownParam(_)
+      | ServerExecError::MissingParam(_)),
+    ) = e.downcast_ref::<ServerExecError>()
+    {
+      return Ok(warp::reply::with_status(
+        warp::reply::json(&serde_json::json!({
+          "error": "invalid_params",
+          "message": e.to_string(),
+        })),
+        StatusCode::BAD_REQUEST,
+      ));
+    }
+    if let Some(e @ CursorError::InvalidToken) = e.downcast_ref::<CursorError>() {
+      return Ok(warp::reply::with_status(
+        warp::reply::json(&serde_json::json!({
+          "error": "invalid_params",
+          "message": e.to_string(),
+        })),
+        StatusCode::BAD_REQUEST,
+      ));
+    }
+    if let Some(e @ ServerExecError::TraceGraphNotReadOnly(_)) = e.downcast_ref::<ServerExecError>()
+    {
+      return Ok(warp::reply::with_status(
+        warp::reply::json(&serde_json::json!({
+          "error": "trace_not_allowed",
+          "message": e.to_string(),
+        })),
+        StatusCode::BAD_REQUEST,
+      ));
+    }
+    if let Some(e @ MountError::ReadOnly) = e.downcast_ref::<MountError>() {
+      return Ok(warp::reply::with_status(
+        warp::reply::json(&serde_json::json!({
+          "error": "script_error",
+          "message": e.to_string(),
+        })),
+        StatusCode::UNPROCESSABLE_ENTITY,
+      ));
+    }
+    if let Some(e @ MeteringError::QuotaExceeded(..)) = e.downcast_ref::<MeteringError>() {
+      return Ok(warp::reply::with_status(
+        warp::reply::json(&serde_json::json!({
+          "error": "quota_exceeded",
+          "message": e.to_string(),
+        })),
+        StatusCode::TOO_MANY_REQUESTS,
+      ));
+    }
+    if matches!(
+      e.downcast_ref::<ExplorerError>(),
+      Some(ExplorerError::BadAuthorization)
+    ) || matches!(
+      e.downcast_ref::<SysQueryError>(),
+      Some(SysQueryError::ApiTokenNotFound)
+    ) || matches!(
+      e.downcast_ref::<AuthError>(),
+      Some(AuthError::BadAuthorization | AuthError::InvalidToken)
+    ) {
+      return Ok(warp::reply::with_status(
+        warp::reply::json(&serde_json::json!({
+          "error": "unauthorized",
+          "message": e.to_string(),
+        })),
+        StatusCode::UNAUTHORIZED,
+      ));
+    }
+    if let Some(
+      e @ (AuthError::NamespaceNotAllowed(_)
+      | AuthError::RootRequired
+      | AuthError::MountNotAllowed(_)),
+    ) = e.downcast_ref::<AuthError>()
+    {
+      return Ok(warp::reply::with_status(
+        warp::reply::json(&serde_json::json!({
+          "error": "forbidden",
+          "message": e.to_string(),
+        })),
+        StatusCode::FORBIDDEN,
+      ));
+    }
+    if let Some(e) = e.downcast_ref::<ApiTokenError>() {
+      return Ok(warp::reply::with_status(
+        warp::reply::json(&serde_json::json!({
+          "error": "forbidden",
+          "message": e.to_string(),
+        })),
+        StatusCode::FORBIDDEN,
+      ));
+    }
+  }
+  Err(err)
+}
+
+/// Attaches the KV operation counts of a query to its response, if KV profiling is enabled.
+fn with_kv_ops(mut res: Response<Body>, kv_ops: Option<KvOpCounts>) -> Response<Body> {
+  if let Some(x) = kv_ops {
+    let value = serde_json::to_string(&x).expect("cannot serialize kv op counts");
+    res.headers_mut().insert(
+      KV_OPS_HEADER,
+      HeaderValue::from_str(&value).expect("invalid kv op counts header"),
+    );
+  }
+  res
+}
+
 async fn invoke_query(
   namespace_id: String,
   query_script_id: String,
   graph_name: String,
+  scope: AuthScope,
   graph_params: Vec<SerializedVmValue>,
-) -> Result<Json, Rejection> {
+) -> Result<Response<Body>, Rejection> {
+  scope
+    .check_namespace(&namespace_id)
+    .map_err(|e| warp::reject::custom(ApiReject::new(e)))?;
   do_invoke_query(
     namespace_id,
     query_script_id,
     graph_name,
+    scope.caller(),
     graph_params,
     &Default::default(),
+    &get_state().exec_limits,
+    &Default::default(),
   )
   .await
-  .map(|x| warp::reply::json(&x))
+  .map(|(x, kv_ops)| with_kv_ops(warp::reply::json(&x).into_response(), kv_ops))
+  .map_err(|e| warp::reject::custom(ApiReject::new(e)))
+}
+
+async fn invoke_query_v1(
+  namespace_id: String,
+  query_script_id: String,
+  graph_name: String,
+  scope: AuthScope,
+  req: V1QueryRequest,
+) -> Result<Response<Body>, Rejection> {
+  async {
+    scope.check_namespace(&namespace_id)?;
+    let exec_ctx = load_exec_ctx(&namespace_id, &query_script_id).await?;
+    let page = req.page();
+    let graph_params = req.params.into_graph_params(&exec_ctx, &graph_name)?;
+    let serialization_config = VmValueEncodeConfig::from(&req.encoding);
+    let limits = get_state()
+      .exec_limits
+      .tighten(&ExecLimits::from(&req.limits));
+    if req.trace {
+      let (output, trace) = do_trace_query(
+        &namespace_id,
+        &exec_ctx,
+        &graph_name,
+        &scope.caller(),
+        &graph_params,
+        &serialization_config,
+        &limits,
+      )
+      .await?;
+      return Ok(
+        warp::reply::json(&serde_json::json!({
+          "output": output,
+          "trace": trace,
+        }))
+        .into_response(),
+      );
+    }
+    do_invoke_query(
+      namespace_id,
+      query_script_id,
+      graph_name,
+      scope.caller(),
+      graph_params,
+      &serialization_config,
+      &limits,
+      &page,
+    )
+    .await
+    .map(|(x, kv_ops)| with_kv_ops(warp::reply::json(&x).into_response(), kv_ops))
+  }
+  .await
+  .map_err(|e| warp::reject::custom(ApiReject::new(e)))
+}
+
+async fn invoke_graph_v1(
+  namespace_id: String,
+  query_script_id: String,
+  graph_name: String,
+  authorization: String,
+  req: V1QueryRequest,
+) -> Result<Response<Body>, Rejection> {
+  async {
+    let token = parse_authorization(&authorization)?;
+    authorize_api_token(&namespace_id, &query_script_id, &graph_name, token).await?;
+    let exec_ctx = load_exec_ctx(&namespace_id, &query_script_id).await?;
+    let page = req.page();
+    let graph_params = req.params.into_graph_params(&exec_ctx, &graph_name)?;
+
+    // API tokens do not carry a role, so all `@acl` protected fields are stripped.
+    do_invoke_query(
+      namespace_id,
+      query_script_id,
+      graph_name,
+      Caller {
+        token_id: token_id(token),
+        role: None,
+      },
+      graph_params,
+      &VmValueEncodeConfig::from(&req.encoding),
+      &get_state()
+        .exec_limits
+        .tighten(&ExecLimits::from(&req.limits)),
+      &page,
+    )
+    .await
+  }
+  .await
+  .map(|(x, kv_ops)| with_kv_ops(warp::reply::json(&x).into_response(), kv_ops))
   .map_err(|e| warp::reject::custom(ApiReject::new(e)))
 }
 
@@ -94,75 +592,384 @@ async fn invoke_query_msgpack(
   namespace_id: String,
   query_script_id: String,
   graph_name: String,
+  scope: AuthScope,
   graph_params: Bytes,
 ) -> Result<Response<Body>, Rejection> {
+  scope
+    .check_namespace(&namespace_id)
+    .map_err(|e| warp::reject::custom(ApiReject::new(e)))?;
   let graph_params: Vec<SerializedVmValue> = rmp_serde::from_slice(&graph_params)
     .map_err(|e| warp::reject::custom(ApiReject::new(anyhow::Error::from(e))))?;
   do_invoke_query(
     namespace_id,
     query_script_id,
     graph_name,
+    scope.caller(),
     graph_params,
     &VmValueEncodeConfig {
       enable_bytes: true,
       enable_double: true,
       enable_int64: true,
     },
+    &get_state().exec_limits,
+    &Default::default(),
   )
   .await
-  .and_then(|x| rmp_serde::to_vec_named(&x).map_err(anyhow::Error::from))
-  .and_then(|x| {
-    Response::builder()
+  .and_then(|(x, kv_ops)| {
+    let x = rmp_serde::to_vec_named(&x)?;
+    let res = Response::builder()
       .header("Content-Type", "application/x-msgpack")
-      .body(Body::from(x))
-      .map_err(anyhow::Error::from)
+      .body(Body::from(x))?;
+    Ok(with_kv_ops(res, kv_ops))
   })
   .map_err(|e| warp::reject::custom(ApiReject::new(e)))
 }
 
-async fn do_invoke_query(
+async fn subscribe(
+  namespace_id: String,
+  path: String,
+  scope: AuthScope,
+  ws: Ws,
+) -> Result<impl Reply, Rejection> {
+  scope
+    .check_namespace(&namespace_id)
+    .map_err(|e| warp::reject::custom(ApiReject::new(e)))?;
+  ns_to_kv_prefix_with_appended_zero(&namespace_id)
+    .await
+    .map_err(|e| warp::reject::custom(ApiReject::new(e)))?;
+  let rx = get_state().subscription_hub.subscribe(&namespace_id);
+  Ok(ws.on_upgrade(move |socket| forward_changes(socket, rx, path)))
+}
+
+/// Sends the changes on or below `path` to a subscriber as JSON messages, until it disconnects.
+async fn forward_changes(
+  socket: WebSocket,
+  mut rx: broadcast::Receiver<Arc<ChangeEvent>>,
+  path: String,
+) {
+  let path = path.split('.').collect::<Vec<_>>();
+  let (mut sink, mut stream) = socket.split();
+  loop {
+    tokio::select! {
+      event = rx.recv() => {
+        let msg = match event {
+          Ok(x) if x.matches(&path) => {
+            serde_json::to_string(&*x).expect("cannot serialize change event")
+          }
+          Ok(_) => continue,
+
+          // The subscriber has to catch up by other means.
+          Err(RecvError::Lagged(n)) => serde_json::json!({
+            "op": "lagged",
+            "missed": n,
+          })
+          .to_string(),
+          Err(RecvError::Closed) => break,
+        };
+        if sink.send(Message::text(msg)).await.is_err() {
+          break;
+        }
+      }
+      msg = stream.next() => match msg {
+        Some(Ok(x)) if !x.is_close() => {}
+        _ => break,
+      },
+    }
+  }
+}
+
+async fn get_metrics(scope: AuthScope) -> Result<impl Reply, Rejection> {
+  scope
+    .check_root()
+    .map_err(|e| warp::reject::custom(ApiReject::new(e)))?;
+  Ok(warp::reply::with_header(
+    encode_metrics(),
+    "Content-Type",
+    "text/plain; version=0.0.4",
+  ))
+}
+
+async fn invoke_explore(
+  namespace_id: String,
+  query_script_id: String,
+  graph_name: String,
+  authorization: String,
+  graph_params: Vec<SerializedVmValue>,
+) -> Result<Json, Rejection> {
+  do_invoke_explore(
+    namespace_id,
+    query_script_id,
+    graph_name,
+    authorization,
+    graph_params,
+  )
+  .await
+  .map(|x| warp::reply::json(&x))
+  .map_err(|e| warp::reject::custom(ApiReject::new(e)))
+}
+
+async fn do_invoke_explore(
   namespace_id: String,
   query_script_id: String,
   graph_name: String,
+  authorization: String,
   graph_params: Vec<SerializedVmValue>,
-  serialization_config: &VmValueEncodeConfig,
 ) -> Result<SerializedVmValue> {
+  let caller = Caller {
+    token_id: token_id(parse_authorization(&authorization)?),
+    role: None,
+  };
+  let token = lookup_explorer_token(&namespace_id, &caller.token_id).await?;
+  token.check_allowed(&query_script_id, &graph_name)?;
+
+  let exec_ctx = load_exec_ctx(&namespace_id, &query_script_id).await?;
+  let kv = open_query_store(&namespace_id, exec_ctx.vm().schema).await?;
+
+  // The allowlist is checked against names only, so the graph may have been changed to a
+  // writing one since the token was issued.
+  let graph_index = exec_ctx.vm().lookup_exported_graph_by_name(&graph_name)?;
+  if !exec_ctx.vm().is_graph_read_only(graph_index) {
+    return Err(ExplorerError::GraphNotReadOnly(graph_name).into());
+  }
+
+  // Explorer tokens do not carry a role, so all `@acl` protected fields are stripped.
+  let mut output = exec_ctx
+    .run_exported_graph_for(
+      &*kv,
+      &graph_name,
+      &graph_params,
+      &Default::default(),
+      OutputAudience::Caller(&caller),
+      None,
+      &get_state().exec_limits,
+      &Default::default(),
+    )
+    .await?;
+  token.redact(&mut output);
+  Ok(output)
+}
+
+pub async fn do_invoke_query(
+  namespace_id: String,
+  query_script_id: String,
+  graph_name: String,
+  caller: Caller,
+  graph_params: Vec<SerializedVmValue>,
+  serialization_config: &VmValueEncodeConfig,
+  limits: &ExecLimits,
+  page: &PageRequest,
+) -> Result<(SerializedVmValue, Option<KvOpCounts>)> {
+  check_namespace_rate_limit(&namespace_id)?;
   let st = get_state();
-  let kv_prefix = ns_to_kv_prefix_with_appended_zero(&namespace_id).await?;
-  let kv = (st.data_store_generator)(&kv_prefix);
+  let _timer = QUERY_DURATION
+    .with_label_values(&[&namespace_id])
+    .start_timer();
+  let mut exec_ctx = load_exec_ctx(&namespace_id, &query_script_id).await?;
+  let read_only = exec_ctx
+    .vm()
+    .is_graph_read_only(exec_ctx.vm().lookup_exported_graph_by_name(&graph_name)?);
 
-  let exec_ctx;
-  if let Some(x) = st
-    .query_cache
-    .get_hot(&namespace_id, &query_script_id)
-    .await
-  {
-    exec_ctx = x;
+  let route = if read_only {
+    st.deployment_router
+      .route(&namespace_id, &query_script_id, &graph_name, &exec_ctx)
+      .await?
   } else {
-    let query_script = lookup_query_script(&namespace_id, &query_script_id).await?;
-
-    let qc_key = QueryCacheKey {
-      namespace_id: namespace_id.clone(),
-      query_script_id: query_script_id.clone(),
-      deployment_id: query_script.associated_deployment.clone(),
-      query_script_create_time: query_script.create_time,
-    };
-    if let Some(x) = st.query_cache.get(&qc_key).await {
-      exec_ctx = x;
-    } else {
-      let deployment =
-        lookup_deployment(&namespace_id, &query_script.associated_deployment).await?;
-      let schema = compile(&parse(&Bump::new(), &deployment.schema)?)?;
-      let plan = StoragePlan::deserialize_compressed(&deployment.plan)?;
-      let schema_ctx = Arc::new(SchemaContext { schema, plan });
-      exec_ctx = Arc::new(ExecContext::load(schema_ctx, &query_script.script)?);
-      log::info!("Loaded query script {:?}.", qc_key);
-      st.query_cache.put(qc_key, exec_ctx.clone()).await;
+    Route::Primary
+  };
+  let mut shadow = None;
+  let mut canary = false;
+  match route {
+    Route::Primary => {}
+    Route::Canary(candidate) => {
+      exec_ctx = candidate;
+      canary = true;
+    }
+    Route::Shadow(candidate) => {
+      shadow = Some(ShadowQuery {
+        namespace_id: namespace_id.clone(),
+        candidate,
+        graph_name: graph_name.clone(),
+        caller: caller.clone(),
+        graph_params: graph_params.clone(),
+        serialization_config: serialization_config.clone(),
+        limits: limits.clone(),
+        page: page.clone(),
+      });
+    }
+  }
+
+  let graph_index = exec_ctx.vm().lookup_exported_graph_by_name(&graph_name)?;
+  let kv = open_query_store(&namespace_id, exec_ctx.vm().schema).await?;
+  let (kv, kv_counters): (Box<dyn KeyValueStore>, _) = if st.kv_profiling {
+    let kv = ProfiledKvStore::new(kv);
+    let counters = kv.counters().clone();
+    (Box::new(kv), Some(counters))
+  } else {
+    (kv, None)
+  };
+
+  // `TwVm` rejects cache directives on graphs that are not read-only. Results of the candidate
+  // deployment are not cached, as the cache key does not tell deployments apart, and neither
+  // are pages of a set other than the first one.
+  let cache = exec_ctx.vm().script.graphs[graph_index]
+    .cache
+    .as_ref()
+    .filter(|_| !canary && page.is_first_page())
+    .map(|directive| {
+      let key = ResultCacheKey::new(
+        &namespace_id,
+        &query_script_id,
+        &graph_name,
+        directive,
+        &graph_params,
+        &caller,
+        serialization_config,
+      );
+      (directive, key)
+    });
+  if let Some((_, key)) = &cache {
+    if let Some(x) = st.result_cache.get(key).await {
+      return Ok((x, kv_counters.map(|x| x.snapshot())));
     }
   }
 
+  // Take the generation before running, so that a write racing with us is not lost.
+  let generation = st.result_cache.generation(&namespace_id);
+  let mut changes = if !read_only && st.subscription_hub.has_subscribers(&namespace_id) {
+    Some(vec![])
+  } else {
+    None
+  };
   let output = exec_ctx
-    .run_exported_graph(&*kv, &graph_name, &graph_params, serialization_config)
+    .run_exported_graph_for(
+      &*kv,
+      &graph_name,
+      &graph_params,
+      serialization_config,
+      OutputAudience::Caller(&caller),
+      changes.as_mut(),
+      limits,
+      page,
+    )
+    .await;
+
+  // Even a failed graph may have committed some of its writes.
+  if !read_only {
+    st.result_cache.bump_generation(&namespace_id);
+  }
+  let output = output?;
+  if let Some(changes) = changes {
+    st.subscription_hub.publish(&namespace_id, changes);
+  }
+  if let Some(shadow) = shadow {
+    shadow.spawn(output.clone());
+  }
+
+  if let Some((directive, key)) = cache {
+    st.result_cache
+      .put(key, generation, directive, output.clone())
+      .await;
+  }
+  Ok((output, kv_counters.map(|x| x.snapshot())))
+}
+
+/// Runs a read-only graph with tracing enabled. The result cache is bypassed, since a cached
+/// result has no trace.
+///
+/// Value summaries are stripped from the trace, as they are not checked against `@acl`
+/// annotations.
+async fn do_trace_query(
+  namespace_id: &str,
+  exec_ctx: &ExecContext,
+  graph_name: &str,
+  caller: &Caller,
+  graph_params: &[SerializedVmValue],
+  serialization_config: &VmValueEncodeConfig,
+  limits: &ExecLimits,
+) -> Result<(SerializedVmValue, ExecTrace)> {
+  check_namespace_rate_limit(namespace_id)?;
+  let _timer = QUERY_DURATION
+    .with_label_values(&[namespace_id])
+    .start_timer();
+  let kv = open_query_store(namespace_id, exec_ctx.vm().schema).await?;
+  let (output, mut trace) = exec_ctx
+    .trace_exported_graph(
+      &*kv,
+      graph_name,
+      graph_params,
+      serialization_config,
+      OutputAudience::Caller(caller),
+      limits,
+    )
     .await?;
-  Ok(output)
+  trace.strip_values();
+  Ok((output, trace))
+}
+
+pub async fn load_exec_ctx(namespace_id: &str, query_script_id: &str) -> Result<Arc<ExecContext>> {
+  let st = get_state();
+  if let Some(x) = st.query_cache.get_hot(namespace_id, query_script_id).await {
+    return Ok(x);
+  }
+  let query_script = lookup_query_script(namespace_id, query_script_id).await?;
+  load_exec_ctx_with_script(
+    namespace_id,
+    query_script_id,
+    &query_script,
+    &query_script.associated_deployment,
+  )
+  .await
+}
+
+/// Loads a query script on `deployment_id` instead of the deployment it is associated with.
+pub async fn load_exec_ctx_on_deployment(
+  namespace_id: &str,
+  query_script_id: &str,
+  deployment_id: &str,
+) -> Result<Arc<ExecContext>> {
+  let query_script = lookup_query_script(namespace_id, query_script_id).await?;
+  load_exec_ctx_with_script(namespace_id, query_script_id, &query_script, deployment_id).await
+}
+
+async fn load_exec_ctx_with_script(
+  namespace_id: &str,
+  query_script_id: &str,
+  query_script: &QueryScript,
+  deployment_id: &str,
+) -> Result<Arc<ExecContext>> {
+  let st = get_state();
+  let associated = deployment_id == query_script.associated_deployment;
+  let qc_key = QueryCacheKey {
+    namespace_id: namespace_id.to_string(),
+    query_script_id: query_script_id.to_string(),
+    deployment_id: deployment_id.to_string(),
+    query_script_create_time: query_script.create_time,
+  };
+  let cached = if associated {
+    st.query_cache.get(&qc_key).await
+  } else {
+    st.query_cache.lookup(&qc_key).await
+  };
+  if let Some(x) = cached {
+    return Ok(x);
+  }
+
+  let schema_ctx = st
+    .schema_cache
+    .get_or_load(namespace_id, deployment_id)
+    .await?;
+  let exec_ctx = Arc::new(
+    load_query_script(schema_ctx, &query_script.script)
+      .await?
+      .with_env(ExecEnv {
+        namespace_id: namespace_id.to_string(),
+        deployment_id: deployment_id.to_string(),
+        query_script_id: query_script_id.to_string(),
+        token_id: String::new(),
+        role: String::new(),
+      }),
+  );
+  log::info!("Loaded query script {:?}.", qc_key);
+  st.query_cache.put(qc_key, exec_ctx.clone()).await;
+  Ok(exec_ctx)
 }
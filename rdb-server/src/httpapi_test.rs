@@ -0,0 +1,90 @@
+use std::{collections::BTreeSet, sync::Arc, time::Duration};
+
+use bumpalo::Bump;
+use rdb_analyzer::{
+  data::treewalker::{
+    asm::codegen::compile_twscript,
+    exec::{generate_root_map, Executor},
+    serialize::SerializedVmValue,
+    typeck::GlobalTyckContext,
+    vm::TwVm,
+  },
+  kv_backend::memory::MemoryKvStore,
+  schema::{compile::compile, grammar::parse},
+  storage_plan::planner::generate_plan_for_schema,
+};
+
+use crate::{auth::TokenRegistry, explorer::token_id, httpapi::with_auth_from};
+
+#[tokio::test]
+async fn role_comes_from_token() {
+  let registry = TokenRegistry::new(Some("root"), Duration::from_secs(3600));
+  let namespaces: BTreeSet<String> = vec!["a".to_string()].into_iter().collect();
+  registry.insert(token_id("t1"), namespaces.clone(), Default::default(), None);
+  registry.insert(
+    token_id("t2"),
+    namespaces,
+    Default::default(),
+    Some("admin".into()),
+  );
+
+  let alloc = Bump::new();
+  let schema = compile(
+    &parse(
+      &alloc,
+      r#"
+  type Item {
+    @primary
+    id: string,
+    @acl("admin")
+    secret: string,
+  }
+  export set<Item> items;
+  "#,
+    )
+    .unwrap(),
+  )
+  .unwrap();
+  let plan = generate_plan_for_schema(&Default::default(), &Default::default(), &schema).unwrap();
+  let script = compile_twscript(
+    r#"
+    graph add(root: schema) {
+      s_insert root.items $ build_table(Item) $ m_insert(id) "a" $ m_insert(secret) "x" create_map;
+    }
+    graph get(root: schema): map { id: string, secret: string } {
+      item = point_get root.items "a";
+      return m_insert(id) item.id $ m_insert(secret) item.secret create_map;
+    }
+    "#,
+  )
+  .unwrap();
+  let vm = TwVm::new(&schema, &plan, &script).unwrap();
+  let type_info = GlobalTyckContext::new(&vm).unwrap().typeck().unwrap();
+  let kv = MemoryKvStore::new(None).with_prefix(b"");
+  let mut executor = Executor::new(&vm, &kv, &type_info);
+  let root = Arc::new(generate_root_map(&schema, &plan).unwrap());
+  executor.run_graph(0, &[root.clone()]).await.unwrap();
+  let output = executor.run_graph(1, &[root]).await.unwrap().unwrap();
+
+  let filter = with_auth_from(registry);
+  for (token, visible) in [("t1", false), ("t2", true)] {
+    // The header is not trusted, whatever the token.
+    let scope = warp::test::request()
+      .header("Authorization", format!("Bearer {}", token))
+      .header("X-Rdb-Role", "admin")
+      .filter(&filter)
+      .await
+      .unwrap();
+    let caller = scope.caller();
+    assert_eq!(caller.token_id, token_id(token));
+    let encoded = SerializedVmValue::encode_with_acl(
+      &output,
+      &Default::default(),
+      &type_info.graphs[1].output_acl,
+      caller.role.as_deref(),
+    )
+    .unwrap();
+    let fields = encoded.try_unwrap_map(&["id"]).unwrap();
+    assert_eq!(fields.contains_key("secret"), visible, "token {}", token);
+  }
+}
@@ -0,0 +1,222 @@
+use std::{str::FromStr, sync::Mutex};
+
+use anyhow::Result;
+use rand::RngCore;
+use thiserror::Error;
+use uuid::Uuid;
+
+use crate::util::current_millis;
+
+/// 2021-01-01T00:00:00Z, in milliseconds.
+const SNOWFLAKE_EPOCH_MS: u64 = 1609459200000;
+
+/// 2014-05-13T16:53:20Z, in seconds. As defined by the KSUID spec.
+const KSUID_EPOCH_SECS: u64 = 1400000000;
+
+const SNOWFLAKE_NODE_ID_BITS: u32 = 10;
+const SNOWFLAKE_SEQUENCE_BITS: u32 = 12;
+
+const CROCKFORD_BASE32: &[u8; 32] = b"0123456789ABCDEFGHJKMNPQRSTVWXYZ";
+const BASE62: &[u8; 62] = b"0123456789ABCDEFGHIJKLMNOPQRSTUVWXYZabcdefghijklmnopqrstuvwxyz";
+
+#[derive(Error, Debug)]
+pub enum IdGenError {
+  #[error("unknown id strategy: `{0}`")]
+  UnknownStrategy(String),
+
+  #[error("snowflake node id out of range: {0}")]
+  NodeIdOutOfRange(u16),
+}
+
+#[derive(Copy, Clone, Debug, Eq, PartialEq)]
+pub enum IdStrategy {
+  /// Random UUIDv4. Not ordered.
+  Uuid,
+
+  /// 48-bit millisecond timestamp + 80 random bits, Crockford base32 encoded.
+  Ulid,
+
+  /// 32-bit second timestamp + 128 random bits, base62 encoded.
+  Ksuid,
+
+  /// 41-bit millisecond timestamp + 10-bit node id + 12-bit sequence, decimal encoded.
+  Snowflake,
+}
+
+impl IdStrategy {
+  pub fn as_str(&self) -> &'static str {
+    match self {
+      Self::Uuid => "uuid",
+      Self::Ulid => "ulid",
+      Self::Ksuid => "ksuid",
+      Self::Snowflake => "snowflake",
+    }
+  }
+
+  /// Parses the id strategy of a namespace. The empty strategy means the server's default.
+  pub fn parse_namespace_strategy(s: &str) -> Result<Option<Self>, IdGenError> {
+    if s.is_empty() {
+      Ok(None)
+    } else {
+      s.parse().map(Some)
+    }
+  }
+}
+
+impl FromStr for IdStrategy {
+  type Err = IdGenError;
+
+  fn from_str(s: &str) -> Result<Self, Self::Err> {
+    Ok(match s {
+      "uuid" => Self::Uuid,
+      "ulid" => Self::Ulid,
+      "ksuid" => Self::Ksuid,
+      "snowflake" => Self::Snowflake,
+      _ => return Err(IdGenError::UnknownStrategy(s.to_string())),
+    })
+  }
+}
+
+pub struct IdGenerator {
+  default_strategy: IdStrategy,
+  node_id: u16,
+  state: Mutex<GeneratorState>,
+}
+
+/// The last id generated by each ordered strategy, as (timestamp, rest). Ids generated within the
+/// same tick of the timestamp continue from the last one instead of drawing new random bits, so
+/// that they still sort in generation order.
+#[derive(Default)]
+struct GeneratorState {
+  ulid: (u64, u128),
+  ksuid: (u64, u128),
+  snowflake: (u64, u64),
+}
+
+impl IdGenerator {
+  pub fn new(default_strategy: IdStrategy, node_id: u16) -> Result<Self> {
+    if node_id >= 1 << SNOWFLAKE_NODE_ID_BITS {
+      return Err(IdGenError::NodeIdOutOfRange(node_id).into());
+    }
+    Ok(Self {
+      default_strategy,
+      node_id,
+      state: Mutex::new(GeneratorState::default()),
+    })
+  }
+
+  /// Generates a new id with `strategy`, or with the default strategy of the server if `None`.
+  /// Ids from all strategies except `Uuid` sort by creation time.
+  ///
+  /// Uniqueness is not guaranteed across server instances for the random strategies - callers
+  /// should still rely on the checks in the system schema.
+  pub fn generate(&self, strategy: Option<IdStrategy>) -> String {
+    self.generate_at(strategy.unwrap_or(self.default_strategy), current_millis())
+  }
+
+  /// Generates a new id as if the current time were `now_ms`.
+  pub fn generate_at(&self, strategy: IdStrategy, now_ms: u64) -> String {
+    match strategy {
+      IdStrategy::Uuid => Uuid::new_v4().to_string(),
+      IdStrategy::Ulid => self.generate_ulid(now_ms),
+      IdStrategy::Ksuid => self.generate_ksuid(now_ms),
+      IdStrategy::Snowflake => self.generate_snowflake(now_ms),
+    }
+  }
+
+  fn generate_ulid(&self, now_ms: u64) -> String {
+    let (timestamp, random) = next_ordered(
+      &mut self.state.lock().unwrap().ulid,
+      now_ms & ((1u64 << 48) - 1),
+      (1u128 << 80) - 1,
+    );
+    let value = ((timestamp as u128) << 80) | random;
+
+    // 26 characters * 5 bits = 130 bits. The first character only carries 3 bits.
+    (0..26)
+      .rev()
+      .map(|i| CROCKFORD_BASE32[((value >> (i * 5)) & 0x1f) as usize] as char)
+      .collect()
+  }
+
+  fn generate_ksuid(&self, now_ms: u64) -> String {
+    let (timestamp, payload) = next_ordered(
+      &mut self.state.lock().unwrap().ksuid,
+      (now_ms / 1000).saturating_sub(KSUID_EPOCH_SECS),
+      u128::MAX,
+    );
+    let mut raw = [0u8; 20];
+    raw[..4].copy_from_slice(&(timestamp as u32).to_be_bytes());
+    raw[4..].copy_from_slice(&payload.to_be_bytes());
+
+    // Big-endian base62 conversion by repeated division.
+    let mut digits = Vec::with_capacity(27);
+    let mut number = raw.to_vec();
+    while number.iter().any(|x| *x != 0) {
+      let mut remainder = 0u32;
+      for b in number.iter_mut() {
+        let acc = (remainder << 8) | *b as u32;
+        *b = (acc / 62) as u8;
+        remainder = acc % 62;
+      }
+      digits.push(BASE62[remainder as usize]);
+    }
+    while digits.len() < 27 {
+      digits.push(BASE62[0]);
+    }
+    digits.reverse();
+    String::from_utf8(digits).unwrap()
+  }
+
+  fn generate_snowflake(&self, now_ms: u64) -> String {
+    let mut state = self.state.lock().unwrap();
+    let (last_timestamp, sequence) = &mut state.snowflake;
+    let max_sequence = (1u64 << SNOWFLAKE_SEQUENCE_BITS) - 1;
+
+    // Never go backwards, even if the system clock does.
+    let mut timestamp = now_ms
+      .saturating_sub(SNOWFLAKE_EPOCH_MS)
+      .max(*last_timestamp);
+    if timestamp == *last_timestamp {
+      if *sequence == max_sequence {
+        // Sequence exhausted for this millisecond. Borrow from the next one.
+        timestamp += 1;
+        *sequence = 0;
+      } else {
+        *sequence += 1;
+      }
+    } else {
+      *sequence = 0;
+    }
+    *last_timestamp = timestamp;
+
+    let id = (timestamp << (SNOWFLAKE_NODE_ID_BITS + SNOWFLAKE_SEQUENCE_BITS))
+      | ((self.node_id as u64) << SNOWFLAKE_SEQUENCE_BITS)
+      | *sequence;
+    // Zero-padded so that the string form sorts the same way as the number.
+    format!("{:020}", id)
+  }
+}
+
+/// Picks the timestamp and random part of the next id of a strategy, given the `last` one. Within
+/// the same tick, the random part is incremented instead of drawn again; when it would exceed
+/// `max_random`, the id borrows the next tick, like the snowflake sequence does.
+fn next_ordered(last: &mut (u64, u128), now: u64, max_random: u128) -> (u64, u128) {
+  let (last_timestamp, last_random) = *last;
+
+  // Never go backwards, even if the system clock does.
+  let mut timestamp = now.max(last_timestamp);
+  let random = if timestamp == last_timestamp && last_random < max_random {
+    last_random + 1
+  } else {
+    if timestamp == last_timestamp {
+      timestamp += 1;
+    }
+    let mut buf = [0u8; 16];
+    rand::thread_rng().fill_bytes(&mut buf);
+    // Leave headroom so that the ids of a busy tick rarely need to borrow the next one.
+    (u128::from_be_bytes(buf) & max_random) >> 1
+  };
+  *last = (timestamp, random);
+  (timestamp, random)
+}
@@ -0,0 +1,124 @@
+use std::collections::HashSet;
+
+use crate::id_gen::{IdGenerator, IdStrategy};
+
+/// 2021-06-01T00:00:00Z, in milliseconds.
+const NOW_MS: u64 = 1622505600000;
+
+fn assert_alphabet(id: &str, alphabet: &[u8]) {
+  assert!(
+    id.bytes().all(|x| alphabet.contains(&x)),
+    "unexpected character in {}",
+    id
+  );
+}
+
+fn assert_strictly_increasing(ids: &[String]) {
+  for w in ids.windows(2) {
+    assert!(w[0] < w[1], "{} >= {}", w[0], w[1]);
+  }
+}
+
+#[test]
+fn encoding() {
+  let gen = IdGenerator::new(IdStrategy::Uuid, 1).unwrap();
+
+  let ulid = gen.generate_at(IdStrategy::Ulid, NOW_MS);
+  assert_eq!(ulid.len(), 26);
+  assert_alphabet(&ulid, b"0123456789ABCDEFGHJKMNPQRSTVWXYZ");
+  // The first 10 characters encode the timestamp.
+  assert_eq!(&ulid[..10], "01F72DXD00");
+
+  let ksuid = gen.generate_at(IdStrategy::Ksuid, NOW_MS);
+  assert_eq!(ksuid.len(), 27);
+  assert_alphabet(
+    &ksuid,
+    b"0123456789ABCDEFGHIJKLMNOPQRSTUVWXYZabcdefghijklmnopqrstuvwxyz",
+  );
+
+  let snowflake = gen.generate_at(IdStrategy::Snowflake, NOW_MS);
+  assert_eq!(snowflake.len(), 20);
+  assert_alphabet(&snowflake, b"0123456789");
+  let x: u64 = snowflake.parse().unwrap();
+  assert_eq!(x >> 22, NOW_MS - 1609459200000);
+  assert_eq!((x >> 12) & 0x3ff, 1);
+  assert_eq!(x & 0xfff, 0);
+
+  let uuid = gen.generate_at(IdStrategy::Uuid, NOW_MS);
+  assert_eq!(uuid.len(), 36);
+  assert_alphabet(&uuid, b"0123456789abcdef-");
+}
+
+#[test]
+fn monotonic_within_one_tick() {
+  for strategy in [IdStrategy::Ulid, IdStrategy::Ksuid, IdStrategy::Snowflake] {
+    let gen = IdGenerator::new(IdStrategy::Uuid, 0).unwrap();
+    let ids = (0..1000)
+      .map(|_| gen.generate_at(strategy, NOW_MS))
+      .collect::<Vec<_>>();
+    assert_strictly_increasing(&ids);
+
+    // A clock going backwards does not reorder ids either.
+    let earlier = gen.generate_at(strategy, NOW_MS - 5000);
+    assert!(ids.last().unwrap() < &earlier, "{:?}", strategy);
+  }
+}
+
+#[test]
+fn snowflake_sequence_overflow() {
+  let gen = IdGenerator::new(IdStrategy::Snowflake, 3).unwrap();
+  let ids = (0..4096 + 10)
+    .map(|_| gen.generate(None))
+    .collect::<Vec<_>>();
+  assert_strictly_increasing(&ids);
+
+  let gen = IdGenerator::new(IdStrategy::Snowflake, 3).unwrap();
+  let ids = (0..4096 + 10)
+    .map(|_| gen.generate_at(IdStrategy::Snowflake, NOW_MS))
+    .collect::<Vec<_>>();
+  assert_strictly_increasing(&ids);
+  assert_eq!(ids.iter().collect::<HashSet<_>>().len(), ids.len());
+
+  // The 4097th id of the millisecond borrows the next one and restarts the sequence.
+  let decode = |x: &String| {
+    let x: u64 = x.parse().unwrap();
+    (x >> 22, x & 0xfff)
+  };
+  let start = NOW_MS - 1609459200000;
+  assert_eq!(decode(&ids[4095]), (start, 4095));
+  assert_eq!(decode(&ids[4096]), (start + 1, 0));
+  assert_eq!(decode(&ids[4097]), (start + 1, 1));
+
+  // Ids of the borrowed millisecond continue from where the overflow left off.
+  let next = gen.generate_at(IdStrategy::Snowflake, NOW_MS + 1);
+  assert_eq!(decode(&next), (start + 1, 10));
+}
+
+#[test]
+fn node_id_range() {
+  assert!(IdGenerator::new(IdStrategy::Snowflake, 1023).is_ok());
+  assert!(IdGenerator::new(IdStrategy::Snowflake, 1024).is_err());
+}
+
+#[test]
+fn namespace_strategy() {
+  assert_eq!(IdStrategy::parse_namespace_strategy("").unwrap(), None);
+  for strategy in [
+    IdStrategy::Uuid,
+    IdStrategy::Ulid,
+    IdStrategy::Ksuid,
+    IdStrategy::Snowflake,
+  ] {
+    assert_eq!(
+      IdStrategy::parse_namespace_strategy(strategy.as_str()).unwrap(),
+      Some(strategy)
+    );
+  }
+  assert!(IdStrategy::parse_namespace_strategy("sequential").is_err());
+
+  // A namespace strategy overrides the default of the server.
+  let gen = IdGenerator::new(IdStrategy::Uuid, 0).unwrap();
+  assert_eq!(gen.generate(None).len(), 36);
+  assert_eq!(gen.generate(Some(IdStrategy::Ulid)).len(), 26);
+  assert_eq!(gen.generate(Some(IdStrategy::Snowflake)).len(), 20);
+}
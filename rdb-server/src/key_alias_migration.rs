@@ -0,0 +1,86 @@
+use anyhow::Result;
+use rdb_analyzer::{
+  data::treewalker::{bytecode::TwScript, exec::Executor, typeck::GlobalTyckContext, vm::TwVm},
+  storage_plan::{planner::assign_key_aliases, StoragePlan},
+};
+use tokio::time::sleep;
+
+use crate::{
+  schema_cache::load_compiled_schema,
+  state::get_state,
+  sysquery::{
+    lookup_deployment, ns_to_kv_prefix_with_appended_zero, set_deployment_blobs,
+    set_deployment_plan, DeploymentBlobs,
+  },
+};
+use thiserror::Error;
+
+#[derive(Error, Debug)]
+pub enum KeyAliasMigrationError {
+  #[error("expecting `NAMESPACE/DEPLOYMENT`, got `{0}`")]
+  BadTarget(String),
+
+  #[error("deployment not found")]
+  DeploymentNotFound,
+}
+
+/// Rewrites the data of a deployment into the layout with key aliases, and switches the
+/// deployment to the aliased storage plan.
+///
+/// `target` is `NAMESPACE/DEPLOYMENT`. This must not run concurrently with servers using the
+/// namespace. An interrupted migration is resumed by running it again. Other deployments of the
+/// namespace keep their plans and no longer see the migrated data.
+pub async fn migrate_to_key_aliases(target: &str, batch_size: usize) -> Result<()> {
+  let (namespace_id, deployment_id) = target
+    .split_once('/')
+    .ok_or_else(|| KeyAliasMigrationError::BadTarget(target.to_string()))?;
+  let st = get_state();
+
+  let deployment = lookup_deployment(namespace_id, deployment_id).await?;
+  let plan = StoragePlan::deserialize_compressed(&deployment.plan)?;
+  if !plan.key_aliases.is_empty() {
+    log::info!(
+      "Deployment {}/{} already uses key aliases.",
+      namespace_id,
+      deployment_id
+    );
+    return Ok(());
+  }
+  let schema = load_compiled_schema(namespace_id, &deployment).await?;
+
+  // Alias assignment is deterministic, so a resumed migration continues with the same plan.
+  let mut aliased_plan = plan.clone();
+  assign_key_aliases(&mut aliased_plan)?;
+
+  let script = TwScript::default();
+  let vm = TwVm::new(&schema, &aliased_plan, &script)?;
+  let type_info = GlobalTyckContext::new(&vm)?.typeck()?;
+  let kv = (st.data_store_generator)(&ns_to_kv_prefix_with_appended_zero(namespace_id).await?);
+  let mut executor = Executor::new(&vm, &*kv, &type_info);
+  executor.set_sleep_fn(|x| Box::pin(sleep(x)));
+  executor.set_retry_policy(st.retry_policy.clone());
+  let num_moved_members = executor.migrate_layout(&plan, batch_size).await?;
+  log::info!(
+    "Moved data of deployment {}/{} to the aliased layout ({} set members).",
+    namespace_id,
+    deployment_id,
+    num_moved_members
+  );
+
+  let serialized_plan = aliased_plan.serialize_compressed()?;
+  if !set_deployment_plan(namespace_id, deployment_id, &serialized_plan).await? {
+    return Err(KeyAliasMigrationError::DeploymentNotFound.into());
+  }
+  set_deployment_blobs(
+    namespace_id,
+    deployment_id,
+    &DeploymentBlobs::encode(&schema, &serialized_plan)?,
+  )
+  .await?;
+  log::info!(
+    "Deployment {}/{} now uses key aliases.",
+    namespace_id,
+    deployment_id
+  );
+  Ok(())
+}
@@ -0,0 +1,213 @@
+use std::sync::{
+  atomic::{AtomicU64, Ordering},
+  Arc,
+};
+
+use anyhow::Result;
+use async_trait::async_trait;
+use rdb_analyzer::data::kv::{
+  KeyValueStore, KvError, KvIterator, KvKeyIterator, KvTransaction, ScanOptions,
+};
+use serde::Serialize;
+
+/// Response header carrying the KV operation counts of a query, as JSON.
+pub const KV_OPS_HEADER: &str = "X-Rdb-Kv-Ops";
+
+/// Counts operations made through a `ProfiledKvStore`.
+#[derive(Default)]
+pub struct KvOpCounters {
+  transactions: AtomicU64,
+  get: AtomicU64,
+  put: AtomicU64,
+  delete: AtomicU64,
+  delete_range: AtomicU64,
+  atomic_add: AtomicU64,
+  scan_keys: AtomicU64,
+  scan_next: AtomicU64,
+  scan: AtomicU64,
+  scan_entry: AtomicU64,
+  add_read_conflict_key: AtomicU64,
+  commit: AtomicU64,
+  conflict: AtomicU64,
+}
+
+#[derive(Serialize, Debug)]
+pub struct KvOpCounts {
+  pub transactions: u64,
+  pub get: u64,
+  pub put: u64,
+  pub delete: u64,
+  pub delete_range: u64,
+  pub atomic_add: u64,
+  pub scan_keys: u64,
+  pub scan_next: u64,
+  pub scan: u64,
+  pub scan_entry: u64,
+  pub add_read_conflict_key: u64,
+  pub commit: u64,
+  pub conflict: u64,
+}
+
+impl KvOpCounters {
+  pub fn snapshot(&self) -> KvOpCounts {
+    let load = |x: &AtomicU64| x.load(Ordering::Relaxed);
+    KvOpCounts {
+      transactions: load(&self.transactions),
+      get: load(&self.get),
+      put: load(&self.put),
+      delete: load(&self.delete),
+      delete_range: load(&self.delete_range),
+      atomic_add: load(&self.atomic_add),
+      scan_keys: load(&self.scan_keys),
+      scan_next: load(&self.scan_next),
+      scan: load(&self.scan),
+      scan_entry: load(&self.scan_entry),
+      add_read_conflict_key: load(&self.add_read_conflict_key),
+      commit: load(&self.commit),
+      conflict: load(&self.conflict),
+    }
+  }
+}
+
+fn bump(x: &AtomicU64) {
+  x.fetch_add(1, Ordering::Relaxed);
+}
+
+/// Wraps a store and counts the operations made through it.
+pub struct ProfiledKvStore {
+  inner: Box<dyn KeyValueStore>,
+  counters: Arc<KvOpCounters>,
+}
+
+impl ProfiledKvStore {
+  pub fn new(inner: Box<dyn KeyValueStore>) -> Self {
+    Self {
+      inner,
+      counters: Arc::new(KvOpCounters::default()),
+    }
+  }
+
+  pub fn counters(&self) -> &Arc<KvOpCounters> {
+    &self.counters
+  }
+}
+
+struct ProfiledKvTransaction {
+  inner: Box<dyn KvTransaction>,
+  counters: Arc<KvOpCounters>,
+}
+
+struct ProfiledKvKeyIterator {
+  inner: Box<dyn KvKeyIterator>,
+  counters: Arc<KvOpCounters>,
+}
+
+struct ProfiledKvIterator {
+  inner: Box<dyn KvIterator>,
+  counters: Arc<KvOpCounters>,
+}
+
+#[async_trait]
+impl KeyValueStore for ProfiledKvStore {
+  async fn begin_transaction(&self) -> Result<Box<dyn KvTransaction>> {
+    bump(&self.counters.transactions);
+    Ok(Box::new(ProfiledKvTransaction {
+      inner: self.inner.begin_transaction().await?,
+      counters: self.counters.clone(),
+    }))
+  }
+
+  async fn begin_read_only_transaction(&self) -> Result<Box<dyn KvTransaction>> {
+    bump(&self.counters.transactions);
+    Ok(Box::new(ProfiledKvTransaction {
+      inner: self.inner.begin_read_only_transaction().await?,
+      counters: self.counters.clone(),
+    }))
+  }
+}
+
+#[async_trait]
+impl KvTransaction for ProfiledKvTransaction {
+  async fn get(&self, key: &[u8]) -> Result<Option<Vec<u8>>> {
+    bump(&self.counters.get);
+    self.inner.get(key).await
+  }
+
+  async fn put(&self, key: &[u8], value: &[u8]) -> Result<()> {
+    bump(&self.counters.put);
+    self.inner.put(key, value).await
+  }
+
+  async fn put_many(&self, entries: &[(Vec<u8>, Vec<u8>)]) -> Result<()> {
+    for _ in entries {
+      bump(&self.counters.put);
+    }
+    self.inner.put_many(entries).await
+  }
+
+  async fn delete(&self, key: &[u8]) -> Result<()> {
+    bump(&self.counters.delete);
+    self.inner.delete(key).await
+  }
+
+  async fn delete_range(&self, start: &[u8], end: &[u8]) -> Result<()> {
+    bump(&self.counters.delete_range);
+    self.inner.delete_range(start, end).await
+  }
+
+  async fn atomic_add(&self, key: &[u8], delta: i64) -> Result<()> {
+    bump(&self.counters.atomic_add);
+    self.inner.atomic_add(key, delta).await
+  }
+
+  async fn scan_keys(&self, start: &[u8], end: &[u8]) -> Result<Box<dyn KvKeyIterator>> {
+    bump(&self.counters.scan_keys);
+    Ok(Box::new(ProfiledKvKeyIterator {
+      inner: self.inner.scan_keys(start, end).await?,
+      counters: self.counters.clone(),
+    }))
+  }
+
+  async fn add_read_conflict_key(&self, key: &[u8]) -> Result<()> {
+    bump(&self.counters.add_read_conflict_key);
+    self.inner.add_read_conflict_key(key).await
+  }
+
+  async fn scan(
+    &self,
+    start: &[u8],
+    end: &[u8],
+    options: ScanOptions,
+  ) -> Result<Box<dyn KvIterator>> {
+    bump(&self.counters.scan);
+    Ok(Box::new(ProfiledKvIterator {
+      inner: self.inner.scan(start, end, options).await?,
+      counters: self.counters.clone(),
+    }))
+  }
+
+  async fn commit(self: Box<Self>) -> Result<(), KvError> {
+    bump(&self.counters.commit);
+    let res = self.inner.commit().await;
+    if let Err(KvError::Conflict) = &res {
+      bump(&self.counters.conflict);
+    }
+    res
+  }
+}
+
+#[async_trait]
+impl KvKeyIterator for ProfiledKvKeyIterator {
+  async fn next(&mut self) -> Result<Option<Vec<u8>>> {
+    bump(&self.counters.scan_next);
+    self.inner.next().await
+  }
+}
+
+#[async_trait]
+impl KvIterator for ProfiledKvIterator {
+  async fn next(&mut self) -> Result<Option<(Vec<u8>, Vec<u8>)>> {
+    bump(&self.counters.scan_entry);
+    self.inner.next().await
+  }
+}
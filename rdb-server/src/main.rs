@@ -1,11 +1,19 @@
-use std::sync::Arc;
+use std::{sync::Arc, time::Duration};
 
 use anyhow::Result;
 use foundationdb::{tuple::Subspace, Database};
 use rdb_analyzer::{
-  data::kv::KeyValueStore,
+  data::{
+    kv::KeyValueStore,
+    treewalker::{
+      exec::{ExecConfig, RetryPolicy, Scheduler},
+      inline::InlineConfig,
+      limits::ExecLimits,
+    },
+  },
   kv_backend::{
     foundationdb::FdbKvStore,
+    memory::MemoryKvStore,
     sqlite::{GlobalSqliteStore, SqliteKvStore},
   },
 };
@@ -14,45 +22,137 @@ use structopt::StructOpt;
 use tokio::runtime::Runtime;
 
 use crate::{
+  auth::{grpc_interceptor, TokenRegistry},
+  control_events::ControlEventLog,
   httpapi::run_http_server,
+  id_gen::IdGenerator,
+  key_alias_migration::migrate_to_key_aliases,
+  metering::UsageMeter,
+  metrics::init_metrics,
   opt::Opt,
+  pgwire::run_pg_server,
   query_cache::{QueryCache, QueryCacheParams},
+  rate_limit::RateLimiter,
+  result_cache::ResultCache,
+  routing::DeploymentRouter,
+  schema_cache::SchemaCache,
   server::ControlServer,
-  state::{set_state, DataStoreGenerator, ServerState},
-  system::SystemSchema,
+  state::{get_state, set_state, DataStoreGenerator, ServerState},
+  subscriptions::SubscriptionHub,
+  sysquery::add_namespace,
+  system::{system_migration_hash, SystemSchema},
+  ttl_reaper::spawn_ttl_reaper,
+  txn_manager::{TxnManager, TxnManagerParams},
 };
+mod api_token;
+mod auth;
+mod changelog;
+mod control_events;
 mod exec;
 mod exec_core;
+mod explorer;
 mod httpapi;
+mod id_gen;
+mod key_alias_migration;
+mod kv_profile;
+mod metering;
+mod metrics;
+mod mount;
 mod opt;
+mod pgwire;
 mod query_cache;
+mod rate_limit;
+mod result_cache;
+mod routing;
+mod schema_cache;
 mod server;
 mod state;
+mod subscriptions;
 mod sysquery;
 mod system;
+mod system_migration;
+mod ttl_reaper;
+mod txn_manager;
 mod util;
 
+#[cfg(test)]
+mod api_token_test;
+#[cfg(test)]
+mod auth_test;
+#[cfg(test)]
+mod changelog_test;
+#[cfg(test)]
+mod httpapi_test;
+#[cfg(test)]
+mod id_gen_test;
+
+/// Listen addresses of `--dev` servers.
+const DEV_GRPC_LISTEN: &str = "127.0.0.1:50051";
+const DEV_HTTP_LISTEN: &str = "127.0.0.1:8080";
+
+/// Namespace created by `--dev` servers.
+const DEV_NAMESPACE: &str = "default";
+
 fn main() {
+  let opt = Opt::from_args();
+  if opt.dev && std::env::var("RUST_LOG").is_err() {
+    std::env::set_var("RUST_LOG", "info,rdb_server=debug,rdb_analyzer=debug");
+  }
   pretty_env_logger::init_timed();
   let network = unsafe { foundationdb::boot() };
 
   Runtime::new()
     .unwrap()
-    .block_on(async move { run().await })
+    .block_on(async move { run(opt).await })
     .unwrap();
 
   // Required for safety
   drop(network);
 }
 
-async fn run() -> Result<()> {
-  let opt = Opt::from_args();
+#[cfg(feature = "rocksdb-backend")]
+fn open_rocksdb_stores(
+  path: &str,
+) -> Result<(
+  Box<dyn KeyValueStore>,
+  Box<dyn KeyValueStore>,
+  DataStoreGenerator,
+)> {
+  use rdb_analyzer::kv_backend::rocksdb::{open_db, RocksdbKvStore};
 
+  let db = open_db(path)?;
+  let system_store = Box::new(RocksdbKvStore::new(db.clone(), b"s"));
+  let system_metadata_store = Box::new(RocksdbKvStore::new(db.clone(), b"m"));
+  let data_store_generator: DataStoreGenerator = Box::new(move |namespace| {
+    Box::new(RocksdbKvStore::new(
+      db.clone(),
+      &b"d"
+        .iter()
+        .copied()
+        .chain(namespace.iter().copied())
+        .collect::<Vec<u8>>(),
+    ))
+  });
+  Ok((system_store, system_metadata_store, data_store_generator))
+}
+
+#[cfg(not(feature = "rocksdb-backend"))]
+fn open_rocksdb_stores(
+  _path: &str,
+) -> Result<(
+  Box<dyn KeyValueStore>,
+  Box<dyn KeyValueStore>,
+  DataStoreGenerator,
+)> {
+  panic!("rdb-server is built without the `rocksdb-backend` feature");
+}
+
+async fn run(opt: Opt) -> Result<()> {
   let data_store_generator: DataStoreGenerator;
   let system_store: Box<dyn KeyValueStore>;
   let system_metadata_store: Box<dyn KeyValueStore>;
   if let Some(x) = &opt.fdb_cluster {
-    if opt.sqlite_db.is_some() {
+    if opt.sqlite_db.is_some() || opt.rocksdb_db.is_some() || opt.memory {
       panic!("cannot select multiple kv backends");
     }
     let db = Arc::new(Database::new(Some(x))?);
@@ -80,7 +180,11 @@ async fn run() -> Result<()> {
       ))
     });
   } else if let Some(x) = &opt.sqlite_db {
-    if opt.fdb_cluster.is_some() || opt.fdb_keyspace.is_some() {
+    if opt.fdb_cluster.is_some()
+      || opt.fdb_keyspace.is_some()
+      || opt.rocksdb_db.is_some()
+      || opt.memory
+    {
       panic!("cannot select multiple kv backends");
     }
     let backend = GlobalSqliteStore::open_leaky(x)?;
@@ -89,35 +193,160 @@ async fn run() -> Result<()> {
     data_store_generator = Box::new(move |namespace| {
       Box::new(SqliteKvStore::new(backend.clone(), "user_data", namespace))
     });
+  } else if let Some(x) = &opt.rocksdb_db {
+    if opt.fdb_cluster.is_some() || opt.fdb_keyspace.is_some() || opt.memory {
+      panic!("cannot select multiple kv backends");
+    }
+    let (a, b, c) = open_rocksdb_stores(x)?;
+    system_store = a;
+    system_metadata_store = b;
+    data_store_generator = c;
+  } else if opt.memory || opt.dev {
+    if opt.fdb_keyspace.is_some() {
+      panic!("cannot select multiple kv backends");
+    }
+    let store = MemoryKvStore::new(opt.memory_ttl_secs.map(Duration::from_secs));
+    system_store = Box::new(store.with_prefix(b"s"));
+    system_metadata_store = Box::new(store.with_prefix(b"m"));
+    data_store_generator = Box::new(move |namespace| {
+      Box::new(
+        store.with_prefix(
+          &b"d"
+            .iter()
+            .copied()
+            .chain(namespace.iter().copied())
+            .collect::<Vec<u8>>(),
+        ),
+      )
+    });
   } else {
     panic!("no kv backend selected");
   }
 
+  // Development servers are not expected to keep data across versions.
+  let migration_hash = if opt.dev {
+    Some(system_migration_hash())
+  } else {
+    opt.migration_hash.clone()
+  };
+  let inline_config = InlineConfig {
+    max_nodes: opt.inline_node_budget,
+    ..Default::default()
+  };
   let system_schema = SystemSchema::new(
-    opt.migration_hash.clone(),
+    migration_hash,
     &*system_store,
     &*system_metadata_store,
+    &inline_config,
   )
-  .await;
+  .await?;
   let query_cache = QueryCache::new(QueryCacheParams {
     process_memory_threshold_kb: opt.process_memory_threshold_kb,
   });
+  let schema_cache = SchemaCache::new(opt.schema_cache_size);
+  let result_cache = ResultCache::new(opt.result_cache_size);
+  let id_generator = IdGenerator::new(opt.id_strategy, opt.snowflake_node_id)?;
+  let txn_manager = TxnManager::new(TxnManagerParams {
+    timeout: Duration::from_millis(opt.explicit_txn_timeout_ms),
+    max_open_txns: opt.max_explicit_txns,
+  });
+  let usage_meter = UsageMeter::new(Duration::from_millis(opt.usage_flush_interval_ms));
+  let token_registry = TokenRegistry::new(
+    opt.root_token.as_deref(),
+    Duration::from_millis(opt.token_refresh_interval_ms),
+  );
 
   set_state(ServerState {
     data_store_generator,
     system_store,
     system_schema,
+    control_events: ControlEventLog::new(),
     query_cache,
+    schema_cache,
+    result_cache,
+    deployment_router: DeploymentRouter::new(),
+    id_generator,
+    kv_profiling: opt.enable_kv_profiling,
+    changelog_enabled: opt.enable_changelog,
+    retry_policy: RetryPolicy {
+      max_attempts: opt.txn_max_attempts.max(1),
+      base_delay: Duration::from_millis(opt.txn_retry_base_delay_ms),
+      max_delay: Duration::from_millis(opt.txn_retry_max_delay_ms),
+    },
+    exec_limits: ExecLimits {
+      timeout: Some(Duration::from_millis(opt.query_timeout_ms)),
+      max_kv_reads: opt.max_kv_reads,
+      max_kv_writes: opt.max_kv_writes,
+      max_subgraph_calls: opt.max_subgraph_calls,
+      max_value_size: opt.max_value_size,
+      max_recursion_depth: opt.max_recursion_depth,
+    },
+    exec_config: ExecConfig {
+      concurrency: opt.exec_concurrency.max(1),
+      scheduler: if opt.deterministic_scheduler {
+        Scheduler::Topological
+      } else {
+        Scheduler::Dataflow
+      },
+      strict_select: !opt.lenient_select,
+    },
+    inline_config,
+    txn_manager,
+    usage_meter,
+    subscription_hub: SubscriptionHub::new(opt.subscription_buffer_size.max(1)),
+    token_registry,
+    namespace_rate_limiter: opt
+      .namespace_rps
+      .map(|rps| RateLimiter::new(rps, opt.namespace_burst.unwrap_or(rps))),
+    token_rate_limiter: opt
+      .token_rps
+      .map(|rps| RateLimiter::new(rps, opt.token_burst.unwrap_or(rps))),
+    reject_lossy_deployments: opt.reject_lossy_deployments,
+    adhoc_queries_enabled: opt.enable_adhoc_queries || opt.dev,
   });
 
+  if let Some(target) = &opt.migrate_key_aliases {
+    return migrate_to_key_aliases(target, opt.key_alias_migration_batch_size.max(1)).await;
+  }
+
+  let st = get_state();
+  if st.token_registry.enabled() {
+    st.token_registry.refresh().await?;
+    log::info!("Authentication enabled.");
+  }
+
+  if opt.dev && add_namespace(DEV_NAMESPACE).await? {
+    log::info!("Created namespace `{}`.", DEV_NAMESPACE);
+  }
+
+  init_metrics();
+  if opt.ttl_reap_interval_ms != 0 {
+    spawn_ttl_reaper(
+      Duration::from_millis(opt.ttl_reap_interval_ms),
+      opt.ttl_reap_batch_size.max(1),
+    );
+  }
   log::info!("RefineDB started.");
 
-  let http_listen = opt.http_listen.clone();
+  let http_listen = opt
+    .http_listen
+    .clone()
+    .unwrap_or_else(|| DEV_HTTP_LISTEN.to_string());
+  let grpc_listen = opt
+    .grpc_listen
+    .clone()
+    .unwrap_or_else(|| DEV_GRPC_LISTEN.to_string());
   tokio::spawn(async move { run_http_server(http_listen).await });
+  if let Some(pg_listen) = opt.pg_listen.clone() {
+    tokio::spawn(async move { run_pg_server(pg_listen).await });
+  }
 
   Server::builder()
-    .add_service(RdbControlServer::new(ControlServer))
-    .serve(opt.grpc_listen.parse()?)
+    .add_service(RdbControlServer::with_interceptor(
+      ControlServer,
+      grpc_interceptor,
+    ))
+    .serve(grpc_listen.parse()?)
     .await?;
 
   Ok(())
@@ -0,0 +1,418 @@
+use std::{
+  collections::HashMap,
+  sync::{
+    atomic::{AtomicU64, Ordering},
+    Arc, Weak,
+  },
+  time::Duration,
+};
+
+use anyhow::Result;
+use async_trait::async_trait;
+use rdb_analyzer::{
+  data::{
+    kv::{KeyValueStore, KvError, KvIterator, KvKeyIterator, KvTransaction, ScanOptions},
+    mount::MountedKvStore,
+  },
+  schema::compile::CompiledSchema,
+};
+use thiserror::Error;
+use tokio::{sync::Mutex, time::sleep};
+
+use crate::{
+  changelog::open_with_changelog,
+  metrics::{NAMESPACE_REQUESTS, TXN_CONFLICTS, TXN_KV_OPS},
+  state::get_state,
+  sysquery::{
+    add_namespace_usage, get_namespace_usage, ns_to_kv_prefix_with_appended_zero, NamespaceUsage,
+  },
+};
+
+#[derive(Error, Debug)]
+pub enum MeteringError {
+  #[error("write quota of namespace `{0}` exceeded ({1} bytes)")]
+  QuotaExceeded(String, u64),
+}
+
+/// Counts the bytes read from and written into each namespace, and enforces write quotas.
+///
+/// Counts are kept in memory and periodically added to the usage counters stored with the
+/// namespace. A quota is checked against the stored counters as of the last flush plus what this
+/// server has counted since, so each other server may overrun it by what it writes between two
+/// flushes. Counts not flushed yet when the server exits are lost.
+pub struct UsageMeter {
+  namespaces: Mutex<HashMap<String, Arc<NamespaceMeter>>>,
+  flush_interval: Duration,
+}
+
+struct NamespaceMeter {
+  namespace_id: String,
+
+  /// Not yet added to the stored counters.
+  pending_read: AtomicU64,
+  pending_written: AtomicU64,
+
+  /// As of the last flush.
+  stored_written: AtomicU64,
+  write_quota: AtomicU64,
+}
+
+/// Opens the data store of a namespace, with its reads and writes metered.
+pub async fn open_namespace_store(namespace_id: &str) -> Result<Box<dyn KeyValueStore>> {
+  let st = get_state();
+  let kv_prefix = ns_to_kv_prefix_with_appended_zero(namespace_id).await?;
+  NAMESPACE_REQUESTS.with_label_values(&[namespace_id]).inc();
+  let meter = st.usage_meter.get(namespace_id).await?;
+  let inner = if st.changelog_enabled {
+    open_with_changelog(&kv_prefix)
+  } else {
+    (st.data_store_generator)(&kv_prefix)
+  };
+  Ok(Box::new(MeteredKvStore { inner, meter }))
+}
+
+/// Opens the data store of a namespace for running a query script with the schema `schema`, with
+/// the namespaces mounted into the schema readable through it. Reads of a mounted namespace are
+/// metered against that namespace.
+pub async fn open_query_store(
+  namespace_id: &str,
+  schema: &CompiledSchema,
+) -> Result<Box<dyn KeyValueStore>> {
+  let kv = open_namespace_store(namespace_id).await?;
+  if schema.mounts.is_empty() {
+    return Ok(kv);
+  }
+  let mut mounts = Vec::with_capacity(schema.mounts.len());
+  for mounted_namespace_id in schema.mounts.keys() {
+    mounts.push(open_namespace_store(mounted_namespace_id).await?);
+  }
+  Ok(Box::new(MountedKvStore::new(kv, mounts)))
+}
+
+impl UsageMeter {
+  pub fn new(flush_interval: Duration) -> Arc<Self> {
+    let me = Arc::new(Self {
+      namespaces: Mutex::new(HashMap::new()),
+      flush_interval,
+    });
+    let me_weak = Arc::downgrade(&me);
+    tokio::spawn(async move {
+      Self::flush_loop(me_weak).await;
+    });
+    me
+  }
+
+  async fn get(&self, namespace_id: &str) -> Result<Arc<NamespaceMeter>> {
+    if let Some(x) = self.namespaces.lock().await.get(namespace_id) {
+      return Ok(x.clone());
+    }
+
+    // Not holding the lock while loading. If we race with another request, the first meter
+    // inserted wins.
+    let usage = get_namespace_usage(namespace_id).await?;
+    let meter = Arc::new(NamespaceMeter {
+      namespace_id: namespace_id.to_string(),
+      pending_read: AtomicU64::new(0),
+      pending_written: AtomicU64::new(0),
+      stored_written: AtomicU64::new(0),
+      write_quota: AtomicU64::new(0),
+    });
+    meter.set_stored(&usage);
+    Ok(
+      self
+        .namespaces
+        .lock()
+        .await
+        .entry(namespace_id.to_string())
+        .or_insert(meter)
+        .clone(),
+    )
+  }
+
+  /// Stored usage of a namespace, plus what this server has counted since the last flush.
+  pub async fn usage(&self, namespace_id: &str) -> Result<NamespaceUsage> {
+    let mut usage = get_namespace_usage(namespace_id).await?;
+    if let Some(x) = self.namespaces.lock().await.get(namespace_id) {
+      usage.bytes_read += x.pending_read.load(Ordering::Relaxed);
+      usage.bytes_written += x.pending_written.load(Ordering::Relaxed);
+    }
+    Ok(usage)
+  }
+
+  /// Applies a quota change without waiting for the next flush.
+  pub async fn set_quota(&self, namespace_id: &str, write_quota_bytes: u64) {
+    if let Some(x) = self.namespaces.lock().await.get(namespace_id) {
+      x.write_quota.store(write_quota_bytes, Ordering::Relaxed);
+    }
+  }
+
+  /// Drops the counts of a deleted namespace.
+  pub async fn forget(&self, namespace_id: &str) {
+    self.namespaces.lock().await.remove(namespace_id);
+  }
+
+  async fn flush_loop(me: Weak<Self>) {
+    loop {
+      let flush_interval = match me.upgrade() {
+        Some(x) => x.flush_interval,
+        None => break,
+      };
+      sleep(flush_interval).await;
+      let me = match me.upgrade() {
+        Some(x) => x,
+        None => {
+          log::warn!("usage meter: exiting");
+          break;
+        }
+      };
+      me.flush().await;
+    }
+  }
+
+  async fn flush(&self) {
+    let meters = self
+      .namespaces
+      .lock()
+      .await
+      .values()
+      .cloned()
+      .collect::<Vec<_>>();
+    for meter in meters {
+      let read = meter.pending_read.swap(0, Ordering::Relaxed);
+      let written = meter.pending_written.swap(0, Ordering::Relaxed);
+
+      // Still refresh the quota, which may have been changed through another server.
+      let res = if read == 0 && written == 0 {
+        get_namespace_usage(&meter.namespace_id).await.map(Some)
+      } else {
+        add_namespace_usage(&meter.namespace_id, read, written).await
+      };
+      match res {
+        Ok(Some(usage)) => meter.set_stored(&usage),
+        Ok(None) => {
+          self.forget(&meter.namespace_id).await;
+        }
+        Err(e) => {
+          log::error!(
+            "usage meter: cannot flush usage of namespace `{}`: {:?}",
+            meter.namespace_id,
+            e
+          );
+          meter.pending_read.fetch_add(read, Ordering::Relaxed);
+          meter.pending_written.fetch_add(written, Ordering::Relaxed);
+        }
+      }
+    }
+  }
+}
+
+impl NamespaceMeter {
+  fn set_stored(&self, usage: &NamespaceUsage) {
+    self
+      .stored_written
+      .store(usage.bytes_written, Ordering::Relaxed);
+    self
+      .write_quota
+      .store(usage.write_quota_bytes, Ordering::Relaxed);
+  }
+
+  /// Fails if writing `txn_written` more bytes would exceed the quota.
+  fn check_quota(&self, txn_written: u64) -> Result<()> {
+    let quota = self.write_quota.load(Ordering::Relaxed);
+    if quota == 0 {
+      return Ok(());
+    }
+    let total = self.stored_written.load(Ordering::Relaxed)
+      + self.pending_written.load(Ordering::Relaxed)
+      + txn_written;
+    if total > quota {
+      return Err(MeteringError::QuotaExceeded(self.namespace_id.clone(), quota).into());
+    }
+    Ok(())
+  }
+}
+
+struct MeteredKvStore {
+  inner: Box<dyn KeyValueStore>,
+  meter: Arc<NamespaceMeter>,
+}
+
+struct MeteredKvTransaction {
+  inner: Box<dyn KvTransaction>,
+  meter: Arc<NamespaceMeter>,
+
+  /// Counted as written on commit.
+  written: AtomicU64,
+
+  ops: KvOpsRecorder,
+}
+
+/// Records the number of KV operations made by a transaction when it ends.
+#[derive(Default)]
+struct KvOpsRecorder(AtomicU64);
+
+impl KvOpsRecorder {
+  fn inc(&self) {
+    self.0.fetch_add(1, Ordering::Relaxed);
+  }
+}
+
+impl Drop for KvOpsRecorder {
+  fn drop(&mut self) {
+    TXN_KV_OPS.observe(self.0.load(Ordering::Relaxed) as f64);
+  }
+}
+
+struct MeteredKvKeyIterator {
+  inner: Box<dyn KvKeyIterator>,
+  meter: Arc<NamespaceMeter>,
+}
+
+struct MeteredKvIterator {
+  inner: Box<dyn KvIterator>,
+  meter: Arc<NamespaceMeter>,
+}
+
+#[async_trait]
+impl KeyValueStore for MeteredKvStore {
+  async fn begin_transaction(&self) -> Result<Box<dyn KvTransaction>> {
+    Ok(Box::new(MeteredKvTransaction {
+      inner: self.inner.begin_transaction().await?,
+      meter: self.meter.clone(),
+      written: AtomicU64::new(0),
+      ops: KvOpsRecorder::default(),
+    }))
+  }
+
+  async fn begin_read_only_transaction(&self) -> Result<Box<dyn KvTransaction>> {
+    Ok(Box::new(MeteredKvTransaction {
+      inner: self.inner.begin_read_only_transaction().await?,
+      meter: self.meter.clone(),
+      written: AtomicU64::new(0),
+      ops: KvOpsRecorder::default(),
+    }))
+  }
+}
+
+#[async_trait]
+impl KvTransaction for MeteredKvTransaction {
+  async fn get(&self, key: &[u8]) -> Result<Option<Vec<u8>>> {
+    self.ops.inc();
+    let value = self.inner.get(key).await?;
+    if let Some(x) = &value {
+      self
+        .meter
+        .pending_read
+        .fetch_add((key.len() + x.len()) as u64, Ordering::Relaxed);
+    }
+    Ok(value)
+  }
+
+  async fn put(&self, key: &[u8], value: &[u8]) -> Result<()> {
+    self.ops.inc();
+    let n = (key.len() + value.len()) as u64;
+    let written = self.written.fetch_add(n, Ordering::Relaxed) + n;
+    self.meter.check_quota(written)?;
+    self.inner.put(key, value).await
+  }
+
+  async fn put_many(&self, entries: &[(Vec<u8>, Vec<u8>)]) -> Result<()> {
+    let n = entries
+      .iter()
+      .map(|(key, value)| (key.len() + value.len()) as u64)
+      .sum::<u64>();
+    for _ in entries {
+      self.ops.inc();
+    }
+    let written = self.written.fetch_add(n, Ordering::Relaxed) + n;
+    self.meter.check_quota(written)?;
+    self.inner.put_many(entries).await
+  }
+
+  // Deletes are not counted, and are allowed over quota.
+  async fn delete(&self, key: &[u8]) -> Result<()> {
+    self.ops.inc();
+    self.inner.delete(key).await
+  }
+
+  async fn delete_range(&self, start: &[u8], end: &[u8]) -> Result<()> {
+    self.ops.inc();
+    self.inner.delete_range(start, end).await
+  }
+
+  async fn atomic_add(&self, key: &[u8], delta: i64) -> Result<()> {
+    self.ops.inc();
+    let n = (key.len() + 8) as u64;
+    let written = self.written.fetch_add(n, Ordering::Relaxed) + n;
+    self.meter.check_quota(written)?;
+    self.inner.atomic_add(key, delta).await
+  }
+
+  async fn scan_keys(&self, start: &[u8], end: &[u8]) -> Result<Box<dyn KvKeyIterator>> {
+    self.ops.inc();
+    Ok(Box::new(MeteredKvKeyIterator {
+      inner: self.inner.scan_keys(start, end).await?,
+      meter: self.meter.clone(),
+    }))
+  }
+
+  async fn add_read_conflict_key(&self, key: &[u8]) -> Result<()> {
+    self.inner.add_read_conflict_key(key).await
+  }
+
+  async fn scan(
+    &self,
+    start: &[u8],
+    end: &[u8],
+    options: ScanOptions,
+  ) -> Result<Box<dyn KvIterator>> {
+    self.ops.inc();
+    Ok(Box::new(MeteredKvIterator {
+      inner: self.inner.scan(start, end, options).await?,
+      meter: self.meter.clone(),
+    }))
+  }
+
+  async fn commit(self: Box<Self>) -> Result<(), KvError> {
+    let written = self.written.load(Ordering::Relaxed);
+    self.ops.inc();
+    let res = self.inner.commit().await;
+    if let Err(KvError::Conflict) = &res {
+      TXN_CONFLICTS.inc();
+    }
+    res?;
+    self
+      .meter
+      .pending_written
+      .fetch_add(written, Ordering::Relaxed);
+    Ok(())
+  }
+}
+
+#[async_trait]
+impl KvKeyIterator for MeteredKvKeyIterator {
+  async fn next(&mut self) -> Result<Option<Vec<u8>>> {
+    let key = self.inner.next().await?;
+    if let Some(x) = &key {
+      self
+        .meter
+        .pending_read
+        .fetch_add(x.len() as u64, Ordering::Relaxed);
+    }
+    Ok(key)
+  }
+}
+
+#[async_trait]
+impl KvIterator for MeteredKvIterator {
+  async fn next(&mut self) -> Result<Option<(Vec<u8>, Vec<u8>)>> {
+    let entry = self.inner.next().await?;
+    if let Some((key, value)) = &entry {
+      self
+        .meter
+        .pending_read
+        .fetch_add((key.len() + value.len()) as u64, Ordering::Relaxed);
+    }
+    Ok(entry)
+  }
+}
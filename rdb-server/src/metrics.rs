@@ -0,0 +1,98 @@
+use once_cell::sync::Lazy;
+use prometheus::{
+  register_histogram, register_histogram_vec, register_int_counter, register_int_counter_vec,
+  Encoder, Histogram, HistogramVec, IntCounter, IntCounterVec, TextEncoder,
+};
+
+/// Latency of exported graphs run through the query APIs, including those answered from the
+/// result cache.
+pub static QUERY_DURATION: Lazy<HistogramVec> = Lazy::new(|| {
+  register_histogram_vec!(
+    "rdb_query_duration_seconds",
+    "Latency of queries.",
+    &["namespace"]
+  )
+  .unwrap()
+});
+
+/// Counted each time the data store of a namespace is opened, which each request that touches
+/// the data of a namespace does once.
+pub static NAMESPACE_REQUESTS: Lazy<IntCounterVec> = Lazy::new(|| {
+  register_int_counter_vec!(
+    "rdb_namespace_requests_total",
+    "Requests that accessed the data of a namespace.",
+    &["namespace"]
+  )
+  .unwrap()
+});
+
+pub static TXN_KV_OPS: Lazy<Histogram> = Lazy::new(|| {
+  register_histogram!(
+    "rdb_txn_kv_ops",
+    "KV operations per transaction on namespace data.",
+    vec![1.0, 2.0, 5.0, 10.0, 20.0, 50.0, 100.0, 200.0, 500.0, 1000.0, 5000.0]
+  )
+  .unwrap()
+});
+
+/// Each conflict is followed by a retry, unless the transaction is out of attempts.
+pub static TXN_CONFLICTS: Lazy<IntCounter> = Lazy::new(|| {
+  register_int_counter!(
+    "rdb_txn_conflicts_total",
+    "Transactions on namespace data that failed to commit with a conflict."
+  )
+  .unwrap()
+});
+
+/// `hot_hit`, `hit` or `miss`. A lookup that misses the hot items goes on to the other items, so
+/// the hit rate is `(hot_hit + hit) / (hot_hit + hit + miss)`.
+pub static QUERY_CACHE_LOOKUPS: Lazy<IntCounterVec> = Lazy::new(|| {
+  register_int_counter_vec!(
+    "rdb_query_cache_lookups_total",
+    "Lookups of compiled query scripts in the query cache.",
+    &["result"]
+  )
+  .unwrap()
+});
+
+/// Queries picked for the candidate deployment of their namespace, by candidate mode. `result` is
+/// `routed`, or `fallback` if the query stayed on the primary deployment.
+pub static ROUTED_QUERIES: Lazy<IntCounterVec> = Lazy::new(|| {
+  register_int_counter_vec!(
+    "rdb_routed_queries_total",
+    "Queries routed to a candidate deployment.",
+    &["namespace", "mode", "result"]
+  )
+  .unwrap()
+});
+
+/// Outputs of shadow queries on the candidate deployment, compared with those of the primary
+/// deployment. `result` is `match`, `mismatch` or `error`.
+pub static SHADOW_QUERIES: Lazy<IntCounterVec> = Lazy::new(|| {
+  register_int_counter_vec!(
+    "rdb_shadow_queries_total",
+    "Shadow queries run on a candidate deployment.",
+    &["namespace", "result"]
+  )
+  .unwrap()
+});
+
+/// Registers all metrics, so that they are exported before first being updated.
+pub fn init_metrics() {
+  Lazy::force(&QUERY_DURATION);
+  Lazy::force(&NAMESPACE_REQUESTS);
+  Lazy::force(&TXN_KV_OPS);
+  Lazy::force(&TXN_CONFLICTS);
+  Lazy::force(&QUERY_CACHE_LOOKUPS);
+  Lazy::force(&ROUTED_QUERIES);
+  Lazy::force(&SHADOW_QUERIES);
+}
+
+/// Encodes all metrics in the Prometheus text format.
+pub fn encode_metrics() -> String {
+  let mut buf = vec![];
+  TextEncoder::new()
+    .encode(&prometheus::gather(), &mut buf)
+    .expect("cannot encode metrics");
+  String::from_utf8(buf).expect("metrics are not valid utf-8")
+}
@@ -0,0 +1,79 @@
+use std::sync::Arc;
+
+use anyhow::Result;
+use rdb_analyzer::data::{mount::mount_namespace, treewalker::asm::codegen::compile_twscript};
+use thiserror::Error;
+
+use crate::{
+  auth::AuthScope,
+  exec_core::{ExecContext, SchemaContext},
+  state::get_state,
+  sysquery::latest_deployment_id,
+};
+
+#[derive(Error, Debug)]
+pub enum NamespaceMountError {
+  #[error("mounted namespace `{0}` has no deployment")]
+  NoDeployment(String),
+
+  #[error("query scripts that mount namespaces cannot run in interactive transactions")]
+  MountInTransaction,
+}
+
+/// Loads a query script, with the namespaces that it declares with `mount "<namespace>";`
+/// mounted read-only.
+///
+/// A mounted namespace is read with the latest deployment it has when the script is loaded, and
+/// stays on that deployment for as long as the loaded script is cached.
+pub async fn load_query_script(
+  schema_ctx: Arc<SchemaContext>,
+  script: &str,
+) -> Result<ExecContext> {
+  let compiled = compile_twscript(script)?;
+  let schema_ctx = with_mounts(schema_ctx, &compiled.mounts).await?;
+  ExecContext::load_compiled(schema_ctx, compiled, script.len())
+}
+
+/// `schema_ctx` with the namespaces in `mounts` mounted read-only, as in `load_query_script`.
+pub async fn with_mounts(
+  schema_ctx: Arc<SchemaContext>,
+  mounts: &[String],
+) -> Result<Arc<SchemaContext>> {
+  if mounts.is_empty() {
+    Ok(schema_ctx)
+  } else {
+    Ok(Arc::new(mount_namespaces(&schema_ctx, mounts).await?))
+  }
+}
+
+async fn mount_namespaces(schema_ctx: &SchemaContext, mounts: &[String]) -> Result<SchemaContext> {
+  let st = get_state();
+  let mut schema = schema_ctx.schema.clone();
+  let mut plan = schema_ctx.plan.clone();
+  for namespace_id in mounts {
+    let deployment_id = latest_deployment_id(namespace_id)
+      .await?
+      .ok_or_else(|| NamespaceMountError::NoDeployment(namespace_id.clone()))?;
+    let mounted = st
+      .schema_cache
+      .get_or_load(namespace_id, &deployment_id)
+      .await?;
+    mount_namespace(
+      &mut schema,
+      &mut plan,
+      namespace_id,
+      &mounted.schema,
+      &mounted.plan,
+    )?;
+  }
+  Ok(SchemaContext { schema, plan })
+}
+
+/// Checks that a query script may be created with `scope`: every namespace the script mounts must
+/// be allowed for the token.
+pub fn check_mounts(scope: &AuthScope, exec_ctx: &ExecContext) -> Result<()> {
+  for namespace_id in &exec_ctx.vm().script.mounts {
+    scope.check_mount(namespace_id)?;
+  }
+  Ok(())
+}
@@ -1,5 +1,7 @@
 use structopt::StructOpt;
 
+use crate::id_gen::IdStrategy;
+
 #[derive(Debug, StructOpt)]
 #[structopt(name = "rdb-server", about = "RefineDB server.")]
 pub struct Opt {
@@ -15,13 +17,44 @@ pub struct Opt {
   #[structopt(long, env = "RDB_SQLITE_DB")]
   pub sqlite_db: Option<String>,
 
+  /// Path to the RocksDB database. Requires the `rocksdb-backend` feature.
+  #[structopt(long, env = "RDB_ROCKSDB_DB")]
+  pub rocksdb_db: Option<String>,
+
+  /// Keep all data in memory. Everything is lost when the server exits.
+  #[structopt(long)]
+  pub memory: bool,
+
+  /// With `--memory`, evict keys this many seconds after they were last written.
+  #[structopt(long, env = "RDB_MEMORY_TTL_SECS", requires = "memory")]
+  pub memory_ttl_secs: Option<u64>,
+
+  /// Start a throwaway server for development and tests. Keeps data in memory unless another
+  /// backend is selected, listens on `127.0.0.1:50051` (gRPC) and `127.0.0.1:8080` (HTTP) unless
+  /// other addresses are given, creates the namespace `default`, migrates the system schema
+  /// without `--migration-hash`, enables ad-hoc queries and logs at the debug level.
+  #[structopt(long)]
+  pub dev: bool,
+
   /// GRPC listen address.
-  #[structopt(long, env = "RDB_GRPC_LISTEN")]
-  pub grpc_listen: String,
+  #[structopt(
+    long,
+    env = "RDB_GRPC_LISTEN",
+    required_unless_one = &["migrate-key-aliases", "dev"]
+  )]
+  pub grpc_listen: Option<String>,
 
   /// HTTP API listen address.
-  #[structopt(long, env = "RDB_HTTP_LISTEN")]
-  pub http_listen: String,
+  #[structopt(
+    long,
+    env = "RDB_HTTP_LISTEN",
+    required_unless_one = &["migrate-key-aliases", "dev"]
+  )]
+  pub http_listen: Option<String>,
+
+  /// Listen address of the read-only PostgreSQL wire protocol adapter. Disabled if not set.
+  #[structopt(long, env = "RDB_PG_LISTEN")]
+  pub pg_listen: Option<String>,
 
   /// Migration hash.
   #[structopt(long, env = "RDB_MIGRATION_HASH")]
@@ -34,4 +67,162 @@ pub struct Opt {
     env = "RDB_PROCESS_MEMORY_THRESHOLD_KB"
   )]
   pub process_memory_threshold_kb: u64,
+
+  /// Max number of deployments to keep compiled schemas and storage plans for.
+  #[structopt(long, default_value = "256", env = "RDB_SCHEMA_CACHE_SIZE")]
+  pub schema_cache_size: usize,
+
+  /// Max number of cached outputs of graphs declaring `@cache(...)`.
+  #[structopt(long, default_value = "4096", env = "RDB_RESULT_CACHE_SIZE")]
+  pub result_cache_size: usize,
+
+  /// Strategy for generating deployment ids: `uuid`, `ulid`, `ksuid` or `snowflake`. Namespaces
+  /// may override it with `rdbctl set-namespace-id-strategy`.
+  #[structopt(long, default_value = "uuid", env = "RDB_ID_STRATEGY")]
+  pub id_strategy: IdStrategy,
+
+  /// Node id (0-1023) embedded in snowflake ids. Must be unique across server instances.
+  #[structopt(long, default_value = "0", env = "RDB_SNOWFLAKE_NODE_ID")]
+  pub snowflake_node_id: u16,
+
+  /// Max number of attempts of a transaction that fails with a conflict.
+  #[structopt(long, default_value = "10", env = "RDB_TXN_MAX_ATTEMPTS")]
+  pub txn_max_attempts: usize,
+
+  /// Upper bound (in milliseconds) of the random delay before retrying a conflicting
+  /// transaction for the first time. Doubles on each further retry.
+  #[structopt(long, default_value = "5", env = "RDB_TXN_RETRY_BASE_DELAY_MS")]
+  pub txn_retry_base_delay_ms: u64,
+
+  /// Cap (in milliseconds) of the delay between retries of a conflicting transaction.
+  #[structopt(long, default_value = "200", env = "RDB_TXN_RETRY_MAX_DELAY_MS")]
+  pub txn_retry_max_delay_ms: u64,
+
+  /// Time limit (in milliseconds) of a query, including retries after conflicts.
+  #[structopt(long, default_value = "5000", env = "RDB_QUERY_TIMEOUT_MS")]
+  pub query_timeout_ms: u64,
+
+  /// Max number of KV reads of a query attempt. Each key returned by a scan counts as one read.
+  #[structopt(long, env = "RDB_MAX_KV_READS")]
+  pub max_kv_reads: Option<u64>,
+
+  /// Max number of KV writes of a query attempt.
+  #[structopt(long, env = "RDB_MAX_KV_WRITES")]
+  pub max_kv_writes: Option<u64>,
+
+  /// Max number of subgraph invocations of a query attempt, including one per member visited by
+  /// a `reduce`.
+  #[structopt(long, env = "RDB_MAX_SUBGRAPH_CALLS")]
+  pub max_subgraph_calls: Option<u64>,
+
+  /// Max size (in bytes) of a single value read or written by a query.
+  #[structopt(long, env = "RDB_MAX_VALUE_SIZE")]
+  pub max_value_size: Option<usize>,
+
+  /// Max depth of nested subgraph invocations.
+  #[structopt(long, default_value = "128", env = "RDB_MAX_RECURSION_DEPTH")]
+  pub max_recursion_depth: usize,
+
+  /// Max number of graph nodes and KV reads that a query runs concurrently.
+  #[structopt(long, default_value = "16", env = "RDB_EXEC_CONCURRENCY")]
+  pub exec_concurrency: usize,
+
+  /// Max number of nodes of a subgraph that is inlined into its callers when a script is loaded.
+  /// Zero disables inlining.
+  #[structopt(long, default_value = "16", env = "RDB_INLINE_NODE_BUDGET")]
+  pub inline_node_budget: usize,
+
+  /// Run the nodes of each graph one by one in topological order. Slower for graphs with
+  /// independent KV operations, but reproducible.
+  #[structopt(long)]
+  pub deterministic_scheduler: bool,
+
+  /// Keep the first candidate when both candidates of a `select` fire, with a warning, instead of
+  /// failing the query.
+  #[structopt(long)]
+  pub lenient_select: bool,
+
+  /// Time (in milliseconds) after which a transaction opened with `beginTransaction` is rolled
+  /// back if not committed.
+  #[structopt(long, default_value = "5000", env = "RDB_EXPLICIT_TXN_TIMEOUT_MS")]
+  pub explicit_txn_timeout_ms: u64,
+
+  /// Max number of transactions opened with `beginTransaction` at the same time.
+  #[structopt(long, default_value = "1024", env = "RDB_MAX_EXPLICIT_TXNS")]
+  pub max_explicit_txns: usize,
+
+  /// Interval (in milliseconds) between flushes of the per-namespace usage counters.
+  #[structopt(long, default_value = "10000", env = "RDB_USAGE_FLUSH_INTERVAL_MS")]
+  pub usage_flush_interval_ms: u64,
+
+  /// Number of changes a subscriber may fall behind before it misses some.
+  #[structopt(long, default_value = "1024", env = "RDB_SUBSCRIPTION_BUFFER_SIZE")]
+  pub subscription_buffer_size: usize,
+
+  /// Max sustained rate (in queries per second) of queries on a namespace. Unlimited if not set.
+  #[structopt(long, env = "RDB_NAMESPACE_RPS")]
+  pub namespace_rps: Option<f64>,
+
+  /// Number of queries on a namespace that may be made at once after being idle. Defaults to
+  /// `--namespace-rps`.
+  #[structopt(long, env = "RDB_NAMESPACE_BURST")]
+  pub namespace_burst: Option<f64>,
+
+  /// Max sustained rate (in queries per second) of queries made with a single API token.
+  /// Unlimited if not set.
+  #[structopt(long, env = "RDB_TOKEN_RPS")]
+  pub token_rps: Option<f64>,
+
+  /// Number of queries made with an API token that may be made at once after being idle.
+  /// Defaults to `--token-rps`.
+  #[structopt(long, env = "RDB_TOKEN_BURST")]
+  pub token_burst: Option<f64>,
+
+  /// Reject deployments with data-lossy schema changes, e.g. removed fields or changed types,
+  /// from the deployment they migrate from, unless the request sets `allow_lossy`.
+  #[structopt(long, env = "RDB_REJECT_LOSSY_DEPLOYMENTS")]
+  pub reject_lossy_deployments: bool,
+
+  /// Secret of the root token, which may access all namespaces and manage tokens. Setting it
+  /// requires all gRPC and HTTP requests to carry a token in the `Authorization: Bearer` header.
+  #[structopt(long, env = "RDB_ROOT_TOKEN")]
+  pub root_token: Option<String>,
+
+  /// Interval (in milliseconds) between reloads of the tokens created through other servers.
+  #[structopt(long, default_value = "5000", env = "RDB_TOKEN_REFRESH_INTERVAL_MS")]
+  pub token_refresh_interval_ms: u64,
+
+  /// Accept `executeAdhocQuery` requests, which run scripts that are not stored on the server.
+  /// Any token with access to a namespace may then run arbitrary code against it.
+  #[structopt(long)]
+  pub enable_adhoc_queries: bool,
+
+  /// Count the KV operations made by each query, and return them in the `X-Rdb-Kv-Ops`
+  /// response header.
+  #[structopt(long)]
+  pub enable_kv_profiling: bool,
+
+  /// Append the writes of each committed transaction on a namespace to its changelog, which is
+  /// read with `tailChangelog`. Serializes all writing transactions on a namespace. Should be
+  /// enabled on all servers or none, or the changelog will miss writes.
+  #[structopt(long)]
+  pub enable_changelog: bool,
+
+  /// Instead of serving, rewrite the data of the deployment `NAMESPACE/DEPLOYMENT` to use key
+  /// aliases and exit. No server may use the namespace while this runs.
+  #[structopt(long)]
+  pub migrate_key_aliases: Option<String>,
+
+  /// Interval (in milliseconds) between runs of the reaper that deletes expired members of sets
+  /// with a `@ttl` member type. Zero disables the reaper.
+  #[structopt(long, default_value = "60000", env = "RDB_TTL_REAP_INTERVAL_MS")]
+  pub ttl_reap_interval_ms: u64,
+
+  /// Number of set members checked per transaction by the ttl reaper.
+  #[structopt(long, default_value = "100", env = "RDB_TTL_REAP_BATCH_SIZE")]
+  pub ttl_reap_batch_size: usize,
+
+  /// Number of set members moved per transaction by `--migrate-key-aliases`.
+  #[structopt(long, default_value = "1000")]
+  pub key_alias_migration_batch_size: usize,
 }
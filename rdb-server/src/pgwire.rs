@@ -0,0 +1,797 @@
+//! A read-only adapter for the PostgreSQL wire protocol, so that `psql` and other Postgres
+//! clients can be used to inspect data.
+//!
+//! Only the simple query protocol is supported, with statements of the form
+//! `SELECT <columns> FROM <path> [WHERE <conditions>] [LIMIT <n>]`. `path` is a dot-separated
+//! path from an export to a set of tables or a table, as in `exportData`, and columns are the
+//! fields of the tables. The database name of a connection is the namespace, and queries run
+//! against its latest deployment. If authentication is enabled, the password is the token.
+
+use std::collections::BTreeMap;
+
+use anyhow::Result;
+use rdb_analyzer::{
+  data::treewalker::serialize::{SerializedVmValue, TaggedVmValue, VmValueEncodeConfig},
+  schema::compile::{CompiledSchema, FieldType, PrimitiveType, SpecializedType},
+};
+use thiserror::Error;
+use tokio::{
+  io::{AsyncReadExt, AsyncWriteExt, BufStream},
+  net::{TcpListener, TcpStream, ToSocketAddrs},
+};
+
+use crate::{
+  auth::{AuthError, AuthScope},
+  exec_core::ExecContext,
+  metering::open_namespace_store,
+  rate_limit::check_namespace_rate_limit,
+  state::get_state,
+  sysquery::{latest_deployment_id, list_namespace_ids},
+};
+
+const PROTOCOL_VERSION_3: u32 = 196608;
+const SSL_REQUEST_CODE: u32 = 80877103;
+const GSSENC_REQUEST_CODE: u32 = 80877104;
+const CANCEL_REQUEST_CODE: u32 = 80877102;
+
+/// Upper bound of the length of a message from the client.
+const MAX_MESSAGE_LEN: usize = 1024 * 256;
+
+/// Number of rows read from the store at a time.
+const SCAN_PAGE_SIZE: usize = 256;
+
+const OID_BYTEA: u32 = 17;
+const OID_INT8: u32 = 20;
+const OID_TEXT: u32 = 25;
+const OID_JSON: u32 = 114;
+const OID_FLOAT8: u32 = 701;
+
+#[derive(Error, Debug)]
+pub enum PgWireError {
+  #[error("unsupported protocol version: {0}")]
+  UnsupportedProtocolVersion(u32),
+
+  #[error("message too long: {0} bytes")]
+  MessageTooLong(usize),
+
+  #[error("malformed message")]
+  MalformedMessage,
+
+  #[error("no database (namespace) given")]
+  NoDatabase,
+
+  #[error("database \"{0}\" does not exist")]
+  NamespaceNotFound(String),
+
+  #[error("namespace `{0}` has no deployment")]
+  NoDeployment(String),
+
+  #[error("syntax error: {0}")]
+  Syntax(String),
+
+  #[error("only `SELECT ... FROM ...` statements are supported")]
+  UnsupportedStatement,
+
+  #[error("the extended query protocol is not supported")]
+  ExtendedQueryProtocol,
+
+  #[error("relation \"{0}\" does not exist or is not a set or table")]
+  UndefinedTable(String),
+
+  #[error("column \"{0}\" does not exist")]
+  UndefinedColumn(String),
+}
+
+impl PgWireError {
+  fn sqlstate(&self) -> &'static str {
+    match self {
+      Self::UnsupportedProtocolVersion(_) => "0A000",
+      Self::MessageTooLong(_) | Self::MalformedMessage => "08P01",
+      Self::NoDatabase | Self::NamespaceNotFound(_) | Self::NoDeployment(_) => "3D000",
+      Self::Syntax(_) => "42601",
+      Self::UnsupportedStatement | Self::ExtendedQueryProtocol => "0A000",
+      Self::UndefinedTable(_) => "42P01",
+      Self::UndefinedColumn(_) => "42703",
+    }
+  }
+}
+
+pub async fn run_pg_server(addr: impl ToSocketAddrs) -> ! {
+  let listener = TcpListener::bind(addr)
+    .await
+    .expect("failed to bind postgres listener");
+  loop {
+    let (stream, peer) = match listener.accept().await {
+      Ok(x) => x,
+      Err(e) => {
+        log::warn!("pgwire: accept error: {}", e);
+        continue;
+      }
+    };
+    tokio::spawn(async move {
+      if let Err(e) = serve_connection(stream).await {
+        log::debug!("pgwire: connection from {} closed: {:?}", peer, e);
+      }
+    });
+  }
+}
+
+async fn serve_connection(stream: TcpStream) -> Result<()> {
+  let mut conn = Connection {
+    stream: BufStream::new(stream),
+    out: vec![],
+  };
+  let params = match conn.read_startup().await? {
+    Some(x) => x,
+    None => return Ok(()),
+  };
+  let namespace_id = match params.get("database").or_else(|| params.get("user")) {
+    Some(x) => x.clone(),
+    None => {
+      conn.error(&PgWireError::NoDatabase.into());
+      conn.flush().await?;
+      return Ok(());
+    }
+  };
+
+  let registry = &get_state().token_registry;
+  let scope = if registry.enabled() {
+    // AuthenticationCleartextPassword
+    conn.message(b'R', |b| b.extend_from_slice(&3u32.to_be_bytes()));
+    conn.flush().await?;
+    let (tag, body) = conn.read_message().await?;
+    if tag != b'p' {
+      return Err(PgWireError::MalformedMessage.into());
+    }
+    let password = read_cstr(&mut &body[..])?;
+    registry.authenticate(Some(&format!("Bearer {}", password)))
+  } else {
+    Ok(AuthScope::Root)
+  };
+  if let Err(e) = scope.and_then(|x| x.check_namespace(&namespace_id)) {
+    conn.error(&e);
+    conn.flush().await?;
+    return Ok(());
+  }
+  if !list_namespace_ids().await?.contains(&namespace_id) {
+    conn.error(&PgWireError::NamespaceNotFound(namespace_id).into());
+    conn.flush().await?;
+    return Ok(());
+  }
+
+  // AuthenticationOk
+  conn.message(b'R', |b| b.extend_from_slice(&0u32.to_be_bytes()));
+  for (k, v) in [
+    ("server_version", "13.0"),
+    ("server_encoding", "UTF8"),
+    ("client_encoding", "UTF8"),
+    ("DateStyle", "ISO, MDY"),
+    ("integer_datetimes", "on"),
+    ("standard_conforming_strings", "on"),
+  ] {
+    conn.message(b'S', |b| {
+      put_cstr(b, k);
+      put_cstr(b, v);
+    });
+  }
+  conn.ready();
+  conn.flush().await?;
+
+  // After an error in the extended query protocol, messages are discarded until `Sync`.
+  let mut discarding = false;
+  loop {
+    let (tag, body) = conn.read_message().await?;
+    match tag {
+      b'Q' => {
+        let query = read_cstr(&mut &body[..])?;
+        conn.simple_query(&namespace_id, &query).await?;
+        conn.ready();
+      }
+      b'X' => return Ok(()),
+      b'S' => {
+        discarding = false;
+        conn.ready();
+      }
+      b'H' => {}
+      _ => {
+        if !discarding {
+          conn.error(&PgWireError::ExtendedQueryProtocol.into());
+          discarding = true;
+        }
+      }
+    }
+    conn.flush().await?;
+  }
+}
+
+struct Connection {
+  stream: BufStream<TcpStream>,
+
+  /// Messages not yet written to `stream`.
+  out: Vec<u8>,
+}
+
+impl Connection {
+  /// Handles SSL and GSSAPI encryption requests, which are declined, and returns the parameters
+  /// of the startup message. Returns `None` for cancel requests.
+  async fn read_startup(&mut self) -> Result<Option<BTreeMap<String, String>>> {
+    loop {
+      let len = self.stream.read_u32().await? as usize;
+      if len < 8 {
+        return Err(PgWireError::MalformedMessage.into());
+      }
+      if len > MAX_MESSAGE_LEN {
+        return Err(PgWireError::MessageTooLong(len).into());
+      }
+      let mut body = vec![0u8; len - 4];
+      self.stream.read_exact(&mut body).await?;
+      let code = u32::from_be_bytes([body[0], body[1], body[2], body[3]]);
+      match code {
+        SSL_REQUEST_CODE | GSSENC_REQUEST_CODE => {
+          self.stream.write_all(b"N").await?;
+          self.stream.flush().await?;
+        }
+        CANCEL_REQUEST_CODE => return Ok(None),
+        PROTOCOL_VERSION_3 => {
+          let mut rest = &body[4..];
+          let mut params = BTreeMap::new();
+          loop {
+            let k = read_cstr(&mut rest)?;
+            if k.is_empty() {
+              break;
+            }
+            let v = read_cstr(&mut rest)?;
+            params.insert(k, v);
+          }
+          return Ok(Some(params));
+        }
+        _ => {
+          self.error(&PgWireError::UnsupportedProtocolVersion(code).into());
+          self.flush().await?;
+          return Ok(None);
+        }
+      }
+    }
+  }
+
+  async fn read_message(&mut self) -> Result<(u8, Vec<u8>)> {
+    let tag = self.stream.read_u8().await?;
+    let len = self.stream.read_u32().await? as usize;
+    if len < 4 {
+      return Err(PgWireError::MalformedMessage.into());
+    }
+    if len > MAX_MESSAGE_LEN {
+      return Err(PgWireError::MessageTooLong(len).into());
+    }
+    let mut body = vec![0u8; len - 4];
+    self.stream.read_exact(&mut body).await?;
+    Ok((tag, body))
+  }
+
+  fn message(&mut self, tag: u8, f: impl FnOnce(&mut Vec<u8>)) {
+    self.out.push(tag);
+    let len_pos = self.out.len();
+    self.out.extend_from_slice(&[0u8; 4]);
+    f(&mut self.out);
+    let len = (self.out.len() - len_pos) as u32;
+    self.out[len_pos..len_pos + 4].copy_from_slice(&len.to_be_bytes());
+  }
+
+  async fn flush(&mut self) -> Result<()> {
+    self.stream.write_all(&self.out).await?;
+    self.stream.flush().await?;
+    self.out.clear();
+    Ok(())
+  }
+
+  /// ReadyForQuery, outside of a transaction block.
+  fn ready(&mut self) {
+    self.message(b'Z', |b| b.push(b'I'));
+  }
+
+  fn error(&mut self, e: &anyhow::Error) {
+    let code = if let Some(e) = e.downcast_ref::<PgWireError>() {
+      e.sqlstate()
+    } else if let Some(e) = e.downcast_ref::<AuthError>() {
+      match e {
+        AuthError::NamespaceNotAllowed(_) => "42501",
+        _ => "28P01",
+      }
+    } else {
+      "XX000"
+    };
+    self.message(b'E', |b| {
+      for (field, value) in [
+        (b'S', "ERROR"),
+        (b'V', "ERROR"),
+        (b'C', code),
+        (b'M', &*e.to_string()),
+      ] {
+        b.push(field);
+        put_cstr(b, value);
+      }
+      b.push(0);
+    });
+  }
+
+  async fn simple_query(&mut self, namespace_id: &str, query: &str) -> Result<()> {
+    let statements = match parse_statements(query) {
+      Ok(x) => x,
+      Err(e) => {
+        self.error(&e);
+        return Ok(());
+      }
+    };
+    if statements.is_empty() {
+      self.message(b'I', |_| {});
+      return Ok(());
+    }
+    for stmt in &statements {
+      if let Err(e) = self.run_select(namespace_id, stmt).await {
+        // Statements after a failed one are not run.
+        self.error(&e);
+        return Ok(());
+      }
+    }
+    Ok(())
+  }
+
+  async fn run_select(&mut self, namespace_id: &str, stmt: &Select) -> Result<()> {
+    check_namespace_rate_limit(namespace_id)?;
+    let st = get_state();
+    let deployment_id = latest_deployment_id(namespace_id)
+      .await?
+      .ok_or_else(|| PgWireError::NoDeployment(namespace_id.to_string()))?;
+    let schema_ctx = st
+      .schema_cache
+      .get_or_load(namespace_id, &deployment_id)
+      .await?;
+    let exec_ctx = ExecContext::load(schema_ctx.clone(), "")?;
+    let (is_set, row_ty) = resolve_rows(&schema_ctx.schema, &stmt.path)
+      .ok_or_else(|| PgWireError::UndefinedTable(stmt.path.clone()))?;
+
+    let columns = match &stmt.columns {
+      Some(x) => x
+        .iter()
+        .map(|name| {
+          row_ty
+            .fields
+            .get(name.as_str())
+            .map(|(ty, _)| (name.clone(), column_type(ty)))
+            .ok_or_else(|| PgWireError::UndefinedColumn(name.clone()))
+        })
+        .collect::<Result<Vec<_>, _>>()?,
+      None => row_ty
+        .fields
+        .iter()
+        .map(|(name, (ty, _))| (name.to_string(), column_type(ty)))
+        .collect(),
+    };
+    for filter in &stmt.filters {
+      if !row_ty.fields.contains_key(filter.column.as_str()) {
+        return Err(PgWireError::UndefinedColumn(filter.column.clone()).into());
+      }
+    }
+
+    let kv = open_namespace_store(namespace_id).await?;
+    let config = VmValueEncodeConfig {
+      enable_bytes: true,
+      enable_int64: true,
+      enable_double: true,
+    };
+
+    // RowDescription
+    self.message(b'T', |b| {
+      b.extend_from_slice(&(columns.len() as u16).to_be_bytes());
+      for (name, oid) in &columns {
+        put_cstr(b, name);
+        b.extend_from_slice(&0u32.to_be_bytes()); // table oid
+        b.extend_from_slice(&0u16.to_be_bytes()); // column number
+        b.extend_from_slice(&oid.to_be_bytes());
+        b.extend_from_slice(&(-1i16).to_be_bytes()); // type size
+        b.extend_from_slice(&(-1i32).to_be_bytes()); // type modifier
+        b.extend_from_slice(&0u16.to_be_bytes()); // text format
+      }
+    });
+
+    let limit = stmt.limit.unwrap_or(u64::MAX);
+    let mut count = 0u64;
+    let mut after: Option<SerializedVmValue> = None;
+    while count < limit {
+      let page = exec_ctx
+        .export_rows(&*kv, &stmt.path, after.as_ref(), SCAN_PAGE_SIZE, &config)
+        .await?;
+      for row in &page.rows {
+        if count >= limit {
+          break;
+        }
+        let fields = match row {
+          SerializedVmValue::Tagged(TaggedVmValue::M(x)) => x,
+          _ => continue,
+        };
+        if !stmt
+          .filters
+          .iter()
+          .all(|x| x.matches(fields.get(&x.column).and_then(render_value).as_deref()))
+        {
+          continue;
+        }
+        self.message(b'D', |b| {
+          b.extend_from_slice(&(columns.len() as u16).to_be_bytes());
+          for (name, _) in &columns {
+            match fields.get(name).and_then(render_value) {
+              Some(x) => {
+                b.extend_from_slice(&(x.len() as u32).to_be_bytes());
+                b.extend_from_slice(x.as_bytes());
+              }
+              None => b.extend_from_slice(&(-1i32).to_be_bytes()),
+            }
+          }
+        });
+        count += 1;
+      }
+      self.flush().await?;
+      after = page.next;
+      if after.is_none() || !is_set {
+        break;
+      }
+    }
+
+    self.message(b'C', |b| put_cstr(b, &format!("SELECT {}", count)));
+    Ok(())
+  }
+}
+
+/// Resolves `path` to the type of its rows, and whether it is a set.
+fn resolve_rows<'a>(schema: &'a CompiledSchema, path: &str) -> Option<(bool, &'a SpecializedType)> {
+  let mut segments = path.split('.');
+  let mut ty = schema.exports.get(segments.next()?)?;
+  for segment in segments {
+    match ty {
+      FieldType::Table(x) => ty = &schema.types.get(x)?.fields.get(segment)?.0,
+      _ => return None,
+    }
+  }
+  match ty {
+    FieldType::Table(x) => Some((false, schema.types.get(x)?)),
+    FieldType::Set(x) => match &**x {
+      FieldType::Table(x) => Some((true, schema.types.get(x)?)),
+      _ => None,
+    },
+    _ => None,
+  }
+}
+
+fn column_type(ty: &FieldType) -> u32 {
+  match ty {
+    FieldType::Primitive(PrimitiveType::Int64) => OID_INT8,
+    FieldType::Primitive(PrimitiveType::Double) => OID_FLOAT8,
+    FieldType::Primitive(PrimitiveType::String) => OID_TEXT,
+    FieldType::Primitive(PrimitiveType::Bytes) => OID_BYTEA,
+    _ => OID_JSON,
+  }
+}
+
+/// The text format of a value, or `None` for null.
+fn render_value(v: &SerializedVmValue) -> Option<String> {
+  Some(match v {
+    SerializedVmValue::Null(_) => return None,
+    SerializedVmValue::String(x) => x.clone(),
+    SerializedVmValue::Bool(x) => if *x { "t" } else { "f" }.to_string(),
+    SerializedVmValue::Int64(x) => x.to_string(),
+    SerializedVmValue::Double(x) if x.is_nan() => "NaN".to_string(),
+    SerializedVmValue::Double(x) if x.is_infinite() => {
+      if *x > 0.0 { "Infinity" } else { "-Infinity" }.to_string()
+    }
+    SerializedVmValue::Double(x) => x.to_string(),
+    SerializedVmValue::Bytes(x) => format!("\\x{}", hex::encode(x)),
+    SerializedVmValue::Tagged(_) => v.to_json().to_string(),
+  })
+}
+
+fn read_cstr(buf: &mut &[u8]) -> Result<String> {
+  let end = buf
+    .iter()
+    .position(|x| *x == 0)
+    .ok_or(PgWireError::MalformedMessage)?;
+  let s = std::str::from_utf8(&buf[..end])
+    .map_err(|_| PgWireError::MalformedMessage)?
+    .to_string();
+  *buf = &buf[end + 1..];
+  Ok(s)
+}
+
+fn put_cstr(buf: &mut Vec<u8>, s: &str) {
+  buf.extend_from_slice(s.as_bytes());
+  buf.push(0);
+}
+
+#[derive(Debug)]
+struct Select {
+  /// `None` for `*`.
+  columns: Option<Vec<String>>,
+  path: String,
+  filters: Vec<Filter>,
+  limit: Option<u64>,
+}
+
+#[derive(Debug)]
+struct Filter {
+  column: String,
+  negated: bool,
+  value: Literal,
+}
+
+#[derive(Debug)]
+enum Literal {
+  Null,
+  String(String),
+  Number(f64),
+}
+
+impl Filter {
+  /// Matches the text format of a value against the filter.
+  fn matches(&self, value: Option<&str>) -> bool {
+    let eq = match (&self.value, value) {
+      (Literal::Null, x) => x.is_none(),
+      (_, None) => return false,
+      (Literal::String(x), Some(y)) => x == y,
+      (Literal::Number(x), Some(y)) => y.parse::<f64>().map(|y| *x == y).unwrap_or(false),
+    };
+    eq != self.negated
+  }
+}
+
+#[derive(Debug, Clone, PartialEq)]
+enum Token {
+  /// Unquoted identifiers and keywords.
+  Word(String),
+  QuotedIdent(String),
+  String(String),
+  Number(String),
+  Symbol(&'static str),
+}
+
+fn tokenize(input: &str) -> Result<Vec<Token>> {
+  let mut out = vec![];
+  let mut chars = input.chars().peekable();
+  while let Some(&c) = chars.peek() {
+    if c.is_whitespace() {
+      chars.next();
+    } else if c.is_ascii_alphabetic() || c == '_' {
+      let mut s = String::new();
+      while let Some(&c) = chars.peek() {
+        if c.is_ascii_alphanumeric() || c == '_' {
+          s.push(c);
+          chars.next();
+        } else {
+          break;
+        }
+      }
+      out.push(Token::Word(s));
+    } else if c.is_ascii_digit() || c == '-' {
+      let mut s = String::new();
+      s.push(c);
+      chars.next();
+      while let Some(&c) = chars.peek() {
+        if c.is_ascii_digit() || c == '.' || c == 'e' || c == 'E' {
+          s.push(c);
+          chars.next();
+        } else {
+          break;
+        }
+      }
+      if s == "-" {
+        return Err(PgWireError::Syntax("unexpected `-`".into()).into());
+      }
+      out.push(Token::Number(s));
+    } else if c == '\'' || c == '"' {
+      chars.next();
+      let mut s = String::new();
+      loop {
+        match chars.next() {
+          Some(x) if x == c => {
+            // A doubled quote is an escaped quote.
+            if chars.peek() == Some(&c) {
+              s.push(c);
+              chars.next();
+            } else {
+              break;
+            }
+          }
+          Some(x) => s.push(x),
+          None => return Err(PgWireError::Syntax("unterminated quoted string".into()).into()),
+        }
+      }
+      out.push(if c == '\'' {
+        Token::String(s)
+      } else {
+        Token::QuotedIdent(s)
+      });
+    } else {
+      chars.next();
+      let symbol = match (c, chars.peek()) {
+        ('<', Some('>')) | ('!', Some('=')) => {
+          chars.next();
+          "<>"
+        }
+        ('*', _) => "*",
+        (',', _) => ",",
+        ('.', _) => ".",
+        ('=', _) => "=",
+        (';', _) => ";",
+        _ => return Err(PgWireError::Syntax(format!("unexpected `{}`", c)).into()),
+      };
+      out.push(Token::Symbol(symbol));
+    }
+  }
+  Ok(out)
+}
+
+fn parse_statements(input: &str) -> Result<Vec<Select>> {
+  let tokens = tokenize(input)?;
+  let mut p = Parser {
+    tokens: &tokens,
+    pos: 0,
+  };
+  let mut out = vec![];
+  loop {
+    while p.eat_symbol(";") {}
+    if p.pos == tokens.len() {
+      break;
+    }
+    out.push(p.select()?);
+    if p.pos < tokens.len() && !p.eat_symbol(";") {
+      return Err(p.unexpected());
+    }
+  }
+  Ok(out)
+}
+
+struct Parser<'a> {
+  tokens: &'a [Token],
+  pos: usize,
+}
+
+impl<'a> Parser<'a> {
+  fn peek(&self) -> Option<&'a Token> {
+    self.tokens.get(self.pos)
+  }
+
+  fn unexpected(&self) -> anyhow::Error {
+    match self.peek() {
+      Some(x) => PgWireError::Syntax(format!("unexpected {:?}", x)).into(),
+      None => PgWireError::Syntax("unexpected end of input".into()).into(),
+    }
+  }
+
+  fn eat_symbol(&mut self, symbol: &'static str) -> bool {
+    if self.peek() == Some(&Token::Symbol(symbol)) {
+      self.pos += 1;
+      true
+    } else {
+      false
+    }
+  }
+
+  /// Keywords are case-insensitive.
+  fn eat_keyword(&mut self, keyword: &str) -> bool {
+    match self.peek() {
+      Some(Token::Word(x)) if x.eq_ignore_ascii_case(keyword) => {
+        self.pos += 1;
+        true
+      }
+      _ => false,
+    }
+  }
+
+  fn expect_keyword(&mut self, keyword: &str) -> Result<()> {
+    if self.eat_keyword(keyword) {
+      Ok(())
+    } else {
+      Err(self.unexpected())
+    }
+  }
+
+  /// Identifiers are case-sensitive, as are the names in schemas.
+  fn ident(&mut self) -> Result<String> {
+    match self.peek() {
+      Some(Token::Word(x)) | Some(Token::QuotedIdent(x)) => {
+        self.pos += 1;
+        Ok(x.clone())
+      }
+      _ => Err(self.unexpected()),
+    }
+  }
+
+  fn select(&mut self) -> Result<Select> {
+    if !self.eat_keyword("select") {
+      return Err(PgWireError::UnsupportedStatement.into());
+    }
+    let columns = if self.eat_symbol("*") {
+      None
+    } else {
+      let mut columns = vec![self.ident()?];
+      while self.eat_symbol(",") {
+        columns.push(self.ident()?);
+      }
+      Some(columns)
+    };
+
+    self.expect_keyword("from")?;
+    let mut path = self.ident()?;
+    while self.eat_symbol(".") {
+      path.push('.');
+      path.push_str(&self.ident()?);
+    }
+
+    let mut filters = vec![];
+    if self.eat_keyword("where") {
+      loop {
+        filters.push(self.filter()?);
+        if !self.eat_keyword("and") {
+          break;
+        }
+      }
+    }
+
+    let limit = if self.eat_keyword("limit") {
+      match self.peek() {
+        Some(Token::Number(x)) => {
+          self.pos += 1;
+          Some(
+            x.parse()
+              .map_err(|_| PgWireError::Syntax(format!("invalid limit: {}", x)))?,
+          )
+        }
+        _ => return Err(self.unexpected()),
+      }
+    } else {
+      None
+    };
+
+    Ok(Select {
+      columns,
+      path,
+      filters,
+      limit,
+    })
+  }
+
+  /// `<column> = <literal>`, `<column> <> <literal>` or `<column> IS [NOT] NULL`.
+  fn filter(&mut self) -> Result<Filter> {
+    let column = self.ident()?;
+    if self.eat_keyword("is") {
+      let negated = self.eat_keyword("not");
+      self.expect_keyword("null")?;
+      return Ok(Filter {
+        column,
+        negated,
+        value: Literal::Null,
+      });
+    }
+    let negated = if self.eat_symbol("=") {
+      false
+    } else if self.eat_symbol("<>") {
+      true
+    } else {
+      return Err(self.unexpected());
+    };
+    let value = match self.peek() {
+      Some(Token::String(x)) => Literal::String(x.clone()),
+      Some(Token::Number(x)) => Literal::Number(
+        x.parse()
+          .map_err(|_| PgWireError::Syntax(format!("invalid number: {}", x)))?,
+      ),
+      Some(Token::Word(x)) if x.eq_ignore_ascii_case("true") => Literal::String("t".into()),
+      Some(Token::Word(x)) if x.eq_ignore_ascii_case("false") => Literal::String("f".into()),
+      _ => return Err(self.unexpected()),
+    };
+    self.pos += 1;
+    Ok(Filter {
+      column,
+      negated,
+      value,
+    })
+  }
+}
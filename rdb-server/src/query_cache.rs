@@ -1,5 +1,8 @@
 use std::{
-  sync::{Arc, Weak},
+  sync::{
+    atomic::{AtomicU64, Ordering},
+    Arc, Weak,
+  },
   time::{Duration, Instant},
 };
 
@@ -7,7 +10,7 @@ use lru::LruCache;
 use sysinfo::{get_current_pid, ProcessExt, System, SystemExt};
 use tokio::{sync::Mutex, time::sleep};
 
-use crate::exec_core::ExecContext;
+use crate::{exec_core::ExecContext, metrics::QUERY_CACHE_LOOKUPS};
 
 /// The minimum threshold to shrink query cache to.
 const MIN_QUERY_CACHE_SIZE: usize = 64;
@@ -21,6 +24,9 @@ pub struct QueryCache {
   items: Mutex<LruCache<QueryCacheKey, Arc<ExecContext>>>,
   hot_items: Mutex<LruCache<(String, String), HotItem>>,
   params: QueryCacheParams,
+  hot_hits: AtomicU64,
+  hits: AtomicU64,
+  misses: AtomicU64,
 }
 
 struct HotItem {
@@ -48,12 +54,36 @@ pub struct QueryCacheKey {
   pub query_script_create_time: i64,
 }
 
+/// A snapshot of the contents of a `QueryCache`, and of its lookup counts since the server
+/// started.
+#[derive(Clone, Debug)]
+pub struct QueryCacheStats {
+  pub entries: Vec<QueryCacheEntry>,
+  pub hot_hits: u64,
+  pub hits: u64,
+  pub misses: u64,
+}
+
+#[derive(Clone, Debug)]
+pub struct QueryCacheEntry {
+  pub key: QueryCacheKey,
+
+  /// See `ExecContext::size_estimate`.
+  pub size_estimate: usize,
+
+  /// Whether the entry is also a hot item, i.e. served without looking up the query script.
+  pub hot: bool,
+}
+
 impl QueryCache {
   pub fn new(params: QueryCacheParams) -> Arc<Self> {
     let me = Arc::new(Self {
       items: Mutex::new(LruCache::unbounded()),
       hot_items: Mutex::new(LruCache::unbounded()),
       params,
+      hot_hits: AtomicU64::new(0),
+      hits: AtomicU64::new(0),
+      misses: AtomicU64::new(0),
     });
     let me_weak = Arc::downgrade(&me);
     tokio::spawn(async move {
@@ -71,16 +101,17 @@ impl QueryCache {
 
     // Peek. Don't update LRU state.
     if let Some(x) = hot_items.peek(&(namespace_id.to_string(), query_script_id.to_string())) {
+      QUERY_CACHE_LOOKUPS.with_label_values(&["hot_hit"]).inc();
+      self.hot_hits.fetch_add(1, Ordering::Relaxed);
       Some(x.exec_ctx.clone())
     } else {
       None
     }
   }
 
+  /// Looks up a query script and makes it the hot item of its namespace and query script id.
   pub async fn get(&self, key: &QueryCacheKey) -> Option<Arc<ExecContext>> {
-    let items = self.items.lock().await;
-    let item = items.peek(key).cloned();
-    drop(items);
+    let item = self.lookup(key).await;
 
     // Insert into hot cache.
     if let Some(item) = &item {
@@ -96,10 +127,84 @@ impl QueryCache {
     item
   }
 
+  /// Looks up a query script without touching the hot items, for query scripts loaded on a
+  /// deployment other than the one they are associated with.
+  pub async fn lookup(&self, key: &QueryCacheKey) -> Option<Arc<ExecContext>> {
+    let items = self.items.lock().await;
+    let item = items.peek(key).cloned();
+    drop(items);
+    QUERY_CACHE_LOOKUPS
+      .with_label_values(&[if item.is_some() { "hit" } else { "miss" }])
+      .inc();
+    if item.is_some() {
+      self.hits.fetch_add(1, Ordering::Relaxed);
+    } else {
+      self.misses.fetch_add(1, Ordering::Relaxed);
+    }
+    item
+  }
+
   pub async fn put(&self, key: QueryCacheKey, value: Arc<ExecContext>) {
     self.items.lock().await.put(key, value);
   }
 
+  pub async fn stats(&self) -> QueryCacheStats {
+    let items = self.items.lock().await;
+    let hot_items = self.hot_items.lock().await;
+    let entries = items
+      .iter()
+      .map(|(k, v)| QueryCacheEntry {
+        key: k.clone(),
+        size_estimate: v.size_estimate(),
+        hot: hot_items
+          .peek(&(k.namespace_id.clone(), k.query_script_id.clone()))
+          .map(|x| Arc::ptr_eq(&x.exec_ctx, v))
+          .unwrap_or(false),
+      })
+      .collect();
+    QueryCacheStats {
+      entries,
+      hot_hits: self.hot_hits.load(Ordering::Relaxed),
+      hits: self.hits.load(Ordering::Relaxed),
+      misses: self.misses.load(Ordering::Relaxed),
+    }
+  }
+
+  /// Removes the compiled query scripts of a namespace, or only those of `query_script_id` if
+  /// provided. Returns the number of entries removed, not counting hot items.
+  ///
+  /// Only the cache of this server is affected. Other servers pick up updated query scripts
+  /// once their hot items expire.
+  pub async fn invalidate(&self, namespace_id: &str, query_script_id: Option<&str>) -> usize {
+    let matches =
+      |ns: &str, qs: &str| ns == namespace_id && query_script_id.map(|x| x == qs).unwrap_or(true);
+
+    let mut items = self.items.lock().await;
+    let keys = items
+      .iter()
+      .map(|(k, _)| k)
+      .filter(|k| matches(&k.namespace_id, &k.query_script_id))
+      .cloned()
+      .collect::<Vec<_>>();
+    for k in &keys {
+      items.pop(k);
+    }
+    drop(items);
+
+    let mut hot_items = self.hot_items.lock().await;
+    let hot_keys = hot_items
+      .iter()
+      .map(|(k, _)| k)
+      .filter(|(ns, qs)| matches(ns, qs))
+      .cloned()
+      .collect::<Vec<_>>();
+    for k in &hot_keys {
+      hot_items.pop(k);
+    }
+
+    keys.len()
+  }
+
   async fn gc(me: Weak<Self>) {
     let system = System::new_all();
     loop {
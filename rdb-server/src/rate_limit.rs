@@ -0,0 +1,106 @@
+use std::{
+  collections::HashMap,
+  sync::Mutex,
+  time::{Duration, Instant},
+};
+
+use anyhow::Result;
+use thiserror::Error;
+
+use crate::state::get_state;
+
+/// Number of buckets above which idle ones are dropped.
+const MAX_IDLE_BUCKETS: usize = 10000;
+
+#[derive(Error, Debug)]
+pub enum RateLimitError {
+  #[error("rate limit of {0} exceeded, retry after {1:?}")]
+  Exceeded(String, Duration),
+}
+
+impl RateLimitError {
+  pub fn retry_after(&self) -> Duration {
+    match self {
+      Self::Exceeded(_, x) => *x,
+    }
+  }
+
+  /// Whole seconds to wait, for the `Retry-After` header. At least one.
+  pub fn retry_after_secs(&self) -> u64 {
+    let x = self.retry_after();
+    (x.as_secs() + if x.subsec_nanos() > 0 { 1 } else { 0 }).max(1)
+  }
+}
+
+/// Token buckets, one per key, each refilled at `rps` tokens per second up to `burst` tokens.
+pub struct RateLimiter {
+  rps: f64,
+  burst: f64,
+  buckets: Mutex<HashMap<String, Bucket>>,
+}
+
+struct Bucket {
+  tokens: f64,
+  updated: Instant,
+}
+
+impl RateLimiter {
+  pub fn new(rps: f64, burst: f64) -> Self {
+    Self {
+      rps,
+      burst: burst.max(1.0),
+      buckets: Mutex::new(HashMap::new()),
+    }
+  }
+
+  /// Takes a token from the bucket of `key`. If the bucket is empty, returns how long it takes
+  /// for a token to become available.
+  pub fn acquire(&self, key: &str) -> Result<(), Duration> {
+    let now = Instant::now();
+    let mut buckets = self.buckets.lock().unwrap();
+    if buckets.len() > MAX_IDLE_BUCKETS {
+      let (rps, burst) = (self.rps, self.burst);
+      buckets.retain(|_, x| x.refilled(now, rps) < burst);
+    }
+    let bucket = buckets.entry(key.to_string()).or_insert_with(|| Bucket {
+      tokens: self.burst,
+      updated: now,
+    });
+    bucket.tokens = bucket.refilled(now, self.rps).min(self.burst);
+    bucket.updated = now;
+    if bucket.tokens >= 1.0 {
+      bucket.tokens -= 1.0;
+      Ok(())
+    } else if self.rps > 0.0 {
+      Err(Duration::from_secs_f64((1.0 - bucket.tokens) / self.rps))
+    } else {
+      Err(Duration::from_secs(1))
+    }
+  }
+}
+
+impl Bucket {
+  fn refilled(&self, now: Instant, rps: f64) -> f64 {
+    self.tokens + now.duration_since(self.updated).as_secs_f64() * rps
+  }
+}
+
+/// Checks the rate limit of queries on a namespace, if one is configured.
+pub fn check_namespace_rate_limit(namespace_id: &str) -> Result<()> {
+  if let Some(limiter) = &get_state().namespace_rate_limiter {
+    limiter
+      .acquire(namespace_id)
+      .map_err(|x| RateLimitError::Exceeded(format!("namespace `{}`", namespace_id), x))?;
+  }
+  Ok(())
+}
+
+/// Checks the rate limit of queries made with an API token, if one is configured.
+pub fn check_token_rate_limit(namespace_id: &str, token_id: &str) -> Result<()> {
+  if let Some(limiter) = &get_state().token_rate_limiter {
+    limiter
+      .acquire(&format!("{}/{}", namespace_id, token_id))
+      .map_err(|x| RateLimitError::Exceeded(format!("API token `{}`", token_id), x))?;
+  }
+  Ok(())
+}
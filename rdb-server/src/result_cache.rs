@@ -0,0 +1,150 @@
+use std::{
+  collections::HashMap,
+  sync::Mutex as SyncMutex,
+  time::{Duration, Instant},
+};
+
+use lru::LruCache;
+use rdb_analyzer::data::treewalker::{
+  bytecode::{TwCacheDirective, TwCacheKey},
+  serialize::{SerializedVmValue, VmValueEncodeConfig},
+};
+use tokio::sync::Mutex;
+
+use crate::exec::Caller;
+
+/// Caches serialized outputs of read-only graphs that declare `@cache(...)`.
+///
+/// Every namespace has a generation number. Entries record the generation they were computed in
+/// and are dropped once it is bumped, i.e. when data in the namespace may have been changed by
+/// this server, or when its query scripts or deployments are changed. Writes made through other
+/// server instances are only observed after the TTL expires.
+pub struct ResultCache {
+  items: Mutex<LruCache<ResultCacheKey, ResultCacheEntry>>,
+  generations: SyncMutex<HashMap<String, u64>>,
+}
+
+struct ResultCacheEntry {
+  generation: u64,
+  expiry: Instant,
+  output: SerializedVmValue,
+}
+
+#[derive(Clone, Debug, Eq, PartialEq, Hash)]
+pub struct ResultCacheKey {
+  /// Namespace id.
+  pub namespace_id: String,
+
+  /// User-provided query script id.
+  pub query_script_id: String,
+
+  /// Name of the exported graph.
+  pub graph_name: String,
+
+  /// JSON-encoded params, or `None` for graphs cached with `key = global`.
+  pub params: Option<String>,
+
+  /// The caller the output is run and serialized for, as graphs may read its token through `env`.
+  pub caller: Caller,
+
+  /// (enable_bytes, enable_int64, enable_double)
+  pub encoding: (bool, bool, bool),
+}
+
+impl ResultCacheKey {
+  pub fn new(
+    namespace_id: &str,
+    query_script_id: &str,
+    graph_name: &str,
+    directive: &TwCacheDirective,
+    params: &[SerializedVmValue],
+    caller: &Caller,
+    serialization_config: &VmValueEncodeConfig,
+  ) -> Self {
+    Self {
+      namespace_id: namespace_id.to_string(),
+      query_script_id: query_script_id.to_string(),
+      graph_name: graph_name.to_string(),
+      params: match directive.key {
+        TwCacheKey::Params => {
+          Some(serde_json::to_string(params).expect("cannot serialize graph params"))
+        }
+        TwCacheKey::Global => None,
+      },
+      caller: caller.clone(),
+      encoding: (
+        serialization_config.enable_bytes,
+        serialization_config.enable_int64,
+        serialization_config.enable_double,
+      ),
+    }
+  }
+}
+
+impl ResultCache {
+  pub fn new(capacity: usize) -> Self {
+    Self {
+      items: Mutex::new(LruCache::new(capacity)),
+      generations: SyncMutex::new(HashMap::new()),
+    }
+  }
+
+  /// The current generation of a namespace. Take this before running the graph, and pass it
+  /// to `put` afterwards.
+  pub fn generation(&self, namespace_id: &str) -> u64 {
+    self
+      .generations
+      .lock()
+      .unwrap()
+      .get(namespace_id)
+      .copied()
+      .unwrap_or(0)
+  }
+
+  /// Invalidates all cached outputs of a namespace.
+  pub fn bump_generation(&self, namespace_id: &str) {
+    *self
+      .generations
+      .lock()
+      .unwrap()
+      .entry(namespace_id.to_string())
+      .or_insert(0) += 1;
+  }
+
+  pub async fn get(&self, key: &ResultCacheKey) -> Option<SerializedVmValue> {
+    let generation = self.generation(&key.namespace_id);
+    let now = Instant::now();
+    let mut items = self.items.lock().await;
+    let entry = items.get(key).map(|x| {
+      (
+        x.generation == generation && x.expiry > now,
+        x.output.clone(),
+      )
+    });
+    match entry {
+      Some((true, x)) => Some(x),
+      Some((false, _)) => {
+        items.pop(key);
+        None
+      }
+      None => None,
+    }
+  }
+
+  pub async fn put(
+    &self,
+    key: ResultCacheKey,
+    generation: u64,
+    directive: &TwCacheDirective,
+    output: SerializedVmValue,
+  ) {
+    self.items.lock().await.put(
+      key,
+      ResultCacheEntry {
+        generation,
+        expiry: Instant::now() + Duration::from_millis(directive.ttl_ms),
+        output,
+      },
+    );
+  }
+}
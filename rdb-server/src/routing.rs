@@ -0,0 +1,272 @@
+//! Routing of read queries between the deployments of a namespace.
+//!
+//! A namespace may mark one of its deployments as primary, and route a percentage of the calls to
+//! read-only graphs of query scripts associated with the primary to a candidate deployment. The
+//! script is compiled against the schema of the candidate, so schema and storage plan changes can
+//! be validated against production traffic before query scripts are switched over:
+//!
+//! - In canary mode, a routed query runs on the candidate instead of the primary, and returns the
+//!   output of the candidate.
+//! - In shadow mode, a routed query runs on the primary as usual, and again on the candidate in the
+//!   background. The outputs are compared and counted in `rdb_shadow_queries_total`.
+//!
+//! If the script cannot be loaded on the candidate, e.g. because it does not typecheck against the
+//! new schema or the candidate was deleted, or the graph writes on the candidate, the query stays
+//! on the primary and is counted as a fallback in `rdb_routed_queries_total`. Graphs that write,
+//! traced queries and queries in explicit transactions are never routed.
+
+use std::{
+  collections::HashMap,
+  sync::Arc,
+  time::{Duration, Instant},
+};
+
+use anyhow::Result;
+use rand::Rng;
+use rdb_analyzer::data::treewalker::{
+  limits::ExecLimits,
+  serialize::{SerializedVmValue, VmValueEncodeConfig},
+};
+use thiserror::Error;
+use tokio::sync::Mutex;
+
+use crate::{
+  exec::{Caller, OutputAudience, PageRequest},
+  exec_core::ExecContext,
+  httpapi::load_exec_ctx_on_deployment,
+  metering::open_query_store,
+  metrics::{ROUTED_QUERIES, SHADOW_QUERIES},
+  sysquery::{get_deployment_routing, DeploymentRouting},
+};
+
+/// How long a server keeps using the routing of a namespace before loading it again. Routing
+/// changes made through another server take up to this long to apply.
+const ROUTING_TTL: Duration = Duration::from_secs(3);
+
+#[derive(Error, Debug)]
+pub enum RoutingError {
+  #[error("deployment not found")]
+  DeploymentNotFound,
+
+  #[error("candidate traffic percentage must be at most 100, got {0}")]
+  InvalidTrafficPercent(u32),
+
+  #[error("a candidate deployment requires a primary deployment")]
+  CandidateWithoutPrimary,
+
+  #[error("the candidate deployment is the primary deployment")]
+  CandidateIsPrimary,
+
+  #[error("graph `{0}` is not read-only on the candidate deployment")]
+  GraphNotReadOnly(String),
+}
+
+#[derive(Copy, Clone, Debug, Default, Eq, PartialEq)]
+pub enum CandidateMode {
+  /// Routed queries run on both deployments, and return the output of the primary.
+  #[default]
+  Shadow,
+
+  /// Routed queries run on the candidate only.
+  Canary,
+}
+
+impl CandidateMode {
+  pub fn as_str(&self) -> &'static str {
+    match self {
+      Self::Shadow => "shadow",
+      Self::Canary => "canary",
+    }
+  }
+
+  /// Unknown modes, including the empty one of namespaces without routing, are taken as shadow,
+  /// which never changes the output of a query.
+  pub fn parse(x: &str) -> Self {
+    match x {
+      "canary" => Self::Canary,
+      _ => Self::Shadow,
+    }
+  }
+}
+
+impl DeploymentRouting {
+  pub fn validate(&self) -> Result<()> {
+    if self.candidate_traffic_percent > 100 {
+      return Err(RoutingError::InvalidTrafficPercent(self.candidate_traffic_percent).into());
+    }
+    if !self.candidate_deployment.is_empty() {
+      if self.primary_deployment.is_empty() {
+        return Err(RoutingError::CandidateWithoutPrimary.into());
+      }
+      if self.candidate_deployment == self.primary_deployment {
+        return Err(RoutingError::CandidateIsPrimary.into());
+      }
+    }
+    Ok(())
+  }
+}
+
+/// Caches the deployment routing of namespaces.
+pub struct DeploymentRouter {
+  entries: Mutex<HashMap<String, RoutingEntry>>,
+}
+
+struct RoutingEntry {
+  routing: Arc<DeploymentRouting>,
+  load_time: Instant,
+}
+
+/// Where a query runs.
+pub enum Route {
+  Primary,
+  Canary(Arc<ExecContext>),
+  Shadow(Arc<ExecContext>),
+}
+
+impl DeploymentRouter {
+  pub fn new() -> Self {
+    Self {
+      entries: Mutex::new(HashMap::new()),
+    }
+  }
+
+  async fn get(&self, namespace_id: &str) -> Result<Arc<DeploymentRouting>> {
+    if let Some(x) = self.entries.lock().await.get(namespace_id) {
+      if x.load_time.elapsed() < ROUTING_TTL {
+        return Ok(x.routing.clone());
+      }
+    }
+    let routing = Arc::new(get_deployment_routing(namespace_id).await?);
+    self.entries.lock().await.insert(
+      namespace_id.to_string(),
+      RoutingEntry {
+        routing: routing.clone(),
+        load_time: Instant::now(),
+      },
+    );
+    Ok(routing)
+  }
+
+  /// Drops the cached routing of a namespace, after it was changed through this server.
+  pub async fn invalidate(&self, namespace_id: &str) {
+    self.entries.lock().await.remove(namespace_id);
+  }
+
+  /// Picks where a call to the read-only graph `graph_name` of a query script runs. `exec_ctx` is
+  /// the script loaded on the deployment it is associated with.
+  pub async fn route(
+    &self,
+    namespace_id: &str,
+    query_script_id: &str,
+    graph_name: &str,
+    exec_ctx: &ExecContext,
+  ) -> Result<Route> {
+    let routing = self.get(namespace_id).await?;
+    if routing.candidate_deployment.is_empty()
+      || exec_ctx.env().deployment_id != routing.primary_deployment
+      || rand::thread_rng().gen_range(0..100) >= routing.candidate_traffic_percent
+    {
+      return Ok(Route::Primary);
+    }
+
+    let mode = routing.candidate_mode.as_str();
+    let candidate = match load_exec_ctx_on_deployment(
+      namespace_id,
+      query_script_id,
+      &routing.candidate_deployment,
+    )
+    .await
+    .and_then(|x| {
+      let graph_index = x.vm().lookup_exported_graph_by_name(graph_name)?;
+      if !x.vm().is_graph_read_only(graph_index) {
+        return Err(RoutingError::GraphNotReadOnly(graph_name.to_string()).into());
+      }
+      Ok(x)
+    }) {
+      Ok(x) => x,
+      Err(e) => {
+        log::warn!(
+          "Cannot load query script {}/{} on candidate deployment {}: {:?}",
+          namespace_id,
+          query_script_id,
+          routing.candidate_deployment,
+          e
+        );
+        ROUTED_QUERIES
+          .with_label_values(&[namespace_id, mode, "fallback"])
+          .inc();
+        return Ok(Route::Primary);
+      }
+    };
+    ROUTED_QUERIES
+      .with_label_values(&[namespace_id, mode, "routed"])
+      .inc();
+    Ok(match routing.candidate_mode {
+      CandidateMode::Canary => Route::Canary(candidate),
+      CandidateMode::Shadow => Route::Shadow(candidate),
+    })
+  }
+}
+
+/// A query to run again on a candidate deployment in shadow mode.
+pub struct ShadowQuery {
+  pub namespace_id: String,
+  pub candidate: Arc<ExecContext>,
+  pub graph_name: String,
+  pub caller: Caller,
+  pub graph_params: Vec<SerializedVmValue>,
+  pub serialization_config: VmValueEncodeConfig,
+  pub limits: ExecLimits,
+  pub page: PageRequest,
+}
+
+impl ShadowQuery {
+  /// Runs the query in the background and compares its output with `primary_output`.
+  pub fn spawn(self, primary_output: SerializedVmValue) {
+    tokio::spawn(async move {
+      let result = match self.run().await {
+        Ok(x) => {
+          if serde_json::to_value(&x).ok() == serde_json::to_value(&primary_output).ok() {
+            "match"
+          } else {
+            log::warn!(
+              "Shadow query of graph {} on namespace {} returned a different output.",
+              self.graph_name,
+              self.namespace_id
+            );
+            "mismatch"
+          }
+        }
+        Err(e) => {
+          log::warn!(
+            "Shadow query of graph {} on namespace {} failed: {:?}",
+            self.graph_name,
+            self.namespace_id,
+            e
+          );
+          "error"
+        }
+      };
+      SHADOW_QUERIES
+        .with_label_values(&[&self.namespace_id, result])
+        .inc();
+    });
+  }
+
+  async fn run(&self) -> Result<SerializedVmValue> {
+    let kv = open_query_store(&self.namespace_id, self.candidate.vm().schema).await?;
+    self
+      .candidate
+      .run_exported_graph_for(
+        &*kv,
+        &self.graph_name,
+        &self.graph_params,
+        &self.serialization_config,
+        OutputAudience::Caller(&self.caller),
+        None,
+        &self.limits,
+        &self.page,
+      )
+      .await
+  }
+}
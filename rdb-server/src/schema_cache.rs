@@ -0,0 +1,140 @@
+use std::sync::{
+  atomic::{AtomicU64, Ordering},
+  Arc,
+};
+
+use anyhow::Result;
+use bumpalo::Bump;
+use lru::LruCache;
+use rdb_analyzer::{
+  schema::{
+    compile::{compile, CompiledSchema},
+    grammar::parse,
+  },
+  storage_plan::StoragePlan,
+};
+use tokio::sync::Mutex;
+
+use crate::{
+  exec_core::SchemaContext,
+  sysquery::{lookup_deployment, set_deployment_blobs, Deployment, DeploymentBlobs},
+};
+
+/// Caches compiled schemas and deserialized storage plans per deployment.
+///
+/// A deployment is immutable once created, so an entry never has to be refreshed. Instead, every
+/// entry records the generation it was loaded in, and `invalidate` bumps the generation to drop
+/// all existing entries at once (e.g. after deployments or namespaces are deleted).
+pub struct SchemaCache {
+  items: Mutex<LruCache<SchemaCacheKey, SchemaCacheEntry>>,
+  generation: AtomicU64,
+}
+
+struct SchemaCacheEntry {
+  generation: u64,
+  schema_ctx: Arc<SchemaContext>,
+}
+
+#[derive(Clone, Debug, Eq, PartialEq, Hash)]
+pub struct SchemaCacheKey {
+  /// Namespace id.
+  pub namespace_id: String,
+
+  /// Generated deployment id.
+  pub deployment_id: String,
+}
+
+impl SchemaCache {
+  pub fn new(capacity: usize) -> Self {
+    Self {
+      items: Mutex::new(LruCache::new(capacity)),
+      generation: AtomicU64::new(0),
+    }
+  }
+
+  async fn get(&self, key: &SchemaCacheKey) -> Option<Arc<SchemaContext>> {
+    let generation = self.generation.load(Ordering::SeqCst);
+    let mut items = self.items.lock().await;
+    let entry = items
+      .get(key)
+      .map(|x| (x.generation == generation, x.schema_ctx.clone()));
+    match entry {
+      Some((true, x)) => Some(x),
+      Some((false, _)) => {
+        items.pop(key);
+        None
+      }
+      None => None,
+    }
+  }
+
+  /// Returns the schema context of a deployment, loading it from the system schema on cache miss.
+  pub async fn get_or_load(
+    &self,
+    namespace_id: &str,
+    deployment_id: &str,
+  ) -> Result<Arc<SchemaContext>> {
+    let key = SchemaCacheKey {
+      namespace_id: namespace_id.to_string(),
+      deployment_id: deployment_id.to_string(),
+    };
+    if let Some(x) = self.get(&key).await {
+      return Ok(x);
+    }
+
+    // Take the generation before loading, so that an invalidation racing with us is not lost.
+    let generation = self.generation.load(Ordering::SeqCst);
+    let deployment = lookup_deployment(namespace_id, deployment_id).await?;
+    let schema = load_compiled_schema(namespace_id, &deployment).await?;
+    let plan = StoragePlan::deserialize_compressed(&deployment.plan)?;
+    let schema_ctx = Arc::new(SchemaContext { schema, plan });
+    log::info!("Loaded schema and storage plan for deployment {:?}.", key);
+
+    self.items.lock().await.put(
+      key,
+      SchemaCacheEntry {
+        generation,
+        schema_ctx: schema_ctx.clone(),
+      },
+    );
+    Ok(schema_ctx)
+  }
+
+  /// Invalidates all entries currently in the cache.
+  pub fn invalidate(&self) {
+    self.generation.fetch_add(1, Ordering::SeqCst);
+  }
+}
+
+/// Loads the compiled schema of a deployment from its structured representation.
+///
+/// Deployments created before the structured representation was introduced, or whose stored blobs
+/// are outdated or fail the hash check, are compiled from their schema text instead, and the
+/// result is written back so that later loads can skip compilation.
+pub async fn load_compiled_schema(
+  namespace_id: &str,
+  deployment: &Deployment,
+) -> Result<CompiledSchema> {
+  if let Some(blobs) = &deployment.blobs {
+    if blobs.is_valid_for(&deployment.plan) {
+      return CompiledSchema::deserialize_compressed(&blobs.compiled_schema);
+    }
+  }
+
+  let schema = compile(&parse(&Bump::new(), &deployment.schema)?)?;
+  let blobs = DeploymentBlobs::encode(&schema, &deployment.plan)?;
+  match set_deployment_blobs(namespace_id, &deployment.id, &blobs).await {
+    Ok(_) => log::info!(
+      "Migrated deployment {}/{} to structured storage.",
+      namespace_id,
+      deployment.id
+    ),
+    Err(e) => log::warn!(
+      "Failed to migrate deployment {}/{} to structured storage: {:?}",
+      namespace_id,
+      deployment.id,
+      e
+    ),
+  }
+  Ok(schema)
+}
@@ -1,34 +1,100 @@
-use std::convert::TryFrom;
-use std::sync::Arc;
+use std::{convert::TryFrom, pin::Pin, sync::Arc, time::Duration};
 
 use async_trait::async_trait;
 use bumpalo::Bump;
+use futures::Stream;
 use maplit::btreemap;
-use rand::RngCore;
+use rdb_analyzer::data::kv::KvError;
+use rdb_analyzer::data::migration_view::build_migration_view;
+use rdb_analyzer::data::mount::MountError;
+use rdb_analyzer::data::treewalker::asm::codegen::compile_twscript;
+use rdb_analyzer::data::treewalker::cursor::CursorError;
+use rdb_analyzer::data::treewalker::dfvis::visualize_df;
+use rdb_analyzer::data::treewalker::exec::{BulkDeleteOutcome, ExecEnv, ExecError, FsckIssueKind};
+use rdb_analyzer::data::treewalker::limits;
 use rdb_analyzer::data::treewalker::serialize::{
   SerializedVmValue, TaggedVmValue, VmValueEncodeConfig,
 };
-use rdb_analyzer::schema::compile::compile;
+use rdb_analyzer::data::treewalker::typeck::GlobalTyckContext;
+use rdb_analyzer::data::treewalker::vm::TwVm;
+use rdb_analyzer::schema::compat;
+use rdb_analyzer::schema::compile::{compile, CompiledSchema, FieldAnnotation, FieldType};
 use rdb_analyzer::schema::grammar::parse;
+use rdb_analyzer::schema::lint::lint;
+use rdb_analyzer::storage_plan::diff::{diff_plans, StoragePlanDiff};
 use rdb_analyzer::storage_plan::planner::generate_plan_for_schema;
 use rdb_analyzer::storage_plan::{StorageKey, StoragePlan};
 use rdb_control_server::RdbControl;
 use rdb_proto::proto::*;
-use rdb_proto::tonic::{Request, Response, Status};
-use uuid::Uuid;
+use rdb_proto::tonic::{metadata::MetadataValue, Request, Response, Status};
 
+use crate::api_token::{self, authorize_api_token, graph_permissions_from_grants, ApiTokenError};
+use crate::auth::{request_scope, AuthError};
+use crate::changelog::{tail_changelog, DEFAULT_TAIL_LIMIT, MAX_TAIL_LIMIT};
+use crate::control_events::WatchFilter;
+use crate::exec::{Caller, ExecError as ServerExecError, OutputAudience, PageRequest};
 use crate::exec_core::{ExecContext, SchemaContext};
+use crate::explorer::{generate_token, token_id, validate_allowed_graphs};
+use crate::httpapi::{do_invoke_query, load_exec_ctx};
+use crate::id_gen::IdStrategy;
+use crate::metering::{open_namespace_store, open_query_store, MeteringError};
+use crate::mount::{check_mounts, load_query_script, with_mounts, NamespaceMountError};
+use crate::rate_limit::{check_namespace_rate_limit, RateLimitError};
+use crate::routing::{self, RoutingError};
 use crate::state::get_state;
-use crate::sysquery::{lookup_deployment, lookup_query_script, ns_to_kv_prefix_with_appended_zero};
+use crate::sysquery::{
+  self, add_namespace, delete_query_script, get_deployment_routing, get_namespace_id_strategy,
+  latest_deployment_id, list_query_scripts_for_deployment, list_tokens, lookup_query_script,
+  ns_to_kv_prefix_with_appended_zero, remove_namespace_from_tokens, set_deployment_routing,
+  set_namespace_id_strategy, set_namespace_quota, ApiToken, DeploymentBlobs, ExplorerToken,
+  SysQueryError,
+};
+use crate::txn_manager::TxnManagerError;
 use crate::util::current_millis;
 use thiserror::Error;
+use tokio::sync::mpsc;
 
 #[derive(Error, Debug)]
 pub enum ServerError {
   #[error("invalid storage plan")]
   InvalidStoragePlan,
+
+  #[error("too many keys: {0} > {1}")]
+  TooManyKeys(usize, usize),
+
+  #[error("too many rows: {0} > {1}")]
+  TooManyRows(usize, usize),
+
+  #[error("a migration script requires the deployment to migrate from")]
+  MigrationScriptWithoutSource,
+
+  #[error("invalid graph permission: {0}")]
+  InvalidGraphPermission(i32),
+
+  #[error("data-lossy schema changes: {}", .0.join(", "))]
+  LossySchemaChanges(Vec<String>),
+
+  #[error("namespace `{0}` has no deployment")]
+  NoDeployment(String),
+
+  #[error("ad-hoc queries are disabled on this server")]
+  AdhocQueriesDisabled,
+
+  #[error("invalid candidate mode: {0}")]
+  InvalidCandidateMode(i32),
+
+  #[error("repairs need the latest deployment of the namespace, `{0}`")]
+  RepairWithOldDeployment(String),
 }
 
+const DEFAULT_BULK_DELETE_CHUNK_SIZE: usize = 100;
+const MAX_BULK_DELETE_KEYS: usize = 100000;
+const DEFAULT_EXPORT_LIMIT: usize = 100;
+const MAX_EXPORT_LIMIT: usize = 10000;
+const DEFAULT_IMPORT_CHUNK_SIZE: usize = 100;
+const MAX_IMPORT_ROWS: usize = 100000;
+const DEFAULT_FSCK_BATCH_SIZE: usize = 100;
+
 pub struct ControlServer;
 
 #[async_trait]
@@ -38,37 +104,18 @@ impl RdbControl for ControlServer {
     request: Request<CreateNamespaceRequest>,
   ) -> Result<Response<CreateNamespaceReply>, Status> {
     let r = request.get_ref();
-    let st = get_state();
-
-    let mut kv_prefix: [u8; 16] = [0u8; 16];
-    rand::thread_rng().fill_bytes(&mut kv_prefix);
-
-    let res = st
-      .system_schema
-      .exec_ctx
-      .run_exported_graph(
-        &*st.system_store,
-        "add_namespace",
-        &[
-          SerializedVmValue::Null(None),
-          SerializedVmValue::String(r.id.clone()),
-          SerializedVmValue::String(base64::encode(&kv_prefix)),
-          SerializedVmValue::String(format!("{}", current_millis())),
-        ],
-        &Default::default(),
-      )
-      .await
-      .translate_err()?;
-    res.check_nonnull().translate_err()?;
-    let ok = res.try_unwrap_bool().translate_err()?;
+    authorize_root(&request)?;
+    let ok = add_namespace(&r.id).await.translate_err()?;
     Ok(Response::new(CreateNamespaceReply { created: ok }))
   }
 
   async fn list_namespace(
     &self,
-    _request: Request<ListNamespaceRequest>,
+    request: Request<ListNamespaceRequest>,
   ) -> Result<Response<ListNamespaceReply>, Status> {
+    let scope = request_scope(&request).translate_err()?;
     let st = get_state();
+    let next_event_seq = st.control_events.next_seq().await.translate_err()?;
     let res = st
       .system_schema
       .exec_ctx
@@ -90,6 +137,9 @@ impl RdbControl for ControlServer {
     for x in res {
       let m = x.try_unwrap_map(&["id", "create_time"]).translate_err()?;
       let id = m.get("id").unwrap().try_unwrap_string().translate_err()?;
+      if !scope.allows_namespace(id) {
+        continue;
+      }
       let create_time: i64 = m
         .get("create_time")
         .unwrap()
@@ -100,7 +150,10 @@ impl RdbControl for ControlServer {
         create_time,
       });
     }
-    Ok(Response::new(ListNamespaceReply { namespaces }))
+    Ok(Response::new(ListNamespaceReply {
+      namespaces,
+      next_event_seq,
+    }))
   }
 
   async fn delete_namespace(
@@ -108,9 +161,14 @@ impl RdbControl for ControlServer {
     request: Request<DeleteNamespaceRequest>,
   ) -> Result<Response<DeleteNamespaceReply>, Status> {
     let r = request.get_ref();
+    authorize_root(&request)?;
     let st = get_state();
 
-    // Delete all data in this namespace
+    // Revoke access through tokens first, see `TokenRegistry`.
+    remove_namespace_from_tokens(&r.id).await.translate_err()?;
+    st.token_registry.remove_namespace(&r.id);
+
+    // Delete all data and the changelog of this namespace
     if let Ok(mut kv_prefix) = ns_to_kv_prefix_with_appended_zero(&r.id).await {
       // Remove trailing zero
       let popped = kv_prefix.pop().unwrap();
@@ -118,7 +176,7 @@ impl RdbControl for ControlServer {
 
       let full_range = (st.data_store_generator)(&kv_prefix);
       let txn = full_range.begin_transaction().await.translate_err()?;
-      txn.delete_range(&[0x00], &[0x01]).await.translate_err()?;
+      txn.delete_range(&[0x00], &[0x02]).await.translate_err()?;
       txn.commit().await.translate_err()?;
     }
 
@@ -138,6 +196,13 @@ impl RdbControl for ControlServer {
       .translate_err()?;
     res.check_nonnull().translate_err()?;
     let ok = res.try_unwrap_bool().translate_err()?;
+    if ok {
+      st.control_events.notify();
+    }
+    st.schema_cache.invalidate();
+    st.result_cache.bump_generation(&r.id);
+    st.query_cache.invalidate(&r.id, None).await;
+    st.usage_meter.forget(&r.id).await;
     Ok(Response::new(DeleteNamespaceReply { deleted: ok }))
   }
 
@@ -146,12 +211,40 @@ impl RdbControl for ControlServer {
     request: Request<CreateDeploymentRequest>,
   ) -> Result<Response<CreateDeploymentReply>, Status> {
     let r = request.get_ref();
+    authorize(&request, &r.namespace_id)?;
     let st = get_state();
 
-    let id = Uuid::new_v4().to_string();
+    let id = st.id_generator.generate(
+      get_namespace_id_strategy(&r.namespace_id)
+        .await
+        .translate_err()?,
+    );
     let now = current_millis();
 
     let new_schema = compile(&parse(&Bump::new(), &r.schema).translate_err()?).translate_err()?;
+    let warnings = lint(&new_schema)
+      .into_iter()
+      .map(|x| x.to_string())
+      .collect::<Vec<_>>();
+    let old_schema_ctx = if r.migrate_from.is_empty() {
+      None
+    } else {
+      Some(
+        st.schema_cache
+          .get_or_load(&r.namespace_id, &r.migrate_from)
+          .await
+          .translate_err()?,
+      )
+    };
+    if r.dry_run {
+      let report = report_migration(r, &new_schema, old_schema_ctx.as_deref()).translate_err()?;
+      return Ok(Response::new(CreateDeploymentReply {
+        deployment_id: None,
+        report: Some(report),
+        warnings,
+      }));
+    }
+
     let new_plan: StoragePlan<String> = serde_yaml::from_str(&r.plan).translate_err()?;
     let new_plan = StoragePlan::<StorageKey>::try_from(&new_plan).translate_err()?;
 
@@ -164,6 +257,52 @@ impl RdbControl for ControlServer {
       Err(ServerError::InvalidStoragePlan).translate_err()?;
     }
 
+    if let Some(old_schema_ctx) = &old_schema_ctx {
+      if st.reject_lossy_deployments && !r.allow_lossy {
+        let lossy_changes = lossy_changes(&old_schema_ctx.schema, &new_schema);
+        if !lossy_changes.is_empty() {
+          Err(ServerError::LossySchemaChanges(lossy_changes)).translate_err()?;
+        }
+      }
+    }
+
+    if !r.migration_script.is_empty() {
+      let exec_ctx = load_migration_script(
+        &r.migration_script,
+        old_schema_ctx.as_deref(),
+        &new_schema,
+        &generated_plan,
+      )
+      .translate_err()?;
+      let kv = open_namespace_store(&r.namespace_id)
+        .await
+        .translate_err()?;
+      let res = exec_ctx
+        .run_exported_graph(
+          &*kv,
+          "migrate",
+          &[SerializedVmValue::Null(None)],
+          &Default::default(),
+        )
+        .await;
+      st.result_cache.bump_generation(&r.namespace_id);
+      res.translate_err()?;
+    }
+
+    let serialized_plan = generated_plan.serialize_compressed().translate_err()?;
+    let mut deployment = btreemap! {
+      "id".to_string() => SerializedVmValue::String(id.clone()),
+      "description".to_string() => SerializedVmValue::String(r.description.clone()),
+      "schema".to_string() => SerializedVmValue::String(r.schema.clone()),
+      "plan".to_string() => SerializedVmValue::String(base64::encode(&serialized_plan)),
+      "create_time".to_string() => SerializedVmValue::String(format!("{}", now)),
+    };
+    deployment.extend(
+      DeploymentBlobs::encode(&new_schema, &serialized_plan)
+        .translate_err()?
+        .to_serialized_fields(),
+    );
+
     // And finally, update our system schema.
     let res = st
       .system_schema
@@ -174,13 +313,7 @@ impl RdbControl for ControlServer {
         &[
           SerializedVmValue::Null(None),
           SerializedVmValue::String(r.namespace_id.clone()),
-          SerializedVmValue::Tagged(TaggedVmValue::M(btreemap! {
-            "id".to_string() => SerializedVmValue::String(id.clone()),
-            "description".to_string() => SerializedVmValue::String(r.description.clone()),
-            "schema".to_string() => SerializedVmValue::String(r.schema.clone()),
-            "plan".to_string() => SerializedVmValue::String(base64::encode(&generated_plan.serialize_compressed().translate_err()?)),
-            "create_time".to_string() => SerializedVmValue::String(format!("{}", now)),
-          })),
+          SerializedVmValue::Tagged(TaggedVmValue::M(deployment)),
         ],
         &Default::default(),
       )
@@ -188,8 +321,13 @@ impl RdbControl for ControlServer {
       .translate_err()?;
     res.check_nonnull().translate_err()?;
     let ok = res.try_unwrap_bool().translate_err()?;
+    if ok {
+      st.control_events.notify();
+    }
     Ok(Response::new(CreateDeploymentReply {
       deployment_id: ok.then(|| DeploymentId { id }),
+      report: None,
+      warnings,
     }))
   }
 
@@ -198,6 +336,7 @@ impl RdbControl for ControlServer {
     request: Request<GetDeploymentRequest>,
   ) -> Result<Response<GetDeploymentReply>, Status> {
     let r = request.get_ref();
+    authorize(&request, &r.namespace_id)?;
     let st = get_state();
     let res = st
       .system_schema
@@ -272,7 +411,9 @@ impl RdbControl for ControlServer {
     request: Request<ListDeploymentRequest>,
   ) -> Result<Response<ListDeploymentReply>, Status> {
     let r = request.get_ref();
+    authorize(&request, &r.namespace_id)?;
     let st = get_state();
+    let next_event_seq = st.control_events.next_seq().await.translate_err()?;
     let res = st
       .system_schema
       .exec_ctx
@@ -315,7 +456,10 @@ impl RdbControl for ControlServer {
         description: description.clone(),
       });
     }
-    Ok(Response::new(ListDeploymentReply { deployments }))
+    Ok(Response::new(ListDeploymentReply {
+      deployments,
+      next_event_seq,
+    }))
   }
 
   async fn delete_deployment(
@@ -323,7 +467,27 @@ impl RdbControl for ControlServer {
     request: Request<DeleteDeploymentRequest>,
   ) -> Result<Response<DeleteDeploymentReply>, Status> {
     let r = request.get_ref();
+    authorize(&request, &r.namespace_id)?;
     let st = get_state();
+
+    // Scripts associated after this check are left without a deployment.
+    let query_scripts = list_query_scripts_for_deployment(&r.namespace_id, &r.id)
+      .await
+      .translate_err()?;
+    if !query_scripts.is_empty() {
+      if !r.force {
+        return Ok(Response::new(DeleteDeploymentReply {
+          deleted: false,
+          query_scripts,
+        }));
+      }
+      for id in &query_scripts {
+        delete_query_script(&r.namespace_id, id)
+          .await
+          .translate_err()?;
+      }
+    }
+
     let res = st
       .system_schema
       .exec_ctx
@@ -341,7 +505,15 @@ impl RdbControl for ControlServer {
       .translate_err()?;
     res.check_nonnull().translate_err()?;
     let deleted = res.try_unwrap_bool().translate_err()?;
-    Ok(Response::new(DeleteDeploymentReply { deleted }))
+    if deleted {
+      st.control_events.notify();
+    }
+    st.schema_cache.invalidate();
+    st.result_cache.bump_generation(&r.namespace_id);
+    Ok(Response::new(DeleteDeploymentReply {
+      deleted,
+      query_scripts,
+    }))
   }
 
   async fn create_query_script(
@@ -349,17 +521,21 @@ impl RdbControl for ControlServer {
     request: Request<CreateQueryScriptRequest>,
   ) -> Result<Response<CreateQueryScriptReply>, Status> {
     let r = request.get_ref();
+    authorize(&request, &r.namespace_id)?;
     let st = get_state();
 
-    let depl = lookup_deployment(&r.namespace_id, &r.associated_deployment)
+    // Validation
+    let schema_ctx = st
+      .schema_cache
+      .get_or_load(&r.namespace_id, &r.associated_deployment)
       .await
       .translate_err()?;
-
-    // Validation
-    let schema = compile(&parse(&Bump::new(), &depl.schema).translate_err()?).translate_err()?;
-    let plan = StoragePlan::deserialize_compressed(&depl.plan).translate_err()?;
-    let schema_ctx = Arc::new(SchemaContext { schema, plan });
-    ExecContext::load(schema_ctx, &r.script).translate_err()?;
+    let exec_ctx = load_query_script(schema_ctx, &r.script)
+      .await
+      .translate_err()?;
+    request_scope(&request)
+      .and_then(|x| check_mounts(&x, &exec_ctx))
+      .translate_err()?;
 
     let res = st
       .system_schema
@@ -383,6 +559,12 @@ impl RdbControl for ControlServer {
       .translate_err()?;
     res.check_nonnull().translate_err()?;
     let created = res.try_unwrap_bool().translate_err()?;
+    st.result_cache.bump_generation(&r.namespace_id);
+
+    // Don't keep serving the old script from the hot items.
+    st.query_cache
+      .invalidate(&r.namespace_id, Some(&r.id))
+      .await;
     Ok(Response::new(CreateQueryScriptReply { created }))
   }
 
@@ -391,24 +573,15 @@ impl RdbControl for ControlServer {
     request: Request<DeleteQueryScriptRequest>,
   ) -> Result<Response<DeleteQueryScriptReply>, Status> {
     let r = request.get_ref();
+    authorize(&request, &r.namespace_id)?;
     let st = get_state();
-    let res = st
-      .system_schema
-      .exec_ctx
-      .run_exported_graph(
-        &*st.system_store,
-        "delete_query_script",
-        &[
-          SerializedVmValue::Null(None),
-          SerializedVmValue::String(r.namespace_id.clone()),
-          SerializedVmValue::String(r.id.clone()),
-        ],
-        &Default::default(),
-      )
+    let deleted = delete_query_script(&r.namespace_id, &r.id)
       .await
       .translate_err()?;
-    res.check_nonnull().translate_err()?;
-    let deleted = res.try_unwrap_bool().translate_err()?;
+    st.result_cache.bump_generation(&r.namespace_id);
+    st.query_cache
+      .invalidate(&r.namespace_id, Some(&r.id))
+      .await;
     Ok(Response::new(DeleteQueryScriptReply { deleted }))
   }
 
@@ -417,15 +590,41 @@ impl RdbControl for ControlServer {
     request: Request<GetQueryScriptRequest>,
   ) -> Result<Response<GetQueryScriptReply>, Status> {
     let r = request.get_ref();
+    authorize(&request, &r.namespace_id)?;
     let qs = lookup_query_script(&r.namespace_id, &r.query_script_id)
       .await
       .translate_err()?;
+    let exec_ctx = load_exec_ctx(&r.namespace_id, &r.query_script_id)
+      .await
+      .translate_err()?;
+    let vm = exec_ctx.vm();
+    let params = vm
+      .script
+      .params
+      .iter()
+      .map(|x| {
+        let default_json = match x.default {
+          Some(d) => {
+            let v = SerializedVmValue::encode(&vm.consts[d as usize], &Default::default())?;
+            serde_json::to_string(&v)?
+          }
+          None => String::new(),
+        };
+        Ok(QueryScriptParam {
+          name: x.name.clone(),
+          r#type: vm.types[x.ty as usize].to_string(),
+          default_json,
+        })
+      })
+      .collect::<anyhow::Result<Vec<_>>>()
+      .translate_err()?;
     Ok(Response::new(GetQueryScriptReply {
       info: Some(QueryScriptFullInfo {
         id: qs.id,
         associated_deployment: qs.associated_deployment,
         script: qs.script,
         create_time: qs.create_time,
+        params,
       }),
     }))
   }
@@ -435,6 +634,7 @@ impl RdbControl for ControlServer {
     request: Request<ListQueryScriptRequest>,
   ) -> Result<Response<ListQueryScriptReply>, Status> {
     let r = request.get_ref();
+    authorize(&request, &r.namespace_id)?;
     let st = get_state();
     let res = st
       .system_schema
@@ -480,22 +680,1524 @@ impl RdbControl for ControlServer {
     }
     Ok(Response::new(ListQueryScriptReply { query_scripts }))
   }
-}
 
-trait ErrorTranslate {
-  type Output;
-  fn translate_err(self) -> Result<Self::Output, Status>;
-}
+  async fn create_explorer_token(
+    &self,
+    request: Request<CreateExplorerTokenRequest>,
+  ) -> Result<Response<CreateExplorerTokenReply>, Status> {
+    let r = request.get_ref();
+    authorize(&request, &r.namespace_id)?;
+    let st = get_state();
 
-impl<T, E> ErrorTranslate for Result<T, E>
-where
-  anyhow::Error: From<E>,
-{
-  type Output = T;
+    validate_allowed_graphs(&r.allowed_graphs).translate_err()?;
+    let (id, token) = generate_token();
 
-  fn translate_err(self) -> Result<Self::Output, Status> {
-    self.map_err(|x| {
-      let x = anyhow::Error::from(x);
+    let res = st
+      .system_schema
+      .exec_ctx
+      .run_exported_graph(
+        &*st.system_store,
+        "add_explorer_token",
+        &[
+          SerializedVmValue::Null(None),
+          SerializedVmValue::String(r.namespace_id.clone()),
+          SerializedVmValue::Tagged(TaggedVmValue::M(btreemap! {
+            "id".to_string() => SerializedVmValue::String(id.clone()),
+            "description".to_string() => SerializedVmValue::String(r.description.clone()),
+            "allowed_graphs".to_string() => SerializedVmValue::String(serde_json::to_string(&r.allowed_graphs).translate_err()?),
+            "redacted_fields".to_string() => SerializedVmValue::String(serde_json::to_string(&r.redacted_fields).translate_err()?),
+            "create_time".to_string() => SerializedVmValue::String(format!("{}", current_millis())),
+          })),
+        ],
+        &Default::default(),
+      )
+      .await
+      .translate_err()?;
+    res.check_nonnull().translate_err()?;
+    let ok = res.try_unwrap_bool().translate_err()?;
+    Ok(Response::new(CreateExplorerTokenReply {
+      token: ok.then(|| ExplorerTokenSecret { id, token }),
+    }))
+  }
+
+  async fn list_explorer_token(
+    &self,
+    request: Request<ListExplorerTokenRequest>,
+  ) -> Result<Response<ListExplorerTokenReply>, Status> {
+    let r = request.get_ref();
+    authorize(&request, &r.namespace_id)?;
+    let st = get_state();
+    let res = st
+      .system_schema
+      .exec_ctx
+      .run_exported_graph(
+        &*st.system_store,
+        "list_explorer_tokens",
+        &[
+          SerializedVmValue::Null(None),
+          SerializedVmValue::String(r.namespace_id.clone()),
+        ],
+        &VmValueEncodeConfig {
+          enable_bytes: true,
+          enable_double: true,
+          enable_int64: true,
+        },
+      )
+      .await
+      .translate_err()?;
+    res.check_nonnull().translate_err()?;
+    let res = res.try_unwrap_list().translate_err()?;
+    let mut tokens: Vec<ExplorerTokenInfo> = Vec::new();
+    for x in res {
+      let token = ExplorerToken::from_serialized(x).translate_err()?;
+      tokens.push(ExplorerTokenInfo {
+        id: token.id,
+        description: token.description,
+        allowed_graphs: token.allowed_graphs,
+        redacted_fields: token.redacted_fields,
+        create_time: token.create_time,
+      });
+    }
+    Ok(Response::new(ListExplorerTokenReply { tokens }))
+  }
+
+  async fn delete_explorer_token(
+    &self,
+    request: Request<DeleteExplorerTokenRequest>,
+  ) -> Result<Response<DeleteExplorerTokenReply>, Status> {
+    let r = request.get_ref();
+    authorize(&request, &r.namespace_id)?;
+    let st = get_state();
+    let res = st
+      .system_schema
+      .exec_ctx
+      .run_exported_graph(
+        &*st.system_store,
+        "delete_explorer_token",
+        &[
+          SerializedVmValue::Null(None),
+          SerializedVmValue::String(r.namespace_id.clone()),
+          SerializedVmValue::String(r.id.clone()),
+        ],
+        &Default::default(),
+      )
+      .await
+      .translate_err()?;
+    res.check_nonnull().translate_err()?;
+    let deleted = res.try_unwrap_bool().translate_err()?;
+    Ok(Response::new(DeleteExplorerTokenReply { deleted }))
+  }
+
+  async fn create_api_token(
+    &self,
+    request: Request<CreateApiTokenRequest>,
+  ) -> Result<Response<CreateApiTokenReply>, Status> {
+    let r = request.get_ref();
+    authorize(&request, &r.namespace_id)?;
+    let st = get_state();
+
+    let mut grants = vec![];
+    for grant in &r.grants {
+      let permission = match GraphPermission::from_i32(grant.permission) {
+        Some(GraphPermission::ReadOnly) => api_token::GraphPermission::ReadOnly,
+        Some(GraphPermission::Effectful) => api_token::GraphPermission::Effectful,
+        None => return Err(ServerError::InvalidGraphPermission(grant.permission)).translate_err(),
+      };
+      grants.push((&*grant.query_script_id, &*grant.graph_name, permission));
+    }
+    let graph_permissions = graph_permissions_from_grants(grants).translate_err()?;
+    let (id, token) = generate_token();
+
+    let res = st
+      .system_schema
+      .exec_ctx
+      .run_exported_graph(
+        &*st.system_store,
+        "add_api_token",
+        &[
+          SerializedVmValue::Null(None),
+          SerializedVmValue::String(r.namespace_id.clone()),
+          SerializedVmValue::Tagged(TaggedVmValue::M(btreemap! {
+            "id".to_string() => SerializedVmValue::String(id.clone()),
+            "description".to_string() => SerializedVmValue::String(r.description.clone()),
+            "graph_permissions".to_string() => SerializedVmValue::String(serde_json::to_string(&graph_permissions).translate_err()?),
+            "create_time".to_string() => SerializedVmValue::String(format!("{}", current_millis())),
+          })),
+        ],
+        &Default::default(),
+      )
+      .await
+      .translate_err()?;
+    res.check_nonnull().translate_err()?;
+    let ok = res.try_unwrap_bool().translate_err()?;
+    Ok(Response::new(CreateApiTokenReply {
+      token: ok.then(|| ApiTokenSecret { id, token }),
+    }))
+  }
+
+  async fn list_api_token(
+    &self,
+    request: Request<ListApiTokenRequest>,
+  ) -> Result<Response<ListApiTokenReply>, Status> {
+    let r = request.get_ref();
+    authorize(&request, &r.namespace_id)?;
+    let st = get_state();
+    let res = st
+      .system_schema
+      .exec_ctx
+      .run_exported_graph(
+        &*st.system_store,
+        "list_api_tokens",
+        &[
+          SerializedVmValue::Null(None),
+          SerializedVmValue::String(r.namespace_id.clone()),
+        ],
+        &VmValueEncodeConfig {
+          enable_bytes: true,
+          enable_double: true,
+          enable_int64: true,
+        },
+      )
+      .await
+      .translate_err()?;
+    res.check_nonnull().translate_err()?;
+    let res = res.try_unwrap_list().translate_err()?;
+    let mut tokens: Vec<ApiTokenInfo> = Vec::new();
+    for x in res {
+      let token = ApiToken::from_serialized(x).translate_err()?;
+      let grants = token
+        .graph_permissions
+        .iter()
+        .map(|(full_name, permission)| {
+          // Validated on creation.
+          let (query_script_id, graph_name) = full_name.split_once('/').unwrap_or_default();
+          let permission = match permission {
+            api_token::GraphPermission::ReadOnly => GraphPermission::ReadOnly,
+            api_token::GraphPermission::Effectful => GraphPermission::Effectful,
+          };
+          GraphGrant {
+            query_script_id: query_script_id.to_string(),
+            graph_name: graph_name.to_string(),
+            permission: permission as i32,
+          }
+        })
+        .collect();
+      tokens.push(ApiTokenInfo {
+        id: token.id,
+        description: token.description,
+        grants,
+        create_time: token.create_time,
+      });
+    }
+    Ok(Response::new(ListApiTokenReply { tokens }))
+  }
+
+  async fn delete_api_token(
+    &self,
+    request: Request<DeleteApiTokenRequest>,
+  ) -> Result<Response<DeleteApiTokenReply>, Status> {
+    let r = request.get_ref();
+    authorize(&request, &r.namespace_id)?;
+    let st = get_state();
+    let res = st
+      .system_schema
+      .exec_ctx
+      .run_exported_graph(
+        &*st.system_store,
+        "delete_api_token",
+        &[
+          SerializedVmValue::Null(None),
+          SerializedVmValue::String(r.namespace_id.clone()),
+          SerializedVmValue::String(r.id.clone()),
+        ],
+        &Default::default(),
+      )
+      .await
+      .translate_err()?;
+    res.check_nonnull().translate_err()?;
+    let deleted = res.try_unwrap_bool().translate_err()?;
+    Ok(Response::new(DeleteApiTokenReply { deleted }))
+  }
+
+  async fn create_token(
+    &self,
+    request: Request<CreateTokenRequest>,
+  ) -> Result<Response<CreateTokenReply>, Status> {
+    let r = request.get_ref();
+    authorize_root(&request)?;
+    let st = get_state();
+    let (id, token) = generate_token();
+
+    let res = st
+      .system_schema
+      .exec_ctx
+      .run_exported_graph(
+        &*st.system_store,
+        "add_token",
+        &[
+          SerializedVmValue::Null(None),
+          SerializedVmValue::Tagged(TaggedVmValue::M(btreemap! {
+            "id".to_string() => SerializedVmValue::String(id.clone()),
+            "description".to_string() => SerializedVmValue::String(r.description.clone()),
+            "namespaces".to_string() => SerializedVmValue::String(serde_json::to_string(&r.namespaces).translate_err()?),
+            "create_time".to_string() => SerializedVmValue::String(format!("{}", current_millis())),
+            "mounts".to_string() => SerializedVmValue::String(serde_json::to_string(&r.mounts).translate_err()?),
+            "role".to_string() => SerializedVmValue::String(r.role.clone()),
+          })),
+        ],
+        &Default::default(),
+      )
+      .await
+      .translate_err()?;
+    res.check_nonnull().translate_err()?;
+    let ok = res.try_unwrap_bool().translate_err()?;
+    if ok {
+      st.token_registry.insert(
+        id.clone(),
+        r.namespaces.iter().cloned().collect(),
+        r.mounts.iter().cloned().collect(),
+        Some(r.role.clone()).filter(|x| !x.is_empty()),
+      );
+    }
+    Ok(Response::new(CreateTokenReply {
+      token: ok.then(|| TokenSecret { id, token }),
+    }))
+  }
+
+  async fn list_token(
+    &self,
+    request: Request<ListTokenRequest>,
+  ) -> Result<Response<ListTokenReply>, Status> {
+    authorize_root(&request)?;
+    let tokens = list_tokens()
+      .await
+      .translate_err()?
+      .into_iter()
+      .map(|x| TokenInfo {
+        id: x.id,
+        description: x.description,
+        namespaces: x.namespaces,
+        create_time: x.create_time,
+        mounts: x.mounts,
+        role: x.role.unwrap_or_default(),
+      })
+      .collect();
+    Ok(Response::new(ListTokenReply { tokens }))
+  }
+
+  async fn revoke_token(
+    &self,
+    request: Request<RevokeTokenRequest>,
+  ) -> Result<Response<RevokeTokenReply>, Status> {
+    let r = request.get_ref();
+    authorize_root(&request)?;
+    let st = get_state();
+    let res = st
+      .system_schema
+      .exec_ctx
+      .run_exported_graph(
+        &*st.system_store,
+        "delete_token",
+        &[
+          SerializedVmValue::Null(None),
+          SerializedVmValue::String(r.id.clone()),
+        ],
+        &Default::default(),
+      )
+      .await
+      .translate_err()?;
+    res.check_nonnull().translate_err()?;
+    let revoked = res.try_unwrap_bool().translate_err()?;
+    st.token_registry.remove(&r.id);
+    Ok(Response::new(RevokeTokenReply { revoked }))
+  }
+
+  async fn get_query_cache_stats(
+    &self,
+    request: Request<GetQueryCacheStatsRequest>,
+  ) -> Result<Response<GetQueryCacheStatsReply>, Status> {
+    authorize_root(&request)?;
+    let stats = get_state().query_cache.stats().await;
+    let memory_estimate = stats.entries.iter().map(|x| x.size_estimate as u64).sum();
+    Ok(Response::new(GetQueryCacheStatsReply {
+      entries: stats
+        .entries
+        .into_iter()
+        .map(|x| QueryCacheEntry {
+          namespace_id: x.key.namespace_id,
+          query_script_id: x.key.query_script_id,
+          deployment_id: x.key.deployment_id,
+          query_script_create_time: x.key.query_script_create_time,
+          size_estimate: x.size_estimate as u64,
+          hot: x.hot,
+        })
+        .collect(),
+      hot_hits: stats.hot_hits,
+      hits: stats.hits,
+      misses: stats.misses,
+      memory_estimate,
+    }))
+  }
+
+  async fn invalidate_query_cache(
+    &self,
+    request: Request<InvalidateQueryCacheRequest>,
+  ) -> Result<Response<InvalidateQueryCacheReply>, Status> {
+    let r = request.get_ref();
+    authorize(&request, &r.namespace_id)?;
+    let query_script_id = if r.query_script_id.is_empty() {
+      None
+    } else {
+      Some(r.query_script_id.as_str())
+    };
+    let invalidated = get_state()
+      .query_cache
+      .invalidate(&r.namespace_id, query_script_id)
+      .await;
+    Ok(Response::new(InvalidateQueryCacheReply {
+      invalidated: invalidated as u64,
+    }))
+  }
+
+  async fn check_schema(
+    &self,
+    request: Request<CheckSchemaRequest>,
+  ) -> Result<Response<CheckSchemaReply>, Status> {
+    request_scope(&request).translate_err()?;
+    let r = request.get_ref();
+    let schema = parse(&Bump::new(), &r.schema)
+      .and_then(|x| compile(&x))
+      .map_err(|e| Status::invalid_argument(e.to_string()))?;
+    Ok(Response::new(CheckSchemaReply {
+      warnings: lint(&schema).into_iter().map(|x| x.to_string()).collect(),
+    }))
+  }
+
+  async fn describe_schema(
+    &self,
+    request: Request<DescribeSchemaRequest>,
+  ) -> Result<Response<DescribeSchemaReply>, Status> {
+    let r = request.get_ref();
+    authorize(&request, &r.namespace_id)?;
+    let deployment_id = resolve_deployment_id(&r.namespace_id, &r.deployment_id)
+      .await
+      .translate_err()?;
+    let deployment = get_state()
+      .schema_cache
+      .get_or_load(&r.namespace_id, &deployment_id)
+      .await
+      .translate_err()?;
+    Ok(Response::new(describe_deployment(
+      deployment_id,
+      &deployment.schema,
+      &deployment.plan,
+    )))
+  }
+
+  async fn invoke_graph(
+    &self,
+    request: Request<InvokeGraphRequest>,
+  ) -> Result<Response<InvokeGraphReply>, Status> {
+    let r = request.get_ref();
+    authorize(&request, &r.namespace_id)?;
+    let params: Vec<SerializedVmValue> = if r.params.is_empty() {
+      vec![]
+    } else {
+      serde_json::from_str(&r.params).translate_err()?
+    };
+
+    // API tokens do not carry a role, so all `@acl` protected fields are stripped.
+    let caller = if !r.api_token.is_empty() {
+      authorize_api_token(
+        &r.namespace_id,
+        &r.query_script_id,
+        &r.graph_name,
+        &r.api_token,
+      )
+      .await
+      .translate_err()?;
+      Caller {
+        token_id: token_id(&r.api_token),
+        role: None,
+      }
+    } else {
+      request_scope(&request).translate_err()?.caller()
+    };
+
+    let (output, _) = do_invoke_query(
+      r.namespace_id.clone(),
+      r.query_script_id.clone(),
+      r.graph_name.clone(),
+      caller,
+      params,
+      &Default::default(),
+      &request_limits(r.limits.as_ref()),
+      &PageRequest {
+        after: Some(r.after.clone()).filter(|x| !x.is_empty()),
+        page_size: Some(r.page_size as usize).filter(|x| *x != 0),
+      },
+    )
+    .await
+    .translate_err()?;
+    Ok(Response::new(InvokeGraphReply {
+      output: serde_json::to_string(&output).translate_err()?,
+    }))
+  }
+
+  async fn execute_adhoc_query(
+    &self,
+    request: Request<ExecuteAdhocQueryRequest>,
+  ) -> Result<Response<ExecuteAdhocQueryReply>, Status> {
+    let r = request.get_ref();
+    if !get_state().adhoc_queries_enabled {
+      return Err(ServerError::AdhocQueriesDisabled).translate_err();
+    }
+    authorize(&request, &r.namespace_id)?;
+    let params: Vec<SerializedVmValue> = if r.params.is_empty() {
+      vec![]
+    } else {
+      serde_json::from_str(&r.params).translate_err()?
+    };
+    check_namespace_rate_limit(&r.namespace_id).translate_err()?;
+    let st = get_state();
+    let deployment_id = resolve_deployment_id(&r.namespace_id, &r.deployment_id)
+      .await
+      .translate_err()?;
+    let schema_ctx = st
+      .schema_cache
+      .get_or_load(&r.namespace_id, &deployment_id)
+      .await
+      .translate_err()?;
+    let diagnostics_reply = |e: anyhow::Error| {
+      Ok(Response::new(ExecuteAdhocQueryReply {
+        output: String::new(),
+        deployment_id: deployment_id.clone(),
+        diagnostics: vec![format!("{:#}", e)],
+      }))
+    };
+
+    let script = match compile_twscript(&r.script) {
+      Ok(x) => x,
+      Err(e) => return diagnostics_reply(e),
+    };
+    let scope = request_scope(&request).translate_err()?;
+    for namespace_id in &script.mounts {
+      scope.check_mount(namespace_id).translate_err()?;
+    }
+    let schema_ctx = with_mounts(schema_ctx, &script.mounts)
+      .await
+      .translate_err()?;
+    let typeck_result = TwVm::new(&schema_ctx.schema, &schema_ctx.plan, &script)
+      .and_then(|vm| GlobalTyckContext::new(&vm)?.typeck().map(|_| ()));
+    if let Err(e) = typeck_result {
+      return diagnostics_reply(e);
+    }
+    let exec_ctx = ExecContext::load_compiled(schema_ctx, script, r.script.len())
+      .translate_err()?
+      .with_env(ExecEnv {
+        namespace_id: r.namespace_id.clone(),
+        deployment_id: deployment_id.clone(),
+        query_script_id: String::new(),
+        token_id: String::new(),
+        role: String::new(),
+      });
+    let params = exec_ctx
+      .with_schema_params(&r.graph_name, params)
+      .translate_err()?;
+
+    let graph_index = exec_ctx
+      .vm()
+      .lookup_exported_graph_by_name(&r.graph_name)
+      .translate_err()?;
+    let read_only = exec_ctx.vm().is_graph_read_only(graph_index);
+    let kv = open_query_store(&r.namespace_id, exec_ctx.vm().schema)
+      .await
+      .translate_err()?;
+    let mut changes = if !read_only && st.subscription_hub.has_subscribers(&r.namespace_id) {
+      Some(vec![])
+    } else {
+      None
+    };
+    let output = exec_ctx
+      .run_exported_graph_for(
+        &*kv,
+        &r.graph_name,
+        &params,
+        &Default::default(),
+        OutputAudience::Trusted,
+        changes.as_mut(),
+        &request_limits(r.limits.as_ref()),
+        &Default::default(),
+      )
+      .await;
+
+    // Even a failed graph may have committed some of its writes.
+    if !read_only {
+      st.result_cache.bump_generation(&r.namespace_id);
+    }
+    let output = output.translate_err()?;
+    if let Some(changes) = changes {
+      st.subscription_hub.publish(&r.namespace_id, changes);
+    }
+    Ok(Response::new(ExecuteAdhocQueryReply {
+      output: serde_json::to_string(&output).translate_err()?,
+      deployment_id,
+      diagnostics: vec![],
+    }))
+  }
+
+  async fn trace_query(
+    &self,
+    request: Request<TraceQueryRequest>,
+  ) -> Result<Response<TraceQueryReply>, Status> {
+    let r = request.get_ref();
+    authorize(&request, &r.namespace_id)?;
+    let params: Vec<SerializedVmValue> = if r.params.is_empty() {
+      vec![]
+    } else {
+      serde_json::from_str(&r.params).translate_err()?
+    };
+    check_namespace_rate_limit(&r.namespace_id).translate_err()?;
+    let exec_ctx = load_exec_ctx(&r.namespace_id, &r.query_script_id)
+      .await
+      .translate_err()?;
+
+    let kv = open_query_store(&r.namespace_id, exec_ctx.vm().schema)
+      .await
+      .translate_err()?;
+    let (output, trace) = exec_ctx
+      .trace_exported_graph(
+        &*kv,
+        &r.graph_name,
+        &params,
+        &Default::default(),
+        OutputAudience::Trusted,
+        &get_state().exec_limits,
+      )
+      .await
+      .translate_err()?;
+    Ok(Response::new(TraceQueryReply {
+      output: serde_json::to_string(&output).translate_err()?,
+      trace: serde_json::to_string(&trace).translate_err()?,
+      dataflow: visualize_df(exec_ctx.vm()).translate_err()?,
+      trace_info: Some(TraceInfo {
+        events: trace
+          .events
+          .into_iter()
+          .map(|x| TraceNodeEvent {
+            seq: x.seq,
+            id: x.id as u64,
+            graph: x.graph,
+            node: x.node,
+            invocation: x.invocation,
+            inputs: x.inputs,
+            output: x.output.unwrap_or_default(),
+            output_type: x.output_type.unwrap_or_default(),
+            error: x.error.unwrap_or_default(),
+            start_us: x.start_us,
+            duration_us: x.duration_us,
+            kv_ops: x.kv_ops,
+            bytes_read: x.bytes_read,
+          })
+          .collect(),
+      }),
+    }))
+  }
+
+  async fn bulk_delete(
+    &self,
+    request: Request<BulkDeleteRequest>,
+  ) -> Result<Response<BulkDeleteReply>, Status> {
+    let r = request.get_ref();
+    authorize(&request, &r.namespace_id)?;
+    let st = get_state();
+    let keys: Vec<SerializedVmValue> = serde_json::from_str(&r.keys).translate_err()?;
+    if keys.len() > MAX_BULK_DELETE_KEYS {
+      return Err(ServerError::TooManyKeys(keys.len(), MAX_BULK_DELETE_KEYS)).translate_err();
+    }
+    let chunk_size = match r.chunk_size {
+      0 => DEFAULT_BULK_DELETE_CHUNK_SIZE,
+      x => x as usize,
+    };
+
+    let schema_ctx = st
+      .schema_cache
+      .get_or_load(&r.namespace_id, &r.deployment_id)
+      .await
+      .translate_err()?;
+
+    // No graphs are run, so an empty script is enough.
+    let exec_ctx = ExecContext::load(schema_ctx, "").translate_err()?;
+
+    let kv = open_namespace_store(&r.namespace_id)
+      .await
+      .translate_err()?;
+    let outcomes = exec_ctx
+      .bulk_delete(&*kv, &r.set_path, &keys, chunk_size)
+      .await;
+
+    // Chunks before a failure may have been committed.
+    st.result_cache.bump_generation(&r.namespace_id);
+
+    let outcomes = outcomes
+      .translate_err()?
+      .into_iter()
+      .map(|x| {
+        let (status, error) = match x {
+          BulkDeleteOutcome::Deleted => (bulk_delete_outcome::Status::Deleted, String::new()),
+          BulkDeleteOutcome::NotFound => (bulk_delete_outcome::Status::NotFound, String::new()),
+          BulkDeleteOutcome::Failed(e) => (bulk_delete_outcome::Status::Failed, e),
+        };
+        rdb_proto::proto::BulkDeleteOutcome {
+          status: status as i32,
+          error,
+        }
+      })
+      .collect();
+    Ok(Response::new(BulkDeleteReply { outcomes }))
+  }
+
+  async fn begin_transaction(
+    &self,
+    request: Request<BeginTransactionRequest>,
+  ) -> Result<Response<BeginTransactionReply>, Status> {
+    let r = request.get_ref();
+    authorize(&request, &r.namespace_id)?;
+    let st = get_state();
+    let kv = open_namespace_store(&r.namespace_id)
+      .await
+      .translate_err()?;
+    let txn = kv.begin_transaction().await.translate_err()?;
+    let transaction_id = st
+      .txn_manager
+      .begin(&r.namespace_id, txn)
+      .await
+      .translate_err()?;
+    Ok(Response::new(BeginTransactionReply { transaction_id }))
+  }
+
+  async fn run_in_transaction(
+    &self,
+    request: Request<RunInTransactionRequest>,
+  ) -> Result<Response<RunInTransactionReply>, Status> {
+    let r = request.get_ref();
+    let st = get_state();
+    let params: Vec<SerializedVmValue> = if r.params.is_empty() {
+      vec![]
+    } else {
+      serde_json::from_str(&r.params).translate_err()?
+    };
+    let open_txn = st
+      .txn_manager
+      .get(&r.transaction_id)
+      .await
+      .translate_err()?;
+    authorize(&request, &open_txn.namespace_id)?;
+    let exec_ctx = load_exec_ctx(&open_txn.namespace_id, &r.query_script_id)
+      .await
+      .translate_err()?;
+
+    // The transaction is only on the namespace's own store.
+    if !exec_ctx.vm().script.mounts.is_empty() {
+      return Err(NamespaceMountError::MountInTransaction).translate_err();
+    }
+    let graph_index = exec_ctx
+      .vm()
+      .lookup_exported_graph_by_name(&r.graph_name)
+      .translate_err()?;
+
+    let kv = open_namespace_store(&open_txn.namespace_id)
+      .await
+      .translate_err()?;
+    let mut state = open_txn.lock().await.translate_err()?;
+    if !exec_ctx.vm().is_graph_read_only(graph_index) {
+      state.wrote = true;
+    }
+    let state = &mut *state;
+
+    // Changes are only tracked while someone is subscribed.
+    let changes = if st.subscription_hub.has_subscribers(&open_txn.namespace_id) {
+      Some(&mut state.changes)
+    } else {
+      None
+    };
+    let output = exec_ctx
+      .run_exported_graph_in_txn(
+        &*kv,
+        &**state.txn.as_ref().unwrap(),
+        &r.graph_name,
+        &params,
+        &Default::default(),
+        OutputAudience::Trusted,
+        changes,
+        &request_limits(r.limits.as_ref()),
+      )
+      .await
+      .translate_err()?;
+    Ok(Response::new(RunInTransactionReply {
+      output: serde_json::to_string(&output).translate_err()?,
+    }))
+  }
+
+  async fn commit_transaction(
+    &self,
+    request: Request<CommitTransactionRequest>,
+  ) -> Result<Response<CommitTransactionReply>, Status> {
+    let r = request.get_ref();
+    let st = get_state();
+    authorize_transaction(&request, &r.transaction_id).await?;
+    let open_txn = st
+      .txn_manager
+      .take(&r.transaction_id)
+      .await
+      .translate_err()?;
+    let mut state = open_txn.lock().await.translate_err()?;
+    let res = state.txn.take().unwrap().commit().await;
+
+    // The commit state may be unknown on error.
+    if state.wrote {
+      st.result_cache.bump_generation(&open_txn.namespace_id);
+    }
+    res.translate_err()?;
+    st.subscription_hub
+      .publish(&open_txn.namespace_id, std::mem::take(&mut state.changes));
+    Ok(Response::new(CommitTransactionReply {}))
+  }
+
+  async fn rollback_transaction(
+    &self,
+    request: Request<RollbackTransactionRequest>,
+  ) -> Result<Response<RollbackTransactionReply>, Status> {
+    let r = request.get_ref();
+    let st = get_state();
+    authorize_transaction(&request, &r.transaction_id).await?;
+    let open_txn = st
+      .txn_manager
+      .take(&r.transaction_id)
+      .await
+      .translate_err()?;
+    open_txn.lock().await.translate_err()?.txn.take();
+    Ok(Response::new(RollbackTransactionReply {}))
+  }
+
+  async fn set_namespace_quota(
+    &self,
+    request: Request<SetNamespaceQuotaRequest>,
+  ) -> Result<Response<SetNamespaceQuotaReply>, Status> {
+    let r = request.get_ref();
+    authorize_root(&request)?;
+    let st = get_state();
+    let updated = set_namespace_quota(&r.namespace_id, r.write_quota_bytes)
+      .await
+      .translate_err()?;
+    if updated {
+      st.usage_meter
+        .set_quota(&r.namespace_id, r.write_quota_bytes)
+        .await;
+    }
+    Ok(Response::new(SetNamespaceQuotaReply { updated }))
+  }
+
+  async fn set_namespace_id_strategy(
+    &self,
+    request: Request<SetNamespaceIdStrategyRequest>,
+  ) -> Result<Response<SetNamespaceIdStrategyReply>, Status> {
+    let r = request.get_ref();
+    authorize(&request, &r.namespace_id)?;
+    let strategy = IdStrategy::parse_namespace_strategy(&r.id_strategy)
+      .map_err(|e| Status::invalid_argument(e.to_string()))?;
+    let updated = set_namespace_id_strategy(&r.namespace_id, strategy)
+      .await
+      .translate_err()?;
+    Ok(Response::new(SetNamespaceIdStrategyReply { updated }))
+  }
+
+  async fn get_namespace_usage(
+    &self,
+    request: Request<GetNamespaceUsageRequest>,
+  ) -> Result<Response<GetNamespaceUsageReply>, Status> {
+    let r = request.get_ref();
+    authorize(&request, &r.namespace_id)?;
+    let st = get_state();
+    let usage = st
+      .usage_meter
+      .usage(&r.namespace_id)
+      .await
+      .translate_err()?;
+    Ok(Response::new(GetNamespaceUsageReply {
+      bytes_read: usage.bytes_read,
+      bytes_written: usage.bytes_written,
+      write_quota_bytes: usage.write_quota_bytes,
+    }))
+  }
+
+  async fn tail_changelog(
+    &self,
+    request: Request<TailChangelogRequest>,
+  ) -> Result<Response<TailChangelogReply>, Status> {
+    let r = request.get_ref();
+    authorize(&request, &r.namespace_id)?;
+    let limit = if r.limit == 0 {
+      DEFAULT_TAIL_LIMIT
+    } else {
+      (r.limit as usize).min(MAX_TAIL_LIMIT)
+    };
+    let (entries, next_seq) = tail_changelog(&r.namespace_id, r.from_seq, limit)
+      .await
+      .translate_err()?;
+    Ok(Response::new(TailChangelogReply { entries, next_seq }))
+  }
+
+  async fn get_plan_diff(
+    &self,
+    request: Request<GetPlanDiffRequest>,
+  ) -> Result<Response<GetPlanDiffReply>, Status> {
+    let r = request.get_ref();
+    authorize(&request, &r.namespace_id)?;
+    let st = get_state();
+    let old = st
+      .schema_cache
+      .get_or_load(&r.namespace_id, &r.old_deployment_id)
+      .await
+      .translate_err()?;
+    let diff = if r.new_deployment_id.is_empty() {
+      let new_plan: StoragePlan<String> = serde_yaml::from_str(&r.new_plan).translate_err()?;
+      let new_plan = StoragePlan::<StorageKey>::try_from(&new_plan).translate_err()?;
+      diff_plans(&old.plan, &new_plan)
+    } else {
+      let new = st
+        .schema_cache
+        .get_or_load(&r.namespace_id, &r.new_deployment_id)
+        .await
+        .translate_err()?;
+      diff_plans(&old.plan, &new.plan)
+    };
+    Ok(Response::new(GetPlanDiffReply {
+      diff: Some(encode_plan_diff(diff)),
+    }))
+  }
+
+  async fn export_data(
+    &self,
+    request: Request<ExportDataRequest>,
+  ) -> Result<Response<ExportDataReply>, Status> {
+    let r = request.get_ref();
+    authorize(&request, &r.namespace_id)?;
+    let st = get_state();
+    let after: Option<SerializedVmValue> = if r.after.is_empty() {
+      None
+    } else {
+      Some(serde_json::from_str(&r.after).translate_err()?)
+    };
+    let limit = match r.limit {
+      0 => DEFAULT_EXPORT_LIMIT,
+      x => (x as usize).min(MAX_EXPORT_LIMIT),
+    };
+
+    let schema_ctx = st
+      .schema_cache
+      .get_or_load(&r.namespace_id, &r.deployment_id)
+      .await
+      .translate_err()?;
+    let exec_ctx = ExecContext::load(schema_ctx, "").translate_err()?;
+    let kv = open_namespace_store(&r.namespace_id)
+      .await
+      .translate_err()?;
+
+    // Rows are typed by the schema, so numbers can be exported as JSON numbers.
+    let page = exec_ctx
+      .export_rows(
+        &*kv,
+        &r.path,
+        after.as_ref(),
+        limit,
+        &VmValueEncodeConfig {
+          enable_int64: true,
+          enable_double: true,
+          enable_bytes: false,
+        },
+      )
+      .await
+      .translate_err()?;
+    Ok(Response::new(ExportDataReply {
+      rows: page
+        .rows
+        .iter()
+        .map(serde_json::to_string)
+        .collect::<Result<_, _>>()
+        .translate_err()?,
+      next_after: match &page.next {
+        Some(x) => serde_json::to_string(x).translate_err()?,
+        None => String::new(),
+      },
+    }))
+  }
+
+  async fn import_data(
+    &self,
+    request: Request<ImportDataRequest>,
+  ) -> Result<Response<ImportDataReply>, Status> {
+    let r = request.get_ref();
+    authorize(&request, &r.namespace_id)?;
+    let st = get_state();
+    let rows: Vec<SerializedVmValue> = serde_json::from_str(&r.rows).translate_err()?;
+    if rows.len() > MAX_IMPORT_ROWS {
+      return Err(ServerError::TooManyRows(rows.len(), MAX_IMPORT_ROWS)).translate_err();
+    }
+    let chunk_size = match r.chunk_size {
+      0 => DEFAULT_IMPORT_CHUNK_SIZE,
+      x => x as usize,
+    };
+
+    let schema_ctx = st
+      .schema_cache
+      .get_or_load(&r.namespace_id, &r.deployment_id)
+      .await
+      .translate_err()?;
+    let exec_ctx = ExecContext::load(schema_ctx, "").translate_err()?;
+    let kv = open_namespace_store(&r.namespace_id)
+      .await
+      .translate_err()?;
+    let res = exec_ctx.import_rows(&*kv, &r.path, &rows, chunk_size).await;
+
+    // Chunks before a failure may have been committed.
+    st.result_cache.bump_generation(&r.namespace_id);
+
+    res.translate_err()?;
+    Ok(Response::new(ImportDataReply {
+      imported: rows.len() as u64,
+    }))
+  }
+
+  async fn check_consistency(
+    &self,
+    request: Request<CheckConsistencyRequest>,
+  ) -> Result<Response<CheckConsistencyReply>, Status> {
+    let r = request.get_ref();
+    authorize(&request, &r.namespace_id)?;
+    let st = get_state();
+    let batch_size = match r.batch_size {
+      0 => DEFAULT_FSCK_BATCH_SIZE,
+      x => x as usize,
+    };
+    let deployment_id = resolve_deployment_id(&r.namespace_id, &r.deployment_id)
+      .await
+      .translate_err()?;
+
+    // Repairs delete data of fields that are not in the schema of the deployment, which newer
+    // deployments may still have.
+    let repairing = r.repair || r.delete_orphan_keys;
+    if repairing {
+      let latest = resolve_deployment_id(&r.namespace_id, "")
+        .await
+        .translate_err()?;
+      if latest != deployment_id {
+        return Err(ServerError::RepairWithOldDeployment(latest)).translate_err();
+      }
+    }
+
+    let schema_ctx = st
+      .schema_cache
+      .get_or_load(&r.namespace_id, &deployment_id)
+      .await
+      .translate_err()?;
+    let exec_ctx = ExecContext::load(schema_ctx, "").translate_err()?;
+    let kv = open_namespace_store(&r.namespace_id)
+      .await
+      .translate_err()?;
+    let res = exec_ctx
+      .fsck(&*kv, batch_size, r.repair, r.delete_orphan_keys)
+      .await;
+
+    // Batches before a failure may have been repaired.
+    if repairing {
+      st.result_cache.bump_generation(&r.namespace_id);
+    }
+
+    let report = res.translate_err()?;
+    let issues = report
+      .issues
+      .into_iter()
+      .map(|x| {
+        let kind = match x.kind {
+          FsckIssueKind::MissingMemberData => consistency_issue::Kind::MissingMemberData,
+          FsckIssueKind::OrphanMemberData => consistency_issue::Kind::OrphanMemberData,
+          FsckIssueKind::UndecodableMember => consistency_issue::Kind::UndecodableMember,
+          FsckIssueKind::MissingIndexEntry => consistency_issue::Kind::MissingIndexEntry,
+          FsckIssueKind::DanglingIndexEntry => consistency_issue::Kind::DanglingIndexEntry,
+          FsckIssueKind::OrphanKey => consistency_issue::Kind::OrphanKey,
+        };
+        ConsistencyIssue {
+          kind: kind as i32,
+          path: x.path,
+          key: x.key,
+          detail: x.detail,
+          repaired: x.repaired,
+        }
+      })
+      .collect();
+    Ok(Response::new(CheckConsistencyReply {
+      issues,
+      num_members: report.num_members,
+    }))
+  }
+
+  async fn set_deployment_routing(
+    &self,
+    request: Request<SetDeploymentRoutingRequest>,
+  ) -> Result<Response<SetDeploymentRoutingReply>, Status> {
+    let r = request.get_ref();
+    authorize(&request, &r.namespace_id)?;
+    let st = get_state();
+    let config = r.routing.clone().unwrap_or_default();
+    let candidate_mode = match CandidateMode::from_i32(config.candidate_mode) {
+      Some(CandidateMode::Shadow) => routing::CandidateMode::Shadow,
+      Some(CandidateMode::Canary) => routing::CandidateMode::Canary,
+      None => return Err(ServerError::InvalidCandidateMode(config.candidate_mode)).translate_err(),
+    };
+    let config = sysquery::DeploymentRouting {
+      primary_deployment: config.primary_deployment_id,
+      candidate_deployment: config.candidate_deployment_id,
+      candidate_traffic_percent: config.candidate_traffic_percent,
+      candidate_mode,
+    };
+    config.validate().translate_err()?;
+    let updated = set_deployment_routing(&r.namespace_id, &config)
+      .await
+      .translate_err()?;
+    st.deployment_router.invalidate(&r.namespace_id).await;
+    Ok(Response::new(SetDeploymentRoutingReply { updated }))
+  }
+
+  async fn get_deployment_routing(
+    &self,
+    request: Request<GetDeploymentRoutingRequest>,
+  ) -> Result<Response<GetDeploymentRoutingReply>, Status> {
+    let r = request.get_ref();
+    authorize(&request, &r.namespace_id)?;
+    let config = get_deployment_routing(&r.namespace_id)
+      .await
+      .translate_err()?;
+    let candidate_mode = match config.candidate_mode {
+      routing::CandidateMode::Shadow => CandidateMode::Shadow,
+      routing::CandidateMode::Canary => CandidateMode::Canary,
+    };
+    Ok(Response::new(GetDeploymentRoutingReply {
+      routing: Some(DeploymentRouting {
+        primary_deployment_id: config.primary_deployment,
+        candidate_deployment_id: config.candidate_deployment,
+        candidate_traffic_percent: config.candidate_traffic_percent,
+        candidate_mode: candidate_mode as i32,
+      }),
+    }))
+  }
+
+  type watchNamespacesStream = ControlEventStream;
+
+  async fn watch_namespaces(
+    &self,
+    request: Request<WatchNamespacesRequest>,
+  ) -> Result<Response<Self::watchNamespacesStream>, Status> {
+    let scope = request_scope(&request).translate_err()?;
+    let rx = get_state()
+      .control_events
+      .watch(WatchFilter::Namespaces(scope), request.get_ref().from_seq);
+    Ok(Response::new(control_event_stream(rx)))
+  }
+
+  type watchDeploymentsStream = ControlEventStream;
+
+  async fn watch_deployments(
+    &self,
+    request: Request<WatchDeploymentsRequest>,
+  ) -> Result<Response<Self::watchDeploymentsStream>, Status> {
+    let r = request.get_ref();
+    authorize(&request, &r.namespace_id)?;
+    let rx = get_state()
+      .control_events
+      .watch(WatchFilter::Deployments(r.namespace_id.clone()), r.from_seq);
+    Ok(Response::new(control_event_stream(rx)))
+  }
+}
+
+type ControlEventStream = Pin<Box<dyn Stream<Item = Result<ControlEvent, Status>> + Send + Sync>>;
+
+fn control_event_stream(
+  mut rx: mpsc::Receiver<anyhow::Result<ControlEvent>>,
+) -> ControlEventStream {
+  Box::pin(futures::stream::poll_fn(move |cx| {
+    rx.poll_recv(cx).map(|x| x.map(|x| x.translate_err()))
+  }))
+}
+
+/// Compiles a migration script against the migration view from `old_schema_ctx` to the new
+/// schema and plan.
+fn load_migration_script(
+  script: &str,
+  old_schema_ctx: Option<&SchemaContext>,
+  new_schema: &CompiledSchema,
+  new_plan: &StoragePlan,
+) -> anyhow::Result<ExecContext> {
+  let old_schema_ctx = old_schema_ctx.ok_or(ServerError::MigrationScriptWithoutSource)?;
+  let (schema, plan) = build_migration_view(
+    &old_schema_ctx.schema,
+    &old_schema_ctx.plan,
+    new_schema,
+    new_plan,
+  )?;
+  let exec_ctx = ExecContext::load(Arc::new(SchemaContext { schema, plan }), script)?;
+  exec_ctx.vm().lookup_exported_graph_by_name("migrate")?;
+  Ok(exec_ctx)
+}
+
+/// Checks a `CreateDeploymentRequest` and reports what the migration from `old_schema_ctx` would
+/// do, without writing anything.
+fn report_migration(
+  r: &CreateDeploymentRequest,
+  new_schema: &CompiledSchema,
+  old_schema_ctx: Option<&SchemaContext>,
+) -> anyhow::Result<MigrationReport> {
+  let empty = SchemaContext {
+    schema: Default::default(),
+    plan: Default::default(),
+  };
+  let old = old_schema_ctx.unwrap_or(&empty);
+  let generated_plan = generate_plan_for_schema(&old.plan, &old.schema, new_schema)?;
+  let generated_report = compare_plans(&old.plan, &generated_plan);
+
+  // Storage keys of new fields are random, so plans are compared by the fields they keep.
+  let (plan, mut report) = if r.plan.is_empty() {
+    (generated_plan, generated_report)
+  } else {
+    let plan: StoragePlan<String> = serde_yaml::from_str(&r.plan)?;
+    let plan = StoragePlan::<StorageKey>::try_from(&plan)?;
+    if rmp_serde::to_vec_named(&generate_plan_for_schema(&plan, new_schema, new_schema)?)?
+      != rmp_serde::to_vec_named(&plan)?
+    {
+      return Err(ServerError::InvalidStoragePlan.into());
+    }
+    let mut report = compare_plans(&old.plan, &plan);
+    report.plan_matches = report.preserved_fields == generated_report.preserved_fields
+      && report.dropped_fields == generated_report.dropped_fields;
+    (plan, report)
+  };
+
+  if !r.migration_script.is_empty() {
+    load_migration_script(&r.migration_script, old_schema_ctx, new_schema, &plan)?;
+  }
+
+  if let Some(old_schema_ctx) = old_schema_ctx {
+    report.lossy_changes = lossy_changes(&old_schema_ctx.schema, new_schema);
+  }
+  report.plan = serde_yaml::to_string(&StoragePlan::<String>::from(&plan))?;
+  Ok(report)
+}
+
+fn lossy_changes(old_schema: &CompiledSchema, new_schema: &CompiledSchema) -> Vec<String> {
+  compat::check(old_schema, new_schema)
+    .into_iter()
+    .filter(|x| x.is_lossy())
+    .map(|x| x.to_string())
+    .collect()
+}
+
+fn compare_plans(old_plan: &StoragePlan, new_plan: &StoragePlan) -> MigrationReport {
+  let diff = diff_plans(old_plan, new_plan);
+  let mut report = MigrationReport {
+    preserved_fields: diff.unchanged,
+    dropped_fields: diff.removed.into_iter().map(|x| x.path).collect(),
+    new_fields: diff.added.into_iter().map(|x| x.path).collect(),
+    ..Default::default()
+  };
+  for x in diff.moved {
+    report.preserved_fields.push(x.new_path);
+  }
+  for x in diff.rekeyed {
+    report.dropped_fields.push(x.path.clone());
+    report.new_fields.push(x.path);
+  }
+  report.preserved_fields.sort();
+  report.dropped_fields.sort();
+  report.new_fields.sort();
+  report
+}
+
+fn encode_plan_diff(diff: StoragePlanDiff) -> PlanDiff {
+  PlanDiff {
+    unchanged: diff.unchanged,
+    added: diff
+      .added
+      .into_iter()
+      .map(|x| PlanDiffNode {
+        path: x.path,
+        key: x.key.to_vec(),
+      })
+      .collect(),
+    removed: diff
+      .removed
+      .into_iter()
+      .map(|x| PlanDiffNode {
+        path: x.path,
+        key: x.key.to_vec(),
+      })
+      .collect(),
+    moved: diff
+      .moved
+      .into_iter()
+      .map(|x| MovedPlanDiffNode {
+        old_path: x.old_path,
+        new_path: x.new_path,
+        key: x.key.to_vec(),
+      })
+      .collect(),
+    rekeyed: diff
+      .rekeyed
+      .into_iter()
+      .map(|x| RekeyedPlanDiffNode {
+        path: x.path,
+        old_key: x.old_key.to_vec(),
+        new_key: x.new_key.to_vec(),
+      })
+      .collect(),
+  }
+}
+
+fn describe_deployment(
+  deployment_id: String,
+  schema: &CompiledSchema,
+  plan: &StoragePlan,
+) -> DescribeSchemaReply {
+  DescribeSchemaReply {
+    deployment_id,
+    types: schema
+      .types
+      .values()
+      .map(|x| TypeDescription {
+        name: x.name.to_string(),
+        fields: x
+          .fields
+          .iter()
+          .map(|(name, (ty, annotations))| describe_field(name, ty, annotations))
+          .collect(),
+        ttl_field: x.ttl.as_deref().unwrap_or_default().to_string(),
+      })
+      .collect(),
+    exports: schema
+      .exports
+      .iter()
+      .map(|(name, ty)| describe_field(name, ty, &[]))
+      .collect(),
+    field_keys: plan
+      .field_keys()
+      .into_iter()
+      .map(|(path, key)| PlanDiffNode {
+        path,
+        key: key.to_vec(),
+      })
+      .collect(),
+  }
+}
+
+fn describe_field(name: &str, ty: &FieldType, annotations: &[FieldAnnotation]) -> FieldDescription {
+  FieldDescription {
+    name: name.to_string(),
+    ty: Some(describe_field_type(ty)),
+    annotations: annotations.iter().map(describe_annotation).collect(),
+  }
+}
+
+fn describe_field_type(ty: &FieldType) -> FieldTypeDescription {
+  let (kind, name, params) = match ty {
+    FieldType::Table(x) => ("table", x.to_string(), vec![]),
+    FieldType::Primitive(x) => ("primitive", x.to_string(), vec![]),
+    FieldType::Set(x) => ("set", String::new(), vec![describe_field_type(x)]),
+    FieldType::List(x) => ("list", String::new(), vec![describe_field_type(x)]),
+    FieldType::Map(k, v) => (
+      "map",
+      String::new(),
+      vec![describe_field_type(k), describe_field_type(v)],
+    ),
+  };
+  FieldTypeDescription {
+    kind: kind.to_string(),
+    name,
+    params,
+  }
+}
+
+fn describe_annotation(x: &FieldAnnotation) -> FieldAnnotationDescription {
+  let string_literal = |x: &str| serde_json::to_string(x).unwrap();
+  let (name, args) = match x {
+    FieldAnnotation::PrimaryKey => ("primary", vec![]),
+    FieldAnnotation::PrimaryKeyComponent(x) => ("primary", vec![x.to_string()]),
+    FieldAnnotation::Unique => ("unique", vec![]),
+    FieldAnnotation::Index => ("index", vec![]),
+    FieldAnnotation::RenameFrom(x) => ("rename_from", vec![string_literal(x)]),
+    FieldAnnotation::Acl(x) => ("acl", x.iter().map(|x| string_literal(x)).collect()),
+    FieldAnnotation::Default(x) => ("default", vec![x.to_string()]),
+    FieldAnnotation::Counter => ("counter", vec![]),
+    FieldAnnotation::Version => ("version", vec![]),
+  };
+  FieldAnnotationDescription {
+    name: name.to_string(),
+    args,
+  }
+}
+
+/// `deployment_id`, or the latest deployment of the namespace if it is empty.
+async fn resolve_deployment_id(namespace_id: &str, deployment_id: &str) -> anyhow::Result<String> {
+  if !deployment_id.is_empty() {
+    return Ok(deployment_id.to_string());
+  }
+  Ok(
+    latest_deployment_id(namespace_id)
+      .await?
+      .ok_or_else(|| ServerError::NoDeployment(namespace_id.to_string()))?,
+  )
+}
+
+/// The configured query limits, tightened by those of a request.
+fn request_limits(limits: Option<&ExecLimits>) -> limits::ExecLimits {
+  let server_limits = &get_state().exec_limits;
+  let x = match limits {
+    Some(x) => x,
+    None => return server_limits.clone(),
+  };
+  let nonzero = |x: u64| if x == 0 { None } else { Some(x) };
+  server_limits.tighten(&limits::ExecLimits {
+    timeout: nonzero(x.timeout_ms).map(Duration::from_millis),
+    max_kv_reads: nonzero(x.max_kv_reads),
+    max_kv_writes: nonzero(x.max_kv_writes),
+    max_subgraph_calls: nonzero(x.max_subgraph_calls),
+    max_value_size: nonzero(x.max_value_size).map(|x| x as usize),
+    max_recursion_depth: nonzero(x.max_recursion_depth)
+      .map(|x| x as usize)
+      .unwrap_or(usize::MAX),
+  })
+}
+
+fn authorize<T>(request: &Request<T>, namespace_id: &str) -> Result<(), Status> {
+  request_scope(request)
+    .and_then(|x| x.check_namespace(namespace_id))
+    .translate_err()
+}
+
+fn authorize_root<T>(request: &Request<T>) -> Result<(), Status> {
+  request_scope(request)
+    .and_then(|x| x.check_root())
+    .translate_err()
+}
+
+/// Checked before taking the transaction, so that other tokens cannot end it.
+async fn authorize_transaction<T>(
+  request: &Request<T>,
+  transaction_id: &str,
+) -> Result<(), Status> {
+  let open_txn = get_state()
+    .txn_manager
+    .get(transaction_id)
+    .await
+    .translate_err()?;
+  authorize(request, &open_txn.namespace_id)
+}
+
+trait ErrorTranslate {
+  type Output;
+  fn translate_err(self) -> Result<Self::Output, Status>;
+}
+
+impl<T, E> ErrorTranslate for Result<T, E>
+where
+  anyhow::Error: From<E>,
+{
+  type Output = T;
+
+  fn translate_err(self) -> Result<Self::Output, Status> {
+    self.map_err(|x| {
+      let x = anyhow::Error::from(x);
+      match x.downcast_ref::<ExecError>() {
+        Some(e @ ExecError::LimitExceeded(_)) => return Status::resource_exhausted(e.to_string()),
+        Some(e @ ExecError::UniqueConstraintViolation(_)) => {
+          return Status::already_exists(e.to_string())
+        }
+        Some(e @ ExecError::InvalidRow(..)) | Some(e @ ExecError::NotSetOrTable(_)) => {
+          return Status::invalid_argument(e.to_string())
+        }
+        Some(e @ ExecError::VersionConflict(..)) => return Status::aborted(e.to_string()),
+        Some(e) if e.is_script_error() => return Status::failed_precondition(e.to_string()),
+        _ => {}
+      }
+      if let Some(e @ ServerExecError::TraceGraphNotReadOnly(_)) =
+        x.downcast_ref::<ServerExecError>()
+      {
+        return Status::invalid_argument(e.to_string());
+      }
+      if let Some(e @ CursorError::InvalidToken) = x.downcast_ref::<CursorError>() {
+        return Status::invalid_argument(e.to_string());
+      }
+      if let Some(e @ ServerError::LossySchemaChanges(_)) = x.downcast_ref::<ServerError>() {
+        return Status::failed_precondition(e.to_string());
+      }
+      if let Some(e @ ServerError::NoDeployment(_)) = x.downcast_ref::<ServerError>() {
+        return Status::not_found(e.to_string());
+      }
+      if let Some(e @ ServerError::AdhocQueriesDisabled) = x.downcast_ref::<ServerError>() {
+        return Status::permission_denied(e.to_string());
+      }
+      if let Some(e @ ServerError::InvalidCandidateMode(_)) = x.downcast_ref::<ServerError>() {
+        return Status::invalid_argument(e.to_string());
+      }
+      if let Some(e) = x.downcast_ref::<RoutingError>() {
+        return match e {
+          RoutingError::DeploymentNotFound => Status::not_found(e.to_string()),
+          _ => Status::invalid_argument(e.to_string()),
+        };
+      }
+      if let Some(e) = x.downcast_ref::<NamespaceMountError>() {
+        return Status::failed_precondition(e.to_string());
+      }
+      if let Some(e @ MountError::ReadOnly) = x.downcast_ref::<MountError>() {
+        return Status::failed_precondition(e.to_string());
+      }
+      if let Some(e @ TxnManagerError::TransactionNotFound(_)) = x.downcast_ref::<TxnManagerError>()
+      {
+        return Status::not_found(e.to_string());
+      }
+      if let Some(e @ KvError::Conflict) = x.downcast_ref::<KvError>() {
+        return Status::aborted(e.to_string());
+      }
+      if let Some(e @ MeteringError::QuotaExceeded(..)) = x.downcast_ref::<MeteringError>() {
+        return Status::resource_exhausted(e.to_string());
+      }
+      if let Some(e) = x.downcast_ref::<RateLimitError>() {
+        let mut status = Status::resource_exhausted(e.to_string());
+        status
+          .metadata_mut()
+          .insert("retry-after", MetadataValue::from(e.retry_after_secs()));
+        return status;
+      }
+      if let Some(e) = x.downcast_ref::<AuthError>() {
+        return match e {
+          AuthError::NamespaceNotAllowed(_)
+          | AuthError::RootRequired
+          | AuthError::MountNotAllowed(_) => Status::permission_denied(e.to_string()),
+          _ => Status::unauthenticated(e.to_string()),
+        };
+      }
+      if let Some(e) = x.downcast_ref::<ApiTokenError>() {
+        return match e {
+          ApiTokenError::InvalidGraphName(_) | ApiTokenError::DuplicateGrant(_) => {
+            Status::invalid_argument(e.to_string())
+          }
+          _ => Status::permission_denied(e.to_string()),
+        };
+      }
+      if let Some(e @ SysQueryError::ApiTokenNotFound) = x.downcast_ref::<SysQueryError>() {
+        return Status::unauthenticated(e.to_string());
+      }
+      if let Some(e @ SysQueryError::NamespaceNotFound) = x.downcast_ref::<SysQueryError>() {
+        return Status::not_found(e.to_string());
+      }
       log::error!("request error: {:?}", x);
       Status::internal(format!("{:?}", x))
     })
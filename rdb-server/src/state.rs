@@ -1,9 +1,21 @@
 use std::sync::Arc;
 
 use once_cell::sync::OnceCell;
-use rdb_analyzer::data::kv::KeyValueStore;
+use rdb_analyzer::data::{
+  kv::KeyValueStore,
+  treewalker::{
+    exec::{ExecConfig, RetryPolicy},
+    inline::InlineConfig,
+    limits::ExecLimits,
+  },
+};
 
-use crate::{query_cache::QueryCache, system::SystemSchema};
+use crate::{
+  auth::TokenRegistry, control_events::ControlEventLog, id_gen::IdGenerator, metering::UsageMeter,
+  query_cache::QueryCache, rate_limit::RateLimiter, result_cache::ResultCache,
+  routing::DeploymentRouter, schema_cache::SchemaCache, subscriptions::SubscriptionHub,
+  system::SystemSchema, txn_manager::TxnManager,
+};
 
 pub type DataStoreGenerator = Box<dyn Fn(&[u8]) -> Box<dyn KeyValueStore> + Send + Sync>;
 
@@ -11,7 +23,38 @@ pub struct ServerState {
   pub data_store_generator: DataStoreGenerator,
   pub system_store: Box<dyn KeyValueStore>,
   pub system_schema: SystemSchema,
+
+  /// Namespaces and deployments created or deleted, in the system metadata store.
+  pub control_events: ControlEventLog,
   pub query_cache: Arc<QueryCache>,
+  pub schema_cache: SchemaCache,
+  pub result_cache: ResultCache,
+  pub deployment_router: DeploymentRouter,
+  pub id_generator: IdGenerator,
+  pub kv_profiling: bool,
+  pub changelog_enabled: bool,
+  pub retry_policy: RetryPolicy,
+
+  /// Limits of queries. Requests may only tighten them.
+  pub exec_limits: ExecLimits,
+  pub exec_config: ExecConfig,
+
+  /// Inlining of subgraph calls in scripts as they are loaded.
+  pub inline_config: InlineConfig,
+  pub txn_manager: Arc<TxnManager>,
+  pub usage_meter: Arc<UsageMeter>,
+  pub subscription_hub: SubscriptionHub,
+  pub token_registry: Arc<TokenRegistry>,
+
+  /// Rate limits of queries, per namespace and per API token.
+  pub namespace_rate_limiter: Option<RateLimiter>,
+  pub token_rate_limiter: Option<RateLimiter>,
+
+  /// Whether deployments with data-lossy schema changes require `allow_lossy`.
+  pub reject_lossy_deployments: bool,
+
+  /// Whether `executeAdhocQuery` is allowed.
+  pub adhoc_queries_enabled: bool,
 }
 
 static STATE: OnceCell<ServerState> = OnceCell::new();
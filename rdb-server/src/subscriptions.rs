@@ -0,0 +1,144 @@
+use std::{
+  collections::HashMap,
+  sync::{Arc, Mutex},
+};
+
+use rdb_analyzer::data::{
+  pathwalker::PathSegment,
+  treewalker::{
+    exec::{Change, ChangeKind},
+    serialize::SerializedVmValue,
+    vm_value::VmValue,
+  },
+  value::PrimitiveValue,
+};
+use serde::Serialize;
+use tokio::sync::broadcast;
+
+/// Fans out the changes committed through this server to subscribers, per namespace.
+///
+/// Changes committed through other servers sharing the same store are not seen.
+pub struct SubscriptionHub {
+  namespaces: Mutex<HashMap<String, broadcast::Sender<Arc<ChangeEvent>>>>,
+
+  /// Number of events a subscriber may fall behind before it misses some.
+  buffer_size: usize,
+}
+
+/// A change as sent to subscribers.
+#[derive(Serialize, Debug)]
+pub struct ChangeEvent {
+  /// Path to the changed set or table. Set members are written as `{"key": primary_key}`.
+  pub path: Vec<EventPathSegment>,
+
+  /// `insert`, `update` or `delete` of a set member, or `set_field` on a table.
+  pub op: &'static str,
+
+  /// Primary key of the inserted, updated or deleted member.
+  #[serde(skip_serializing_if = "Option::is_none")]
+  pub key: Option<SerializedVmValue>,
+
+  /// Name of the field set by `set_field`.
+  #[serde(skip_serializing_if = "Option::is_none")]
+  pub field: Option<String>,
+}
+
+#[derive(Serialize, Debug)]
+#[serde(untagged)]
+pub enum EventPathSegment {
+  Field(String),
+  Member { key: SerializedVmValue },
+}
+
+impl SubscriptionHub {
+  pub fn new(buffer_size: usize) -> Self {
+    Self {
+      namespaces: Mutex::new(HashMap::new()),
+      buffer_size,
+    }
+  }
+
+  /// Whether changes to the namespace need to be tracked.
+  pub fn has_subscribers(&self, namespace_id: &str) -> bool {
+    self
+      .namespaces
+      .lock()
+      .unwrap()
+      .get(namespace_id)
+      .map(|x| x.receiver_count() != 0)
+      .unwrap_or(false)
+  }
+
+  pub fn subscribe(&self, namespace_id: &str) -> broadcast::Receiver<Arc<ChangeEvent>> {
+    let mut namespaces = self.namespaces.lock().unwrap();
+    match namespaces.get(namespace_id) {
+      Some(x) => x.subscribe(),
+      None => {
+        let (tx, rx) = broadcast::channel(self.buffer_size);
+        namespaces.insert(namespace_id.to_string(), tx);
+        rx
+      }
+    }
+  }
+
+  pub fn publish(&self, namespace_id: &str, changes: Vec<Change>) {
+    if changes.is_empty() {
+      return;
+    }
+    let mut namespaces = self.namespaces.lock().unwrap();
+    let tx = match namespaces.get(namespace_id) {
+      Some(x) => x,
+      None => return,
+    };
+    for change in changes {
+      if tx.send(Arc::new(ChangeEvent::from(change))).is_err() {
+        // All subscribers are gone.
+        namespaces.remove(namespace_id);
+        return;
+      }
+    }
+  }
+}
+
+impl ChangeEvent {
+  /// Whether this change is on `path` or below it. `path` is a dot-separated path from an
+  /// export, e.g. `posts` or `site_config.theme`, and matches all members of the sets on it.
+  pub fn matches(&self, path: &[&str]) -> bool {
+    let mut fields = self.path.iter().filter_map(|x| match x {
+      EventPathSegment::Field(x) => Some(x.as_str()),
+      EventPathSegment::Member { .. } => None,
+    });
+    path.iter().all(|x| fields.next() == Some(*x))
+  }
+}
+
+impl From<Change> for ChangeEvent {
+  fn from(x: Change) -> Self {
+    let path = x
+      .path
+      .into_iter()
+      .map(|x| match x {
+        PathSegment::Field(x) => EventPathSegment::Field(x),
+        PathSegment::Member(x) => EventPathSegment::Member { key: encode_key(x) },
+      })
+      .collect();
+    let (op, key, field) = match x.kind {
+      ChangeKind::Insert(x) => ("insert", Some(encode_key(x)), None),
+      ChangeKind::Update(x) => ("update", Some(encode_key(x)), None),
+      ChangeKind::Delete(x) => ("delete", Some(encode_key(x)), None),
+      ChangeKind::SetField(x) => ("set_field", None, Some(x)),
+    };
+    Self {
+      path,
+      op,
+      key,
+      field,
+    }
+  }
+}
+
+/// Encodes a primary key the same way as in query results with the default encoding.
+fn encode_key(x: PrimitiveValue) -> SerializedVmValue {
+  SerializedVmValue::encode(&VmValue::Primitive(x), &Default::default())
+    .expect("encode_key: cannot encode primitive value")
+}
@@ -1,9 +1,28 @@
+use std::collections::BTreeMap;
+
 use anyhow::Result;
-use rdb_analyzer::data::treewalker::serialize::{SerializedVmValue, VmValueEncodeConfig};
+use maplit::btreemap;
+use rand::RngCore;
+use rdb_analyzer::{
+  data::treewalker::serialize::{SerializedVmValue, TaggedVmValue, VmValueEncodeConfig},
+  schema::compile::CompiledSchema,
+};
+use sha2::{Digest, Sha256};
 
-use crate::state::get_state;
+use crate::{
+  api_token::GraphPermission,
+  id_gen::IdStrategy,
+  routing::{CandidateMode, RoutingError},
+  state::get_state,
+  util::current_millis,
+};
 use thiserror::Error;
 
+/// Version of the structured representation stored alongside the schema text of a deployment.
+///
+/// Deployments whose stored version differs are re-derived from their schema text on load.
+pub const DEPLOYMENT_FORMAT_VERSION: i64 = 1;
+
 #[derive(Error, Debug)]
 pub enum SysQueryError {
   #[error("namespace not found")]
@@ -11,6 +30,12 @@ pub enum SysQueryError {
 
   #[error("query script not found")]
   QueryScriptNotFound,
+
+  #[error("explorer token not found")]
+  ExplorerTokenNotFound,
+
+  #[error("api token not found")]
+  ApiTokenNotFound,
 }
 
 pub struct QueryScript {
@@ -25,9 +50,238 @@ pub struct Deployment {
   pub description: String,
   pub schema: String,
   pub plan: Vec<u8>,
+
+  /// Structured representation. `None` for deployments created before it was introduced.
+  pub blobs: Option<DeploymentBlobs>,
   pub create_time: i64,
 }
 
+/// Compiled schema of a deployment, together with content hashes of it and of the storage plan.
+pub struct DeploymentBlobs {
+  pub compiled_schema: Vec<u8>,
+  pub schema_hash: String,
+  pub plan_hash: String,
+  pub format_version: i64,
+}
+
+impl DeploymentBlobs {
+  pub fn encode(schema: &CompiledSchema, plan: &[u8]) -> Result<Self> {
+    let compiled_schema = schema.serialize_compressed()?;
+    Ok(Self {
+      schema_hash: content_hash(&compiled_schema),
+      plan_hash: content_hash(plan),
+      compiled_schema,
+      format_version: DEPLOYMENT_FORMAT_VERSION,
+    })
+  }
+
+  /// Returns whether the blobs are in the current format and match the given storage plan.
+  pub fn is_valid_for(&self, plan: &[u8]) -> bool {
+    self.format_version == DEPLOYMENT_FORMAT_VERSION
+      && self.schema_hash == content_hash(&self.compiled_schema)
+      && self.plan_hash == content_hash(plan)
+  }
+
+  pub fn to_serialized_fields(&self) -> BTreeMap<String, SerializedVmValue> {
+    btreemap! {
+      "compiled_schema".to_string() => SerializedVmValue::String(base64::encode(&self.compiled_schema)),
+      "schema_hash".to_string() => SerializedVmValue::String(self.schema_hash.clone()),
+      "plan_hash".to_string() => SerializedVmValue::String(self.plan_hash.clone()),
+      "format_version".to_string() => SerializedVmValue::String(format!("{}", self.format_version)),
+    }
+  }
+
+  fn from_serialized_fields(m: &BTreeMap<String, SerializedVmValue>) -> Result<Option<Self>> {
+    let fields = [
+      "compiled_schema",
+      "schema_hash",
+      "plan_hash",
+      "format_version",
+    ];
+    if fields
+      .iter()
+      .any(|x| matches!(m.get(*x), None | Some(SerializedVmValue::Null(_))))
+    {
+      return Ok(None);
+    }
+    Ok(Some(Self {
+      compiled_schema: m
+        .get("compiled_schema")
+        .unwrap()
+        .try_unwrap_bytes()?
+        .clone(),
+      schema_hash: m.get("schema_hash").unwrap().try_unwrap_string()?.clone(),
+      plan_hash: m.get("plan_hash").unwrap().try_unwrap_string()?.clone(),
+      format_version: m.get("format_version").unwrap().try_unwrap_int64()?,
+    }))
+  }
+}
+
+/// Hex-encoded SHA-256 hash of a stored blob.
+pub fn content_hash(data: &[u8]) -> String {
+  let mut hasher = Sha256::new();
+  hasher.update(data);
+  hex::encode(&hasher.finalize()[..])
+}
+
+pub struct ExplorerToken {
+  pub id: String,
+  pub description: String,
+  pub allowed_graphs: Vec<String>,
+  pub redacted_fields: Vec<String>,
+  pub create_time: i64,
+}
+
+impl ExplorerToken {
+  pub fn from_serialized(x: &SerializedVmValue) -> Result<Self> {
+    let m = x.try_unwrap_map(&[
+      "id",
+      "description",
+      "allowed_graphs",
+      "redacted_fields",
+      "create_time",
+    ])?;
+    Ok(Self {
+      id: m.get("id").unwrap().try_unwrap_string()?.clone(),
+      description: m.get("description").unwrap().try_unwrap_string()?.clone(),
+      allowed_graphs: serde_json::from_str(m.get("allowed_graphs").unwrap().try_unwrap_string()?)?,
+      redacted_fields: serde_json::from_str(
+        m.get("redacted_fields").unwrap().try_unwrap_string()?,
+      )?,
+      create_time: m.get("create_time").unwrap().try_unwrap_int64()?,
+    })
+  }
+}
+
+pub struct ApiToken {
+  pub id: String,
+  pub description: String,
+
+  /// Keyed by `query_script_id/graph_name`.
+  pub graph_permissions: BTreeMap<String, GraphPermission>,
+  pub create_time: i64,
+}
+
+impl ApiToken {
+  pub fn from_serialized(x: &SerializedVmValue) -> Result<Self> {
+    let m = x.try_unwrap_map(&["id", "description", "graph_permissions", "create_time"])?;
+    Ok(Self {
+      id: m.get("id").unwrap().try_unwrap_string()?.clone(),
+      description: m.get("description").unwrap().try_unwrap_string()?.clone(),
+      graph_permissions: serde_json::from_str(
+        m.get("graph_permissions").unwrap().try_unwrap_string()?,
+      )?,
+      create_time: m.get("create_time").unwrap().try_unwrap_int64()?,
+    })
+  }
+}
+
+pub struct Token {
+  pub id: String,
+  pub description: String,
+  pub namespaces: Vec<String>,
+  pub create_time: i64,
+
+  /// Namespaces that query scripts created with this token may mount read-only.
+  pub mounts: Vec<String>,
+
+  /// The role checked against `@acl` annotations for requests with this token.
+  pub role: Option<String>,
+}
+
+impl Token {
+  pub fn from_serialized(x: &SerializedVmValue) -> Result<Self> {
+    let m = x.try_unwrap_map(&[
+      "id",
+      "description",
+      "namespaces",
+      "create_time",
+      "mounts",
+      "role",
+    ])?;
+    Ok(Self {
+      id: m.get("id").unwrap().try_unwrap_string()?.clone(),
+      description: m.get("description").unwrap().try_unwrap_string()?.clone(),
+      namespaces: serde_json::from_str(m.get("namespaces").unwrap().try_unwrap_string()?)?,
+      create_time: m.get("create_time").unwrap().try_unwrap_int64()?,
+      mounts: serde_json::from_str(m.get("mounts").unwrap().try_unwrap_string()?)?,
+      role: Some(m.get("role").unwrap().try_unwrap_string()?.clone()).filter(|x| !x.is_empty()),
+    })
+  }
+}
+
+/// Usage counters and write quota of a namespace, in bytes.
+#[derive(Copy, Clone, Debug, Default)]
+pub struct NamespaceUsage {
+  pub bytes_read: u64,
+  pub bytes_written: u64,
+
+  /// Zero if the namespace has no quota.
+  pub write_quota_bytes: u64,
+}
+
+impl NamespaceUsage {
+  fn from_serialized(x: &SerializedVmValue) -> Result<Self> {
+    let m = x.try_unwrap_map(&["bytes_read", "bytes_written", "write_quota_bytes"])?;
+    Ok(Self {
+      bytes_read: m.get("bytes_read").unwrap().try_unwrap_int64()? as u64,
+      bytes_written: m.get("bytes_written").unwrap().try_unwrap_int64()? as u64,
+      write_quota_bytes: m.get("write_quota_bytes").unwrap().try_unwrap_int64()? as u64,
+    })
+  }
+}
+
+/// Which deployments serve the read queries of a namespace. See `routing`.
+#[derive(Clone, Debug, Default, Eq, PartialEq)]
+pub struct DeploymentRouting {
+  /// Empty if no deployment is primary.
+  pub primary_deployment: String,
+
+  /// Empty if no queries are routed to a candidate.
+  pub candidate_deployment: String,
+
+  /// Percentage of the read queries on the primary deployment that are routed to the candidate.
+  pub candidate_traffic_percent: u32,
+  pub candidate_mode: CandidateMode,
+}
+
+impl DeploymentRouting {
+  fn from_serialized(x: &SerializedVmValue) -> Result<Self> {
+    let m = x.try_unwrap_map(&[
+      "primary_deployment",
+      "candidate_deployment",
+      "candidate_traffic_percent",
+      "candidate_mode",
+    ])?;
+    Ok(Self {
+      primary_deployment: m
+        .get("primary_deployment")
+        .unwrap()
+        .try_unwrap_string()?
+        .clone(),
+      candidate_deployment: m
+        .get("candidate_deployment")
+        .unwrap()
+        .try_unwrap_string()?
+        .clone(),
+      candidate_traffic_percent: m
+        .get("candidate_traffic_percent")
+        .unwrap()
+        .try_unwrap_int64()? as u32,
+      candidate_mode: CandidateMode::parse(m.get("candidate_mode").unwrap().try_unwrap_string()?),
+    })
+  }
+
+  fn to_serialized_fields(&self) -> BTreeMap<String, SerializedVmValue> {
+    btreemap! {
+      "primary_deployment".to_string() => SerializedVmValue::String(self.primary_deployment.clone()),
+      "candidate_deployment".to_string() => SerializedVmValue::String(self.candidate_deployment.clone()),
+      "candidate_traffic_percent".to_string() => SerializedVmValue::String(format!("{}", self.candidate_traffic_percent)),
+      "candidate_mode".to_string() => SerializedVmValue::String(self.candidate_mode.as_str().to_string()),
+    }
+  }
+}
+
 pub async fn ns_to_kv_prefix_with_appended_zero(ns_id: &str) -> Result<Vec<u8>> {
   let st = get_state();
   let res = st
@@ -57,6 +311,100 @@ pub async fn ns_to_kv_prefix_with_appended_zero(ns_id: &str) -> Result<Vec<u8>>
   }
 }
 
+/// Creates a namespace with a random KV prefix. Returns false if it already exists.
+pub async fn add_namespace(namespace_id: &str) -> Result<bool> {
+  let st = get_state();
+  let mut kv_prefix: [u8; 16] = [0u8; 16];
+  rand::thread_rng().fill_bytes(&mut kv_prefix);
+
+  let res = st
+    .system_schema
+    .exec_ctx
+    .run_exported_graph(
+      &*st.system_store,
+      "add_namespace",
+      &[
+        SerializedVmValue::Null(None),
+        SerializedVmValue::String(namespace_id.into()),
+        SerializedVmValue::String(base64::encode(&kv_prefix)),
+        SerializedVmValue::String(format!("{}", current_millis())),
+      ],
+      &Default::default(),
+    )
+    .await?;
+  res.check_nonnull()?;
+  let created = res.try_unwrap_bool()?;
+  if created {
+    st.control_events.notify();
+  }
+  Ok(created)
+}
+
+pub async fn list_namespace_ids() -> Result<Vec<String>> {
+  let st = get_state();
+  let res = st
+    .system_schema
+    .exec_ctx
+    .run_exported_graph(
+      &*st.system_store,
+      "list_namespaces",
+      &[SerializedVmValue::Null(None)],
+      &VmValueEncodeConfig {
+        enable_bytes: true,
+        enable_double: true,
+        enable_int64: true,
+      },
+    )
+    .await?;
+  res
+    .try_unwrap_list()?
+    .iter()
+    .map(|x| {
+      Ok(
+        x.try_unwrap_map(&["id"])?
+          .get("id")
+          .unwrap()
+          .try_unwrap_string()?
+          .clone(),
+      )
+    })
+    .collect()
+}
+
+/// The most recently created deployment of a namespace, if it has any.
+pub async fn latest_deployment_id(namespace_id: &str) -> Result<Option<String>> {
+  let st = get_state();
+  let res = st
+    .system_schema
+    .exec_ctx
+    .run_exported_graph(
+      &*st.system_store,
+      "list_deployment",
+      &[
+        SerializedVmValue::Null(None),
+        SerializedVmValue::String(namespace_id.into()),
+      ],
+      &VmValueEncodeConfig {
+        enable_bytes: true,
+        enable_double: true,
+        enable_int64: true,
+      },
+    )
+    .await?;
+  let mut latest: Option<(i64, String)> = None;
+  for x in res.try_unwrap_list()? {
+    let m = x.try_unwrap_map(&["id", "create_time"])?;
+    let create_time = m.get("create_time").unwrap().try_unwrap_int64()?;
+    if latest.as_ref().map(|x| create_time > x.0).unwrap_or(true) {
+      latest = Some((
+        create_time,
+        m.get("id").unwrap().try_unwrap_string()?.clone(),
+      ));
+    }
+  }
+  Ok(latest.map(|x| x.1))
+}
+
 pub async fn lookup_query_script(ns_id: &str, qs_id: &str) -> Result<QueryScript> {
   let st = get_state();
   let res = st
@@ -95,6 +443,60 @@ pub async fn lookup_query_script(ns_id: &str, qs_id: &str) -> Result<QueryScript
   }
 }
 
+/// Returns the ids of the query scripts associated with a deployment.
+pub async fn list_query_scripts_for_deployment(
+  namespace_id: &str,
+  deployment_id: &str,
+) -> Result<Vec<String>> {
+  let st = get_state();
+  let res = st
+    .system_schema
+    .exec_ctx
+    .run_exported_graph(
+      &*st.system_store,
+      "list_query_script",
+      &[
+        SerializedVmValue::Null(None),
+        SerializedVmValue::String(namespace_id.into()),
+      ],
+      &Default::default(),
+    )
+    .await?;
+  let mut out = vec![];
+  for x in res.try_unwrap_list()? {
+    let m = x.try_unwrap_map(&["id", "associated_deployment"])?;
+    if m
+      .get("associated_deployment")
+      .unwrap()
+      .try_unwrap_string()?
+      == deployment_id
+    {
+      out.push(m.get("id").unwrap().try_unwrap_string()?.clone());
+    }
+  }
+  Ok(out)
+}
+
+pub async fn delete_query_script(namespace_id: &str, qs_id: &str) -> Result<bool> {
+  let st = get_state();
+  let res = st
+    .system_schema
+    .exec_ctx
+    .run_exported_graph(
+      &*st.system_store,
+      "delete_query_script",
+      &[
+        SerializedVmValue::Null(None),
+        SerializedVmValue::String(namespace_id.into()),
+        SerializedVmValue::String(qs_id.into()),
+      ],
+      &Default::default(),
+    )
+    .await?;
+  res.check_nonnull()?;
+  res.try_unwrap_bool()
+}
+
 pub async fn lookup_deployment(namespace_id: &str, deployment_id: &str) -> Result<Deployment> {
   let st = get_state();
   let res = st
@@ -121,7 +523,362 @@ pub async fn lookup_deployment(namespace_id: &str, deployment_id: &str) -> Resul
     description: res.get("description").unwrap().try_unwrap_string()?.clone(),
     schema: res.get("schema").unwrap().try_unwrap_string()?.clone(),
     plan: res.get("plan").unwrap().try_unwrap_bytes()?.clone(),
+    blobs: DeploymentBlobs::from_serialized_fields(res)?,
     create_time: res.get("create_time").unwrap().try_unwrap_int64()?,
   };
   Ok(depl)
 }
+
+pub async fn lookup_explorer_token(namespace_id: &str, token_id: &str) -> Result<ExplorerToken> {
+  let st = get_state();
+  let res = st
+    .system_schema
+    .exec_ctx
+    .run_exported_graph(
+      &*st.system_store,
+      "get_explorer_token",
+      &[
+        SerializedVmValue::Null(None),
+        SerializedVmValue::String(namespace_id.into()),
+        SerializedVmValue::String(token_id.into()),
+      ],
+      &VmValueEncodeConfig {
+        enable_bytes: true,
+        enable_double: true,
+        enable_int64: true,
+      },
+    )
+    .await?;
+  match res {
+    SerializedVmValue::Null(_) => Err(SysQueryError::ExplorerTokenNotFound.into()),
+    _ => ExplorerToken::from_serialized(&res),
+  }
+}
+
+pub async fn lookup_api_token(namespace_id: &str, token_id: &str) -> Result<ApiToken> {
+  let st = get_state();
+  let res = st
+    .system_schema
+    .exec_ctx
+    .run_exported_graph(
+      &*st.system_store,
+      "get_api_token",
+      &[
+        SerializedVmValue::Null(None),
+        SerializedVmValue::String(namespace_id.into()),
+        SerializedVmValue::String(token_id.into()),
+      ],
+      &VmValueEncodeConfig {
+        enable_bytes: true,
+        enable_double: true,
+        enable_int64: true,
+      },
+    )
+    .await?;
+  match res {
+    SerializedVmValue::Null(_) => Err(SysQueryError::ApiTokenNotFound.into()),
+    _ => ApiToken::from_serialized(&res),
+  }
+}
+
+/// Stores the structured representation of an existing deployment. Returns `false` if the
+/// deployment does not exist.
+pub async fn list_tokens() -> Result<Vec<Token>> {
+  let st = get_state();
+  let res = st
+    .system_schema
+    .exec_ctx
+    .run_exported_graph(
+      &*st.system_store,
+      "list_tokens",
+      &[SerializedVmValue::Null(None)],
+      &VmValueEncodeConfig {
+        enable_bytes: true,
+        enable_double: true,
+        enable_int64: true,
+      },
+    )
+    .await?;
+  res
+    .try_unwrap_list()?
+    .iter()
+    .map(Token::from_serialized)
+    .collect()
+}
+
+/// Removes a namespace from the namespaces and mounts of every token in the system store. Returns
+/// the ids of the updated tokens.
+pub async fn remove_namespace_from_tokens(namespace_id: &str) -> Result<Vec<String>> {
+  let st = get_state();
+  let mut updated = vec![];
+  for mut token in list_tokens().await? {
+    let len = token.namespaces.len() + token.mounts.len();
+    token.namespaces.retain(|x| x != namespace_id);
+    token.mounts.retain(|x| x != namespace_id);
+    if token.namespaces.len() + token.mounts.len() == len {
+      continue;
+    }
+    let res = st
+      .system_schema
+      .exec_ctx
+      .run_exported_graph(
+        &*st.system_store,
+        "set_token_scope",
+        &[
+          SerializedVmValue::Null(None),
+          SerializedVmValue::String(token.id.clone()),
+          SerializedVmValue::String(serde_json::to_string(&token.namespaces)?),
+          SerializedVmValue::String(serde_json::to_string(&token.mounts)?),
+        ],
+        &Default::default(),
+      )
+      .await?;
+    res.check_nonnull()?;
+    if res.try_unwrap_bool()? {
+      updated.push(token.id);
+    }
+  }
+  Ok(updated)
+}
+
+pub async fn set_deployment_blobs(
+  namespace_id: &str,
+  deployment_id: &str,
+  blobs: &DeploymentBlobs,
+) -> Result<bool> {
+  let st = get_state();
+  let res = st
+    .system_schema
+    .exec_ctx
+    .run_exported_graph(
+      &*st.system_store,
+      "set_deployment_blobs",
+      &[
+        SerializedVmValue::Null(None),
+        SerializedVmValue::String(namespace_id.into()),
+        SerializedVmValue::String(deployment_id.into()),
+        SerializedVmValue::Tagged(TaggedVmValue::M(blobs.to_serialized_fields())),
+      ],
+      &Default::default(),
+    )
+    .await?;
+  res.check_nonnull()?;
+  res.try_unwrap_bool()
+}
+
+/// Replaces the storage plan of an existing deployment. Returns `false` if the deployment does
+/// not exist.
+///
+/// The structured representation of the deployment must be updated afterwards, since it is only
+/// valid for the plan it was encoded with.
+pub async fn set_deployment_plan(
+  namespace_id: &str,
+  deployment_id: &str,
+  plan: &[u8],
+) -> Result<bool> {
+  let st = get_state();
+  let res = st
+    .system_schema
+    .exec_ctx
+    .run_exported_graph(
+      &*st.system_store,
+      "set_deployment_plan",
+      &[
+        SerializedVmValue::Null(None),
+        SerializedVmValue::String(namespace_id.into()),
+        SerializedVmValue::String(deployment_id.into()),
+        SerializedVmValue::String(base64::encode(plan)),
+      ],
+      &Default::default(),
+    )
+    .await?;
+  res.check_nonnull()?;
+  res.try_unwrap_bool()
+}
+
+pub async fn get_namespace_usage(namespace_id: &str) -> Result<NamespaceUsage> {
+  let st = get_state();
+  let res = st
+    .system_schema
+    .exec_ctx
+    .run_exported_graph(
+      &*st.system_store,
+      "get_namespace_usage",
+      &[
+        SerializedVmValue::Null(None),
+        SerializedVmValue::String(namespace_id.into()),
+      ],
+      &VmValueEncodeConfig {
+        enable_bytes: true,
+        enable_double: true,
+        enable_int64: true,
+      },
+    )
+    .await?;
+  match res {
+    SerializedVmValue::Null(_) => Err(SysQueryError::NamespaceNotFound.into()),
+    _ => NamespaceUsage::from_serialized(&res),
+  }
+}
+
+/// Adds to the stored usage counters of a namespace and returns the new ones. Returns `None` if
+/// the namespace does not exist.
+pub async fn add_namespace_usage(
+  namespace_id: &str,
+  bytes_read: u64,
+  bytes_written: u64,
+) -> Result<Option<NamespaceUsage>> {
+  let st = get_state();
+  let res = st
+    .system_schema
+    .exec_ctx
+    .run_exported_graph(
+      &*st.system_store,
+      "add_namespace_usage",
+      &[
+        SerializedVmValue::Null(None),
+        SerializedVmValue::String(namespace_id.into()),
+        SerializedVmValue::String(format!("{}", bytes_read)),
+        SerializedVmValue::String(format!("{}", bytes_written)),
+      ],
+      &VmValueEncodeConfig {
+        enable_bytes: true,
+        enable_double: true,
+        enable_int64: true,
+      },
+    )
+    .await?;
+  match res {
+    SerializedVmValue::Null(_) => Ok(None),
+    _ => NamespaceUsage::from_serialized(&res).map(Some),
+  }
+}
+
+/// Sets the write quota of a namespace. Zero removes the quota. Returns `false` if the namespace
+/// does not exist.
+pub async fn set_namespace_quota(namespace_id: &str, write_quota_bytes: u64) -> Result<bool> {
+  let st = get_state();
+  let res = st
+    .system_schema
+    .exec_ctx
+    .run_exported_graph(
+      &*st.system_store,
+      "set_namespace_quota",
+      &[
+        SerializedVmValue::Null(None),
+        SerializedVmValue::String(namespace_id.into()),
+        SerializedVmValue::String(format!("{}", write_quota_bytes)),
+      ],
+      &Default::default(),
+    )
+    .await?;
+  res.check_nonnull()?;
+  res.try_unwrap_bool()
+}
+
+/// The id strategy of a namespace. `None` if the namespace uses the default strategy of the
+/// server.
+pub async fn get_namespace_id_strategy(namespace_id: &str) -> Result<Option<IdStrategy>> {
+  let st = get_state();
+  let res = st
+    .system_schema
+    .exec_ctx
+    .run_exported_graph(
+      &*st.system_store,
+      "get_namespace_id_strategy",
+      &[
+        SerializedVmValue::Null(None),
+        SerializedVmValue::String(namespace_id.into()),
+      ],
+      &Default::default(),
+    )
+    .await?;
+  match res {
+    SerializedVmValue::Null(_) => Err(SysQueryError::NamespaceNotFound.into()),
+    _ => Ok(IdStrategy::parse_namespace_strategy(
+      res.try_unwrap_string()?,
+    )?),
+  }
+}
+
+/// Sets the id strategy of a namespace. `None` reverts to the default strategy of the server.
+/// Returns `false` if the namespace does not exist.
+pub async fn set_namespace_id_strategy(
+  namespace_id: &str,
+  strategy: Option<IdStrategy>,
+) -> Result<bool> {
+  let st = get_state();
+  let res = st
+    .system_schema
+    .exec_ctx
+    .run_exported_graph(
+      &*st.system_store,
+      "set_namespace_id_strategy",
+      &[
+        SerializedVmValue::Null(None),
+        SerializedVmValue::String(namespace_id.into()),
+        SerializedVmValue::String(strategy.map(|x| x.as_str()).unwrap_or("").into()),
+      ],
+      &Default::default(),
+    )
+    .await?;
+  res.check_nonnull()?;
+  res.try_unwrap_bool()
+}
+
+pub async fn get_deployment_routing(namespace_id: &str) -> Result<DeploymentRouting> {
+  let st = get_state();
+  let res = st
+    .system_schema
+    .exec_ctx
+    .run_exported_graph(
+      &*st.system_store,
+      "get_deployment_routing",
+      &[
+        SerializedVmValue::Null(None),
+        SerializedVmValue::String(namespace_id.into()),
+      ],
+      &VmValueEncodeConfig {
+        enable_bytes: true,
+        enable_double: true,
+        enable_int64: true,
+      },
+    )
+    .await?;
+  match res {
+    SerializedVmValue::Null(_) => Err(SysQueryError::NamespaceNotFound.into()),
+    _ => DeploymentRouting::from_serialized(&res),
+  }
+}
+
+/// Replaces the deployment routing of a namespace. Returns `false` if the namespace does not
+/// exist, and fails with `RoutingError::DeploymentNotFound` if a deployment it names does not.
+pub async fn set_deployment_routing(
+  namespace_id: &str,
+  routing: &DeploymentRouting,
+) -> Result<bool> {
+  let st = get_state();
+  let res = st
+    .system_schema
+    .exec_ctx
+    .run_exported_graph(
+      &*st.system_store,
+      "set_deployment_routing",
+      &[
+        SerializedVmValue::Null(None),
+        SerializedVmValue::String(namespace_id.into()),
+        SerializedVmValue::Tagged(TaggedVmValue::M(routing.to_serialized_fields())),
+      ],
+      &VmValueEncodeConfig {
+        enable_bytes: true,
+        enable_double: true,
+        enable_int64: true,
+      },
+    )
+    .await?;
+  match res.try_unwrap_int64()? {
+    0 => Ok(true),
+    1 => Ok(false),
+    _ => Err(RoutingError::DeploymentNotFound.into()),
+  }
+}
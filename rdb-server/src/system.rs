@@ -1,16 +1,20 @@
 use std::sync::Arc;
 
+use anyhow::Result;
 use bumpalo::Bump;
 use console::Style;
 use rdb_analyzer::{
-  data::kv::KeyValueStore,
+  data::{kv::KeyValueStore, treewalker::inline::InlineConfig},
   schema::{compile::compile, grammar::parse},
   storage_plan::{planner::generate_plan_for_schema, StoragePlan},
 };
 use sha2::{Digest, Sha256};
 use similar::{ChangeTag, TextDiff};
 
-use crate::exec_core::{ExecContext, SchemaContext};
+use crate::{
+  exec_core::{ExecContext, SchemaContext},
+  system_migration::{latest_version, read_version, record_version, run_pending_migrations},
+};
 
 pub struct SystemSchema {
   pub exec_ctx: ExecContext,
@@ -19,12 +23,26 @@ pub struct SystemSchema {
 pub const SCHEMA: &str = include_str!("./system_schema.rschema");
 pub const SYS_RASM: &str = include_str!("./sys.rasm");
 
+/// The `--migration-hash` that confirms a migration of the system schema to the one of this build.
+pub fn system_migration_hash() -> String {
+  let mut hasher = Sha256::new();
+
+  // XXX: Plan may contain randomly generated data and we only know that the schema doesn't change across restarts
+  hasher.update(SCHEMA.as_bytes());
+  hex::encode(&hasher.finalize()[..])
+}
+
 impl SystemSchema {
+  /// Brings the system schema and plan in `meta_store` up to date with this build, then runs the
+  /// pending data migrations of `system_migration` on `store`.
+  ///
+  /// Fails if the system store was migrated by a newer server.
   pub async fn new(
     migration_hash: Option<String>,
-    _store: &dyn KeyValueStore,
+    store: &dyn KeyValueStore,
     meta_store: &dyn KeyValueStore,
-  ) -> Self {
+    inline_config: &InlineConfig,
+  ) -> Result<Self> {
     let schema = compile(&parse(&Bump::new(), SCHEMA).unwrap()).unwrap();
     let txn = meta_store.begin_transaction().await.unwrap();
     let old_schema_text = txn
@@ -34,6 +52,9 @@ impl SystemSchema {
       .map(|x| String::from_utf8(x))
       .transpose()
       .unwrap();
+
+    // Checked first, so that the schema of a newer server is not taken for a migration.
+    let version = read_version(&*txn, old_schema_text.is_some()).await?;
     let old_plan = txn
       .get(b"plan")
       .await
@@ -52,11 +73,7 @@ impl SystemSchema {
 
       if old_schema_text.as_str() != SCHEMA || old_plan_serialized != new_plan_serialized {
         // Migration required
-        let mut hasher = Sha256::new();
-
-        // XXX: Plan may contain randomly generated data and we only know that the schema doesn't change across restarts
-        hasher.update(SCHEMA.as_bytes());
-        let hash = hex::encode(&hasher.finalize()[..]);
+        let hash = system_migration_hash();
         if migration_hash != Some(hash.clone()) {
           print_diff(&old_plan, &new_plan);
           log::error!("Schema change detected. Please check the storage plan diff and rerun the server with `--migration-hash={}`.", hash);
@@ -83,13 +100,19 @@ impl SystemSchema {
         .put(b"plan", &new_plan.serialize_compressed().unwrap())
         .await
         .unwrap();
+      record_version(&*txn, latest_version(), "create system store").await?;
       txn.commit().await.unwrap();
       new_plan
     };
 
-    let exec_ctx = ExecContext::load(Arc::new(SchemaContext { schema, plan }), SYS_RASM).unwrap();
+    let schema_ctx = Arc::new(SchemaContext { schema, plan });
+    if let Some(version) = version {
+      run_pending_migrations(version, store, meta_store, &schema_ctx).await?;
+    }
+    let exec_ctx =
+      ExecContext::load_with_inline_config(schema_ctx, SYS_RASM, inline_config).unwrap();
 
-    Self { exec_ctx }
+    Ok(Self { exec_ctx })
   }
 }
 
@@ -0,0 +1,142 @@
+//! Versioned migrations of the data in the system store.
+//!
+//! Changes to the system schema reach the storage plan in `SystemSchema::new`, after they are
+//! confirmed with `--migration-hash`. Changes that also need existing data to be rewritten add a
+//! step to `MIGRATIONS`: the i-th step brings the system store from version
+//! `BASELINE_VERSION + i` to `BASELINE_VERSION + i + 1`.
+//!
+//! The version of the system store and a history of the versions applied to it are kept in the
+//! system metadata store. Each step runs in one transaction on the system store, and the new
+//! version is recorded after that transaction commits. A server that stops in between runs the
+//! step again on its next start, so steps must be idempotent.
+
+use std::convert::TryInto;
+
+use anyhow::Result;
+use futures::future::BoxFuture;
+use rdb_analyzer::data::kv::{KeyValueStore, KvTransaction};
+use serde::{Deserialize, Serialize};
+use thiserror::Error;
+
+use crate::{exec_core::SchemaContext, util::current_millis};
+
+/// The version of system stores created before versions were recorded.
+const BASELINE_VERSION: u64 = 1;
+
+const VERSION_KEY: &[u8] = b"version";
+const HISTORY_PREFIX: &[u8] = b"version_history\x00";
+
+/// Steps after `BASELINE_VERSION`, in order. Steps are only ever appended.
+const MIGRATIONS: &[SystemMigration] = &[];
+
+pub struct SystemMigration {
+  pub description: &'static str,
+
+  /// Called with a transaction on the system store, which is committed after the step, and the
+  /// current system schema and plan.
+  pub run: for<'a> fn(&'a dyn KvTransaction, &'a SchemaContext) -> BoxFuture<'a, Result<()>>,
+}
+
+#[derive(Error, Debug)]
+pub enum SystemMigrationError {
+  #[error("the system store is at version {stored}, but this server only knows versions up to {supported}; refusing to start")]
+  UnknownVersion { stored: u64, supported: u64 },
+
+  #[error("bad system store version")]
+  BadVersion,
+}
+
+/// An entry of the version history.
+#[derive(Serialize, Deserialize, Debug)]
+pub struct AppliedVersion {
+  pub version: u64,
+  pub description: String,
+  pub applied_at: u64,
+  pub server_version: String,
+}
+
+/// The version of the system store that this build migrates to.
+pub fn latest_version() -> u64 {
+  BASELINE_VERSION + MIGRATIONS.len() as u64
+}
+
+/// Reads the version of the system store from a metadata transaction. `None` if the store is new,
+/// i.e. has no system schema yet.
+///
+/// Fails with `SystemMigrationError::UnknownVersion` if the store was migrated by a newer server.
+pub async fn read_version(meta_txn: &dyn KvTransaction, has_schema: bool) -> Result<Option<u64>> {
+  let version = match meta_txn.get(VERSION_KEY).await? {
+    Some(x) => decode_version(&x)?,
+    None if has_schema => BASELINE_VERSION,
+    None => return Ok(None),
+  };
+  if version > latest_version() {
+    return Err(
+      SystemMigrationError::UnknownVersion {
+        stored: version,
+        supported: latest_version(),
+      }
+      .into(),
+    );
+  }
+  Ok(Some(version))
+}
+
+/// Sets the version of the system store and appends it to the history.
+pub async fn record_version(
+  meta_txn: &dyn KvTransaction,
+  version: u64,
+  description: &str,
+) -> Result<()> {
+  let entry = AppliedVersion {
+    version,
+    description: description.to_string(),
+    applied_at: current_millis(),
+    server_version: env!("CARGO_PKG_VERSION").to_string(),
+  };
+  let mut history_key = HISTORY_PREFIX.to_vec();
+  history_key.extend_from_slice(&version.to_be_bytes());
+  meta_txn
+    .put(&history_key, &serde_json::to_vec(&entry)?)
+    .await?;
+  meta_txn.put(VERSION_KEY, &version.to_be_bytes()).await?;
+  Ok(())
+}
+
+/// Runs the steps after `version`, in order.
+pub async fn run_pending_migrations(
+  version: u64,
+  store: &dyn KeyValueStore,
+  meta_store: &dyn KeyValueStore,
+  schema: &SchemaContext,
+) -> Result<()> {
+  for (i, step) in MIGRATIONS
+    .iter()
+    .enumerate()
+    .skip((version - BASELINE_VERSION) as usize)
+  {
+    let target = BASELINE_VERSION + i as u64 + 1;
+    log::warn!(
+      "Migrating system store to version {}: {}",
+      target,
+      step.description
+    );
+    let txn = store.begin_transaction().await?;
+    (step.run)(&*txn, schema).await?;
+    txn.commit().await?;
+
+    // Another server may have run the same step concurrently.
+    let meta_txn = meta_store.begin_transaction().await?;
+    if read_version(&*meta_txn, true).await? < Some(target) {
+      record_version(&*meta_txn, target, step.description).await?;
+      meta_txn.commit().await?;
+    }
+  }
+  log::info!("System store at version {}.", latest_version());
+  Ok(())
+}
+
+fn decode_version(x: &[u8]) -> Result<u64> {
+  let x: [u8; 8] = x.try_into().map_err(|_| SystemMigrationError::BadVersion)?;
+  Ok(u64::from_be_bytes(x))
+}
@@ -0,0 +1,96 @@
+use std::time::Duration;
+
+use anyhow::Result;
+use rdb_analyzer::{
+  data::treewalker::{bytecode::TwScript, exec::Executor, typeck::GlobalTyckContext, vm::TwVm},
+  schema::compile::FieldType,
+  storage_plan::StoragePlan,
+};
+use tokio::time::sleep;
+
+use crate::{
+  changelog::open_with_changelog,
+  schema_cache::load_compiled_schema,
+  state::get_state,
+  sysquery::{
+    latest_deployment_id, list_namespace_ids, lookup_deployment, ns_to_kv_prefix_with_appended_zero,
+  },
+};
+
+/// Periodically deletes the expired members of exported sets whose member type has a `@ttl`
+/// field.
+///
+/// Queries already skip expired members, so this only reclaims their space. Each namespace is
+/// reaped with its most recently created deployment. Members are deleted `batch_size` at a time,
+/// each batch in its own transaction, so that reaping a large set neither holds a long
+/// transaction nor conflicts much with queries.
+pub fn spawn_ttl_reaper(interval: Duration, batch_size: usize) {
+  tokio::spawn(async move {
+    loop {
+      sleep(interval).await;
+      let namespace_ids = match list_namespace_ids().await {
+        Ok(x) => x,
+        Err(e) => {
+          log::error!("ttl reaper: cannot list namespaces: {:?}", e);
+          continue;
+        }
+      };
+      for namespace_id in &namespace_ids {
+        if let Err(e) = reap_namespace(namespace_id, batch_size).await {
+          log::error!("ttl reaper: namespace {}: {:?}", namespace_id, e);
+        }
+      }
+    }
+  });
+}
+
+async fn reap_namespace(namespace_id: &str, batch_size: usize) -> Result<()> {
+  let st = get_state();
+  let deployment_id = match latest_deployment_id(namespace_id).await? {
+    Some(x) => x,
+    None => return Ok(()),
+  };
+  let deployment = lookup_deployment(namespace_id, &deployment_id).await?;
+  let schema = load_compiled_schema(namespace_id, &deployment).await?;
+  let sets = schema
+    .exports
+    .iter()
+    .filter(|(_, ty)| match ty {
+      FieldType::Set(x) => match &**x {
+        FieldType::Table(x) => schema.types.get(x).map(|x| x.ttl.is_some()) == Some(true),
+        _ => false,
+      },
+      _ => false,
+    })
+    .map(|(name, _)| name.clone())
+    .collect::<Vec<_>>();
+  if sets.is_empty() {
+    return Ok(());
+  }
+
+  let plan = StoragePlan::deserialize_compressed(&deployment.plan)?;
+  let script = TwScript::default();
+  let vm = TwVm::new(&schema, &plan, &script)?;
+  let type_info = GlobalTyckContext::new(&vm)?.typeck()?;
+  let kv_prefix = ns_to_kv_prefix_with_appended_zero(namespace_id).await?;
+  let kv = if st.changelog_enabled {
+    open_with_changelog(&kv_prefix)
+  } else {
+    (st.data_store_generator)(&kv_prefix)
+  };
+  let mut executor = Executor::new(&vm, &*kv, &type_info);
+  executor.set_sleep_fn(|x| Box::pin(sleep(x)));
+  executor.set_retry_policy(st.retry_policy.clone());
+  for name in &sets {
+    let n = executor.reap_expired(name, batch_size).await?;
+    if n != 0 {
+      log::info!(
+        "ttl reaper: deleted {} expired members of `{}` in namespace {}.",
+        n,
+        name,
+        namespace_id
+      );
+    }
+  }
+  Ok(())
+}
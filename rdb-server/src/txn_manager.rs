@@ -0,0 +1,144 @@
+use std::{
+  collections::HashMap,
+  sync::{Arc, Weak},
+  time::{Duration, Instant},
+};
+
+use anyhow::Result;
+use rdb_analyzer::data::{kv::KvTransaction, treewalker::exec::Change};
+use thiserror::Error;
+use tokio::{
+  sync::{Mutex, MutexGuard},
+  time::sleep,
+};
+
+#[derive(Error, Debug)]
+pub enum TxnManagerError {
+  #[error("transaction not found or expired: `{0}`")]
+  TransactionNotFound(String),
+
+  #[error("too many open transactions")]
+  TooManyTransactions,
+}
+
+/// Transactions opened with `beginTransaction`, spanning multiple RPCs.
+///
+/// A transaction is rolled back if it is not committed before its deadline.
+pub struct TxnManager {
+  txns: Mutex<HashMap<String, Arc<OpenTxn>>>,
+  params: TxnManagerParams,
+}
+
+#[derive(Clone, Debug)]
+pub struct TxnManagerParams {
+  pub timeout: Duration,
+  pub max_open_txns: usize,
+}
+
+pub struct OpenTxn {
+  pub id: String,
+  pub namespace_id: String,
+  deadline: Instant,
+  state: Mutex<OpenTxnState>,
+}
+
+pub struct OpenTxnState {
+  /// `None` after the transaction is committed or rolled back by another request.
+  pub txn: Option<Box<dyn KvTransaction>>,
+
+  /// Whether any graph that is not read-only has been run in the transaction.
+  pub wrote: bool,
+
+  /// Changes to publish to subscribers on commit.
+  pub changes: Vec<Change>,
+}
+
+impl TxnManager {
+  pub fn new(params: TxnManagerParams) -> Arc<Self> {
+    let me = Arc::new(Self {
+      txns: Mutex::new(HashMap::new()),
+      params,
+    });
+    let me_weak = Arc::downgrade(&me);
+    tokio::spawn(async move {
+      Self::gc(me_weak).await;
+    });
+    me
+  }
+
+  /// Registers `txn` and returns its id.
+  pub async fn begin(&self, namespace_id: &str, txn: Box<dyn KvTransaction>) -> Result<String> {
+    let mut txns = self.txns.lock().await;
+    if txns.len() >= self.params.max_open_txns {
+      return Err(TxnManagerError::TooManyTransactions.into());
+    }
+    let id = uuid::Uuid::new_v4().to_string();
+    txns.insert(
+      id.clone(),
+      Arc::new(OpenTxn {
+        id: id.clone(),
+        namespace_id: namespace_id.to_string(),
+        deadline: Instant::now() + self.params.timeout,
+        state: Mutex::new(OpenTxnState {
+          txn: Some(txn),
+          wrote: false,
+          changes: vec![],
+        }),
+      }),
+    );
+    Ok(id)
+  }
+
+  pub async fn get(&self, id: &str) -> Result<Arc<OpenTxn>> {
+    let txns = self.txns.lock().await;
+    match txns.get(id) {
+      Some(x) if x.deadline > Instant::now() => Ok(x.clone()),
+      _ => Err(TxnManagerError::TransactionNotFound(id.to_string()).into()),
+    }
+  }
+
+  /// Removes a transaction, so that it can be committed or rolled back.
+  pub async fn take(&self, id: &str) -> Result<Arc<OpenTxn>> {
+    let mut txns = self.txns.lock().await;
+    match txns.remove(id) {
+      Some(x) if x.deadline > Instant::now() => Ok(x),
+      _ => Err(TxnManagerError::TransactionNotFound(id.to_string()).into()),
+    }
+  }
+
+  async fn gc(me: Weak<Self>) {
+    loop {
+      sleep(Duration::from_secs(1)).await;
+      let me = match me.upgrade() {
+        Some(x) => x,
+        None => {
+          log::warn!("gc: exiting");
+          break;
+        }
+      };
+
+      // Dropping an uncommitted transaction rolls it back.
+      let now = Instant::now();
+      let mut txns = me.txns.lock().await;
+      let count = txns.len();
+      txns.retain(|_, x| x.deadline > now);
+      if txns.len() != count {
+        log::info!(
+          "gc: Rolled back {} expired transaction(s).",
+          count - txns.len()
+        );
+      }
+    }
+  }
+}
+
+impl OpenTxn {
+  /// Locks the transaction. Requests on the same transaction are serialized.
+  pub async fn lock(&self) -> Result<MutexGuard<'_, OpenTxnState>> {
+    let state = self.state.lock().await;
+    if state.txn.is_none() {
+      return Err(TxnManagerError::TransactionNotFound(self.id.clone()).into());
+    }
+    Ok(state)
+  }
+}
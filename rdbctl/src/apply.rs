@@ -0,0 +1,449 @@
+//! Declarative sync of namespaces, schemas and query scripts from a project file.
+//!
+//! A project file lists namespaces, each with a schema file and the query scripts it should have:
+//!
+//! ```yaml
+//! namespaces:
+//!   - id: shop
+//!     schema: shop.rschema
+//!     description: optional, for new deployments
+//!     query_scripts:
+//!       - id: orders
+//!         script: scripts/orders.rasm
+//! ```
+//!
+//! Paths are relative to the project file. `apply` compares the file with the server and plans
+//! the changes that make them match:
+//!
+//! - Namespaces that do not exist are created. Namespaces not in the file are left alone.
+//! - If the schema differs from that of the latest deployment of a namespace, a deployment is
+//!   created, with its storage plan migrated from the latest deployment.
+//! - Query scripts are created, or updated if their source differs or they are associated with
+//!   another deployment than the latest one. Query scripts of a namespace that are not in the file
+//!   are deleted.
+//!
+//! Deployments are never deleted, as the data of a namespace may still be stored with their
+//! plans. Query scripts are type checked locally before any change is made.
+
+use std::{
+  collections::BTreeMap,
+  convert::TryFrom,
+  path::{Path, PathBuf},
+};
+
+use anyhow::{Context, Result};
+use bumpalo::Bump;
+use clap::Clap;
+use console::style;
+use dialoguer::{theme::ColorfulTheme, Confirm};
+use rdb_analyzer::{
+  schema::{compile::compile, format::format_schema, grammar::parse, lint::lint},
+  storage_plan::{planner::generate_plan_for_schema, StorageKey, StoragePlan},
+};
+use rdb_proto::{
+  proto::{
+    rdb_control_client::RdbControlClient, CreateDeploymentRequest, CreateNamespaceRequest,
+    CreateQueryScriptRequest, DeleteQueryScriptRequest, GetDeploymentRequest,
+    GetQueryScriptRequest, ListDeploymentRequest, ListNamespaceRequest, ListQueryScriptRequest,
+  },
+  tonic::{transport::Channel, Request},
+};
+use serde::Deserialize;
+use tokio::task::block_in_place;
+
+use crate::{bundle::check_query_script, diff::print_diff, CliError};
+
+#[derive(Clap)]
+pub struct Apply {
+  /// Path to the project file.
+  #[clap(short, long)]
+  file: String,
+
+  /// Print the plan without applying it.
+  #[clap(long)]
+  dry_run: bool,
+
+  /// Apply the plan without asking for confirmation.
+  #[clap(long)]
+  yes: bool,
+
+  /// Create deployments even if the server rejects data-lossy schema changes.
+  #[clap(long)]
+  allow_lossy: bool,
+}
+
+#[derive(Deserialize)]
+#[serde(deny_unknown_fields)]
+struct Project {
+  namespaces: Vec<NamespaceSpec>,
+}
+
+#[derive(Deserialize)]
+#[serde(deny_unknown_fields)]
+struct NamespaceSpec {
+  id: String,
+  schema: PathBuf,
+
+  #[serde(default)]
+  description: String,
+
+  #[serde(default)]
+  query_scripts: Vec<QueryScriptSpec>,
+}
+
+#[derive(Deserialize)]
+#[serde(deny_unknown_fields)]
+struct QueryScriptSpec {
+  id: String,
+  script: PathBuf,
+}
+
+/// Changes to one namespace.
+struct NamespacePlan<'a> {
+  spec: &'a NamespaceSpec,
+  create_namespace: bool,
+  deployment: DeploymentChange,
+
+  /// Query scripts to create or update, by id, with their sources.
+  put_scripts: BTreeMap<String, (ScriptChange, String)>,
+  delete_scripts: Vec<String>,
+}
+
+enum DeploymentChange {
+  /// The latest deployment has the schema of the project.
+  Keep(String),
+
+  /// A deployment is created, migrated from the latest deployment if there is one.
+  Create {
+    schema: String,
+    plan: StoragePlan,
+    migrate_from: Option<String>,
+  },
+}
+
+#[derive(Copy, Clone)]
+enum ScriptChange {
+  Create,
+  Update,
+}
+
+impl NamespacePlan<'_> {
+  fn is_empty(&self) -> bool {
+    !self.create_namespace
+      && matches!(self.deployment, DeploymentChange::Keep(_))
+      && self.put_scripts.is_empty()
+      && self.delete_scripts.is_empty()
+  }
+
+  fn print(&self) {
+    let ns = &self.spec.id;
+    if self.create_namespace {
+      eprintln!("{} namespace `{}`", style("+").green(), ns);
+    }
+    match &self.deployment {
+      DeploymentChange::Keep(_) => {}
+      DeploymentChange::Create { migrate_from, .. } => match migrate_from {
+        Some(x) => eprintln!(
+          "{} deployment of `{}` from {}, migrated from `{}`",
+          style("+").green(),
+          ns,
+          self.spec.schema.display(),
+          x
+        ),
+        None => eprintln!(
+          "{} deployment of `{}` from {}",
+          style("+").green(),
+          ns,
+          self.spec.schema.display()
+        ),
+      },
+    }
+    for (id, (change, _)) in &self.put_scripts {
+      match change {
+        ScriptChange::Create => eprintln!("{} query script `{}/{}`", style("+").green(), ns, id),
+        ScriptChange::Update => eprintln!("{} query script `{}/{}`", style("~").yellow(), ns, id),
+      }
+    }
+    for id in &self.delete_scripts {
+      eprintln!("{} query script `{}/{}`", style("-").red(), ns, id);
+    }
+  }
+}
+
+pub async fn run_apply(client: &mut RdbControlClient<Channel>, opts: &Apply) -> Result<()> {
+  let project_text = std::fs::read_to_string(&opts.file)?;
+  let project: Project = serde_yaml::from_str(&project_text)
+    .with_context(|| format!("invalid project file {}", opts.file))?;
+  let base_dir = Path::new(&opts.file)
+    .parent()
+    .unwrap_or_else(|| Path::new(""));
+
+  let existing_namespaces = client
+    .list_namespace(Request::new(ListNamespaceRequest {}))
+    .await?
+    .into_inner()
+    .namespaces
+    .into_iter()
+    .map(|x| x.id)
+    .collect::<Vec<_>>();
+
+  let mut plans = vec![];
+  let mut invalid_scripts = vec![];
+  for spec in &project.namespaces {
+    let exists = existing_namespaces.contains(&spec.id);
+    let (plan, invalid) = plan_namespace(client, base_dir, spec, exists).await?;
+    plans.push(plan);
+    invalid_scripts.extend(invalid);
+  }
+  if !invalid_scripts.is_empty() {
+    return Err(CliError::InvalidQueryScripts(invalid_scripts).into());
+  }
+
+  if plans.iter().all(|x| x.is_empty()) {
+    eprintln!("No changes.");
+    return Ok(());
+  }
+  for plan in &plans {
+    plan.print();
+  }
+  if opts.dry_run {
+    return Ok(());
+  }
+  if !opts.yes {
+    let proceed = block_in_place(|| {
+      Confirm::with_theme(&ColorfulTheme::default())
+        .with_prompt("Do you wish to apply these changes?")
+        .interact()
+    })?;
+    if !proceed {
+      return Err(CliError::AbortedByUser.into());
+    }
+  }
+
+  for plan in &plans {
+    apply_namespace(client, plan, opts.allow_lossy)
+      .await
+      .with_context(|| format!("cannot apply changes to namespace `{}`", plan.spec.id))?;
+  }
+  eprintln!("Applied.");
+  Ok(())
+}
+
+/// Plans the changes to a namespace. Also returns the ids of query scripts that do not type check
+/// against the schema of the project.
+async fn plan_namespace<'a>(
+  client: &mut RdbControlClient<Channel>,
+  base_dir: &Path,
+  spec: &'a NamespaceSpec,
+  exists: bool,
+) -> Result<(NamespacePlan<'a>, Vec<String>)> {
+  let schema_text = std::fs::read_to_string(base_dir.join(&spec.schema))
+    .with_context(|| format!("cannot read schema {}", spec.schema.display()))?;
+  let new_schema = compile(&parse(&Bump::new(), &schema_text)?)?;
+  for x in lint(&new_schema) {
+    log::warn!("{}: {}", spec.id, x);
+  }
+
+  let latest = if exists {
+    client
+      .list_deployment(Request::new(ListDeploymentRequest {
+        namespace_id: spec.id.clone(),
+      }))
+      .await?
+      .into_inner()
+      .deployments
+      .into_iter()
+      .rev()
+      .max_by_key(|x| x.create_time)
+  } else {
+    None
+  };
+  let latest = match latest {
+    Some(x) => {
+      client
+        .get_deployment(Request::new(GetDeploymentRequest {
+          namespace_id: spec.id.clone(),
+          deployment_id: x.id,
+        }))
+        .await?
+        .into_inner()
+        .info
+    }
+    None => None,
+  };
+
+  let (deployment, plan) = match latest {
+    Some(info) if same_schema(&info.schema, &schema_text) => {
+      let plan: StoragePlan<String> = serde_yaml::from_str(&info.plan)?;
+      (
+        DeploymentChange::Keep(info.id),
+        StoragePlan::<StorageKey>::try_from(&plan)?,
+      )
+    }
+    Some(info) => {
+      let old_schema = compile(&parse(&Bump::new(), &info.schema)?)?;
+      let old_plan: StoragePlan<String> = serde_yaml::from_str(&info.plan)?;
+      let old_plan = StoragePlan::<StorageKey>::try_from(&old_plan)?;
+      let plan = generate_plan_for_schema(&old_plan, &old_schema, &new_schema)?;
+      eprintln!("Storage plan changes of `{}`:", spec.id);
+      print_diff(&old_plan, &plan);
+      (
+        DeploymentChange::Create {
+          schema: schema_text,
+          plan: plan.clone(),
+          migrate_from: Some(info.id),
+        },
+        plan,
+      )
+    }
+    None => {
+      let plan = generate_plan_for_schema(&Default::default(), &Default::default(), &new_schema)?;
+      (
+        DeploymentChange::Create {
+          schema: schema_text,
+          plan: plan.clone(),
+          migrate_from: None,
+        },
+        plan,
+      )
+    }
+  };
+
+  let mut invalid_scripts = vec![];
+  let mut desired = BTreeMap::new();
+  for x in &spec.query_scripts {
+    let source = std::fs::read_to_string(base_dir.join(&x.script))
+      .with_context(|| format!("cannot read query script {}", x.script.display()))?;
+    if !check_query_script(&new_schema, &plan, &x.id, &source) {
+      invalid_scripts.push(format!("{}/{}", spec.id, x.id));
+    }
+    if desired.insert(x.id.clone(), source).is_some() {
+      return Err(CliError::DuplicateQueryScript(spec.id.clone(), x.id.clone()).into());
+    }
+  }
+
+  let existing_scripts = if exists {
+    client
+      .list_query_script(Request::new(ListQueryScriptRequest {
+        namespace_id: spec.id.clone(),
+      }))
+      .await?
+      .into_inner()
+      .query_scripts
+  } else {
+    vec![]
+  };
+  let mut put_scripts = BTreeMap::new();
+  let mut delete_scripts = vec![];
+  for x in &existing_scripts {
+    let source = match desired.remove(&x.id) {
+      Some(x) => x,
+      None => {
+        delete_scripts.push(x.id.clone());
+        continue;
+      }
+    };
+    let up_to_date = match &deployment {
+      DeploymentChange::Keep(id) if *id == x.associated_deployment => {
+        let info = client
+          .get_query_script(Request::new(GetQueryScriptRequest {
+            namespace_id: spec.id.clone(),
+            query_script_id: x.id.clone(),
+          }))
+          .await?
+          .into_inner()
+          .info;
+        info.map(|x| x.script == source).unwrap_or(false)
+      }
+      _ => false,
+    };
+    if !up_to_date {
+      put_scripts.insert(x.id.clone(), (ScriptChange::Update, source));
+    }
+  }
+  for (id, source) in desired {
+    put_scripts.insert(id, (ScriptChange::Create, source));
+  }
+  delete_scripts.sort();
+
+  Ok((
+    NamespacePlan {
+      spec,
+      create_namespace: !exists,
+      deployment,
+      put_scripts,
+      delete_scripts,
+    },
+    invalid_scripts,
+  ))
+}
+
+async fn apply_namespace(
+  client: &mut RdbControlClient<Channel>,
+  plan: &NamespacePlan<'_>,
+  allow_lossy: bool,
+) -> Result<()> {
+  let ns = &plan.spec.id;
+  if plan.create_namespace {
+    client
+      .create_namespace(Request::new(CreateNamespaceRequest { id: ns.clone() }))
+      .await?;
+  }
+  let deployment_id = match &plan.deployment {
+    DeploymentChange::Keep(id) => id.clone(),
+    DeploymentChange::Create {
+      schema,
+      plan: storage_plan,
+      migrate_from,
+    } => {
+      let res = client
+        .create_deployment(Request::new(CreateDeploymentRequest {
+          namespace_id: ns.clone(),
+          schema: schema.clone(),
+          plan: serde_yaml::to_string(&StoragePlan::<String>::from(storage_plan))?,
+          description: plan.spec.description.clone(),
+          migrate_from: migrate_from.clone().unwrap_or_default(),
+          migration_script: String::new(),
+          dry_run: false,
+          allow_lossy,
+        }))
+        .await?;
+      let id = res
+        .into_inner()
+        .deployment_id
+        .ok_or(CliError::DeploymentNotCreated)?
+        .id;
+      log::info!("Created deployment `{}` of namespace `{}`.", id, ns);
+      id
+    }
+  };
+  for (id, (_, source)) in &plan.put_scripts {
+    client
+      .create_query_script(Request::new(CreateQueryScriptRequest {
+        namespace_id: ns.clone(),
+        id: id.clone(),
+        associated_deployment: deployment_id.clone(),
+        script: source.clone(),
+      }))
+      .await
+      .with_context(|| format!("cannot create query script `{}`", id))?;
+  }
+  for id in &plan.delete_scripts {
+    client
+      .delete_query_script(Request::new(DeleteQueryScriptRequest {
+        namespace_id: ns.clone(),
+        id: id.clone(),
+      }))
+      .await
+      .with_context(|| format!("cannot delete query script `{}`", id))?;
+  }
+  Ok(())
+}
+
+/// Whether two schema sources are the same, ignoring formatting.
+fn same_schema(a: &str, b: &str) -> bool {
+  match (format_schema(a), format_schema(b)) {
+    (Ok(a), Ok(b)) => a == b,
+    _ => a == b,
+  }
+}
@@ -0,0 +1,300 @@
+use std::{
+  collections::BTreeMap,
+  sync::Arc,
+  time::{Duration, Instant},
+};
+
+use anyhow::Result;
+use clap::Clap;
+use hyper::{client::HttpConnector, Body, Client, Method, Request, Uri};
+use serde::Serialize;
+use tokio::sync::Mutex;
+
+/// Response header carrying the KV operation counts of a query. Only set by servers started with
+/// `--enable-kv-profiling`.
+const KV_OPS_HEADER: &str = "X-Rdb-Kv-Ops";
+
+/// Upper bounds of latency histogram buckets, in milliseconds.
+const HISTOGRAM_BOUNDS_MS: &[u64] = &[1, 2, 5, 10, 20, 50, 100, 200, 500, 1000, 2000, 5000];
+
+#[derive(Clap)]
+pub struct Bench {
+  #[clap(subcommand)]
+  subcmd: BenchCommand,
+}
+
+#[derive(Clap)]
+enum BenchCommand {
+  /// Run an exported graph repeatedly and report its latency.
+  RunQuery(RunQuery),
+}
+
+#[derive(Clap)]
+struct RunQuery {
+  /// HTTP API URL of the server.
+  #[clap(long)]
+  http: String,
+
+  /// Namespace id.
+  #[clap(long)]
+  namespace: String,
+
+  /// Query script id.
+  #[clap(long)]
+  script: String,
+
+  /// Name of the exported graph.
+  #[clap(long)]
+  graph: String,
+
+  /// Graph parameters, as a JSON list.
+  #[clap(long, default_value = "[]")]
+  params: String,
+
+  /// Number of requests in flight at the same time.
+  #[clap(long, default_value = "8")]
+  concurrency: usize,
+
+  /// Target number of requests per second. Unlimited if zero.
+  #[clap(long, default_value = "0")]
+  qps: f64,
+
+  /// How long to run for, in seconds.
+  #[clap(long, default_value = "10")]
+  duration: u64,
+}
+
+enum Outcome {
+  Ok(Option<BTreeMap<String, u64>>),
+  Conflict,
+  Error,
+}
+
+#[derive(Default)]
+struct Stats {
+  /// Latencies of successful requests, in microseconds.
+  latencies_us: Vec<u64>,
+  conflicts: u64,
+  errors: u64,
+  kv_ops: Option<BTreeMap<String, u64>>,
+}
+
+#[derive(Serialize)]
+struct Report {
+  requests: u64,
+  succeeded: u64,
+  conflicts: u64,
+  errors: u64,
+  conflict_rate: f64,
+  error_rate: f64,
+  duration_secs: f64,
+  throughput_qps: f64,
+  latency_ms: LatencySummary,
+  histogram: Vec<HistogramBucket>,
+
+  /// Total KV operations, if the server has KV profiling enabled.
+  kv_ops: Option<BTreeMap<String, u64>>,
+}
+
+#[derive(Serialize)]
+struct LatencySummary {
+  mean: f64,
+  p50: f64,
+  p95: f64,
+  p99: f64,
+  max: f64,
+}
+
+#[derive(Serialize)]
+struct HistogramBucket {
+  /// Upper bound of the bucket in milliseconds, or `None` for the overflow bucket.
+  le_ms: Option<u64>,
+  count: u64,
+}
+
+pub async fn run_bench(opts: &Bench, token: Option<&str>) -> Result<()> {
+  match &opts.subcmd {
+    BenchCommand::RunQuery(x) => run_query(x, token).await,
+  }
+}
+
+async fn run_query(opts: &RunQuery, token: Option<&str>) -> Result<()> {
+  // Validate early, so that a typo does not turn into a run full of errors.
+  let params: serde_json::Value = serde_json::from_str(&opts.params)?;
+  let body = Arc::new(serde_json::to_vec(&params)?);
+  let uri: Uri = format!(
+    "{}/query/{}/{}/{}",
+    opts.http.trim_end_matches('/'),
+    opts.namespace,
+    opts.script,
+    opts.graph
+  )
+  .parse()?;
+
+  let client = Client::new();
+  let stats = Arc::new(Mutex::new(Stats::default()));
+  let concurrency = opts.concurrency.max(1);
+  let period = if opts.qps > 0.0 {
+    Some(Duration::from_secs_f64(concurrency as f64 / opts.qps))
+  } else {
+    None
+  };
+  let start = Instant::now();
+  let deadline = start + Duration::from_secs(opts.duration);
+
+  let mut workers = Vec::with_capacity(concurrency);
+  for _ in 0..concurrency {
+    let client = client.clone();
+    let uri = uri.clone();
+    let body = body.clone();
+    let token = token.map(|x| x.to_string());
+    let stats = stats.clone();
+    workers.push(tokio::spawn(async move {
+      let mut interval = period.map(tokio::time::interval);
+      while Instant::now() < deadline {
+        if let Some(x) = &mut interval {
+          x.tick().await;
+        }
+        let req_start = Instant::now();
+        let outcome = send_query(&client, &uri, &body, token.as_deref()).await;
+        let latency = req_start.elapsed();
+
+        let mut stats = stats.lock().await;
+        match outcome {
+          Outcome::Ok(kv_ops) => {
+            stats.latencies_us.push(latency.as_micros() as u64);
+            if let Some(kv_ops) = kv_ops {
+              let total = stats.kv_ops.get_or_insert_with(BTreeMap::new);
+              for (k, v) in kv_ops {
+                *total.entry(k).or_insert(0) += v;
+              }
+            }
+          }
+          Outcome::Conflict => stats.conflicts += 1,
+          Outcome::Error => stats.errors += 1,
+        }
+      }
+    }));
+  }
+  for w in workers {
+    w.await?;
+  }
+  let elapsed = start.elapsed();
+
+  let stats = std::mem::take(&mut *stats.lock().await);
+  let report = build_report(stats, elapsed);
+  println!("{}", serde_json::to_string(&report)?);
+  Ok(())
+}
+
+async fn send_query(
+  client: &Client<HttpConnector>,
+  uri: &Uri,
+  body: &[u8],
+  token: Option<&str>,
+) -> Outcome {
+  let mut req = Request::builder()
+    .method(Method::POST)
+    .uri(uri.clone())
+    .header("Content-Type", "application/json");
+  if let Some(token) = token {
+    req = req.header("Authorization", format!("Bearer {}", token));
+  }
+  let req = match req.body(Body::from(body.to_vec())) {
+    Ok(x) => x,
+    Err(_) => return Outcome::Error,
+  };
+  let res = match client.request(req).await {
+    Ok(x) => x,
+    Err(e) => {
+      log::debug!("request failed: {:?}", e);
+      return Outcome::Error;
+    }
+  };
+  let kv_ops = res
+    .headers()
+    .get(KV_OPS_HEADER)
+    .and_then(|x| x.to_str().ok())
+    .and_then(|x| serde_json::from_str(x).ok());
+  let status = res.status();
+  let body = hyper::body::to_bytes(res.into_body()).await;
+  if status.is_success() {
+    return Outcome::Ok(kv_ops);
+  }
+
+  let is_conflict = body
+    .ok()
+    .and_then(|x| serde_json::from_slice::<serde_json::Value>(&x).ok())
+    .map(|x| x["error"] == "conflict")
+    .unwrap_or(false);
+  if is_conflict {
+    Outcome::Conflict
+  } else {
+    Outcome::Error
+  }
+}
+
+fn build_report(mut stats: Stats, elapsed: Duration) -> Report {
+  stats.latencies_us.sort_unstable();
+  let latencies = &stats.latencies_us;
+  let succeeded = latencies.len() as u64;
+  let requests = succeeded + stats.conflicts + stats.errors;
+  let rate = |x: u64| {
+    if requests == 0 {
+      0.0
+    } else {
+      x as f64 / requests as f64
+    }
+  };
+  let percentile = |p: f64| -> f64 {
+    if latencies.is_empty() {
+      return 0.0;
+    }
+    let rank = ((p * latencies.len() as f64).ceil() as usize).clamp(1, latencies.len());
+    latencies[rank - 1] as f64 / 1000.0
+  };
+  let mean = if latencies.is_empty() {
+    0.0
+  } else {
+    latencies.iter().sum::<u64>() as f64 / latencies.len() as f64 / 1000.0
+  };
+
+  let mut histogram = HISTOGRAM_BOUNDS_MS
+    .iter()
+    .map(|&x| HistogramBucket {
+      le_ms: Some(x),
+      count: 0,
+    })
+    .chain(std::iter::once(HistogramBucket {
+      le_ms: None,
+      count: 0,
+    }))
+    .collect::<Vec<_>>();
+  for &x in latencies {
+    let index = HISTOGRAM_BOUNDS_MS
+      .iter()
+      .position(|&bound| x <= bound * 1000)
+      .unwrap_or(HISTOGRAM_BOUNDS_MS.len());
+    histogram[index].count += 1;
+  }
+
+  Report {
+    requests,
+    succeeded,
+    conflicts: stats.conflicts,
+    errors: stats.errors,
+    conflict_rate: rate(stats.conflicts),
+    error_rate: rate(stats.errors),
+    duration_secs: elapsed.as_secs_f64(),
+    throughput_qps: succeeded as f64 / elapsed.as_secs_f64(),
+    latency_ms: LatencySummary {
+      mean,
+      p50: percentile(0.5),
+      p95: percentile(0.95),
+      p99: percentile(0.99),
+      max: latencies.last().map(|&x| x as f64 / 1000.0).unwrap_or(0.0),
+    },
+    histogram,
+    kv_ops: stats.kv_ops,
+  }
+}
@@ -0,0 +1,333 @@
+//! Bundles of a deployment and its query scripts.
+//!
+//! `bundle export` writes the schema and storage plan of a deployment, along with the query
+//! scripts associated with it, into a single JSON file. `bundle import` creates a deployment from
+//! the bundle in another namespace, e.g. to promote changes from staging to production, and
+//! points the query scripts of the bundle at it.
+//!
+//! Every query script is type checked against the bundled schema before anything is created, so
+//! a bundle is either imported as a whole or not at all, save for server errors halfway through.
+//! Scripts that mount other namespaces are only checked by the server as they are created.
+
+use std::convert::TryFrom;
+
+use anyhow::{Context, Result};
+use bumpalo::Bump;
+use clap::Clap;
+use rdb_analyzer::{
+  data::treewalker::{asm::codegen::compile_twscript, typeck::GlobalTyckContext, vm::TwVm},
+  schema::{
+    compile::{compile, CompiledSchema},
+    grammar::parse,
+    lint::lint,
+  },
+  storage_plan::{planner::generate_plan_for_schema, StorageKey, StoragePlan},
+};
+use rdb_proto::{
+  proto::{
+    rdb_control_client::RdbControlClient, CreateDeploymentRequest, CreateQueryScriptRequest,
+    GetDeploymentRequest, GetQueryScriptRequest, ListDeploymentRequest, ListQueryScriptRequest,
+  },
+  tonic::{transport::Channel, Request},
+};
+use serde::{Deserialize, Serialize};
+
+use crate::{diff::print_diff, CliError};
+
+/// Version of the bundle format, bumped on incompatible changes.
+const BUNDLE_FORMAT_VERSION: u32 = 1;
+
+#[derive(Clap)]
+pub struct Bundle {
+  #[clap(subcommand)]
+  subcmd: BundleCommand,
+}
+
+#[derive(Clap)]
+enum BundleCommand {
+  /// Write a deployment and its query scripts into a bundle.
+  Export(ExportBundle),
+
+  /// Create a deployment and its query scripts from a bundle.
+  Import(ImportBundle),
+}
+
+#[derive(Clap)]
+struct ExportBundle {
+  /// Namespace id.
+  #[clap(long)]
+  namespace: String,
+
+  /// Deployment id. Defaults to the latest deployment of the namespace.
+  #[clap(long)]
+  deployment: Option<String>,
+
+  /// Output file. The bundle is written to stdout if not set.
+  #[clap(long)]
+  out: Option<String>,
+}
+
+#[derive(Clap)]
+struct ImportBundle {
+  /// Namespace id.
+  #[clap(long)]
+  namespace: String,
+
+  /// Bundle file, as written by `bundle export`.
+  #[clap(long = "in")]
+  input: String,
+
+  /// Deployment of the namespace to migrate from. The storage plan is then generated from the
+  /// plan of that deployment, instead of taken from the bundle.
+  #[clap(long)]
+  migrate_from: Option<String>,
+
+  /// Create the deployment even if the server rejects data-lossy schema changes from
+  /// `--migrate-from`.
+  #[clap(long)]
+  allow_lossy: bool,
+
+  /// Check the bundle and print the server's report of the migration without creating anything.
+  #[clap(long)]
+  dry_run: bool,
+}
+
+#[derive(Serialize, Deserialize)]
+struct BundleFile {
+  format_version: u32,
+
+  /// Where the bundle was exported from. Informational only.
+  namespace_id: String,
+  deployment_id: String,
+
+  description: String,
+  schema: String,
+  plan: String,
+
+  /// Sorted by id.
+  query_scripts: Vec<BundledQueryScript>,
+}
+
+#[derive(Serialize, Deserialize)]
+struct BundledQueryScript {
+  id: String,
+  script: String,
+}
+
+pub async fn run_bundle(client: &mut RdbControlClient<Channel>, opts: &Bundle) -> Result<()> {
+  match &opts.subcmd {
+    BundleCommand::Export(x) => export_bundle(client, x).await,
+    BundleCommand::Import(x) => import_bundle(client, x).await,
+  }
+}
+
+async fn export_bundle(client: &mut RdbControlClient<Channel>, opts: &ExportBundle) -> Result<()> {
+  let deployment_id = match &opts.deployment {
+    Some(x) => x.clone(),
+    None => client
+      .list_deployment(Request::new(ListDeploymentRequest {
+        namespace_id: opts.namespace.clone(),
+      }))
+      .await?
+      .get_ref()
+      .deployments
+      .iter()
+      .rev()
+      .max_by_key(|x| x.create_time)
+      .map(|x| x.id.clone())
+      .ok_or(CliError::NoDeployment)?,
+  };
+  let res = client
+    .get_deployment(Request::new(GetDeploymentRequest {
+      namespace_id: opts.namespace.clone(),
+      deployment_id: deployment_id.clone(),
+    }))
+    .await?;
+  let info = res.into_inner().info.ok_or(CliError::DeploymentNotFound)?;
+
+  let mut script_ids = client
+    .list_query_script(Request::new(ListQueryScriptRequest {
+      namespace_id: opts.namespace.clone(),
+    }))
+    .await?
+    .into_inner()
+    .query_scripts
+    .into_iter()
+    .filter(|x| x.associated_deployment == deployment_id)
+    .map(|x| x.id)
+    .collect::<Vec<_>>();
+  script_ids.sort();
+  let mut query_scripts = Vec::with_capacity(script_ids.len());
+  for id in script_ids {
+    let res = client
+      .get_query_script(Request::new(GetQueryScriptRequest {
+        namespace_id: opts.namespace.clone(),
+        query_script_id: id.clone(),
+      }))
+      .await?;
+    let script_info = res.into_inner().info.ok_or(CliError::QueryScriptNotFound)?;
+    query_scripts.push(BundledQueryScript {
+      id,
+      script: script_info.script,
+    });
+  }
+
+  let bundle = BundleFile {
+    format_version: BUNDLE_FORMAT_VERSION,
+    namespace_id: opts.namespace.clone(),
+    deployment_id,
+    description: info.description,
+    schema: info.schema,
+    plan: info.plan,
+    query_scripts,
+  };
+  let text = serde_json::to_string_pretty(&bundle)?;
+  match &opts.out {
+    Some(x) => {
+      std::fs::write(x, text)?;
+      eprintln!(
+        "Exported deployment `{}` with {} query script(s).",
+        bundle.deployment_id,
+        bundle.query_scripts.len()
+      );
+    }
+    None => println!("{}", text),
+  }
+  Ok(())
+}
+
+async fn import_bundle(client: &mut RdbControlClient<Channel>, opts: &ImportBundle) -> Result<()> {
+  let bundle: BundleFile =
+    serde_json::from_str(&std::fs::read_to_string(&opts.input)?).context("invalid bundle")?;
+  if bundle.format_version != BUNDLE_FORMAT_VERSION {
+    return Err(CliError::UnsupportedBundleVersion(bundle.format_version).into());
+  }
+
+  let new_schema = compile(&parse(&Bump::new(), &bundle.schema)?)?;
+  for x in lint(&new_schema) {
+    log::warn!("{}", x);
+  }
+  let new_plan = match &opts.migrate_from {
+    Some(reference) => {
+      let res = client
+        .get_deployment(Request::new(GetDeploymentRequest {
+          namespace_id: opts.namespace.clone(),
+          deployment_id: reference.clone(),
+        }))
+        .await?;
+      let info = res
+        .get_ref()
+        .info
+        .as_ref()
+        .ok_or(CliError::ReferenceDeploymentNotFound)?;
+      let reference_schema = compile(&parse(&Bump::new(), &info.schema)?)?;
+      let reference_plan: StoragePlan<String> = serde_yaml::from_str(&info.plan)?;
+      let reference_plan = StoragePlan::<StorageKey>::try_from(&reference_plan)?;
+      let new_plan = generate_plan_for_schema(&reference_plan, &reference_schema, &new_schema)?;
+      print_diff(&reference_plan, &new_plan);
+      new_plan
+    }
+    None => {
+      let plan: StoragePlan<String> = serde_yaml::from_str(&bundle.plan)?;
+      StoragePlan::<StorageKey>::try_from(&plan)?
+    }
+  };
+
+  let invalid_scripts = bundle
+    .query_scripts
+    .iter()
+    .filter(|x| !check_query_script(&new_schema, &new_plan, &x.id, &x.script))
+    .map(|x| x.id.clone())
+    .collect::<Vec<_>>();
+  if !invalid_scripts.is_empty() {
+    return Err(CliError::InvalidQueryScripts(invalid_scripts).into());
+  }
+
+  let res = client
+    .create_deployment(Request::new(CreateDeploymentRequest {
+      namespace_id: opts.namespace.clone(),
+      schema: bundle.schema.clone(),
+      plan: serde_yaml::to_string(&StoragePlan::<String>::from(&new_plan))?,
+      description: bundle.description.clone(),
+      migrate_from: opts.migrate_from.clone().unwrap_or_default(),
+      migration_script: String::new(),
+      dry_run: opts.dry_run,
+      allow_lossy: opts.allow_lossy,
+    }))
+    .await?;
+  if let Some(report) = &res.get_ref().report {
+    println!(
+      "{}",
+      serde_json::to_string(&serde_json::json!({
+        "preserved_fields": report.preserved_fields,
+        "dropped_fields": report.dropped_fields,
+        "new_fields": report.new_fields,
+        "plan_matches": report.plan_matches,
+        "lossy_changes": report.lossy_changes,
+        "query_scripts": bundle.query_scripts.iter().map(|x| &x.id).collect::<Vec<_>>(),
+      }))?
+    );
+    return Ok(());
+  }
+  let deployment_id = res
+    .get_ref()
+    .deployment_id
+    .as_ref()
+    .ok_or(CliError::DeploymentNotCreated)?
+    .id
+    .clone();
+
+  for (i, x) in bundle.query_scripts.iter().enumerate() {
+    client
+      .create_query_script(Request::new(CreateQueryScriptRequest {
+        namespace_id: opts.namespace.clone(),
+        id: x.id.clone(),
+        associated_deployment: deployment_id.clone(),
+        script: x.script.clone(),
+      }))
+      .await
+      .with_context(|| {
+        format!(
+          "cannot create query script `{}`; deployment `{}` and {} query script(s) were created before it",
+          x.id, deployment_id, i
+        )
+      })?;
+  }
+  println!(
+    "{}",
+    serde_json::to_string(&serde_json::json!({
+      "id": deployment_id,
+      "query_scripts": bundle.query_scripts.iter().map(|x| &x.id).collect::<Vec<_>>(),
+    }))?
+  );
+  Ok(())
+}
+
+/// Type checks a query script against a schema and plan, logging the error if it does not check.
+/// Scripts that mount other namespaces are left to the server, which resolves the mounts.
+pub fn check_query_script(
+  schema: &CompiledSchema,
+  plan: &StoragePlan,
+  id: &str,
+  script: &str,
+) -> bool {
+  let res = compile_twscript(script).and_then(|script| {
+    if !script.mounts.is_empty() {
+      log::info!(
+        "Query script `{}` mounts other namespaces and is checked by the server.",
+        id
+      );
+      return Ok(());
+    }
+    let vm = TwVm::new(schema, plan, &script)?;
+    GlobalTyckContext::new(&vm)?.typeck()?;
+    Ok(())
+  });
+  match res {
+    Ok(()) => true,
+    Err(e) => {
+      log::error!("Query script `{}`: {}", id, e);
+      false
+    }
+  }
+}
@@ -1,29 +1,63 @@
+mod apply;
+mod bench;
+mod bundle;
 mod diff;
+mod repl;
 
-use std::convert::TryFrom;
+use std::{
+  convert::TryFrom,
+  io::{BufWriter, Write},
+};
 
-use anyhow::Result;
+use anyhow::{Context, Result};
 
 use bumpalo::Bump;
 use clap::{AppSettings, Clap};
 use dialoguer::{theme::ColorfulTheme, Confirm};
 use rdb_analyzer::{
-  schema::{compile::compile, grammar::parse},
-  storage_plan::{planner::generate_plan_for_schema, StorageKey, StoragePlan},
+  codegen::{rust::generate_rust, ts::generate_typescript},
+  data::{
+    kv::decode_counter,
+    treewalker::{
+      asm::codegen::compile_twscript, typeck::GlobalTyckContext, viz::visualize_dot, vm::TwVm,
+    },
+  },
+  schema::{compile::compile, format::format_schema, grammar::parse, lint::lint},
+  storage_plan::{
+    planner::{assign_key_aliases, generate_plan_for_schema},
+    StorageKey, StoragePlan,
+  },
 };
 use rdb_proto::{
   proto::{
-    rdb_control_client::RdbControlClient, CreateDeploymentRequest, CreateNamespaceRequest,
-    CreateQueryScriptRequest, DeleteNamespaceRequest, DeleteQueryScriptRequest,
-    GetDeploymentRequest, GetQueryScriptRequest, ListDeploymentRequest, ListNamespaceRequest,
-    ListQueryScriptRequest,
+    bulk_delete_outcome, changelog_op, consistency_issue, control_event,
+    rdb_control_client::RdbControlClient, BulkDeleteRequest, CandidateMode,
+    CheckConsistencyRequest, CheckSchemaRequest, ControlEvent, CreateApiTokenRequest,
+    CreateDeploymentRequest, CreateExplorerTokenRequest, CreateNamespaceRequest,
+    CreateQueryScriptRequest, CreateTokenRequest, DeleteApiTokenRequest, DeleteDeploymentRequest,
+    DeleteExplorerTokenRequest, DeleteNamespaceRequest, DeleteQueryScriptRequest,
+    DeploymentRouting, DescribeSchemaRequest, ExecLimits, ExportDataRequest, FieldDescription,
+    FieldTypeDescription, GetDeploymentRequest, GetDeploymentRoutingRequest,
+    GetNamespaceUsageRequest, GetPlanDiffRequest, GetQueryCacheStatsRequest, GetQueryScriptRequest,
+    GraphGrant, GraphPermission, ImportDataRequest, InvalidateQueryCacheRequest,
+    InvokeGraphRequest, ListApiTokenRequest, ListDeploymentRequest, ListExplorerTokenRequest,
+    ListNamespaceRequest, ListQueryScriptRequest, ListTokenRequest, PlanDiffNode,
+    RevokeTokenRequest, SetDeploymentRoutingRequest, SetNamespaceIdStrategyRequest,
+    SetNamespaceQuotaRequest, TailChangelogRequest, TraceQueryRequest, WatchDeploymentsRequest,
+    WatchNamespacesRequest,
   },
-  tonic::Request,
+  tonic::{metadata::MetadataValue, transport::Endpoint, Request, Status},
 };
 use thiserror::Error;
 use tokio::task::block_in_place;
 
-use crate::diff::print_diff;
+use crate::{
+  apply::{run_apply, Apply},
+  bench::{run_bench, Bench},
+  bundle::{run_bundle, Bundle},
+  diff::print_diff,
+  repl::{run_repl, Repl},
+};
 
 /// RefineDB CLI.
 #[derive(Clap)]
@@ -33,6 +67,10 @@ struct Opts {
   /// Server URL.
   #[clap(short, long)]
   server: String,
+
+  /// Token to authenticate with, if the server requires authentication.
+  #[clap(long)]
+  token: Option<String>,
   #[clap(subcommand)]
   subcmd: SubCommand,
 }
@@ -48,23 +86,132 @@ enum SubCommand {
   /// Delete a namespace.
   DeleteNamespace(DeleteNamespace),
 
+  /// Set the write quota of a namespace.
+  SetNamespaceQuota(SetNamespaceQuota),
+
+  /// Set how ids of new deployments in a namespace are generated.
+  SetNamespaceIdStrategy(SetNamespaceIdStrategy),
+
+  /// Show the bytes read from and written into a namespace.
+  GetNamespaceUsage(GetNamespaceUsage),
+
+  /// Route a percentage of read queries of a namespace to a candidate deployment.
+  SetDeploymentRouting(SetDeploymentRouting),
+
+  /// Show the deployment routing of a namespace.
+  GetDeploymentRouting(GetDeploymentRouting),
+
+  /// Print changelog entries of a namespace.
+  TailChangelog(TailChangelog),
+
+  /// Print namespaces as they are created or deleted, one JSON object per line.
+  WatchNamespaces(WatchNamespaces),
+
+  /// Print deployments of a namespace as they are created or deleted, one JSON object per line.
+  WatchDeployments(WatchDeployments),
+
   /// Create a deployment.
   CreateDeployment(CreateDeployment),
 
   /// List deployments.
   ListDeployment(ListDeployment),
 
+  /// Delete a deployment.
+  DeleteDeployment(DeleteDeployment),
+
+  /// Compare the storage plans of two deployments.
+  GetPlanDiff(GetPlanDiff),
+
+  /// Check a schema for foot-guns without creating a deployment.
+  CheckSchema(CheckSchema),
+
+  /// Print the types, exports and storage keys of a deployment as JSON.
+  DescribeSchema(DescribeSchema),
+
+  /// Format a schema in the canonical style. Does not connect to the server.
+  FmtSchema(FmtSchema),
+
+  /// Generate typed bindings for a schema. Does not connect to the server.
+  Codegen(Codegen),
+
   /// Create query script.
   CreateQueryScript(CreateQueryScript),
 
   /// Get query script.
   GetQueryScript(GetQueryScript),
 
+  /// Render the graphs of a query script as Graphviz DOT, typed against its deployment.
+  VisualizeScript(VisualizeScript),
+
   /// Delete query script.
   DeleteQueryScript(DeleteQueryScript),
 
   /// List query scripts.
   ListQueryScript(ListQueryScript),
+
+  /// Create a read-only explorer token.
+  CreateExplorerToken(CreateExplorerToken),
+
+  /// List explorer tokens.
+  ListExplorerToken(ListExplorerToken),
+
+  /// Delete an explorer token.
+  DeleteExplorerToken(DeleteExplorerToken),
+
+  /// Create an API token that may run a set of graphs.
+  CreateApiToken(CreateApiToken),
+
+  /// List API tokens.
+  ListApiToken(ListApiToken),
+
+  /// Delete an API token.
+  DeleteApiToken(DeleteApiToken),
+
+  /// Run a graph and print its output.
+  InvokeGraph(InvokeGraph),
+
+  /// Create a token that may access a set of namespaces. Requires the root token.
+  CreateToken(CreateToken),
+
+  /// List tokens. Requires the root token.
+  ListToken(ListToken),
+
+  /// Revoke a token. Requires the root token.
+  RevokeToken(RevokeToken),
+
+  /// Show the query scripts compiled and cached by the server. Requires the root token.
+  QueryCacheStats(QueryCacheStats),
+
+  /// Drop cached compilations of the query scripts in a namespace.
+  InvalidateQueryCache(InvalidateQueryCache),
+
+  /// Run a read-only graph and print its execution trace.
+  TraceQuery(TraceQuery),
+
+  /// Delete members of a set by primary key.
+  BulkDelete(BulkDelete),
+
+  /// Export the rows of a set or a table as NDJSON.
+  Export(Export),
+
+  /// Import NDJSON rows into a set or a table.
+  Import(Import),
+
+  /// Export or import a deployment with its query scripts as a single file.
+  Bundle(Bundle),
+
+  /// Create, update and delete namespaces, deployments and query scripts to match a project
+  /// file.
+  Apply(Apply),
+
+  /// Check the stored data of a namespace for inconsistencies, and optionally repair them.
+  Fsck(Fsck),
+
+  /// Load testing.
+  Bench(Bench),
+
+  /// Run graphs interactively against a deployment.
+  Repl(Repl),
 }
 
 #[derive(Clap)]
@@ -80,6 +227,85 @@ struct DeleteNamespace {
   namespace_id: String,
 }
 
+#[derive(Clap)]
+struct SetNamespaceQuota {
+  namespace_id: String,
+
+  /// Max total size in bytes of the keys and values written into the namespace. Zero removes the
+  /// quota.
+  #[clap(long)]
+  write_quota_bytes: u64,
+}
+
+#[derive(Clap)]
+struct SetNamespaceIdStrategy {
+  namespace_id: String,
+
+  /// `uuid`, `ulid`, `ksuid` or `snowflake`. The default strategy of the server if not set.
+  #[clap(long)]
+  strategy: Option<String>,
+}
+
+#[derive(Clap)]
+struct GetNamespaceUsage {
+  namespace_id: String,
+}
+
+#[derive(Clap)]
+struct SetDeploymentRouting {
+  namespace_id: String,
+
+  /// Deployment whose query scripts are routed. Routing is removed if not provided.
+  #[clap(long)]
+  primary: Option<String>,
+
+  /// Deployment that routed queries run on.
+  #[clap(long)]
+  candidate: Option<String>,
+
+  /// Percentage of calls to read-only graphs that are routed.
+  #[clap(long, default_value = "0")]
+  traffic_percent: u32,
+
+  /// Return the output of the candidate, instead of only comparing it with that of the primary.
+  #[clap(long)]
+  canary: bool,
+}
+
+#[derive(Clap)]
+struct GetDeploymentRouting {
+  namespace_id: String,
+}
+
+#[derive(Clap)]
+struct TailChangelog {
+  namespace_id: String,
+
+  /// Sequence number of the first entry to print.
+  #[clap(long, default_value = "0")]
+  from_seq: u64,
+
+  /// Max number of entries to print.
+  #[clap(long, default_value = "100")]
+  limit: u32,
+}
+
+#[derive(Clap)]
+struct WatchNamespaces {
+  /// Sequence number of the first event to print. Only new events are printed if not set.
+  #[clap(long, default_value = "0")]
+  from_seq: u64,
+}
+
+#[derive(Clap)]
+struct WatchDeployments {
+  namespace_id: String,
+
+  /// Sequence number of the first event to print. Only new events are printed if not set.
+  #[clap(long, default_value = "0")]
+  from_seq: u64,
+}
+
 #[derive(Clap)]
 struct CreateDeployment {
   /// The source deployment to migrate from.
@@ -97,6 +323,32 @@ struct CreateDeployment {
   /// Namespace id.
   #[clap(long)]
   namespace: String,
+
+  /// Use 2-byte aliases for storage keys in composed keys. Deployments migrated from one with
+  /// key aliases always use them.
+  #[clap(long)]
+  key_aliases: bool,
+
+  /// Store the table fields of a type that is used more than once as references to a single
+  /// shared layout. Deployments migrated from one with shared layouts always share them.
+  #[clap(long)]
+  share_layouts: bool,
+
+  /// Path to a RefineAsm script with a `migrate(root: schema)` graph to run before the
+  /// deployment is created. Requires `--migrate-from`. Exports of the old schema are available
+  /// as `old_<name>`.
+  #[clap(long)]
+  migration_script: Option<String>,
+
+  /// Print the server's report of preserved, dropped and new fields without creating the
+  /// deployment.
+  #[clap(long)]
+  dry_run: bool,
+
+  /// Create the deployment even if the server rejects data-lossy schema changes from
+  /// `--migrate-from`.
+  #[clap(long)]
+  allow_lossy: bool,
 }
 
 #[derive(Clap)]
@@ -134,6 +386,17 @@ struct GetQueryScript {
   id: String,
 }
 
+#[derive(Clap)]
+struct VisualizeScript {
+  /// Namespace id.
+  #[clap(long)]
+  namespace: String,
+
+  /// Query script id.
+  #[clap(long)]
+  id: String,
+}
+
 #[derive(Clap)]
 struct DeleteQueryScript {
   /// Namespace id.
@@ -150,6 +413,369 @@ struct ListQueryScript {
   namespace: String,
 }
 
+#[derive(Clap)]
+struct CreateExplorerToken {
+  /// Namespace id.
+  #[clap(long)]
+  namespace: String,
+
+  /// Token description.
+  #[clap(long)]
+  description: Option<String>,
+
+  /// A graph this token may run, in the form `query_script_id/graph_name`. Can be repeated.
+  #[clap(long = "allow", required = true)]
+  allowed_graphs: Vec<String>,
+
+  /// Name of a map field to redact from query results. Can be repeated.
+  #[clap(long = "redact")]
+  redacted_fields: Vec<String>,
+}
+
+#[derive(Clap)]
+struct ListExplorerToken {
+  namespace: String,
+}
+
+#[derive(Clap)]
+struct DeleteExplorerToken {
+  /// Namespace id.
+  #[clap(long)]
+  namespace: String,
+
+  /// Token id.
+  #[clap(long)]
+  id: String,
+}
+
+#[derive(Clap)]
+struct CreateApiToken {
+  /// Namespace id.
+  #[clap(long)]
+  namespace: String,
+
+  /// Token description.
+  #[clap(long)]
+  description: Option<String>,
+
+  /// A graph this token may run while it is read-only, in the form
+  /// `query_script_id/graph_name`. Can be repeated.
+  #[clap(long = "read-only")]
+  read_only_graphs: Vec<String>,
+
+  /// A graph this token may run even if it writes, in the form `query_script_id/graph_name`.
+  /// Can be repeated.
+  #[clap(long = "effectful")]
+  effectful_graphs: Vec<String>,
+}
+
+#[derive(Clap)]
+struct ListApiToken {
+  namespace: String,
+}
+
+#[derive(Clap)]
+struct DeleteApiToken {
+  /// Namespace id.
+  #[clap(long)]
+  namespace: String,
+
+  /// Token id.
+  #[clap(long)]
+  id: String,
+}
+
+#[derive(Clap)]
+struct InvokeGraph {
+  /// Namespace id.
+  #[clap(long)]
+  namespace: String,
+
+  /// Query script id.
+  #[clap(long)]
+  script: String,
+
+  /// Name of the exported graph.
+  #[clap(long)]
+  graph: String,
+
+  /// Graph parameters, as a JSON list.
+  #[clap(long, default_value = "[]")]
+  params: String,
+
+  /// Run the graph only if this API token allows it.
+  #[clap(long)]
+  api_token: Option<String>,
+
+  /// Time limit in milliseconds. Only takes effect if stricter than the server's.
+  #[clap(long)]
+  timeout_ms: Option<u64>,
+
+  /// Max number of KV reads.
+  #[clap(long)]
+  max_kv_reads: Option<u64>,
+
+  /// Max number of KV writes.
+  #[clap(long)]
+  max_kv_writes: Option<u64>,
+
+  /// Max number of subgraph invocations.
+  #[clap(long)]
+  max_subgraph_calls: Option<u64>,
+
+  /// Max size of a single value read or written, in bytes.
+  #[clap(long)]
+  max_value_size: Option<u64>,
+
+  /// Max depth of nested subgraph invocations.
+  #[clap(long)]
+  max_recursion_depth: Option<u64>,
+
+  /// If the graph returns a set, the `next` token of the previous page of its members.
+  #[clap(long)]
+  after: Option<String>,
+
+  /// Members per page of a returned set.
+  #[clap(long)]
+  page_size: Option<u32>,
+}
+
+#[derive(Clap)]
+struct CreateToken {
+  /// Token description.
+  #[clap(long)]
+  description: Option<String>,
+
+  /// A namespace this token may access. Can be repeated.
+  #[clap(long = "namespace", required = true)]
+  namespaces: Vec<String>,
+
+  /// A namespace that query scripts created with this token may mount read-only. Can be
+  /// repeated.
+  #[clap(long = "mount")]
+  mounts: Vec<String>,
+
+  /// The role checked against `@acl` field annotations for requests with this token.
+  #[clap(long)]
+  role: Option<String>,
+}
+
+#[derive(Clap)]
+struct ListToken {}
+
+#[derive(Clap)]
+struct RevokeToken {
+  /// Token id.
+  #[clap(long)]
+  id: String,
+}
+
+#[derive(Clap)]
+struct QueryCacheStats {}
+
+#[derive(Clap)]
+struct InvalidateQueryCache {
+  /// Namespace id.
+  #[clap(long)]
+  namespace: String,
+
+  /// Only invalidate this query script.
+  #[clap(long)]
+  script: Option<String>,
+}
+
+#[derive(Clap)]
+struct TraceQuery {
+  /// Namespace id.
+  #[clap(long)]
+  namespace: String,
+
+  /// Query script id.
+  #[clap(long)]
+  script: String,
+
+  /// Name of the exported graph.
+  #[clap(long)]
+  graph: String,
+
+  /// Graph parameters, as a JSON list.
+  #[clap(long, default_value = "[]")]
+  params: String,
+}
+
+#[derive(Clap)]
+struct BulkDelete {
+  /// Namespace id.
+  #[clap(long)]
+  namespace: String,
+
+  /// Deployment id.
+  #[clap(long)]
+  deployment: String,
+
+  /// Dot-separated path from an export to the set.
+  #[clap(long)]
+  set: String,
+
+  /// Primary keys, as a JSON list.
+  #[clap(long)]
+  keys: String,
+
+  /// Number of members deleted in each transaction.
+  #[clap(long, default_value = "100")]
+  chunk_size: u32,
+}
+
+#[derive(Clap)]
+struct DeleteDeployment {
+  /// Namespace id.
+  #[clap(long)]
+  namespace: String,
+
+  /// Deployment id.
+  #[clap(long)]
+  id: String,
+
+  /// Also delete the query scripts associated with the deployment.
+  #[clap(long)]
+  force: bool,
+}
+
+#[derive(Clap)]
+struct GetPlanDiff {
+  /// Namespace id.
+  #[clap(long)]
+  namespace: String,
+
+  /// Deployment with the old plan.
+  #[clap(long)]
+  from: String,
+
+  /// Deployment with the new plan.
+  #[clap(long)]
+  to: String,
+}
+
+#[derive(Clap)]
+struct CheckSchema {
+  /// Path to the schema.
+  #[clap(long)]
+  schema: String,
+}
+
+#[derive(Clap)]
+struct DescribeSchema {
+  /// Namespace id.
+  #[clap(long)]
+  namespace: String,
+
+  /// Deployment id. Defaults to the latest deployment of the namespace.
+  #[clap(long)]
+  deployment: Option<String>,
+}
+
+#[derive(Clap)]
+struct FmtSchema {
+  /// Path to the schema.
+  file: String,
+
+  /// Overwrite the file instead of printing the formatted schema.
+  #[clap(long)]
+  write: bool,
+
+  /// Fail if the file is not formatted, without changing it.
+  #[clap(long)]
+  check: bool,
+}
+
+#[derive(Clap)]
+struct Codegen {
+  /// Target language: `rust`, or `ts` for a TypeScript client of the HTTP API.
+  #[clap(long)]
+  lang: String,
+
+  /// Path to the schema.
+  #[clap(long)]
+  schema: String,
+
+  /// Path to the query script to generate a client for. Required for `ts`.
+  #[clap(long)]
+  script: Option<String>,
+}
+
+#[derive(Clap)]
+struct Export {
+  /// Namespace id.
+  #[clap(long)]
+  namespace: String,
+
+  /// Deployment id.
+  #[clap(long)]
+  deployment: String,
+
+  /// Dot-separated path from an export to the set or table.
+  #[clap(long)]
+  export: String,
+
+  /// Output file. Rows are written to stdout if not set.
+  #[clap(long)]
+  out: Option<String>,
+
+  /// Number of rows requested at a time.
+  #[clap(long, default_value = "1000")]
+  page_size: u32,
+}
+
+#[derive(Clap)]
+struct Import {
+  /// Namespace id.
+  #[clap(long)]
+  namespace: String,
+
+  /// Deployment id.
+  #[clap(long)]
+  deployment: String,
+
+  /// Dot-separated path from an export to the set or table.
+  #[clap(long)]
+  export: String,
+
+  /// Input file, with one row per line in the format written by `export`.
+  #[clap(long = "in")]
+  input: String,
+
+  /// Number of rows sent in each request.
+  #[clap(long, default_value = "1000")]
+  batch_size: usize,
+
+  /// Number of rows written in each transaction.
+  #[clap(long, default_value = "100")]
+  chunk_size: u32,
+}
+
+#[derive(Clap)]
+struct Fsck {
+  /// Namespace id.
+  #[clap(long)]
+  namespace: String,
+
+  /// Deployment to check against. Defaults to the latest deployment of the namespace.
+  #[clap(long)]
+  deployment: Option<String>,
+
+  /// Repair the issues found in sets. Only allowed with the latest deployment.
+  #[clap(long)]
+  repair: bool,
+
+  /// Delete keys outside of every export. Only allowed with the latest deployment.
+  #[clap(long)]
+  delete_orphan_keys: bool,
+
+  /// Number of keys checked in each transaction.
+  #[clap(long, default_value = "100")]
+  batch_size: u32,
+}
+
 #[derive(Error, Debug)]
 enum CliError {
   #[error("reference deployment not found")]
@@ -163,6 +789,54 @@ enum CliError {
 
   #[error("query script not found")]
   QueryScriptNotFound,
+
+  #[error("explorer token not created")]
+  ExplorerTokenNotCreated,
+
+  #[error("api token not created")]
+  ApiTokenNotCreated,
+
+  #[error("token not created")]
+  TokenNotCreated,
+
+  #[error("invalid graph name: `{0}` (expecting `query_script_id/graph_name`)")]
+  InvalidGraphName(String),
+
+  #[error("a token needs at least one `--read-only` or `--effectful` graph")]
+  NoGraphsGranted,
+
+  #[error("the reference deployment does not use key aliases - migrate its data with `rdb-server --migrate-key-aliases` first")]
+  ReferenceDeploymentWithoutKeyAliases,
+
+  #[error("a migration script requires `--migrate-from`")]
+  MigrationScriptWithoutSource,
+
+  #[error("schema is not formatted: {0}")]
+  SchemaNotFormatted(String),
+
+  #[error("unsupported codegen language: `{0}`")]
+  UnsupportedCodegenLanguage(String),
+
+  #[error("`--lang {0}` requires `--script`")]
+  CodegenScriptRequired(String),
+
+  #[error("the deployment is used by query scripts {0:?} - delete them first or pass `--force`")]
+  DeploymentInUse(Vec<String>),
+
+  #[error("namespace has no deployments")]
+  NoDeployment,
+
+  #[error("deployment not found")]
+  DeploymentNotFound,
+
+  #[error("unsupported bundle format version: {0}")]
+  UnsupportedBundleVersion(u32),
+
+  #[error("query scripts {0:?} do not type check against the schema")]
+  InvalidQueryScripts(Vec<String>),
+
+  #[error("query script `{1}` is declared twice in namespace `{0}`")]
+  DuplicateQueryScript(String, String),
 }
 
 #[tokio::main]
@@ -180,7 +854,35 @@ async fn main() -> Result<()> {
     std::process::exit(1);
   })?;
 
-  let mut client = RdbControlClient::connect(opts.server.clone()).await?;
+  // Benchmarks talk to the HTTP API instead.
+  if let SubCommand::Bench(x) = &opts.subcmd {
+    return run_bench(x, opts.token.as_deref()).await;
+  }
+  if let SubCommand::FmtSchema(x) = &opts.subcmd {
+    return fmt_schema(x);
+  }
+  if let SubCommand::Codegen(x) = &opts.subcmd {
+    return codegen(x);
+  }
+
+  let channel = Endpoint::from_shared(opts.server.clone())?
+    .connect()
+    .await?;
+  let mut client = match &opts.token {
+    Some(token) => {
+      let authorization = MetadataValue::from_str(&format!("Bearer {}", token))?;
+      RdbControlClient::with_interceptor(
+        channel,
+        move |mut req: Request<()>| -> Result<Request<()>, Status> {
+          req
+            .metadata_mut()
+            .insert("authorization", authorization.clone());
+          Ok(req)
+        },
+      )
+    }
+    None => RdbControlClient::new(channel),
+  };
 
   match &opts.subcmd {
     SubCommand::CreateNamespace(x) => {
@@ -225,10 +927,176 @@ async fn main() -> Result<()> {
         }))?
       );
     }
+    SubCommand::SetNamespaceQuota(x) => {
+      let req = Request::new(SetNamespaceQuotaRequest {
+        namespace_id: x.namespace_id.clone(),
+        write_quota_bytes: x.write_quota_bytes,
+      });
+      let res = client.set_namespace_quota(req).await?;
+      println!(
+        "{}",
+        serde_json::to_string(&serde_json::json!({
+          "updated": res.get_ref().updated,
+        }))?
+      );
+    }
+    SubCommand::SetNamespaceIdStrategy(x) => {
+      let req = Request::new(SetNamespaceIdStrategyRequest {
+        namespace_id: x.namespace_id.clone(),
+        id_strategy: x.strategy.clone().unwrap_or_default(),
+      });
+      let res = client.set_namespace_id_strategy(req).await?;
+      println!(
+        "{}",
+        serde_json::to_string(&serde_json::json!({
+          "updated": res.get_ref().updated,
+        }))?
+      );
+    }
+    SubCommand::GetNamespaceUsage(x) => {
+      let req = Request::new(GetNamespaceUsageRequest {
+        namespace_id: x.namespace_id.clone(),
+      });
+      let res = client.get_namespace_usage(req).await?;
+      let res = res.get_ref();
+      println!(
+        "{}",
+        serde_json::to_string(&serde_json::json!({
+          "bytes_read": res.bytes_read,
+          "bytes_written": res.bytes_written,
+          "write_quota_bytes": res.write_quota_bytes,
+        }))?
+      );
+    }
+    SubCommand::SetDeploymentRouting(x) => {
+      let req = Request::new(SetDeploymentRoutingRequest {
+        namespace_id: x.namespace_id.clone(),
+        routing: Some(DeploymentRouting {
+          primary_deployment_id: x.primary.clone().unwrap_or_default(),
+          candidate_deployment_id: x.candidate.clone().unwrap_or_default(),
+          candidate_traffic_percent: x.traffic_percent,
+          candidate_mode: if x.canary {
+            CandidateMode::Canary
+          } else {
+            CandidateMode::Shadow
+          } as i32,
+        }),
+      });
+      let res = client.set_deployment_routing(req).await?;
+      println!(
+        "{}",
+        serde_json::to_string(&serde_json::json!({
+          "updated": res.get_ref().updated,
+        }))?
+      );
+    }
+    SubCommand::GetDeploymentRouting(x) => {
+      let req = Request::new(GetDeploymentRoutingRequest {
+        namespace_id: x.namespace_id.clone(),
+      });
+      let res = client.get_deployment_routing(req).await?;
+      let routing = res.get_ref().routing.clone().unwrap_or_default();
+      let mode = match CandidateMode::from_i32(routing.candidate_mode) {
+        Some(CandidateMode::Shadow) => "shadow",
+        Some(CandidateMode::Canary) => "canary",
+        None => "unknown",
+      };
+      println!(
+        "{}",
+        serde_json::to_string(&serde_json::json!({
+          "primary_deployment_id": routing.primary_deployment_id,
+          "candidate_deployment_id": routing.candidate_deployment_id,
+          "candidate_traffic_percent": routing.candidate_traffic_percent,
+          "candidate_mode": mode,
+        }))?
+      );
+    }
+    SubCommand::TailChangelog(x) => {
+      let req = Request::new(TailChangelogRequest {
+        namespace_id: x.namespace_id.clone(),
+        from_seq: x.from_seq,
+        limit: x.limit,
+      });
+      let res = client.tail_changelog(req).await?;
+      let res = res.get_ref();
+      let entries = res
+        .entries
+        .iter()
+        .map(|entry| {
+          let ops = entry
+            .ops
+            .iter()
+            .map(|op| match changelog_op::Kind::from_i32(op.kind) {
+              Some(changelog_op::Kind::Put) => serde_json::json!({
+                "op": "put",
+                "key": hex::encode(&op.key),
+                "value": hex::encode(&op.value),
+              }),
+              Some(changelog_op::Kind::Delete) => serde_json::json!({
+                "op": "delete",
+                "key": hex::encode(&op.key),
+              }),
+              Some(changelog_op::Kind::DeleteRange) => serde_json::json!({
+                "op": "delete_range",
+                "start": hex::encode(&op.key),
+                "end": hex::encode(&op.value),
+              }),
+              Some(changelog_op::Kind::Add) => serde_json::json!({
+                "op": "add",
+                "key": hex::encode(&op.key),
+                "delta": decode_counter(&op.value),
+              }),
+              None => serde_json::json!({ "op": "unknown" }),
+            })
+            .collect::<Vec<_>>();
+          serde_json::json!({
+            "seq": entry.seq,
+            "commit_time": entry.commit_time,
+            "ops": ops,
+          })
+        })
+        .collect::<Vec<_>>();
+      println!(
+        "{}",
+        serde_json::to_string(&serde_json::json!({
+          "entries": entries,
+          "next_seq": res.next_seq,
+        }))?
+      );
+    }
+    SubCommand::WatchNamespaces(x) => {
+      let req = Request::new(WatchNamespacesRequest {
+        from_seq: x.from_seq,
+      });
+      let mut stream = client.watch_namespaces(req).await?.into_inner();
+      while let Some(event) = stream.message().await? {
+        println!("{}", serde_json::to_string(&control_event_json(&event))?);
+      }
+    }
+    SubCommand::WatchDeployments(x) => {
+      let req = Request::new(WatchDeploymentsRequest {
+        namespace_id: x.namespace_id.clone(),
+        from_seq: x.from_seq,
+      });
+      let mut stream = client.watch_deployments(req).await?.into_inner();
+      while let Some(event) = stream.message().await? {
+        println!("{}", serde_json::to_string(&control_event_json(&event))?);
+      }
+    }
     SubCommand::CreateDeployment(subopts) => {
       let schema_text = std::fs::read_to_string(&subopts.schema)?;
+      let migration_script = match &subopts.migration_script {
+        Some(_) if subopts.migrate_from.is_none() => {
+          return Err(CliError::MigrationScriptWithoutSource.into())
+        }
+        Some(x) => std::fs::read_to_string(x)?,
+        None => String::new(),
+      };
 
       let new_schema = compile(&parse(&Bump::new(), &schema_text)?)?;
+      for x in lint(&new_schema) {
+        log::warn!("{}", x);
+      }
       let new_plan = if let Some(reference) = &subopts.migrate_from {
         let reference_deployment = client
           .get_deployment(Request::new(GetDeploymentRequest {
@@ -243,11 +1111,17 @@ async fn main() -> Result<()> {
           .ok_or_else(|| CliError::ReferenceDeploymentNotFound)?;
         let reference_schema = compile(&parse(&Bump::new(), &info.schema)?)?;
         let reference_plan: StoragePlan<String> = serde_yaml::from_str(&info.plan)?;
-        let reference_plan = StoragePlan::<StorageKey>::try_from(&reference_plan)?;
+        let mut reference_plan = StoragePlan::<StorageKey>::try_from(&reference_plan)?;
+        if subopts.key_aliases && reference_plan.key_aliases.is_empty() {
+          return Err(CliError::ReferenceDeploymentWithoutKeyAliases.into());
+        }
+        reference_plan.share_layouts |= subopts.share_layouts;
         let new_plan = generate_plan_for_schema(&reference_plan, &reference_schema, &new_schema)?;
 
         let (n_insert, n_delete) = print_diff(&reference_plan, &new_plan);
-        if n_insert != 0 || n_delete != 0 {
+        if n_insert == 0 && n_delete == 0 {
+          log::info!("Storage plan unchanged.");
+        } else if !subopts.dry_run {
           let proceed = block_in_place(|| {
             Confirm::with_theme(&ColorfulTheme::default())
               .with_prompt("Do you wish to apply the new storage plan?")
@@ -257,12 +1131,18 @@ async fn main() -> Result<()> {
             return Err(CliError::AbortedByUser.into());
           }
           log::info!("Storage plan migrated from reference deployment.");
-        } else {
-          log::info!("Storage plan unchanged.");
         }
         new_plan
       } else {
-        generate_plan_for_schema(&Default::default(), &Default::default(), &new_schema)?
+        let empty_plan = StoragePlan {
+          share_layouts: subopts.share_layouts,
+          ..Default::default()
+        };
+        let mut new_plan = generate_plan_for_schema(&empty_plan, &Default::default(), &new_schema)?;
+        if subopts.key_aliases {
+          assign_key_aliases(&mut new_plan)?;
+        }
+        new_plan
       };
 
       let res = client
@@ -271,8 +1151,25 @@ async fn main() -> Result<()> {
           schema: schema_text,
           plan: serde_yaml::to_string(&StoragePlan::<String>::from(&new_plan))?,
           description: subopts.description.clone().unwrap_or_default(),
+          migrate_from: subopts.migrate_from.clone().unwrap_or_default(),
+          migration_script,
+          dry_run: subopts.dry_run,
+          allow_lossy: subopts.allow_lossy,
         }))
         .await?;
+      if let Some(report) = &res.get_ref().report {
+        println!(
+          "{}",
+          serde_json::to_string(&serde_json::json!({
+            "preserved_fields": report.preserved_fields,
+            "dropped_fields": report.dropped_fields,
+            "new_fields": report.new_fields,
+            "plan_matches": report.plan_matches,
+            "lossy_changes": report.lossy_changes,
+          }))?
+        );
+        return Ok(());
+      }
       let deployment_id = res
         .get_ref()
         .deployment_id
@@ -377,7 +1274,652 @@ async fn main() -> Result<()> {
         }))?
       );
     }
+    SubCommand::VisualizeScript(subopts) => {
+      let res = client
+        .get_query_script(Request::new(GetQueryScriptRequest {
+          namespace_id: subopts.namespace.clone(),
+          query_script_id: subopts.id.clone(),
+        }))
+        .await?;
+      let script_info = res
+        .get_ref()
+        .info
+        .as_ref()
+        .ok_or_else(|| CliError::QueryScriptNotFound)?;
+      let res = client
+        .get_deployment(Request::new(GetDeploymentRequest {
+          namespace_id: subopts.namespace.clone(),
+          deployment_id: script_info.associated_deployment.clone(),
+        }))
+        .await?;
+      let info = res
+        .get_ref()
+        .info
+        .as_ref()
+        .ok_or_else(|| CliError::DeploymentNotFound)?;
+      let schema = compile(&parse(&Bump::new(), &info.schema)?)?;
+      let plan: StoragePlan<String> = serde_yaml::from_str(&info.plan)?;
+      let plan = StoragePlan::<StorageKey>::try_from(&plan)?;
+      let script = compile_twscript(&script_info.script)?;
+      let vm = TwVm::new(&schema, &plan, &script)?;
+
+      // Scripts that mount other namespaces only type check against the mounted schemas, which
+      // are resolved by the server. Render them untyped.
+      let type_info = match GlobalTyckContext::new(&vm).and_then(|mut x| x.typeck()) {
+        Ok(x) => Some(x),
+        Err(e) => {
+          log::warn!("Rendering without types: {}", e);
+          None
+        }
+      };
+      print!("{}", visualize_dot(&vm, type_info.as_ref())?);
+    }
+    SubCommand::CreateExplorerToken(subopts) => {
+      let req = Request::new(CreateExplorerTokenRequest {
+        namespace_id: subopts.namespace.clone(),
+        description: subopts.description.clone().unwrap_or_default(),
+        allowed_graphs: subopts.allowed_graphs.clone(),
+        redacted_fields: subopts.redacted_fields.clone(),
+      });
+      let res = client.create_explorer_token(req).await?;
+      let token = res
+        .get_ref()
+        .token
+        .as_ref()
+        .ok_or_else(|| CliError::ExplorerTokenNotCreated)?;
+      println!(
+        "{}",
+        serde_json::to_string(&serde_json::json!({
+          "id": token.id,
+          "token": token.token,
+        }))?
+      );
+    }
+    SubCommand::ListExplorerToken(subopts) => {
+      let req = Request::new(ListExplorerTokenRequest {
+        namespace_id: subopts.namespace.clone(),
+      });
+      let res = client.list_explorer_token(req).await?;
+      println!(
+        "{}",
+        serde_json::to_string(
+          &res
+            .get_ref()
+            .tokens
+            .iter()
+            .map(|x| serde_json::json!({
+              "id": x.id,
+              "description": x.description,
+              "allowed_graphs": x.allowed_graphs,
+              "redacted_fields": x.redacted_fields,
+              "create_time": x.create_time,
+            }))
+            .collect::<Vec<_>>()
+        )?
+      );
+    }
+    SubCommand::DeleteExplorerToken(subopts) => {
+      let req = Request::new(DeleteExplorerTokenRequest {
+        namespace_id: subopts.namespace.clone(),
+        id: subopts.id.clone(),
+      });
+      let res = client.delete_explorer_token(req).await?;
+      println!(
+        "{}",
+        serde_json::to_string(&serde_json::json!({
+          "deleted": res.get_ref().deleted,
+        }))?
+      );
+    }
+    SubCommand::CreateApiToken(subopts) => {
+      if subopts.read_only_graphs.is_empty() && subopts.effectful_graphs.is_empty() {
+        return Err(CliError::NoGraphsGranted.into());
+      }
+      let mut grants = vec![];
+      for (graphs, permission) in [
+        (&subopts.read_only_graphs, GraphPermission::ReadOnly),
+        (&subopts.effectful_graphs, GraphPermission::Effectful),
+      ] {
+        for x in graphs {
+          let (query_script_id, graph_name) = x
+            .split_once('/')
+            .ok_or_else(|| CliError::InvalidGraphName(x.clone()))?;
+          grants.push(GraphGrant {
+            query_script_id: query_script_id.to_string(),
+            graph_name: graph_name.to_string(),
+            permission: permission as i32,
+          });
+        }
+      }
+      let req = Request::new(CreateApiTokenRequest {
+        namespace_id: subopts.namespace.clone(),
+        description: subopts.description.clone().unwrap_or_default(),
+        grants,
+      });
+      let res = client.create_api_token(req).await?;
+      let token = res
+        .get_ref()
+        .token
+        .as_ref()
+        .ok_or_else(|| CliError::ApiTokenNotCreated)?;
+      println!(
+        "{}",
+        serde_json::to_string(&serde_json::json!({
+          "id": token.id,
+          "token": token.token,
+        }))?
+      );
+    }
+    SubCommand::ListApiToken(subopts) => {
+      let req = Request::new(ListApiTokenRequest {
+        namespace_id: subopts.namespace.clone(),
+      });
+      let res = client.list_api_token(req).await?;
+      let tokens = res
+        .get_ref()
+        .tokens
+        .iter()
+        .map(|x| {
+          let grants = x
+            .grants
+            .iter()
+            .map(|g| {
+              let permission = match GraphPermission::from_i32(g.permission) {
+                Some(GraphPermission::ReadOnly) => "read_only",
+                Some(GraphPermission::Effectful) => "effectful",
+                None => "unknown",
+              };
+              serde_json::json!({
+                "graph": format!("{}/{}", g.query_script_id, g.graph_name),
+                "permission": permission,
+              })
+            })
+            .collect::<Vec<_>>();
+          serde_json::json!({
+            "id": x.id,
+            "description": x.description,
+            "grants": grants,
+            "create_time": x.create_time,
+          })
+        })
+        .collect::<Vec<_>>();
+      println!("{}", serde_json::to_string(&tokens)?);
+    }
+    SubCommand::DeleteApiToken(subopts) => {
+      let req = Request::new(DeleteApiTokenRequest {
+        namespace_id: subopts.namespace.clone(),
+        id: subopts.id.clone(),
+      });
+      let res = client.delete_api_token(req).await?;
+      println!(
+        "{}",
+        serde_json::to_string(&serde_json::json!({
+          "deleted": res.get_ref().deleted,
+        }))?
+      );
+    }
+    SubCommand::InvokeGraph(subopts) => {
+      let req = Request::new(InvokeGraphRequest {
+        namespace_id: subopts.namespace.clone(),
+        query_script_id: subopts.script.clone(),
+        graph_name: subopts.graph.clone(),
+        params: subopts.params.clone(),
+        api_token: subopts.api_token.clone().unwrap_or_default(),
+        limits: Some(ExecLimits {
+          timeout_ms: subopts.timeout_ms.unwrap_or_default(),
+          max_kv_reads: subopts.max_kv_reads.unwrap_or_default(),
+          max_kv_writes: subopts.max_kv_writes.unwrap_or_default(),
+          max_subgraph_calls: subopts.max_subgraph_calls.unwrap_or_default(),
+          max_value_size: subopts.max_value_size.unwrap_or_default(),
+          max_recursion_depth: subopts.max_recursion_depth.unwrap_or_default(),
+        }),
+        after: subopts.after.clone().unwrap_or_default(),
+        page_size: subopts.page_size.unwrap_or_default(),
+      });
+      let res = client.invoke_graph(req).await?;
+      println!("{}", res.get_ref().output);
+    }
+    SubCommand::CreateToken(subopts) => {
+      let req = Request::new(CreateTokenRequest {
+        description: subopts.description.clone().unwrap_or_default(),
+        namespaces: subopts.namespaces.clone(),
+        mounts: subopts.mounts.clone(),
+        role: subopts.role.clone().unwrap_or_default(),
+      });
+      let res = client.create_token(req).await?;
+      let token = res
+        .get_ref()
+        .token
+        .as_ref()
+        .ok_or_else(|| CliError::TokenNotCreated)?;
+      println!(
+        "{}",
+        serde_json::to_string(&serde_json::json!({
+          "id": token.id,
+          "token": token.token,
+        }))?
+      );
+    }
+    SubCommand::ListToken(_) => {
+      let req = Request::new(ListTokenRequest {});
+      let res = client.list_token(req).await?;
+      let tokens = res
+        .get_ref()
+        .tokens
+        .iter()
+        .map(|x| {
+          serde_json::json!({
+            "id": x.id,
+            "description": x.description,
+            "namespaces": x.namespaces,
+            "create_time": x.create_time,
+            "mounts": x.mounts,
+            "role": x.role,
+          })
+        })
+        .collect::<Vec<_>>();
+      println!("{}", serde_json::to_string(&tokens)?);
+    }
+    SubCommand::RevokeToken(subopts) => {
+      let req = Request::new(RevokeTokenRequest {
+        id: subopts.id.clone(),
+      });
+      let res = client.revoke_token(req).await?;
+      println!(
+        "{}",
+        serde_json::to_string(&serde_json::json!({
+          "revoked": res.get_ref().revoked,
+        }))?
+      );
+    }
+    SubCommand::QueryCacheStats(_) => {
+      let req = Request::new(GetQueryCacheStatsRequest {});
+      let res = client.get_query_cache_stats(req).await?;
+      let res = res.get_ref();
+      let entries = res
+        .entries
+        .iter()
+        .map(|x| {
+          serde_json::json!({
+            "namespace_id": x.namespace_id,
+            "query_script_id": x.query_script_id,
+            "deployment_id": x.deployment_id,
+            "query_script_create_time": x.query_script_create_time,
+            "size_estimate": x.size_estimate,
+            "hot": x.hot,
+          })
+        })
+        .collect::<Vec<_>>();
+      println!(
+        "{}",
+        serde_json::to_string(&serde_json::json!({
+          "entries": entries,
+          "hot_hits": res.hot_hits,
+          "hits": res.hits,
+          "misses": res.misses,
+          "memory_estimate": res.memory_estimate,
+        }))?
+      );
+    }
+    SubCommand::InvalidateQueryCache(subopts) => {
+      let req = Request::new(InvalidateQueryCacheRequest {
+        namespace_id: subopts.namespace.clone(),
+        query_script_id: subopts.script.clone().unwrap_or_default(),
+      });
+      let res = client.invalidate_query_cache(req).await?;
+      println!(
+        "{}",
+        serde_json::to_string(&serde_json::json!({
+          "invalidated": res.get_ref().invalidated,
+        }))?
+      );
+    }
+    SubCommand::Repl(subopts) => {
+      run_repl(&mut client, subopts).await?;
+    }
+    SubCommand::Bundle(subopts) => {
+      run_bundle(&mut client, subopts).await?;
+    }
+    SubCommand::Apply(subopts) => {
+      run_apply(&mut client, subopts).await?;
+    }
+    SubCommand::TraceQuery(subopts) => {
+      let req = Request::new(TraceQueryRequest {
+        namespace_id: subopts.namespace.clone(),
+        query_script_id: subopts.script.clone(),
+        graph_name: subopts.graph.clone(),
+        params: subopts.params.clone(),
+      });
+      let res = client.trace_query(req).await?;
+      let res = res.get_ref();
+      println!(
+        "{}",
+        serde_json::to_string(&serde_json::json!({
+          "output": serde_json::from_str::<serde_json::Value>(&res.output)?,
+          "trace": serde_json::from_str::<serde_json::Value>(&res.trace)?,
+          "dataflow": serde_json::from_str::<serde_json::Value>(&res.dataflow)?,
+        }))?
+      );
+    }
+    SubCommand::BulkDelete(subopts) => {
+      let req = Request::new(BulkDeleteRequest {
+        namespace_id: subopts.namespace.clone(),
+        deployment_id: subopts.deployment.clone(),
+        set_path: subopts.set.clone(),
+        keys: subopts.keys.clone(),
+        chunk_size: subopts.chunk_size,
+      });
+      let res = client.bulk_delete(req).await?;
+      let outcomes = res
+        .get_ref()
+        .outcomes
+        .iter()
+        .map(|x| {
+          let status = match bulk_delete_outcome::Status::from_i32(x.status) {
+            Some(bulk_delete_outcome::Status::Deleted) => "deleted",
+            Some(bulk_delete_outcome::Status::NotFound) => "not_found",
+            Some(bulk_delete_outcome::Status::Failed) | None => "failed",
+          };
+          serde_json::json!({
+            "status": status,
+            "error": x.error,
+          })
+        })
+        .collect::<Vec<_>>();
+      println!("{}", serde_json::to_string(&outcomes)?);
+    }
+    SubCommand::DeleteDeployment(subopts) => {
+      let req = Request::new(DeleteDeploymentRequest {
+        namespace_id: subopts.namespace.clone(),
+        id: subopts.id.clone(),
+        force: subopts.force,
+      });
+      let res = client.delete_deployment(req).await?;
+      let res = res.get_ref();
+      if !res.deleted && !res.query_scripts.is_empty() && !subopts.force {
+        return Err(CliError::DeploymentInUse(res.query_scripts.clone()).into());
+      }
+      println!(
+        "{}",
+        serde_json::to_string(&serde_json::json!({
+          "deleted": res.deleted,
+          "deleted_query_scripts": res.query_scripts,
+        }))?
+      );
+    }
+    SubCommand::CheckSchema(subopts) => {
+      let req = Request::new(CheckSchemaRequest {
+        schema: std::fs::read_to_string(&subopts.schema)?,
+      });
+      let res = client.check_schema(req).await?;
+      println!(
+        "{}",
+        serde_json::to_string(&serde_json::json!({
+          "warnings": res.get_ref().warnings,
+        }))?
+      );
+    }
+    SubCommand::DescribeSchema(subopts) => {
+      let req = Request::new(DescribeSchemaRequest {
+        namespace_id: subopts.namespace.clone(),
+        deployment_id: subopts.deployment.clone().unwrap_or_default(),
+      });
+      let res = client.describe_schema(req).await?;
+      let res = res.get_ref();
+      let types = res
+        .types
+        .iter()
+        .map(|x| {
+          serde_json::json!({
+            "name": x.name,
+            "fields": x.fields.iter().map(field_description_json).collect::<Vec<_>>(),
+            "ttl_field": x.ttl_field,
+          })
+        })
+        .collect::<Vec<_>>();
+      let field_keys = res
+        .field_keys
+        .iter()
+        .map(|x| serde_json::json!({ "path": x.path, "key": hex::encode(&x.key) }))
+        .collect::<Vec<_>>();
+      println!(
+        "{}",
+        serde_json::to_string(&serde_json::json!({
+          "deployment_id": res.deployment_id,
+          "types": types,
+          "exports": res.exports.iter().map(field_description_json).collect::<Vec<_>>(),
+          "field_keys": field_keys,
+        }))?
+      );
+    }
+    SubCommand::GetPlanDiff(subopts) => {
+      let req = Request::new(GetPlanDiffRequest {
+        namespace_id: subopts.namespace.clone(),
+        old_deployment_id: subopts.from.clone(),
+        new_deployment_id: subopts.to.clone(),
+        new_plan: String::new(),
+      });
+      let res = client.get_plan_diff(req).await?;
+      let diff = res.get_ref().diff.clone().unwrap_or_default();
+      let nodes = |x: &[PlanDiffNode]| {
+        x.iter()
+          .map(|x| serde_json::json!({ "path": x.path, "key": hex::encode(&x.key) }))
+          .collect::<Vec<_>>()
+      };
+      let moved = diff
+        .moved
+        .iter()
+        .map(|x| {
+          serde_json::json!({
+            "old_path": x.old_path,
+            "new_path": x.new_path,
+            "key": hex::encode(&x.key),
+          })
+        })
+        .collect::<Vec<_>>();
+      let rekeyed = diff
+        .rekeyed
+        .iter()
+        .map(|x| {
+          serde_json::json!({
+            "path": x.path,
+            "old_key": hex::encode(&x.old_key),
+            "new_key": hex::encode(&x.new_key),
+          })
+        })
+        .collect::<Vec<_>>();
+      println!(
+        "{}",
+        serde_json::to_string(&serde_json::json!({
+          "unchanged": diff.unchanged,
+          "added": nodes(&diff.added),
+          "removed": nodes(&diff.removed),
+          "moved": moved,
+          "rekeyed": rekeyed,
+        }))?
+      );
+    }
+    SubCommand::Export(subopts) => {
+      let mut out: Box<dyn Write> = match &subopts.out {
+        Some(x) => Box::new(BufWriter::new(std::fs::File::create(x)?)),
+        None => Box::new(std::io::stdout()),
+      };
+      let mut after = String::new();
+      let mut count = 0usize;
+      loop {
+        let req = Request::new(ExportDataRequest {
+          namespace_id: subopts.namespace.clone(),
+          deployment_id: subopts.deployment.clone(),
+          path: subopts.export.clone(),
+          after: after.clone(),
+          limit: subopts.page_size,
+        });
+        let res = client.export_data(req).await?;
+        let res = res.into_inner();
+        for row in &res.rows {
+          writeln!(out, "{}", row)?;
+        }
+        count += res.rows.len();
+        if res.next_after.is_empty() {
+          break;
+        }
+        after = res.next_after;
+      }
+      out.flush()?;
+      if subopts.out.is_some() {
+        println!("Exported {} row(s).", count);
+      }
+    }
+    SubCommand::Import(subopts) => {
+      let input = std::fs::read_to_string(&subopts.input)?;
+      let lines = input
+        .lines()
+        .enumerate()
+        .filter(|(_, x)| !x.trim().is_empty())
+        .collect::<Vec<_>>();
+      let mut count = 0u64;
+      for batch in lines.chunks(subopts.batch_size.max(1)) {
+        let rows = batch
+          .iter()
+          .map(|(i, x)| {
+            serde_json::from_str::<serde_json::Value>(x)
+              .with_context(|| format!("invalid JSON on line {}", i + 1))
+          })
+          .collect::<Result<Vec<_>>>()?;
+        let req = Request::new(ImportDataRequest {
+          namespace_id: subopts.namespace.clone(),
+          deployment_id: subopts.deployment.clone(),
+          path: subopts.export.clone(),
+          rows: serde_json::to_string(&rows)?,
+          chunk_size: subopts.chunk_size,
+        });
+        let res = client.import_data(req).await.with_context(|| {
+          format!(
+            "import failed in the batch starting on line {}; {} row(s) imported before it",
+            batch[0].0 + 1,
+            count
+          )
+        })?;
+        count += res.get_ref().imported;
+      }
+      println!("Imported {} row(s).", count);
+    }
+    SubCommand::Fsck(subopts) => {
+      let req = Request::new(CheckConsistencyRequest {
+        namespace_id: subopts.namespace.clone(),
+        deployment_id: subopts.deployment.clone().unwrap_or_default(),
+        repair: subopts.repair,
+        batch_size: subopts.batch_size,
+        delete_orphan_keys: subopts.delete_orphan_keys,
+      });
+      let res = client.check_consistency(req).await?;
+      let res = res.get_ref();
+      let issues = res
+        .issues
+        .iter()
+        .map(|x| {
+          let kind = match consistency_issue::Kind::from_i32(x.kind) {
+            Some(consistency_issue::Kind::MissingMemberData) => "missing_member_data",
+            Some(consistency_issue::Kind::OrphanMemberData) => "orphan_member_data",
+            Some(consistency_issue::Kind::UndecodableMember) => "undecodable_member",
+            Some(consistency_issue::Kind::MissingIndexEntry) => "missing_index_entry",
+            Some(consistency_issue::Kind::DanglingIndexEntry) => "dangling_index_entry",
+            Some(consistency_issue::Kind::OrphanKey) => "orphan_key",
+            None => "unknown",
+          };
+          serde_json::json!({
+            "kind": kind,
+            "path": x.path,
+            "key": hex::encode(&x.key),
+            "detail": x.detail,
+            "repaired": x.repaired,
+          })
+        })
+        .collect::<Vec<_>>();
+      println!(
+        "{}",
+        serde_json::to_string(&serde_json::json!({
+          "num_members": res.num_members,
+          "issues": issues,
+        }))?
+      );
+    }
+    SubCommand::Bench(_) | SubCommand::FmtSchema(_) | SubCommand::Codegen(_) => unreachable!(),
   }
 
   Ok(())
 }
+
+fn control_event_json(x: &ControlEvent) -> serde_json::Value {
+  let kind = match control_event::Kind::from_i32(x.kind) {
+    Some(control_event::Kind::Created) => "created",
+    Some(control_event::Kind::Deleted) => "deleted",
+    None => "unknown",
+  };
+  serde_json::json!({
+    "seq": x.seq,
+    "time": x.time,
+    "kind": kind,
+    "namespace_id": x.namespace_id,
+    "deployment_id": x.deployment_id,
+  })
+}
+
+fn field_description_json(x: &FieldDescription) -> serde_json::Value {
+  serde_json::json!({
+    "name": x.name,
+    "type": x.ty.as_ref().map(field_type_json),
+    "annotations": x
+      .annotations
+      .iter()
+      .map(|x| serde_json::json!({ "name": x.name, "args": x.args }))
+      .collect::<Vec<_>>(),
+  })
+}
+
+fn field_type_json(x: &FieldTypeDescription) -> serde_json::Value {
+  serde_json::json!({
+    "kind": x.kind,
+    "name": x.name,
+    "params": x.params.iter().map(field_type_json).collect::<Vec<_>>(),
+  })
+}
+
+fn codegen(opts: &Codegen) -> Result<()> {
+  let schema = std::fs::read_to_string(&opts.schema)?;
+  let schema = compile(&parse(&Bump::new(), &schema)?)?;
+  let out = match opts.lang.as_str() {
+    "rust" => generate_rust(&schema)?,
+    "ts" => {
+      let script = opts
+        .script
+        .as_ref()
+        .ok_or_else(|| CliError::CodegenScriptRequired(opts.lang.clone()))?;
+      let script = compile_twscript(&std::fs::read_to_string(script)?)?;
+      let plan = generate_plan_for_schema(&Default::default(), &Default::default(), &schema)?;
+      let vm = TwVm::new(&schema, &plan, &script)?;
+      let type_info = GlobalTyckContext::new(&vm)?.typeck()?;
+      generate_typescript(&vm, &type_info)?
+    }
+    _ => return Err(CliError::UnsupportedCodegenLanguage(opts.lang.clone()).into()),
+  };
+  print!("{}", out);
+  Ok(())
+}
+
+fn fmt_schema(opts: &FmtSchema) -> Result<()> {
+  let source = std::fs::read_to_string(&opts.file)?;
+  let formatted = format_schema(&source)?;
+  if opts.check {
+    if formatted != source {
+      return Err(CliError::SchemaNotFormatted(opts.file.clone()).into());
+    }
+  } else if opts.write {
+    if formatted != source {
+      std::fs::write(&opts.file, formatted)?;
+    }
+  } else {
+    print!("{}", formatted);
+  }
+  Ok(())
+}
@@ -0,0 +1,360 @@
+//! An interactive session against a deployment.
+//!
+//! Graphs and other top-level items entered at the prompt are collected into a query script that
+//! lives only in the session. The script is compiled and type checked locally as items are
+//! entered, and sent along with every run through the `executeAdhocQuery` RPC, so nothing needs to
+//! be created on the server.
+
+use std::{
+  convert::TryFrom,
+  io::{BufRead, Write},
+  time::Instant,
+};
+
+use anyhow::Result;
+use bumpalo::Bump;
+use clap::Clap;
+use console::style;
+use rdb_analyzer::{
+  data::treewalker::{
+    asm::{codegen::compile_twscript, TwAsmError},
+    bytecode::TwScript,
+    serialize::SerializedVmValue,
+    typeck::GlobalTyckContext,
+    vm::TwVm,
+    vm_value::VmType,
+  },
+  schema::{
+    compile::{compile, CompiledSchema},
+    grammar::parse,
+  },
+  storage_plan::StoragePlan,
+};
+use rdb_proto::{
+  proto::{
+    rdb_control_client::RdbControlClient, ExecuteAdhocQueryRequest, GetDeploymentRequest,
+    ListDeploymentRequest,
+  },
+  tonic::{transport::Channel, Request, Status},
+};
+use tokio::task::block_in_place;
+
+use crate::CliError;
+
+#[derive(Clap)]
+pub struct Repl {
+  /// Namespace id.
+  #[clap(long)]
+  namespace: String,
+
+  /// Deployment id. Defaults to the latest deployment of the namespace.
+  #[clap(long)]
+  deployment: Option<String>,
+}
+
+const HELP: &str = r#"Enter graphs, types, consts, params or mounts to add them to the session script. Entering
+a graph again replaces it. Exported graphs without parameters run as soon as they are entered.
+
+  :run <graph> [params]  Run an exported graph. Params are a JSON list, without `schema` params.
+  :show                  Print the session script.
+  :reset                 Clear the session script.
+  :help                  Print this message.
+  :quit                  Leave the session."#;
+
+struct Session {
+  deployment_id: String,
+  schema: CompiledSchema,
+  plan: StoragePlan,
+
+  /// Source of each entered item, with the names of the graphs it defines.
+  items: Vec<(String, Vec<String>)>,
+}
+
+impl Session {
+  fn script(&self) -> String {
+    self
+      .items
+      .iter()
+      .map(|(x, _)| x.as_str())
+      .collect::<Vec<_>>()
+      .join("\n\n")
+  }
+
+  /// Adds `source` to the script, replacing the items that define graphs of the same names.
+  /// Returns the compiled script and the names of the graphs that `source` defines.
+  fn add(&mut self, source: &str) -> Result<(TwScript, Vec<String>)> {
+    let previous = compile_twscript(&self.script())?
+      .graphs
+      .into_iter()
+      .map(|x| x.name)
+      .collect::<Vec<_>>();
+    let mut replaced = vec![];
+    let script = loop {
+      let mut items = self
+        .items
+        .iter()
+        .enumerate()
+        .filter(|(i, _)| !replaced.contains(i))
+        .map(|(_, (x, _))| x.as_str())
+        .collect::<Vec<_>>();
+      items.push(source);
+      match compile_twscript(&items.join("\n\n")) {
+        Ok(x) => {
+          self.check(&x)?;
+          break x;
+        }
+        Err(e) => {
+          let index = match e.downcast_ref::<TwAsmError>() {
+            Some(TwAsmError::DuplicateGraph(name)) => self
+              .items
+              .iter()
+              .position(|(_, graphs)| graphs.contains(name))
+              .filter(|x| !replaced.contains(x)),
+            _ => None,
+          };
+          match index {
+            Some(x) => replaced.push(x),
+            None => return Err(e),
+          }
+        }
+      }
+    };
+
+    let defined = script
+      .graphs
+      .iter()
+      .map(|x| x.name.clone())
+      .filter(|x| !previous.contains(x) || replaced.iter().any(|i| self.items[*i].1.contains(x)))
+      .collect::<Vec<_>>();
+    let mut index = 0usize;
+    self.items.retain(|_| {
+      index += 1;
+      !replaced.contains(&(index - 1))
+    });
+    self.items.push((source.to_string(), defined.clone()));
+    Ok((script, defined))
+  }
+
+  /// Type checks `script` against the schema of the deployment. Scripts that mount other
+  /// namespaces are left to the server, which knows the schemas of those.
+  fn check(&self, script: &TwScript) -> Result<()> {
+    if !script.mounts.is_empty() {
+      return Ok(());
+    }
+    let vm = TwVm::new(&self.schema, &self.plan, script)?;
+    GlobalTyckContext::new(&vm)?.typeck()?;
+    Ok(())
+  }
+}
+
+pub async fn run_repl(client: &mut RdbControlClient<Channel>, opts: &Repl) -> Result<()> {
+  let mut session = load_session(client, opts).await?;
+  eprintln!(
+    "Using deployment `{}` of namespace `{}`. Type `:help` for help.",
+    session.deployment_id, opts.namespace
+  );
+  while let Some(input) = block_in_place(read_input)? {
+    let input = input.trim();
+    if input.is_empty() {
+      continue;
+    }
+    if let Some(command) = input.strip_prefix(':') {
+      let (command, args) = match command.find(char::is_whitespace) {
+        Some(x) => (&command[..x], command[x..].trim()),
+        None => (command, ""),
+      };
+      match command {
+        "run" => {
+          let (graph, params) = match args.find(char::is_whitespace) {
+            Some(x) => (&args[..x], args[x..].trim()),
+            None => (args, "[]"),
+          };
+          if graph.is_empty() {
+            print_error("usage: `:run <graph> [params]`");
+          } else if let Err(e) = run(client, opts, &session, graph, params).await {
+            print_error(&describe_error(&e));
+          }
+        }
+        "show" => println!("{}", session.script()),
+        "reset" => session.items.clear(),
+        "help" => eprintln!("{}", HELP),
+        "quit" | "q" => break,
+        _ => print_error(&format!("unknown command `:{}`", command)),
+      }
+      continue;
+    }
+
+    let (script, defined) = match session.add(input) {
+      Ok(x) => x,
+      Err(e) => {
+        print_error(&describe_error(&e));
+        continue;
+      }
+    };
+    for graph in script.graphs.iter().filter(|x| defined.contains(&x.name)) {
+      // Bodies of `try` blocks are compiled into graphs of their own.
+      if graph.name.contains('$') {
+        continue;
+      }
+      let takes_params = graph
+        .param_types
+        .iter()
+        .any(|x| !matches!(script.types[*x as usize], VmType::Schema));
+      if !graph.exported {
+        eprintln!("defined `{}`", graph.name);
+      } else if takes_params {
+        eprintln!(
+          "defined `{}` - run it with `:run {} [params]`",
+          graph.name, graph.name
+        );
+      } else if let Err(e) = run(client, opts, &session, &graph.name, "[]").await {
+        print_error(&describe_error(&e));
+      }
+    }
+  }
+  Ok(())
+}
+
+/// Fetches the schema of the deployment to check scripts against. Sessions stay on the deployment
+/// they started with, even if a newer one is created.
+async fn load_session(client: &mut RdbControlClient<Channel>, opts: &Repl) -> Result<Session> {
+  let deployment_id = match &opts.deployment {
+    Some(x) => x.clone(),
+    None => client
+      .list_deployment(Request::new(ListDeploymentRequest {
+        namespace_id: opts.namespace.clone(),
+      }))
+      .await?
+      .get_ref()
+      .deployments
+      .iter()
+      .rev()
+      .max_by_key(|x| x.create_time)
+      .map(|x| x.id.clone())
+      .ok_or_else(|| CliError::NoDeployment)?,
+  };
+  let res = client
+    .get_deployment(Request::new(GetDeploymentRequest {
+      namespace_id: opts.namespace.clone(),
+      deployment_id: deployment_id.clone(),
+    }))
+    .await?;
+  let info = res
+    .get_ref()
+    .info
+    .as_ref()
+    .ok_or_else(|| CliError::DeploymentNotFound)?;
+  let schema = compile(&parse(&Bump::new(), &info.schema)?)?;
+  let plan: StoragePlan<String> = serde_yaml::from_str(&info.plan)?;
+  Ok(Session {
+    deployment_id,
+    schema,
+    plan: StoragePlan::try_from(&plan)?,
+    items: vec![],
+  })
+}
+
+async fn run(
+  client: &mut RdbControlClient<Channel>,
+  opts: &Repl,
+  session: &Session,
+  graph: &str,
+  params: &str,
+) -> Result<()> {
+  let start_time = Instant::now();
+  let res = client
+    .execute_adhoc_query(Request::new(ExecuteAdhocQueryRequest {
+      namespace_id: opts.namespace.clone(),
+      deployment_id: session.deployment_id.clone(),
+      script: session.script(),
+      graph_name: graph.to_string(),
+      params: params.to_string(),
+      limits: None,
+    }))
+    .await?;
+  let elapsed = start_time.elapsed();
+  let res = res.get_ref();
+
+  // Scripts that mount other namespaces are only type checked by the server.
+  if !res.diagnostics.is_empty() {
+    for x in &res.diagnostics {
+      print_error(x);
+    }
+    return Ok(());
+  }
+  let output = serde_json::from_str::<SerializedVmValue>(&res.output)?;
+  println!("{}", serde_json::to_string_pretty(&output.to_json())?);
+  eprintln!(
+    "{}",
+    style(format!("({} ms)", elapsed.as_millis()))
+      .for_stderr()
+      .dim()
+  );
+  Ok(())
+}
+
+/// Reads lines until the braces in them are balanced. Returns `None` at the end of the input.
+fn read_input() -> Result<Option<String>> {
+  let stdin = std::io::stdin();
+  let mut input = String::new();
+  loop {
+    eprint!("{}", if input.is_empty() { "rdb> " } else { "...> " });
+    std::io::stderr().flush()?;
+    if stdin.lock().read_line(&mut input)? == 0 {
+      return Ok(if input.trim().is_empty() {
+        None
+      } else {
+        Some(input)
+      });
+    }
+    if brace_depth(&input) <= 0 {
+      return Ok(Some(input));
+    }
+  }
+}
+
+/// Opening minus closing braces in `input`, outside string literals and comments.
+fn brace_depth(input: &str) -> i64 {
+  let mut depth = 0i64;
+  let mut chars = input.chars().peekable();
+  while let Some(c) = chars.next() {
+    match c {
+      '{' => depth += 1,
+      '}' => depth -= 1,
+      '"' => {
+        while let Some(c) = chars.next() {
+          match c {
+            '\\' => {
+              chars.next();
+            }
+            '"' => break,
+            _ => {}
+          }
+        }
+      }
+      '/' if chars.peek() == Some(&'/') => {
+        while let Some(c) = chars.next() {
+          if c == '\n' {
+            break;
+          }
+        }
+      }
+      _ => {}
+    }
+  }
+  depth
+}
+
+fn describe_error(e: &anyhow::Error) -> String {
+  match e.downcast_ref::<Status>() {
+    Some(x) => x.message().to_string(),
+    None => format!("{:#}", e),
+  }
+}
+
+fn print_error(message: &str) {
+  eprintln!(
+    "{}",
+    style(format!("error: {}", message)).for_stderr().red()
+  );
+}